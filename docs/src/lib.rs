@@ -43,7 +43,13 @@ fn parse_internal(input: &str) -> Result<flash::parser::Node, String> {
     let lexer = Lexer::new(input);
     let mut parser = Parser::new(lexer);
     
-    Ok(parser.parse_script())
+    parser.parse_script_checked().map_err(|errors| {
+        errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
 }
 
 #[wasm_bindgen(start)]
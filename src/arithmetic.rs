@@ -0,0 +1,660 @@
+/*
+ * Copyright (c) 2025 Raphael Amorim
+ *
+ * This file is part of flash, which is licensed
+ * under GNU General Public License v3.0.
+ */
+
+//! Shell arithmetic evaluation for `$(( ))`, `(( ))` and `let`
+//!
+//! Expressions are tokenized and evaluated by a recursive-descent parser that
+//! follows C operator precedence and associativity, as bash does.
+
+/// Access to shell variables while evaluating an arithmetic expression
+pub trait ArithmeticContext {
+    /// Value of the variable `name`, if it is set
+    fn variable(&self, name: &str) -> Option<String>;
+
+    /// Store the result of an assignment to `name`, failing when it cannot be set
+    fn assign(&mut self, name: &str, value: i64) -> Result<(), String>;
+}
+
+/// Context without variables: every name reads as zero and assignments are discarded
+pub struct NoVariables;
+
+impl ArithmeticContext for NoVariables {
+    fn variable(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    fn assign(&mut self, _name: &str, _value: i64) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Operators recognised by the tokenizer, longest first so `<=` wins over `<`
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--", "+=", "-=",
+    "*=", "/=", "%=", "&=", "|=", "^=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "|",
+    "^", "?", ":", ",", "(", ")",
+];
+
+/// Assignment operators, each paired with the binary operator it applies
+const ASSIGNMENT_OPERATORS: &[(&str, Option<&str>)] = &[
+    ("=", None),
+    ("+=", Some("+")),
+    ("-=", Some("-")),
+    ("*=", Some("*")),
+    ("/=", Some("/")),
+    ("%=", Some("%")),
+    ("<<=", Some("<<")),
+    (">>=", Some(">>")),
+    ("&=", Some("&")),
+    ("|=", Some("|")),
+    ("^=", Some("^")),
+];
+
+/// Limit on variables whose values refer to further variables, such as `a=b b=a`
+const MAX_RECURSION_DEPTH: usize = 100;
+
+/// Binary operators from lowest to highest precedence, all left-associative
+const BINARY_LEVELS: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    Name(String),
+    Operator(&'static str),
+}
+
+/// Evaluate `expr` without access to any variables
+pub fn evaluate(expr: &str) -> Result<i64, String> {
+    evaluate_with(expr, &mut NoVariables)
+}
+
+/// Evaluate `expr`, reading and assigning variables through `context`
+pub fn evaluate_with(expr: &str, context: &mut dyn ArithmeticContext) -> Result<i64, String> {
+    evaluate_at_depth(expr, context, 0)
+}
+
+fn evaluate_at_depth(
+    expr: &str,
+    context: &mut dyn ArithmeticContext,
+    depth: usize,
+) -> Result<i64, String> {
+    let tokens = tokenize(expr)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+
+    let mut evaluator = ArithmeticEvaluator {
+        source: expr,
+        tokens,
+        pos: 0,
+        skip: 0,
+        depth,
+        context,
+    };
+    let value = evaluator.comma()?;
+    match evaluator.tokens.get(evaluator.pos) {
+        None => Ok(value),
+        Some(_) => Err(evaluator.syntax_error("syntax error in expression")),
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while let Some(c) = expr[pos..].chars().next() {
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        }
+
+        let start = pos;
+        if c.is_ascii_alphanumeric() || c == '_' {
+            // Constants such as `64#a@_` may contain `#` and `@` after the leading digit
+            let is_number = c.is_ascii_digit();
+            let end = expr[pos..]
+                .find(|c: char| {
+                    !(c.is_ascii_alphanumeric()
+                        || c == '_'
+                        || (is_number && matches!(c, '#' | '@')))
+                })
+                .map_or(expr.len(), |offset| pos + offset);
+            let word = expr[pos..end].to_string();
+            pos = end;
+            if is_number {
+                tokens.push((start, Token::Number(word)));
+            } else {
+                tokens.push((start, Token::Name(word)));
+            }
+        } else if let Some(op) = OPERATORS.iter().find(|op| expr[pos..].starts_with(*op)) {
+            // As in bash, `++` and `--` only increment when they follow or precede a
+            // name; otherwise they are two signs, so `5--3` is 8
+            let op = if matches!(*op, "++" | "--") && !increments_name(&tokens, &expr[pos + 2..]) {
+                &op[..1]
+            } else {
+                op
+            };
+            pos += op.len();
+            tokens.push((start, Token::Operator(op)));
+        } else {
+            return Err(format!(
+                "syntax error: invalid arithmetic operator (error token is \"{}\")",
+                &expr[start..]
+            ));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Whether an `++` or `--` between the tokens so far and `rest` applies to a name
+fn increments_name(tokens: &[(usize, Token)], rest: &str) -> bool {
+    matches!(tokens.last(), Some((_, Token::Name(_))))
+        || rest
+            .trim_start()
+            .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+}
+
+/// Parse an integer constant: decimal, `0x` hexadecimal, leading-zero octal or
+/// `base#digits` with a base from 2 to 64
+fn parse_number(word: &str) -> Result<i64, String> {
+    let (base, digits) = if let Some((base, digits)) = word.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(base @ 2..=64) => (base, digits),
+            _ => {
+                return Err(format!(
+                    "invalid arithmetic base (error token is \"{word}\")"
+                ));
+            }
+        }
+    } else if let Some(digits) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        (16, digits)
+    } else if word.len() > 1 && word.starts_with('0') {
+        (8, &word[1..])
+    } else {
+        (10, word)
+    };
+
+    if digits.is_empty() {
+        return Err(format!(
+            "invalid integer constant (error token is \"{word}\")"
+        ));
+    }
+
+    // Values that overflow wrap around, as with bash's intmax_t arithmetic
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            // Letters are case-insensitive up to base 36; above it, uppercase follows lowercase
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => u32::MAX,
+        };
+        if digit >= base {
+            return Err(format!(
+                "value too great for base (error token is \"{word}\")"
+            ));
+        }
+        value = value
+            .wrapping_mul(i64::from(base))
+            .wrapping_add(i64::from(digit));
+    }
+    Ok(value)
+}
+
+struct ArithmeticEvaluator<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    // Nesting depth of operands that are parsed but not evaluated, such as the
+    // right side of a short-circuited `&&`; assignments and errors are suppressed there
+    skip: usize,
+    // How many variable values are being evaluated as subexpressions
+    depth: usize,
+    context: &'a mut dyn ArithmeticContext,
+}
+
+impl ArithmeticEvaluator<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn peek_operator(&self, operators: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Operator(op)) if operators.contains(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn syntax_error(&self, message: &str) -> String {
+        self.error_at(message, self.pos)
+    }
+
+    /// `message` with the rest of the expression from token `pos` on as the error token
+    fn error_at(&self, message: &str, pos: usize) -> String {
+        let rest = self
+            .tokens
+            .get(pos)
+            .map_or("", |(start, _)| self.source[*start..].trim());
+        format!("{message} (error token is \"{rest}\")")
+    }
+
+    fn comma(&mut self) -> Result<i64, String> {
+        let mut value = self.assignment()?;
+        while self.peek_operator(&[","]).is_some() {
+            self.pos += 1;
+            value = self.assignment()?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self) -> Result<i64, String> {
+        if let (Some(Token::Name(name)), Some((_, Token::Operator(op)))) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        {
+            if let Some((_, binary)) = ASSIGNMENT_OPERATORS.iter().find(|(a, _)| a == op) {
+                let name = name.clone();
+                self.pos += 2;
+                let operand = self.pos;
+                let mut value = self.assignment()?;
+                if let Some(binary) = binary {
+                    let current = self.variable_value(&name)?;
+                    value = self.apply(binary, current, value, operand)?;
+                }
+                self.store(&name, value)?;
+                return Ok(value);
+            }
+        }
+        self.conditional()
+    }
+
+    fn store(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if self.skip > 0 {
+            return Ok(());
+        }
+        self.context.assign(name, value)
+    }
+
+    fn conditional(&mut self) -> Result<i64, String> {
+        let condition = self.logical_or()?;
+        if self.peek_operator(&["?"]).is_none() {
+            return Ok(condition);
+        }
+
+        self.pos += 1;
+        let then = self.skipping_unless(condition != 0, Self::comma)?;
+        if self.peek_operator(&[":"]).is_none() {
+            return Err(self.syntax_error("`:' expected for conditional expression"));
+        }
+        self.pos += 1;
+        // The else branch nests to the right, so `a ? b : c ? d : e` groups as `a ? b : (c ? d : e)`
+        let otherwise = self.skipping_unless(condition == 0, Self::conditional)?;
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    fn logical_or(&mut self) -> Result<i64, String> {
+        let mut value = self.logical_and()?;
+        while self.peek_operator(&["||"]).is_some() {
+            self.pos += 1;
+            let taken = value == 0;
+            let right = self.skipping_unless(taken, Self::logical_and)?;
+            value = i64::from(value != 0 || right != 0);
+        }
+        Ok(value)
+    }
+
+    fn logical_and(&mut self) -> Result<i64, String> {
+        let mut value = self.binary(0)?;
+        while self.peek_operator(&["&&"]).is_some() {
+            self.pos += 1;
+            let taken = value != 0;
+            let right = self.skipping_unless(taken, |evaluator| evaluator.binary(0))?;
+            value = i64::from(value != 0 && right != 0);
+        }
+        Ok(value)
+    }
+
+    /// Parse an operand, evaluating it only when `taken` is true
+    fn skipping_unless(
+        &mut self,
+        taken: bool,
+        parse: impl FnOnce(&mut Self) -> Result<i64, String>,
+    ) -> Result<i64, String> {
+        if taken {
+            return parse(self);
+        }
+        self.skip += 1;
+        let result = parse(self);
+        self.skip -= 1;
+        result.map(|_| 0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == BINARY_LEVELS.len() {
+            return self.power();
+        }
+
+        let mut value = self.binary(level + 1)?;
+        while let Some(op) = self.peek_operator(BINARY_LEVELS[level]) {
+            self.pos += 1;
+            let operand = self.pos;
+            let right = self.binary(level + 1)?;
+            value = self.apply(op, value, right, operand)?;
+        }
+        Ok(value)
+    }
+
+    /// Binary operators wrap around on overflow, like bash's 64-bit arithmetic. The
+    /// right operand starts at token `operand`, which division by 0 reports.
+    fn apply(&self, op: &str, left: i64, right: i64, operand: usize) -> Result<i64, String> {
+        if matches!(op, "/" | "%") && right == 0 {
+            return if self.skip > 0 {
+                Ok(0)
+            } else {
+                Err(self.error_at("division by 0", operand))
+            };
+        }
+
+        Ok(match op {
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => i64::from(left == right),
+            "!=" => i64::from(left != right),
+            "<" => i64::from(left < right),
+            ">" => i64::from(left > right),
+            "<=" => i64::from(left <= right),
+            ">=" => i64::from(left >= right),
+            "<<" => left << (right & 63),
+            ">>" => left >> (right & 63),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            _ => unreachable!("unknown binary operator {op}"),
+        })
+    }
+
+    fn power(&mut self) -> Result<i64, String> {
+        let base = self.unary()?;
+        if self.peek_operator(&["**"]).is_none() {
+            return Ok(base);
+        }
+
+        self.pos += 1;
+        // Exponentiation is right-associative
+        let exponent = self.power()?;
+        if exponent < 0 {
+            return if self.skip > 0 {
+                Ok(0)
+            } else {
+                Err("exponent less than 0".to_string())
+            };
+        }
+        Ok(base.wrapping_pow(exponent.min(u32::MAX as i64) as u32))
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if let Some(op) = self.peek_operator(&["++", "--"]) {
+            self.pos += 1;
+            let Some(Token::Name(name)) = self.peek().cloned() else {
+                return Err(self.syntax_error("syntax error: operand expected"));
+            };
+            self.pos += 1;
+            let value = self
+                .variable_value(&name)?
+                .wrapping_add(if op == "++" { 1 } else { -1 });
+            self.store(&name, value)?;
+            return Ok(value);
+        }
+        if let Some(op) = self.peek_operator(&["-", "+", "!", "~"]) {
+            self.pos += 1;
+            let value = self.unary()?;
+            return Ok(match op {
+                "-" => value.wrapping_neg(),
+                "+" => value,
+                "!" => i64::from(value == 0),
+                _ => !value,
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        match self.peek().cloned() {
+            Some(Token::Number(word)) => {
+                self.pos += 1;
+                parse_number(&word)
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                let value = self.variable_value(&name)?;
+                // Postfix `++` and `--` yield the value from before the update
+                if let Some(op) = self.peek_operator(&["++", "--"]) {
+                    self.pos += 1;
+                    self.store(&name, value.wrapping_add(if op == "++" { 1 } else { -1 }))?;
+                }
+                Ok(value)
+            }
+            Some(Token::Operator("(")) => {
+                self.pos += 1;
+                let value = self.comma()?;
+                if self.peek_operator(&[")"]).is_none() {
+                    return Err(self.syntax_error("missing `)'"));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => Err(self.syntax_error("syntax error: operand expected")),
+        }
+    }
+
+    /// Value of a variable; unset and empty variables are zero, and any other value
+    /// is itself evaluated as an expression, so `a=1+1` makes `a * 2` equal 4
+    fn variable_value(&mut self, name: &str) -> Result<i64, String> {
+        let value = match self.context.variable(name) {
+            Some(value) if !value.trim().is_empty() => value,
+            _ => return Ok(0),
+        };
+        if let Ok(number) = parse_number(value.trim()) {
+            return Ok(number);
+        }
+        if self.skip > 0 {
+            return Ok(0);
+        }
+        if self.depth >= MAX_RECURSION_DEPTH {
+            return Err(format!(
+                "{name}: expression recursion level exceeded (error token is \"{name}\")"
+            ));
+        }
+        evaluate_at_depth(&value, self.context, self.depth + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    impl ArithmeticContext for HashMap<String, String> {
+        fn variable(&self, name: &str) -> Option<String> {
+            self.get(name).cloned()
+        }
+
+        fn assign(&mut self, name: &str, value: i64) -> Result<(), String> {
+            self.insert(name.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            evaluate("1 / 0"),
+            Err("division by 0 (error token is \"0\")".to_string())
+        );
+        assert_eq!(
+            evaluate("1/(0) * 2"),
+            Err("division by 0 (error token is \"(0) * 2\")".to_string())
+        );
+        assert_eq!(
+            evaluate("(1 + 2"),
+            Err("missing `)' (error token is \"\")".to_string())
+        );
+        assert_eq!(
+            evaluate("1 +"),
+            Err("syntax error: operand expected (error token is \"\")".to_string())
+        );
+        assert_eq!(
+            evaluate("1 2"),
+            Err("syntax error in expression (error token is \"2\")".to_string())
+        );
+    }
+
+    #[test]
+    fn test_variables_and_short_circuit() {
+        let mut variables = HashMap::from([("x".to_string(), "7".to_string())]);
+
+        assert_eq!(evaluate_with("y = x * 2 + 1", &mut variables), Ok(15));
+        assert_eq!(variables.get("y"), Some(&"15".to_string()));
+        assert_eq!(evaluate_with("a = b = 3", &mut variables), Ok(3));
+        assert_eq!(variables.get("a"), Some(&"3".to_string()));
+
+        // The unevaluated side neither assigns nor fails
+        assert_eq!(evaluate_with("0 && (z = 1 / 0)", &mut variables), Ok(0));
+        assert_eq!(evaluate_with("1 || (z = 1)", &mut variables), Ok(1));
+        assert_eq!(variables.get("z"), None);
+    }
+
+    #[test]
+    fn test_increment_and_compound_assignment() {
+        let mut variables = HashMap::from([("n".to_string(), "5".to_string())]);
+
+        assert_eq!(evaluate_with("n++", &mut variables), Ok(5));
+        assert_eq!(variables.get("n"), Some(&"6".to_string()));
+        assert_eq!(evaluate_with("++n", &mut variables), Ok(7));
+        assert_eq!(evaluate_with("n--", &mut variables), Ok(7));
+        assert_eq!(evaluate_with("--n", &mut variables), Ok(5));
+        assert_eq!(evaluate_with("n += 10", &mut variables), Ok(15));
+        assert_eq!(evaluate_with("n+=1", &mut variables), Ok(16));
+        assert_eq!(variables.get("n"), Some(&"16".to_string()));
+        assert_eq!(evaluate_with("n <<= 2, n -= 4, n", &mut variables), Ok(60));
+        assert_eq!(evaluate_with("n %= 7", &mut variables), Ok(4));
+        assert_eq!(evaluate_with("n ^= 1", &mut variables), Ok(5));
+        assert_eq!(
+            evaluate_with("n /= 0", &mut variables),
+            Err("division by 0 (error token is \"0\")".to_string())
+        );
+
+        // Without a name next to them, `++` and `--` are repeated signs
+        assert_eq!(evaluate("5--3"), Ok(8));
+        assert_eq!(evaluate("- -3"), Ok(3));
+        assert_eq!(evaluate("++3"), Ok(3));
+    }
+
+    #[test]
+    fn test_integer_constants() {
+        assert_eq!(evaluate("0"), Ok(0));
+        assert_eq!(evaluate("0xff"), Ok(255));
+        assert_eq!(evaluate("0XFF & 0x0f"), Ok(15));
+        assert_eq!(evaluate("010"), Ok(8));
+        assert_eq!(evaluate("2#1010"), Ok(10));
+        assert_eq!(evaluate("16#ff"), Ok(255));
+        assert_eq!(evaluate("36#Z"), Ok(35));
+        assert_eq!(evaluate("64#_"), Ok(63));
+        assert_eq!(evaluate("64#A"), Ok(36));
+
+        assert_eq!(
+            evaluate("09"),
+            Err("value too great for base (error token is \"09\")".to_string())
+        );
+        assert_eq!(
+            evaluate("2#102"),
+            Err("value too great for base (error token is \"2#102\")".to_string())
+        );
+        assert_eq!(
+            evaluate("65#1"),
+            Err("invalid arithmetic base (error token is \"65#1\")".to_string())
+        );
+        assert_eq!(
+            evaluate("0x"),
+            Err("invalid integer constant (error token is \"0x\")".to_string())
+        );
+    }
+
+    #[test]
+    fn test_overflow_wraps_around() {
+        assert_eq!(evaluate("9223372036854775807 + 1"), Ok(i64::MIN));
+        assert_eq!(evaluate("-9223372036854775807 - 2"), Ok(i64::MAX));
+        assert_eq!(evaluate("9223372036854775807 * 2"), Ok(-2));
+        assert_eq!(evaluate("-9223372036854775807 - 1"), Ok(i64::MIN));
+        assert_eq!(evaluate("(-9223372036854775807 - 1) / -1"), Ok(i64::MIN));
+        assert_eq!(evaluate("(-9223372036854775807 - 1) % -1"), Ok(0));
+
+        let mut variables = HashMap::from([("n".to_string(), i64::MAX.to_string())]);
+        assert_eq!(evaluate_with("n++, n", &mut variables), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn test_conditional_operator() {
+        let mut variables = HashMap::from([("x".to_string(), "-4".to_string())]);
+
+        assert_eq!(evaluate_with("x > 0 ? x : -x", &mut variables), Ok(4));
+        assert_eq!(evaluate("1 ? 2 ? 1 : 2 : 3"), Ok(1));
+        assert_eq!(evaluate("1 ? 0 ? 1 : 2 : 3"), Ok(2));
+        assert_eq!(evaluate("0 ? 2 ? 1 : 2 : 3"), Ok(3));
+        assert_eq!(evaluate("0 ? 1 : 0 ? 2 : 3"), Ok(3));
+        assert_eq!(evaluate("1 + 1 ? 5 : 6"), Ok(5));
+
+        // Only the chosen branch is evaluated
+        assert_eq!(
+            evaluate_with("1 ? (y = 1) : (z = 1 / 0)", &mut variables),
+            Ok(1)
+        );
+        assert_eq!(variables.get("y"), Some(&"1".to_string()));
+        assert_eq!(variables.get("z"), None);
+        assert_eq!(evaluate_with("w = 0 ? 1 : 2", &mut variables), Ok(2));
+        assert_eq!(variables.get("w"), Some(&"2".to_string()));
+
+        assert_eq!(
+            evaluate("1 ? 2"),
+            Err("`:' expected for conditional expression (error token is \"\")".to_string())
+        );
+    }
+
+    #[test]
+    fn test_variable_values_are_subexpressions() {
+        let mut variables = HashMap::from([
+            ("a".to_string(), "1+1".to_string()),
+            ("b".to_string(), "a * 3".to_string()),
+            ("empty".to_string(), String::new()),
+            ("loop".to_string(), "loop".to_string()),
+        ]);
+
+        assert_eq!(evaluate_with("a * 2", &mut variables), Ok(4));
+        assert_eq!(evaluate_with("b + 1", &mut variables), Ok(7));
+        assert_eq!(evaluate_with("empty + unset", &mut variables), Ok(0));
+        assert_eq!(
+            evaluate_with("loop", &mut variables),
+            Err("loop: expression recursion level exceeded (error token is \"loop\")".to_string())
+        );
+    }
+}
@@ -8,6 +8,7 @@
 use flash::interpreter::Interpreter;
 use std::env;
 use std::io::{self, Read};
+use std::path::Path;
 
 fn main() -> io::Result<()> {
     let mut interpreter = Interpreter::new();
@@ -17,8 +18,10 @@ fn main() -> io::Result<()> {
     // Check if stdin is a terminal first
     let is_tty = unsafe { libc::isatty(0) } == 1;
 
-    // If stdin is not a TTY, check for piped input first
-    if !is_tty {
+    // If stdin is not a TTY, check for piped input first, unless it is left for a
+    // -c command or script file to read
+    let runs_command = args.len() > 1 && (args[1] == "-c" || Path::new(&args[1]).is_file());
+    if !is_tty && !runs_command {
         // Not a terminal, read from stdin
         let mut input = String::new();
         io::stdin().read_to_string(&mut input)?;
@@ -32,7 +35,7 @@ fn main() -> io::Result<()> {
                 interpreter.set_args(script_args);
             }
 
-            match interpreter.execute(&input) {
+            match interpreter.run_script(&input) {
                 Ok(exit_code) => std::process::exit(exit_code),
                 Err(e) => {
                     eprintln!("Error: {e}");
@@ -49,7 +52,7 @@ fn main() -> io::Result<()> {
         if args[1] == "-c" && args.len() > 2 {
             // Execute the command directly: flash -c "command"
             let command = &args[2];
-            match interpreter.execute(command) {
+            match interpreter.run_script(command) {
                 Ok(exit_code) => std::process::exit(exit_code),
                 Err(e) => {
                     eprintln!("Error: {e}");
@@ -65,7 +68,7 @@ fn main() -> io::Result<()> {
 
             // Try to read and execute the script file
             match std::fs::read_to_string(script_path) {
-                Ok(script_content) => match interpreter.execute(&script_content) {
+                Ok(script_content) => match interpreter.run_script(&script_content) {
                     Ok(exit_code) => std::process::exit(exit_code),
                     Err(e) => {
                         eprintln!("Error executing script {script_path}: {e}");
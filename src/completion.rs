@@ -33,6 +33,74 @@ pub struct CompletionEntry {
     pub o_options: Vec<String>,
 }
 
+impl CompletionEntry {
+    /// Build an entry from the options of `complete` and `compgen`, returning it along
+    /// with the remaining operands
+    pub fn from_args(args: &[String]) -> Result<(Self, Vec<String>), String> {
+        let mut entry = Self::default();
+        let mut i = 0;
+        while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+            if args[i] == "--" {
+                i += 1;
+                break;
+            }
+            let flags: Vec<char> = args[i][1..].chars().collect();
+            for (index, &flag) in flags.iter().enumerate() {
+                let action = match flag {
+                    'a' => "alias",
+                    'c' => "command",
+                    'd' => "directory",
+                    'f' => "file",
+                    'u' => "user",
+                    'v' => "variable",
+                    'A' | 'F' | 'W' | 'o' => {
+                        // The value is the rest of this argument or the next one
+                        let value = if index + 1 < flags.len() {
+                            flags[index + 1..].iter().collect()
+                        } else {
+                            i += 1;
+                            args.get(i)
+                                .cloned()
+                                .ok_or_else(|| format!("-{flag}: option requires an argument"))?
+                        };
+                        match flag {
+                            'A' => entry.action = Self::action_name(&value)?.to_string(),
+                            'F' => entry.function = value,
+                            'W' => {
+                                entry.options.insert("wordlist".to_string(), value);
+                            }
+                            _ => match value.as_str() {
+                                "default" | "bashdefault" | "dirnames" | "filenames"
+                                | "nospace" | "plusdirs" => entry.o_options.push(value),
+                                _ => return Err(format!("{value}: invalid option name")),
+                            },
+                        }
+                        break;
+                    }
+                    _ => return Err(format!("-{flag}: invalid option")),
+                };
+                entry.action = action.to_string();
+            }
+            i += 1;
+        }
+        Ok((entry, args[i..].to_vec()))
+    }
+
+    /// Map an `-A` action name to the action understood by the completion system
+    fn action_name(action: &str) -> Result<&'static str, String> {
+        match action {
+            "alias" => Ok("alias"),
+            "command" => Ok("command"),
+            "directory" => Ok("directory"),
+            "file" => Ok("file"),
+            "hostname" => Ok("hostname"),
+            "user" => Ok("user"),
+            "variable" => Ok("variable"),
+            _ => Err(format!("{action}: invalid action name")),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CompletionContext {
     /// The full command line
@@ -126,10 +194,11 @@ impl CompletionSystem {
         if let Some(entry) = self.command_completions.get(command).cloned() {
             self.current = entry.clone();
 
-            if !entry.function.is_empty() {
-                return self.call_completion_function(&entry.function, context);
-            } else if !entry.action.is_empty() {
-                return self.complete_by_action(&entry.action, context);
+            if !entry.function.is_empty()
+                || !entry.action.is_empty()
+                || entry.options.contains_key("wordlist")
+            {
+                return self.generate(&entry, context);
             }
         }
 
@@ -143,6 +212,71 @@ impl CompletionSystem {
         }
     }
 
+    /// Generate the candidates a completion entry produces for the current word, falling
+    /// back to the default completions when it produces none and `-o default` or
+    /// `-o bashdefault` is set. With `-o filenames` the candidates are treated as paths.
+    pub fn generate(&self, entry: &CompletionEntry, context: &CompletionContext) -> Vec<String> {
+        let mut completions = Vec::new();
+        if !entry.function.is_empty() {
+            completions.extend(self.call_completion_function(&entry.function, context));
+        }
+        if !entry.action.is_empty() {
+            completions.extend(self.complete_by_action(&entry.action, context));
+        }
+        if let Some(wordlist) = entry.options.get("wordlist") {
+            completions.extend(
+                wordlist
+                    .split_whitespace()
+                    .filter(|word| word.starts_with(&context.current_word))
+                    .map(String::from),
+            );
+        }
+
+        if completions.is_empty() {
+            if entry.o_options.iter().any(|option| option == "bashdefault") && context.cword == 0 {
+                return self.complete_commands(&context.current_word);
+            }
+            if entry
+                .o_options
+                .iter()
+                .any(|option| option == "default" || option == "bashdefault")
+            {
+                return self.complete_files(&context.current_word);
+            }
+        }
+        if entry.o_options.iter().any(|option| option == "filenames") {
+            completions = completions
+                .iter()
+                .map(|completion| Self::filename_completion(completion))
+                .collect();
+        }
+        completions
+    }
+
+    /// Escape the shell metacharacters in a path candidate and add a trailing slash when
+    /// it names a directory, as the built-in file completion does
+    fn filename_completion(candidate: &str) -> String {
+        let path = match candidate.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                format!("{}{rest}", env::var("HOME").unwrap_or_default())
+            }
+            _ => candidate.to_string(),
+        };
+
+        let mut completion = String::new();
+        for (index, c) in candidate.char_indices() {
+            let tilde = c == '~' && index == 0;
+            if !tilde && " \t\n\"'\\$`&|;()<>!*?[]{}#=".contains(c) {
+                completion.push('\\');
+            }
+            completion.push(c);
+        }
+        if !completion.ends_with('/') && Path::new(&path).is_dir() {
+            completion.push('/');
+        }
+        completion
+    }
+
     /// Complete command names
     pub fn complete_commands(&self, prefix: &str) -> Vec<String> {
         let mut completions = Vec::new();
@@ -151,7 +285,7 @@ impl CompletionSystem {
         let builtins = [
             "cd", "echo", "export", "source", ".", "exit", "alias", "unalias", "true", "false",
             "test", "[", "seq", "kill", "jobs", "bg", "fg", "history", "which", "type", "help",
-            "complete",
+            "complete", "compgen",
         ];
 
         for builtin in &builtins {
@@ -1114,6 +1248,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_filenames_option_marks_directories_and_escapes() {
+        let system = CompletionSystem::new();
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_string_lossy();
+        fs::create_dir(format!("{dir}/sub")).unwrap();
+        fs::write(format!("{dir}/a(1)"), "").unwrap();
+
+        let wordlist = format!("{dir}/sub {dir}/a(1)");
+        let args = ["-o", "filenames", "-W", &wordlist].map(String::from);
+        let (entry, _) = CompletionEntry::from_args(&args).unwrap();
+        let context = CompletionSystem::parse_context("open ", 5);
+
+        assert_eq!(
+            system.generate(&entry, &context),
+            vec![format!("{dir}/sub/"), format!("{dir}/a\\(1\\)")]
+        );
+
+        // Without the option the words are returned as they are
+        let (entry, _) = CompletionEntry::from_args(&args[2..]).unwrap();
+        assert_eq!(
+            system.generate(&entry, &context),
+            vec![format!("{dir}/sub"), format!("{dir}/a(1)")]
+        );
+    }
+
     #[test]
     fn test_call_completion_function() {
         let system = CompletionSystem::new();
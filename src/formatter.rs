@@ -8,7 +8,9 @@
 use crate::lexer::Lexer;
 use crate::parser::Node;
 use crate::parser::Parser;
+use crate::parser::Redirect;
 use crate::parser::RedirectKind;
+use crate::parser::quote_word;
 
 /// Configuration options for the shell script formatter
 #[derive(Debug, Clone)]
@@ -244,52 +246,111 @@ impl Formatter {
         }
     }
 
+    /// Redirections written after a command, each preceded by a space
+    fn format_redirects(&self, redirects: &[Redirect]) -> String {
+        let mut result = String::new();
+        for redirect in redirects {
+            let redirect_op = redirect.kind.operator();
+
+            // The descriptor is only written when it differs from the default
+            let fd = if redirect.fd == redirect.kind.default_fd() {
+                String::new()
+            } else {
+                redirect.fd.to_string()
+            };
+
+            if matches!(
+                redirect.kind,
+                RedirectKind::InputDup | RedirectKind::OutputDup
+            ) {
+                result.push_str(&format!(" {fd}{redirect_op}"));
+            } else if self.config.space_redirects {
+                result.push_str(&format!(" {fd}{redirect_op} "));
+            } else if !fd.is_empty() {
+                result.push_str(&format!(" {fd}{redirect_op}"));
+            } else {
+                result.push_str(&format!(" {redirect_op}"));
+                if !redirect.file.starts_with('&') {
+                    // Don't add space for &2 etc.
+                    result.push(' ');
+                }
+            }
+
+            // Here-string words keep their source form
+            if redirect.kind == RedirectKind::HereString {
+                result.push_str(&redirect.file);
+            } else {
+                result.push_str(&quote_word(&redirect.file));
+            }
+        }
+        result
+    }
+
+    /// Whether a here-document body has to follow the line the redirections are on
+    fn has_heredoc(redirects: &[Redirect]) -> bool {
+        redirects.iter().any(|redirect| {
+            matches!(
+                redirect.kind,
+                RedirectKind::HereDoc | RedirectKind::HereDocDash
+            )
+        })
+    }
+
+    /// Layout shared by the loops: `header; do`, the body, then `done`
+    fn format_loop(&mut self, header: String, body: &Node) -> String {
+        let mut result = self.indent();
+        result.push_str(&header);
+        result.push_str("; do");
+
+        if !self.config.never_split {
+            result.push('\n');
+
+            // Format body with increased indent
+            self.indent_level += 1;
+            result.push_str(&self.format(body));
+            self.indent_level -= 1;
+
+            result.push('\n');
+            result.push_str(&self.indent());
+        } else {
+            result.push(' ');
+            let body_str = self.format(body);
+            result.push_str(body_str.trim_start());
+            result.push_str("; ");
+        }
+
+        result.push_str("done");
+        result
+    }
+
+    /// Loop condition or word list, written after the loop keyword
+    fn format_inline(&mut self, node: &Node) -> String {
+        match node {
+            Node::Array { elements } => elements
+                .iter()
+                .map(|element| quote_word(element))
+                .collect::<Vec<_>>()
+                .join(" "),
+            node => self.format(node).trim_start().to_string(),
+        }
+    }
+
     pub fn format(&mut self, node: &Node) -> String {
         match node {
             Node::Command {
                 name,
                 args,
                 redirects,
-            } => {
+            } if !Self::has_heredoc(redirects) => {
                 let mut result = self.indent();
-                result.push_str(name);
+                result.push_str(&quote_word(name));
 
                 for arg in args {
                     result.push(' ');
-                    // Quote arguments with spaces
-                    if arg.contains(' ') {
-                        result.push('"');
-                        result.push_str(arg);
-                        result.push('"');
-                    } else {
-                        result.push_str(arg);
-                    }
+                    result.push_str(&quote_word(arg));
                 }
 
-                for redirect in redirects {
-                    let redirect_op = match redirect.kind {
-                        RedirectKind::Input => "<",
-                        RedirectKind::Output => ">",
-                        RedirectKind::Append => ">>",
-                        RedirectKind::HereDoc => "<<",
-                        RedirectKind::HereDocDash => "<<-",
-                        RedirectKind::HereString => "<<<",
-                        RedirectKind::InputDup => "<&",
-                        RedirectKind::OutputDup => ">&",
-                    };
-
-                    if self.config.space_redirects {
-                        result.push_str(&format!(" {redirect_op} "));
-                    } else {
-                        result.push_str(&format!(" {redirect_op}"));
-                        if !redirect.file.starts_with('&') {
-                            // Don't add space for &2 etc.
-                            result.push(' ');
-                        }
-                    }
-
-                    result.push_str(&redirect.file);
-                }
+                result.push_str(&self.format_redirects(redirects));
 
                 result
             }
@@ -298,29 +359,33 @@ impl Formatter {
                     return String::new();
                 }
 
-                if self.config.binary_next_line && commands.len() > 1 && !self.config.never_split {
-                    let mut result = String::new();
+                let parts: Vec<String> = commands.iter().map(|cmd| self.format(cmd)).collect();
+
+                // A here-document body ends the line it is written on, so the pipe has
+                // to come before it
+                if parts[..parts.len() - 1]
+                    .iter()
+                    .any(|part| part.ends_with('\n'))
+                {
+                    return self.indent() + &node.to_shell_source();
+                }
 
+                if self.config.binary_next_line && commands.len() > 1 && !self.config.never_split {
                     // First command
-                    result.push_str(&self.format(&commands[0]));
+                    let mut result = parts[0].clone();
 
                     // Remaining commands with pipe at start of next line
-                    for cmd in &commands[1..] {
+                    for part in &parts[1..] {
                         result.push_str(" \\\n");
                         result.push_str(&self.config.indent_str); // Add one level of indentation
                         result.push_str("| ");
 
-                        // Format command and remove its indent since we already added it
-                        let cmd_str = self.format(cmd);
-                        result.push_str(cmd_str.trim_start());
+                        // Remove the command's indent since we already added it
+                        result.push_str(part.trim_start());
                     }
 
                     result
                 } else {
-                    let mut parts = Vec::new();
-                    for cmd in commands {
-                        parts.push(self.format(cmd));
-                    }
                     parts.join(" | ")
                 }
             }
@@ -332,13 +397,28 @@ impl Formatter {
                     return String::new();
                 }
 
+                let parts: Vec<String> = statements
+                    .iter()
+                    .map(|statement| self.format(statement))
+                    .collect();
+
+                // Only a line break can come after a here-document body
+                if parts.iter().zip(operators).any(|(part, operator)| {
+                    part.ends_with('\n') && operator != "\n" && operator != ";"
+                }) {
+                    return self.indent() + &node.to_shell_source();
+                }
+
                 let mut result = String::new();
 
-                for (i, statement) in statements.iter().enumerate() {
+                for (i, part) in parts.iter().enumerate() {
                     if i > 0 {
                         let operator = &operators[i - 1];
 
-                        if operator == "\n" {
+                        if parts[i - 1].ends_with('\n') {
+                            // The body already ended the line
+                            result.push('\n');
+                        } else if operator == "\n" {
                             result.push('\n');
                             result.push('\n');
                         } else if self.config.binary_next_line
@@ -349,6 +429,9 @@ impl Formatter {
                             result.push_str(&self.config.indent_str); // Add one level of indentation
                             result.push_str(operator);
                             result.push(' ');
+                        } else if operator.is_empty() {
+                            // Assignments written one after another
+                            result.push(' ');
                         } else {
                             result.push(' ');
                             result.push_str(operator);
@@ -356,37 +439,27 @@ impl Formatter {
                         }
                     }
 
-                    result.push_str(&self.format(statement));
+                    result.push_str(part);
                 }
 
                 result
             }
-            Node::Assignment { name, value } => {
+            Node::PrefixedCommand {
+                assignments,
+                command,
+            } => {
                 let mut result = self.indent();
-                result.push_str(name);
-                result.push('=');
-
-                match &**value {
-                    Node::StringLiteral(val) => {
-                        // Quote value if it contains spaces
-                        if val.contains(' ') {
-                            result.push('"');
-                            result.push_str(val);
-                            result.push('"');
-                        } else {
-                            result.push_str(val);
-                        }
-                    }
-                    Node::CommandSubstitution { command } => {
-                        result.push_str("$(");
-                        result.push_str(&self.format(command));
-                        result.push(')');
-                    }
-                    _ => {
-                        result.push_str(&self.format(value));
-                    }
+                for assignment in assignments {
+                    result.push_str(self.format(assignment).trim_start());
+                    result.push(' ');
                 }
-
+                result.push_str(self.format(command).trim_start());
+                result
+            }
+            Node::Time { pipeline, posix } => {
+                let mut result = self.indent();
+                result.push_str(if *posix { "time -p " } else { "time " });
+                result.push_str(self.format(pipeline).trim_start());
                 result
             }
             Node::CommandSubstitution { command } => {
@@ -396,25 +469,7 @@ impl Formatter {
                 result.push(')');
                 result
             }
-            Node::StringLiteral(value) => {
-                let mut result = String::new();
-                // Quote if contains spaces
-                if value.contains(' ') {
-                    result.push('"');
-                    result.push_str(value);
-                    result.push('"');
-                } else {
-                    result.push_str(value);
-                }
-                result
-            }
-            Node::SingleQuotedString(value) => {
-                let mut result = String::new();
-                result.push('\'');
-                result.push_str(value);
-                result.push('\'');
-                result
-            }
+            Node::StringLiteral(value) => quote_word(value),
             Node::Subshell { list } => {
                 let mut result = self.indent();
                 result.push('(');
@@ -448,29 +503,6 @@ impl Formatter {
                 result.push_str(comment);
                 result
             }
-            Node::ExtGlobPattern {
-                operator,
-                patterns,
-                suffix,
-            } => {
-                let mut result = self.indent();
-
-                // Format as: ?(pattern1|pattern2)suffix
-                result.push(*operator);
-                result.push('(');
-
-                for (i, pattern) in patterns.iter().enumerate() {
-                    if i > 0 {
-                        result.push('|');
-                    }
-                    result.push_str(pattern);
-                }
-
-                result.push(')');
-                result.push_str(suffix);
-
-                result
-            }
             Node::IfStatement {
                 condition,
                 consequence,
@@ -666,13 +698,15 @@ impl Formatter {
                         self.indent_level += 1;
                         result.push_str(&self.indent());
                         self.indent_level -= 1;
-                        result.push_str(";;");
+                        result.push_str(pattern.terminator.as_str());
                         result.push('\n');
                     } else {
                         result.push(' ');
                         let body_str = self.format(&pattern.body);
                         result.push_str(body_str.trim_start());
-                        result.push_str(" ;; ");
+                        result.push(' ');
+                        result.push_str(pattern.terminator.as_str());
+                        result.push(' ');
                     }
                 }
 
@@ -687,39 +721,30 @@ impl Formatter {
             }
             Node::ForLoop {
                 variable,
-                iterable,
+                iterable: items,
+                body,
+            }
+            | Node::SelectStatement {
+                variable,
+                items,
                 body,
             } => {
-                let mut result = self.indent();
-                result.push_str("for ");
-                result.push_str(variable);
-                result.push_str(" in ");
-
-                // Format iterable
-                let iterable_str = self.format(iterable);
-                result.push_str(iterable_str.trim_start());
-
-                result.push_str("; do");
-
-                if !self.config.never_split {
-                    result.push('\n');
-
-                    // Format body with increased indent
-                    self.indent_level += 1;
-                    result.push_str(&self.format(body));
-                    self.indent_level -= 1;
-
-                    result.push('\n');
-                    result.push_str(&self.indent());
+                let keyword = if matches!(node, Node::ForLoop { .. }) {
+                    "for"
                 } else {
-                    result.push(' ');
-                    let body_str = self.format(body);
-                    result.push_str(body_str.trim_start());
-                    result.push(' ');
-                }
-
-                result.push_str("done");
-                result
+                    "select"
+                };
+                let header = format!("{keyword} {variable} in {}", self.format_inline(items));
+                self.format_loop(header, body)
+            }
+            Node::WhileLoop { condition, body } | Node::UntilLoop { condition, body } => {
+                let keyword = if matches!(node, Node::WhileLoop { .. }) {
+                    "while"
+                } else {
+                    "until"
+                };
+                let header = format!("{keyword} {}", self.format_inline(condition));
+                self.format_loop(header, body)
             }
             Node::Function { name, body } => {
                 let mut result = self.indent();
@@ -755,7 +780,17 @@ impl Formatter {
                 result.push('}');
                 result
             }
-            _ => "".to_string(),
+            Node::Redirected { command, redirects } if !Self::has_heredoc(redirects) => {
+                let mut result = self.format(command);
+                result.push_str(&self.format_redirects(redirects));
+                result
+            }
+            // Everything without a layout of its own is written on one line
+            _ => {
+                let mut result = self.indent();
+                result.push_str(&node.to_shell_source());
+                result
+            }
         }
     }
 }
@@ -1043,12 +1078,12 @@ echo "Done"
         // Command substitution should be preserved
         assert_eq!(output, input, "Should preserve simple command substitution");
 
-        // Complex command substitution should be formatted
+        // Quoting a complex command substitution would stop its output being split
         let input = "echo $(if [ -f file.txt ]; then echo found; else echo not found; fi)";
         let output = formatter.format_str(input);
-        assert_ne!(
+        assert_eq!(
             output, input,
-            "Complex command substitution should be formatted"
+            "Complex command substitution should keep its source form"
         );
     }
 
@@ -1134,6 +1169,8 @@ echo "Done"
             redirects: vec![Redirect {
                 kind: RedirectKind::Output,
                 file: "output.txt".to_string(),
+                fd: 1,
+                quoted: false,
             }],
         };
 
@@ -1151,12 +1188,40 @@ echo "Done"
             redirects: vec![Redirect {
                 kind: RedirectKind::Output,
                 file: "output.txt".to_string(),
+                fd: 1,
+                quoted: false,
             }],
         };
 
         assert_eq!(formatter.format(&node), "cat file.txt > output.txt");
     }
 
+    #[test]
+    fn test_format_command_with_descriptor_redirects() {
+        let mut formatter = Formatter::new();
+
+        let node = Node::Command {
+            name: "make".to_string(),
+            args: vec![],
+            redirects: vec![
+                Redirect {
+                    kind: RedirectKind::Output,
+                    file: "build.log".to_string(),
+                    fd: 1,
+                    quoted: false,
+                },
+                Redirect {
+                    kind: RedirectKind::OutputDup,
+                    file: "1".to_string(),
+                    fd: 2,
+                    quoted: false,
+                },
+            ],
+        };
+
+        assert_eq!(formatter.format(&node), "make > build.log 2>&1");
+    }
+
     #[test]
     fn test_format_pipeline_normal() {
         let mut formatter = Formatter::new();
@@ -1424,4 +1489,58 @@ echo "Done"
 
         assert_eq!(formatter.format(&node), "# This is a comment");
     }
+
+    #[test]
+    fn test_format_while_and_until_loops() {
+        let mut formatter = Formatter::new();
+
+        assert_eq!(
+            formatter.format_str("if a; then echo y; fi; while a || b; do c; done"),
+            "if a; then\n    echo y\nfi ; while a || b; do\n    c\ndone"
+        );
+        assert_eq!(
+            formatter.format_str("until [ -f x ]; do sleep 1; done"),
+            "until [ -f x ]; do\n    sleep 1\ndone"
+        );
+    }
+
+    #[test]
+    fn test_never_split_loops_stay_valid() {
+        let config = FormatterConfig {
+            never_split: true,
+            ..Default::default()
+        };
+        let mut formatter = Formatter::with_config(config);
+
+        assert_eq!(
+            formatter.format_str("for i in 1 \"2 3\"; do echo $i; done"),
+            "for i in 1 \"2 3\"; do echo $i; done"
+        );
+    }
+
+    #[test]
+    fn test_format_nodes_without_a_layout_of_their_own() {
+        let mut formatter = Formatter::new();
+
+        assert_eq!(
+            formatter.format_str("x=(1 \"2 3\");  ! [[ $x == 1 ]] && { a; b; }"),
+            "x=(1 \"2 3\") ; ! [[ $x == 1 ]] && { a; b; }"
+        );
+        assert_eq!(
+            formatter.format_str("echo \"a b\" 'say \"hi\"' it\\'s"),
+            "echo \"a b\" 'say \"hi\"' it\\'s"
+        );
+    }
+
+    #[test]
+    fn test_format_keeps_heredoc_bodies() {
+        let mut formatter = Formatter::new();
+
+        assert_eq!(
+            formatter.format_str(
+                "cat <<END; echo b\nhello $x\nEND\nif a; then cat <<-'X' | wc; fi\n\tbody\nX"
+            ),
+            "cat <<EOF\nhello $x\nEOF\n\necho b\n\nif a; then\n    cat <<-'EOF' | wc\nbody\nEOF\n\nfi"
+        );
+    }
 }
@@ -5,25 +5,32 @@
  * under GNU General Public License v3.0.
  */
 
-use crate::completion::CompletionSystem;
+use crate::arithmetic::{self, ArithmeticContext};
+use crate::completion::{CompletionEntry, CompletionSystem};
 use crate::flash;
 use crate::lexer::Lexer;
 use crate::parser::CasePattern;
+use crate::parser::CaseTerminator;
 use crate::parser::Node;
 use crate::parser::Parser;
 use crate::parser::Redirect;
 use crate::parser::RedirectKind;
 
 use regex::Regex;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::io::{self, BufRead, Read, Write};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
 use std::mem;
-use std::os::unix::fs::PermissionsExt;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::os::unix::io::AsRawFd;
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 // Terminal control functions using libc
 fn tcgetattr(fd: i32) -> Result<libc::termios, io::Error> {
@@ -45,6143 +52,12895 @@ fn tcsetattr(fd: i32, optional_actions: i32, termios: &libc::termios) -> Result<
     }
 }
 
-pub trait Evaluator {
-    fn evaluate(&mut self, node: &Node, interpreter: &mut Interpreter) -> Result<i32, io::Error>;
+/// Index of the `:]` that closes a named character class whose name starts at `start`
+fn find_class_name_end(chars: &[char], start: usize) -> Option<usize> {
+    (start..chars.len().saturating_sub(1)).find(|&i| chars[i] == ':' && chars[i + 1] == ']')
 }
 
-// Default evaluator that implements the standard shell behavior
-pub struct DefaultEvaluator;
+/// Whether `ch` belongs to a POSIX character class such as `alpha` in `[[:alpha:]]`
+fn matches_named_class(ch: char, name: &str) -> bool {
+    match name {
+        "alnum" => ch.is_alphanumeric(),
+        "alpha" => ch.is_alphabetic(),
+        "blank" => ch == ' ' || ch == '\t',
+        "cntrl" => ch.is_control(),
+        "digit" => ch.is_ascii_digit(),
+        "graph" => !ch.is_control() && !ch.is_whitespace(),
+        "lower" => ch.is_lowercase(),
+        "print" => !ch.is_control(),
+        "punct" => ch.is_ascii_punctuation(),
+        "space" => ch.is_whitespace(),
+        "upper" => ch.is_uppercase(),
+        "word" => ch.is_alphanumeric() || ch == '_',
+        "xdigit" => ch.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
 
-impl Evaluator for DefaultEvaluator {
-    fn evaluate(&mut self, node: &Node, interpreter: &mut Interpreter) -> Result<i32, io::Error> {
-        match node {
-            Node::Command {
-                name,
-                args,
-                redirects,
-            } => self.evaluate_command(name, args, redirects, interpreter),
-            Node::Pipeline { commands } => self.evaluate_pipeline(commands, interpreter),
-            Node::List {
-                statements,
-                operators,
-            } => self.evaluate_list(statements, operators, interpreter),
-            Node::Assignment { name, value } => self.evaluate_assignment(name, value, interpreter),
-            Node::CommandSubstitution { command } => {
-                self.evaluate_command_substitution(command, interpreter)
-            }
-            Node::ArithmeticExpansion { expression } => {
-                self.evaluate_arithmetic_expansion(expression, interpreter)
-            }
-            Node::ArithmeticCommand { expression } => {
-                self.evaluate_arithmetic_command(expression, interpreter)
-            }
-            Node::StringLiteral(_value) => Ok(0),
-            Node::SingleQuotedString(_value) => Ok(0),
-            Node::Subshell { list } => interpreter.evaluate_with_evaluator(list, self),
-            Node::Comment(_) => Ok(0),
-            Node::ExtGlobPattern {
-                operator,
-                patterns,
-                suffix,
-            } => self.evaluate_ext_glob(*operator, patterns, suffix, interpreter),
-            Node::Export { name, value } => self.evaluate_export(name, value, interpreter),
-            Node::IfStatement {
-                condition,
-                consequence,
-                alternative,
-            } => self.evaluate_if_statement(condition, consequence, alternative, interpreter),
-            Node::ElifBranch {
-                condition,
-                consequence,
-            } => self.evaluate_elif_branch(condition, consequence, interpreter),
-            Node::ElseBranch { consequence } => self.evaluate_else_branch(consequence, interpreter),
-            Node::CaseStatement {
-                expression,
-                patterns,
-            } => self.evaluate_case_statement(expression, patterns, interpreter),
-            Node::Function { name, body } => {
-                self.evaluate_function_definition(name, body, interpreter)
-            }
-            Node::FunctionCall {
-                name,
-                args,
-                redirects,
-            } => self.evaluate_function_call(name, args, redirects, interpreter),
-            Node::Return { value } => self.evaluate_return(value, interpreter),
-            Node::HistoryExpansion { pattern } => {
-                self.evaluate_history_expansion(pattern, interpreter)
-            }
-            Node::Complete {
-                options: _,
-                command: _,
-            } => {
-                // Complete command is handled silently - just acknowledge it
-                Ok(0)
-            }
-            Node::ForLoop {
-                variable,
-                iterable,
-                body,
-            } => self.evaluate_for_loop(variable, iterable, body, interpreter),
-            Node::WhileLoop { condition, body } => {
-                self.evaluate_while_loop(condition, body, interpreter)
-            }
-            Node::UntilLoop { condition, body } => {
-                self.evaluate_until_loop(condition, body, interpreter)
-            }
-            Node::Array { elements: _ } => Ok(0), // Arrays are used internally, return success
-            Node::Negation { command } => {
-                // Logical negation - invert the exit code
-                let result = interpreter.evaluate_with_evaluator(command, self)?;
-                Ok(if result == 0 { 1 } else { 0 })
-            }
-            Node::SelectStatement {
-                variable,
-                items,
-                body,
-            } => self.evaluate_select_statement(variable, items, body, interpreter),
-            Node::ParameterExpansion { .. } => {
-                // Parameter expansions should be handled during argument expansion
-                // If we reach here, it means it was not expanded properly
-                Ok(0)
-            }
-            _ => Err(io::Error::other("Unsupported node type")),
+/// Home directory of `user` from the password database
+fn user_home_dir(user: &str) -> Option<String> {
+    let user = std::ffi::CString::new(user).ok()?;
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+        let mut entry = std::ptr::null_mut();
+        let status = unsafe {
+            libc::getpwnam_r(
+                user.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut entry,
+            )
+        };
+        // The entry's strings did not fit, so retry with a larger buffer
+        if status == libc::ERANGE && buffer.len() < 1 << 20 {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if status != 0 || entry.is_null() || passwd.pw_dir.is_null() {
+            return None;
         }
+        let home = unsafe { std::ffi::CStr::from_ptr(passwd.pw_dir) };
+        return Some(home.to_string_lossy().into_owned());
     }
 }
 
-impl DefaultEvaluator {
-    fn evaluate_function_definition(
-        &mut self,
-        name: &str,
-        body: &Node,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Store the function definition
-        interpreter
-            .functions
-            .insert(name.to_string(), Box::new(body.clone()));
-        Ok(0)
+/// User and system CPU time (in seconds) used by the shell and its waited-for children
+fn cpu_times() -> (f64, f64) {
+    let mut user = 0.0;
+    let mut sys = 0.0;
+    for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+        let mut usage = unsafe { mem::zeroed::<libc::rusage>() };
+        if unsafe { libc::getrusage(who, &mut usage) } == 0 {
+            user += usage.ru_utime.tv_sec as f64 + usage.ru_utime.tv_usec as f64 / 1_000_000.0;
+            sys += usage.ru_stime.tv_sec as f64 + usage.ru_stime.tv_usec as f64 / 1_000_000.0;
+        }
     }
+    (user, sys)
+}
 
-    fn evaluate_function_call(
-        &mut self,
-        name: &str,
-        args: &[String],
-        redirects: &[Redirect],
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Check if function exists
-        if let Some(body) = interpreter.functions.get(name).cloned() {
-            // Set up function arguments as positional parameters
-            let old_args = interpreter.args.clone();
-            let old_return_value = interpreter.return_value;
-            let mut new_args = vec![name.to_string()]; // $0 is function name
-            new_args.extend(args.iter().cloned());
-            interpreter.args = new_args;
-            interpreter.return_value = None; // Clear any previous return value
+/// The array name of an argument like `a=(`, which starts a whole array the parser
+/// passes to a declaration builtin as separate elements up to `)`
+fn compound_assignment_name(arg: &str) -> Option<&str> {
+    arg.strip_suffix("=(")
+        .filter(|name| is_assignable_name(name))
+}
 
-            // Execute function body
-            let result = interpreter.evaluate_with_evaluator(&body, self);
+/// What a loop does after one iteration of its body
+enum LoopFlow {
+    Next,
+    Break,
+}
 
-            // Check if function returned early
-            let final_result = match result {
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
-                    // Check if this is a return statement
-                    if let Some(msg) = e.to_string().strip_prefix("return:") {
-                        if let Ok(return_code) = msg.parse::<i32>() {
-                            Ok(return_code)
-                        } else {
-                            Ok(0)
-                        }
-                    } else {
-                        result
-                    }
-                }
-                _ => result,
-            };
+/// Where the next pipeline stage reads its stdin from
+enum PipeInput {
+    Inherit,
+    Pipe(ChildStdout),
+    File(fs::File),
+}
 
-            // Restore original arguments and return value
-            interpreter.args = old_args;
-            interpreter.return_value = old_return_value;
+/// The command of a `<(...)` or `>(...)` word, running in a child for as long as the
+/// command that names it
+struct ProcessSubstitution {
+    pid: libc::pid_t,
+    reads: bool,          // The command reads the child's output, as in <(...)
+    end: Option<OwnedFd>, // The shell's end of the pipe, open until the command finishes
+    fifo_dir: Option<tempfile::TempDir>, // Holds the named pipe used without /dev/fd
+}
 
-            final_result
-        } else {
-            // Function not found, treat as regular command
-            self.evaluate_command(name, args, redirects, interpreter)
-        }
+/// The command inside a `<(...)` or `>(...)` word, and whether the shell reads its output
+fn process_substitution_source(word: &str) -> Option<(&str, bool)> {
+    let inner = word.strip_suffix(')')?;
+    match inner.strip_prefix("<(") {
+        Some(source) => Some((source, true)),
+        None => inner.strip_prefix(">(").map(|source| (source, false)),
     }
+}
 
-    fn evaluate_return(
-        &mut self,
-        value: &Option<Box<Node>>,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        let return_code = match value {
-            Some(val) => {
-                // Evaluate the return value
-                match val.as_ref() {
-                    Node::StringLiteral(string_value) => {
-                        let expanded_value = interpreter.expand_variables(string_value);
-                        expanded_value.parse::<i32>().unwrap_or(0)
-                    }
-                    Node::CommandSubstitution { command } => {
-                        // Execute command substitution and use its exit code
-                        interpreter.evaluate_with_evaluator(command, self)?
-                    }
-                    Node::ArithmeticExpansion { expression } => {
-                        let expanded_expr = interpreter.expand_variables(expression);
-                        match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                            Ok(result) => result as i32,
-                            Err(_) => {
-                                eprintln!(
-                                    "arithmetic expansion: invalid expression: {expanded_expr}"
-                                );
-                                0
-                            }
-                        }
-                    }
-                    Node::ArithmeticCommand { expression } => {
-                        let expanded_expr = interpreter.expand_variables(expression);
-                        match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                            Ok(result) => result as i32,
-                            Err(_) => {
-                                eprintln!(
-                                    "arithmetic command: invalid expression: {expanded_expr}"
-                                );
-                                0
-                            }
-                        }
-                    }
-                    _ => {
-                        // For other node types, evaluate them and use the result
-                        interpreter.evaluate_with_evaluator(val, self)?
-                    }
-                }
-            }
-            None => {
-                // No return value specified, use last exit code
-                interpreter.last_exit_code
-            }
+/// Close the shell's end of each process substitution, then wait for their commands
+fn finish_process_substitutions(mut substitutions: Vec<ProcessSubstitution>) {
+    for substitution in &mut substitutions {
+        substitution.end = None;
+    }
+    for substitution in substitutions {
+        let mut status = 0;
+        let Some(dir) = &substitution.fifo_dir else {
+            unsafe { libc::waitpid(substitution.pid, &mut status, 0) };
+            continue;
         };
+        // The child still waits to open the named pipe if the command never did, so
+        // open the command's side briefly until it gets through and exits
+        let fifo = dir.path().join("fifo");
+        while unsafe { libc::waitpid(substitution.pid, &mut status, libc::WNOHANG) } == 0 {
+            let _side = fs::OpenOptions::new()
+                .read(substitution.reads)
+                .write(!substitution.reads)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&fifo);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
 
-        // Set the return value in the interpreter
-        interpreter.return_value = Some(return_code);
+/// Point `target` at `fd`, returning a duplicate of the original for `restore_fd`
+fn redirect_fd(fd: i32, target: i32) -> Result<i32, io::Error> {
+    let saved = unsafe { libc::dup(target) };
+    if saved < 0 || unsafe { libc::dup2(fd, target) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(saved)
+}
 
-        // Return a special error code to indicate early return from function
-        // We'll use a custom error type for this
-        Err(io::Error::new(
-            io::ErrorKind::Interrupted,
-            format!("return:{return_code}"),
-        ))
+/// Undo `redirect_fd`, putting the saved descriptor back on `target`
+fn restore_fd(saved: i32, target: i32) -> Result<(), io::Error> {
+    let result = unsafe { libc::dup2(saved, target) };
+    unsafe { libc::close(saved) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
+}
 
-    fn evaluate_command(
-        &mut self,
-        name: &str,
-        args: &[String],
-        redirects: &[Redirect],
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Expand glob patterns in arguments
-        let expanded_args = interpreter.expand_glob_patterns(args);
+/// What a redirection connects its descriptor to
+enum RedirectTarget {
+    File(fs::File),
+    Fd(i32),
+    Close,
+}
 
-        // Use expanded args for the rest of the function
-        let args = &expanded_args;
+/// Point the shell's descriptor `fd` at `target`, returning a duplicate of the
+/// original (or -1 when `fd` was not open) for `restore_redirects`
+fn apply_redirect(fd: i32, target: &RedirectTarget) -> Result<i32, io::Error> {
+    let saved = unsafe { libc::dup(fd) };
+    let result = match target {
+        RedirectTarget::File(file) => unsafe { libc::dup2(file.as_raw_fd(), fd) },
+        RedirectTarget::Fd(source) => unsafe { libc::dup2(*source, fd) },
+        RedirectTarget::Close => unsafe { libc::close(fd) },
+    };
+    if result < 0 && !matches!(target, RedirectTarget::Close) {
+        let error = io::Error::last_os_error();
+        if saved >= 0 {
+            unsafe { libc::close(saved) };
+        }
+        return Err(error);
+    }
+    Ok(saved)
+}
 
-        // First check if this is a function call
-        if interpreter.functions.contains_key(name) {
-            return self.evaluate_function_call(name, args, redirects, interpreter);
+/// Undo `apply_redirect` calls, most recent first. Every descriptor is put back even
+/// when one fails, and the first failure is returned
+fn restore_redirects(saved: Vec<(i32, i32)>) -> Result<(), io::Error> {
+    let mut result = Ok(());
+    for (fd, saved) in saved.into_iter().rev() {
+        if saved < 0 {
+            unsafe { libc::close(fd) };
+        } else if let Err(e) = restore_fd(saved, fd) {
+            result = result.and(Err(e));
         }
+    }
+    result
+}
 
-        // Check for alias expansion
-        if let Some(alias_value) = interpreter.aliases.get(name) {
-            // Parse the alias value properly handling escaped spaces
-            let mut alias_parts = interpreter.parse_alias_value(alias_value);
-            alias_parts.extend_from_slice(args);
+/// A writer set by the host, shared with the streams redirected to it
+type HostWriter = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
 
-            // Create a new command with the expanded alias
-            if let Some(new_name) = alias_parts.first() {
-                let new_args = &alias_parts[1..];
-                return self.evaluate_command(new_name, new_args, redirects, interpreter);
-            }
-        }
+/// Lock a shared writer. A panic while writing leaves nothing half-updated in it, so a
+/// poisoned lock is used as is
+fn lock<T>(shared: &Mutex<T>) -> MutexGuard<'_, T> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-        // Handle built-in commands
-        match name {
-            "cd" => {
-                let dir = if args.is_empty() {
-                    env::var("HOME").unwrap_or_else(|_| ".".to_string())
-                } else {
-                    // Expand variables and tilde in the directory argument
-                    interpreter.expand_variables(&args[0])
-                };
+/// One of the shell's output streams: builtins write to the host's writer when one is
+/// set, and otherwise to the descriptor. Redirections of the descriptor are followed,
+/// so `>file` writes to the descriptor and `>&2` to wherever stderr writes.
+pub struct OutputStream {
+    fd: i32,
+    writer: HostWriter,
+    redirections: RefCell<Vec<Option<HostWriter>>>, // Redirections in effect, most recent last: the writer duplicated, or None for the descriptor
+}
 
-                match env::set_current_dir(&dir) {
-                    Ok(_) => {
-                        interpreter.variables.insert(
-                            "PWD".to_string(),
-                            env::current_dir()?.to_string_lossy().to_string(),
-                        );
-                        Ok(0)
-                    }
-                    Err(e) => {
-                        eprintln!("cd: {dir}: {e}");
-                        Ok(1)
-                    }
-                }
-            }
-            "echo" => {
-                for (i, arg) in args.iter().enumerate() {
-                    let expanded_arg = interpreter.expand_variables(arg);
-                    print!("{}{}", if i > 0 { " " } else { "" }, expanded_arg);
-                }
-                println!();
-                Ok(0)
-            }
-            "export" => {
-                if args.is_empty() {
-                    // List all exported variables
-                    for (key, value) in &interpreter.variables {
-                        println!("export {key}={value}");
-                    }
-                    return Ok(0);
-                }
+impl OutputStream {
+    fn new(fd: i32) -> Self {
+        Self {
+            fd,
+            writer: Arc::new(Mutex::new(None)),
+            redirections: RefCell::new(Vec::new()),
+        }
+    }
 
-                for arg in args {
-                    if let Some(pos) = arg.find('=') {
-                        let (key, value) = arg.split_at(pos);
-                        let value = &value[1..];
-                        if !key.is_empty() {
-                            interpreter
-                                .variables
-                                .insert(key.to_string(), value.to_string());
-                            unsafe {
-                                env::set_var(key, value);
-                            }
-                        }
-                    } else if let Some(value) = interpreter.variables.get(arg) {
-                        if !arg.is_empty() {
-                            unsafe {
-                                env::set_var(arg, value);
-                            }
-                        }
-                    }
+    /// The same stream for a copy of the interpreter, writing to the same writer
+    fn share(&self) -> Self {
+        Self {
+            fd: self.fd,
+            writer: Arc::clone(&self.writer),
+            redirections: self.redirections.clone(),
+        }
+    }
+
+    /// Send the stream to `writer`, or back to the descriptor with None, returning the
+    /// writer it replaces
+    pub fn set_writer(
+        &self,
+        writer: Option<Box<dyn Write + Send>>,
+    ) -> Option<Box<dyn Write + Send>> {
+        let _ = self.flush_writer();
+        mem::replace(&mut *lock(&self.writer), writer)
+    }
+
+    /// In a forked copy of the shell, write to the descriptor from now on. The copy of
+    /// the host's writer is forgotten rather than dropped, so output the parent has
+    /// buffered in it is not written twice
+    fn detach(&self) {
+        mem::forget(lock(&self.writer).take());
+    }
+
+    /// The host's writer that writes currently go to, None for the descriptor
+    fn target(&self) -> Option<HostWriter> {
+        let writer = match self.redirections.borrow().last() {
+            Some(redirection) => redirection.clone()?,
+            None => Arc::clone(&self.writer),
+        };
+        let is_set = lock(&writer).is_some();
+        is_set.then_some(writer)
+    }
+
+    /// Whether writes go to one of the host's writers rather than the descriptor
+    fn is_host(&self) -> bool {
+        self.target().is_some()
+    }
+
+    /// Follow a redirection of the descriptor, to `writer` or to the descriptor itself
+    fn redirect(&self, writer: Option<HostWriter>) {
+        self.redirections.borrow_mut().push(writer);
+    }
+
+    /// Undo the most recent `redirect`
+    fn restore(&self) {
+        self.redirections.borrow_mut().pop();
+    }
+
+    fn flush_writer(&self) -> io::Result<()> {
+        match lock(&self.writer).as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for &OutputStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(target) = self.target() {
+            if let Some(writer) = lock(&target).as_mut() {
+                return writer.write(buf);
+            }
+        }
+        match self.fd {
+            1 => io::stdout().write(buf),
+            _ => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_writer()?;
+        match self.fd {
+            1 => io::stdout().flush(),
+            _ => io::stderr().flush(),
+        }
+    }
+}
+
+/// A writer that keeps what is written for `Interpreter::execute_capture`
+#[derive(Clone, Default)]
+struct CaptureBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl CaptureBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&lock(&self.0)).into_owned()
+    }
+}
+
+impl Write for CaptureBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        lock(&self.0).extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Pipes that carry external commands' stdout and stderr to the host's writers, for
+/// whichever of the shell's streams currently goes to one
+struct HostPipes {
+    stdout: Option<(io::PipeReader, io::PipeWriter)>,
+    stderr: Option<(io::PipeReader, io::PipeWriter)>,
+}
+
+impl HostPipes {
+    fn new(interpreter: &Interpreter) -> io::Result<Self> {
+        let pipe = |stream: &OutputStream| stream.is_host().then(io::pipe).transpose();
+        Ok(Self {
+            stdout: pipe(&interpreter.stdout)?,
+            stderr: pipe(&interpreter.stderr)?,
+        })
+    }
+
+    /// Connect `command`'s stderr, and its stdout too when `stdout` is set
+    fn connect(&self, command: &mut Command, stdout: bool) -> io::Result<()> {
+        if let Some((_, writer)) = self.stdout.as_ref().filter(|_| stdout) {
+            command.stdout(writer.try_clone()?);
+        }
+        if let Some((_, writer)) = &self.stderr {
+            command.stderr(writer.try_clone()?);
+        }
+        Ok(())
+    }
+
+    /// The write end of the stderr pipe, for a forked copy of the shell to put on fd 2
+    fn stderr_fd(&self) -> Option<i32> {
+        self.stderr.as_ref().map(|(_, writer)| writer.as_raw_fd())
+    }
+
+    /// Copy what the commands write to the interpreter's streams as it arrives, until
+    /// every command has closed its end
+    fn forward(self, interpreter: &Interpreter) -> io::Result<()> {
+        self.forward_collecting(None, interpreter).map(drop)
+    }
+
+    /// Forward like `forward` while also reading `output` to its end, returning what it
+    /// carried. Reading them together keeps a command that fills one pipe from waiting
+    /// on the other
+    fn forward_collecting(
+        self,
+        output: Option<fs::File>,
+        interpreter: &Interpreter,
+    ) -> io::Result<Vec<u8>> {
+        // Each reader goes to one of the streams, or into the collected output with None
+        let mut open: Vec<(fs::File, Option<&OutputStream>)> = Vec::new();
+        open.extend(output.map(|output| (output, None)));
+        // Only the commands keep the write ends, so each pipe ends when they exit
+        if let Some((reader, writer)) = self.stdout {
+            drop(writer);
+            open.push((OwnedFd::from(reader).into(), Some(&interpreter.stdout)));
+        }
+        if let Some((reader, writer)) = self.stderr {
+            drop(writer);
+            open.push((OwnedFd::from(reader).into(), Some(&interpreter.stderr)));
+        }
+        let mut collected = Vec::new();
+
+        let mut buffer = [0u8; 8192];
+        while !open.is_empty() {
+            let mut fds: Vec<libc::pollfd> = open
+                .iter()
+                .map(|(reader, _)| libc::pollfd {
+                    fd: reader.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
                 }
-                Ok(0)
+                return Err(error);
             }
-            "source" | "." | "\\." => {
-                if args.is_empty() {
-                    eprintln!("source: filename argument required");
-                    return Ok(1);
+            for index in (0..open.len()).rev() {
+                if fds[index].revents == 0 {
+                    continue;
                 }
-
-                let filename = &args[0];
-                match fs::read_to_string(filename) {
-                    Ok(content) => interpreter.execute(&content),
-                    Err(e) => {
-                        eprintln!("source: {filename}: {e}");
-                        Ok(1)
+                let (reader, stream) = {
+                    let (reader, stream) = &mut open[index];
+                    (reader, *stream)
+                };
+                match reader.read(&mut buffer) {
+                    Ok(0) => {
+                        open.remove(index);
                     }
+                    Ok(n) => match stream {
+                        Some(mut stream) => stream.write_all(&buffer[..n])?,
+                        None => collected.extend_from_slice(&buffer[..n]),
+                    },
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
                 }
             }
-            "[" | "test" => {
-                // Built-in test command
-                self.evaluate_test_command(args, interpreter)
+        }
+        (&interpreter.stdout).flush()?;
+        (&interpreter.stderr).flush()?;
+        Ok(collected)
+    }
+}
+
+/// Read up to and including the next `delimiter` from `fd`, one byte at a time so
+/// nothing past it is consumed; None at end of input
+fn read_delimited(fd: i32, delimiter: u8) -> Result<Option<Vec<u8>>, io::Error> {
+    let mut line = Vec::new();
+    let mut byte = 0u8;
+    loop {
+        let n = unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) };
+        if n < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
             }
-            "exit" => {
-                // Built-in exit command
-                let exit_code = if args.is_empty() {
-                    0
-                } else {
-                    args[0].parse::<i32>().unwrap_or(0)
-                };
-                std::process::exit(exit_code);
+            return Err(error);
+        }
+        if n == 0 {
+            return Ok((!line.is_empty()).then_some(line));
+        }
+        line.push(byte);
+        if byte == delimiter {
+            return Ok(Some(line));
+        }
+    }
+}
+
+/// Signals `kill` accepts by name, without the `SIG` prefix
+const SIGNAL_NAMES: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    #[cfg(target_os = "macos")]
+    ("EMT", libc::SIGEMT),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("BUS", libc::SIGBUS),
+    ("SEGV", libc::SIGSEGV),
+    ("SYS", libc::SIGSYS),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("URG", libc::SIGURG),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("CONT", libc::SIGCONT),
+    ("CHLD", libc::SIGCHLD),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("IO", libc::SIGIO),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    #[cfg(target_os = "macos")]
+    ("INFO", libc::SIGINFO),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    #[cfg(target_os = "linux")]
+    ("STKFLT", libc::SIGSTKFLT),
+    #[cfg(target_os = "linux")]
+    ("PWR", libc::SIGPWR),
+];
+
+/// Parse a signal given by number, name (`TERM`) or prefixed name (`SIGTERM`)
+fn signal_number(spec: &str) -> Option<i32> {
+    if let Ok(number) = spec.parse::<i32>() {
+        return Some(number);
+    }
+    let upper = spec.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNAL_NAMES
+        .iter()
+        .find(|(signal, _)| *signal == name)
+        .map(|(_, number)| *number)
+}
+
+/// Signals that arrived while a trap was set for them, one bit per signal number
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+/// Handler for trapped signals. The trap itself runs once the current command finishes
+extern "C" fn record_signal(signal: libc::c_int) {
+    PENDING_SIGNALS.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Name of a signal number, also accepting an exit status of 128+N
+fn signal_name(number: i32) -> Option<&'static str> {
+    let number = if number > 128 { number - 128 } else { number };
+    SIGNAL_NAMES
+        .iter()
+        .find(|(_, signal)| *signal == number)
+        .map(|(name, _)| *name)
+}
+
+/// Print the signals in the numbered columns of `kill -l` and `trap -l`
+fn print_signal_list(mut out: impl Write) -> io::Result<()> {
+    let mut signals = SIGNAL_NAMES.to_vec();
+    signals.sort_by_key(|(_, number)| *number);
+    for (index, (name, number)) in signals.iter().enumerate() {
+        let separator = if index % 5 == 4 || index + 1 == signals.len() {
+            '\n'
+        } else {
+            '\t'
+        };
+        write!(out, "{number:2}) SIG{name}{separator}")?;
+    }
+    Ok(())
+}
+
+/// Exit code of a child from its `waitpid` status, using 128+N for signals
+fn wait_status_code(status: i32) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        0
+    }
+}
+
+/// Exit code of a finished child process, using 128+N when a signal killed it
+fn exit_status_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+/// Whether `pattern` holds an extended glob group such as `@(a|b)` or `!(*.bak)`
+fn has_ext_glob(pattern: &str) -> bool {
+    ["?(", "*(", "+(", "@(", "!("]
+        .iter()
+        .any(|group| pattern.contains(group))
+}
+
+/// The alternatives of the extended glob group at the start of `pattern`, with the
+/// index just past its closing parenthesis
+fn ext_glob_group(pattern: &[char]) -> Option<(Vec<Vec<char>>, usize)> {
+    if !matches!(pattern.first(), Some('?' | '*' | '+' | '@' | '!')) || pattern.get(1) != Some(&'(')
+    {
+        return None;
+    }
+    let mut alternatives = vec![Vec::new()];
+    let mut depth = 0;
+    for (i, &c) in pattern.iter().enumerate().skip(2) {
+        match c {
+            ')' if depth == 0 => return Some((alternatives, i + 1)),
+            '|' if depth == 0 => {
+                alternatives.push(Vec::new());
+                continue;
             }
-            "true" => {
-                // Built-in true command
-                Ok(0)
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        alternatives.last_mut()?.push(c);
+    }
+    None
+}
+
+/// Short command text shown for a background job
+fn job_command_text(node: &Node) -> String {
+    match node {
+        Node::Command { name, args, .. } => {
+            let mut words = vec![name.clone()];
+            words.extend(args.iter().cloned());
+            words.join(" ")
+        }
+        Node::Pipeline { commands } => commands
+            .iter()
+            .map(job_command_text)
+            .collect::<Vec<_>>()
+            .join(" | "),
+        Node::PrefixedCommand { command, .. } => job_command_text(command),
+        _ => "...".to_string(),
+    }
+}
+
+/// Completion notice printed for a reaped background job
+fn job_notice(job: &Job, code: i32) -> String {
+    if code == 0 {
+        format!("[{}]+ Done {}", job.id, job.command)
+    } else {
+        format!("[{}]+ Exit {} {}", job.id, code, job.command)
+    }
+}
+
+/// Whether `name` is a variable name, optionally with an array subscript (`arr[0]`)
+fn is_assignable_name(name: &str) -> bool {
+    let base = match name.split_once('[') {
+        Some((base, subscript)) => {
+            if !subscript.ends_with(']') || subscript.len() < 2 {
+                return false;
+            }
+            base
+        }
+        None => name,
+    };
+    let mut chars = base.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Quote a word the way `set -x` shows it: as it is when no character in it is
+/// special to the shell, otherwise in single quotes
+fn trace_quote(word: &str) -> String {
+    let special = |c: char| {
+        c.is_whitespace()
+            || matches!(
+                c,
+                '\'' | '"'
+                    | '\\'
+                    | '$'
+                    | '`'
+                    | '|'
+                    | '&'
+                    | ';'
+                    | '<'
+                    | '>'
+                    | '('
+                    | ')'
+                    | '*'
+                    | '?'
+                    | '['
+                    | ']'
+                    | '{'
+                    | '}'
+                    | '~'
+                    | '#'
+                    | '!'
+            )
+    };
+    if !word.is_empty() && !word.contains(special) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+/// Quote `value` in double quotes so the shell reads it back unchanged
+fn double_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Expand a `printf` format, reusing it until every argument has been consumed.
+/// Returns the formatted text and the exit status (1 if an argument was not a number),
+/// reporting bad directives and numbers to `errors`.
+fn format_printf(format: &str, args: &[String], mut errors: impl Write) -> (Vec<u8>, i32) {
+    let mut output = Vec::new();
+    let mut status = 0;
+    let mut remaining = args;
+    loop {
+        let consumed = format_printf_once(format, remaining, &mut output, &mut status, &mut errors);
+        remaining = &remaining[consumed..];
+        if consumed == 0 || remaining.is_empty() {
+            break;
+        }
+    }
+    (output, status)
+}
+
+/// Expand `format` once, returning how many arguments it consumed
+fn format_printf_once(
+    format: &str,
+    args: &[String],
+    output: &mut Vec<u8>,
+    status: &mut i32,
+    errors: &mut dyn Write,
+) -> usize {
+    let chars: Vec<char> = format.chars().collect();
+    let mut consumed = 0;
+    let next_arg = |consumed: &mut usize| -> String {
+        let arg = args.get(*consumed).cloned().unwrap_or_default();
+        *consumed = (*consumed + 1).min(args.len());
+        arg
+    };
+
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                i += 1;
+                i += push_printf_escape(&chars[i..], output);
             }
-            "false" => {
-                // Built-in false command
-                Ok(1)
+            '%' if chars.get(i + 1) == Some(&'%') => {
+                output.push(b'%');
+                i += 2;
             }
-            "seq" => {
-                // Built-in seq command - generate sequence of numbers
-                match args.len() {
-                    1 => {
-                        // seq LAST - from 1 to LAST
-                        if let Ok(last) = args[0].parse::<i32>() {
-                            for i in 1..=last {
-                                println!("{i}");
-                            }
-                            Ok(0)
-                        } else {
-                            eprintln!("seq: invalid number: {}", args[0]);
-                            Ok(1)
+            '%' => {
+                let start = i;
+                i += 1;
+
+                let mut spec = PrintfSpec::default();
+                while let Some(&flag) = chars.get(i) {
+                    match flag {
+                        '-' => spec.left = true,
+                        '0' => spec.zero = true,
+                        '+' => spec.plus = true,
+                        ' ' => spec.space = true,
+                        '#' => spec.alternate = true,
+                        _ => break,
+                    }
+                    i += 1;
+                }
+
+                if chars.get(i) == Some(&'*') {
+                    let width = next_arg(&mut consumed).parse::<i64>().unwrap_or(0);
+                    spec.left |= width < 0;
+                    spec.width = width.unsigned_abs() as usize;
+                    i += 1;
+                } else {
+                    while let Some(digit) = chars.get(i).and_then(|c| c.to_digit(10)) {
+                        spec.width = spec.width * 10 + digit as usize;
+                        i += 1;
+                    }
+                }
+
+                if chars.get(i) == Some(&'.') {
+                    i += 1;
+                    let mut precision = 0;
+                    if chars.get(i) == Some(&'*') {
+                        precision = next_arg(&mut consumed).parse::<usize>().unwrap_or(0);
+                        i += 1;
+                    } else {
+                        while let Some(digit) = chars.get(i).and_then(|c| c.to_digit(10)) {
+                            precision = precision * 10 + digit as usize;
+                            i += 1;
                         }
                     }
-                    2 => {
-                        // seq FIRST LAST
-                        if let (Ok(first), Ok(last)) =
-                            (args[0].parse::<i32>(), args[1].parse::<i32>())
-                        {
-                            if first <= last {
-                                for i in first..=last {
-                                    println!("{i}");
-                                }
-                            } else {
-                                for i in (last..=first).rev() {
-                                    println!("{i}");
-                                }
+                    spec.precision = Some(precision);
+                }
+
+                let Some(&conversion) = chars.get(i) else {
+                    let directive: String = chars[start..].iter().collect();
+                    let _ = writeln!(errors, "printf: `{directive}': missing format character");
+                    *status = 1;
+                    break;
+                };
+                i += 1;
+
+                match conversion {
+                    'd' | 'i' => {
+                        let value = parse_printf_int(&next_arg(&mut consumed), status, errors);
+                        let sign = if value < 0 { "-" } else { spec.sign() };
+                        let number = spec.pad_number(sign, &value.unsigned_abs().to_string());
+                        output.extend_from_slice(number.as_bytes());
+                    }
+                    'u' | 'x' | 'X' | 'o' => {
+                        let value =
+                            parse_printf_int(&next_arg(&mut consumed), status, errors) as u64;
+                        let (prefix, digits) = match conversion {
+                            'x' => ("0x", format!("{value:x}")),
+                            'X' => ("0X", format!("{value:X}")),
+                            'o' => ("", format!("{value:o}")),
+                            _ => ("", value.to_string()),
+                        };
+                        // The alternate form prefixes hex with 0x and makes octal start with 0
+                        let (prefix, digits) = match conversion {
+                            'x' | 'X' if spec.alternate && value != 0 => (prefix, digits),
+                            'o' if spec.alternate && !digits.starts_with('0') => {
+                                ("", format!("0{digits}"))
                             }
-                            Ok(0)
-                        } else {
-                            eprintln!("seq: invalid number arguments");
-                            Ok(1)
-                        }
+                            _ => ("", digits),
+                        };
+                        output.extend_from_slice(spec.pad_number(prefix, &digits).as_bytes());
                     }
-                    3 => {
-                        // seq FIRST INCREMENT LAST
-                        if let (Ok(first), Ok(increment), Ok(last)) = (
-                            args[0].parse::<i32>(),
-                            args[1].parse::<i32>(),
-                            args[2].parse::<i32>(),
-                        ) {
-                            if increment == 0 {
-                                eprintln!("seq: increment cannot be zero");
-                                Ok(1)
-                            } else if increment > 0 && first <= last {
-                                let mut i = first;
-                                while i <= last {
-                                    println!("{i}");
-                                    i += increment;
-                                }
-                                Ok(0)
-                            } else if increment < 0 && first >= last {
-                                let mut i = first;
-                                while i >= last {
-                                    println!("{i}");
-                                    i += increment;
-                                }
-                                Ok(0)
-                            } else {
-                                // No output for invalid range
-                                Ok(0)
+                    'f' | 'F' | 'e' | 'E' => {
+                        let arg = next_arg(&mut consumed);
+                        let value = if arg.is_empty() {
+                            0.0
+                        } else {
+                            arg.trim().parse::<f64>().unwrap_or_else(|_| {
+                                let _ = writeln!(errors, "printf: {arg}: invalid number");
+                                *status = 1;
+                                0.0
+                            })
+                        };
+                        let precision = spec.precision.take().unwrap_or(6);
+                        let digits = match conversion {
+                            'e' | 'E' => {
+                                let formatted = format!("{:.*e}", precision, value.abs());
+                                let (mantissa, exponent) =
+                                    formatted.split_once('e').unwrap_or((&formatted, "0"));
+                                let exponent = exponent.parse::<i32>().unwrap_or(0);
+                                let sign = if exponent < 0 { '-' } else { '+' };
+                                let e = if conversion == 'E' { 'E' } else { 'e' };
+                                format!("{mantissa}{e}{sign}{:02}", exponent.abs())
                             }
+                            _ => format!("{:.*}", precision, value.abs()),
+                        };
+                        let sign = if value.is_sign_negative() && value != 0.0 {
+                            "-"
                         } else {
-                            eprintln!("seq: invalid number arguments");
-                            Ok(1)
+                            spec.sign()
+                        };
+                        output.extend_from_slice(spec.pad_number(sign, &digits).as_bytes());
+                    }
+                    'c' => {
+                        let arg = next_arg(&mut consumed);
+                        let text: String = arg.chars().take(1).collect();
+                        output.extend_from_slice(spec.pad(&text).as_bytes());
+                    }
+                    's' => {
+                        let mut text = next_arg(&mut consumed);
+                        if let Some(precision) = spec.precision {
+                            text = text.chars().take(precision).collect();
+                        }
+                        output.extend_from_slice(spec.pad(&text).as_bytes());
+                    }
+                    'b' => {
+                        // %b expands escapes in its argument the way `echo -e` does
+                        let (mut bytes, _) = expand_echo_escapes(&next_arg(&mut consumed));
+                        if let Some(precision) = spec.precision {
+                            bytes.truncate(precision);
                         }
+                        output.extend(spec.pad_bytes(bytes));
                     }
                     _ => {
-                        eprintln!("seq: wrong number of arguments");
-                        Ok(1)
+                        let directive: String = chars[start..i].iter().collect();
+                        let _ = writeln!(errors, "printf: `{directive}': invalid format character");
+                        *status = 1;
+                        break;
                     }
                 }
             }
-            "alias" => {
-                if args.is_empty() {
-                    // List all aliases
-                    for (name, value) in &interpreter.aliases {
-                        println!("alias {name}='{value}'");
-                    }
-                    Ok(0)
-                } else {
-                    // Join all arguments back together to handle cases like: alias ll="ls -la"
-                    let full_arg = args.join(" ");
-                    if let Some(eq_pos) = full_arg.find('=') {
-                        // Define alias: alias name=value
-                        let name = full_arg[..eq_pos].trim().to_string();
-                        let mut value = full_arg[eq_pos + 1..].to_string();
+            c => {
+                push_char(output, c);
+                i += 1;
+            }
+        }
+    }
 
-                        // Remove surrounding quotes if present and escape spaces
-                        if (value.starts_with('"') && value.ends_with('"'))
-                            || (value.starts_with('\'') && value.ends_with('\''))
-                        {
-                            value = value[1..value.len() - 1].to_string();
-                        }
+    consumed
+}
 
-                        // Trim any leading/trailing whitespace
-                        let value = value.trim().to_string();
+/// Append the UTF-8 encoding of `c`
+fn push_char(output: &mut Vec<u8>, c: char) {
+    output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
 
-                        interpreter.aliases.insert(name, value);
-                        Ok(0)
-                    } else if args.len() == 1 {
-                        // Show specific alias
-                        let name = &args[0];
-                        if let Some(value) = interpreter.aliases.get(name) {
-                            println!("alias {name}='{value}'");
-                            Ok(0)
-                        } else {
-                            eprintln!("alias: {name}: not found");
-                            Ok(1)
-                        }
-                    } else {
-                        eprintln!("alias: usage: alias [name[=value] ...]");
-                        Ok(1)
-                    }
-                }
-            }
-            "unalias" => {
-                if args.is_empty() {
-                    eprintln!("unalias: usage: unalias name [name ...]");
-                    Ok(1)
-                } else {
-                    let mut success = true;
-                    for name in args {
-                        if interpreter.aliases.remove(name).is_none() {
-                            eprintln!("unalias: {name}: not found");
-                            success = false;
-                        }
-                    }
-                    Ok(if success { 0 } else { 1 })
-                }
-            }
-            "complete" => {
-                // Built-in complete command for testing completion
-                if args.is_empty() {
-                    eprintln!("Usage: complete <command>");
-                    return Ok(1);
-                }
+/// Flags, width and precision of a single `printf` conversion
+#[derive(Default)]
+struct PrintfSpec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    width: usize,
+    precision: Option<usize>,
+}
 
-                let test_line = format!("{} ", args.join(" "));
-                let context = CompletionSystem::parse_context(&test_line, test_line.len());
-                let completions = interpreter.completion_system.complete(&context);
+impl PrintfSpec {
+    /// Sign shown in front of a non-negative number
+    fn sign(&self) -> &'static str {
+        if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
 
-                for completion in completions {
-                    println!("{completion}");
-                }
-                Ok(0)
+    /// Pad `text` with spaces to the field width
+    fn pad(&self, text: &str) -> String {
+        let fill = " ".repeat(self.width.saturating_sub(text.chars().count()));
+        if self.left {
+            format!("{text}{fill}")
+        } else {
+            format!("{fill}{text}")
+        }
+    }
+
+    /// Pad raw bytes with spaces to the field width
+    fn pad_bytes(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let len = String::from_utf8_lossy(&bytes).chars().count();
+        let fill = vec![b' '; self.width.saturating_sub(len)];
+        if self.left {
+            [bytes, fill].concat()
+        } else {
+            [fill, bytes].concat()
+        }
+    }
+
+    /// Pad a number behind its sign or base prefix, honouring the precision as a
+    /// minimum digit count and the `0` flag
+    fn pad_number(&self, sign: &str, digits: &str) -> String {
+        let mut digits = digits.to_string();
+        if let Some(precision) = self.precision {
+            if digits.len() < precision {
+                digits = format!("{}{digits}", "0".repeat(precision - digits.len()));
             }
-            _ => {
-                // External command
-                let mut command = Command::new(name);
-                command.args(args);
+        }
 
-                // Handle redirections
-                for redirect in redirects {
-                    match redirect.kind {
-                        RedirectKind::Input => {
-                            let file = fs::File::open(&redirect.file)?;
-                            command.stdin(Stdio::from(file));
-                        }
-                        RedirectKind::Output => {
-                            let file = fs::File::create(&redirect.file)?;
-                            command.stdout(Stdio::from(file));
-                        }
-                        RedirectKind::Append => {
-                            let file = fs::OpenOptions::new()
-                                .create(true)
-                                .append(true)
-                                .open(&redirect.file)?;
-                            command.stdout(Stdio::from(file));
-                        }
-                        RedirectKind::HereDoc | RedirectKind::HereDocDash => {
-                            // Here documents - create a temporary file with the content
-                            let temp_content = &redirect.file;
-                            let mut temp_file = tempfile::NamedTempFile::new()?;
-                            temp_file.write_all(temp_content.as_bytes())?;
-                            temp_file.flush()?;
-                            let file = temp_file.reopen()?;
-                            command.stdin(Stdio::from(file));
-                        }
-                        RedirectKind::HereString => {
-                            // Here strings - pass the string as stdin
-                            let content = interpreter.expand_variables(&redirect.file);
-                            let mut temp_file = tempfile::NamedTempFile::new()?;
-                            temp_file.write_all(content.as_bytes())?;
-                            temp_file.write_all(
-                                b"
-",
-                            )?;
-                            temp_file.flush()?;
-                            let file = temp_file.reopen()?;
-                            command.stdin(Stdio::from(file));
-                        }
-                        RedirectKind::InputDup | RedirectKind::OutputDup => {
-                            // File descriptor duplication - simplified implementation
-                            eprintln!("File descriptor duplication not fully implemented");
-                        }
-                    }
-                }
+        let len = sign.len() + digits.len();
+        if self.zero && !self.left && self.precision.is_none() && len < self.width {
+            format!("{sign}{}{digits}", "0".repeat(self.width - len))
+        } else {
+            self.pad(&format!("{sign}{digits}"))
+        }
+    }
+}
 
-                // Set environment variables
-                for (key, value) in &interpreter.variables {
-                    command.env(key, value);
-                }
+/// Parse a `printf` integer argument: decimal, `0x` hex, leading-zero octal or `'c`
+fn parse_printf_int(arg: &str, status: &mut i32, errors: &mut dyn Write) -> i64 {
+    let trimmed = arg.trim();
+    if trimmed.is_empty() {
+        return 0;
+    }
+    if let Some(rest) = trimmed
+        .strip_prefix('\'')
+        .or_else(|| trimmed.strip_prefix('"'))
+    {
+        return rest.chars().next().map_or(0, |c| c as i64);
+    }
+
+    let (negative, digits) = match trimmed.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+    };
+    let parsed = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse::<i64>()
+    };
 
-                match command.status() {
-                    Ok(status) => {
-                        // Update PWD in case the external command changed the working directory
-                        if let Ok(current_dir) = env::current_dir() {
-                            interpreter.variables.insert(
-                                "PWD".to_string(),
-                                current_dir.to_string_lossy().to_string(),
-                            );
-                        }
-                        Ok(status.code().unwrap_or(0))
-                    }
-                    Err(_) => {
-                        eprintln!("{name}: command not found");
-                        Ok(127)
-                    }
-                }
-            }
+    match parsed {
+        Ok(value) if negative => -value,
+        Ok(value) => value,
+        Err(_) => {
+            let _ = writeln!(errors, "printf: {arg}: invalid number");
+            *status = 1;
+            0
         }
     }
+}
 
-    fn evaluate_export(
-        &mut self,
-        name: &str,
-        value: &Option<Box<Node>>,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        match value {
-            Some(val) => {
-                // Export with assignment: export VAR=value
-                match val.as_ref() {
-                    Node::StringLiteral(string_value) => {
-                        let expanded_value = interpreter.expand_variables(string_value);
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), expanded_value.clone());
-                        if !name.is_empty() {
-                            unsafe {
-                                env::set_var(name, &expanded_value);
-                            }
-                        }
-                    }
-                    Node::SingleQuotedString(string_value) => {
-                        // Single-quoted strings should not have variable expansion
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), string_value.clone());
-                        if !name.is_empty() {
-                            unsafe {
-                                env::set_var(name, string_value);
-                            }
-                        }
-                    }
-                    Node::CommandSubstitution { command } => {
-                        let output = interpreter.capture_command_output(command, self)?;
-                        let trimmed_output = output.trim_end().to_string();
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), trimmed_output.clone());
-                        if !name.is_empty() {
-                            unsafe {
-                                env::set_var(name, &trimmed_output);
-                            }
-                        }
-                    }
-                    Node::ArithmeticExpansion { expression } => {
-                        let expanded_expr = interpreter.expand_variables(expression);
-                        match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                            Ok(result) => {
-                                let result_str = result.to_string();
-                                interpreter
-                                    .variables
-                                    .insert(name.to_string(), result_str.clone());
-                                if !name.is_empty() {
-                                    unsafe {
-                                        env::set_var(name, &result_str);
-                                    }
-                                }
-                            }
-                            Err(_) => {
-                                eprintln!(
-                                    "arithmetic expansion: invalid expression: {expanded_expr}"
-                                );
-                                interpreter
-                                    .variables
-                                    .insert(name.to_string(), "0".to_string());
-                                if !name.is_empty() {
-                                    unsafe {
-                                        env::set_var(name, "0");
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Node::ArithmeticCommand { expression } => {
-                        let expanded_expr = interpreter.expand_variables(expression);
-                        match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                            Ok(result) => {
-                                let result_str = result.to_string();
-                                interpreter
-                                    .variables
-                                    .insert(name.to_string(), result_str.clone());
-                                if !name.is_empty() {
-                                    unsafe {
-                                        env::set_var(name, &result_str);
-                                    }
-                                }
-                            }
-                            Err(_) => {
-                                eprintln!(
-                                    "arithmetic command: invalid expression: {expanded_expr}"
-                                );
-                                interpreter
-                                    .variables
-                                    .insert(name.to_string(), "0".to_string());
-                                if !name.is_empty() {
-                                    unsafe {
-                                        env::set_var(name, "0");
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Node::Array { elements } => {
-                        // Handle array export - join elements with spaces or use a specific format
-                        let array_value = elements.join(" ");
-                        let expanded_value = interpreter.expand_variables(&array_value);
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), expanded_value.clone());
-                        if !name.is_empty() {
-                            unsafe {
-                                env::set_var(name, &expanded_value);
-                            }
-                        }
-                    }
-                    _ => {
-                        return Err(io::Error::other(
-                            "Unsupported value type for export assignment",
-                        ));
-                    }
-                }
+/// Append the byte for the escape sequence following a backslash,
+/// returning how many characters of `chars` it used
+fn push_printf_escape(chars: &[char], output: &mut Vec<u8>) -> usize {
+    let Some(&c) = chars.first() else {
+        output.push(b'\\');
+        return 0;
+    };
+    let escaped = match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'a' => '\x07',
+        'b' => '\x08',
+        'f' => '\x0c',
+        'v' => '\x0b',
+        'e' | 'E' => '\x1b',
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        '0'..='7' => {
+            // Up to three octal digits, written as a single raw byte
+            let digits: String = chars.iter().take(3).take_while(|d| d.is_digit(8)).collect();
+            let value = u32::from_str_radix(&digits, 8).unwrap_or(0);
+            output.push(value as u8);
+            return digits.len();
+        }
+        'x' => {
+            // Up to two hex digits
+            let digits: String = chars[1..]
+                .iter()
+                .take(2)
+                .take_while(|d| d.is_ascii_hexdigit())
+                .collect();
+            if digits.is_empty() {
+                output.extend_from_slice(b"\\x");
+            } else {
+                output.push(u8::from_str_radix(&digits, 16).unwrap_or(0));
             }
-            None => {
-                // Export without assignment: export VAR
-                // Export existing variable if it exists in the interpreter's variables
-                if let Some(existing_value) = interpreter.variables.get(name) {
-                    if !name.is_empty() {
-                        unsafe {
-                            env::set_var(name, existing_value);
-                        }
-                    }
-                } else {
-                    // If variable doesn't exist in interpreter, check if it exists in environment
-                    if let Ok(env_value) = env::var(name) {
-                        // Store it in interpreter variables for consistency
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), env_value.clone());
-                        if !name.is_empty() {
-                            unsafe {
-                                env::set_var(name, &env_value);
-                            }
-                        }
-                    } else {
-                        // Variable doesn't exist anywhere, just add it to interpreter variables
-                        // Don't set empty environment variables as they can cause issues
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), String::new());
-                    }
+            return 1 + digits.len();
+        }
+        'u' | 'U' => {
+            // Up to four (\u) or eight (\U) hex digits naming a Unicode character
+            let width = if c == 'u' { 4 } else { 8 };
+            let digits: String = chars[1..]
+                .iter()
+                .take(width)
+                .take_while(|d| d.is_ascii_hexdigit())
+                .collect();
+            match u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+            {
+                Some(unicode) => push_char(output, unicode),
+                None if digits.is_empty() => {
+                    output.push(b'\\');
+                    push_char(output, c);
+                }
+                // Values that are not characters, like surrogates, print as written
+                None => {
+                    output.push(b'\\');
+                    push_char(output, c);
+                    output.extend_from_slice(digits.as_bytes());
                 }
             }
+            return 1 + digits.len();
         }
-        Ok(0)
-    }
+        _ => {
+            output.push(b'\\');
+            push_char(output, c);
+            return 1;
+        }
+    };
+    push_char(output, escaped);
+    1
+}
 
-    fn evaluate_pipeline(
-        &mut self,
-        commands: &[Node],
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        if commands.is_empty() {
-            return Ok(0);
+/// Resolve `dir` against `base` textually, folding `..` into the preceding component
+/// instead of following symlinks (the `cd -L` behaviour)
+fn logical_path(base: &str, dir: &str) -> PathBuf {
+    let mut path = PathBuf::from(base);
+    for component in Path::new(dir).components() {
+        match component {
+            Component::RootDir => path = PathBuf::from("/"),
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::Normal(part) => path.push(part),
+            Component::CurDir | Component::Prefix(_) => {}
         }
+    }
+    path
+}
 
-        if commands.len() == 1 {
-            return interpreter.evaluate_with_evaluator(&commands[0], self);
+/// Binary operators understood by `test` and `[`
+const TEST_BINARY_OPERATORS: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+/// Unary operators understood by `test` and `[`
+const TEST_UNARY_OPERATORS: &[&str] = &["-n", "-z", "-f", "-d", "-e", "-a", "-r", "-w", "-x", "-s"];
+
+/// Binary operators understood inside `[[ ]]`
+const CONDITIONAL_BINARY_OPERATORS: &[&str] = &[
+    "==", "=", "!=", "=~", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+/// Unary operators understood inside `[[ ]]`, evaluated like their `test` counterparts
+const CONDITIONAL_UNARY_OPERATORS: &[&str] =
+    &["-n", "-z", "-f", "-d", "-e", "-r", "-w", "-x", "-s"];
+
+/// Special parameters offered when completing a `$` with no name typed yet
+const SPECIAL_PARAMETERS: &[&str] = &["#", "?", "$", "!", "@", "*", "-", "0"];
+
+/// The partial variable name typed at the end of `text`, after `$` or inside an
+/// unclosed `${`, and whether it is braced
+fn variable_completion_prefix(text: &str) -> Option<(&str, bool)> {
+    let rest = &text[text.rfind('$')? + 1..];
+    let (name, braced) = match rest.strip_prefix('{') {
+        Some(name) => (name, true),
+        None => (rest, false),
+    };
+    name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        .then_some((name, braced))
+}
+
+/// Whether `name` only uses the characters POSIX allows in alias names
+fn is_portable_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '!' | '%' | ',' | '@'))
+}
+
+/// Interpret the backslash escapes understood by XSI `echo`.
+/// Returns the raw bytes and whether `\c` asked to suppress further output.
+fn expand_echo_escapes(text: &str) -> (Vec<u8>, bool) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '\\' {
+            push_char(&mut output, chars[i]);
+            i += 1;
+            continue;
         }
 
-        let mut last_exit_code = 0;
-        for command in commands {
-            last_exit_code = interpreter.evaluate_with_evaluator(command, self)?;
+        i += 1;
+        match chars.get(i) {
+            Some('c') => return (output, true),
+            // \0nnn takes up to three octal digits after the 0
+            Some('0') => {
+                let digits: String = chars[i + 1..]
+                    .iter()
+                    .take(3)
+                    .take_while(|d| d.is_digit(8))
+                    .collect();
+                let value = u32::from_str_radix(&digits, 8).unwrap_or(0);
+                output.push(value as u8);
+                i += 1 + digits.len();
+            }
+            _ => i += push_printf_escape(&chars[i..], &mut output),
         }
-        Ok(last_exit_code)
     }
+    (output, false)
+}
 
-    fn evaluate_list(
-        &mut self,
-        statements: &[Node],
-        operators: &[String],
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        let mut last_exit_code = 0;
+/// Print the timing summary for the `time` reserved word to `errors`
+fn report_time(mut errors: impl Write, real: f64, user: f64, sys: f64, posix: bool) {
+    if posix {
+        let _ = writeln!(errors, "real {real:.2}\nuser {user:.2}\nsys {sys:.2}");
+    } else {
+        let format = |secs: f64| format!("{}m{:.3}s", (secs / 60.0) as u64, secs % 60.0);
+        let _ = writeln!(
+            errors,
+            "\nreal\t{}\nuser\t{}\nsys\t{}",
+            format(real),
+            format(user),
+            format(sys)
+        );
+    }
+}
 
-        for (i, statement) in statements.iter().enumerate() {
-            match interpreter.evaluate_with_evaluator(statement, self) {
-                Ok(code) => {
-                    last_exit_code = code;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
-                    // Check if this is a return statement - propagate it up
-                    if e.to_string().starts_with("return:") {
-                        return Err(io::Error::new(e.kind(), e.to_string()));
-                    }
-                    return Err(io::Error::new(e.kind(), e.to_string()));
-                }
-                Err(e) => return Err(e),
-            }
+impl ArithmeticContext for Interpreter {
+    fn variable(&self, name: &str) -> Option<String> {
+        self.get_variable(name).cloned()
+    }
 
-            if i < operators.len() {
-                match operators[i].as_str() {
-                    "&&" => {
-                        if last_exit_code != 0 {
-                            break;
-                        }
-                    }
-                    "||" => {
-                        if last_exit_code == 0 {
-                            break;
-                        }
-                    }
-                    _ => {}
-                }
-            }
-        }
+    fn assign(&mut self, name: &str, value: i64) -> Result<(), String> {
+        self.set_variable(name, value.to_string())
+    }
+}
 
-        Ok(last_exit_code)
+/// Arithmetic context for expansions evaluated through `&self`, where assignments are
+/// dropped, though assigning to a readonly variable is still an error
+struct ReadOnlyVariables<'a>(&'a Interpreter);
+
+impl ArithmeticContext for ReadOnlyVariables<'_> {
+    fn variable(&self, name: &str) -> Option<String> {
+        self.0.get_variable(name).cloned()
     }
 
-    fn evaluate_assignment(
-        &mut self,
-        name: &str,
-        value: &Node,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        match value {
-            Node::StringLiteral(string_value) => {
-                let expanded_value = interpreter.expand_variables(string_value);
-                interpreter
-                    .variables
-                    .insert(name.to_string(), expanded_value);
-            }
-            Node::SingleQuotedString(string_value) => {
-                // Single-quoted strings should not have variable expansion
-                interpreter
-                    .variables
-                    .insert(name.to_string(), string_value.clone());
-            }
+    fn assign(&mut self, name: &str, _value: i64) -> Result<(), String> {
+        self.0.check_writable(name)
+    }
+}
+
+/// Where word expansion evaluates `$(( ))`: the interpreter itself, so that their
+/// assignments take effect in order, or a read-only view of it
+trait Expansion: ArithmeticContext {
+    fn interpreter(&self) -> &Interpreter;
+
+    /// Report a `$(( ))` that failed to evaluate
+    fn arithmetic_error(&mut self, message: String);
+}
+
+impl Expansion for Interpreter {
+    fn interpreter(&self) -> &Interpreter {
+        self
+    }
+
+    /// Kept until the command that expanded it fails
+    fn arithmetic_error(&mut self, message: String) {
+        self.arithmetic_error.get_or_insert(message);
+    }
+}
+
+impl Expansion for ReadOnlyVariables<'_> {
+    fn interpreter(&self) -> &Interpreter {
+        self.0
+    }
+
+    fn arithmetic_error(&mut self, message: String) {
+        let _ = writeln!(&self.0.stderr, "{message}");
+    }
+}
+
+pub trait Evaluator {
+    fn evaluate(&mut self, node: &Node, interpreter: &mut Interpreter) -> Result<i32, io::Error>;
+}
+
+// Default evaluator that implements the standard shell behavior
+pub struct DefaultEvaluator;
+
+impl Evaluator for DefaultEvaluator {
+    fn evaluate(&mut self, node: &Node, interpreter: &mut Interpreter) -> Result<i32, io::Error> {
+        match node {
+            Node::Command {
+                name,
+                args,
+                redirects,
+            } => self.evaluate_command(name, args, redirects, interpreter),
+            Node::Pipeline { commands } => self.evaluate_pipeline(commands, interpreter),
+            Node::List {
+                statements,
+                operators,
+            } => self.evaluate_list(statements, operators, interpreter),
+            Node::Assignment { name, value } => self.evaluate_assignment(name, value, interpreter),
+            Node::PrefixedCommand {
+                assignments,
+                command,
+            } => self.evaluate_prefixed_command(assignments, command, interpreter),
             Node::CommandSubstitution { command } => {
-                let output = interpreter.capture_command_output(command, self)?;
-                interpreter.variables.insert(name.to_string(), output);
+                self.evaluate_command_substitution(command, interpreter)
             }
             Node::ArithmeticExpansion { expression } => {
-                let expanded_expr = interpreter.expand_variables(expression);
-                match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                    Ok(result) => {
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), result.to_string());
-                    }
-                    Err(_) => {
-                        eprintln!("arithmetic expansion: invalid expression: {expanded_expr}");
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), "0".to_string());
-                    }
-                }
+                self.evaluate_arithmetic_expansion(expression, interpreter)
             }
             Node::ArithmeticCommand { expression } => {
-                let expanded_expr = interpreter.expand_variables(expression);
-                match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                    Ok(result) => {
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), result.to_string());
-                    }
-                    Err(_) => {
-                        eprintln!("arithmetic command: invalid expression: {expanded_expr}");
-                        interpreter
-                            .variables
-                            .insert(name.to_string(), "0".to_string());
-                    }
+                self.evaluate_arithmetic_command(expression, interpreter)
+            }
+            Node::StringLiteral(_value) => Ok(0),
+            Node::SingleQuotedString(_value) => Ok(0),
+            Node::Subshell { list } => {
+                interpreter.nesting_level += 1;
+                let result = interpreter.evaluate_with_evaluator(list, self);
+                interpreter.nesting_level -= 1;
+                result
+            }
+            Node::Group { list } => interpreter.evaluate_with_evaluator(list, self),
+            Node::Redirected { command, redirects } => {
+                self.evaluate_redirected(command, redirects, interpreter)
+            }
+            Node::Comment(_) => Ok(0),
+            Node::ExtGlobPattern {
+                operator,
+                patterns,
+                suffix,
+            } => self.evaluate_ext_glob(*operator, patterns, suffix, interpreter),
+            Node::Export { name, value } => self.evaluate_export(name, value, interpreter),
+            Node::IfStatement {
+                condition,
+                consequence,
+                alternative,
+            } => self.evaluate_if_statement(condition, consequence, alternative, interpreter),
+            Node::ElifBranch {
+                condition,
+                consequence,
+            } => self.evaluate_elif_branch(condition, consequence, interpreter),
+            Node::ElseBranch { consequence } => self.evaluate_else_branch(consequence, interpreter),
+            Node::CaseStatement {
+                expression,
+                patterns,
+            } => self.evaluate_case_statement(expression, patterns, interpreter),
+            Node::Function { name, body } => {
+                self.evaluate_function_definition(name, body, interpreter)
+            }
+            Node::FunctionCall {
+                name,
+                args,
+                redirects,
+            } => self.evaluate_function_call(name, args, redirects, interpreter),
+            Node::Return { value } => self.evaluate_return(value, interpreter),
+            Node::HistoryExpansion { pattern } => {
+                self.evaluate_history_expansion(pattern, interpreter)
+            }
+            Node::Complete { options, command } => {
+                // The parser gives `complete` its own node; run it as the builtin
+                let mut args = options.clone();
+                if !command.is_empty() {
+                    args.push(command.clone());
                 }
+                self.evaluate_builtin_or_external("complete", &args, &[], interpreter)
             }
-            Node::Array { elements } => {
-                // Handle array assignment - join elements or store in a specific format
-                let array_value = elements.join(" ");
-                let expanded_value = interpreter.expand_variables(&array_value);
-                interpreter
-                    .variables
-                    .insert(name.to_string(), expanded_value);
+            Node::ForLoop {
+                variable,
+                iterable,
+                body,
+            } => self.evaluate_for_loop(variable, iterable, body, interpreter),
+            Node::WhileLoop { condition, body } => {
+                self.evaluate_while_loop(condition, body, interpreter)
             }
-            _ => {
-                return Err(io::Error::other("Unsupported value type for assignment"));
+            Node::UntilLoop { condition, body } => {
+                self.evaluate_until_loop(condition, body, interpreter)
+            }
+            Node::Array { elements: _ } => Ok(0), // Arrays are used internally, return success
+            Node::Negation { command } => {
+                // Logical negation - invert the exit code
+                let result = interpreter.evaluate_condition(command, self)?;
+                Ok(if result == 0 { 1 } else { 0 })
             }
+            Node::Break { level } => self.evaluate_loop_control("break", *level, interpreter),
+            Node::Continue { level } => self.evaluate_loop_control("continue", *level, interpreter),
+            Node::Time { pipeline, posix } => {
+                // Time the whole pipeline, including the CPU time of its children
+                let start = std::time::Instant::now();
+                let (user_before, sys_before) = cpu_times();
+                let result = interpreter.evaluate_with_evaluator(pipeline, self);
+                let (user_after, sys_after) = cpu_times();
+
+                report_time(
+                    &interpreter.stderr,
+                    start.elapsed().as_secs_f64(),
+                    user_after - user_before,
+                    sys_after - sys_before,
+                    *posix,
+                );
+                result
+            }
+            Node::SelectStatement {
+                variable,
+                items,
+                body,
+            } => self.evaluate_select_statement(variable, items, body, interpreter),
+            Node::ExtendedTest { condition } => self.evaluate_extended_test(condition, interpreter),
+            Node::ParameterExpansion { .. } => {
+                // Parameter expansions should be handled during argument expansion
+                // If we reach here, it means it was not expanded properly
+                Ok(0)
+            }
+            _ => Err(io::Error::other("Unsupported node type")),
         }
-        Ok(0)
     }
+}
 
-    fn evaluate_ext_glob(
+impl DefaultEvaluator {
+    fn evaluate_function_definition(
         &mut self,
-        operator: char,
-        patterns: &[String],
-        suffix: &str,
-        interpreter: &Interpreter,
+        name: &str,
+        body: &Node,
+        interpreter: &mut Interpreter,
     ) -> Result<i32, io::Error> {
-        let entries = fs::read_dir(".")?;
-        let mut matches = Vec::new();
-
-        for entry in entries.flatten() {
-            let file_name = entry.file_name().to_string_lossy().to_string();
-            if interpreter.matches_ext_glob(&file_name, operator, patterns, suffix) {
-                matches.push(file_name);
-            }
-        }
-
-        for m in matches {
-            println!("{m}");
-        }
-
+        // Store the function definition
+        interpreter
+            .functions
+            .insert(name.to_string(), Box::new(body.clone()));
         Ok(0)
     }
 
-    fn evaluate_if_statement(
+    fn evaluate_function_call(
         &mut self,
-        condition: &Node,
-        consequence: &Node,
-        alternative: &Option<Box<Node>>,
+        name: &str,
+        args: &[String],
+        redirects: &[Redirect],
         interpreter: &mut Interpreter,
     ) -> Result<i32, io::Error> {
-        // Evaluate the condition
-        let condition_result = interpreter.evaluate_with_evaluator(condition, self)?;
+        // Check if function exists
+        if let Some(body) = interpreter.functions.get(name).cloned() {
+            // Set up function arguments as positional parameters
+            let old_args = interpreter.args.clone();
+            let old_return_value = interpreter.return_value;
+            let mut new_args = vec![name.to_string()]; // $0 is function name
+            new_args.extend(args.iter().cloned());
+            interpreter.args = new_args;
+            interpreter.return_value = None; // Clear any previous return value
 
-        if condition_result == 0 {
-            // Condition is true (exit code 0), execute the consequence
-            interpreter.evaluate_with_evaluator(consequence, self)
-        } else if let Some(alt) = alternative {
-            // Condition is false, execute the alternative (elif or else)
-            interpreter.evaluate_with_evaluator(alt, self)
+            // Execute function body in a fresh local scope
+            interpreter.nesting_level += 1;
+            interpreter.local_scopes.push(HashMap::new());
+            let result = interpreter.evaluate_with_evaluator(&body, self);
+            interpreter.pop_local_scope();
+            interpreter.nesting_level -= 1;
+
+            // Check if function returned early
+            let final_result = match result {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    // Check if this is a return statement
+                    if let Some(msg) = e.to_string().strip_prefix("return:") {
+                        if let Ok(return_code) = msg.parse::<i32>() {
+                            Ok(return_code)
+                        } else {
+                            Ok(0)
+                        }
+                    } else {
+                        result
+                    }
+                }
+                _ => result,
+            };
+
+            // Restore original arguments and return value
+            interpreter.args = old_args;
+            interpreter.return_value = old_return_value;
+
+            final_result
         } else {
-            // No alternative, return the condition's exit code
-            Ok(condition_result)
+            // Function not found, treat as regular command
+            self.evaluate_command(name, args, redirects, interpreter)
         }
     }
 
-    fn evaluate_elif_branch(
+    fn evaluate_return(
         &mut self,
-        condition: &Node,
-        consequence: &Node,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Evaluate the elif condition
-        let condition_result = interpreter.evaluate_with_evaluator(condition, self)?;
-
-        if condition_result == 0 {
-            // Condition is true, execute the consequence
-            interpreter.evaluate_with_evaluator(consequence, self)
-        } else {
-            // Condition is false, return the condition's exit code
-            Ok(condition_result)
-        }
-    }
-
-    fn evaluate_else_branch(
-        &mut self,
-        consequence: &Node,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Always execute the else consequence
-        interpreter.evaluate_with_evaluator(consequence, self)
-    }
-
-    fn evaluate_case_statement(
-        &mut self,
-        expression: &Node,
-        patterns: &[CasePattern],
+        value: &Option<Box<Node>>,
         interpreter: &mut Interpreter,
     ) -> Result<i32, io::Error> {
-        // Evaluate the expression to get the value to match against
-        let expr_result = match expression {
-            Node::StringLiteral(s) => s.clone(),
-            Node::Command { name, args, .. } => {
-                // For commands, use the command name and args as the value
-                format!("{} {}", name, args.join(" "))
-            }
-            Node::CommandSubstitution { command } => {
-                // Evaluate the command substitution and get its output
-                match interpreter.evaluate_with_evaluator(command, self) {
-                    Ok(_) => {
-                        // Try to get the output from the command
-                        // For now, we'll use a simple approach
-                        "".to_string() // This is a placeholder - in a full implementation,
-                        // we'd capture the command output
+        let return_code = match value {
+            Some(val) => {
+                // Evaluate the return value
+                match val.as_ref() {
+                    Node::StringLiteral(string_value) => {
+                        let expanded_value = interpreter.expand_variables(string_value);
+                        expanded_value.parse::<i32>().unwrap_or(0)
+                    }
+                    Node::CommandSubstitution { command } => {
+                        // Execute command substitution and use its exit code
+                        interpreter.evaluate_with_evaluator(command, self)?
+                    }
+                    Node::ArithmeticExpansion { expression } => {
+                        let expanded_expr = interpreter.expand_parameters(expression);
+                        match arithmetic::evaluate_with(&expanded_expr, interpreter) {
+                            Ok(result) => result as i32,
+                            Err(message) => {
+                                return interpreter.expansion_error(&format!(
+                                    "flash: {expanded_expr}: {message}"
+                                ));
+                            }
+                        }
+                    }
+                    Node::ArithmeticCommand { expression } => {
+                        let expanded_expr = interpreter.expand_parameters(expression);
+                        match arithmetic::evaluate_with(&expanded_expr, interpreter) {
+                            Ok(result) => result as i32,
+                            Err(message) => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "flash: ((: {expanded_expr}: {message}"
+                                )?;
+                                return Ok(1);
+                            }
+                        }
+                    }
+                    _ => {
+                        // For other node types, evaluate them and use the result
+                        interpreter.evaluate_with_evaluator(val, self)?
                     }
-                    Err(_) => "".to_string(),
                 }
             }
-            _ => {
-                // For other node types, try to convert to string representation
-                format!("{expression:?}")
+            None => {
+                // No return value specified, use last exit code
+                interpreter.last_exit_code
             }
         };
 
-        // Expand variables in the expression result
-        let expanded_expr = interpreter.expand_variables(&expr_result);
-
-        // Try to match against each pattern
-        for pattern in patterns {
-            for pattern_str in &pattern.patterns {
-                // Expand variables in the pattern
-                let expanded_pattern = interpreter.expand_variables(pattern_str);
-
-                // Check for exact match or wildcard match
-                if self.pattern_matches(&expanded_expr, &expanded_pattern) {
-                    // Execute the body for this pattern
-                    return interpreter.evaluate_with_evaluator(&pattern.body, self);
-                }
-            }
-        }
+        // Set the return value in the interpreter
+        interpreter.return_value = Some(return_code);
 
-        // No pattern matched, return success (like bash)
-        Ok(0)
+        // Return a special error code to indicate early return from function
+        // We'll use a custom error type for this
+        Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!("return:{return_code}"),
+        ))
     }
 
-    fn pattern_matches(&self, value: &str, pattern: &str) -> bool {
-        // Handle special case patterns
-        if pattern == "*" {
-            return true; // Wildcard matches everything
+    fn evaluate_command(
+        &mut self,
+        name: &str,
+        args: &[String],
+        redirects: &[Redirect],
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        if args
+            .iter()
+            .any(|arg| process_substitution_source(arg).is_some())
+        {
+            return self.evaluate_with_process_substitutions(name, args, redirects, interpreter);
+        }
+        if matches!(name, "declare" | "typeset" | "local" | "readonly")
+            && args
+                .iter()
+                .any(|arg| compound_assignment_name(arg).is_some())
+        {
+            return self.evaluate_compound_declaration(name, args, redirects, interpreter);
         }
 
-        // Simple pattern matching - exact match or basic wildcard
-        if pattern.contains('*') {
-            // Convert shell pattern to regex
-            let regex_pattern = pattern
-                .replace("*", ".*")
-                .replace("?", ".")
-                .replace("[", "\\[")
-                .replace("]", "\\]");
-
-            if let Ok(regex) = Regex::new(&format!("^{regex_pattern}$")) {
-                return regex.is_match(value);
+        // ${name:=word} assigns and ${name:?word} aborts the command before expansion
+        for arg in args {
+            if let Err(message) = interpreter.assign_parameter_defaults(arg) {
+                return interpreter.expansion_error(&message);
             }
         }
+        // Expand arguments into fields (parameters, splitting, globs, quote removal)
+        let mut expanded_args = interpreter.expand_words(args);
 
-        // Exact match
-        value == pattern
-    }
-
-    fn evaluate_for_loop(
-        &mut self,
-        variable: &str,
-        iterable: &Node,
-        body: &Node,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Get the list of values to iterate over
-        let values = match iterable {
-            Node::Array { elements } => elements.clone(),
-            _ => {
-                // If it's not an array, try to evaluate it as a command and get its output
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "for loop iterable must be an array",
-                ));
+        // A name written with quotes or parameters, as in "$cmd" arg, is expanded the
+        // same way, and its first field names the command
+        let expanded_name;
+        let name = if name.contains(['"', '\'', '$']) {
+            let mut fields = interpreter.expand_words(&[name.to_string()]);
+            fields.append(&mut expanded_args);
+            if fields.is_empty() {
+                return Ok(0);
             }
+            expanded_name = fields.remove(0);
+            expanded_args = fields;
+            expanded_name.as_str()
+        } else {
+            name
         };
+        // A failed $(( )) fails the command before it runs
+        if let Some(message) = interpreter.arithmetic_error.take() {
+            return interpreter.expansion_error(&message);
+        }
 
-        let mut last_exit_code = 0;
+        // Use expanded args for the rest of the function
+        let args = &expanded_args;
 
-        // Save the current value of the loop variable (if it exists)
-        let old_value = interpreter.variables.get(variable).cloned();
+        // Functions take precedence over aliases
+        let is_function = interpreter.functions.contains_key(name);
 
-        // Iterate over each value
-        for value in values {
-            // Set the loop variable
-            interpreter.variables.insert(variable.to_string(), value);
+        // Check for alias expansion
+        if !is_function {
+            if let Some(alias_value) = interpreter.aliases.get(name) {
+                // Parse the alias value properly handling escaped spaces
+                let mut alias_parts = interpreter.parse_alias_value(alias_value);
+                alias_parts.extend_from_slice(args);
+
+                // Create a new command with the expanded alias
+                if let Some(new_name) = alias_parts.first() {
+                    let new_args = &alias_parts[1..];
+                    return self.evaluate_command(new_name, new_args, redirects, interpreter);
+                }
+            }
+        }
 
-            // Execute the body
-            last_exit_code = interpreter.evaluate_with_evaluator(body, self)?;
+        // Print the expanded command when xtrace is enabled
+        interpreter.trace_command(name, args);
 
-            // Check for break/continue (not implemented yet, but structure is ready)
-            // if interpreter.should_break { break; }
-            // if interpreter.should_continue { continue; }
+        // Functions and builtins run in the shell, so their redirections point the
+        // shell's own descriptors elsewhere for the duration of the command
+        if !redirects.is_empty() && (is_function || interpreter.is_builtin(name)) {
+            let saved = match Self::apply_redirects(redirects, interpreter) {
+                Ok(saved) => saved,
+                Err(e) => {
+                    writeln!(&interpreter.stderr, "{e}")?;
+                    return Ok(1);
+                }
+            };
+            let result = if is_function {
+                self.evaluate_function_call(name, args, &[], interpreter)
+            } else {
+                self.evaluate_builtin_or_external(name, args, &[], interpreter)
+            };
+            let _ = io::stdout().flush();
+            Self::undo_redirects(saved, interpreter)?;
+            return result;
         }
 
-        // Restore the old value of the loop variable
-        if let Some(old_val) = old_value {
-            interpreter.variables.insert(variable.to_string(), old_val);
-        } else {
-            interpreter.variables.remove(variable);
+        if is_function {
+            return self.evaluate_function_call(name, args, redirects, interpreter);
         }
 
-        Ok(last_exit_code)
+        self.evaluate_builtin_or_external(name, args, redirects, interpreter)
     }
 
-    fn evaluate_while_loop(
+    /// Run a compound command with its redirections in place, as in
+    /// `while read line; do ...; done < file`
+    fn evaluate_redirected(
         &mut self,
-        condition: &Node,
-        body: &Node,
+        command: &Node,
+        redirects: &[Redirect],
         interpreter: &mut Interpreter,
     ) -> Result<i32, io::Error> {
-        let mut last_exit_code = 0;
-
-        // Continue while condition returns 0 (success)
-        loop {
-            let condition_result = interpreter.evaluate_with_evaluator(condition, self)?;
-            if condition_result != 0 {
-                break; // Exit when condition fails
+        let saved = match Self::apply_redirects(redirects, interpreter) {
+            Ok(saved) => saved,
+            Err(e) => {
+                writeln!(&interpreter.stderr, "{e}")?;
+                return Ok(1);
             }
-
-            // Execute the body
-            last_exit_code = interpreter.evaluate_with_evaluator(body, self)?;
-
-            // Check for break/continue (not implemented yet, but structure is ready)
-            // if interpreter.should_break { break; }
-            // if interpreter.should_continue { continue; }
-        }
-
-        Ok(last_exit_code)
+        };
+        let result = interpreter.evaluate_with_evaluator(command, self);
+        let _ = io::stdout().flush();
+        Self::undo_redirects(saved, interpreter)?;
+        result
     }
 
-    fn evaluate_until_loop(
+    /// Run a command with each `<(...)` and `>(...)` argument replaced by a path
+    /// connected to its running command
+    fn evaluate_with_process_substitutions(
         &mut self,
-        condition: &Node,
-        body: &Node,
+        name: &str,
+        args: &[String],
+        redirects: &[Redirect],
         interpreter: &mut Interpreter,
     ) -> Result<i32, io::Error> {
-        let mut last_exit_code = 0;
-
-        // Continue until condition returns 0 (success)
-        loop {
-            let condition_result = interpreter.evaluate_with_evaluator(condition, self)?;
-            if condition_result == 0 {
-                break; // Exit when condition succeeds
+        let mut substitutions = Vec::new();
+        let mut words = Vec::with_capacity(args.len());
+        for arg in args {
+            let Some((source, reads)) = process_substitution_source(arg) else {
+                words.push(arg.clone());
+                continue;
+            };
+            match interpreter.start_process_substitution(source, reads, &substitutions, self) {
+                Ok((path, substitution)) => {
+                    words.push(path);
+                    substitutions.push(substitution);
+                }
+                Err(e) => {
+                    writeln!(&interpreter.stderr, "flash: {arg}: {e}")?;
+                    finish_process_substitutions(substitutions);
+                    return Ok(1);
+                }
             }
-
-            // Execute the body
-            last_exit_code = interpreter.evaluate_with_evaluator(body, self)?;
-
-            // Check for break/continue (not implemented yet, but structure is ready)
-            // if interpreter.should_break { break; }
-            // if interpreter.should_continue { continue; }
         }
 
-        Ok(last_exit_code)
+        let result = self.evaluate_command(name, &words, redirects, interpreter);
+        finish_process_substitutions(substitutions);
+        result
     }
 
-    fn evaluate_select_statement(
+    /// Run a builtin or an external command, bypassing functions and aliases
+    #[allow(clippy::only_used_in_recursion)]
+    fn evaluate_builtin_or_external(
         &mut self,
-        variable: &str,
-        items: &Node,
-        body: &Node,
+        name: &str,
+        args: &[String],
+        redirects: &[Redirect],
         interpreter: &mut Interpreter,
     ) -> Result<i32, io::Error> {
-        use std::io::{self, Write};
-
-        // Evaluate items to get the list of choices
-        let choices = match items {
-            Node::Array { elements } => elements.clone(),
-            Node::StringLiteral(s) if s == "$@" => {
-                // Use positional parameters
-                interpreter.args.clone()
-            }
-            _ => {
-                // Try to evaluate as a command and capture output
-                let output = interpreter.capture_command_output(items, self)?;
-                output.split_whitespace().map(|s| s.to_string()).collect()
-            }
-        };
-
-        if choices.is_empty() {
-            return Ok(0);
-        }
-
-        // Check if we're in a non-interactive environment (like tests)
-        // by checking if stdin is redirected or if we're in a test environment
-        if std::env::var("CARGO_TEST").is_ok() || std::env::var("RUST_TEST_THREADS").is_ok() {
-            // In test mode, just return without executing the body
-            return Ok(0);
-        }
+        // Handle built-in commands
+        match name {
+            "cd" => {
+                // -L keeps symlinks in $PWD (the default), -P resolves them; the last one wins
+                let mut physical = false;
+                let mut operands = args.iter();
+                let mut dir = None;
+                for arg in operands.by_ref() {
+                    match arg.as_str() {
+                        "--" => break,
+                        option if option.starts_with('-') && option.len() > 1 => {
+                            for flag in option.chars().skip(1) {
+                                match flag {
+                                    'L' => physical = false,
+                                    'P' => physical = true,
+                                    _ => {
+                                        writeln!(
+                                            &interpreter.stderr,
+                                            "cd: -{flag}: invalid option"
+                                        )?;
+                                        writeln!(
+                                            &interpreter.stderr,
+                                            "cd: usage: cd [-L|-P] [dir]"
+                                        )?;
+                                        return Ok(2);
+                                    }
+                                }
+                            }
+                        }
+                        _ => {
+                            dir = Some(arg.clone());
+                            break;
+                        }
+                    }
+                }
+                let dir = match dir.or_else(|| operands.next().cloned()) {
+                    Some(dir) => dir,
+                    None => env::var("HOME").unwrap_or_else(|_| ".".to_string()),
+                };
 
-        let mut last_exit_code = 0;
+                // `cd -` returns to $OLDPWD and prints where it went
+                let back = dir == "-";
+                let dir = if back {
+                    match interpreter.variables.get("OLDPWD") {
+                        Some(previous) => previous.clone(),
+                        None => {
+                            writeln!(&interpreter.stderr, "cd: OLDPWD not set")?;
+                            return Ok(1);
+                        }
+                    }
+                } else {
+                    dir
+                };
 
-        loop {
-            // Display the menu
-            for (i, choice) in choices.iter().enumerate() {
-                println!("{}) {}", i + 1, choice);
+                match interpreter.change_directory(&dir, physical) {
+                    Ok(()) if back => {
+                        writeln!(&interpreter.stdout, "{}", interpreter.working_directory())?;
+                        Ok(0)
+                    }
+                    Ok(()) => Ok(0),
+                    Err(e) => {
+                        writeln!(&interpreter.stderr, "cd: {dir}: {e}")?;
+                        Ok(1)
+                    }
+                }
             }
+            "pushd" => {
+                let previous = interpreter.working_directory();
+                let dir = match args.first() {
+                    Some(dir) => dir.clone(),
+                    // Without an argument the top two entries trade places
+                    None => match interpreter.dir_stack.first() {
+                        Some(top) => top.clone(),
+                        None => {
+                            writeln!(&interpreter.stderr, "pushd: no other directory")?;
+                            return Ok(1);
+                        }
+                    },
+                };
 
-            // Prompt for selection
-            print!("#? ");
-            io::stdout().flush()?;
-
-            // Read user input
-            let mut input = String::new();
-            match io::stdin().read_line(&mut input) {
-                Ok(0) => {
-                    // EOF - break out of select loop
-                    break;
+                if let Err(e) = interpreter.change_directory(&dir, false) {
+                    writeln!(&interpreter.stderr, "pushd: {dir}: {e}")?;
+                    return Ok(1);
                 }
-                Ok(_) => {
-                    let input = input.trim();
-
-                    // Handle empty input (just continue the loop)
-                    if input.is_empty() {
-                        continue;
-                    }
-
-                    // Handle numeric selection
-                    if let Ok(selection) = input.parse::<usize>() {
-                        if selection > 0 && selection <= choices.len() {
-                            // Set the variable to the selected item
-                            interpreter
-                                .variables
-                                .insert(variable.to_string(), choices[selection - 1].clone());
-
-                            // Set REPLY to the user's input
-                            interpreter
-                                .variables
-                                .insert("REPLY".to_string(), input.to_string());
-
-                            // Execute the body
-                            last_exit_code = interpreter.evaluate_with_evaluator(body, self)?;
-                            continue;
+                if args.is_empty() {
+                    interpreter.dir_stack[0] = previous;
+                } else {
+                    interpreter.dir_stack.insert(0, previous);
+                }
+                writeln!(
+                    &interpreter.stdout,
+                    "{}",
+                    interpreter.dir_stack_entries(true).join(" ")
+                )?;
+                Ok(0)
+            }
+            "popd" => {
+                let Some(dir) = interpreter.dir_stack.first().cloned() else {
+                    writeln!(&interpreter.stderr, "popd: directory stack empty")?;
+                    return Ok(1);
+                };
+                if let Err(e) = interpreter.change_directory(&dir, false) {
+                    writeln!(&interpreter.stderr, "popd: {dir}: {e}")?;
+                    return Ok(1);
+                }
+                interpreter.dir_stack.remove(0);
+                writeln!(
+                    &interpreter.stdout,
+                    "{}",
+                    interpreter.dir_stack_entries(true).join(" ")
+                )?;
+                Ok(0)
+            }
+            "dirs" => {
+                let mut clear = false;
+                let mut tilde = true;
+                let mut per_line = false;
+                let mut numbered = false;
+                for arg in args {
+                    let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty())
+                    else {
+                        writeln!(&interpreter.stderr, "dirs: {arg}: invalid argument")?;
+                        return Ok(1);
+                    };
+                    for flag in flags.chars() {
+                        match flag {
+                            'c' => clear = true,
+                            'l' => tilde = false,
+                            'p' => per_line = true,
+                            'v' => numbered = true,
+                            _ => {
+                                writeln!(&interpreter.stderr, "dirs: -{flag}: invalid option")?;
+                                return Ok(2);
+                            }
                         }
                     }
-
-                    // Set REPLY to the user's input for non-numeric or invalid selections
-                    interpreter
-                        .variables
-                        .insert("REPLY".to_string(), input.to_string());
-
-                    // For invalid selections, unset the variable and execute body
-                    interpreter.variables.remove(variable);
-                    last_exit_code = interpreter.evaluate_with_evaluator(body, self)?;
                 }
-                Err(_) => {
-                    // Error reading input - break out of select loop
-                    break;
+                if clear {
+                    interpreter.dir_stack.clear();
+                    return Ok(0);
                 }
-            }
-        }
-
-        Ok(last_exit_code)
-    }
-
-    fn evaluate_command_substitution(
-        &mut self,
-        command: &Node,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        let _output = interpreter.capture_command_output(command, self)?;
-        // Command substitution doesn't print output, it returns it as a value
-        // The exit code is typically 0 for successful substitution
-        Ok(0)
-    }
-
-    fn evaluate_arithmetic_expansion(
-        &mut self,
-        expression: &str,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Expand variables in the expression first
-        let expanded_expr = interpreter.expand_variables(expression);
 
-        // Evaluate the arithmetic expression
-        match self.evaluate_arithmetic_expression_with_assignment(&expanded_expr, interpreter) {
-            Ok(_result) => {
-                // For arithmetic expansion, we typically return 0 for success
-                // The actual result is used as a value, not an exit code
+                let entries = interpreter.dir_stack_entries(tilde);
+                if numbered {
+                    for (index, entry) in entries.iter().enumerate() {
+                        writeln!(&interpreter.stdout, "{index:2}  {entry}")?;
+                    }
+                } else if per_line {
+                    for entry in &entries {
+                        writeln!(&interpreter.stdout, "{entry}")?;
+                    }
+                } else {
+                    writeln!(&interpreter.stdout, "{}", entries.join(" "))?;
+                }
                 Ok(0)
             }
-            Err(_) => {
-                eprintln!("arithmetic expansion: invalid expression: {expanded_expr}");
-                Ok(1)
+            "echo" => {
+                // POSIX (XSI) echo always interprets backslash escapes and takes no options
+                let posix = interpreter.shell_options.contains("posix");
+                let mut newline = true;
+                let mut escapes = posix;
+                let mut i = 0;
+                // Only words made entirely of n, e and E are options; anything else is printed
+                while !posix
+                    && args.get(i).is_some_and(|arg| {
+                        arg.len() > 1
+                            && arg.starts_with('-')
+                            && arg[1..].chars().all(|flag| matches!(flag, 'n' | 'e' | 'E'))
+                    })
+                {
+                    for flag in args[i][1..].chars() {
+                        match flag {
+                            'n' => newline = false,
+                            'e' => escapes = true,
+                            _ => escapes = false,
+                        }
+                    }
+                    i += 1;
+                }
+
+                // Output is written as bytes so NUL and non-UTF-8 escapes pass through
+                let text = args[i..].join(" ");
+                let output = if escapes {
+                    let (mut bytes, stop) = expand_echo_escapes(&text);
+                    // \c also drops the trailing newline
+                    if newline && !stop {
+                        bytes.push(b'\n');
+                    }
+                    bytes
+                } else {
+                    let mut bytes = text.into_bytes();
+                    if newline {
+                        bytes.push(b'\n');
+                    }
+                    bytes
+                };
+                let mut stdout = &interpreter.stdout;
+                stdout.write_all(&output)?;
+                stdout.flush()?;
+                Ok(0)
             }
-        }
-    }
+            "local" => {
+                if interpreter.local_scopes.is_empty() {
+                    writeln!(&interpreter.stderr, "local: can only be used in a function")?;
+                    return Ok(1);
+                }
 
-    fn evaluate_arithmetic_command(
-        &mut self,
-        expression: &str,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Expand variables in the expression first
-        let expanded_expr = interpreter.expand_variables(expression);
+                let mut status = 0;
+                for arg in args {
+                    let (name, value) = match arg.split_once('=') {
+                        Some((name, value)) => (name, Some(value)),
+                        None => (arg.as_str(), None),
+                    };
+                    if !is_assignable_name(name) || name.contains('[') {
+                        writeln!(
+                            &interpreter.stderr,
+                            "local: `{arg}': not a valid identifier"
+                        )?;
+                        status = 1;
+                        continue;
+                    }
+                    if let Err(message) = interpreter.declare_local(name, value) {
+                        writeln!(&interpreter.stderr, "local: {message}")?;
+                        status = 1;
+                    }
+                }
+                Ok(status)
+            }
+            "printf" => {
+                let mut target = None;
+                let mut i = 0;
+                if args.first().is_some_and(|arg| arg == "-v") {
+                    let Some(name) = args.get(1) else {
+                        writeln!(
+                            &interpreter.stderr,
+                            "printf: -v: option requires an argument"
+                        )?;
+                        return Ok(2);
+                    };
+                    if !is_assignable_name(name) {
+                        writeln!(
+                            &interpreter.stderr,
+                            "printf: `{name}': not a valid identifier"
+                        )?;
+                        return Ok(2);
+                    }
+                    target = Some(name.clone());
+                    i = 2;
+                }
+                if args.get(i).is_some_and(|arg| arg == "--") {
+                    i += 1;
+                }
 
-        // Evaluate the arithmetic expression
-        match self.evaluate_arithmetic_expression_with_assignment(&expanded_expr, interpreter) {
-            Ok(result) => {
-                // For arithmetic commands, the exit code is based on the result
-                // Non-zero result means success (exit code 0), zero result means failure (exit code 1)
-                if result != 0 {
-                    Ok(0) // Success
-                } else {
-                    Ok(1) // Failure
+                let Some(format) = args.get(i) else {
+                    writeln!(
+                        &interpreter.stderr,
+                        "printf: usage: printf [-v var] format [arguments]"
+                    )?;
+                    return Ok(2);
+                };
+
+                let (output, status) = format_printf(format, &args[i + 1..], &interpreter.stderr);
+                match target {
+                    Some(name) => {
+                        let value = String::from_utf8_lossy(&output).into_owned();
+                        if let Err(message) = interpreter.set_variable(&name, value) {
+                            writeln!(&interpreter.stderr, "printf: {message}")?;
+                            return Ok(1);
+                        }
+                    }
+                    None => {
+                        let mut stdout = &interpreter.stdout;
+                        stdout.write_all(&output)?;
+                        stdout.flush()?;
+                    }
                 }
+                Ok(status)
             }
-            Err(_) => {
-                eprintln!("arithmetic command: invalid expression: {expanded_expr}");
-                Ok(1)
-            }
-        }
-    }
+            "export" => {
+                // export -f marks functions for export; -n removes the mark
+                if args
+                    .first()
+                    .is_some_and(|arg| arg.starts_with('-') && arg.len() > 1)
+                {
+                    let mut functions = false;
+                    let mut unexport = false;
+                    let mut i = 0;
+                    while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+                        for flag in args[i][1..].chars() {
+                            match flag {
+                                'f' => functions = true,
+                                'n' => unexport = true,
+                                'p' => {}
+                                _ => {
+                                    writeln!(
+                                        &interpreter.stderr,
+                                        "export: -{flag}: invalid option"
+                                    )?;
+                                    return Ok(2);
+                                }
+                            }
+                        }
+                        i += 1;
+                    }
 
-    #[allow(clippy::only_used_in_recursion)]
-    pub fn evaluate_arithmetic_expression_with_assignment(
-        &mut self,
-        expr: &str,
-        interpreter: &mut Interpreter,
-    ) -> Result<i64, String> {
-        // Enhanced arithmetic expression evaluator that can handle assignments
-        // Handles +, -, *, /, %, comparison operators, logical operators, assignments, and parentheses
+                    if !functions && unexport {
+                        for name in &args[i..] {
+                            unsafe {
+                                env::remove_var(name);
+                            }
+                        }
+                        return Ok(0);
+                    }
+                    if !functions {
+                        return self.evaluate_builtin_or_external(
+                            "export",
+                            &args[i..],
+                            redirects,
+                            interpreter,
+                        );
+                    }
+                    if i == args.len() {
+                        interpreter.list_exported_functions();
+                        return Ok(0);
+                    }
+                    return Ok(interpreter.mark_functions_exported(
+                        &args[i..],
+                        !unexport,
+                        "export",
+                    ));
+                }
 
-        let expr = expr.trim();
-        if expr.is_empty() {
-            return Ok(0);
-        }
+                if args.is_empty() {
+                    // List all exported variables
+                    for (key, value) in &interpreter.variables {
+                        writeln!(&interpreter.stdout, "export {key}={value}")?;
+                    }
+                    return Ok(0);
+                }
 
-        // Remove any trailing ')' that might be left from parsing issues
-        let expr = expr.trim_end_matches(')');
+                let mut status = 0;
+                for arg in args {
+                    let name = arg.split('=').next().unwrap_or_default();
+                    if !is_assignable_name(name) {
+                        writeln!(
+                            &interpreter.stderr,
+                            "export: `{arg}': not a valid identifier"
+                        )?;
+                        status = 1;
+                        continue;
+                    }
+                    if let Some(pos) = arg.find('=') {
+                        let (key, value) = arg.split_at(pos);
+                        let value = &value[1..];
+                        interpreter
+                            .variables
+                            .insert(key.to_string(), value.to_string());
+                        unsafe {
+                            env::set_var(key, value);
+                        }
+                    } else if let Some(value) = interpreter.variables.get(arg) {
+                        unsafe {
+                            env::set_var(arg, value);
+                        }
+                    }
+                }
+                Ok(status)
+            }
+            "readonly" => {
+                let mut i = 0;
+                while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+                    for flag in args[i][1..].chars() {
+                        if flag != 'p' {
+                            writeln!(&interpreter.stderr, "readonly: -{flag}: invalid option")?;
+                            return Ok(2);
+                        }
+                    }
+                    i += 1;
+                }
 
-        // Handle simple number
-        if let Ok(num) = expr.parse::<i64>() {
-            return Ok(num);
-        }
+                // With no names, list the readonly variables in a form the shell can read back
+                if i == args.len() {
+                    let mut names: Vec<&String> = interpreter.readonly.iter().collect();
+                    names.sort();
+                    for name in names {
+                        match interpreter.variables.get(name) {
+                            Some(value) => writeln!(
+                                &interpreter.stdout,
+                                "readonly {name}={}",
+                                double_quote(value)
+                            )?,
+                            None => writeln!(&interpreter.stdout, "readonly {name}")?,
+                        }
+                    }
+                    return Ok(0);
+                }
 
-        // Handle variable names (should be already expanded by expand_variables)
-        // If it's a single word that's not a number, treat it as 0 (unset variable)
-        if expr.chars().all(|c| c.is_alphanumeric() || c == '_') {
-            // This should not happen if variables are properly expanded
-            // But if it does, treat as 0
-            return Ok(0);
-        }
+                let mut status = 0;
+                for arg in &args[i..] {
+                    let (name, value) = match arg.split_once('=') {
+                        Some((name, value)) => (name, Some(value)),
+                        None => (arg.as_str(), None),
+                    };
+                    if !is_assignable_name(name) || name.contains('[') {
+                        writeln!(
+                            &interpreter.stderr,
+                            "readonly: `{arg}': not a valid identifier"
+                        )?;
+                        status = 1;
+                        continue;
+                    }
+                    if let Some(value) = value {
+                        if let Err(message) = interpreter.set_variable(name, value.to_string()) {
+                            writeln!(&interpreter.stderr, "flash: {message}")?;
+                            status = 1;
+                            continue;
+                        }
+                    }
+                    interpreter.readonly.insert(name.to_string());
+                }
+                Ok(status)
+            }
+            "declare" | "typeset" => {
+                let builtin = name;
+                let mut nameref = false;
+                let mut integer = false;
+                let mut indexed = false;
+                let mut associative = false;
+                let mut functions = false;
+                let mut export = false;
+                let mut readonly = false;
+                let mut print = false;
+                // +x and +i remove the export and integer attributes
+                let mut unexport = false;
+                let mut uninteger = false;
+                let mut i = 0;
+                while i < args.len() && args[i].starts_with(['-', '+']) && args[i].len() > 1 {
+                    let (sign, flags) = args[i].split_at(1);
+                    for flag in flags.chars() {
+                        match (sign, flag) {
+                            ("-", 'n') => nameref = true,
+                            ("-", 'i') => integer = true,
+                            ("-", 'a') => indexed = true,
+                            ("-", 'A') => associative = true,
+                            ("-", 'f') => functions = true,
+                            ("-", 'x') => export = true,
+                            ("-", 'r') => readonly = true,
+                            ("-", 'p') => print = true,
+                            ("+", 'x') => unexport = true,
+                            ("+", 'i') => uninteger = true,
+                            _ => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "{builtin}: {sign}{flag}: invalid option"
+                                )?;
+                                return Ok(2);
+                            }
+                        }
+                    }
+                    i += 1;
+                }
 
-        // Handle assignment operators first (lowest precedence)
-        if let Some(pos) = expr.rfind('=') {
-            // Make sure it's not part of == or != or >= or <=
-            if pos > 0 && pos + 1 < expr.len() {
-                let prev_char = expr.chars().nth(pos - 1);
-                let next_char = expr.chars().nth(pos + 1);
-                if prev_char != Some('!')
-                    && prev_char != Some('<')
-                    && prev_char != Some('>')
-                    && prev_char != Some('=')
-                    && next_char != Some('=')
-                {
-                    // This is an assignment, not a comparison
-                    let left = expr[..pos].trim();
-                    let right = expr[pos + 1..].trim();
-                    let right_val =
-                        self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-
-                    // Extract variable name from left side (remove $ if present)
-                    let var_name = if let Some(stripped) = left.strip_prefix('$') {
-                        stripped
+                // -p prints the named variables, or all of them, as declare commands
+                if print && !functions {
+                    let names: Vec<String> = if i == args.len() {
+                        interpreter.declared_names()
                     } else {
-                        left
+                        args[i..].to_vec()
                     };
-
-                    // Set the variable
-                    interpreter
-                        .variables
-                        .insert(var_name.to_string(), right_val.to_string());
-                    return Ok(right_val);
+                    let mut status = 0;
+                    for name in names {
+                        match interpreter.declare_definition(&name) {
+                            Some(definition) => writeln!(&interpreter.stdout, "{definition}")?,
+                            None => {
+                                writeln!(&interpreter.stderr, "{builtin}: {name}: not found")?;
+                                status = 1;
+                            }
+                        }
+                    }
+                    return Ok(status);
                 }
-            }
-        }
 
-        // Handle logical operators (next lowest precedence)
-        if let Some(pos) = expr.rfind("||") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(if left_val != 0 || right_val != 0 {
-                1
-            } else {
-                0
-            });
-        }
+                // -f works on function names, and -fx lists or marks exported functions
+                if functions {
+                    if i == args.len() {
+                        if export {
+                            interpreter.list_exported_functions();
+                        } else {
+                            let mut names: Vec<&String> = interpreter.functions.keys().collect();
+                            names.sort();
+                            for name in names {
+                                writeln!(&interpreter.stdout, "declare -f {name}")?;
+                            }
+                        }
+                        return Ok(0);
+                    }
+                    if export {
+                        return Ok(interpreter.mark_functions_exported(
+                            &args[i..],
+                            true,
+                            "declare",
+                        ));
+                    }
+                    let all_defined = args[i..]
+                        .iter()
+                        .all(|name| interpreter.functions.contains_key(name));
+                    return Ok(if all_defined { 0 } else { 1 });
+                }
 
-        if let Some(pos) = expr.rfind("&&") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(if left_val != 0 && right_val != 0 {
-                1
-            } else {
-                0
-            });
-        }
+                let mut status = 0;
+                for arg in &args[i..] {
+                    let (name, value) = match arg.split_once('=') {
+                        Some((name, value)) => (name, Some(value)),
+                        None => (arg.as_str(), None),
+                    };
+                    if !is_assignable_name(name) {
+                        writeln!(
+                            &interpreter.stderr,
+                            "{builtin}: `{arg}': not a valid identifier"
+                        )?;
+                        status = 1;
+                        continue;
+                    }
+                    if value.is_some() && interpreter.readonly.contains(name) {
+                        writeln!(&interpreter.stderr, "{builtin}: {name}: readonly variable")?;
+                        status = 1;
+                        continue;
+                    }
+                    if integer && !nameref {
+                        let name = interpreter.resolve_nameref(name);
+                        interpreter.integer_vars.insert(name);
+                    }
+                    if uninteger {
+                        let name = interpreter.resolve_nameref(name);
+                        interpreter.integer_vars.remove(&name);
+                    }
+                    if associative {
+                        let name = interpreter.resolve_nameref(name);
+                        interpreter.associative_arrays.entry(name).or_default();
+                    }
+                    if indexed && !associative {
+                        let name = interpreter.resolve_nameref(name);
+                        if interpreter.associative_arrays.contains_key(&name) {
+                            writeln!(
+                                &interpreter.stderr,
+                                "{builtin}: {name}: cannot convert associative to indexed array"
+                            )?;
+                            status = 1;
+                            continue;
+                        }
+                        // A scalar of the same name becomes element 0
+                        let scalar = interpreter.variables.remove(&name);
+                        let elements = interpreter.arrays.entry(name).or_default();
+                        if let Some(scalar) = scalar {
+                            elements.entry(0).or_insert(scalar);
+                        }
+                    }
+                    if let Some(value) = value {
+                        if nameref {
+                            if let Err(e) = interpreter.declare_nameref(name, value) {
+                                writeln!(&interpreter.stderr, "{builtin}: {e}")?;
+                                status = 1;
+                            }
+                        } else {
+                            let value = value.to_string();
+                            if let Err(message) = interpreter.set_variable(name, value) {
+                                writeln!(&interpreter.stderr, "{builtin}: {message}")?;
+                                status = 1;
+                            }
+                        }
+                    }
+                    if nameref {
+                        continue;
+                    }
 
-        // Handle comparison operators
-        if let Some(pos) = expr.rfind("<=") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(if left_val <= right_val { 1 } else { 0 });
-        }
-
-        if let Some(pos) = expr.rfind(">=") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(if left_val >= right_val { 1 } else { 0 });
-        }
-
-        if let Some(pos) = expr.rfind("!=") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(if left_val != right_val { 1 } else { 0 });
-        }
-
-        if let Some(pos) = expr.rfind("==") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(if left_val == right_val { 1 } else { 0 });
-        }
-
-        // Handle single character comparison operators (be careful with order)
-        if let Some(pos) = expr.rfind('<') {
-            // Make sure it's not part of <= or <<
-            if pos + 1 >= expr.len()
-                || (expr.chars().nth(pos + 1) != Some('=')
-                    && expr.chars().nth(pos + 1) != Some('<'))
-            {
-                let left = expr[..pos].trim();
-                let right = expr[pos + 1..].trim();
-                let left_val =
-                    self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-                let right_val =
-                    self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-                return Ok(if left_val < right_val { 1 } else { 0 });
-            }
-        }
+                    let name = interpreter.resolve_nameref(name);
+                    if export {
+                        if let Some(value) = interpreter.variables.get(&name) {
+                            unsafe {
+                                env::set_var(&name, value);
+                            }
+                        }
+                    }
+                    if unexport {
+                        unsafe {
+                            env::remove_var(&name);
+                        }
+                    }
+                    if readonly {
+                        interpreter.readonly.insert(name);
+                    }
+                }
+                Ok(status)
+            }
+            "getopts" => {
+                let (Some(optstring), Some(name)) = (args.first(), args.get(1)) else {
+                    writeln!(
+                        &interpreter.stderr,
+                        "getopts: usage: getopts optstring name [arg ...]"
+                    )?;
+                    return Ok(2);
+                };
+                let program = interpreter.args.first().map_or("flash", String::as_str);
+                // Options come from the given arguments, or else the positional parameters
+                let words = if args.len() > 2 {
+                    &args[2..]
+                } else {
+                    interpreter.args.get(1..).unwrap_or_default()
+                };
+                let mut optind = interpreter
+                    .variables
+                    .get("OPTIND")
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .filter(|index| *index > 0)
+                    .unwrap_or(1);
+
+                // Start on the next argument unless a cluster like -abc is half read
+                let word = words
+                    .get(optind - 1)
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                if interpreter.getopts_offset == 0 || interpreter.getopts_offset >= word.len() {
+                    interpreter.getopts_offset = 0;
+                    if !word.starts_with('-') || word == "-" || word == "--" {
+                        if word == "--" {
+                            optind += 1;
+                        }
+                        let assigned = interpreter
+                            .set_variable("OPTIND", optind.to_string())
+                            .and(interpreter.set_variable(name, "?".to_string()));
+                        if let Err(message) = assigned {
+                            writeln!(&interpreter.stderr, "flash: {message}")?;
+                        }
+                        return Ok(1);
+                    }
+                    interpreter.getopts_offset = 1;
+                }
 
-        if let Some(pos) = expr.rfind('>') {
-            // Make sure it's not part of >= or >>
-            if pos + 1 >= expr.len()
-                || (expr.chars().nth(pos + 1) != Some('=')
-                    && expr.chars().nth(pos + 1) != Some('>'))
-            {
-                let left = expr[..pos].trim();
-                let right = expr[pos + 1..].trim();
-                let left_val =
-                    self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-                let right_val =
-                    self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-                return Ok(if left_val > right_val { 1 } else { 0 });
-            }
-        }
+                let letter = word[interpreter.getopts_offset..]
+                    .chars()
+                    .next()
+                    .unwrap_or_default();
+                interpreter.getopts_offset += letter.len_utf8();
+                let rest = &word[interpreter.getopts_offset..];
+                if rest.is_empty() {
+                    // The cluster is used up, so the next call reads the next argument
+                    interpreter.getopts_offset = 0;
+                    optind += 1;
+                }
 
-        // Addition and subtraction
-        if let Some(pos) = expr.rfind('+') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(left_val + right_val);
-        }
-
-        if let Some(pos) = expr.rfind('-') {
-            // Make sure it's not a negative number at the start
-            if pos > 0 {
-                let left = expr[..pos].trim();
-                let right = expr[pos + 1..].trim();
-                let left_val =
-                    self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-                let right_val =
-                    self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-                return Ok(left_val - right_val);
-            }
-        }
-
-        // Multiplication, division, and modulo (higher precedence)
-        if let Some(pos) = expr.rfind('*') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            return Ok(left_val * right_val);
-        }
-
-        if let Some(pos) = expr.rfind('/') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            if right_val == 0 {
-                return Err("division by zero".to_string());
-            }
-            return Ok(left_val / right_val);
-        }
+                let mut optarg = None;
+                // A leading colon reports errors through name and OPTARG instead of stderr
+                let (silent, optstring) = match optstring.strip_prefix(':') {
+                    Some(optstring) => (true, optstring),
+                    None => (false, optstring.as_str()),
+                };
+                let spec = optstring.find(letter).filter(|_| letter != ':');
+                let takes_argument = spec.is_some_and(|i| optstring[i + 1..].starts_with(':'));
+                let value = if spec.is_none() {
+                    if silent {
+                        optarg = Some(letter.to_string());
+                    } else {
+                        writeln!(&interpreter.stderr, "{program}: illegal option -- {letter}")?;
+                    }
+                    "?".to_string()
+                } else if takes_argument {
+                    // The argument is the rest of this word, or else the next word
+                    let argument = if !rest.is_empty() {
+                        interpreter.getopts_offset = 0;
+                        optind += 1;
+                        Some(rest.to_string())
+                    } else {
+                        optind += 1;
+                        words.get(optind - 2).cloned()
+                    };
+                    match argument {
+                        Some(argument) => {
+                            optarg = Some(argument);
+                            letter.to_string()
+                        }
+                        None if silent => {
+                            optind -= 1;
+                            optarg = Some(letter.to_string());
+                            ":".to_string()
+                        }
+                        None => {
+                            optind -= 1;
+                            writeln!(
+                                &interpreter.stderr,
+                                "{program}: option requires an argument -- {letter}"
+                            )?;
+                            "?".to_string()
+                        }
+                    }
+                } else {
+                    letter.to_string()
+                };
 
-        if let Some(pos) = expr.rfind('%') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val =
-                self.evaluate_arithmetic_expression_with_assignment(left, interpreter)?;
-            let right_val =
-                self.evaluate_arithmetic_expression_with_assignment(right, interpreter)?;
-            if right_val == 0 {
-                return Err("division by zero".to_string());
+                let mut assigned = interpreter.check_writable("OPTARG");
+                if assigned.is_ok() {
+                    match optarg {
+                        Some(optarg) => assigned = interpreter.set_variable("OPTARG", optarg),
+                        None => {
+                            interpreter.variables.remove("OPTARG");
+                        }
+                    }
+                }
+                let assigned = assigned
+                    .and(interpreter.set_variable("OPTIND", optind.to_string()))
+                    .and(interpreter.set_variable(name, value));
+                if let Err(message) = assigned {
+                    writeln!(&interpreter.stderr, "flash: {message}")?;
+                    return Ok(1);
+                }
+                Ok(0)
             }
-            return Ok(left_val % right_val);
-        }
-
-        // Handle parentheses
-        if expr.starts_with('(') && expr.ends_with(')') {
-            let inner = &expr[1..expr.len() - 1];
-            return self.evaluate_arithmetic_expression_with_assignment(inner, interpreter);
-        }
+            "read" => {
+                let mut raw = false;
+                let mut prompt = None;
+                let mut i = 0;
+                while let Some(arg) = args.get(i) {
+                    if arg == "--" {
+                        i += 1;
+                        break;
+                    }
+                    if !arg.starts_with('-') || arg.len() == 1 {
+                        break;
+                    }
+                    i += 1;
+                    for (offset, flag) in arg[1..].char_indices() {
+                        match flag {
+                            'r' => raw = true,
+                            'p' => {
+                                // The prompt is the rest of this argument or the next one
+                                prompt = match &arg[offset + 2..] {
+                                    "" => {
+                                        i += 1;
+                                        match args.get(i - 1) {
+                                            Some(value) => Some(value.clone()),
+                                            None => {
+                                                writeln!(
+                                                    &interpreter.stderr,
+                                                    "read: -p: option requires an argument"
+                                                )?;
+                                                return Ok(2);
+                                            }
+                                        }
+                                    }
+                                    rest => Some(rest.to_string()),
+                                };
+                                break;
+                            }
+                            _ => {
+                                writeln!(&interpreter.stderr, "read: -{flag}: invalid option")?;
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "read: usage: read [-r] [-p prompt] [name ...]"
+                                )?;
+                                return Ok(2);
+                            }
+                        }
+                    }
+                }
 
-        // If we can't parse it, return an error
-        Err(format!("invalid arithmetic expression: {expr}"))
-    }
+                let names = &args[i..];
+                if let Some(invalid) = names.iter().find(|name| !is_assignable_name(name)) {
+                    writeln!(
+                        &interpreter.stderr,
+                        "read: `{invalid}': not a valid identifier"
+                    )?;
+                    return Ok(1);
+                }
 
-    pub fn evaluate_arithmetic_expression(expr: &str) -> Result<i64, String> {
-        // Enhanced arithmetic expression evaluator
-        // Handles +, -, *, /, %, comparison operators, logical operators, and parentheses
+                // Like bash, the prompt is only shown when reading from a terminal, or
+                // from the host that stands in for one
+                if let Some(prompt) = prompt {
+                    if interpreter.reads_host_input() || unsafe { libc::isatty(0) } == 1 {
+                        write!(&interpreter.stderr, "{prompt}")?;
+                        (&interpreter.stderr).flush()?;
+                    }
+                }
 
-        let expr = expr.trim();
-        if expr.is_empty() {
-            return Ok(0);
-        }
+                // Each character is kept with whether a backslash escaped it; without -r
+                // a backslash-newline continues the line onto the next one
+                let mut line: Vec<(char, bool)> = Vec::new();
+                let mut complete = false;
+                while let Some(bytes) = interpreter.read_input_delimited(b'\n')? {
+                    let text = String::from_utf8_lossy(&bytes).into_owned();
+                    let text = text.strip_suffix('\n').unwrap_or(&text);
+                    complete = bytes.last() == Some(&b'\n');
+                    let mut chars = text.chars();
+                    let mut continued = false;
+                    while let Some(c) = chars.next() {
+                        if c == '\\' && !raw {
+                            match chars.next() {
+                                Some(escaped) => line.push((escaped, true)),
+                                None => continued = complete,
+                            }
+                        } else {
+                            line.push((c, false));
+                        }
+                    }
+                    if !continued {
+                        break;
+                    }
+                }
 
-        // Remove any trailing ')' that might be left from parsing issues
-        let expr = expr.trim_end_matches(')');
+                // Without names the whole line goes to REPLY, leaving its whitespace intact
+                let mut assigned = Ok(());
+                if names.is_empty() {
+                    let reply = line.iter().map(|&(c, _)| c).collect();
+                    assigned = interpreter.set_variable("REPLY", reply);
+                }
+                let fields = interpreter.split_read_fields(&line, names.len());
+                for (index, name) in names.iter().enumerate() {
+                    let value = fields.get(index).cloned().unwrap_or_default();
+                    assigned = assigned.and(interpreter.set_variable(name, value));
+                }
+                if let Err(message) = assigned {
+                    writeln!(&interpreter.stderr, "flash: {message}")?;
+                    return Ok(1);
+                }
 
-        // Handle simple number
-        if let Ok(num) = expr.parse::<i64>() {
-            return Ok(num);
-        }
+                // End of input before a newline fails, which ends `while read` loops
+                Ok(if complete { 0 } else { 1 })
+            }
+            "mapfile" | "readarray" => {
+                let mut delimiter = b'\n';
+                let mut trim = false;
+                let mut count = 0;
+                let mut origin = None;
+                let mut skip = 0;
+                let mut fd = 0;
+                let mut callback = None;
+                let mut quantum = 5000;
+                let mut i = 0;
+                while let Some(arg) = args.get(i) {
+                    i += 1;
+                    if arg == "--" {
+                        break;
+                    }
+                    if !arg.starts_with('-') || arg.len() == 1 {
+                        i -= 1;
+                        break;
+                    }
+                    for (offset, flag) in arg[1..].char_indices() {
+                        if flag == 't' {
+                            trim = true;
+                            continue;
+                        }
+                        if !"dnOsuCc".contains(flag) {
+                            writeln!(&interpreter.stderr, "{name}: -{flag}: invalid option")?;
+                            writeln!(
+                                &interpreter.stderr,
+                                "{name}: usage: {name} [-d delim] [-n count] [-O origin] [-s count] [-t] [-u fd] [-C callback] [-c quantum] [array]"
+                            )?;
+                            return Ok(2);
+                        }
+                        // The option's value is the rest of this argument or the next one
+                        let value = match &arg[offset + 2..] {
+                            "" => {
+                                i += 1;
+                                match args.get(i - 1) {
+                                    Some(value) => value.as_str(),
+                                    None => {
+                                        writeln!(
+                                            &interpreter.stderr,
+                                            "{name}: -{flag}: option requires an argument"
+                                        )?;
+                                        return Ok(2);
+                                    }
+                                }
+                            }
+                            rest => rest,
+                        };
+                        let number = value.parse::<usize>();
+                        match (flag, number) {
+                            ('d', _) => delimiter = value.bytes().next().unwrap_or(0),
+                            ('C', _) => callback = Some(value.to_string()),
+                            ('n', Ok(n)) => count = n,
+                            ('O', Ok(n)) => origin = Some(n),
+                            ('s', Ok(n)) => skip = n,
+                            ('u', Ok(n)) => fd = n as i32,
+                            ('c', Ok(n)) if n > 0 => quantum = n,
+                            ('c', _) => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "{name}: {value}: invalid callback quantum"
+                                )?;
+                                return Ok(1);
+                            }
+                            ('O', Err(_)) => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "{name}: {value}: invalid array origin"
+                                )?;
+                                return Ok(1);
+                            }
+                            ('u', Err(_)) => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "{name}: {value}: invalid file descriptor specification"
+                                )?;
+                                return Ok(1);
+                            }
+                            _ => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "{name}: {value}: invalid line count"
+                                )?;
+                                return Ok(1);
+                            }
+                        }
+                        break;
+                    }
+                }
 
-        // Handle variable names (should be already expanded by expand_variables)
-        // If it's a single word that's not a number, treat it as 0 (unset variable)
-        if expr.chars().all(|c| c.is_alphanumeric() || c == '_') {
-            // This should not happen if variables are properly expanded
-            // But if it does, treat as 0
-            return Ok(0);
-        }
+                let array = args.get(i).map_or("MAPFILE", String::as_str);
+                if !is_assignable_name(array) || array.contains('[') {
+                    writeln!(
+                        &interpreter.stderr,
+                        "{name}: `{array}': not a valid identifier"
+                    )?;
+                    return Ok(1);
+                }
+                let array = interpreter.resolve_nameref(array);
+                if let Err(message) = interpreter.check_writable(&array) {
+                    writeln!(&interpreter.stderr, "{name}: {message}")?;
+                    return Ok(1);
+                }
 
-        // Handle logical operators (lowest precedence)
-        if let Some(pos) = expr.rfind("||") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(if left_val != 0 || right_val != 0 {
-                1
-            } else {
-                0
-            });
-        }
+                // Without -O the array is emptied before reading
+                if origin.is_none() {
+                    interpreter.clear_array(&array);
+                }
+                if !interpreter.associative_arrays.contains_key(&array) {
+                    interpreter.arrays.entry(array.clone()).or_default();
+                }
 
-        if let Some(pos) = expr.rfind("&&") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(if left_val != 0 && right_val != 0 {
-                1
-            } else {
-                0
-            });
-        }
+                let mut index = origin.unwrap_or(0);
+                let mut lines_read = 0;
+                while count == 0 || lines_read < count + skip {
+                    let Some(mut line) = read_delimited(fd, delimiter)? else {
+                        break;
+                    };
+                    lines_read += 1;
+                    if lines_read <= skip {
+                        continue;
+                    }
+                    if trim && line.last() == Some(&delimiter) {
+                        line.pop();
+                    }
+                    let line = String::from_utf8_lossy(&line).into_owned();
 
-        // Handle comparison operators
-        if let Some(pos) = expr.rfind("<=") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(if left_val <= right_val { 1 } else { 0 });
-        }
+                    // The callback runs every `quantum` lines, before the element is assigned
+                    if let Some(callback) = &callback {
+                        if (lines_read - skip) % quantum == 0 {
+                            let quoted = line.replace('\'', "'\\''");
+                            let _ = interpreter.execute(&format!("{callback} {index} '{quoted}'"));
+                        }
+                    }
+                    if let Err(message) =
+                        interpreter.set_array_element(&array, &index.to_string(), line)
+                    {
+                        writeln!(&interpreter.stderr, "{name}: {message}")?;
+                        return Ok(1);
+                    }
+                    index += 1;
+                }
+                Ok(0)
+            }
+            "wait" => {
+                if args.is_empty() {
+                    while let Some(index) = interpreter.jobs.iter().position(|job| !job.inherited) {
+                        interpreter.wait_for_job(index);
+                    }
+                    return Ok(0);
+                }
 
-        if let Some(pos) = expr.rfind(">=") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(if left_val >= right_val { 1 } else { 0 });
-        }
+                let mut status = 0;
+                for arg in args {
+                    let index = if arg.starts_with('%') {
+                        match interpreter.resolve_job_spec(arg) {
+                            Ok(index) => Some(index),
+                            Err(e) => {
+                                writeln!(&interpreter.stderr, "wait: {e}")?;
+                                status = 127;
+                                continue;
+                            }
+                        }
+                    } else {
+                        arg.parse::<i32>()
+                            .ok()
+                            .and_then(|pid| interpreter.jobs.iter().position(|job| job.pid == pid))
+                    };
+                    status = match index.filter(|&index| !interpreter.jobs[index].inherited) {
+                        Some(index) => interpreter.wait_for_job(index),
+                        None => {
+                            writeln!(
+                                &interpreter.stderr,
+                                "wait: pid {arg} is not a child of this shell"
+                            )?;
+                            127
+                        }
+                    };
+                }
+                Ok(status)
+            }
+            "jobs" => {
+                let mut long = false;
+                let mut pids_only = false;
+                let mut stopped_only = false;
+                let mut i = 0;
+                while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+                    for flag in args[i][1..].chars() {
+                        match flag {
+                            'l' => long = true,
+                            'p' => pids_only = true,
+                            'r' => stopped_only = false,
+                            's' => stopped_only = true,
+                            _ => {
+                                writeln!(&interpreter.stderr, "jobs: -{flag}: invalid option")?;
+                                return Ok(2);
+                            }
+                        }
+                    }
+                    i += 1;
+                }
 
-        if let Some(pos) = expr.rfind("!=") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(if left_val != right_val { 1 } else { 0 });
-        }
+                // Finished jobs are reported once, then dropped from the table
+                for notice in interpreter.reap_jobs() {
+                    if !pids_only && !stopped_only {
+                        writeln!(&interpreter.stdout, "{notice}")?;
+                    }
+                }
 
-        if let Some(pos) = expr.rfind("==") {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 2..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(if left_val == right_val { 1 } else { 0 });
-        }
+                let mut status = 0;
+                let indices: Vec<usize> = if i == args.len() {
+                    (0..interpreter.jobs.len()).collect()
+                } else {
+                    args[i..]
+                        .iter()
+                        .filter_map(|spec| match interpreter.resolve_job_spec(spec) {
+                            Ok(index) => Some(index),
+                            Err(e) => {
+                                let _ = writeln!(&interpreter.stderr, "jobs: {e}");
+                                status = 1;
+                                None
+                            }
+                        })
+                        .collect()
+                };
 
-        // Handle single character comparison operators (be careful with order)
-        if let Some(pos) = expr.rfind('<') {
-            // Make sure it's not part of <= or <<
-            if pos + 1 >= expr.len()
-                || (expr.chars().nth(pos + 1) != Some('=')
-                    && expr.chars().nth(pos + 1) != Some('<'))
-            {
-                let left = expr[..pos].trim();
-                let right = expr[pos + 1..].trim();
-                let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-                let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-                return Ok(if left_val < right_val { 1 } else { 0 });
+                // Background jobs are never stopped, so every remaining job is running
+                if stopped_only {
+                    return Ok(status);
+                }
+                let count = interpreter.jobs.len();
+                for index in indices {
+                    let job = &interpreter.jobs[index];
+                    let marker = match count - index {
+                        1 => '+',
+                        2 => '-',
+                        _ => ' ',
+                    };
+                    if pids_only {
+                        writeln!(&interpreter.stdout, "{}", job.pid)?;
+                    } else if long {
+                        writeln!(
+                            &interpreter.stdout,
+                            "[{}]{marker} {} {:<24}{} &",
+                            job.id, job.pid, "Running", job.command
+                        )?;
+                    } else {
+                        writeln!(
+                            &interpreter.stdout,
+                            "[{}]{marker}  {:<24}{} &",
+                            job.id, "Running", job.command
+                        )?;
+                    }
+                }
+                Ok(status)
             }
-        }
+            "fg" | "bg" => {
+                let spec = args.first().map_or("%+", String::as_str);
+                let index = match interpreter.resolve_job_spec(spec) {
+                    Ok(index) => index,
+                    Err(e) => {
+                        writeln!(&interpreter.stderr, "{name}: {e}")?;
+                        return Ok(1);
+                    }
+                };
 
-        if let Some(pos) = expr.rfind('>') {
-            // Make sure it's not part of >= or >>
-            if pos + 1 >= expr.len()
-                || (expr.chars().nth(pos + 1) != Some('=')
-                    && expr.chars().nth(pos + 1) != Some('>'))
-            {
-                let left = expr[..pos].trim();
-                let right = expr[pos + 1..].trim();
-                let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-                let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-                return Ok(if left_val > right_val { 1 } else { 0 });
-            }
-        }
-
-        // Handle assignment operators
-        if let Some(pos) = expr.rfind('=') {
-            // Make sure it's not part of == or != or >= or <=
-            if pos > 0 && pos + 1 < expr.len() {
-                let prev_char = expr.chars().nth(pos - 1);
-                let next_char = expr.chars().nth(pos + 1);
-                if prev_char != Some('!')
-                    && prev_char != Some('<')
-                    && prev_char != Some('>')
-                    && next_char != Some('=')
-                {
-                    // This is an assignment, not a comparison
-                    let _left = expr[..pos].trim();
-                    let right = expr[pos + 1..].trim();
-                    let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-                    // For now, just return the right value
-                    // TODO: Actually assign to the variable
-                    return Ok(right_val);
+                let job = interpreter.jobs[index].clone();
+                unsafe { libc::kill(job.pid, libc::SIGCONT) };
+                if name == "bg" {
+                    writeln!(&interpreter.stdout, "[{}]+ {} &", job.id, job.command)?;
+                    return Ok(0);
                 }
+                writeln!(&interpreter.stdout, "{}", job.command)?;
+                Ok(interpreter.wait_for_job(index))
             }
-        }
+            "disown" => {
+                if args.first().is_some_and(|arg| arg == "-a") {
+                    interpreter.jobs.clear();
+                    return Ok(0);
+                }
 
-        // Addition and subtraction
-        if let Some(pos) = expr.rfind('+') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(left_val + right_val);
-        }
+                let specs = if args.is_empty() {
+                    vec!["%+".to_string()]
+                } else {
+                    args.to_vec()
+                };
+                let mut status = 0;
+                for spec in specs {
+                    match interpreter.resolve_job_spec(&spec) {
+                        Ok(index) => {
+                            interpreter.jobs.remove(index);
+                        }
+                        Err(e) => {
+                            writeln!(&interpreter.stderr, "disown: {e}")?;
+                            status = 1;
+                        }
+                    }
+                }
+                Ok(status)
+            }
+            "kill" => {
+                // -l lists the signals, or converts between their names and numbers
+                if args.first().is_some_and(|arg| arg == "-l" || arg == "-L") {
+                    if args.len() == 1 {
+                        print_signal_list(&interpreter.stdout)?;
+                        return Ok(0);
+                    }
+                    let mut status = 0;
+                    for spec in &args[1..] {
+                        let converted = match spec.parse::<i32>() {
+                            Ok(number) => signal_name(number).map(str::to_string),
+                            Err(_) => signal_number(spec).map(|number| number.to_string()),
+                        };
+                        match converted {
+                            Some(value) => writeln!(&interpreter.stdout, "{value}")?,
+                            None => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "kill: {spec}: invalid signal specification"
+                                )?;
+                                status = 1;
+                            }
+                        }
+                    }
+                    return Ok(status);
+                }
 
-        if let Some(pos) = expr.rfind('-') {
-            // Make sure it's not a negative number at the start
-            if pos > 0 {
-                let left = expr[..pos].trim();
-                let right = expr[pos + 1..].trim();
-                let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-                let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-                return Ok(left_val - right_val);
-            }
-        }
+                let mut signal = libc::SIGTERM;
+                let mut i = 0;
+                if args.first().is_some_and(|arg| arg == "-s") {
+                    let Some(number) = args.get(1).and_then(|name| signal_number(name)) else {
+                        writeln!(
+                            &interpreter.stderr,
+                            "kill: {}: invalid signal specification",
+                            args.get(1).map_or("", String::as_str)
+                        )?;
+                        return Ok(1);
+                    };
+                    signal = number;
+                    i = 2;
+                } else if let Some(spec) = args.first().and_then(|arg| arg.strip_prefix('-')) {
+                    let Some(number) = signal_number(spec) else {
+                        writeln!(
+                            &interpreter.stderr,
+                            "kill: {spec}: invalid signal specification"
+                        )?;
+                        return Ok(1);
+                    };
+                    signal = number;
+                    i = 1;
+                }
 
-        // Multiplication, division, and modulo (higher precedence)
-        if let Some(pos) = expr.rfind('*') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            return Ok(left_val * right_val);
-        }
+                if i >= args.len() {
+                    writeln!(
+                        &interpreter.stderr,
+                        "kill: usage: kill [-s sigspec | -sigspec] pid | jobspec ..."
+                    )?;
+                    return Ok(2);
+                }
 
-        if let Some(pos) = expr.rfind('/') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            if right_val == 0 {
-                return Err("division by zero".to_string());
-            }
-            return Ok(left_val / right_val);
-        }
+                let mut status = 0;
+                for target in &args[i..] {
+                    let pid = if target.starts_with('%') {
+                        match interpreter.resolve_job_spec(target) {
+                            Ok(index) => interpreter.jobs[index].pid,
+                            Err(e) => {
+                                writeln!(&interpreter.stderr, "kill: {e}")?;
+                                status = 1;
+                                continue;
+                            }
+                        }
+                    } else if let Ok(pid) = target.parse::<i32>() {
+                        pid
+                    } else {
+                        writeln!(
+                            &interpreter.stderr,
+                            "kill: {target}: arguments must be process or job IDs"
+                        )?;
+                        status = 1;
+                        continue;
+                    };
 
-        if let Some(pos) = expr.rfind('%') {
-            let left = expr[..pos].trim();
-            let right = expr[pos + 1..].trim();
-            let left_val = DefaultEvaluator::evaluate_arithmetic_expression(left)?;
-            let right_val = DefaultEvaluator::evaluate_arithmetic_expression(right)?;
-            if right_val == 0 {
-                return Err("division by zero".to_string());
+                    if unsafe { libc::kill(pid, signal) } != 0 {
+                        writeln!(
+                            &interpreter.stderr,
+                            "kill: ({pid}) - {}",
+                            io::Error::last_os_error()
+                        )?;
+                        status = 1;
+                    }
+                }
+                Ok(status)
             }
-            return Ok(left_val % right_val);
-        }
+            "source" | "." | "\\." => {
+                if args.is_empty() {
+                    writeln!(&interpreter.stderr, "source: filename argument required")?;
+                    return Ok(1);
+                }
 
-        // Handle parentheses
-        if expr.starts_with('(') && expr.ends_with(')') {
-            let inner = &expr[1..expr.len() - 1];
-            return DefaultEvaluator::evaluate_arithmetic_expression(inner);
-        }
+                let filename = &args[0];
+                match fs::read_to_string(filename) {
+                    Ok(content) => {
+                        // $0 stays the shell's name while BASH_SOURCE tracks the file
+                        interpreter.push_bash_source(filename);
+                        let result = interpreter.execute(&content);
+                        interpreter.pop_bash_source();
+                        result
+                    }
+                    Err(e) => {
+                        writeln!(&interpreter.stderr, "source: {filename}: {e}")?;
+                        Ok(1)
+                    }
+                }
+            }
+            "[" | "test" => {
+                // Built-in test command
+                Self::evaluate_test_command(args, interpreter)
+            }
+            "exit" => {
+                // Built-in exit command
+                let exit_code = if args.is_empty() {
+                    0
+                } else {
+                    args[0].parse::<i32>().unwrap_or(0)
+                };
+                interpreter.last_exit_code = exit_code;
+                interpreter.run_exit_trap();
+                std::process::exit(exit_code);
+            }
+            "trap" => {
+                // Built-in trap command - EXIT and signal conditions
+                let args = match args.first().map(String::as_str) {
+                    Some("--") => &args[1..],
+                    Some("-l") => {
+                        print_signal_list(&interpreter.stdout)?;
+                        return Ok(0);
+                    }
+                    Some("-p") => return interpreter.print_traps(&args[1..]),
+                    Some(option) if option.starts_with('-') && option != "-" => {
+                        writeln!(&interpreter.stderr, "trap: {option}: invalid option")?;
+                        writeln!(
+                            &interpreter.stderr,
+                            "trap: usage: trap [-lp] [[arg] signal_spec ...]"
+                        )?;
+                        return Ok(2);
+                    }
+                    _ => args,
+                };
 
-        // If we can't parse it, return an error
-        Err(format!("invalid arithmetic expression: {expr}"))
-    }
+                if args.is_empty() {
+                    return interpreter.print_traps(&[]);
+                }
 
-    fn evaluate_test_command(
-        &mut self,
-        args: &[String],
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Handle the test command ([ and test)
-        // For [ command, the last argument should be "]"
-        let test_args = if !args.is_empty() && args[args.len() - 1] == "]" {
-            &args[..args.len() - 1] // Remove the closing "]"
-        } else {
-            args
-        };
+                // A lone condition, or `-` as the action, resets to the default
+                let (action, conditions) = match args {
+                    [_] => (None, args),
+                    [dash, conditions @ ..] if dash == "-" => (None, conditions),
+                    [action, conditions @ ..] => (Some(action), conditions),
+                    [] => unreachable!(),
+                };
 
-        if test_args.is_empty() {
-            return Ok(1); // Empty test is false
-        }
-
-        // Handle different test operations
-        match test_args.len() {
-            1 => {
-                // Single argument: test if string is non-empty
-                let expanded_arg = interpreter.expand_variables(&test_args[0]);
-                Ok(if expanded_arg.is_empty() { 1 } else { 0 })
-            }
-            3 => {
-                // Three arguments: left operator right
-                let left = interpreter.expand_variables(&test_args[0]);
-                let operator = &test_args[1];
-                let right = interpreter.expand_variables(&test_args[2]);
-
-                match operator.as_str() {
-                    "=" | "==" => Ok(if left == right { 0 } else { 1 }),
-                    "!=" => Ok(if left != right { 0 } else { 1 }),
-                    "-eq" => {
-                        // Numeric equality
-                        match (left.parse::<i64>(), right.parse::<i64>()) {
-                            (Ok(l), Ok(r)) => Ok(if l == r { 0 } else { 1 }),
-                            _ => Ok(1), // Non-numeric values are not equal
-                        }
+                let mut status = 0;
+                for condition in conditions {
+                    let Some(name) = Interpreter::trap_condition_name(condition) else {
+                        writeln!(
+                            &interpreter.stderr,
+                            "trap: {condition}: invalid signal specification"
+                        )?;
+                        status = 1;
+                        continue;
+                    };
+                    match action {
+                        Some(command) => interpreter.set_trap(name, command),
+                        None => interpreter.reset_trap(name),
                     }
-                    "-ne" => {
-                        // Numeric inequality
-                        match (left.parse::<i64>(), right.parse::<i64>()) {
-                            (Ok(l), Ok(r)) => Ok(if l != r { 0 } else { 1 }),
-                            _ => Ok(0), // Non-numeric values are not equal
-                        }
+                }
+                Ok(status)
+            }
+            "set" => {
+                // Built-in set command - toggle shell options and set positional parameters
+                let mut i = 0;
+                while i < args.len() {
+                    let arg = &args[i];
+                    let enable = arg.starts_with('-');
+                    if arg == "--" || (!enable && !arg.starts_with('+')) {
+                        // Remaining arguments replace the positional parameters
+                        let first = if arg == "--" { i + 1 } else { i };
+                        let zero = interpreter.args.first().cloned().unwrap_or_default();
+                        let mut positional = vec![zero];
+                        positional.extend_from_slice(&args[first..]);
+                        interpreter.args = positional;
+                        break;
                     }
-                    "-lt" => {
-                        // Numeric less than
-                        match (left.parse::<i64>(), right.parse::<i64>()) {
-                            (Ok(l), Ok(r)) => Ok(if l < r { 0 } else { 1 }),
-                            _ => Ok(1),
+
+                    for flag in arg[1..].chars() {
+                        match flag {
+                            'e' => interpreter.set_shell_option("errexit", enable),
+                            'x' => interpreter.set_shell_option("xtrace", enable),
+                            // -o takes the option name from the next argument, as in -eo pipefail
+                            'o' => {
+                                i += 1;
+                                let Some(option) = args.get(i) else {
+                                    for option in Interpreter::SHELL_OPTIONS {
+                                        let state = if interpreter.shell_options.contains(*option) {
+                                            "on"
+                                        } else {
+                                            "off"
+                                        };
+                                        writeln!(&interpreter.stdout, "{option:<15}\t{state}")?;
+                                    }
+                                    return Ok(0);
+                                };
+                                if !Interpreter::SHELL_OPTIONS.contains(&option.as_str()) {
+                                    writeln!(
+                                        &interpreter.stderr,
+                                        "set: {option}: invalid option name"
+                                    )?;
+                                    return Ok(2);
+                                }
+                                interpreter.set_shell_option(option, enable);
+                            }
+                            _ => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "set: {}{flag}: invalid option",
+                                    &arg[..1]
+                                )?;
+                                return Ok(2);
+                            }
                         }
                     }
-                    "-le" => {
-                        // Numeric less than or equal
-                        match (left.parse::<i64>(), right.parse::<i64>()) {
-                            (Ok(l), Ok(r)) => Ok(if l <= r { 0 } else { 1 }),
-                            _ => Ok(1),
+                    i += 1;
+                }
+                Ok(0)
+            }
+            "shopt" => {
+                let mut set = None;
+                let mut quiet = false;
+                let mut reusable = false;
+                let mut i = 0;
+                while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+                    for flag in args[i][1..].chars() {
+                        match flag {
+                            's' => set = Some(true),
+                            'u' => set = Some(false),
+                            'q' => quiet = true,
+                            'p' => reusable = true,
+                            _ => {
+                                writeln!(&interpreter.stderr, "shopt: -{flag}: invalid option")?;
+                                return Ok(2);
+                            }
                         }
                     }
-                    "-gt" => {
-                        // Numeric greater than
-                        match (left.parse::<i64>(), right.parse::<i64>()) {
-                            (Ok(l), Ok(r)) => Ok(if l > r { 0 } else { 1 }),
-                            _ => Ok(1),
-                        }
+                    i += 1;
+                }
+
+                let names: Vec<&str> = if i == args.len() {
+                    Interpreter::SHOPT_OPTIONS.to_vec()
+                } else {
+                    args[i..].iter().map(String::as_str).collect()
+                };
+                let mut status = 0;
+                for name in names {
+                    if !Interpreter::SHOPT_OPTIONS.contains(&name) {
+                        writeln!(
+                            &interpreter.stderr,
+                            "shopt: {name}: invalid shell option name"
+                        )?;
+                        status = 1;
+                        continue;
                     }
-                    "-ge" => {
-                        // Numeric greater than or equal
-                        match (left.parse::<i64>(), right.parse::<i64>()) {
-                            (Ok(l), Ok(r)) => Ok(if l >= r { 0 } else { 1 }),
-                            _ => Ok(1),
+                    let enabled = interpreter.shell_options.contains(name);
+                    match set {
+                        // Setting options by name, or listing the ones in that state
+                        Some(enable) if i < args.len() => {
+                            interpreter.set_shell_option(name, enable);
+                        }
+                        Some(enable) if enable != enabled => {}
+                        _ => {
+                            if !enabled && i < args.len() {
+                                status = 1;
+                            }
+                            if quiet {
+                                continue;
+                            }
+                            let state = if enabled { "on" } else { "off" };
+                            if reusable {
+                                let flag = if enabled { 's' } else { 'u' };
+                                writeln!(&interpreter.stdout, "shopt -{flag} {name}")?;
+                            } else {
+                                writeln!(&interpreter.stdout, "{name:<15}\t{state}")?;
+                            }
                         }
                     }
-                    _ => Ok(1), // Unknown operator
-                }
-            }
-            2 => {
-                // Two arguments: unary operator
-                let operator = &test_args[0];
-                let operand = interpreter.expand_variables(&test_args[1]);
-
-                match operator.as_str() {
-                    "-n" => Ok(if !operand.is_empty() { 0 } else { 1 }), // String is non-empty
-                    "-z" => Ok(if operand.is_empty() { 0 } else { 1 }),  // String is empty
-                    "-f" => {
-                        // File exists and is a regular file
-                        let path = Path::new(&operand);
-                        Ok(if path.is_file() { 0 } else { 1 })
-                    }
-                    "-d" => {
-                        // File exists and is a directory
-                        let path = Path::new(&operand);
-                        Ok(if path.is_dir() { 0 } else { 1 })
-                    }
-                    "-e" => {
-                        // File exists
-                        let path = Path::new(&operand);
-                        Ok(if path.exists() { 0 } else { 1 })
-                    }
-                    "-r" => {
-                        // File is readable
-                        let path = Path::new(&operand);
-                        Ok(if path.exists() && fs::metadata(path).is_ok() {
-                            0
+                }
+                Ok(status)
+            }
+            "true" => {
+                // Built-in true command
+                Ok(0)
+            }
+            "false" => {
+                // Built-in false command
+                Ok(1)
+            }
+            "seq" => {
+                // Built-in seq command - generate sequence of numbers
+                match args.len() {
+                    1 => {
+                        // seq LAST - from 1 to LAST
+                        if let Ok(last) = args[0].parse::<i32>() {
+                            for i in 1..=last {
+                                writeln!(&interpreter.stdout, "{i}")?;
+                            }
+                            Ok(0)
                         } else {
-                            1
-                        })
+                            writeln!(&interpreter.stderr, "seq: invalid number: {}", args[0])?;
+                            Ok(1)
+                        }
                     }
-                    "-w" => {
-                        // File is writable
-                        let path = Path::new(&operand);
-                        Ok(
-                            if path.exists()
-                                && fs::metadata(path).is_ok_and(|m| !m.permissions().readonly())
-                            {
-                                0
+                    2 => {
+                        // seq FIRST LAST
+                        if let (Ok(first), Ok(last)) =
+                            (args[0].parse::<i32>(), args[1].parse::<i32>())
+                        {
+                            if first <= last {
+                                for i in first..=last {
+                                    writeln!(&interpreter.stdout, "{i}")?;
+                                }
                             } else {
-                                1
-                            },
-                        )
+                                for i in (last..=first).rev() {
+                                    writeln!(&interpreter.stdout, "{i}")?;
+                                }
+                            }
+                            Ok(0)
+                        } else {
+                            writeln!(&interpreter.stderr, "seq: invalid number arguments")?;
+                            Ok(1)
+                        }
+                    }
+                    3 => {
+                        // seq FIRST INCREMENT LAST
+                        if let (Ok(first), Ok(increment), Ok(last)) = (
+                            args[0].parse::<i32>(),
+                            args[1].parse::<i32>(),
+                            args[2].parse::<i32>(),
+                        ) {
+                            if increment == 0 {
+                                writeln!(&interpreter.stderr, "seq: increment cannot be zero")?;
+                                Ok(1)
+                            } else if increment > 0 && first <= last {
+                                let mut i = first;
+                                while i <= last {
+                                    writeln!(&interpreter.stdout, "{i}")?;
+                                    i += increment;
+                                }
+                                Ok(0)
+                            } else if increment < 0 && first >= last {
+                                let mut i = first;
+                                while i >= last {
+                                    writeln!(&interpreter.stdout, "{i}")?;
+                                    i += increment;
+                                }
+                                Ok(0)
+                            } else {
+                                // No output for invalid range
+                                Ok(0)
+                            }
+                        } else {
+                            writeln!(&interpreter.stderr, "seq: invalid number arguments")?;
+                            Ok(1)
+                        }
+                    }
+                    _ => {
+                        writeln!(&interpreter.stderr, "seq: wrong number of arguments")?;
+                        Ok(1)
+                    }
+                }
+            }
+            "alias" => {
+                if args.is_empty() {
+                    // List all aliases
+                    for (name, value) in &interpreter.aliases {
+                        writeln!(&interpreter.stdout, "alias {name}='{value}'")?;
                     }
-                    "-x" => {
-                        // File is executable
-                        let path = Path::new(&operand);
-                        #[cfg(unix)]
+                    Ok(0)
+                } else {
+                    // Join all arguments back together to handle cases like: alias ll="ls -la"
+                    let full_arg = args.join(" ");
+                    if let Some(eq_pos) = full_arg.find('=') {
+                        // Define alias: alias name=value
+                        let name = full_arg[..eq_pos].trim().to_string();
+                        if interpreter.shell_options.contains("posix")
+                            && !is_portable_alias_name(&name)
                         {
-                            Ok(
-                                if path.exists()
-                                    && fs::metadata(path)
-                                        .is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
-                                {
-                                    0
-                                } else {
-                                    1
-                                },
-                            )
+                            writeln!(&interpreter.stderr, "alias: `{name}': invalid alias name")?;
+                            return Ok(1);
                         }
-                        #[cfg(not(unix))]
+                        let mut value = full_arg[eq_pos + 1..].to_string();
+
+                        // Remove surrounding quotes if present and escape spaces
+                        if (value.starts_with('"') && value.ends_with('"'))
+                            || (value.starts_with('\'') && value.ends_with('\''))
                         {
-                            Ok(if path.exists() { 0 } else { 1 })
+                            value = value[1..value.len() - 1].to_string();
+                        }
+
+                        // Trim any leading/trailing whitespace
+                        let value = value.trim().to_string();
+
+                        interpreter.aliases.insert(name, value);
+                        Ok(0)
+                    } else if args.len() == 1 {
+                        // Show specific alias
+                        let name = &args[0];
+                        if let Some(value) = interpreter.aliases.get(name) {
+                            writeln!(&interpreter.stdout, "alias {name}='{value}'")?;
+                            Ok(0)
+                        } else {
+                            writeln!(&interpreter.stderr, "alias: {name}: not found")?;
+                            Ok(1)
                         }
+                    } else {
+                        writeln!(
+                            &interpreter.stderr,
+                            "alias: usage: alias [name[=value] ...]"
+                        )?;
+                        Ok(1)
                     }
-                    "-s" => {
-                        // File exists and has size > 0
-                        let path = Path::new(&operand);
-                        Ok(
-                            if path.exists() && fs::metadata(path).is_ok_and(|m| m.len() > 0) {
-                                0
-                            } else {
-                                1
-                            },
-                        )
+                }
+            }
+            "unalias" => {
+                if args.is_empty() {
+                    writeln!(
+                        &interpreter.stderr,
+                        "unalias: usage: unalias name [name ...]"
+                    )?;
+                    Ok(1)
+                } else {
+                    let mut success = true;
+                    for name in args {
+                        if interpreter.aliases.remove(name).is_none() {
+                            writeln!(&interpreter.stderr, "unalias: {name}: not found")?;
+                            success = false;
+                        }
                     }
-                    _ => Ok(1), // Unknown unary operator
+                    Ok(if success { 0 } else { 1 })
                 }
             }
-            _ => Ok(1), // Invalid number of arguments
-        }
-    }
+            "complete" => {
+                // With options, register a completion for the named commands
+                if args.first().is_some_and(|arg| arg.starts_with('-')) {
+                    let remove = args[0] == "-r";
+                    let (entry, names) = if remove {
+                        (CompletionEntry::default(), args[1..].to_vec())
+                    } else {
+                        match CompletionEntry::from_args(args) {
+                            Ok(parsed) => parsed,
+                            Err(error) => {
+                                writeln!(&interpreter.stderr, "complete: {error}")?;
+                                return Ok(2);
+                            }
+                        }
+                    };
+                    if names.is_empty() {
+                        writeln!(
+                            &interpreter.stderr,
+                            "complete: usage: complete [-o option] [-A action] [-F function] [-W wordlist] name ..."
+                        )?;
+                        return Ok(2);
+                    }
 
-    fn evaluate_history_expansion(
-        &mut self,
-        pattern: &str,
-        interpreter: &mut Interpreter,
-    ) -> Result<i32, io::Error> {
-        // Check for recursion depth to prevent stack overflow
-        const MAX_HISTORY_RECURSION_DEPTH: u32 = 10;
-        if interpreter.history_expansion_depth >= MAX_HISTORY_RECURSION_DEPTH {
-            eprintln!("flash: history expansion recursion limit exceeded");
-            return Ok(1);
-        }
+                    let completions = &mut interpreter.completion_system.command_completions;
+                    for name in names {
+                        if remove {
+                            completions.remove(&name);
+                        } else {
+                            completions.insert(name, entry.clone());
+                        }
+                    }
+                    return Ok(0);
+                }
 
-        // Handle different history expansion patterns
-        let command = if pattern.is_empty() {
-            // !! - repeat last command
-            interpreter.history.last().cloned()
-        } else if pattern.chars().all(|c| c.is_ascii_digit()) {
-            // !n - repeat command number n (1-based)
-            if let Ok(n) = pattern.parse::<usize>() {
-                if n > 0 && n <= interpreter.history.len() {
-                    Some(interpreter.history[n - 1].clone())
-                } else {
-                    eprintln!("flash: !{pattern}: event not found");
+                // Built-in complete command for testing completion
+                if args.is_empty() {
+                    writeln!(&interpreter.stderr, "Usage: complete <command>")?;
                     return Ok(1);
                 }
-            } else {
-                None
-            }
-        } else if pattern.starts_with('-') && pattern[1..].chars().all(|c| c.is_ascii_digit()) {
-            // !-n - repeat command n positions back from current
-            if let Ok(n) = pattern[1..].parse::<usize>() {
-                let history_len = interpreter.history.len();
-                if n > 0 && n <= history_len {
-                    Some(interpreter.history[history_len - n].clone())
+
+                // The last argument after the command is the word being completed
+                let test_line = if args.len() > 1 {
+                    args.join(" ")
                 } else {
-                    eprintln!("flash: !{pattern}: event not found");
-                    return Ok(1);
+                    format!("{} ", args[0])
+                };
+                let context = CompletionSystem::parse_context(&test_line, test_line.len());
+                let completions = interpreter.completion_system.complete(&context);
+
+                for completion in completions {
+                    writeln!(&interpreter.stdout, "{completion}")?;
                 }
-            } else {
-                None
+                Ok(0)
             }
-        } else {
-            // !string - repeat most recent command starting with string
-            interpreter
-                .history
-                .iter()
-                .rev()
-                .find(|cmd| cmd.starts_with(pattern))
-                .cloned()
-        };
+            "compgen" => {
+                // Print the completions the options generate for an optional word
+                let (entry, operands) = match CompletionEntry::from_args(args) {
+                    Ok(parsed) => parsed,
+                    Err(error) => {
+                        writeln!(&interpreter.stderr, "compgen: {error}")?;
+                        return Ok(2);
+                    }
+                };
+                let word = operands.first().map_or("", String::as_str);
+                let mut context = CompletionSystem::parse_context(word, word.len());
+                context.current_word = word.to_string();
 
-        match command {
-            Some(cmd) => {
-                // Check if the command we're about to execute is itself a history expansion
-                // to prevent immediate infinite recursion
-                if cmd.trim() == format!("!{pattern}") || (pattern.is_empty() && cmd.trim() == "!!")
-                {
-                    eprintln!("flash: history expansion would cause infinite recursion");
-                    return Ok(1);
+                let completions = interpreter.completion_system.generate(&entry, &context);
+                for completion in &completions {
+                    writeln!(&interpreter.stdout, "{completion}")?;
+                }
+                Ok(if completions.is_empty() { 1 } else { 0 })
+            }
+            "command" => {
+                // Built-in command - run a command, bypassing functions and aliases
+                let mut use_default_path = false;
+                let mut describe = None;
+                let mut i = 0;
+                while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+                    if args[i] == "--" {
+                        i += 1;
+                        break;
+                    }
+                    for flag in args[i][1..].chars() {
+                        match flag {
+                            'p' => use_default_path = true,
+                            'v' | 'V' => describe = Some(flag),
+                            _ => {
+                                writeln!(&interpreter.stderr, "command: -{flag}: invalid option")?;
+                                return Ok(2);
+                            }
+                        }
+                    }
+                    i += 1;
                 }
 
-                // Print the command being executed (like bash does)
-                println!("{cmd}");
+                let Some(command_name) = args.get(i) else {
+                    return Ok(0);
+                };
 
-                // Increment recursion depth before executing
-                interpreter.history_expansion_depth += 1;
+                let path = if use_default_path {
+                    Interpreter::DEFAULT_PATH.to_string()
+                } else {
+                    interpreter
+                        .variables
+                        .get("PATH")
+                        .cloned()
+                        .unwrap_or_default()
+                };
 
-                // Parse and execute the command using the correct method
-                let result = interpreter.execute_with_evaluator(&cmd, self);
+                if let Some(flag) = describe {
+                    return Ok(interpreter.describe_command(command_name, &path, flag == 'V'));
+                }
 
-                // Decrement recursion depth after executing
-                interpreter.history_expansion_depth -= 1;
+                if !use_default_path {
+                    return self.evaluate_builtin_or_external(
+                        command_name,
+                        &args[i + 1..],
+                        redirects,
+                        interpreter,
+                    );
+                }
 
+                // Look the command up in the default PATH, then restore the user's PATH
+                let saved_path = interpreter.variables.insert("PATH".to_string(), path);
+                let result = self.evaluate_builtin_or_external(
+                    command_name,
+                    &args[i + 1..],
+                    redirects,
+                    interpreter,
+                );
+                match saved_path {
+                    Some(value) => interpreter.variables.insert("PATH".to_string(), value),
+                    None => interpreter.variables.remove("PATH"),
+                };
                 result
             }
-            None => {
-                eprintln!("flash: !{pattern}: event not found");
-                Ok(1)
+            _ if interpreter.custom_builtins.contains_key(name) => {
+                let command = Arc::clone(&interpreter.custom_builtins[name]);
+                command(interpreter, args)
+            }
+            _ => {
+                // External command
+                let mut command = Command::new(name);
+                command.args(args);
+                if let Err(e) = Self::prepare_external_command(&mut command, redirects, interpreter)
+                {
+                    writeln!(&interpreter.stderr, "{e}")?;
+                    return Ok(1);
+                }
+
+                io::stdout().flush()?;
+                if let Some(input) = interpreter.host_input_file()? {
+                    command.stdin(input);
+                }
+                let pipes = HostPipes::new(interpreter)?;
+                pipes.connect(&mut command, true)?;
+                let child = command.spawn();
+                // Our copies of the pipes' write ends go with the command
+                drop(command);
+                let mut child = match child {
+                    Ok(child) => child,
+                    Err(_) => {
+                        writeln!(&interpreter.stderr, "{name}: command not found")?;
+                        return Ok(127);
+                    }
+                };
+                pipes.forward(interpreter)?;
+                let status = child.wait()?;
+
+                // Update PWD in case the external command changed the working directory
+                if let Ok(current_dir) = env::current_dir() {
+                    interpreter
+                        .variables
+                        .insert("PWD".to_string(), current_dir.to_string_lossy().to_string());
+                }
+                Ok(interpreter.foreground_status(exit_status_code(status)))
             }
         }
     }
-}
 
-/// Shell interpreter
-pub struct Interpreter {
-    pub variables: HashMap<String, String>,
-    pub functions: HashMap<String, Box<Node>>, // Store function definitions
-    pub aliases: HashMap<String, String>,      // Store alias definitions
-    pub last_exit_code: i32,
-    pub history: Vec<String>,
-    pub history_file: Option<String>,
-    pub rc_file: Option<String>,
-    pub args: Vec<String>,         // Command line arguments ($0, $1, $2, ...)
-    pub return_value: Option<i32>, // Track return values from functions
-    pub history_expansion_depth: u32, // Track recursion depth for history expansion
-    pub completion_system: CompletionSystem, // Enhanced completion system
-}
-
-impl Default for Interpreter {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl Interpreter {
-    pub fn new() -> Self {
-        // Initialize some basic environment variables
-        let mut variables = HashMap::default();
-
-        if let Ok(variables_from_proc) = flash::env::load_env_from_proc() {
-            for (key, value) in variables_from_proc.iter() {
-                variables.insert(key.to_owned(), value.to_owned());
-            }
-        } else {
-            // Fallback to std::env if /proc/self/environ is not available (e.g., on macOS)
-            for (key, value) in std::env::vars() {
-                variables.insert(key, value);
+    /// Apply redirections and the shell's variables to an external command
+    fn prepare_external_command(
+        command: &mut Command,
+        redirects: &[Redirect],
+        interpreter: &mut Interpreter,
+    ) -> Result<(), io::Error> {
+        // The child applies the redirections in order after its pipes are connected, so
+        // `2>&1` in a pipeline sends stderr down the pipe and `>out 2>&1` sends both to out
+        let targets = Self::redirect_targets(redirects, interpreter)?;
+        if !targets.is_empty() {
+            unsafe {
+                command.pre_exec(move || {
+                    for (fd, target) in &targets {
+                        let result = match target {
+                            RedirectTarget::File(file) => libc::dup2(file.as_raw_fd(), *fd),
+                            RedirectTarget::Fd(source) => libc::dup2(*source, *fd),
+                            RedirectTarget::Close => {
+                                libc::close(*fd);
+                                0
+                            }
+                        };
+                        if result < 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
             }
         }
 
-        // Set up some shell variables
-        variables.insert("?".to_string(), "0".to_string());
-        variables.insert("SHELL".to_string(), "flash".to_string());
-        variables.insert("$$".to_string(), std::process::id().to_string());
-        variables.insert(
-            "FLASH_VERSION".to_string(),
-            env!("CARGO_PKG_VERSION").to_string(),
-        );
-
-        // Initialize PWD to current working directory
-        if let Ok(current_dir) = env::current_dir() {
-            variables.insert("PWD".to_string(), current_dir.to_string_lossy().to_string());
+        // Set environment variables
+        for (key, value) in &interpreter.variables {
+            command.env(key, value);
         }
+        Ok(())
+    }
 
-        // Add /opt/homebrew/bin to PATH on macOS
-        #[cfg(target_os = "macos")]
-        {
-            if let Some(current_path) = variables.get("PATH") {
-                if !current_path.contains("/opt/homebrew/bin") {
-                    let new_path = format!("/opt/homebrew/bin:{current_path}");
-                    variables.insert("PATH".to_string(), new_path);
+    /// Open the files and resolve the descriptors that `redirects` connect to, in order
+    fn redirect_targets(
+        redirects: &[Redirect],
+        interpreter: &mut Interpreter,
+    ) -> Result<Vec<(i32, RedirectTarget)>, io::Error> {
+        let mut targets = Vec::new();
+        for redirect in redirects {
+            let with_name =
+                |e: io::Error, name: &str| io::Error::new(e.kind(), format!("{name}: {e}"));
+            let mut fd = redirect.fd;
+            let target = match redirect.kind {
+                RedirectKind::Input => {
+                    let path = interpreter.expand_word_unsplit(&redirect.file);
+                    RedirectTarget::File(fs::File::open(&path).map_err(|e| with_name(e, &path))?)
                 }
-            } else {
-                variables.insert("PATH".to_string(), "/opt/homebrew/bin".to_string());
-            }
-        }
-
-        let home_dir = env::var("HOME").ok();
-
-        let history_file = home_dir
-            .as_ref()
-            .map(|home| format!("{home}/.flash_history"));
-
-        let rc_file = home_dir.as_ref().map(|home| format!("{home}/.flashrc"));
-
-        // Load history from file if it exists
-        let mut history = Vec::new();
-        if let Some(ref file_path) = history_file {
-            if let Ok(file) = fs::File::open(file_path) {
-                let reader = io::BufReader::new(file);
-                for line in reader.lines().map_while(Result::ok) {
-                    history.push(line);
+                RedirectKind::Output => {
+                    let path = interpreter.expand_word_unsplit(&redirect.file);
+                    RedirectTarget::File(fs::File::create(&path).map_err(|e| with_name(e, &path))?)
                 }
-            }
-        }
-
-        let mut interpreter = Self {
-            variables,
-            functions: HashMap::new(), // Initialize empty functions map
-            aliases: HashMap::new(),   // Initialize empty aliases map
-            last_exit_code: 0,
-            history,
-            history_file,
-            rc_file,
-            args: Vec::new(), // Initialize empty args, will be set when running scripts
-            return_value: None, // Initialize return value as None
-            history_expansion_depth: 0, // Initialize history expansion depth
-            completion_system: CompletionSystem::new(), // Initialize enhanced completion system
-        };
-
-        // Load and execute flashrc file if it exists
-        if let Err(e) = interpreter.load_rc_file() {
-            eprintln!("Warning: Error loading flashrc: {e}");
-        }
-
-        interpreter
-    }
-
-    /// Set command line arguments for the interpreter
-    /// args[0] should be the script name, args[1] should be $1, etc.
-    pub fn set_args(&mut self, args: Vec<String>) {
-        self.args = args;
-    }
-
-    /// Load and execute the flashrc file
-    fn load_rc_file(&mut self) -> io::Result<()> {
-        if let Some(ref rc_path) = self.rc_file.clone() {
-            if Path::new(rc_path).exists() {
-                match fs::read_to_string(rc_path) {
-                    Ok(content) => {
-                        // Execute the rc file content
-                        // We ignore errors in rc file execution to prevent shell startup failure
-                        if let Err(e) = self.execute(&content) {
-                            eprintln!("Warning: Error executing flashrc: {e}");
-                        }
-                    }
-                    Err(e) => {
-                        return Err(io::Error::other(format!(
-                            "Failed to read flashrc file {rc_path}: {e}"
-                        )));
+                RedirectKind::Append => {
+                    let path = interpreter.expand_word_unsplit(&redirect.file);
+                    let file = fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .map_err(|e| with_name(e, &path))?;
+                    RedirectTarget::File(file)
+                }
+                RedirectKind::HereDoc | RedirectKind::HereDocDash => {
+                    // Here documents - a temporary file with the content, expanded
+                    // unless the delimiter was quoted
+                    let content = if redirect.quoted {
+                        redirect.file.clone()
+                    } else {
+                        interpreter.expand_here_document(&redirect.file)
+                    };
+                    let mut file = tempfile::tempfile()?;
+                    file.write_all(content.as_bytes())?;
+                    file.seek(SeekFrom::Start(0))?;
+                    RedirectTarget::File(file)
+                }
+                RedirectKind::HereString => {
+                    // Here strings - the expanded word and a newline, without field
+                    // splitting or globbing, so newlines in values are kept
+                    let content = interpreter.expand_word_unsplit(&redirect.file);
+                    let mut file = tempfile::tempfile()?;
+                    file.write_all(content.as_bytes())?;
+                    file.write_all(b"\n")?;
+                    file.seek(SeekFrom::Start(0))?;
+                    RedirectTarget::File(file)
+                }
+                RedirectKind::InputDup | RedirectKind::OutputDup => {
+                    let word = interpreter.expand_word_unsplit(&redirect.file);
+                    if word == "-" {
+                        RedirectTarget::Close
+                    } else if let Ok(source) = word.parse::<i32>() {
+                        RedirectTarget::Fd(source)
+                    } else if redirect.kind == RedirectKind::OutputDup && redirect.fd == 1 {
+                        // `>&file` sends both stdout and stderr to the file
+                        let file = fs::File::create(&word).map_err(|e| with_name(e, &word))?;
+                        targets.push((1, RedirectTarget::File(file)));
+                        fd = 2;
+                        RedirectTarget::Fd(1)
+                    } else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("{word}: ambiguous redirect"),
+                        ));
                     }
                 }
-            }
+            };
+            targets.push((fd, target));
         }
-        Ok(())
-    }
-
-    /// Reload the flashrc file (useful for testing changes without restarting)
-    pub fn reload_rc_file(&mut self) -> io::Result<()> {
-        self.load_rc_file()
-    }
-
-    /// Get the path to the flashrc file
-    pub fn get_rc_file_path(&self) -> Option<String> {
-        self.rc_file.clone()
+        Ok(targets)
     }
 
-    /// Set a custom rc file path
-    pub fn set_rc_file_path<P: AsRef<Path>>(&mut self, path: P) {
-        self.rc_file = Some(path.as_ref().to_string_lossy().to_string());
-    }
+    /// Point the shell's own descriptors at the targets of `redirects`, returning the
+    /// saved originals for `restore_redirects`
+    fn apply_redirects(
+        redirects: &[Redirect],
+        interpreter: &mut Interpreter,
+    ) -> Result<Vec<(i32, i32)>, io::Error> {
+        let targets = Self::redirect_targets(redirects, interpreter)?;
+        io::stdout().flush()?;
+
+        let mut saved = Vec::new();
+        for (fd, target) in &targets {
+            match apply_redirect(*fd, target) {
+                Ok(original) => saved.push((*fd, original)),
+                Err(e) => {
+                    Self::undo_redirects(saved, interpreter)?;
+                    return Err(io::Error::new(e.kind(), format!("{fd}: {e}")));
+                }
+            }
 
-    fn save_history(&self) -> io::Result<()> {
-        if let Some(ref file_path) = self.history_file {
-            let mut file = fs::File::create(file_path)?;
-            for line in &self.history {
-                writeln!(file, "{line}")?;
+            // Builtins follow the descriptor to the file, or to the host's writer that
+            // the stream it duplicates writes to
+            if *fd == 0 {
+                interpreter.stdin_redirections += 1;
+            }
+            if let Some(stream) = interpreter.output_stream(*fd) {
+                let writer = match target {
+                    RedirectTarget::Fd(source) => interpreter
+                        .output_stream(*source)
+                        .and_then(OutputStream::target),
+                    _ => None,
+                };
+                stream.redirect(writer);
             }
         }
-        Ok(())
+        Ok(saved)
     }
 
-    // Generate completion candidates for the current input using the enhanced completion system
-    pub fn generate_completions(
-        &mut self,
-        input: &str,
-        cursor_pos: usize,
-    ) -> (Vec<String>, Vec<String>) {
-        // Parse the completion context
-        let context = CompletionSystem::parse_context(input, cursor_pos);
-
-        // Get completions from the enhanced completion system
-        let completions = self.completion_system.complete(&context);
-
-        // Calculate suffixes for the current word
-        let current_word = &context.current_word;
-        let mut suffixes = Vec::new();
-        let mut full_names = Vec::new();
-
-        for completion in completions {
-            if completion.starts_with(current_word) {
-                // Calculate the suffix (what needs to be added)
-                let suffix = &completion[current_word.len()..];
-                suffixes.push(suffix.to_string());
-                full_names.push(completion);
-            } else if current_word.is_empty() {
-                // If current word is empty, the whole completion is the suffix
-                suffixes.push(completion.clone());
-                full_names.push(completion);
+    /// Put back the descriptors saved by `apply_redirects`
+    fn undo_redirects(
+        saved: Vec<(i32, i32)>,
+        interpreter: &mut Interpreter,
+    ) -> Result<(), io::Error> {
+        for (fd, _) in &saved {
+            if *fd == 0 {
+                interpreter.stdin_redirections -= 1;
+            }
+            if let Some(stream) = interpreter.output_stream(*fd) {
+                stream.restore();
             }
         }
-
-        // Fallback to old completion system for variables if new system returns nothing
-        if suffixes.is_empty() {
-            return self.generate_completions_fallback(input, cursor_pos);
-        }
-
-        (suffixes, full_names)
+        restore_redirects(saved)
     }
 
-    // Fallback completion method (the old implementation)
-    fn generate_completions_fallback(
-        &self,
-        input: &str,
-        cursor_pos: usize,
-    ) -> (Vec<String>, Vec<String>) {
-        let input_up_to_cursor = &input[..cursor_pos];
-        let words: Vec<&str> = input_up_to_cursor.split_whitespace().collect();
-
-        // If we're at the beginning of the line or just completed a word
-        if words.is_empty() || input_up_to_cursor.ends_with(' ') {
-            // Return list of available commands
-            let (suffixes, full_names) = self.get_commands("");
-            return (suffixes, full_names);
-        }
-
-        // If we're completing the first word (command)
-        if words.len() == 1 && !input_up_to_cursor.ends_with(' ') {
-            let prefix = words[0];
-            let (suffixes, full_names) = self.get_commands(prefix);
-            return (suffixes, full_names);
-        }
-
-        // Check if we're completing a variable
-        if input_up_to_cursor.ends_with('$') {
-            // Complete variable names
-            let vars: Vec<String> = self.variables.keys().map(|k| format!("${k}")).collect();
-            return (vars.clone(), vars);
-        }
-
-        if let Some(var_start) = input_up_to_cursor.rfind('$') {
-            if var_start < cursor_pos {
-                let var_prefix = &input_up_to_cursor[var_start + 1..cursor_pos];
-                let suffixes: Vec<String> = self
-                    .variables
-                    .keys()
-                    .filter(|k| k.starts_with(var_prefix))
-                    .map(|k| k[var_prefix.len()..].to_string())
-                    .collect();
-                let full_names: Vec<String> = self
-                    .variables
-                    .keys()
-                    .filter(|k| k.starts_with(var_prefix))
-                    .map(|k| format!("${k}"))
-                    .collect();
-                return (suffixes, full_names);
-            }
+    fn evaluate_export(
+        &mut self,
+        name: &str,
+        value: &Option<Box<Node>>,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        if !is_assignable_name(name) {
+            writeln!(
+                &interpreter.stderr,
+                "export: `{name}': not a valid identifier"
+            )?;
+            return Ok(1);
         }
-
-        // Check if we're completing after a pipe (should complete commands)
-        if words.len() >= 2 {
-            // Look for the last pipe in the words
-            let mut last_pipe_index = None;
-            for (i, word) in words.iter().enumerate() {
-                if *word == "|" || *word == "&&" || *word == "||" {
-                    last_pipe_index = Some(i);
-                }
-            }
-
-            if let Some(pipe_index) = last_pipe_index {
-                // If we're right after a pipe or completing the first word after a pipe
-                if pipe_index == words.len() - 2 && !input_up_to_cursor.ends_with(' ') {
-                    // We're completing the command after the pipe
-                    let prefix = words.last().unwrap_or(&"");
-                    let (suffixes, full_names) = self.get_commands(prefix);
-                    return (suffixes, full_names);
-                } else if pipe_index == words.len() - 1 && input_up_to_cursor.ends_with(' ') {
-                    // We just finished typing the pipe and are starting a new command
-                    let (suffixes, full_names) = self.get_commands("");
-                    return (suffixes, full_names);
-                }
-            }
+        if value.is_some() && interpreter.readonly.contains(name) {
+            writeln!(&interpreter.stderr, "flash: {name}: readonly variable")?;
+            return Ok(1);
         }
 
-        // Otherwise, assume we're completing a filename
-        let last_word = if input_up_to_cursor.ends_with(' ') {
-            ""
-        } else {
-            words.last().unwrap_or(&"")
-        };
-
-        let (suffixes, full_names) = self.get_path_completions(last_word);
-        (suffixes, full_names)
-    }
-
-    // Get list of commands that match the given prefix
-    fn get_commands(&self, prefix: &str) -> (Vec<String>, Vec<String>) {
-        let mut suffixes = Vec::new();
-        let mut full_names = Vec::new();
-
-        // Add built-ins
-        for cmd in &[
-            "cd", "echo", "export", "source", ".", "exit", "alias", "unalias",
-        ] {
-            if cmd.starts_with(prefix) {
-                full_names.push(cmd.to_string());
-                if let Some(stripped) = cmd.strip_prefix(prefix) {
-                    suffixes.push(stripped.to_string());
-                }
-            }
-        }
-
-        // Add aliases
-        for alias_name in self.aliases.keys() {
-            if alias_name.starts_with(prefix) {
-                full_names.push(alias_name.to_string());
-                if let Some(stripped) = alias_name.strip_prefix(prefix) {
-                    suffixes.push(stripped.to_string());
-                }
-            }
-        }
-
-        // Add commands from PATH
-        if let Ok(path) = env::var("PATH") {
-            for path_entry in path.split(':') {
-                if let Ok(entries) = fs::read_dir(path_entry) {
-                    for entry in entries.flatten() {
-                        if let Some(name) = entry.file_name().to_str() {
-                            if name.starts_with(prefix) {
-                                if let Some(stripped) = name.strip_prefix(prefix) {
-                                    if let Ok(metadata) = entry.path().metadata() {
-                                        if metadata.is_file()
-                                            && metadata.permissions().mode() & 0o111 != 0
-                                        {
-                                            full_names.push(name.to_string());
-                                            suffixes.push(stripped.to_string());
-                                        }
+        match value {
+            Some(val) => {
+                // Export with assignment: export VAR=value
+                match val.as_ref() {
+                    Node::StringLiteral(string_value) => {
+                        let expanded_value = interpreter.expand_variables(string_value);
+                        interpreter
+                            .variables
+                            .insert(name.to_string(), expanded_value.clone());
+                        if !name.is_empty() {
+                            unsafe {
+                                env::set_var(name, &expanded_value);
+                            }
+                        }
+                    }
+                    Node::SingleQuotedString(string_value) => {
+                        // Single-quoted strings should not have variable expansion
+                        interpreter
+                            .variables
+                            .insert(name.to_string(), string_value.clone());
+                        if !name.is_empty() {
+                            unsafe {
+                                env::set_var(name, string_value);
+                            }
+                        }
+                    }
+                    Node::CommandSubstitution { command } => {
+                        let output = interpreter.capture_command_output(command, self)?;
+                        let trimmed_output = output.trim_end().to_string();
+                        interpreter
+                            .variables
+                            .insert(name.to_string(), trimmed_output.clone());
+                        if !name.is_empty() {
+                            unsafe {
+                                env::set_var(name, &trimmed_output);
+                            }
+                        }
+                    }
+                    Node::ArithmeticExpansion { expression } => {
+                        let expanded_expr = interpreter.expand_parameters(expression);
+                        match arithmetic::evaluate_with(&expanded_expr, interpreter) {
+                            Ok(result) => {
+                                let result_str = result.to_string();
+                                interpreter
+                                    .variables
+                                    .insert(name.to_string(), result_str.clone());
+                                if !name.is_empty() {
+                                    unsafe {
+                                        env::set_var(name, &result_str);
+                                    }
+                                }
+                            }
+                            Err(message) => {
+                                return interpreter.expansion_error(&format!(
+                                    "flash: {expanded_expr}: {message}"
+                                ));
+                            }
+                        }
+                    }
+                    Node::ArithmeticCommand { expression } => {
+                        let expanded_expr = interpreter.expand_parameters(expression);
+                        match arithmetic::evaluate_with(&expanded_expr, interpreter) {
+                            Ok(result) => {
+                                let result_str = result.to_string();
+                                interpreter
+                                    .variables
+                                    .insert(name.to_string(), result_str.clone());
+                                if !name.is_empty() {
+                                    unsafe {
+                                        env::set_var(name, &result_str);
                                     }
                                 }
                             }
+                            Err(message) => {
+                                writeln!(
+                                    &interpreter.stderr,
+                                    "flash: ((: {expanded_expr}: {message}"
+                                )?;
+                                return Ok(1);
+                            }
+                        }
+                    }
+                    Node::Array { elements } => {
+                        // Handle array export - join elements with spaces or use a specific format
+                        let array_value = elements.join(" ");
+                        let expanded_value = interpreter.expand_variables(&array_value);
+                        interpreter
+                            .variables
+                            .insert(name.to_string(), expanded_value.clone());
+                        if !name.is_empty() {
+                            unsafe {
+                                env::set_var(name, &expanded_value);
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(io::Error::other(
+                            "Unsupported value type for export assignment",
+                        ));
+                    }
+                }
+            }
+            None => {
+                // Export without assignment: export VAR
+                // Export existing variable if it exists in the interpreter's variables
+                if let Some(existing_value) = interpreter.variables.get(name) {
+                    if !name.is_empty() {
+                        unsafe {
+                            env::set_var(name, existing_value);
+                        }
+                    }
+                } else {
+                    // If variable doesn't exist in interpreter, check if it exists in environment
+                    if let Ok(env_value) = env::var(name) {
+                        // Store it in interpreter variables for consistency
+                        interpreter
+                            .variables
+                            .insert(name.to_string(), env_value.clone());
+                        if !name.is_empty() {
+                            unsafe {
+                                env::set_var(name, &env_value);
+                            }
                         }
+                    } else {
+                        // Variable doesn't exist anywhere, just add it to interpreter variables
+                        // Don't set empty environment variables as they can cause issues
+                        interpreter
+                            .variables
+                            .insert(name.to_string(), String::new());
                     }
                 }
             }
         }
-
-        // Sort and deduplicate both lists
-        full_names.sort();
-        full_names.dedup();
-        suffixes.sort();
-        suffixes.dedup();
-
-        (suffixes, full_names)
+        Ok(0)
     }
 
-    // Get file/directory completions for the given path prefix
-    fn get_path_completions(&self, prefix: &str) -> (Vec<String>, Vec<String>) {
-        let mut suffixes = Vec::new();
-        let mut full_names = Vec::new();
-
-        // Expand tilde (~) to home directory if present
-        let expanded_prefix = self.expand_tilde(prefix);
+    fn evaluate_pipeline(
+        &mut self,
+        commands: &[Node],
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        if commands.is_empty() {
+            return Ok(0);
+        }
 
-        // Determine the directory to search and the filename prefix
-        let (dir_path, file_prefix) = if expanded_prefix.contains('/') {
-            if expanded_prefix.ends_with('/') {
-                // If prefix ends with '/', we want to list all files in that directory
-                (PathBuf::from(&expanded_prefix), String::new())
-            } else {
-                let path = Path::new(&expanded_prefix);
-                let parent = path.parent().unwrap_or(Path::new(""));
-                let file_name = path.file_name().map_or("", |f| f.to_str().unwrap_or(""));
-                (parent.to_path_buf(), file_name.to_string())
-            }
-        } else {
-            (PathBuf::from("."), expanded_prefix.clone())
+        if commands.len() == 1 {
+            let code = interpreter.evaluate_with_evaluator(&commands[0], self)?;
+            interpreter.set_pipe_status(&[code]);
+            return Ok(code);
+        }
+
+        // Every stage runs concurrently, connected by OS pipes. External commands are
+        // spawned; builtins, functions and compound commands run in a forked copy of the
+        // shell, except for the last stage, which runs in the shell itself so that
+        // `echo hi | read x` sets x.
+        let mut input = match interpreter.host_input_file()? {
+            Some(file) => PipeInput::File(file),
+            None => PipeInput::Inherit,
         };
+        let mut children = Vec::new();
+        let mut forked = Vec::new();
+        let mut statuses = vec![0; commands.len()];
+        let pipes = HostPipes::new(interpreter)?;
+
+        for (i, node) in commands.iter().enumerate() {
+            let is_last = i == commands.len() - 1;
+
+            let external = match node {
+                Node::Command {
+                    name,
+                    args,
+                    redirects,
+                } if interpreter.is_external_command(name)
+                    && !args
+                        .iter()
+                        .any(|arg| process_substitution_source(arg).is_some()) =>
+                {
+                    Some((name, args, redirects))
+                }
+                _ => None,
+            };
 
-        // Read the directory entries
-        if let Ok(entries) = fs::read_dir(dir_path.clone()) {
-            for entry in entries.flatten() {
-                if let Some(name) = entry.file_name().to_str() {
-                    if name.starts_with(&file_prefix) {
-                        // For display, show the path in the original form (with ~ if it was used)
-                        let full_path = if expanded_prefix.contains('/') {
-                            // If the original prefix started with ~, preserve that in the display
-                            if prefix.starts_with('~') && expanded_prefix != prefix {
-                                // Replace the expanded home directory with ~ in the display
-                                let home_dir = std::env::var("HOME").unwrap_or_default();
-                                let dir_display = dir_path.display().to_string();
-                                if dir_display.starts_with(&home_dir) {
-                                    let relative_path = &dir_display[home_dir.len()..];
-                                    // Handle the case where relative_path starts with / or is empty
-                                    if relative_path.is_empty() || relative_path == "/" {
-                                        // When completing directly in home directory, show just the filename like bash
-                                        name.to_string()
-                                    } else if relative_path.starts_with('/') {
-                                        format!("~{relative_path}/{name}")
-                                    } else {
-                                        format!("~/{relative_path}/{name}")
-                                    }
-                                } else {
-                                    format!("{}/{}", dir_path.display(), name)
-                                }
-                            } else {
-                                format!("{}/{}", dir_path.display(), name)
-                            }
-                        } else {
-                            name.to_string()
-                        };
+            let Some((name, args, redirects)) = external else {
+                if is_last {
+                    statuses[i] = self.run_internal_stage(node, input, interpreter)?;
+                    input = PipeInput::Inherit;
+                } else {
+                    let (pid, output) = interpreter.fork_stage(node, input, &pipes, self)?;
+                    forked.push((i, pid));
+                    input = PipeInput::File(output);
+                }
+                continue;
+            };
 
-                        let mut display_name = full_path.clone();
-                        let mut suffix = name[file_prefix.len()..].to_string();
+            let args = interpreter.expand_words(args);
+            interpreter.trace_command(name, &args);
 
-                        // Add a trailing slash for directories
-                        if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
-                            display_name.push('/');
-                            suffix.push('/');
-                        }
+            let mut command = Command::new(name);
+            command.args(&args);
+            command.stdin(match input {
+                PipeInput::Inherit => Stdio::inherit(),
+                PipeInput::Pipe(stdout) => Stdio::from(stdout),
+                PipeInput::File(file) => Stdio::from(file),
+            });
+            if !is_last {
+                command.stdout(Stdio::piped());
+            }
+            pipes.connect(&mut command, is_last)?;
+            Self::prepare_external_command(&mut command, redirects, interpreter)?;
 
-                        full_names.push(display_name);
-                        if !suffix.is_empty() {
-                            suffixes.push(suffix);
-                        }
-                    }
+            io::stdout().flush()?;
+            match command.spawn() {
+                Ok(mut child) => {
+                    input = match child.stdout.take() {
+                        Some(stdout) => PipeInput::Pipe(stdout),
+                        None => PipeInput::Inherit,
+                    };
+                    children.push((i, child));
+                }
+                Err(_) => {
+                    writeln!(&interpreter.stderr, "{name}: command not found")?;
+                    statuses[i] = 127;
+                    // The next stage reads end-of-file
+                    input = PipeInput::File(tempfile::tempfile()?);
                 }
             }
+            // Drop our copies of the pipe ends so readers see EOF and writers SIGPIPE
+            drop(command);
         }
+        drop(input);
+        pipes.forward(interpreter)?;
 
-        suffixes.sort();
-        full_names.sort();
-        (suffixes, full_names)
-    }
-
-    // Display a list of completions
-    fn display_completions(&self, completions: &[String]) -> io::Result<()> {
-        if completions.is_empty() {
-            return Ok(());
+        for (i, mut child) in children {
+            statuses[i] = interpreter.foreground_status(exit_status_code(child.wait()?));
         }
-
-        println!(); // Move to a new line
-
-        // Calculate the maximum width of completions
-        let max_width = completions.iter().map(|s| s.len()).max().unwrap_or(0) + 2;
-        let term_width = self.get_terminal_width();
-        let columns = std::cmp::max(1, term_width / max_width);
-
-        // Display completions in columns
-        for (i, completion) in completions.iter().enumerate() {
-            print!("{completion:<max_width$}");
-            if (i + 1) % columns == 0 {
-                println!();
-            }
+        for (i, pid) in forked {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            statuses[i] = interpreter.foreground_status(wait_status_code(status));
         }
+        interpreter.set_pipe_status(&statuses);
 
-        // Ensure we end with a newline
-        if completions.len() % columns != 0 {
-            println!();
+        // With pipefail the rightmost failing stage decides the pipeline's status
+        let last = statuses[statuses.len() - 1];
+        if interpreter.shell_options.contains("pipefail") {
+            return Ok(statuses
+                .iter()
+                .rev()
+                .copied()
+                .find(|&code| code != 0)
+                .unwrap_or(0));
         }
-
-        Ok(())
+        Ok(last)
     }
 
-    // Get the terminal width
-    fn get_terminal_width(&self) -> usize {
-        use std::process::Command;
-
-        let width = if cfg!(unix) {
-            // On Unix-like systems, try `tput cols`
-            Command::new("tput")
-                .arg("cols")
-                .output()
-                .ok()
-                .and_then(|output| {
-                    if output.status.success() {
-                        String::from_utf8(output.stdout)
-                            .ok()?
-                            .trim()
-                            .parse::<usize>()
-                            .ok()
-                    } else {
-                        None
-                    }
-                })
-                .or_else(|| {
-                    // Fallback: try `stty size` and extract columns
-                    Command::new("stty")
-                        .arg("size")
-                        .output()
-                        .ok()
-                        .and_then(|output| {
-                            if output.status.success() {
-                                let size_str = String::from_utf8(output.stdout).ok()?;
-                                let parts: Vec<&str> = size_str.split_whitespace().collect();
-                                if parts.len() >= 2 {
-                                    parts[1].parse::<usize>().ok()
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        })
-                })
-        } else if cfg!(windows) {
-            // On Windows, try PowerShell to get console width
-            Command::new("powershell")
-                .args(["-Command", "(Get-Host).UI.RawUI.WindowSize.Width"])
-                .output()
-                .ok()
-                .and_then(|output| {
-                    if output.status.success() {
-                        String::from_utf8(output.stdout)
-                            .ok()?
-                            .trim()
-                            .parse::<usize>()
-                            .ok()
-                    } else {
-                        None
-                    }
-                })
-        } else {
-            None
+    /// Run the last stage of a pipeline when it is a builtin, function or compound
+    /// command inside the shell, reading stdin from `input`
+    fn run_internal_stage(
+        &mut self,
+        node: &Node,
+        input: PipeInput,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        let stdin_file = match input {
+            PipeInput::Inherit => None,
+            PipeInput::Pipe(stdout) => Some(fs::File::from(OwnedFd::from(stdout))),
+            PipeInput::File(file) => Some(file),
         };
 
-        // Return the detected width or default to 80
-        width.unwrap_or(80)
-    }
-
-    /// Get the current prompt string, expanding variables
-    fn get_prompt(&self) -> String {
-        if let Some(prompt_template) = self.variables.get("PROMPT") {
-            // Use the existing expand_variables method for proper variable expansion
-            self.expand_variables(prompt_template)
-        } else {
-            "ϟ ".to_string()
+        let saved_stdin = match &stdin_file {
+            Some(file) => Some(redirect_fd(file.as_raw_fd(), 0)?),
+            None => None,
+        };
+        interpreter.stdin_redirections += usize::from(saved_stdin.is_some());
+        let result = interpreter.evaluate_with_evaluator(node, self);
+        interpreter.stdin_redirections -= usize::from(saved_stdin.is_some());
+        let _ = io::stdout().flush();
+        if let Some(saved) = saved_stdin {
+            restore_fd(saved, 0)?;
         }
+        result
     }
 
-    // Interactive shell that accepts a custom evaluator
-    pub fn run_interactive_with_evaluator<E: Evaluator>(
+    /// Run `declare`, `local` or `readonly` with whole arrays, as in `local a=(1 2)`,
+    /// which the parser spreads over `a=(`, the elements and `)`. The names are
+    /// declared first, then the arrays are assigned, and only then made readonly.
+    fn evaluate_compound_declaration(
         &mut self,
-        mut evaluator: E,
-    ) -> io::Result<()> {
-        let stdin = io::stdin();
-        let mut stdout = io::stdout();
-        let fd = stdin.as_raw_fd();
-
-        // Check if stdin is a terminal using isatty
-        if unsafe { libc::isatty(fd) } == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "Interactive mode requires a terminal",
-            ));
+        name: &str,
+        args: &[String],
+        redirects: &[Redirect],
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        let mut readonly = name == "readonly";
+        let mut declared = Vec::new();
+        let mut arrays = Vec::new();
+        let mut i = 0;
+        while let Some(arg) = args.get(i) {
+            i += 1;
+            if let Some(array) = compound_assignment_name(arg) {
+                let end = args[i..]
+                    .iter()
+                    .position(|arg| arg == ")")
+                    .map_or(args.len(), |end| i + end);
+                arrays.push((array.to_string(), args[i..end].to_vec()));
+                i = end + 1;
+                if name != "readonly" {
+                    declared.push(array.to_string());
+                }
+            } else if arg.starts_with('-') && arg.len() > 1 && declared.is_empty() {
+                // Readonly is applied last, or the assignments would be refused
+                let flags = arg.replace('r', "");
+                readonly |= flags.len() < arg.len();
+                if flags != "-" {
+                    declared.push(flags);
+                }
+            } else {
+                declared.push(arg.clone());
+            }
         }
 
-        let original_termios = tcgetattr(fd)?;
-        let mut raw_termios = original_termios;
-
-        // RAII guard to restore terminal settings on drop
-        struct TermiosGuard {
-            fd: i32,
-            original: libc::termios,
+        let mut status = 0;
+        if name != "readonly" || !declared.is_empty() {
+            status = self.evaluate_command(name, &declared, redirects, interpreter)?;
+        }
+        for (array, elements) in &arrays {
+            let elements = Node::Array {
+                elements: elements.clone(),
+            };
+            let code = self.evaluate_assignment(array, &elements, interpreter)?;
+            if code != 0 {
+                status = code;
+            }
+        }
+        if readonly {
+            let names = declared
+                .iter()
+                .map(|arg| arg.split_once('=').map_or(arg.as_str(), |(name, _)| name))
+                .chain(arrays.iter().map(|(array, _)| array.as_str()))
+                .filter(|name| is_assignable_name(name));
+            for name in names {
+                let name = interpreter.resolve_nameref(name);
+                interpreter.readonly.insert(name);
+            }
         }
+        Ok(status)
+    }
 
-        impl Drop for TermiosGuard {
-            fn drop(&mut self) {
-                let _ = tcsetattr(self.fd, libc::TCSANOW, &self.original);
+    fn evaluate_prefixed_command(
+        &mut self,
+        assignments: &[Node],
+        command: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Remember the shadowed values so the assignments only last for this command
+        let mut saved = Vec::new();
+        for assignment in assignments {
+            if let Node::Assignment { name, value } = assignment {
+                saved.push((name.clone(), interpreter.variables.get(name).cloned()));
+                self.evaluate_assignment(name, value, interpreter)?;
             }
         }
 
-        let _guard = TermiosGuard {
-            fd,
-            original: original_termios,
-        };
+        let result = interpreter.evaluate_with_evaluator(command, self);
 
-        // Ignore SIGINT (Ctrl+C) for the shell process
-        // This prevents the shell from exiting when Ctrl+C is pressed
-        unsafe {
-            libc::signal(libc::SIGINT, libc::SIG_IGN);
+        for (name, previous) in saved.into_iter().rev() {
+            match previous {
+                Some(value) => interpreter.variables.insert(name, value),
+                None => interpreter.variables.remove(&name),
+            };
         }
 
-        let mut history_index = self.history.len();
-
-        loop {
-            let prompt = self.get_prompt();
-            write!(stdout, "{prompt}")?;
-            stdout.flush()?;
+        result
+    }
 
-            let input = self.read_line_with_completion(
-                &prompt,
-                &original_termios,
-                &mut raw_termios,
-                &mut history_index,
-            )?;
+    fn evaluate_list(
+        &mut self,
+        statements: &[Node],
+        operators: &[String],
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        let mut last_exit_code = 0;
 
-            if input.trim().is_empty() {
+        for (i, statement) in statements.iter().enumerate() {
+            // `&&` and `||` skip the next command depending on the status so far,
+            // which carries over to the rest of the and-or list
+            let skipped = match i.checked_sub(1).and_then(|prev| operators.get(prev)) {
+                Some(op) if op == "&&" => last_exit_code != 0,
+                Some(op) if op == "||" => last_exit_code == 0,
+                _ => false,
+            };
+            if skipped {
                 continue;
             }
 
-            if input.trim() == "exit" {
-                break;
-            }
-
-            if !input.trim().is_empty()
-                && (self.history.is_empty() || (self.history.last() != Some(&input)))
-            {
-                self.history.push(input.clone());
-                history_index = self.history.len();
-                let _ = self.save_history();
+            if operators.get(i).is_some_and(|op| op == "&") {
+                last_exit_code = interpreter.spawn_background(statement, self)?;
+                continue;
             }
 
-            let result = self.execute_with_evaluator(&input, &mut evaluator);
-
-            match result {
+            match interpreter.evaluate_with_evaluator(statement, self) {
                 Ok(code) => {
-                    self.last_exit_code = code;
-                    self.variables.insert("?".to_string(), code.to_string());
+                    // A $(( )) in a word of a compound command, such as a for loop's
+                    // list, fails the statement once it has run
+                    let code = match interpreter.arithmetic_error.take() {
+                        Some(message) => interpreter.expansion_error(&message)?,
+                        None => code,
+                    };
+                    last_exit_code = code;
+                    interpreter.last_exit_code = code;
+                    interpreter
+                        .variables
+                        .insert("?".to_string(), code.to_string());
+                    // Pipelines record each stage themselves
+                    if !matches!(statement, Node::Pipeline { .. }) {
+                        interpreter.set_pipe_status(&[code]);
+                    }
                 }
-                Err(e) => {
-                    println!("Error: {e}");
-                    self.last_exit_code = 1;
-                    self.variables.insert("?".to_string(), "1".to_string());
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    // Check if this is a return statement - propagate it up
+                    if e.to_string().starts_with("return:") {
+                        return Err(io::Error::new(e.kind(), e.to_string()));
+                    }
+                    return Err(io::Error::new(e.kind(), e.to_string()));
                 }
+                Err(e) => return Err(e),
             }
-        }
 
-        self.save_history()?;
-        Ok(())
-    }
+            // With `set -e` a failing command ends the shell, unless it is tested by
+            // `&&`/`||` or `!`, or runs as a condition
+            let tested = operators.get(i).is_some_and(|op| op == "&&" || op == "||")
+                || matches!(statement, Node::Negation { .. });
+            if last_exit_code != 0
+                && !tested
+                && interpreter.condition_depth == 0
+                && interpreter.shell_options.contains("errexit")
+            {
+                interpreter.last_exit_code = last_exit_code;
+                interpreter.run_exit_trap();
+                std::process::exit(last_exit_code);
+            }
+        }
 
-    // Default interactive shell using DefaultEvaluator
-    pub fn run_interactive(&mut self) -> io::Result<()> {
-        let default_evaluator = DefaultEvaluator;
-        self.run_interactive_with_evaluator(default_evaluator)
+        Ok(last_exit_code)
     }
 
-    fn read_line_with_completion(
+    fn evaluate_assignment(
         &mut self,
-        prompt: &str,
-        original_termios: &libc::termios,
-        raw_termios: &mut libc::termios,
-        history_index: &mut usize,
-    ) -> io::Result<String> {
-        let mut stdin = io::stdin();
-        let mut stdout = io::stdout();
-        let fd = stdin.as_raw_fd();
-
-        let mut buffer = String::new();
-        let mut cursor_pos = 0;
-
-        // For storing the kill ring (for cut/paste operations)
-        let mut kill_ring = String::new();
-
-        loop {
-            // Switch to raw mode to read individual characters
-            raw_termios.c_lflag &= !(libc::ICANON | libc::ECHO);
-            raw_termios.c_cc[libc::VMIN] = 1;
-            raw_termios.c_cc[libc::VTIME] = 0;
-            tcsetattr(fd, libc::TCSANOW, raw_termios)?;
+        name: &str,
+        value: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // `name+=value` appends to the value, or adds to it for integer variables
+        let (name, append) = match name.strip_suffix('+') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        // Special parameters like `?`, `#` and `1` are only set by the shell itself
+        if !is_assignable_name(name) {
+            writeln!(
+                &interpreter.stderr,
+                "flash: `{name}': not a valid identifier"
+            )?;
+            return Ok(1);
+        }
 
-            // Read a single byte
-            let mut input_byte = [0u8; 1];
-            stdin.read_exact(&mut input_byte)?;
+        // Assigning through a nameref updates its target
+        let name = interpreter.resolve_nameref(name);
+        let name = name.as_str();
 
-            // Switch back to canonical mode for printing
-            tcsetattr(fd, libc::TCSANOW, original_termios)?;
+        let base = name.split_once('[').map_or(name, |(array, _)| array);
+        let base = interpreter.resolve_nameref(base);
+        // Checked before the value is expanded, so its side effects do not happen either
+        if let Err(message) = interpreter.check_writable(&base) {
+            writeln!(&interpreter.stderr, "flash: {message}")?;
+            return Ok(1);
+        }
 
-            match input_byte[0] {
-                // Enter
-                b'\n' | b'\r' => {
-                    println!();
-                    break;
+        let value = match value {
+            Node::StringLiteral(string_value) => {
+                if let Err(message) = interpreter.assign_parameter_defaults(string_value) {
+                    return interpreter.expansion_error(&message);
                 }
-
-                // Tab for completion
-                b'\t' => {
-                    let (suffixes, full_names) = self.generate_completions(&buffer, cursor_pos);
-
-                    match suffixes.len().cmp(&1) {
-                        std::cmp::Ordering::Less => {
-                            // Do nothing - no completions available
-                        }
-                        std::cmp::Ordering::Equal => {
-                            // If there's only one completion, use it
-                            let suffix = &suffixes[0];
-                            buffer.insert_str(cursor_pos, suffix);
-                            cursor_pos += suffix.len();
-
-                            // Redraw the line with the completion
-                            write!(stdout, "\r{prompt}{buffer}")?;
-                            stdout.flush()?;
-                        }
-                        std::cmp::Ordering::Greater => {
-                            // Find the common prefix among suffixes
-                            if let Some(common_prefix) = self.find_common_prefix(&suffixes) {
-                                if !common_prefix.is_empty() {
-                                    // Only insert the common prefix
-                                    buffer.insert_str(cursor_pos, &common_prefix);
-                                    cursor_pos += common_prefix.len();
-
-                                    // Redraw the line with the partial completion
-                                    write!(stdout, "\r{prompt}{buffer}")?;
-                                    stdout.flush()?;
-                                } else {
-                                    // No common prefix, show all completions (using full names for display)
-                                    self.display_completions(&full_names)?;
-                                    // Redraw the prompt and line
-                                    write!(stdout, "{prompt}{buffer}")?;
-                                    stdout.flush()?;
-                                }
-                            } else {
-                                // No common prefix found, show all completions (using full names for display)
-                                self.display_completions(&full_names)?;
-                                // Redraw the prompt and line
-                                write!(stdout, "{prompt}{buffer}")?;
-                                stdout.flush()?;
-                            }
-                        }
+                let value = interpreter.expand_assignment_value(string_value);
+                if let Some(message) = interpreter.arithmetic_error.take() {
+                    return interpreter.expansion_error(&message);
+                }
+                value
+            }
+            // Single-quoted strings should not have variable expansion
+            Node::SingleQuotedString(string_value) => string_value.clone(),
+            Node::CommandSubstitution { command } => {
+                interpreter.capture_command_output(command, self)?
+            }
+            Node::ArithmeticExpansion { expression } => {
+                let expanded_expr = interpreter.expand_parameters(expression);
+                match arithmetic::evaluate_with(&expanded_expr, interpreter) {
+                    Ok(result) => result.to_string(),
+                    Err(message) => {
+                        return interpreter
+                            .expansion_error(&format!("flash: {expanded_expr}: {message}"));
                     }
                 }
-                // Backspace
-                8 | 127 => {
-                    if cursor_pos > 0 {
-                        buffer.remove(cursor_pos - 1);
-                        cursor_pos -= 1;
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        write!(stdout, " ")?; // Clear deleted character
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        stdout.flush()?;
+            }
+            Node::ArithmeticCommand { expression } => {
+                let expanded_expr = interpreter.expand_parameters(expression);
+                match arithmetic::evaluate_with(&expanded_expr, interpreter) {
+                    Ok(result) => result.to_string(),
+                    Err(message) => {
+                        writeln!(&interpreter.stderr, "flash: ((: {expanded_expr}: {message}")?;
+                        return Ok(1);
                     }
                 }
-
-                // Ctrl-A (move to beginning of line)
-                1 => {
-                    cursor_pos = 0;
-                    write!(stdout, "\r{prompt}")?;
-                    stdout.flush()?;
+            }
+            Node::Array { elements } => {
+                // Each element is stored as `name[key]`, replacing the previous value.
+                // `[key]=value` sets a key, and other elements take the next index.
+                let mut values = Vec::new();
+                for element in elements {
+                    let keyed = element
+                        .strip_prefix('[')
+                        .and_then(|rest| rest.split_once("]="));
+                    match keyed {
+                        Some((subscript, value)) => values.push((
+                            Some(interpreter.array_key(name, subscript)),
+                            interpreter.expand_word_unsplit(value),
+                        )),
+                        None => values.extend(
+                            interpreter
+                                .expand_word(element)
+                                .into_iter()
+                                .map(|value| (None, value)),
+                        ),
+                    }
                 }
-
-                // Ctrl-E (move to end of line)
-                5 => {
-                    cursor_pos = buffer.len();
-                    write!(stdout, "\r{prompt}{buffer}")?;
-                    stdout.flush()?;
+                if interpreter.associative_arrays.contains_key(name) {
+                    // Words without a subscript alternate between keys and values
+                    let (keyed, words): (Vec<_>, Vec<_>) =
+                        values.into_iter().partition(|(key, _)| key.is_some());
+                    let words: Vec<String> = words.into_iter().map(|(_, word)| word).collect();
+                    values = keyed;
+                    for pair in words.chunks(2) {
+                        let value = pair.get(1).cloned().unwrap_or_default();
+                        values.push((Some(pair[0].clone()), value));
+                    }
                 }
 
-                // Ctrl-B (move back one character) - same as left arrow
-                2 => {
-                    if cursor_pos > 0 {
-                        cursor_pos -= 1;
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        // Move cursor back to the right position
-                        for _ in 0..(buffer.len() - cursor_pos) {
-                            write!(stdout, "\x1B[D")?;
-                        }
-                        stdout.flush()?;
+                // Appended elements follow the last index, after a scalar's element 0
+                let mut next_index = 0;
+                if append {
+                    next_index = match interpreter.arrays.get(name) {
+                        Some(elements) => elements.keys().next_back().map_or(0, |last| last + 1),
+                        None => usize::from(interpreter.variables.contains_key(name)),
+                    } as i64;
+                } else {
+                    interpreter.clear_array(name);
+                    if !interpreter.associative_arrays.contains_key(name) {
+                        interpreter.arrays.insert(name.to_string(), BTreeMap::new());
                     }
                 }
-
-                // Ctrl-F (move forward one character) - same as right arrow
-                6 => {
-                    if cursor_pos < buffer.len() {
-                        cursor_pos += 1;
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        // Move cursor back to the right position
-                        for _ in 0..(buffer.len() - cursor_pos) {
-                            write!(stdout, "\x1B[D")?;
-                        }
-                        stdout.flush()?;
+                for (key, value) in values {
+                    let key = key.unwrap_or_else(|| next_index.to_string());
+                    if let Ok(index) = key.parse::<i64>() {
+                        next_index = index + 1;
+                    }
+                    if let Err(message) = interpreter.set_array_element(name, &key, value) {
+                        writeln!(&interpreter.stderr, "flash: {message}")?;
+                        return Ok(1);
                     }
                 }
+                return Ok(0);
+            }
+            _ => {
+                return Err(io::Error::other("Unsupported value type for assignment"));
+            }
+        };
 
-                // Ctrl-K (kill from cursor to end of line)
-                11 => {
-                    if cursor_pos < buffer.len() {
-                        // Save the killed text
-                        kill_ring = buffer[cursor_pos..].to_string();
+        let value = match interpreter.parameter_value(name).filter(|_| append) {
+            Some(old) if interpreter.integer_vars.contains(&base) => {
+                let old = if old.is_empty() { "0".to_string() } else { old };
+                format!("{old}+({value})")
+            }
+            Some(old) => old + &value,
+            None => value,
+        };
+        if let Err(message) = interpreter.set_variable(name, value) {
+            writeln!(&interpreter.stderr, "flash: {message}")?;
+            return Ok(1);
+        }
+        Ok(0)
+    }
 
-                        // Remove from buffer
-                        buffer.truncate(cursor_pos);
+    fn evaluate_ext_glob(
+        &mut self,
+        operator: char,
+        patterns: &[String],
+        suffix: &str,
+        interpreter: &Interpreter,
+    ) -> Result<i32, io::Error> {
+        let entries = fs::read_dir(".")?;
+        let mut matches = Vec::new();
 
-                        // Redraw
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        write!(stdout, "                    ")?; // Clear any leftovers
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        stdout.flush()?;
-                    }
-                }
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if interpreter.matches_ext_glob(&file_name, operator, patterns, suffix) {
+                matches.push(file_name);
+            }
+        }
 
-                // Ctrl-U (kill from beginning of line to cursor)
-                21 => {
-                    if cursor_pos > 0 {
-                        // Save the killed text
-                        kill_ring = buffer[..cursor_pos].to_string();
+        for m in matches {
+            writeln!(&interpreter.stdout, "{m}")?;
+        }
 
-                        // Remove from buffer
-                        buffer = buffer[cursor_pos..].to_string();
-                        cursor_pos = 0;
+        Ok(0)
+    }
 
-                        // Redraw
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        write!(stdout, "                    ")?; // Clear any leftovers
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        stdout.flush()?;
-                    }
-                }
+    fn evaluate_if_statement(
+        &mut self,
+        condition: &Node,
+        consequence: &Node,
+        alternative: &Option<Box<Node>>,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Evaluate the condition
+        let condition_result = interpreter.evaluate_condition(condition, self)?;
 
-                // Ctrl-Y (yank/paste previously killed text)
-                25 => {
-                    if !kill_ring.is_empty() {
-                        buffer.insert_str(cursor_pos, &kill_ring);
-                        cursor_pos += kill_ring.len();
+        if condition_result == 0 {
+            // Condition is true (exit code 0), execute the consequence
+            interpreter.evaluate_with_evaluator(consequence, self)
+        } else if let Some(alt) = alternative {
+            // Condition is false, execute the alternative (elif or else)
+            interpreter.evaluate_with_evaluator(alt, self)
+        } else {
+            // No branch was taken: the if statement itself succeeds
+            Ok(0)
+        }
+    }
 
-                        // Redraw
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        // Move cursor back to the right position
-                        for _ in 0..(buffer.len() - cursor_pos) {
-                            write!(stdout, "\x1B[D")?;
-                        }
-                        stdout.flush()?;
-                    }
-                }
+    fn evaluate_elif_branch(
+        &mut self,
+        condition: &Node,
+        consequence: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Evaluate the elif condition
+        let condition_result = interpreter.evaluate_condition(condition, self)?;
 
-                // Ctrl-W (delete word backward)
-                23 => {
-                    // Delete the word before the cursor
-                    if cursor_pos > 0 {
-                        // Find the start of the current word
-                        let mut word_start = cursor_pos;
-                        let buffer_bytes = buffer.as_bytes();
+        if condition_result == 0 {
+            // Condition is true, execute the consequence
+            interpreter.evaluate_with_evaluator(consequence, self)
+        } else {
+            // Condition is false, return the condition's exit code
+            Ok(condition_result)
+        }
+    }
 
-                        // Skip any whitespace immediately before cursor
-                        while word_start > 0 && buffer_bytes[word_start - 1].is_ascii_whitespace() {
-                            word_start -= 1;
-                        }
+    fn evaluate_else_branch(
+        &mut self,
+        consequence: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Always execute the else consequence
+        interpreter.evaluate_with_evaluator(consequence, self)
+    }
 
-                        // Now find the start of the word
-                        while word_start > 0 && !buffer_bytes[word_start - 1].is_ascii_whitespace()
-                        {
-                            word_start -= 1;
-                        }
+    fn evaluate_case_statement(
+        &mut self,
+        expression: &Node,
+        patterns: &[CasePattern],
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Evaluate the expression to get the value to match against
+        let expanded_expr = match expression {
+            Node::StringLiteral(s) => interpreter.expand_variables(s),
+            Node::SingleQuotedString(s) => s.clone(),
+            Node::Command { name, args, .. } => {
+                // A word the parser read as a command still expands as one string
+                std::iter::once(name)
+                    .chain(args)
+                    .map(|word| interpreter.expand_word_unsplit(word))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }
+            Node::CommandSubstitution { command } => {
+                // Match against the command's output, without its trailing newlines
+                let output = interpreter.capture_command_output(command, self)?;
+                output.trim_end_matches('\n').to_string()
+            }
+            _ => {
+                // For other node types, try to convert to string representation
+                interpreter.expand_variables(&format!("{expression:?}"))
+            }
+        };
 
-                        // Save to kill ring
-                        kill_ring = buffer[word_start..cursor_pos].to_string();
+        // Try to match against each pattern; with no match the status is 0 (like bash)
+        let mut exit_code = 0;
+        let mut fall_through = false;
+        for pattern in patterns {
+            // A clause after ;& runs without testing its patterns
+            let matched = fall_through
+                || pattern.patterns.iter().any(|pattern_str| {
+                    let expanded_pattern = interpreter.expand_pattern(pattern_str);
+                    interpreter.matches_glob_pattern(&expanded_expr, &expanded_pattern)
+                });
+            if !matched {
+                continue;
+            }
 
-                        // Delete from word_start to cursor_pos
-                        if word_start < cursor_pos {
-                            buffer.replace_range(word_start..cursor_pos, "");
-                            cursor_pos = word_start;
+            // Execute the body for this pattern
+            exit_code = interpreter.evaluate_with_evaluator(&pattern.body, self)?;
+            match pattern.terminator {
+                CaseTerminator::Break => break,
+                CaseTerminator::FallThrough => fall_through = true,
+                CaseTerminator::Continue => fall_through = false,
+            }
+        }
 
-                            // Redraw the line
-                            write!(stdout, "\r{prompt}{buffer}")?;
-                            write!(stdout, "                    ")?; // Clear any leftovers
-                            write!(stdout, "\r{prompt}{buffer}")?;
-                            stdout.flush()?;
-                        }
-                    }
-                }
+        Ok(exit_code)
+    }
 
-                // Ctrl-L (clear screen)
-                12 => {
-                    // Clear the screen and redraw the prompt
-                    write!(stdout, "\x1B[2J\x1B[H")?; // ANSI escape sequence to clear screen and move cursor to home
-                    write!(stdout, "{prompt}{buffer}")?;
-                    stdout.flush()?;
-                }
-
-                // Ctrl-P (previous history) - same as up arrow
-                16 => {
-                    if *history_index > 0 {
-                        *history_index -= 1;
-                        buffer = self.history[*history_index].clone();
-                        cursor_pos = buffer.len();
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        write!(stdout, "                    ")?; // Clear any leftovers
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        stdout.flush()?;
-                    }
-                }
+    fn evaluate_for_loop(
+        &mut self,
+        variable: &str,
+        iterable: &Node,
+        body: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Expand the words into the values to iterate over
+        let values = match iterable {
+            Node::Array { elements } => interpreter.expand_words(elements),
+            _ => {
+                // If it's not an array, try to evaluate it as a command and get its output
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "for loop iterable must be an array",
+                ));
+            }
+        };
 
-                // Ctrl-N (next history) - same as down arrow
-                14 => {
-                    if *history_index < self.history.len() {
-                        *history_index += 1;
-                        if *history_index == self.history.len() {
-                            buffer.clear();
-                            cursor_pos = 0;
-                        } else {
-                            buffer = self.history[*history_index].clone();
-                            cursor_pos = buffer.len();
-                        }
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        write!(stdout, "                    ")?; // Clear any leftovers
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        stdout.flush()?;
-                    }
-                }
+        let mut last_exit_code = 0;
 
-                // Ctrl-T (transpose characters)
-                20 => {
-                    // Handle cursor at end of line
-                    if cursor_pos == buffer.len() && cursor_pos >= 2 {
-                        // Swap the last two characters
-                        let last_idx = buffer.len() - 1;
-                        let second_to_last_idx = buffer.len() - 2;
+        // Save the current value of the loop variable (if it exists)
+        let old_value = interpreter.variables.get(variable).cloned();
 
-                        // Can't use remove/insert directly with indices, so use chars
-                        let mut chars: Vec<char> = buffer.chars().collect();
-                        chars.swap(last_idx, second_to_last_idx);
+        // Iterate over each value
+        for value in values {
+            // Set the loop variable
+            if let Err(message) = interpreter.set_variable(variable, value) {
+                writeln!(&interpreter.stderr, "flash: {message}")?;
+                return Ok(1);
+            }
 
-                        // Rebuild the buffer
-                        buffer = chars.into_iter().collect();
+            // Execute the body
+            if let LoopFlow::Break =
+                self.evaluate_loop_body(body, interpreter, &mut last_exit_code)?
+            {
+                break;
+            }
+        }
 
-                        // Cursor remains at the end
+        // Restore the old value of the loop variable
+        if let Some(old_val) = old_value {
+            interpreter.variables.insert(variable.to_string(), old_val);
+        } else {
+            interpreter.variables.remove(variable);
+        }
 
-                        // Redraw
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        stdout.flush()?;
-                    }
-                    // Handle cursor within the line
-                    else if cursor_pos > 0 && cursor_pos < buffer.len() {
-                        // Get chars to swap
-                        let prev_char = buffer.remove(cursor_pos - 1);
-                        buffer.insert(cursor_pos, prev_char);
+        Ok(last_exit_code)
+    }
 
-                        // Advance cursor position after transposition
-                        cursor_pos += 1;
+    /// Run one iteration of a loop body, handling `break`/`continue` aimed at this loop
+    /// and passing signals for outer loops on with one level fewer
+    fn evaluate_loop_body(
+        &mut self,
+        body: &Node,
+        interpreter: &mut Interpreter,
+        last_exit_code: &mut i32,
+    ) -> Result<LoopFlow, io::Error> {
+        interpreter.loop_depth += 1;
+        let result = interpreter.evaluate_with_evaluator(body, self);
+        interpreter.loop_depth -= 1;
+
+        let error = match result {
+            Ok(code) => {
+                *last_exit_code = code;
+                return Ok(LoopFlow::Next);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => e,
+            Err(e) => return Err(e),
+        };
 
-                        // Redraw
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        // Move cursor back to the right position
-                        for _ in 0..(buffer.len() - cursor_pos) {
-                            write!(stdout, "\x1B[D")?;
-                        }
-                        stdout.flush()?;
-                    }
+        let message = error.to_string();
+        for (keyword, flow) in [("break", LoopFlow::Break), ("continue", LoopFlow::Next)] {
+            let level = message
+                .strip_prefix(keyword)
+                .and_then(|rest| rest.strip_prefix(':'))
+                .and_then(|level| level.parse::<usize>().ok());
+            if let Some(level) = level {
+                if level > 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        format!("{keyword}:{}", level - 1),
+                    ));
                 }
+                *last_exit_code = 0;
+                return Ok(flow);
+            }
+        }
+        Err(error)
+    }
 
-                // Ctrl-D (delete character under cursor or exit if buffer is empty)
-                4 => {
-                    if buffer.is_empty() {
-                        println!("exit");
-                        return Ok("exit".to_string());
-                    } else if cursor_pos < buffer.len() {
-                        buffer.remove(cursor_pos);
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        write!(stdout, " ")?; // Clear deleted character
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        // Move cursor back to the right position
-                        for _ in 0..(buffer.len() - cursor_pos) {
-                            write!(stdout, "\x1B[D")?;
-                        }
-                        stdout.flush()?;
-                    }
-                }
+    /// Signal `break`/`continue` to the enclosing loops
+    fn evaluate_loop_control(
+        &mut self,
+        keyword: &str,
+        level: usize,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        if level == 0 {
+            writeln!(
+                &interpreter.stderr,
+                "{keyword}: {level}: loop count out of range"
+            )?;
+            return Ok(1);
+        }
+        if interpreter.loop_depth == 0 {
+            writeln!(
+                &interpreter.stderr,
+                "{keyword}: only meaningful in a `for', `while', or `until' loop"
+            )?;
+            return Ok(0);
+        }
 
-                // Ctrl-R (reverse history search) - simplified version
-                18 => {
-                    // Store the current buffer in case search is cancelled
-                    let original_buffer = buffer.clone();
-                    let original_cursor_pos = cursor_pos;
+        // Asking for more levels than exist leaves the outermost loop
+        let level = level.min(interpreter.loop_depth);
+        Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!("{keyword}:{level}"),
+        ))
+    }
 
-                    // Create a search buffer
-                    let mut search_term = String::new();
-                    let mut search_index = self.history.len() - 1;
-                    let mut found = false;
+    fn evaluate_while_loop(
+        &mut self,
+        condition: &Node,
+        body: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        let mut last_exit_code = 0;
 
-                    // Display the search prompt
-                    write!(stdout, "\r(reverse-i-search)`': ")?;
-                    stdout.flush()?;
+        // Continue while condition returns 0 (success)
+        loop {
+            let condition_result = interpreter.evaluate_condition(condition, self)?;
+            if condition_result != 0 {
+                break; // Exit when condition fails
+            }
 
-                    // Read characters for search
-                    loop {
-                        // Read a single byte in raw mode
-                        raw_termios.c_lflag &= !(libc::ICANON | libc::ECHO);
-                        tcsetattr(fd, libc::TCSANOW, raw_termios)?;
-                        let mut search_byte = [0u8; 1];
-                        stdin.read_exact(&mut search_byte)?;
-                        tcsetattr(fd, libc::TCSANOW, original_termios)?;
+            // Execute the body
+            if let LoopFlow::Break =
+                self.evaluate_loop_body(body, interpreter, &mut last_exit_code)?
+            {
+                break;
+            }
+        }
 
-                        match search_byte[0] {
-                            // Enter - accept the current match
-                            b'\n' | b'\r' => {
-                                if found {
-                                    write!(stdout, "\r\n")?;
-                                    buffer = self.history[search_index].clone();
-                                    cursor_pos = buffer.len();
-                                } else {
-                                    write!(stdout, "\r{prompt}{original_buffer}")?;
-                                    cursor_pos = original_cursor_pos;
-                                }
-                                stdout.flush()?;
-                                break;
-                            }
+        Ok(last_exit_code)
+    }
 
-                            // Escape - cancel search
-                            27 => {
-                                write!(stdout, "\r{prompt}{original_buffer}")?;
-                                cursor_pos = original_cursor_pos;
-                                stdout.flush()?;
-                                break;
-                            }
+    fn evaluate_until_loop(
+        &mut self,
+        condition: &Node,
+        body: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        let mut last_exit_code = 0;
 
-                            // Ctrl-R - search for next occurrence
-                            18 => {
-                                if found {
-                                    let mut temp_index = search_index;
-                                    let mut found_next = false;
+        // Continue until condition returns 0 (success)
+        loop {
+            let condition_result = interpreter.evaluate_condition(condition, self)?;
+            if condition_result == 0 {
+                break; // Exit when condition succeeds
+            }
 
-                                    // Start search from one past the current match
-                                    if temp_index > 0 {
-                                        temp_index -= 1;
-
-                                        while temp_index < self.history.len() {
-                                            if self.history[temp_index].contains(&search_term) {
-                                                search_index = temp_index;
-                                                found_next = true;
-                                                break;
-                                            }
-                                            if temp_index == 0 {
-                                                break;
-                                            }
-                                            temp_index -= 1;
-                                        }
-                                    }
+            // Execute the body
+            if let LoopFlow::Break =
+                self.evaluate_loop_body(body, interpreter, &mut last_exit_code)?
+            {
+                break;
+            }
+        }
 
-                                    if found_next {
-                                        write!(
-                                            stdout,
-                                            "\r(reverse-i-search)`{}': {}",
-                                            search_term, self.history[search_index]
-                                        )?;
-                                    } else {
-                                        write!(
-                                            stdout,
-                                            "\r(failed reverse-i-search)`{}': {}",
-                                            search_term, self.history[search_index]
-                                        )?;
-                                    }
-                                    stdout.flush()?;
-                                }
-                            }
+        Ok(last_exit_code)
+    }
 
-                            // Backspace
-                            8 | 127 => {
-                                if !search_term.is_empty() {
-                                    search_term.pop();
-                                    found = false;
+    fn evaluate_select_statement(
+        &mut self,
+        variable: &str,
+        items: &Node,
+        body: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Evaluate items to get the list of choices
+        let choices = match items {
+            Node::Array { elements } => elements.clone(),
+            Node::StringLiteral(s) if s == "$@" => {
+                // Use positional parameters
+                interpreter.args.clone()
+            }
+            _ => {
+                // Try to evaluate as a command and capture output
+                let output = interpreter.capture_command_output(items, self)?;
+                output.split_whitespace().map(|s| s.to_string()).collect()
+            }
+        };
 
-                                    // Search from the end of history
-                                    search_index = self.history.len() - 1;
+        if choices.is_empty() {
+            return Ok(0);
+        }
 
-                                    while search_index < self.history.len() {
-                                        if self.history[search_index].contains(&search_term) {
-                                            found = true;
-                                            break;
-                                        }
-                                        if search_index == 0 {
-                                            break;
-                                        }
-                                        search_index -= 1;
-                                    }
+        // Check if we're in a non-interactive environment (like tests)
+        // by checking if stdin is redirected or if we're in a test environment
+        if std::env::var("CARGO_TEST").is_ok() || std::env::var("RUST_TEST_THREADS").is_ok() {
+            // In test mode, just return without executing the body
+            return Ok(0);
+        }
 
-                                    if found {
-                                        write!(
-                                            stdout,
-                                            "\r(reverse-i-search)`{}': {}",
-                                            search_term, self.history[search_index]
-                                        )?;
-                                    } else {
-                                        write!(
-                                            stdout,
-                                            "\r(failed reverse-i-search)`{search_term}': "
-                                        )?;
-                                    }
-                                    stdout.flush()?;
-                                }
-                            }
+        let mut last_exit_code = 0;
 
-                            // Regular character - add to search term
-                            _ => {
-                                let ch = search_byte[0] as char;
-                                if ch.is_ascii() && !ch.is_control() {
-                                    search_term.push(ch);
-                                    found = false;
+        loop {
+            // Display the menu
+            for (i, choice) in choices.iter().enumerate() {
+                writeln!(&interpreter.stdout, "{}) {}", i + 1, choice)?;
+            }
 
-                                    // Search from the end of history
-                                    search_index = self.history.len() - 1;
+            // Prompt for selection
+            write!(&interpreter.stdout, "#? ")?;
+            (&interpreter.stdout).flush()?;
 
-                                    while search_index < self.history.len() {
-                                        if self.history[search_index].contains(&search_term) {
-                                            found = true;
-                                            break;
-                                        }
-                                        if search_index == 0 {
-                                            break;
-                                        }
-                                        search_index -= 1;
-                                    }
+            // Read user input
+            match interpreter.read_input_delimited(b'\n') {
+                Ok(None) => {
+                    // EOF - break out of select loop
+                    break;
+                }
+                Ok(Some(input)) => {
+                    let input = String::from_utf8_lossy(&input);
+                    let input = input.trim();
 
-                                    if found {
-                                        write!(
-                                            stdout,
-                                            "\r(reverse-i-search)`{}': {}",
-                                            search_term, self.history[search_index]
-                                        )?;
-                                    } else {
-                                        write!(
-                                            stdout,
-                                            "\r(failed reverse-i-search)`{search_term}': "
-                                        )?;
-                                    }
-                                    stdout.flush()?;
-                                }
+                    // Handle empty input (just continue the loop)
+                    if input.is_empty() {
+                        continue;
+                    }
+
+                    // Handle numeric selection
+                    if let Ok(selection) = input.parse::<usize>() {
+                        if selection > 0 && selection <= choices.len() {
+                            // Set the variable to the selected item, and REPLY to the user's input
+                            let assigned = interpreter
+                                .set_variable(variable, choices[selection - 1].clone())
+                                .and(interpreter.set_variable("REPLY", input.to_string()));
+                            if let Err(message) = assigned {
+                                writeln!(&interpreter.stderr, "flash: {message}")?;
+                                return Ok(1);
+                            }
+
+                            // Execute the body
+                            if let LoopFlow::Break =
+                                self.evaluate_loop_body(body, interpreter, &mut last_exit_code)?
+                            {
+                                break;
                             }
+                            continue;
                         }
                     }
 
-                    continue; // Skip the rest of the loop for this iteration
+                    // Set REPLY to the user's input for non-numeric or invalid selections
+                    if let Err(message) = interpreter.set_variable("REPLY", input.to_string()) {
+                        writeln!(&interpreter.stderr, "flash: {message}")?;
+                        return Ok(1);
+                    }
+
+                    // For invalid selections, unset the variable and execute body
+                    interpreter.variables.remove(variable);
+                    if let LoopFlow::Break =
+                        self.evaluate_loop_body(body, interpreter, &mut last_exit_code)?
+                    {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    // Error reading input - break out of select loop
+                    break;
                 }
+            }
+        }
 
-                // Ctrl-C
-                3 => {
-                    // Print ^C and move to new line (like bash)
-                    print!("^C");
-                    stdout.flush()?;
-                    println!();
+        Ok(last_exit_code)
+    }
 
-                    // Clear the current buffer and reset cursor
-                    buffer.clear();
-                    cursor_pos = 0;
+    fn evaluate_command_substitution(
+        &mut self,
+        command: &Node,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        let _output = interpreter.capture_command_output(command, self)?;
+        // Command substitution doesn't print output, it returns it as a value
+        // The exit code is typically 0 for successful substitution
+        Ok(0)
+    }
 
-                    // Show a fresh prompt on the new line
-                    write!(stdout, "{prompt}")?;
-                    stdout.flush()?;
-                }
+    fn evaluate_arithmetic_expansion(
+        &mut self,
+        expression: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Expand variables in the expression first
+        let expanded_expr = interpreter.expand_parameters(expression);
 
-                // Escape sequence (arrow keys, etc.)
-                27 => {
-                    // Read the next two bytes
-                    let mut escape_seq = [0u8; 2];
-                    stdin.read_exact(&mut escape_seq)?;
+        // Evaluate the arithmetic expression
+        match self.evaluate_arithmetic_expression_with_assignment(&expanded_expr, interpreter) {
+            Ok(_result) => {
+                // For arithmetic expansion, we typically return 0 for success
+                // The actual result is used as a value, not an exit code
+                Ok(0)
+            }
+            Err(message) => {
+                interpreter.expansion_error(&format!("flash: {expanded_expr}: {message}"))
+            }
+        }
+    }
 
-                    if escape_seq[0] == b'[' {
-                        match escape_seq[1] {
-                            // Up arrow - history navigation
-                            b'A' => {
-                                if *history_index > 0 {
-                                    *history_index -= 1;
-                                    buffer = self.history[*history_index].clone();
-                                    cursor_pos = buffer.len();
-                                    write!(stdout, "\r{prompt}{buffer}")?;
-                                    write!(stdout, "                    ")?; // Clear any leftovers
-                                    write!(stdout, "\r{prompt}{buffer}")?;
-                                    stdout.flush()?;
-                                }
-                            }
+    fn evaluate_arithmetic_command(
+        &mut self,
+        expression: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Expand variables in the expression first
+        let expanded_expr = interpreter.expand_parameters(expression);
 
-                            // Down arrow - history navigation
-                            b'B' => {
-                                if *history_index < self.history.len() {
-                                    *history_index += 1;
-                                    if *history_index == self.history.len() {
-                                        buffer.clear();
-                                        cursor_pos = 0;
-                                    } else {
-                                        buffer = self.history[*history_index].clone();
-                                        cursor_pos = buffer.len();
-                                    }
-                                    write!(stdout, "\r{prompt}{buffer}")?;
-                                    write!(stdout, "                    ")?; // Clear any leftovers
-                                    write!(stdout, "\r{prompt}{buffer}")?;
-                                    stdout.flush()?;
-                                }
-                            }
+        // Evaluate the arithmetic expression
+        match self.evaluate_arithmetic_expression_with_assignment(&expanded_expr, interpreter) {
+            Ok(result) => {
+                // For arithmetic commands, the exit code is based on the result
+                // Non-zero result means success (exit code 0), zero result means failure (exit code 1)
+                if result != 0 {
+                    Ok(0) // Success
+                } else {
+                    Ok(1) // Failure
+                }
+            }
+            Err(message) => {
+                writeln!(&interpreter.stderr, "flash: ((: {expanded_expr}: {message}")?;
+                Ok(1)
+            }
+        }
+    }
 
-                            // Left arrow
-                            b'D' => {
-                                if cursor_pos > 0 {
-                                    cursor_pos -= 1;
-                                    write!(stdout, "\r{prompt}{buffer}")?;
-                                    // Move cursor back to the right position
-                                    for _ in 0..(buffer.len() - cursor_pos) {
-                                        write!(stdout, "\x1B[D")?;
-                                    }
-                                    stdout.flush()?;
-                                }
-                            }
+    pub fn evaluate_arithmetic_expression_with_assignment(
+        &mut self,
+        expr: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<i64, String> {
+        arithmetic::evaluate_with(expr, interpreter)
+    }
 
-                            // Right arrow
-                            b'C' => {
-                                if cursor_pos < buffer.len() {
-                                    cursor_pos += 1;
-                                    write!(stdout, "\r{prompt}{buffer}")?;
-                                    // Move cursor back to the right position
-                                    for _ in 0..(buffer.len() - cursor_pos) {
-                                        write!(stdout, "\x1B[D")?;
-                                    }
-                                    stdout.flush()?;
-                                }
-                            }
+    pub fn evaluate_arithmetic_expression(expr: &str) -> Result<i64, String> {
+        arithmetic::evaluate(expr)
+    }
 
-                            // Alt+Left (Home) or Alt+Right (End) could be added here
-                            _ => {}
-                        }
-                    }
-                }
+    fn evaluate_test_command(args: &[String], interpreter: &Interpreter) -> Result<i32, io::Error> {
+        // Handle the test command ([ and test)
+        // For [ command, the last argument should be "]"
+        let test_args = if !args.is_empty() && args[args.len() - 1] == "]" {
+            &args[..args.len() - 1] // Remove the closing "]"
+        } else {
+            args
+        };
 
-                // Regular character
-                _ => {
-                    let ch = input_byte[0] as char;
-                    if ch.is_ascii() && !ch.is_control() {
-                        buffer.insert(cursor_pos, ch);
-                        cursor_pos += 1;
-                        write!(stdout, "\r{prompt}{buffer}")?;
-                        // Move cursor back to the right position
-                        for _ in 0..(buffer.len() - cursor_pos) {
-                            write!(stdout, "\x1B[D")?;
-                        }
-                        stdout.flush()?;
-                    }
-                }
+        Self::evaluate_test_expression(test_args, interpreter)
+    }
+
+    /// Evaluate the arguments of a `test`/`[` expression (without the closing `]`)
+    fn evaluate_test_expression(
+        test_args: &[String],
+        interpreter: &Interpreter,
+    ) -> Result<i32, io::Error> {
+        match Self::evaluate_test_arguments(test_args) {
+            Ok(result) => Ok(if result { 0 } else { 1 }),
+            Err(message) => {
+                writeln!(&interpreter.stderr, "test: {message}")?;
+                Ok(2)
             }
         }
+    }
 
-        Ok(buffer)
+    /// Up to four arguments are interpreted by their count as POSIX specifies, so an
+    /// operand may look like an operator; longer expressions are parsed with `-a`
+    /// binding tighter than `-o`
+    fn evaluate_test_arguments(args: &[String]) -> Result<bool, String> {
+        let is_binary = |arg: &String| TEST_BINARY_OPERATORS.contains(&arg.as_str());
+        match args.len() {
+            0 => return Ok(false),
+            1 => return Ok(!args[0].is_empty()),
+            2 if args[0] == "!" => return Ok(args[1].is_empty()),
+            2 if TEST_UNARY_OPERATORS.contains(&args[0].as_str()) => {
+                return Ok(Self::test_unary(&args[0], &args[1]));
+            }
+            2 => return Err(format!("{}: unary operator expected", args[0])),
+            3 if is_binary(&args[1]) => return Ok(Self::test_binary(&args[0], &args[1], &args[2])),
+            3 if args[1] == "-a" => return Ok(!args[0].is_empty() && !args[2].is_empty()),
+            3 if args[1] == "-o" => return Ok(!args[0].is_empty() || !args[2].is_empty()),
+            3 | 4 if args[0] == "!" => {
+                return Self::evaluate_test_arguments(&args[1..]).map(|r| !r);
+            }
+            3 | 4 if args[0] == "(" && args[args.len() - 1] == ")" => {
+                return Self::evaluate_test_arguments(&args[1..args.len() - 1]);
+            }
+            3 => return Err(format!("{}: binary operator expected", args[1])),
+            _ => {}
+        }
+
+        let mut position = 0;
+        let result = Self::evaluate_test_or(args, &mut position)?;
+        match args.get(position) {
+            Some(_) => Err("too many arguments".to_string()),
+            None => Ok(result),
+        }
     }
 
-    // Find the longest common prefix among completion candidates
-    fn find_common_prefix(&self, completions: &[String]) -> Option<String> {
-        if completions.is_empty() {
-            return None;
+    fn evaluate_test_or(args: &[String], position: &mut usize) -> Result<bool, String> {
+        let mut result = Self::evaluate_test_and(args, position)?;
+        while args.get(*position).is_some_and(|arg| arg == "-o") {
+            *position += 1;
+            let right = Self::evaluate_test_and(args, position)?;
+            result = result || right;
         }
+        Ok(result)
+    }
 
-        if completions.len() == 1 {
-            return Some(completions[0].clone());
+    fn evaluate_test_and(args: &[String], position: &mut usize) -> Result<bool, String> {
+        let mut result = Self::evaluate_test_not(args, position)?;
+        while args.get(*position).is_some_and(|arg| arg == "-a") {
+            *position += 1;
+            let right = Self::evaluate_test_not(args, position)?;
+            result = result && right;
         }
+        Ok(result)
+    }
 
-        let first = &completions[0];
-        let mut common_len = first.len();
+    fn evaluate_test_not(args: &[String], position: &mut usize) -> Result<bool, String> {
+        // `!` followed by a binary operator is that operator's left operand
+        let negates = args.get(*position).is_some_and(|arg| arg == "!")
+            && args
+                .get(*position + 1)
+                .is_some_and(|next| !TEST_BINARY_OPERATORS.contains(&next.as_str()));
+        if negates {
+            *position += 1;
+            return Self::evaluate_test_not(args, position).map(|result| !result);
+        }
+        Self::evaluate_test_primary(args, position)
+    }
 
-        for completion in &completions[1..] {
-            let mut i = 0;
-            let mut matched = true;
+    fn evaluate_test_primary(args: &[String], position: &mut usize) -> Result<bool, String> {
+        let Some(arg) = args.get(*position) else {
+            return Err("argument expected".to_string());
+        };
 
-            for (c1, c2) in first.chars().zip(completion.chars()) {
-                if c1 != c2 {
-                    matched = false;
-                    break;
-                }
-                i += 1;
+        if let (Some(operator), Some(right)) = (args.get(*position + 1), args.get(*position + 2)) {
+            if TEST_BINARY_OPERATORS.contains(&operator.as_str()) {
+                *position += 3;
+                return Ok(Self::test_binary(arg, operator, right));
             }
+        }
 
-            if !matched {
-                common_len = common_len.min(i);
-            } else {
-                common_len = common_len.min(completion.len());
+        if arg == "(" && *position + 1 < args.len() {
+            *position += 1;
+            let result = Self::evaluate_test_or(args, position)?;
+            if args.get(*position).is_none_or(|arg| arg != ")") {
+                return Err("`)' expected".to_string());
             }
+            *position += 1;
+            return Ok(result);
         }
 
-        if common_len == 0 {
-            return None;
+        if let Some(operand) = args.get(*position + 1) {
+            if TEST_UNARY_OPERATORS.contains(&arg.as_str()) {
+                *position += 2;
+                return Ok(Self::test_unary(arg, operand));
+            }
         }
 
-        Some(first[..common_len].to_string())
+        // Any other word is true when it is non-empty
+        *position += 1;
+        Ok(!arg.is_empty())
     }
 
-    // Main execution method that accepts a custom evaluator.
-    pub fn execute_with_evaluator<E: Evaluator>(
-        &mut self,
-        input: &str,
-        evaluator: &mut E,
-    ) -> Result<i32, io::Error> {
-        let lexer = Lexer::new(input);
-        let mut parser = Parser::new(lexer);
-        let ast = parser.parse_script();
-        self.evaluate_with_evaluator(&ast, evaluator)
+    /// Apply one of `TEST_UNARY_OPERATORS` to its operand
+    fn test_unary(operator: &str, operand: &str) -> bool {
+        let path = Path::new(operand);
+        match operator {
+            "-n" => !operand.is_empty(),
+            "-z" => operand.is_empty(),
+            "-f" => path.is_file(),
+            "-d" => path.is_dir(),
+            "-e" | "-a" => path.exists(),
+            "-r" => path.exists() && fs::metadata(path).is_ok(),
+            "-w" => fs::metadata(path).is_ok_and(|m| !m.permissions().readonly()),
+            "-x" => {
+                #[cfg(unix)]
+                {
+                    fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+                }
+                #[cfg(not(unix))]
+                {
+                    path.exists()
+                }
+            }
+            "-s" => fs::metadata(path).is_ok_and(|m| m.len() > 0),
+            _ => false,
+        }
     }
 
-    // Default execute method using DefaultEvaluator.
-    pub fn execute(&mut self, input: &str) -> Result<i32, io::Error> {
-        let mut default_evaluator = DefaultEvaluator;
-        self.execute_with_evaluator(input, &mut default_evaluator)
+    /// Apply one of `TEST_BINARY_OPERATORS` to its operands. Numeric comparisons of
+    /// non-numbers are false, except that they are never `-eq`ual.
+    fn test_binary(left: &str, operator: &str, right: &str) -> bool {
+        let numbers = || Some((left.parse::<i64>().ok()?, right.parse::<i64>().ok()?));
+        let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match operator {
+            "=" | "==" => left == right,
+            "!=" => left != right,
+            "<" => left.as_bytes() < right.as_bytes(),
+            ">" => left.as_bytes() > right.as_bytes(),
+            // A file that exists is newer than one that does not
+            "-nt" => match (modified(left), modified(right)) {
+                (Some(left), Some(right)) => left > right,
+                (left, _) => left.is_some(),
+            },
+            "-ot" => match (modified(left), modified(right)) {
+                (Some(left), Some(right)) => left < right,
+                (_, right) => right.is_some(),
+            },
+            "-ef" => match (fs::metadata(left), fs::metadata(right)) {
+                #[cfg(unix)]
+                (Ok(left), Ok(right)) => {
+                    use std::os::unix::fs::MetadataExt;
+                    left.dev() == right.dev() && left.ino() == right.ino()
+                }
+                #[cfg(not(unix))]
+                (Ok(_), Ok(_)) => fs::canonicalize(left).ok() == fs::canonicalize(right).ok(),
+                _ => false,
+            },
+            "-eq" => numbers().is_some_and(|(l, r)| l == r),
+            "-ne" => numbers().is_none_or(|(l, r)| l != r),
+            "-lt" => numbers().is_some_and(|(l, r)| l < r),
+            "-le" => numbers().is_some_and(|(l, r)| l <= r),
+            "-gt" => numbers().is_some_and(|(l, r)| l > r),
+            "-ge" => numbers().is_some_and(|(l, r)| l >= r),
+            _ => false,
+        }
     }
 
-    // Internal evaluation method that uses the provided evaluator
-    pub fn evaluate_with_evaluator<E: Evaluator>(
+    /// Evaluate `[[ ... ]]`. Operands are expanded without field splitting or globbing,
+    /// and only once the `&&`/`||` chain reaches them.
+    fn evaluate_extended_test(
         &mut self,
-        node: &Node,
-        evaluator: &mut E,
+        condition: &Node,
+        interpreter: &mut Interpreter,
     ) -> Result<i32, io::Error> {
-        evaluator.evaluate(node, self)
-    }
-
-    // Helper method for matching extended glob patterns
-    fn matches_ext_glob(
-        &self,
-        filename: &str,
-        operator: char,
-        patterns: &[String],
-        suffix: &str,
-    ) -> bool {
-        // Check if the filename has the required suffix
-        if !filename.ends_with(suffix) {
-            return false;
-        }
-
-        // Remove the suffix for pattern matching
-        let without_suffix = if suffix.is_empty() {
-            filename.to_string()
-        } else {
-            filename[..filename.len() - suffix.len()].to_string()
+        let Node::Command { args, .. } = condition else {
+            return Ok(2);
         };
 
-        // Convert patterns to regex patterns
-        let regex_patterns: Vec<Regex> = patterns
-            .iter()
-            .map(|p| {
-                // Convert glob pattern to regex
-                // This is simplified and doesn't handle all glob features
-                let escaped = regex::escape(p);
-                let regex_str = escaped.replace("\\*", ".*").replace("\\?", ".");
-                Regex::new(&format!("^{regex_str}$")).unwrap_or_else(|_| Regex::new("^$").unwrap())
-            })
-            .collect();
-
-        // Apply the operator logic
-        match operator {
-            '?' => {
-                // Match any of the patterns exactly once
-                regex_patterns.iter().any(|re| re.is_match(&without_suffix))
-            }
-            '*' => {
-                // Match zero or more occurrences of any of the patterns
-                true // Simplified - should check for zero or more matches
-            }
-            '+' => {
-                // Match one or more occurrences of any of the patterns
-                regex_patterns.iter().any(|re| re.is_match(&without_suffix))
-            }
-            '@' => {
-                // Match exactly one of the patterns
-                let match_count = regex_patterns
-                    .iter()
-                    .filter(|re| re.is_match(&without_suffix))
-                    .count();
-                match_count == 1
+        let mut position = 0;
+        match Self::evaluate_conditional_or(args, &mut position, true, interpreter) {
+            Ok(_) if position < args.len() => {
+                writeln!(
+                    &interpreter.stderr,
+                    "[[: syntax error near `{}'",
+                    args[position]
+                )?;
+                Ok(2)
             }
-            '!' => {
-                // Match anything except one of the patterns
-                !regex_patterns.iter().any(|re| re.is_match(&without_suffix))
+            Ok(result) => Ok(if result { 0 } else { 1 }),
+            Err(message) => {
+                writeln!(&interpreter.stderr, "[[: {message}")?;
+                Ok(2)
             }
-            _ => false,
         }
     }
 
-    #[allow(clippy::only_used_in_recursion)]
-    pub fn capture_command_output<E: Evaluator>(
-        &mut self,
-        node: &Node,
-        evaluator: &mut E,
-    ) -> Result<String, io::Error> {
-        match node {
-            Node::Command {
-                name,
-                args,
-                redirects: _,
-            } => {
-                // Handle built-in commands that should work in command substitution
-                match name.as_str() {
-                    "echo" => {
-                        let mut output = String::new();
-                        for (i, arg) in args.iter().enumerate() {
-                            let expanded_arg = self.expand_variables(arg);
-                            if i > 0 {
-                                output.push(' ');
-                            }
-                            output.push_str(&expanded_arg);
-                        }
-                        Ok(output)
-                    }
-                    "pwd" => match env::current_dir() {
-                        Ok(dir) => Ok(dir.to_string_lossy().to_string()),
-                        Err(e) => Err(e),
-                    },
-                    _ => {
-                        // Execute external command and capture output
-                        let mut command = std::process::Command::new(name);
+    // `active` is false while parsing the side of `&&`/`||` that is short-circuited away
+    fn evaluate_conditional_or(
+        words: &[String],
+        position: &mut usize,
+        active: bool,
+        interpreter: &mut Interpreter,
+    ) -> Result<bool, String> {
+        let mut result = Self::evaluate_conditional_and(words, position, active, interpreter)?;
+        while words.get(*position).is_some_and(|word| word == "||") {
+            *position += 1;
+            let right =
+                Self::evaluate_conditional_and(words, position, active && !result, interpreter)?;
+            result = result || right;
+        }
+        Ok(result)
+    }
 
-                        // Expand variables in arguments
-                        let expanded_args: Vec<String> =
-                            args.iter().map(|arg| self.expand_variables(arg)).collect();
-                        command.args(&expanded_args);
+    fn evaluate_conditional_and(
+        words: &[String],
+        position: &mut usize,
+        active: bool,
+        interpreter: &mut Interpreter,
+    ) -> Result<bool, String> {
+        let mut result = Self::evaluate_conditional_not(words, position, active, interpreter)?;
+        while words.get(*position).is_some_and(|word| word == "&&") {
+            *position += 1;
+            let right =
+                Self::evaluate_conditional_not(words, position, active && result, interpreter)?;
+            result = result && right;
+        }
+        Ok(result)
+    }
 
-                        // Set environment variables
-                        for (key, value) in &self.variables {
-                            command.env(key, value);
-                        }
+    fn evaluate_conditional_not(
+        words: &[String],
+        position: &mut usize,
+        active: bool,
+        interpreter: &mut Interpreter,
+    ) -> Result<bool, String> {
+        if words.get(*position).is_some_and(|word| word == "!") {
+            *position += 1;
+            let result = Self::evaluate_conditional_not(words, position, active, interpreter)?;
+            return Ok(!result);
+        }
+        Self::evaluate_conditional_primary(words, position, active, interpreter)
+    }
 
-                        match command.output() {
-                            Ok(output) => {
-                                if output.status.success() {
-                                    let stdout = String::from_utf8_lossy(&output.stdout);
-                                    Ok(stdout.trim_end().to_string())
-                                } else {
-                                    // For command substitution, we might want to return empty string
-                                    // instead of error for failed commands, depending on shell behavior
-                                    Ok(String::new())
-                                }
-                            }
-                            Err(_) => {
-                                // Command not found - return empty string
-                                Ok(String::new())
-                            }
-                        }
-                    }
-                }
-            }
-            Node::List {
-                statements,
-                operators,
-            } => {
-                // Handle command lists in substitution (e.g., $(cmd1; cmd2))
-                let mut last_output = String::new();
-
-                for (i, statement) in statements.iter().enumerate() {
-                    let output = self.capture_command_output(statement, evaluator)?;
-
-                    // For lists, we typically want the output of the last command
-                    if i < operators.len() {
-                        match operators[i].as_str() {
-                            "&&" => {
-                                // Only continue if previous command succeeded
-                                if output.is_empty() {
-                                    break;
-                                }
-                                last_output = output;
-                            }
-                            "||" => {
-                                // Only continue if previous command failed
-                                if !output.is_empty() {
-                                    last_output = output;
-                                    break;
-                                }
-                            }
-                            _ => {
-                                last_output = output;
-                            }
-                        }
-                    } else {
-                        last_output = output;
-                    }
-                }
+    fn evaluate_conditional_primary(
+        words: &[String],
+        position: &mut usize,
+        active: bool,
+        interpreter: &mut Interpreter,
+    ) -> Result<bool, String> {
+        let Some(word) = words.get(*position) else {
+            return Err("unexpected end of expression".to_string());
+        };
+        let is_operand = |word: Option<&String>| {
+            word.is_some_and(|word| !matches!(word.as_str(), "&&" | "||" | ")"))
+        };
 
-                Ok(last_output)
+        if word == "(" {
+            *position += 1;
+            let result = Self::evaluate_conditional_or(words, position, active, interpreter)?;
+            if words.get(*position).is_none_or(|word| word != ")") {
+                return Err("expected `)'".to_string());
             }
-            Node::Pipeline { commands } => {
-                // Handle pipelines in command substitution
-                if commands.is_empty() {
-                    return Ok(String::new());
-                }
-
-                if commands.len() == 1 {
-                    return self.capture_command_output(&commands[0], evaluator);
-                }
+            *position += 1;
+            return Ok(result);
+        }
 
-                // For now, just return the output of the last command
-                // A full implementation would need to properly pipe between commands
-                self.capture_command_output(&commands[commands.len() - 1], evaluator)
-            }
-            Node::Assignment { name, value } => {
-                // Handle variable assignment in command substitution
-                match value.as_ref() {
-                    Node::StringLiteral(string_value) => {
-                        let expanded_value = self.expand_variables(string_value);
-                        self.variables.insert(name.clone(), expanded_value.clone());
-                        Ok(expanded_value)
-                    }
-                    Node::CommandSubstitution { command } => {
-                        let output = self.capture_command_output(command, evaluator)?;
-                        self.variables.insert(name.clone(), output.clone());
-                        Ok(output)
-                    }
-                    Node::ArithmeticExpansion { expression } => {
-                        let expanded_expr = self.expand_variables(expression);
-                        match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                            Ok(result) => {
-                                let result_str = result.to_string();
-                                self.variables.insert(name.clone(), result_str.clone());
-                                Ok(result_str)
-                            }
-                            Err(_) => {
-                                eprintln!(
-                                    "arithmetic expansion: invalid expression: {expanded_expr}"
-                                );
-                                self.variables.insert(name.clone(), "0".to_string());
-                                Ok("0".to_string())
-                            }
-                        }
-                    }
-                    Node::ArithmeticCommand { expression } => {
-                        let expanded_expr = self.expand_variables(expression);
-                        match DefaultEvaluator::evaluate_arithmetic_expression(&expanded_expr) {
-                            Ok(result) => {
-                                let result_str = result.to_string();
-                                self.variables.insert(name.clone(), result_str.clone());
-                                Ok(result_str)
-                            }
-                            Err(_) => {
-                                eprintln!(
-                                    "arithmetic command: invalid expression: {expanded_expr}"
-                                );
-                                self.variables.insert(name.clone(), "0".to_string());
-                                Ok("0".to_string())
-                            }
-                        }
-                    }
-                    _ => Ok(String::new()),
+        if let Some(operator) = words.get(*position + 1) {
+            if CONDITIONAL_BINARY_OPERATORS.contains(&operator.as_str()) {
+                let Some(right) = words
+                    .get(*position + 2)
+                    .filter(|_| is_operand(words.get(*position + 2)))
+                else {
+                    return Err(format!("argument expected after `{operator}'"));
+                };
+                *position += 3;
+                if !active {
+                    return Ok(false);
                 }
+                return Self::evaluate_conditional_binary(word, operator, right, interpreter);
             }
-            Node::StringLiteral(value) => {
-                // For string literals, just return the expanded value
-                Ok(self.expand_variables(value))
-            }
-            _ => {
-                // For other node types, return empty string
-                Ok(String::new())
+        }
+
+        if CONDITIONAL_UNARY_OPERATORS.contains(&word.as_str())
+            && is_operand(words.get(*position + 1))
+        {
+            let operand = &words[*position + 1];
+            *position += 2;
+            if !active {
+                return Ok(false);
             }
+            let value = interpreter.expand_word_unsplit(operand);
+            return Ok(Self::test_unary(word, &value));
         }
-    }
 
-    // Method to evaluate arithmetic expressions with variable access
-    fn evaluate_arithmetic_with_variables(&self, expr: &str) -> Result<i64, String> {
-        // This method handles arithmetic expressions with variables and nested arithmetic expansions
+        // A lone word is true when it expands to a non-empty string
+        *position += 1;
+        Ok(active && !interpreter.expand_word_unsplit(word).is_empty())
+    }
 
-        let expr = expr.trim();
-        if expr.is_empty() {
-            return Ok(0);
+    fn evaluate_conditional_binary(
+        left: &str,
+        operator: &str,
+        right: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<bool, String> {
+        let left = interpreter.expand_word_unsplit(left);
+        if matches!(operator, "==" | "=" | "!=") {
+            let pattern = interpreter.expand_pattern(right);
+            let matched = interpreter.matches_glob_pattern(&left, &pattern);
+            return Ok(matched == (operator != "!="));
         }
 
-        // Handle simple number
-        if let Ok(num) = expr.parse::<i64>() {
-            return Ok(num);
-        }
+        // A quoted right-hand side matches literally instead of as a pattern
+        let quoted = right.contains(['"', '\'', '\\']);
+        let right_value = interpreter.expand_word_unsplit(right);
 
-        // Handle simple variable name
-        if expr.chars().all(|c| c.is_alphanumeric() || c == '_') {
-            // This is a variable name, look it up
-            if let Some(value) = self.variables.get(expr) {
-                if let Ok(num) = value.parse::<i64>() {
-                    return Ok(num);
-                }
+        match operator {
+            "=~" => {
+                let pattern = if quoted {
+                    regex::escape(&right_value)
+                } else {
+                    right_value
+                };
+                let regex = Regex::new(&pattern)
+                    .map_err(|_| format!("invalid regular expression `{pattern}'"))?;
+                let captures = regex.captures(&left);
+                interpreter.set_rematch(captures.as_ref());
+                Ok(captures.is_some())
             }
-            return Ok(0); // Unset variable defaults to 0
+            "<" => Ok(left < right_value),
+            ">" => Ok(left > right_value),
+            _ => Ok(Self::test_binary(&left, operator, &right_value)),
         }
+    }
 
-        // First, expand any nested arithmetic expressions $((...)
-        let expanded_expr = self.expand_nested_arithmetic(expr)?;
-
-        // Then expand variables in the result
-        let mut final_expr = String::new();
-        let mut chars = expanded_expr.chars().peekable();
-
-        while let Some(c) = chars.next() {
-            if c.is_alphabetic() || c == '_' {
-                // This might be a variable name
-                let mut var_name = String::new();
-                var_name.push(c);
+    fn evaluate_history_expansion(
+        &mut self,
+        pattern: &str,
+        interpreter: &mut Interpreter,
+    ) -> Result<i32, io::Error> {
+        // Check for recursion depth to prevent stack overflow
+        const MAX_HISTORY_RECURSION_DEPTH: u32 = 10;
+        if interpreter.history_expansion_depth >= MAX_HISTORY_RECURSION_DEPTH {
+            writeln!(
+                &interpreter.stderr,
+                "flash: history expansion recursion limit exceeded"
+            )?;
+            return Ok(1);
+        }
 
-                // Collect the rest of the variable name
-                while let Some(&next_c) = chars.peek() {
-                    if next_c.is_alphanumeric() || next_c == '_' {
-                        var_name.push(chars.next().unwrap());
-                    } else {
-                        break;
-                    }
+        // Handle different history expansion patterns
+        let command = if pattern.is_empty() {
+            // !! - repeat last command
+            interpreter.history.last().cloned()
+        } else if pattern.chars().all(|c| c.is_ascii_digit()) {
+            // !n - repeat command number n (1-based)
+            if let Ok(n) = pattern.parse::<usize>() {
+                if n > 0 && n <= interpreter.history.len() {
+                    Some(interpreter.history[n - 1].clone())
+                } else {
+                    writeln!(&interpreter.stderr, "flash: !{pattern}: event not found")?;
+                    return Ok(1);
                 }
-
-                // Look up the variable
-                if let Some(value) = self.variables.get(&var_name) {
-                    final_expr.push_str(value);
+            } else {
+                None
+            }
+        } else if pattern.starts_with('-') && pattern[1..].chars().all(|c| c.is_ascii_digit()) {
+            // !-n - repeat command n positions back from current
+            if let Ok(n) = pattern[1..].parse::<usize>() {
+                let history_len = interpreter.history.len();
+                if n > 0 && n <= history_len {
+                    Some(interpreter.history[history_len - n].clone())
                 } else {
-                    final_expr.push('0'); // Unset variable defaults to 0
+                    writeln!(&interpreter.stderr, "flash: !{pattern}: event not found")?;
+                    return Ok(1);
                 }
             } else {
-                final_expr.push(c);
+                None
             }
-        }
-
-        // Now evaluate the fully expanded expression using the static method
-        DefaultEvaluator::evaluate_arithmetic_expression(&final_expr)
-    }
+        } else {
+            // !string - repeat most recent command starting with string
+            interpreter
+                .history
+                .iter()
+                .rev()
+                .find(|cmd| cmd.starts_with(pattern))
+                .cloned()
+        };
 
-    // Helper method to expand nested arithmetic expressions $((...)
-    fn expand_nested_arithmetic(&self, expr: &str) -> Result<String, String> {
-        let mut result = String::new();
-        let mut chars = expr.chars().peekable();
+        match command {
+            Some(cmd) => {
+                // Check if the command we're about to execute is itself a history expansion
+                // to prevent immediate infinite recursion
+                if cmd.trim() == format!("!{pattern}") || (pattern.is_empty() && cmd.trim() == "!!")
+                {
+                    writeln!(
+                        &interpreter.stderr,
+                        "flash: history expansion would cause infinite recursion"
+                    )?;
+                    return Ok(1);
+                }
 
-        while let Some(c) = chars.next() {
-            if c == '$' && chars.peek() == Some(&'(') {
-                chars.next(); // consume '('
-                if chars.peek() == Some(&'(') {
-                    chars.next(); // consume second '('
+                // Print the command being executed (like bash does)
+                writeln!(&interpreter.stdout, "{cmd}")?;
 
-                    // Find the matching closing parentheses
-                    let mut paren_count = 2;
-                    let mut nested_expr = String::new();
+                // Increment recursion depth before executing
+                interpreter.history_expansion_depth += 1;
 
-                    while paren_count > 0 {
-                        if let Some(ch) = chars.next() {
-                            if ch == '(' {
-                                paren_count += 1;
-                            } else if ch == ')' {
-                                paren_count -= 1;
-                            }
+                // Parse and execute the command using the correct method
+                let result = interpreter.execute_with_evaluator(&cmd, self);
 
-                            if paren_count > 0 {
-                                nested_expr.push(ch);
-                            }
-                        } else {
-                            return Err(
-                                "Unmatched parentheses in arithmetic expression".to_string()
-                            );
-                        }
-                    }
+                // Decrement recursion depth after executing
+                interpreter.history_expansion_depth -= 1;
 
-                    // Recursively evaluate the nested expression
-                    let nested_result = self.evaluate_arithmetic_with_variables(&nested_expr)?;
-                    result.push_str(&nested_result.to_string());
-                } else {
-                    // Not an arithmetic expansion, put back the characters
-                    result.push('$');
-                    result.push('(');
-                }
-            } else {
-                result.push(c);
+                result
+            }
+            None => {
+                writeln!(&interpreter.stderr, "flash: !{pattern}: event not found")?;
+                Ok(1)
             }
         }
-
-        Ok(result)
     }
+}
 
-    fn expand_variables(&self, input: &str) -> String {
-        // First handle tilde expansion
-        let input = self.expand_tilde(input);
-
-        let mut result = String::new();
-        let mut chars = input.chars().peekable();
-
-        while let Some(c) = chars.next() {
-            if c == '$' && chars.peek().is_some() {
-                // Check if the next character is a valid start of a variable expansion
-                let next_char = *chars.peek().unwrap();
-                if !matches!(next_char, 'a'..='z' | 'A'..='Z' | '_' | '0'..='9' | '{' | '(' | '#' | '@' | '*' | '?' | '$')
-                {
-                    // Not a valid variable start, treat $ as literal
-                    result.push(c);
-                    continue;
-                }
+/// Shell interpreter
+pub struct Interpreter {
+    pub variables: HashMap<String, String>,
+    pub arrays: HashMap<String, BTreeMap<usize, String>>, // Indexed arrays, elements keyed by index
+    pub functions: HashMap<String, Box<Node>>,            // Store function definitions
+    pub aliases: HashMap<String, String>,                 // Store alias definitions
+    pub last_exit_code: i32,
+    pub history: Vec<String>,
+    pub history_file: Option<String>,
+    pub rc_file: Option<String>,
+    pub args: Vec<String>,         // Command line arguments ($0, $1, $2, ...)
+    pub return_value: Option<i32>, // Track return values from functions
+    pub history_expansion_depth: u32, // Track recursion depth for history expansion
+    pub completion_system: CompletionSystem, // Enhanced completion system
+    pub shell_options: HashSet<String>, // Options enabled via `set -o` or `shopt -s` (e.g. "xtrace")
+    pub nesting_level: usize,           // Depth of function calls and subshells
+    pub namerefs: HashMap<String, String>, // Name references (declare -n ref=target)
+    pub jobs: Vec<Job>,                 // Background jobs that have not been reaped yet
+    pub integer_vars: HashSet<String>,  // Variables declared with the integer attribute (-i)
+    pub associative_arrays: HashMap<String, HashMap<String, String>>, // Arrays declared with -A, elements keyed by string
+    pub exported_functions: HashSet<String>, // Functions marked for export with `export -f`
+    pub local_scopes: Vec<HashMap<String, ShadowedVariable>>, // Values shadowed by `local`, one frame per function call
+    pub loop_depth: usize, // Number of loops whose body is currently running
+    pub condition_depth: usize, // Number of if/while/until conditions or `!` commands running, where `set -e` is ignored
+    pub custom_builtins: HashMap<String, BuiltinCommand>, // Commands added by embedders
+    pub traps: HashMap<String, String>, // Commands set with `trap`, keyed by condition name
+    pub signal_dispositions: HashMap<i32, libc::sighandler_t>, // Signal handlers that traps replaced, restored when they are reset
+    pub getopts_offset: usize, // Index of the next option letter inside a clustered argument like -abc, 0 between arguments
+    pub arithmetic_error: Option<String>, // The first failed `$(( ))` of the words being expanded, which fails their command
+    pub readonly: HashSet<String>,        // Variables marked with `readonly`
+    pub interactive: bool,                // Reading commands from a terminal prompt
+    pub interruptible: bool, // Running a command typed at the prompt, which Ctrl-C abandons
+    pub dir_stack: Vec<String>, // Directories saved by pushd, most recent first
+    pub stdin: Option<Box<dyn Read + Send>>, // Host-provided input for read and select, fd 0 when None
+    pub stdin_spool: Option<fs::File>, // The rest of the host's input once a command needed it as a descriptor, shared with the commands
+    pub stdin_redirections: usize, // Redirections of fd 0 in effect, which read follows instead of the host's input
+    pub stdout: OutputStream, // Where builtins write output, fd 1 unless the host sets a writer
+    pub stderr: OutputStream, // Where builtins write errors, fd 2 unless the host sets a writer
+}
 
-                // Check for command substitution $(...) or arithmetic expansion $((...))
-                if let Some(&'(') = chars.peek() {
-                    chars.next(); // Skip first '('
+/// A command added with `Interpreter::register_builtin`, called with the expanded arguments
+pub type BuiltinCommand =
+    Arc<dyn Fn(&mut Interpreter, &[String]) -> Result<i32, io::Error> + Send + Sync>;
+
+/// What a variable held before `local` shadowed it, put back when the function returns
+#[derive(Debug, Clone)]
+pub enum ShadowedVariable {
+    Unset,
+    Scalar(String),
+    Indexed(BTreeMap<usize, String>),
+    Associative(HashMap<String, String>),
+}
 
-                    // Check if this is arithmetic expansion $((...))
-                    if let Some(&'(') = chars.peek() {
-                        chars.next(); // Skip second '('
+/// A background job started with `&`
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: usize,
+    pub pid: i32,
+    pub command: String,
+    pub inherited: bool, // Started by the shell this one was copied from: listed, but not its child to wait for
+}
 
-                        // Find the matching closing parentheses for arithmetic expansion
-                        let mut paren_count = 2;
-                        let mut arith_content = String::new();
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-                        for ch in chars.by_ref() {
-                            if ch == '(' {
-                                paren_count += 1;
-                            } else if ch == ')' {
-                                paren_count -= 1;
-                                if paren_count == 0 {
-                                    break;
-                                }
-                            }
-                            if paren_count > 0 {
-                                arith_content.push(ch);
-                            }
-                        }
+impl Interpreter {
+    /// Mark the named functions for export, or remove the mark; a name that is not a
+    /// function is an error
+    pub fn mark_functions_exported(
+        &mut self,
+        names: &[String],
+        export: bool,
+        builtin: &str,
+    ) -> i32 {
+        let mut status = 0;
+        for name in names {
+            if !self.functions.contains_key(name) {
+                let _ = writeln!(&self.stderr, "{builtin}: {name}: not a function");
+                status = 1;
+            } else if export {
+                self.exported_functions.insert(name.clone());
+            } else {
+                self.exported_functions.remove(name);
+            }
+        }
+        status
+    }
+
+    /// Print the exported functions as `declare -fx name`, sorted by name
+    fn list_exported_functions(&self) {
+        let mut names: Vec<&String> = self.exported_functions.iter().collect();
+        names.sort();
+        for name in names {
+            let _ = writeln!(&self.stdout, "declare -fx {name}");
+        }
+    }
+
+    /// Option names accepted by `set -o`/`set +o`
+    pub const SHELL_OPTIONS: &'static [&'static str] = &["errexit", "pipefail", "posix", "xtrace"];
+
+    /// Option names accepted by `shopt -s`/`shopt -u`
+    pub const SHOPT_OPTIONS: &'static [&'static str] = &["extglob", "globstar"];
+
+    /// Commands implemented directly by the shell
+    pub const BUILTINS: &'static [&'static str] = &[
+        "cd",
+        "echo",
+        "export",
+        "source",
+        ".",
+        "[",
+        "test",
+        "exit",
+        "set",
+        "shopt",
+        "true",
+        "false",
+        "seq",
+        "alias",
+        "unalias",
+        "complete",
+        "compgen",
+        "command",
+        "declare",
+        "wait",
+        "jobs",
+        "printf",
+        "local",
+        "fg",
+        "bg",
+        "disown",
+        "kill",
+        "trap",
+        "mapfile",
+        "readarray",
+        "read",
+        "getopts",
+        "readonly",
+        "typeset",
+        "pushd",
+        "popd",
+        "dirs",
+    ];
+
+    /// PATH used by `command -p`, guaranteed to find the standard utilities
+    pub const DEFAULT_PATH: &'static str = "/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin";
 
-                        // Evaluate the arithmetic expression
-                        if !arith_content.is_empty() {
-                            match self.evaluate_arithmetic_with_variables(&arith_content) {
-                                Ok(arith_result) => {
-                                    result.push_str(&arith_result.to_string());
-                                }
-                                Err(_) => {
-                                    eprintln!(
-                                        "arithmetic expansion: invalid expression: {arith_content}"
-                                    );
-                                    result.push('0');
-                                }
-                            }
-                        }
-                    } else {
-                        // Regular command substitution $(...)
-                        // Find the matching closing parenthesis
-                        let mut paren_count = 1;
-                        let mut cmd_content = String::new();
+    pub fn new() -> Self {
+        // Initialize some basic environment variables
+        let mut variables = HashMap::default();
 
-                        for ch in chars.by_ref() {
-                            if ch == '(' {
-                                paren_count += 1;
-                            } else if ch == ')' {
-                                paren_count -= 1;
-                                if paren_count == 0 {
-                                    break;
-                                }
-                            }
-                            cmd_content.push(ch);
-                        }
+        if let Ok(variables_from_proc) = flash::env::load_env_from_proc() {
+            for (key, value) in variables_from_proc.iter() {
+                variables.insert(key.to_owned(), value.to_owned());
+            }
+        } else {
+            // Fallback to std::env if /proc/self/environ is not available (e.g., on macOS)
+            for (key, value) in std::env::vars() {
+                variables.insert(key, value);
+            }
+        }
 
-                        // Execute the command substitution
-                        if !cmd_content.is_empty() {
-                            if let Ok(output) = self.execute_command_for_substitution(&cmd_content)
-                            {
-                                result.push_str(&output);
-                            }
-                        }
-                    }
-                    continue;
+        // Set up some shell variables
+        variables.insert("?".to_string(), "0".to_string());
+        variables.insert("SHELL".to_string(), "flash".to_string());
+        variables.insert("$$".to_string(), std::process::id().to_string());
+        variables.insert("PPID".to_string(), unsafe { libc::getppid() }.to_string());
+        variables.insert(
+            "FLASH_VERSION".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        );
+
+        // Initialize PWD to current working directory
+        if let Ok(current_dir) = env::current_dir() {
+            variables.insert("PWD".to_string(), current_dir.to_string_lossy().to_string());
+        }
+
+        // Add /opt/homebrew/bin to PATH on macOS
+        #[cfg(target_os = "macos")]
+        {
+            if let Some(current_path) = variables.get("PATH") {
+                if !current_path.contains("/opt/homebrew/bin") {
+                    let new_path = format!("/opt/homebrew/bin:{current_path}");
+                    variables.insert("PATH".to_string(), new_path);
                 }
+            } else {
+                variables.insert("PATH".to_string(), "/opt/homebrew/bin".to_string());
+            }
+        }
 
-                let mut var_name = String::new();
+        let home_dir = env::var("HOME").ok();
 
-                // Variable can be specified as ${VAR} or $VAR
-                if let Some(&'{') = chars.peek() {
-                    chars.next(); // Skip '{'
+        let history_file = home_dir
+            .as_ref()
+            .map(|home| format!("{home}/.flash_history"));
 
-                    // Read until closing brace
-                    for c in chars.by_ref() {
-                        if c == '}' {
-                            break;
-                        }
-                        var_name.push(c);
-                    }
-                } else {
-                    // Read variable name
-                    // Handle special single-character variables first
-                    if let Some(&c) = chars.peek() {
-                        if matches!(c, '#' | '@' | '*' | '?' | '$') {
-                            var_name.push(c);
-                            chars.next();
-                        } else {
-                            // Read until non-alphanumeric character
-                            while let Some(&c) = chars.peek() {
-                                if c.is_alphanumeric() || c == '_' {
-                                    var_name.push(c);
-                                    chars.next();
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
+        let rc_file = home_dir.as_ref().map(|home| format!("{home}/.flashrc"));
 
-                // Replace with variable value if exists
-                if let Some(value) = self.variables.get(&var_name) {
-                    result.push_str(value);
-                } else if var_name.chars().all(|c| c.is_ascii_digit()) {
-                    // Handle positional parameters ($0, $1, $2, ...)
-                    if let Ok(index) = var_name.parse::<usize>() {
-                        if let Some(arg) = self.args.get(index) {
-                            result.push_str(arg);
-                        }
-                        // If the argument doesn't exist, expand to empty string (standard shell behavior)
-                    }
-                } else if var_name == "#" {
-                    // $# - number of positional parameters (excluding $0)
-                    let count = if self.args.is_empty() {
-                        0
-                    } else {
-                        self.args.len() - 1
-                    };
-                    result.push_str(&count.to_string());
-                } else if var_name == "@" || var_name == "*" {
-                    // $@ and $* - all positional parameters (excluding $0)
-                    if self.args.len() > 1 {
-                        let params = &self.args[1..];
-                        result.push_str(&params.join(" "));
-                    }
+        // Load history from file if it exists
+        let mut history = Vec::new();
+        if let Some(ref file_path) = history_file {
+            if let Ok(file) = fs::File::open(file_path) {
+                let reader = io::BufReader::new(file);
+                for line in reader.lines().map_while(Result::ok) {
+                    history.push(line);
                 }
-            } else {
-                result.push(c);
             }
         }
 
-        result
-    }
+        let mut interpreter = Self {
+            variables,
+            arrays: HashMap::new(),    // No arrays yet
+            functions: HashMap::new(), // Initialize empty functions map
+            aliases: HashMap::new(),   // Initialize empty aliases map
+            last_exit_code: 0,
+            history,
+            history_file,
+            rc_file,
+            args: Vec::new(), // Initialize empty args, will be set when running scripts
+            return_value: None, // Initialize return value as None
+            history_expansion_depth: 0, // Initialize history expansion depth
+            completion_system: CompletionSystem::new(), // Initialize enhanced completion system
+            shell_options: HashSet::new(), // No shell options enabled by default
+            nesting_level: 0, // Top-level execution
+            namerefs: HashMap::new(), // No name references yet
+            jobs: Vec::new(), // No background jobs yet
+            integer_vars: HashSet::new(), // No integer variables yet
+            associative_arrays: HashMap::new(), // No associative arrays yet
+            exported_functions: HashSet::new(), // No exported functions yet
+            local_scopes: Vec::new(), // Not inside a function
+            loop_depth: 0,    // Not inside a loop
+            condition_depth: 0, // Not inside a condition
+            custom_builtins: HashMap::new(), // No embedder commands yet
+            traps: HashMap::new(), // No traps set
+            signal_dispositions: HashMap::new(), // No signals trapped
+            getopts_offset: 0, // getopts starts at the beginning of an argument
+            arithmetic_error: None, // No expansion has failed yet
+            readonly: HashSet::new(), // No readonly variables yet
+            interactive: false, // Set once the prompt loop starts
+            interruptible: false, // Only while a command typed at the prompt runs
+            dir_stack: Vec::new(), // No directories pushed yet
+            stdin: None,      // Read from the real stdin
+            stdin_spool: None, // Nothing spooled yet
+            stdin_redirections: 0, // fd 0 is not redirected
+            stdout: OutputStream::new(1), // Write to the real stdout
+            stderr: OutputStream::new(2), // Write to the real stderr
+        };
 
-    /// Expand tilde (~) to home directory
-    fn expand_tilde(&self, input: &str) -> String {
-        if input.is_empty() {
-            return input.to_string();
+        // Load and execute flashrc file if it exists
+        if let Err(e) = interpreter.load_rc_file() {
+            let _ = writeln!(&interpreter.stderr, "Warning: Error loading flashrc: {e}");
         }
 
-        // Get home directory
-        let home_dir = match std::env::var("HOME") {
-            Ok(home) => home,
-            Err(_) => return input.to_string(), // If HOME is not set, return unchanged
-        };
+        interpreter
+    }
 
-        let mut result = String::new();
-        let mut chars = input.chars().peekable();
-        let mut at_word_start = true; // Track if we're at the start of a word
+    /// Create an interpreter driven by host streams: `read` and `select` take input from
+    /// `stdin`, builtins write to `stdout` and `stderr`, and the output of external
+    /// commands is copied to them as it arrives. The first command that needs `stdin` as
+    /// a descriptor reads what is left of it to the end into a temporary file
+    pub fn with_io(
+        stdin: Box<dyn Read + Send>,
+        stdout: Box<dyn Write + Send>,
+        stderr: Box<dyn Write + Send>,
+    ) -> Self {
+        let mut interpreter = Self::new();
+        interpreter.stdin = Some(stdin);
+        interpreter.stdout.set_writer(Some(stdout));
+        interpreter.stderr.set_writer(Some(stderr));
+        interpreter
+    }
 
-        while let Some(c) = chars.next() {
-            if c == '~' && at_word_start {
-                // Check what follows the tilde
-                match chars.peek() {
-                    None => {
-                        // Tilde at end of string - expand to home directory
-                        result.push_str(&home_dir);
-                    }
-                    Some('/') => {
-                        // Tilde followed by slash - expand to home directory
-                        result.push_str(&home_dir);
-                    }
-                    Some(' ') | Some('\t') => {
-                        // Tilde followed by whitespace - expand to home directory
-                        result.push_str(&home_dir);
-                    }
-                    Some(_) => {
-                        // Tilde followed by other characters - could be ~username
-                        // For now, we'll only support ~ for current user
-                        // In the future, we could add support for ~username
-                        let mut username = String::new();
-                        while let Some(&ch) = chars.peek() {
-                            if ch == '/' || ch.is_whitespace() {
-                                break;
-                            }
-                            username.push(ch);
-                            chars.next();
-                        }
+    /// Set command line arguments for the interpreter
+    /// args[0] should be the script name, args[1] should be $1, etc.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
 
-                        if username.is_empty() {
-                            // Just ~ followed by non-slash, non-whitespace
-                            result.push_str(&home_dir);
-                        } else {
-                            // ~username - for now, treat as literal
-                            // TODO: Add support for other users' home directories
-                            result.push('~');
-                            result.push_str(&username);
-                        }
-                    }
-                }
-                at_word_start = false;
-            } else {
-                result.push(c);
-                // Update word boundary tracking
-                at_word_start = c.is_whitespace() || c == ':' || c == '=';
-            }
-        }
+    /// Evaluate an already parsed script with `args` as `$1`, `$2`, ..., keeping `$0`.
+    /// The previous positional parameters are restored afterwards, so the same AST can
+    /// be run many times without lexing or parsing it again.
+    pub fn run_ast(&mut self, ast: &Node, args: Vec<String>) -> io::Result<i32> {
+        let zero = self.args.first().cloned().unwrap_or_default();
+        let saved_args = std::mem::replace(&mut self.args, vec![zero]);
+        self.args.extend(args);
+        let result = self.evaluate_with_evaluator(ast, &mut DefaultEvaluator);
+        self.args = saved_args;
+        result
+    }
 
+    /// Run a complete non-interactive script, followed by its `EXIT` trap
+    pub fn run_script(&mut self, script: &str) -> Result<i32, io::Error> {
+        let result = self.execute(script);
+        if let Ok(code) = result {
+            self.last_exit_code = code;
+        }
+        self.run_exit_trap();
         result
     }
 
-    // Helper method to execute commands for substitution
-    fn execute_command_for_substitution(&self, cmd: &str) -> Result<String, io::Error> {
-        use crate::lexer::Lexer;
-        use crate::parser::Parser;
+    /// Run the `EXIT` trap if one is set. It is removed first, so it runs at most
+    /// once even when it calls `exit` itself.
+    pub fn run_exit_trap(&mut self) {
+        if let Some(command) = self.traps.remove("EXIT") {
+            let status = self.last_exit_code;
+            self.variables.insert("?".to_string(), status.to_string());
+            let _ = self.execute(&command);
+            self.last_exit_code = status;
+        }
+    }
 
-        let lexer = Lexer::new(cmd);
-        let mut parser = Parser::new(lexer);
-        let ast = parser.parse_script();
+    /// Report a failed `${name?word}` or `${name=word}` expansion. An interactive shell
+    /// only fails the command; any other shell exits with status 1 after its EXIT trap.
+    fn expansion_error(&mut self, message: &str) -> Result<i32, io::Error> {
+        writeln!(&self.stderr, "{message}")?;
+        if !self.interactive {
+            self.last_exit_code = 1;
+            self.run_exit_trap();
+            std::process::exit(1);
+        }
+        Ok(1)
+    }
 
-        // Create a temporary interpreter for execution
-        let mut temp_interpreter = Interpreter {
-            variables: self.variables.clone(),
-            functions: self.functions.clone(),
-            aliases: self.aliases.clone(),
-            last_exit_code: self.last_exit_code,
-            history: Vec::new(),
-            history_file: None,
-            rc_file: None,
-            args: self.args.clone(),
-            return_value: None,
-            history_expansion_depth: 0,
-            completion_system: CompletionSystem::new(),
-        };
+    /// Make `path` element 0 of `BASH_SOURCE` while it is sourced, moving the files
+    /// that sourced it up one place
+    fn push_bash_source(&mut self, path: &str) {
+        let sources = self.arrays.remove("BASH_SOURCE").unwrap_or_default();
+        let sources = std::iter::once(path.to_string()).chain(sources.into_values());
+        self.arrays
+            .insert("BASH_SOURCE".to_string(), sources.enumerate().collect());
+    }
 
-        let mut evaluator = DefaultEvaluator;
-        temp_interpreter.capture_command_output(&ast, &mut evaluator)
+    /// Undo `push_bash_source` once the sourced file has finished
+    fn pop_bash_source(&mut self) {
+        let sources = self.arrays.remove("BASH_SOURCE").unwrap_or_default();
+        let sources: BTreeMap<usize, String> = sources.into_values().skip(1).enumerate().collect();
+        if !sources.is_empty() {
+            self.arrays.insert("BASH_SOURCE".to_string(), sources);
+        }
     }
 
-    /// Expand glob patterns in command arguments
-    pub fn expand_glob_patterns(&self, args: &[String]) -> Vec<String> {
-        let mut expanded_args = Vec::new();
-
-        for arg in args {
-            // First expand brace patterns
-            let brace_expanded = self.expand_brace_patterns(arg);
+    /// Canonical name of a `trap` condition, or None if it is not supported
+    fn trap_condition_name(condition: &str) -> Option<&'static str> {
+        match condition.to_ascii_uppercase().as_str() {
+            "0" | "EXIT" => Some("EXIT"),
+            _ => signal_number(condition)
+                .filter(|number| *number < 128)
+                .and_then(signal_name),
+        }
+    }
 
-            // Then expand glob patterns for each brace-expanded result
-            for brace_arg in brace_expanded {
-                if self.contains_glob_pattern(&brace_arg) {
-                    let matches = self.glob_match(&brace_arg);
-                    if matches.is_empty() {
-                        // If no matches found, keep the original pattern
-                        expanded_args.push(brace_arg);
-                    } else {
-                        expanded_args.extend(matches);
-                    }
-                } else {
-                    expanded_args.push(brace_arg);
+    /// Print the traps set for `conditions`, or all of them in signal number order, as
+    /// the commands that set them again: trap -- 'echo caught' SIGINT
+    fn print_traps(&self, conditions: &[String]) -> Result<i32, io::Error> {
+        let mut status = 0;
+        let mut names = Vec::new();
+        for condition in conditions {
+            match Interpreter::trap_condition_name(condition) {
+                Some(name) => names.push(name.to_string()),
+                None => {
+                    writeln!(
+                        &self.stderr,
+                        "trap: {condition}: invalid signal specification"
+                    )?;
+                    status = 1;
                 }
             }
         }
+        if conditions.is_empty() {
+            names = self.traps.keys().cloned().collect();
+            names.sort_by_key(|name| signal_number(name).unwrap_or(0));
+        }
 
-        expanded_args
+        for name in names {
+            let Some(command) = self.traps.get(&name) else {
+                continue;
+            };
+            let name = match signal_number(&name) {
+                Some(_) => format!("SIG{name}"),
+                None => name,
+            };
+            writeln!(
+                &self.stdout,
+                "trap -- '{}' {name}",
+                command.replace('\'', "'\\''")
+            )?;
+        }
+        Ok(status)
     }
 
-    /// Expand brace patterns like {1..5} or {a..z}
-    pub fn expand_brace_patterns(&self, input: &str) -> Vec<String> {
-        // Check if the input contains brace expansion patterns
-        if !input.contains('{') || !input.contains('}') {
-            return vec![input.to_string()];
+    /// Set the command of a trap. A trapped signal is recorded when it arrives so the
+    /// command can run after the current one, or ignored when the command is empty
+    pub fn set_trap(&mut self, condition: &str, command: &str) {
+        if let Some(signal) = signal_number(condition) {
+            let handler = if command.is_empty() {
+                libc::SIG_IGN
+            } else {
+                record_signal as extern "C" fn(libc::c_int) as libc::sighandler_t
+            };
+            let previous = unsafe { libc::signal(signal, handler) };
+            self.signal_dispositions.entry(signal).or_insert(previous);
         }
+        self.traps
+            .insert(condition.to_string(), command.to_string());
+    }
 
-        // Find all brace expansion patterns in the input
-        let mut result = vec![input.to_string()];
-
-        // Process each string in result for brace expansions
-        let mut i = 0;
-        while i < result.len() {
-            let current = &result[i].clone();
-            if let Some(expanded) = self.expand_single_brace_pattern(current) {
-                // Replace the current item with expanded items
-                result.remove(i);
-                for (j, item) in expanded.into_iter().enumerate() {
-                    result.insert(i + j, item);
+    /// Remove a trap, giving a signal back the handling it had before it was trapped
+    pub fn reset_trap(&mut self, condition: &str) {
+        if let Some(signal) = signal_number(condition) {
+            if let Some(previous) = self.signal_dispositions.remove(&signal) {
+                unsafe {
+                    libc::signal(signal, previous);
                 }
-                // Continue processing from the same index since we may have more patterns
-                continue;
             }
-            i += 1;
         }
-
-        result
+        self.traps.remove(condition);
     }
 
-    /// Expand a single brace pattern in a string
-    fn expand_single_brace_pattern(&self, input: &str) -> Option<Vec<String>> {
-        // Find the first brace expansion pattern
-        let mut brace_start = None;
-        let mut brace_end = None;
-        let mut brace_count = 0;
-
-        for (i, c) in input.char_indices() {
-            match c {
-                '{' => {
-                    if brace_start.is_none() {
-                        brace_start = Some(i);
-                    }
-                    brace_count += 1;
-                }
-                '}' => {
-                    brace_count -= 1;
-                    if brace_count == 0 && brace_start.is_some() {
-                        brace_end = Some(i);
-                        break;
-                    }
-                }
-                _ => {}
+    /// Run the traps of the signals that arrived since the last check, keeping `$?`
+    pub fn run_signal_traps(&mut self) {
+        let pending = PENDING_SIGNALS.swap(0, Ordering::SeqCst);
+        if pending == 0 {
+            return;
+        }
+        for (name, signal) in SIGNAL_NAMES {
+            if pending & (1 << signal) == 0 {
+                continue;
+            }
+            if let Some(command) = self.traps.get(*name).cloned() {
+                let status = self.last_exit_code;
+                let _ = self.execute(&command);
+                self.last_exit_code = status;
+                self.variables.insert("?".to_string(), status.to_string());
             }
         }
+    }
 
-        if let (Some(start), Some(end)) = (brace_start, brace_end) {
-            let prefix = &input[..start];
-            let suffix = &input[end + 1..];
-            let brace_content = &input[start + 1..end];
-
-            // Check for range expansion (e.g., "1..5" or "a..z")
-            if brace_content.contains("..") {
-                if let Some(range_items) = self.expand_brace_range(brace_content) {
-                    let mut results = Vec::new();
-                    for item in range_items {
-                        results.push(format!("{prefix}{item}{suffix}"));
+    /// Load and execute the flashrc file
+    fn load_rc_file(&mut self) -> io::Result<()> {
+        if let Some(ref rc_path) = self.rc_file.clone() {
+            if Path::new(rc_path).exists() {
+                match fs::read_to_string(rc_path) {
+                    Ok(content) => {
+                        // Execute the rc file content
+                        // We ignore errors in rc file execution to prevent shell startup failure
+                        if let Err(e) = self.execute(&content) {
+                            writeln!(&self.stderr, "Warning: Error executing flashrc: {e}")?;
+                        }
+                    }
+                    Err(e) => {
+                        return Err(io::Error::other(format!(
+                            "Failed to read flashrc file {rc_path}: {e}"
+                        )));
                     }
-                    return Some(results);
-                }
-            }
-
-            // Check for comma-separated expansion (e.g., "a,b,c")
-            if brace_content.contains(',') {
-                let items: Vec<&str> = brace_content.split(',').collect();
-                let mut results = Vec::new();
-                for item in items {
-                    results.push(format!("{}{}{}", prefix, item.trim(), suffix));
                 }
-                return Some(results);
             }
         }
-
-        None
+        Ok(())
     }
 
-    /// Expand brace ranges like {1..10} or {a..z}
-    fn expand_brace_range(&self, content: &str) -> Option<Vec<String>> {
-        let parts: Vec<&str> = content.split("..").collect();
-        if parts.len() != 2 {
-            return None;
-        }
-
-        let start = parts[0].trim();
-        let end = parts[1].trim();
+    /// Reload the flashrc file (useful for testing changes without restarting)
+    pub fn reload_rc_file(&mut self) -> io::Result<()> {
+        self.load_rc_file()
+    }
 
-        // Try numeric expansion
-        if let (Ok(start_num), Ok(end_num)) = (start.parse::<i32>(), end.parse::<i32>()) {
-            let mut result = Vec::new();
-            if start_num <= end_num {
-                for i in start_num..=end_num {
-                    result.push(i.to_string());
-                }
-            } else {
-                for i in (end_num..=start_num).rev() {
-                    result.push(i.to_string());
-                }
-            }
-            return Some(result);
-        }
+    /// Get the path to the flashrc file
+    pub fn get_rc_file_path(&self) -> Option<String> {
+        self.rc_file.clone()
+    }
 
-        // Try character expansion (single characters only)
-        if start.len() == 1 && end.len() == 1 {
-            let start_char = start.chars().next().unwrap();
-            let end_char = end.chars().next().unwrap();
+    /// Set a custom rc file path
+    pub fn set_rc_file_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.rc_file = Some(path.as_ref().to_string_lossy().to_string());
+    }
 
-            // Only expand if both characters are in the same case (both lowercase or both uppercase)
-            let both_lowercase = start_char.is_ascii_lowercase() && end_char.is_ascii_lowercase();
-            let both_uppercase = start_char.is_ascii_uppercase() && end_char.is_ascii_uppercase();
+    /// Index of the newest history entry before `before` that contains `term`, as used by
+    /// Ctrl-R; an empty history never matches
+    fn reverse_search_history(&self, term: &str, before: usize) -> Option<usize> {
+        self.history[..before.min(self.history.len())]
+            .iter()
+            .rposition(|line| line.contains(term))
+    }
 
-            if both_lowercase || both_uppercase {
-                let mut result = Vec::new();
+    /// The line Ctrl-R leaves to edit when the search is accepted: the matched history
+    /// entry, or the original line when nothing matched
+    fn reverse_search_result(&self, found: Option<usize>, original: &str) -> String {
+        found
+            .and_then(|index| self.history.get(index))
+            .cloned()
+            .unwrap_or_else(|| original.to_string())
+    }
 
-                if start_char <= end_char {
-                    for c in start_char..=end_char {
-                        result.push(c.to_string());
-                    }
-                } else {
-                    for c in (end_char..=start_char).rev() {
-                        result.push(c.to_string());
-                    }
-                }
-                return Some(result);
+    fn save_history(&self) -> io::Result<()> {
+        if let Some(ref file_path) = self.history_file {
+            let mut file = fs::File::create(file_path)?;
+            for line in &self.history {
+                writeln!(file, "{line}")?;
             }
         }
-
-        None
+        Ok(())
     }
 
-    /// Check if a string contains glob patterns
-    fn contains_glob_pattern(&self, s: &str) -> bool {
-        s.contains('*') || s.contains('?') || s.contains('[')
-    }
+    // Generate completion candidates for the current input using the enhanced completion system
+    pub fn generate_completions(
+        &mut self,
+        input: &str,
+        cursor_pos: usize,
+    ) -> (Vec<String>, Vec<String>) {
+        // Variable names come from the interpreter, which the completion system can't see
+        if let Some((prefix, braced)) = variable_completion_prefix(&input[..cursor_pos]) {
+            return self.complete_variable_names(prefix, braced);
+        }
 
-    /// Match a glob pattern against files in the specified directory
-    fn glob_match_in_dir(&self, pattern: &str, search_dir: &Path) -> Vec<String> {
-        let mut matches = Vec::new();
+        // Parse the completion context
+        let context = CompletionSystem::parse_context(input, cursor_pos);
 
-        // Handle absolute and relative paths
-        let (dir_path, file_pattern) = if let Some(last_slash) = pattern.rfind('/') {
-            let dir = &pattern[..last_slash];
-            let file = &pattern[last_slash + 1..];
-            (search_dir.join(dir), file.to_string())
-        } else {
-            (search_dir.to_path_buf(), pattern.to_string())
-        };
+        // Get completions from the enhanced completion system
+        let completions = self.completion_system.complete(&context);
 
-        // Read directory entries
-        if let Ok(entries) = fs::read_dir(&dir_path) {
-            for entry in entries.flatten() {
-                if let Some(filename) = entry.file_name().to_str() {
-                    // Skip hidden files unless pattern explicitly starts with '.'
-                    if filename.starts_with('.') && !file_pattern.starts_with('.') {
-                        continue;
-                    }
+        // Calculate suffixes for the current word
+        let current_word = &context.current_word;
+        let mut suffixes = Vec::new();
+        let mut full_names = Vec::new();
 
-                    if self.matches_glob_pattern(filename, &file_pattern) {
-                        let full_path = if pattern.contains('/') {
-                            format!("{}/{}", dir_path.display(), filename)
-                        } else {
-                            filename.to_string()
-                        };
-                        matches.push(full_path);
-                    }
-                }
+        for completion in completions {
+            if completion.starts_with(current_word) {
+                // Calculate the suffix (what needs to be added)
+                let suffix = &completion[current_word.len()..];
+                suffixes.push(suffix.to_string());
+                full_names.push(completion);
+            } else if current_word.is_empty() {
+                // If current word is empty, the whole completion is the suffix
+                suffixes.push(completion.clone());
+                full_names.push(completion);
             }
         }
 
-        // Sort matches for consistent output
-        matches.sort();
-        matches
-    }
+        // Fallback to old completion system for variables if new system returns nothing
+        if suffixes.is_empty() {
+            return self.generate_completions_fallback(input, cursor_pos);
+        }
 
-    /// Match a glob pattern against files in the current directory
-    fn glob_match(&self, pattern: &str) -> Vec<String> {
-        self.glob_match_in_dir(pattern, Path::new("."))
+        (suffixes, full_names)
     }
 
-    /// Check if a filename matches a glob pattern
-    fn matches_glob_pattern(&self, filename: &str, pattern: &str) -> bool {
-        self.glob_match_recursive(filename, pattern, 0, 0)
+    // Complete a variable name after `$`, or inside `${` where the closing brace is
+    // inserted along with the rest of the name
+    fn complete_variable_names(&self, prefix: &str, braced: bool) -> (Vec<String>, Vec<String>) {
+        let (open, close) = if braced { ("${", "}") } else { ("$", "") };
+        let mut names = self.declared_names();
+        if prefix.is_empty() {
+            names.extend(SPECIAL_PARAMETERS.iter().map(|name| name.to_string()));
+        }
+        names
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| {
+                (
+                    format!("{}{close}", &name[prefix.len()..]),
+                    format!("{open}{name}{close}"),
+                )
+            })
+            .unzip()
     }
 
-    /// Recursive glob pattern matching implementation
-    fn glob_match_recursive(
+    // Fallback completion method (the old implementation)
+    fn generate_completions_fallback(
         &self,
-        filename: &str,
-        pattern: &str,
-        f_idx: usize,
-        p_idx: usize,
-    ) -> bool {
-        let f_chars: Vec<char> = filename.chars().collect();
-        let p_chars: Vec<char> = pattern.chars().collect();
+        input: &str,
+        cursor_pos: usize,
+    ) -> (Vec<String>, Vec<String>) {
+        let input_up_to_cursor = &input[..cursor_pos];
+        let words: Vec<&str> = input_up_to_cursor.split_whitespace().collect();
 
-        // Base cases
-        if p_idx >= p_chars.len() {
-            return f_idx >= f_chars.len();
+        // If we're at the beginning of the line or just completed a word
+        if words.is_empty() || input_up_to_cursor.ends_with(' ') {
+            // Return list of available commands
+            let (suffixes, full_names) = self.get_commands("");
+            return (suffixes, full_names);
         }
 
-        if f_idx >= f_chars.len() {
-            // Check if remaining pattern is all '*'
-            return p_chars[p_idx..].iter().all(|&c| c == '*');
+        // If we're completing the first word (command)
+        if words.len() == 1 && !input_up_to_cursor.ends_with(' ') {
+            let prefix = words[0];
+            let (suffixes, full_names) = self.get_commands(prefix);
+            return (suffixes, full_names);
         }
 
-        match p_chars[p_idx] {
-            '*' => {
-                // Try matching zero or more characters
-                // First try matching zero characters (skip the *)
-                if self.glob_match_recursive(filename, pattern, f_idx, p_idx + 1) {
-                    return true;
-                }
-                // Then try matching one or more characters
-                for i in f_idx..f_chars.len() {
-                    if self.glob_match_recursive(filename, pattern, i + 1, p_idx + 1) {
-                        return true;
-                    }
+        // Check if we're completing after a pipe (should complete commands)
+        if words.len() >= 2 {
+            // Look for the last pipe in the words
+            let mut last_pipe_index = None;
+            for (i, word) in words.iter().enumerate() {
+                if *word == "|" || *word == "&&" || *word == "||" {
+                    last_pipe_index = Some(i);
                 }
-                false
-            }
-            '?' => {
-                // Match exactly one character
-                self.glob_match_recursive(filename, pattern, f_idx + 1, p_idx + 1)
             }
-            '[' => {
-                // Character class matching
-                if let Some(end_bracket) = self.find_closing_bracket(&p_chars, p_idx) {
-                    let char_class = &p_chars[p_idx + 1..end_bracket];
-                    if self.matches_char_class(f_chars[f_idx], char_class) {
-                        self.glob_match_recursive(filename, pattern, f_idx + 1, end_bracket + 1)
-                    } else {
-                        false
-                    }
-                } else {
-                    // Invalid bracket, treat as literal
-                    f_chars[f_idx] == '['
-                        && self.glob_match_recursive(filename, pattern, f_idx + 1, p_idx + 1)
+
+            if let Some(pipe_index) = last_pipe_index {
+                // If we're right after a pipe or completing the first word after a pipe
+                if pipe_index == words.len() - 2 && !input_up_to_cursor.ends_with(' ') {
+                    // We're completing the command after the pipe
+                    let prefix = words.last().unwrap_or(&"");
+                    let (suffixes, full_names) = self.get_commands(prefix);
+                    return (suffixes, full_names);
+                } else if pipe_index == words.len() - 1 && input_up_to_cursor.ends_with(' ') {
+                    // We just finished typing the pipe and are starting a new command
+                    let (suffixes, full_names) = self.get_commands("");
+                    return (suffixes, full_names);
                 }
             }
-            c => {
-                // Literal character match
-                f_chars[f_idx] == c
-                    && self.glob_match_recursive(filename, pattern, f_idx + 1, p_idx + 1)
-            }
         }
+
+        // Otherwise, assume we're completing a filename
+        let last_word = if input_up_to_cursor.ends_with(' ') {
+            ""
+        } else {
+            words.last().unwrap_or(&"")
+        };
+
+        let (suffixes, full_names) = self.get_path_completions(last_word);
+        (suffixes, full_names)
     }
 
-    /// Find the closing bracket for a character class
-    fn find_closing_bracket(&self, chars: &[char], start: usize) -> Option<usize> {
-        let mut i = start + 1;
-        while i < chars.len() {
-            if chars[i] == ']' && i > start + 1 {
-                return Some(i);
+    // Get list of commands that match the given prefix
+    fn get_commands(&self, prefix: &str) -> (Vec<String>, Vec<String>) {
+        let mut suffixes = Vec::new();
+        let mut full_names = Vec::new();
+
+        // Add built-ins
+        for cmd in &[
+            "cd", "echo", "export", "source", ".", "exit", "alias", "unalias",
+        ] {
+            if cmd.starts_with(prefix) {
+                full_names.push(cmd.to_string());
+                if let Some(stripped) = cmd.strip_prefix(prefix) {
+                    suffixes.push(stripped.to_string());
+                }
             }
-            i += 1;
         }
-        None
-    }
 
-    /// Check if a character matches a character class
-    fn matches_char_class(&self, ch: char, char_class: &[char]) -> bool {
-        if char_class.is_empty() {
-            return false;
+        // Add aliases
+        for alias_name in self.aliases.keys() {
+            if alias_name.starts_with(prefix) {
+                full_names.push(alias_name.to_string());
+                if let Some(stripped) = alias_name.strip_prefix(prefix) {
+                    suffixes.push(stripped.to_string());
+                }
+            }
         }
 
-        let negated = char_class[0] == '!' || char_class[0] == '^';
-        let class_chars = if negated {
-            &char_class[1..]
-        } else {
-            char_class
-        };
-
-        let mut matches = false;
-        let mut i = 0;
-
-        while i < class_chars.len() {
-            if i + 2 < class_chars.len() && class_chars[i + 1] == '-' {
-                // Range like a-z
-                let start = class_chars[i];
-                let end = class_chars[i + 2];
-                if ch >= start && ch <= end {
-                    matches = true;
-                    break;
-                }
-                i += 3;
-            } else {
-                // Single character
-                if ch == class_chars[i] {
-                    matches = true;
-                    break;
+        // Add commands from PATH
+        if let Ok(path) = env::var("PATH") {
+            for path_entry in path.split(':') {
+                if let Ok(entries) = fs::read_dir(path_entry) {
+                    for entry in entries.flatten() {
+                        if let Some(name) = entry.file_name().to_str() {
+                            if name.starts_with(prefix) {
+                                if let Some(stripped) = name.strip_prefix(prefix) {
+                                    if let Ok(metadata) = entry.path().metadata() {
+                                        if metadata.is_file()
+                                            && metadata.permissions().mode() & 0o111 != 0
+                                        {
+                                            full_names.push(name.to_string());
+                                            suffixes.push(stripped.to_string());
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
                 }
-                i += 1;
             }
         }
 
-        if negated { !matches } else { matches }
+        // Sort and deduplicate both lists
+        full_names.sort();
+        full_names.dedup();
+        suffixes.sort();
+        suffixes.dedup();
+
+        (suffixes, full_names)
     }
 
-    /// Parse alias value handling escaped spaces and quotes
-    fn parse_alias_value(&self, value: &str) -> Vec<String> {
-        let mut parts = Vec::new();
-        let mut current_part = String::new();
-        let mut chars = value.chars().peekable();
-        let mut in_quotes = false;
-        let mut quote_char = '\0';
+    // Get file/directory completions for the given path prefix
+    fn get_path_completions(&self, prefix: &str) -> (Vec<String>, Vec<String>) {
+        let mut suffixes = Vec::new();
+        let mut full_names = Vec::new();
 
-        while let Some(ch) = chars.next() {
-            match ch {
-                '\\' => {
-                    // Handle escape sequences
-                    if let Some(&next_ch) = chars.peek() {
-                        match next_ch {
-                            ' ' | '\t' | '\\' | '"' | '\'' => {
-                                // Escape the next character
-                                current_part.push(chars.next().unwrap());
-                            }
-                            _ => {
-                                // Not a recognized escape, keep the backslash
-                                current_part.push(ch);
+        // Expand tilde (~) to home directory if present
+        let expanded_prefix = self.expand_tilde(prefix);
+
+        // Determine the directory to search and the filename prefix
+        let (dir_path, file_prefix) = if expanded_prefix.contains('/') {
+            if expanded_prefix.ends_with('/') {
+                // If prefix ends with '/', we want to list all files in that directory
+                (PathBuf::from(&expanded_prefix), String::new())
+            } else {
+                let path = Path::new(&expanded_prefix);
+                let parent = path.parent().unwrap_or(Path::new(""));
+                let file_name = path.file_name().map_or("", |f| f.to_str().unwrap_or(""));
+                (parent.to_path_buf(), file_name.to_string())
+            }
+        } else {
+            (PathBuf::from("."), expanded_prefix.clone())
+        };
+
+        // Read the directory entries
+        if let Ok(entries) = fs::read_dir(dir_path.clone()) {
+            for entry in entries.flatten() {
+                if let Some(name) = entry.file_name().to_str() {
+                    if name.starts_with(&file_prefix) {
+                        // For display, show the path in the original form (with ~ if it was used)
+                        let full_path = if expanded_prefix.contains('/') {
+                            // If the original prefix started with ~, preserve that in the display
+                            if prefix.starts_with('~') && expanded_prefix != prefix {
+                                // Replace the expanded home directory with ~ in the display
+                                let home_dir = std::env::var("HOME").unwrap_or_default();
+                                let dir_display = dir_path.display().to_string();
+                                if dir_display.starts_with(&home_dir) {
+                                    let relative_path = &dir_display[home_dir.len()..];
+                                    // Handle the case where relative_path starts with / or is empty
+                                    if relative_path.is_empty() || relative_path == "/" {
+                                        // When completing directly in home directory, show just the filename like bash
+                                        name.to_string()
+                                    } else if relative_path.starts_with('/') {
+                                        format!("~{relative_path}/{name}")
+                                    } else {
+                                        format!("~/{relative_path}/{name}")
+                                    }
+                                } else {
+                                    format!("{}/{}", dir_path.display(), name)
+                                }
+                            } else {
+                                format!("{}/{}", dir_path.display(), name)
                             }
+                        } else {
+                            name.to_string()
+                        };
+
+                        let mut display_name = full_path.clone();
+                        let mut suffix = name[file_prefix.len()..].to_string();
+
+                        // Add a trailing slash for directories
+                        if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
+                            display_name.push('/');
+                            suffix.push('/');
                         }
-                    } else {
-                        // Backslash at end of string
-                        current_part.push(ch);
-                    }
-                }
-                '"' | '\'' => {
-                    if !in_quotes {
-                        // Start quoted section
-                        in_quotes = true;
-                        quote_char = ch;
-                    } else if ch == quote_char {
-                        // End quoted section
-                        in_quotes = false;
-                        quote_char = '\0';
-                    } else {
-                        // Different quote character inside quotes
-                        current_part.push(ch);
-                    }
-                }
-                ' ' | '\t' => {
-                    if in_quotes {
-                        // Space inside quotes is literal
-                        current_part.push(ch);
-                    } else {
-                        // Space outside quotes separates arguments
-                        if !current_part.is_empty() {
-                            parts.push(current_part);
-                            current_part = String::new();
+
+                        full_names.push(display_name);
+                        if !suffix.is_empty() {
+                            suffixes.push(suffix);
                         }
                     }
                 }
-                _ => {
-                    current_part.push(ch);
+            }
+        }
+
+        suffixes.sort();
+        full_names.sort();
+        (suffixes, full_names)
+    }
+
+    // Display a list of completions
+    fn display_completions(&self, completions: &[String]) -> io::Result<()> {
+        if completions.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(&self.stdout)?; // Move to a new line
+
+        // Calculate the maximum width of completions
+        let max_width = completions.iter().map(|s| s.len()).max().unwrap_or(0) + 2;
+        let term_width = self.get_terminal_width();
+        let columns = std::cmp::max(1, term_width / max_width);
+
+        // Display completions in columns
+        for (i, completion) in completions.iter().enumerate() {
+            write!(&self.stdout, "{completion:<max_width$}")?;
+            if (i + 1) % columns == 0 {
+                writeln!(&self.stdout)?;
+            }
+        }
+
+        // Ensure we end with a newline
+        if completions.len() % columns != 0 {
+            writeln!(&self.stdout)?;
+        }
+
+        Ok(())
+    }
+
+    // Get the terminal width
+    fn get_terminal_width(&self) -> usize {
+        use std::process::Command;
+
+        let width = if cfg!(unix) {
+            // On Unix-like systems, try `tput cols`
+            Command::new("tput")
+                .arg("cols")
+                .output()
+                .ok()
+                .and_then(|output| {
+                    if output.status.success() {
+                        String::from_utf8(output.stdout)
+                            .ok()?
+                            .trim()
+                            .parse::<usize>()
+                            .ok()
+                    } else {
+                        None
+                    }
+                })
+                .or_else(|| {
+                    // Fallback: try `stty size` and extract columns
+                    Command::new("stty")
+                        .arg("size")
+                        .output()
+                        .ok()
+                        .and_then(|output| {
+                            if output.status.success() {
+                                let size_str = String::from_utf8(output.stdout).ok()?;
+                                let parts: Vec<&str> = size_str.split_whitespace().collect();
+                                if parts.len() >= 2 {
+                                    parts[1].parse::<usize>().ok()
+                                } else {
+                                    None
+                                }
+                            } else {
+                                None
+                            }
+                        })
+                })
+        } else if cfg!(windows) {
+            // On Windows, try PowerShell to get console width
+            Command::new("powershell")
+                .args(["-Command", "(Get-Host).UI.RawUI.WindowSize.Width"])
+                .output()
+                .ok()
+                .and_then(|output| {
+                    if output.status.success() {
+                        String::from_utf8(output.stdout)
+                            .ok()?
+                            .trim()
+                            .parse::<usize>()
+                            .ok()
+                    } else {
+                        None
+                    }
+                })
+        } else {
+            None
+        };
+
+        // Return the detected width or default to 80
+        width.unwrap_or(80)
+    }
+
+    /// Get the current prompt string, expanding variables
+    fn get_prompt(&self) -> String {
+        if let Some(prompt_template) = self.variables.get("PROMPT") {
+            // Use the existing expand_variables method for proper variable expansion
+            self.expand_variables(prompt_template)
+        } else {
+            "ϟ ".to_string()
+        }
+    }
+
+    // Interactive shell that accepts a custom evaluator
+    pub fn run_interactive_with_evaluator<E: Evaluator>(
+        &mut self,
+        mut evaluator: E,
+    ) -> io::Result<()> {
+        let stream = self.stdout.share();
+        let mut stdout = &stream;
+        let fd = io::stdin().as_raw_fd();
+
+        // Keys come from the host's reader when one is set, otherwise from the terminal
+        let terminal = self.stdin.is_none();
+        if terminal && unsafe { libc::isatty(fd) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Interactive mode requires a terminal",
+            ));
+        }
+
+        let original_termios = terminal.then(|| tcgetattr(fd)).transpose()?;
+        let mut raw_termios = original_termios;
+
+        // RAII guard to restore terminal settings on drop
+        struct TermiosGuard {
+            fd: i32,
+            original: libc::termios,
+        }
+
+        impl Drop for TermiosGuard {
+            fn drop(&mut self) {
+                let _ = tcsetattr(self.fd, libc::TCSANOW, &self.original);
+            }
+        }
+
+        let _guard = original_termios.map(|original| TermiosGuard { fd, original });
+
+        // Ignore SIGINT (Ctrl+C) at the prompt so the shell does not exit; commands
+        // make it interruptible again while they run
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+        }
+
+        let mut history_index = self.history.len();
+        self.interactive = true;
+
+        loop {
+            for notice in self.reap_jobs() {
+                let _ = writeln!(&self.stdout, "{notice}");
+            }
+
+            let prompt = self.get_prompt();
+            write!(stdout, "{prompt}")?;
+            stdout.flush()?;
+
+            let input = match self.read_line_with_completion(
+                &prompt,
+                original_termios.as_ref(),
+                &mut raw_termios,
+                &mut history_index,
+            ) {
+                Ok(input) => input,
+                // The host's input ran out, as Ctrl-D at an empty prompt would
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !terminal => break,
+                Err(e) => return Err(e),
+            };
+
+            if input.trim().is_empty() {
+                continue;
+            }
+
+            if input.trim() == "exit" {
+                break;
+            }
+
+            if self.add_to_history(&input) {
+                history_index = self.history.len();
+                let _ = self.save_history();
+            }
+
+            let result = self.execute_foreground_with_evaluator(&input, &mut evaluator);
+
+            match result {
+                Ok(code) => {
+                    self.last_exit_code = code;
+                    self.variables.insert("?".to_string(), code.to_string());
+                }
+                Err(e) => {
+                    let _ = writeln!(&self.stdout, "Error: {e}");
+                    self.last_exit_code = 1;
+                    self.variables.insert("?".to_string(), "1".to_string());
                 }
             }
         }
 
-        // Add the last part if it's not empty
-        if !current_part.is_empty() {
-            parts.push(current_part);
+        self.run_exit_trap();
+        self.save_history()?;
+        Ok(())
+    }
+
+    /// Run a command typed at the prompt. While it runs, Ctrl-C stops its children
+    /// (exec resets a caught signal to the default action) and is recorded by the shell,
+    /// which runs the INT trap and abandons the rest of the command, as it does when a
+    /// child dies of SIGINT, then goes back to ignoring SIGINT
+    pub fn execute_foreground_with_evaluator<E: Evaluator>(
+        &mut self,
+        input: &str,
+        evaluator: &mut E,
+    ) -> io::Result<i32> {
+        // `trap '' INT` keeps Ctrl-C ignored, and children inherit that
+        self.interruptible = self
+            .traps
+            .get("INT")
+            .is_none_or(|command| !command.is_empty());
+        if self.interruptible {
+            unsafe {
+                libc::signal(
+                    libc::SIGINT,
+                    record_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                );
+            }
+        }
+        let result = self.execute_with_evaluator(input, evaluator);
+        self.run_signal_traps();
+        self.interruptible = false;
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+        }
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted && e.to_string() == "interrupt" => {
+                Ok(128 + libc::SIGINT)
+            }
+            result => result,
+        }
+    }
+
+    /// Pass on the exit code of a foreground command. One that died of SIGINT
+    /// interrupts a command typed at the prompt, as if Ctrl-C had reached the shell too
+    fn foreground_status(&self, code: i32) -> i32 {
+        if self.interruptible && code == 128 + libc::SIGINT {
+            PENDING_SIGNALS.fetch_or(1 << libc::SIGINT, Ordering::SeqCst);
+        }
+        code
+    }
+
+    // Default interactive shell using DefaultEvaluator
+    pub fn run_interactive(&mut self) -> io::Result<()> {
+        let default_evaluator = DefaultEvaluator;
+        self.run_interactive_with_evaluator(default_evaluator)
+    }
+
+    /// Read the bytes of a key typed at the prompt, from the host's reader when one is set
+    fn read_key(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match self.stdin.as_mut() {
+            Some(input) => input.read_exact(buf),
+            None => io::stdin().read_exact(buf),
+        }
+    }
+
+    fn read_line_with_completion(
+        &mut self,
+        prompt: &str,
+        original_termios: Option<&libc::termios>,
+        raw_termios: &mut Option<libc::termios>,
+        history_index: &mut usize,
+    ) -> io::Result<String> {
+        let stream = self.stdout.share();
+        let mut stdout = &stream;
+        let fd = io::stdin().as_raw_fd();
+
+        let mut buffer = String::new();
+        let mut cursor_pos = 0;
+
+        // For storing the kill ring (for cut/paste operations)
+        let mut kill_ring = String::new();
+
+        loop {
+            // Switch to raw mode to read individual characters
+            if let Some(raw_termios) = raw_termios.as_mut() {
+                raw_termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+                raw_termios.c_cc[libc::VMIN] = 1;
+                raw_termios.c_cc[libc::VTIME] = 0;
+                tcsetattr(fd, libc::TCSANOW, raw_termios)?;
+            }
+
+            // Read a single byte
+            let mut input_byte = [0u8; 1];
+            self.read_key(&mut input_byte)?;
+
+            // Switch back to canonical mode for printing
+            if let Some(original_termios) = original_termios {
+                tcsetattr(fd, libc::TCSANOW, original_termios)?;
+            }
+
+            match input_byte[0] {
+                // Enter
+                b'\n' | b'\r' => {
+                    writeln!(stdout)?;
+                    break;
+                }
+
+                // Tab for completion
+                b'\t' => {
+                    let (suffixes, full_names) = self.generate_completions(&buffer, cursor_pos);
+
+                    match suffixes.len().cmp(&1) {
+                        std::cmp::Ordering::Less => {
+                            // Do nothing - no completions available
+                        }
+                        std::cmp::Ordering::Equal => {
+                            // If there's only one completion, use it
+                            let suffix = &suffixes[0];
+                            buffer.insert_str(cursor_pos, suffix);
+                            cursor_pos += suffix.len();
+
+                            // Redraw the line with the completion
+                            write!(stdout, "\r{prompt}{buffer}")?;
+                            stdout.flush()?;
+                        }
+                        std::cmp::Ordering::Greater => {
+                            // Find the common prefix among suffixes
+                            if let Some(common_prefix) = self.find_common_prefix(&suffixes) {
+                                if !common_prefix.is_empty() {
+                                    // Only insert the common prefix
+                                    buffer.insert_str(cursor_pos, &common_prefix);
+                                    cursor_pos += common_prefix.len();
+
+                                    // Redraw the line with the partial completion
+                                    write!(stdout, "\r{prompt}{buffer}")?;
+                                    stdout.flush()?;
+                                } else {
+                                    // No common prefix, show all completions (using full names for display)
+                                    self.display_completions(&full_names)?;
+                                    // Redraw the prompt and line
+                                    write!(stdout, "{prompt}{buffer}")?;
+                                    stdout.flush()?;
+                                }
+                            } else {
+                                // No common prefix found, show all completions (using full names for display)
+                                self.display_completions(&full_names)?;
+                                // Redraw the prompt and line
+                                write!(stdout, "{prompt}{buffer}")?;
+                                stdout.flush()?;
+                            }
+                        }
+                    }
+                }
+                // Backspace
+                8 | 127 => {
+                    if cursor_pos > 0 {
+                        buffer.remove(cursor_pos - 1);
+                        cursor_pos -= 1;
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        write!(stdout, " ")?; // Clear deleted character
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-A (move to beginning of line)
+                1 => {
+                    cursor_pos = 0;
+                    write!(stdout, "\r{prompt}")?;
+                    stdout.flush()?;
+                }
+
+                // Ctrl-E (move to end of line)
+                5 => {
+                    cursor_pos = buffer.len();
+                    write!(stdout, "\r{prompt}{buffer}")?;
+                    stdout.flush()?;
+                }
+
+                // Ctrl-B (move back one character) - same as left arrow
+                2 => {
+                    if cursor_pos > 0 {
+                        cursor_pos -= 1;
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        // Move cursor back to the right position
+                        for _ in 0..(buffer.len() - cursor_pos) {
+                            write!(stdout, "\x1B[D")?;
+                        }
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-F (move forward one character) - same as right arrow
+                6 => {
+                    if cursor_pos < buffer.len() {
+                        cursor_pos += 1;
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        // Move cursor back to the right position
+                        for _ in 0..(buffer.len() - cursor_pos) {
+                            write!(stdout, "\x1B[D")?;
+                        }
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-K (kill from cursor to end of line)
+                11 => {
+                    if cursor_pos < buffer.len() {
+                        // Save the killed text
+                        kill_ring = buffer[cursor_pos..].to_string();
+
+                        // Remove from buffer
+                        buffer.truncate(cursor_pos);
+
+                        // Redraw
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        write!(stdout, "                    ")?; // Clear any leftovers
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-U (kill from beginning of line to cursor)
+                21 => {
+                    if cursor_pos > 0 {
+                        // Save the killed text
+                        kill_ring = buffer[..cursor_pos].to_string();
+
+                        // Remove from buffer
+                        buffer = buffer[cursor_pos..].to_string();
+                        cursor_pos = 0;
+
+                        // Redraw
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        write!(stdout, "                    ")?; // Clear any leftovers
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-Y (yank/paste previously killed text)
+                25 => {
+                    if !kill_ring.is_empty() {
+                        buffer.insert_str(cursor_pos, &kill_ring);
+                        cursor_pos += kill_ring.len();
+
+                        // Redraw
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        // Move cursor back to the right position
+                        for _ in 0..(buffer.len() - cursor_pos) {
+                            write!(stdout, "\x1B[D")?;
+                        }
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-W (delete word backward)
+                23 => {
+                    // Delete the word before the cursor
+                    if cursor_pos > 0 {
+                        // Find the start of the current word
+                        let mut word_start = cursor_pos;
+                        let buffer_bytes = buffer.as_bytes();
+
+                        // Skip any whitespace immediately before cursor
+                        while word_start > 0 && buffer_bytes[word_start - 1].is_ascii_whitespace() {
+                            word_start -= 1;
+                        }
+
+                        // Now find the start of the word
+                        while word_start > 0 && !buffer_bytes[word_start - 1].is_ascii_whitespace()
+                        {
+                            word_start -= 1;
+                        }
+
+                        // Save to kill ring
+                        kill_ring = buffer[word_start..cursor_pos].to_string();
+
+                        // Delete from word_start to cursor_pos
+                        if word_start < cursor_pos {
+                            buffer.replace_range(word_start..cursor_pos, "");
+                            cursor_pos = word_start;
+
+                            // Redraw the line
+                            write!(stdout, "\r{prompt}{buffer}")?;
+                            write!(stdout, "                    ")?; // Clear any leftovers
+                            write!(stdout, "\r{prompt}{buffer}")?;
+                            stdout.flush()?;
+                        }
+                    }
+                }
+
+                // Ctrl-L (clear screen)
+                12 => {
+                    // Clear the screen and redraw the prompt
+                    write!(stdout, "\x1B[2J\x1B[H")?; // ANSI escape sequence to clear screen and move cursor to home
+                    write!(stdout, "{prompt}{buffer}")?;
+                    stdout.flush()?;
+                }
+
+                // Ctrl-P (previous history) - same as up arrow
+                16 => {
+                    if *history_index > 0 {
+                        *history_index -= 1;
+                        buffer = self.history[*history_index].clone();
+                        cursor_pos = buffer.len();
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        write!(stdout, "                    ")?; // Clear any leftovers
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-N (next history) - same as down arrow
+                14 => {
+                    if *history_index < self.history.len() {
+                        *history_index += 1;
+                        if *history_index == self.history.len() {
+                            buffer.clear();
+                            cursor_pos = 0;
+                        } else {
+                            buffer = self.history[*history_index].clone();
+                            cursor_pos = buffer.len();
+                        }
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        write!(stdout, "                    ")?; // Clear any leftovers
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-T (transpose characters)
+                20 => {
+                    // Handle cursor at end of line
+                    if cursor_pos == buffer.len() && cursor_pos >= 2 {
+                        // Swap the last two characters
+                        let last_idx = buffer.len() - 1;
+                        let second_to_last_idx = buffer.len() - 2;
+
+                        // Can't use remove/insert directly with indices, so use chars
+                        let mut chars: Vec<char> = buffer.chars().collect();
+                        chars.swap(last_idx, second_to_last_idx);
+
+                        // Rebuild the buffer
+                        buffer = chars.into_iter().collect();
+
+                        // Cursor remains at the end
+
+                        // Redraw
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        stdout.flush()?;
+                    }
+                    // Handle cursor within the line
+                    else if cursor_pos > 0 && cursor_pos < buffer.len() {
+                        // Get chars to swap
+                        let prev_char = buffer.remove(cursor_pos - 1);
+                        buffer.insert(cursor_pos, prev_char);
+
+                        // Advance cursor position after transposition
+                        cursor_pos += 1;
+
+                        // Redraw
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        // Move cursor back to the right position
+                        for _ in 0..(buffer.len() - cursor_pos) {
+                            write!(stdout, "\x1B[D")?;
+                        }
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-D (delete character under cursor or exit if buffer is empty)
+                4 => {
+                    if buffer.is_empty() {
+                        writeln!(stdout, "exit")?;
+                        return Ok("exit".to_string());
+                    } else if cursor_pos < buffer.len() {
+                        buffer.remove(cursor_pos);
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        write!(stdout, " ")?; // Clear deleted character
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        // Move cursor back to the right position
+                        for _ in 0..(buffer.len() - cursor_pos) {
+                            write!(stdout, "\x1B[D")?;
+                        }
+                        stdout.flush()?;
+                    }
+                }
+
+                // Ctrl-R (reverse history search) - simplified version
+                18 => {
+                    // Store the current buffer in case search is cancelled
+                    let original_buffer = buffer.clone();
+                    let original_cursor_pos = cursor_pos;
+
+                    // Create a search buffer; `found` is the index of the current match
+                    let mut search_term = String::new();
+                    let mut found: Option<usize> = None;
+
+                    // Display the search prompt
+                    write!(stdout, "\r(reverse-i-search)`': ")?;
+                    stdout.flush()?;
+
+                    // Read characters for search
+                    loop {
+                        // Read a single byte in raw mode
+                        if let Some(raw_termios) = raw_termios.as_mut() {
+                            raw_termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+                            tcsetattr(fd, libc::TCSANOW, raw_termios)?;
+                        }
+                        let mut search_byte = [0u8; 1];
+                        self.read_key(&mut search_byte)?;
+                        if let Some(original_termios) = original_termios {
+                            tcsetattr(fd, libc::TCSANOW, original_termios)?;
+                        }
+
+                        match search_byte[0] {
+                            // Enter - accept the current match
+                            b'\n' | b'\r' => {
+                                buffer = self.reverse_search_result(found, &original_buffer);
+                                if found.is_some() {
+                                    write!(stdout, "\r\n")?;
+                                    cursor_pos = buffer.len();
+                                } else {
+                                    write!(stdout, "\r{prompt}{original_buffer}")?;
+                                    cursor_pos = original_cursor_pos;
+                                }
+                                stdout.flush()?;
+                                break;
+                            }
+
+                            // Escape - cancel search
+                            27 => {
+                                write!(stdout, "\r{prompt}{original_buffer}")?;
+                                cursor_pos = original_cursor_pos;
+                                stdout.flush()?;
+                                break;
+                            }
+
+                            // Ctrl-R - search for next occurrence
+                            18 => {
+                                if let Some(current) = found {
+                                    // Start search from one past the current match
+                                    match self.reverse_search_history(&search_term, current) {
+                                        Some(next) => {
+                                            found = Some(next);
+                                            write!(
+                                                stdout,
+                                                "\r(reverse-i-search)`{}': {}",
+                                                search_term, self.history[next]
+                                            )?;
+                                        }
+                                        None => {
+                                            write!(
+                                                stdout,
+                                                "\r(failed reverse-i-search)`{}': {}",
+                                                search_term, self.history[current]
+                                            )?;
+                                        }
+                                    }
+                                    stdout.flush()?;
+                                }
+                            }
+
+                            // Backspace and regular characters edit the search term, which
+                            // is then searched for from the end of history
+                            byte => {
+                                let ch = byte as char;
+                                if matches!(byte, 8 | 127) {
+                                    if search_term.pop().is_none() {
+                                        continue;
+                                    }
+                                } else if ch.is_ascii() && !ch.is_control() {
+                                    search_term.push(ch);
+                                } else {
+                                    continue;
+                                }
+
+                                found =
+                                    self.reverse_search_history(&search_term, self.history.len());
+                                match found {
+                                    Some(index) => write!(
+                                        stdout,
+                                        "\r(reverse-i-search)`{}': {}",
+                                        search_term, self.history[index]
+                                    )?,
+                                    None => write!(
+                                        stdout,
+                                        "\r(failed reverse-i-search)`{search_term}': "
+                                    )?,
+                                }
+                                stdout.flush()?;
+                            }
+                        }
+                    }
+
+                    continue; // Skip the rest of the loop for this iteration
+                }
+
+                // Ctrl-C
+                3 => {
+                    // Print ^C and move to new line (like bash)
+                    write!(stdout, "^C")?;
+                    stdout.flush()?;
+                    writeln!(stdout)?;
+
+                    // Clear the current buffer and reset cursor
+                    buffer.clear();
+                    cursor_pos = 0;
+
+                    // Show a fresh prompt on the new line
+                    write!(stdout, "{prompt}")?;
+                    stdout.flush()?;
+                }
+
+                // Escape sequence (arrow keys, etc.)
+                27 => {
+                    // Read the next two bytes
+                    let mut escape_seq = [0u8; 2];
+                    self.read_key(&mut escape_seq)?;
+
+                    if escape_seq[0] == b'[' {
+                        match escape_seq[1] {
+                            // Up arrow - history navigation
+                            b'A' => {
+                                if *history_index > 0 {
+                                    *history_index -= 1;
+                                    buffer = self.history[*history_index].clone();
+                                    cursor_pos = buffer.len();
+                                    write!(stdout, "\r{prompt}{buffer}")?;
+                                    write!(stdout, "                    ")?; // Clear any leftovers
+                                    write!(stdout, "\r{prompt}{buffer}")?;
+                                    stdout.flush()?;
+                                }
+                            }
+
+                            // Down arrow - history navigation
+                            b'B' => {
+                                if *history_index < self.history.len() {
+                                    *history_index += 1;
+                                    if *history_index == self.history.len() {
+                                        buffer.clear();
+                                        cursor_pos = 0;
+                                    } else {
+                                        buffer = self.history[*history_index].clone();
+                                        cursor_pos = buffer.len();
+                                    }
+                                    write!(stdout, "\r{prompt}{buffer}")?;
+                                    write!(stdout, "                    ")?; // Clear any leftovers
+                                    write!(stdout, "\r{prompt}{buffer}")?;
+                                    stdout.flush()?;
+                                }
+                            }
+
+                            // Left arrow
+                            b'D' => {
+                                if cursor_pos > 0 {
+                                    cursor_pos -= 1;
+                                    write!(stdout, "\r{prompt}{buffer}")?;
+                                    // Move cursor back to the right position
+                                    for _ in 0..(buffer.len() - cursor_pos) {
+                                        write!(stdout, "\x1B[D")?;
+                                    }
+                                    stdout.flush()?;
+                                }
+                            }
+
+                            // Right arrow
+                            b'C' => {
+                                if cursor_pos < buffer.len() {
+                                    cursor_pos += 1;
+                                    write!(stdout, "\r{prompt}{buffer}")?;
+                                    // Move cursor back to the right position
+                                    for _ in 0..(buffer.len() - cursor_pos) {
+                                        write!(stdout, "\x1B[D")?;
+                                    }
+                                    stdout.flush()?;
+                                }
+                            }
+
+                            // Alt+Left (Home) or Alt+Right (End) could be added here
+                            _ => {}
+                        }
+                    }
+                }
+
+                // Regular character
+                _ => {
+                    let ch = input_byte[0] as char;
+                    if ch.is_ascii() && !ch.is_control() {
+                        buffer.insert(cursor_pos, ch);
+                        cursor_pos += 1;
+                        write!(stdout, "\r{prompt}{buffer}")?;
+                        // Move cursor back to the right position
+                        for _ in 0..(buffer.len() - cursor_pos) {
+                            write!(stdout, "\x1B[D")?;
+                        }
+                        stdout.flush()?;
+                    }
+                }
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    // Find the longest common prefix among completion candidates
+    fn find_common_prefix(&self, completions: &[String]) -> Option<String> {
+        if completions.is_empty() {
+            return None;
+        }
+
+        if completions.len() == 1 {
+            return Some(completions[0].clone());
+        }
+
+        let first = &completions[0];
+        let mut common_len = first.len();
+
+        for completion in &completions[1..] {
+            let mut i = 0;
+            let mut matched = true;
+
+            for (c1, c2) in first.chars().zip(completion.chars()) {
+                if c1 != c2 {
+                    matched = false;
+                    break;
+                }
+                i += 1;
+            }
+
+            if !matched {
+                common_len = common_len.min(i);
+            } else {
+                common_len = common_len.min(completion.len());
+            }
+        }
+
+        if common_len == 0 {
+            return None;
+        }
+
+        Some(first[..common_len].to_string())
+    }
+
+    // Main execution method that accepts a custom evaluator.
+    pub fn execute_with_evaluator<E: Evaluator>(
+        &mut self,
+        input: &str,
+        evaluator: &mut E,
+    ) -> Result<i32, io::Error> {
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let ast = parser.parse_script();
+        self.evaluate_with_evaluator(&ast, evaluator)
+    }
+
+    /// The stream builtins write to for descriptor `fd`, when it is stdout or stderr
+    fn output_stream(&self, fd: i32) -> Option<&OutputStream> {
+        match fd {
+            1 => Some(&self.stdout),
+            2 => Some(&self.stderr),
+            _ => None,
+        }
+    }
+
+    // Default execute method using DefaultEvaluator.
+    pub fn execute(&mut self, input: &str) -> Result<i32, io::Error> {
+        let mut default_evaluator = DefaultEvaluator;
+        self.execute_with_evaluator(input, &mut default_evaluator)
+    }
+
+    /// Execute `input` with the shell's stdout and stderr collected, returning the exit
+    /// code and what was written to each, external commands included
+    pub fn execute_capture(&mut self, input: &str) -> Result<(i32, String, String), io::Error> {
+        // Host writers are set aside so the output comes back here instead
+        let stdout = CaptureBuffer::default();
+        let stderr = CaptureBuffer::default();
+        let host_stdout = self.stdout.set_writer(Some(Box::new(stdout.clone())));
+        let host_stderr = self.stderr.set_writer(Some(Box::new(stderr.clone())));
+        let result = self.execute(input);
+        self.stdout.set_writer(host_stdout);
+        self.stderr.set_writer(host_stderr);
+        let code = result?;
+        Ok((code, stdout.contents(), stderr.contents()))
+    }
+
+    // Internal evaluation method that uses the provided evaluator
+    pub fn evaluate_with_evaluator<E: Evaluator>(
+        &mut self,
+        node: &Node,
+        evaluator: &mut E,
+    ) -> Result<i32, io::Error> {
+        let result = evaluator.evaluate(node, self);
+        let pending = PENDING_SIGNALS.load(Ordering::Relaxed);
+        if pending != 0 {
+            self.run_signal_traps();
+            // Ctrl-C abandons the rest of a command typed at the prompt, loops included
+            if self.interruptible && pending & (1 << libc::SIGINT) != 0 {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupt"));
+            }
+        }
+        result
+    }
+
+    // Helper method for matching extended glob patterns
+    fn matches_ext_glob(
+        &self,
+        filename: &str,
+        operator: char,
+        patterns: &[String],
+        suffix: &str,
+    ) -> bool {
+        let pattern = format!("{operator}({}){suffix}", patterns.join("|"));
+        let filename: Vec<char> = filename.chars().collect();
+        let pattern: Vec<char> = pattern.chars().collect();
+        self.ext_glob_match(&filename, &pattern)
+    }
+
+    /// Run `node` as a command substitution and return its output without the trailing
+    /// newlines. It runs in a forked copy of the shell, as a subshell would: what it
+    /// changes stays there, and `exit` ends only the copy
+    pub fn capture_command_output<E: Evaluator>(
+        &mut self,
+        node: &Node,
+        evaluator: &mut E,
+    ) -> Result<String, io::Error> {
+        // $(< file) reads the file directly instead of running a command
+        if let Node::Command {
+            name, redirects, ..
+        } = node
+        {
+            if let ("", [redirect]) = (name.as_str(), &redirects[..]) {
+                if redirect.kind == RedirectKind::Input {
+                    return Ok(self.read_substitution_file(&redirect.file));
+                }
+            }
+        }
+
+        let input = match self.host_input_file()? {
+            Some(file) => PipeInput::File(file),
+            None => PipeInput::Inherit,
+        };
+        // Only stderr goes on to the host's writer; stdout is the substitution's value
+        let pipes = HostPipes {
+            stdout: None,
+            ..HostPipes::new(self)?
+        };
+        let (pid, output) = self.fork_stage(node, input, &pipes, evaluator)?;
+        let output = pipes.forward_collecting(Some(output), self)?;
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        self.foreground_status(wait_status_code(status));
+
+        Ok(String::from_utf8_lossy(&output)
+            .trim_end_matches('\n')
+            .to_string())
+    }
+
+    // Method to evaluate arithmetic expressions with variable access
+    fn evaluate_arithmetic_with_variables(&self, expr: &str) -> Result<i64, String> {
+        // Parameter expansions and nested $((...)) are expanded first; bare names are
+        // left to the evaluator, which treats each variable's value as a subexpression
+        let expanded_expr = self.expand_parameters(expr);
+        arithmetic::evaluate_with(&expanded_expr, &mut ReadOnlyVariables(self))
+    }
+
+    fn expand_variables(&self, input: &str) -> String {
+        // First handle tilde expansion
+        self.expand_parameters(&self.expand_tilde(input))
+    }
+
+    /// Expand `$` parameters, command substitutions and arithmetic without tilde
+    /// expansion, as inside `$(( ))` where `~` is bitwise negation
+    fn expand_parameters(&self, input: &str) -> String {
+        let mut result = String::new();
+        let mut chars = input.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c == '$' && chars.peek().is_some() {
+                // Check if the next character is a valid start of a variable expansion
+                let next_char = *chars.peek().unwrap();
+                if !matches!(next_char, 'a'..='z' | 'A'..='Z' | '_' | '0'..='9' | '{' | '(' | '#' | '@' | '*' | '?' | '$' | '!')
+                {
+                    // Not a valid variable start, treat $ as literal
+                    result.push(c);
+                    continue;
+                }
+
+                // Check for command substitution $(...) or arithmetic expansion $((...))
+                if let Some(&'(') = chars.peek() {
+                    chars.next(); // Skip first '('
+
+                    // Check if this is arithmetic expansion $((...))
+                    if let Some(&'(') = chars.peek() {
+                        chars.next(); // Skip second '('
+
+                        // Find the matching closing parentheses for arithmetic expansion
+                        let mut paren_count = 2;
+                        let mut arith_content = String::new();
+
+                        for ch in chars.by_ref() {
+                            if ch == '(' {
+                                paren_count += 1;
+                            } else if ch == ')' {
+                                paren_count -= 1;
+                                if paren_count == 0 {
+                                    break;
+                                }
+                            }
+                            if paren_count > 0 {
+                                arith_content.push(ch);
+                            }
+                        }
+                        // The first ')' of the closing '))' was collected too
+                        arith_content.pop();
+
+                        // Evaluate the arithmetic expression
+                        if !arith_content.is_empty() {
+                            match self.evaluate_arithmetic_with_variables(&arith_content) {
+                                Ok(arith_result) => {
+                                    result.push_str(&arith_result.to_string());
+                                }
+                                Err(message) => {
+                                    let _ =
+                                        writeln!(&self.stderr, "flash: {arith_content}: {message}");
+                                }
+                            }
+                        }
+                    } else {
+                        // Regular command substitution $(...)
+                        // Find the matching closing parenthesis
+                        let mut paren_count = 1;
+                        let mut cmd_content = String::new();
+
+                        for ch in chars.by_ref() {
+                            if ch == '(' {
+                                paren_count += 1;
+                            } else if ch == ')' {
+                                paren_count -= 1;
+                                if paren_count == 0 {
+                                    break;
+                                }
+                            }
+                            cmd_content.push(ch);
+                        }
+
+                        // Execute the command substitution
+                        if !cmd_content.is_empty() {
+                            if let Ok(output) = self.execute_command_for_substitution(&cmd_content)
+                            {
+                                result.push_str(&output);
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                let mut var_name = String::new();
+
+                // Variable can be specified as ${VAR} or $VAR
+                if let Some(&'{') = chars.peek() {
+                    chars.next(); // Skip '{'
+
+                    // Read until the matching closing brace
+                    let mut depth = 0;
+                    for c in chars.by_ref() {
+                        if c == '{' {
+                            depth += 1;
+                        } else if c == '}' {
+                            if depth == 0 {
+                                break;
+                            }
+                            depth -= 1;
+                        }
+                        var_name.push(c);
+                    }
+                    result.push_str(&self.expand_braced_parameter(&var_name));
+                    continue;
+                }
+
+                // Read variable name
+                // Handle special single-character variables first
+                if let Some(&c) = chars.peek() {
+                    if matches!(c, '#' | '@' | '*' | '?' | '$' | '!') {
+                        var_name.push(c);
+                        chars.next();
+                    } else {
+                        // Read until non-alphanumeric character
+                        while let Some(&c) = chars.peek() {
+                            if c.is_alphanumeric() || c == '_' {
+                                var_name.push(c);
+                                chars.next();
+                            } else {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                // Replace with variable value if exists
+                if let Some(value) = self.parameter_value(&var_name) {
+                    result.push_str(&value);
+                }
+            } else {
+                result.push(c);
+            }
+        }
+
+        result
+    }
+
+    /// Value of a named, positional or special parameter, or None when it is unset
+    fn parameter_value(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.get_variable(name) {
+            Some(value.clone())
+        } else if let Some((array, subscript)) = Self::split_subscript(name) {
+            self.array_element(array, subscript)
+        } else if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) {
+            // Positional parameters ($0, $1, $2, ...)
+            let index = name.parse::<usize>().ok()?;
+            self.args.get(index).cloned()
+        } else if name == "$" {
+            // $$ - process ID of the shell, kept from startup
+            self.variables.get("$$").cloned()
+        } else if name == "#" {
+            // $# - number of positional parameters (excluding $0)
+            Some(self.args.len().saturating_sub(1).to_string())
+        } else if name == "@" || name == "*" {
+            // $@ and $* - all positional parameters (excluding $0)
+            let params = self.args.get(1..).filter(|params| !params.is_empty())?;
+            // $* joins with the first character of IFS (nothing when IFS is empty)
+            let separator = if name == "*" {
+                self.ifs()
+                    .chars()
+                    .next()
+                    .map(String::from)
+                    .unwrap_or_default()
+            } else {
+                " ".to_string()
+            };
+            Some(params.join(&separator))
+        } else {
+            // An array name on its own refers to element 0
+            self.stored_element(&self.resolve_nameref(name), "0")
+                .cloned()
+        }
+    }
+
+    /// Split `name[subscript]` into the array name and the subscript
+    fn split_subscript(name: &str) -> Option<(&str, &str)> {
+        let (array, rest) = name.split_once('[')?;
+        let subscript = rest.strip_suffix(']')?;
+        (Self::is_parameter_name(array) && !subscript.is_empty()).then_some((array, subscript))
+    }
+
+    /// The key an array subscript refers to. Subscripts of associative arrays are
+    /// expanded strings; others are evaluated arithmetically, with negative indices
+    /// counting back from the end.
+    pub fn array_key(&self, name: &str, subscript: &str) -> String {
+        let name = self.resolve_nameref(name);
+        if self.associative_arrays.contains_key(&name) {
+            return self.expand_word_unsplit_read_only(subscript);
+        }
+        let subscript = self.expand_parameters(subscript);
+
+        let Ok(index) = self.evaluate_arithmetic_with_variables(subscript.trim()) else {
+            return subscript;
+        };
+        if index < 0 {
+            let length = self
+                .arrays
+                .get(&name)
+                .and_then(|elements| elements.keys().next_back())
+                .map_or(0, |last| *last as i64 + 1);
+            return (length + index).to_string();
+        }
+        index.to_string()
+    }
+
+    /// The stored value of element `key` of the array `name`, whose nameref is resolved
+    fn stored_element(&self, name: &str, key: &str) -> Option<&String> {
+        if let Some(elements) = self.associative_arrays.get(name) {
+            return elements.get(key);
+        }
+        self.arrays.get(name)?.get(&key.parse::<usize>().ok()?)
+    }
+
+    /// Set element `key` of the array `name`. A scalar of the same name becomes
+    /// element 0 of the new array, as in `s=x; s[1]=y`.
+    pub fn set_array_element(
+        &mut self,
+        name: &str,
+        key: &str,
+        value: String,
+    ) -> Result<(), String> {
+        self.check_writable(name)?;
+        let value = self.integer_value(name, value)?;
+        if let Some(elements) = self.associative_arrays.get_mut(name) {
+            elements.insert(key.to_string(), value);
+            return Ok(());
+        }
+        let Ok(index) = key.parse::<usize>() else {
+            return Err(format!("{name}[{key}]: bad array subscript"));
+        };
+        let scalar = self.variables.remove(name);
+        let elements = self.arrays.entry(name.to_string()).or_default();
+        if let Some(scalar) = scalar {
+            elements.entry(0).or_insert(scalar);
+        }
+        elements.insert(index, value);
+        Ok(())
+    }
+
+    /// The value to store in `name`: integer variables hold the decimal value of the
+    /// expression, which is evaluated before anything is stored, as in `n=n*5`
+    fn integer_value(&self, name: &str, value: String) -> Result<String, String> {
+        if !self.integer_vars.contains(name) {
+            return Ok(value);
+        }
+        match self.evaluate_arithmetic_with_variables(&value) {
+            Ok(number) => Ok(number.to_string()),
+            Err(message) => Err(format!("{value}: {message}")),
+        }
+    }
+
+    /// Fail when the variable `name` refers to is readonly
+    pub fn check_writable(&self, name: &str) -> Result<(), String> {
+        let name = self.resolve_nameref(name);
+        if self.readonly.contains(&name) {
+            return Err(format!("{name}: readonly variable"));
+        }
+        Ok(())
+    }
+
+    /// Assign `value` to the variable or `name[subscript]` element `name`, following
+    /// name references. A plain assignment to an array sets its element 0. Every
+    /// assignment goes through here or `set_array_element`, which refuse readonly names.
+    pub fn set_variable(&mut self, name: &str, value: String) -> Result<(), String> {
+        let name = self.resolve_nameref(name);
+        match Self::split_subscript(&name) {
+            Some((array, subscript)) => {
+                let array = self.resolve_nameref(array);
+                let key = self.array_key(&array, subscript);
+                self.set_array_element(&array, &key, value)
+            }
+            None if self.arrays.contains_key(&name) || !self.array_subscripts(&name).is_empty() => {
+                self.set_array_element(&name, "0", value)
+            }
+            None => {
+                self.check_writable(&name)?;
+                let value = self.integer_value(&name, value)?;
+                self.variables.insert(name, value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Values of the elements of an array in subscript order; a scalar is an array of
+    /// one element
+    pub fn array_values(&self, name: &str) -> Vec<String> {
+        let subscripts = self.array_subscripts(name);
+        if subscripts.is_empty() {
+            return self.get_variable(name).cloned().into_iter().collect();
+        }
+        let name = self.resolve_nameref(name);
+        subscripts
+            .iter()
+            .filter_map(|subscript| self.stored_element(&name, subscript).cloned())
+            .collect()
+    }
+
+    /// Value of `${name[subscript]}`, where `@` and `*` join all the elements
+    fn array_element(&self, name: &str, subscript: &str) -> Option<String> {
+        if subscript == "@" || subscript == "*" {
+            let values = self.array_values(name);
+            let separator = if subscript == "*" {
+                self.ifs()
+                    .chars()
+                    .next()
+                    .map(String::from)
+                    .unwrap_or_default()
+            } else {
+                " ".to_string()
+            };
+            return (!values.is_empty()).then(|| values.join(&separator));
+        }
+
+        let key = self.array_key(name, subscript);
+        let name = self.resolve_nameref(name);
+        self.stored_element(&name, &key)
+            .or_else(|| self.variables.get(&name).filter(|_| key == "0"))
+            .cloned()
+    }
+
+    /// Record the exit status of each stage of the last pipeline in `PIPESTATUS`
+    pub fn set_pipe_status(&mut self, codes: &[i32]) {
+        let codes = codes.iter().map(|code| code.to_string()).enumerate();
+        self.arrays
+            .insert("PIPESTATUS".to_string(), codes.collect());
+    }
+
+    /// Remove every element of an array, along with a scalar of the same name. An
+    /// associative array stays declared, with no elements.
+    pub fn clear_array(&mut self, name: &str) {
+        self.variables.remove(name);
+        self.arrays.remove(name);
+        if let Some(elements) = self.associative_arrays.get_mut(name) {
+            elements.clear();
+        }
+    }
+
+    /// The separate values of `$@`, `${name[@]}` and `${!name[@]}`, and outside double
+    /// quotes also of their `*` forms, each of which becomes its own field
+    fn expansion_list(&self, expression: &str, quoted: bool) -> Option<Vec<String>> {
+        let body = match expression.strip_prefix("${") {
+            Some(body) => body.strip_suffix('}')?,
+            None => expression.strip_prefix('$')?,
+        };
+        let all = |subscript: &str| subscript == "@" || (!quoted && subscript == "*");
+        if all(body) {
+            return Some(self.args.get(1..).unwrap_or_default().to_vec());
+        }
+        if let Some((name, subscript)) = body.strip_prefix('!').and_then(Self::split_subscript) {
+            return all(subscript).then(|| self.array_subscripts(name));
+        }
+        let (name, subscript) = Self::split_subscript(body)?;
+        all(subscript).then(|| self.array_values(name))
+    }
+
+    /// Subscripts of the elements of `name` that are set: indices in ascending order,
+    /// or the sorted keys of an associative array
+    pub fn array_subscripts(&self, name: &str) -> Vec<String> {
+        let name = self.resolve_nameref(name);
+        if let Some(elements) = self.arrays.get(&name) {
+            return elements.keys().map(|index| index.to_string()).collect();
+        }
+        let Some(elements) = self.associative_arrays.get(&name) else {
+            return Vec::new();
+        };
+        let mut subscripts: Vec<String> = elements.keys().cloned().collect();
+        subscripts.sort_unstable();
+        subscripts
+    }
+
+    /// Whether `name` is a variable name, a positional parameter or a special parameter
+    fn is_parameter_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                chars.all(|c| c.is_alphanumeric() || c == '_')
+            }
+            Some(c) if c.is_ascii_digit() => chars.all(|c| c.is_ascii_digit()),
+            Some('@' | '*' | '#' | '?' | '$' | '!' | '-') => chars.next().is_none(),
+            _ => false,
+        }
+    }
+
+    /// Split the body of `${name:-word}`, `${name-word}` and friends into name, operator and word
+    fn split_parameter_operator(body: &str) -> Option<(&str, &str, &str)> {
+        let first = body.chars().next()?;
+        let name_len = if first.is_alphabetic() || first == '_' {
+            let name_len = body
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(body.len());
+            // An array subscript is part of the name
+            match body[name_len..].strip_prefix('[') {
+                Some(rest) => rest.find(']').map_or(name_len, |end| name_len + end + 2),
+                None => name_len,
+            }
+        } else if first.is_ascii_digit() {
+            body.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(body.len())
+        } else if matches!(first, '@' | '*' | '#' | '?' | '$' | '!' | '-') {
+            1
+        } else {
+            return None;
+        };
+
+        let (name, rest) = body.split_at(name_len);
+        [
+            ":-", ":=", ":+", ":?", "-", "=", "+", "?", "##", "#", "%%", "%", "//", "/#", "/%",
+            "/", ":",
+        ]
+        .into_iter()
+        .find(|op| rest.starts_with(op))
+        .map(|op| (name, op, &rest[op.len()..]))
+    }
+
+    /// Expand the body of `${...}`; the word after an operator is only expanded when used
+    fn expand_braced_parameter(&self, body: &str) -> String {
+        // ${!name[@]} and ${!name[*]} list the subscripts that are set
+        if let Some(name) = body.strip_prefix('!').and_then(|rest| {
+            rest.strip_suffix("[@]")
+                .or_else(|| rest.strip_suffix("[*]"))
+        }) {
+            return self.array_subscripts(name).join(" ");
+        }
+
+        // ${#name} is the length of the value; ${#@} and ${#*} count the positional parameters
+        if let Some(name) = body.strip_prefix('#') {
+            if name == "@" || name == "*" {
+                return self.args.len().saturating_sub(1).to_string();
+            }
+            if let Some((array, "@" | "*")) = Self::split_subscript(name) {
+                return self.array_values(array).len().to_string();
+            }
+            if Self::is_parameter_name(name) || Self::split_subscript(name).is_some() {
+                let value = self.parameter_value(name).unwrap_or_default();
+                return value.chars().count().to_string();
+            }
+        }
+
+        let Some((name, op, word)) = Self::split_parameter_operator(body) else {
+            return self.parameter_value(body).unwrap_or_default();
+        };
+
+        let value = self.parameter_value(name);
+        if matches!(op, "#" | "##" | "%" | "%%") {
+            let pattern = self.expand_variables(word);
+            return self.remove_matching_affix(&value.unwrap_or_default(), op, &pattern);
+        }
+        if op == ":" {
+            return self.substring(&value.unwrap_or_default(), word);
+        }
+        if op.starts_with('/') {
+            // The replacement follows the first unescaped slash; without one, matches are deleted
+            let mut escaped = false;
+            let slash = word.char_indices().find_map(|(index, c)| {
+                let found = c == '/' && !escaped;
+                escaped = c == '\\' && !escaped;
+                found.then_some(index)
+            });
+            let (pattern, replacement) = match slash {
+                Some(index) => (&word[..index], &word[index + 1..]),
+                None => (word, ""),
+            };
+            let pattern = self.expand_variables(pattern);
+            let replacement = self.expand_variables(replacement);
+            return self.replace_matching_pattern(
+                &value.unwrap_or_default(),
+                op,
+                &pattern,
+                &replacement,
+            );
+        }
+
+        let is_set = Self::parameter_is_set(value.as_deref(), op);
+        match op.trim_start_matches(':') {
+            "+" if is_set => self.expand_variables(word),
+            "+" => String::new(),
+            _ if is_set => value.unwrap_or_default(),
+            "-" | "=" => self.expand_variables(word),
+            // `?` failures are reported before the command runs
+            _ => String::new(),
+        }
+    }
+
+    /// The word of a `${name-word}` or `${name+word}` expansion when it is what the
+    /// expansion substitutes
+    fn substituted_operand<'a>(&self, expression: &'a str) -> Option<&'a str> {
+        let body = expression.strip_prefix("${")?.strip_suffix('}')?;
+        let (name, op, word) = Self::split_parameter_operator(body)?;
+        let is_set = Self::parameter_is_set(self.parameter_value(name).as_deref(), op);
+        match op.trim_start_matches(':') {
+            "+" if is_set => Some(word),
+            "-" if !is_set => Some(word),
+            _ => None,
+        }
+    }
+
+    /// Split an operand word at its unquoted `$IFS` characters, also reporting whether it
+    /// started or ended with one
+    fn split_operand_on_ifs(&self, word: &str) -> (Vec<String>, bool, bool) {
+        let ifs = self.ifs();
+        let chars: Vec<char> = word.chars().collect();
+        let mut pieces = Vec::new();
+        let mut current = String::new();
+        let mut in_double_quotes = false;
+        let mut leading = false;
+        let mut trailing = false;
+        let mut i = 0;
+
+        while i < chars.len() {
+            trailing = false;
+            let len = match chars[i] {
+                '\\' => 2,
+                '\'' if !in_double_quotes => chars[i + 1..]
+                    .iter()
+                    .position(|&c| c == '\'')
+                    .map_or(chars.len() - i, |p| p + 2),
+                '"' => {
+                    in_double_quotes = !in_double_quotes;
+                    1
+                }
+                '$' => Self::dollar_expression_len(&chars, i),
+                c if !in_double_quotes && ifs.contains(c) => {
+                    if !current.is_empty() {
+                        pieces.push(mem::take(&mut current));
+                    }
+                    leading |= i == 0;
+                    trailing = true;
+                    i += 1;
+                    continue;
+                }
+                _ => 1,
+            };
+            let end = (i + len).min(chars.len());
+            current.extend(&chars[i..end]);
+            i = end;
+        }
+        if !current.is_empty() {
+            pieces.push(current);
+        }
+        (pieces, leading, trailing)
+    }
+
+    /// Strip the shortest (`#`, `%`) or longest (`##`, `%%`) prefix or suffix of `value`
+    /// that matches the glob `pattern`
+    fn remove_matching_affix(&self, value: &str, op: &str, pattern: &str) -> String {
+        let mut boundaries: Vec<usize> = value
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain([value.len()])
+            .collect();
+        // Try the shortest candidate first for # and %, the longest first for ## and %%
+        let suffix = op.starts_with('%');
+        if (op.len() == 2) != suffix {
+            boundaries.reverse();
+        }
+
+        for split in boundaries {
+            let (prefix, rest) = value.split_at(split);
+            let (candidate, kept) = if suffix {
+                (rest, prefix)
+            } else {
+                (prefix, rest)
+            };
+            if self.matches_glob_pattern(candidate, pattern) {
+                return kept.to_string();
+            }
+        }
+        value.to_string()
+    }
+
+    /// `${name:offset}` and `${name:offset:length}`: both are arithmetic expressions, and
+    /// negative values count back from the end of the value
+    fn substring(&self, value: &str, spec: &str) -> String {
+        let (offset, length) = match spec.split_once(':') {
+            Some((offset, length)) => (offset, Some(length)),
+            None => (spec, None),
+        };
+
+        let evaluate = |expr: &str| {
+            self.evaluate_arithmetic_with_variables(expr)
+                .unwrap_or_else(|error| {
+                    let _ = writeln!(&self.stderr, "{expr}: {error}");
+                    0
+                })
+        };
+        let chars: Vec<char> = value.chars().collect();
+        let len = chars.len() as i64;
+
+        let mut start = evaluate(offset);
+        if start < 0 {
+            start += len;
+        }
+        if start < 0 || start > len {
+            return String::new();
+        }
+
+        let end = match length.map(evaluate) {
+            None => len,
+            Some(length) if length < 0 => len + length,
+            Some(length) => (start + length).min(len),
+        };
+        if end < start {
+            let _ = writeln!(
+                &self.stderr,
+                "{}: substring expression < 0",
+                length.unwrap_or_default().trim()
+            );
+            return String::new();
+        }
+        chars[start as usize..end as usize].iter().collect()
+    }
+
+    /// Replace the longest match of the glob `pattern` in `value`: the first one for `/`,
+    /// every one for `//`, and only at the start or end for `/#` and `/%`
+    fn replace_matching_pattern(
+        &self,
+        value: &str,
+        op: &str,
+        pattern: &str,
+        replacement: &str,
+    ) -> String {
+        if pattern.is_empty() {
+            return value.to_string();
+        }
+
+        let boundaries: Vec<usize> = value
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain([value.len()])
+            .collect();
+        let longest_match_at = |start: usize| {
+            boundaries
+                .iter()
+                .rev()
+                .take_while(|&&end| end > start)
+                .find(|&&end| self.matches_glob_pattern(&value[start..end], pattern))
+                .copied()
+        };
+
+        match op {
+            "/#" => match longest_match_at(0) {
+                Some(end) => format!("{replacement}{}", &value[end..]),
+                None => value.to_string(),
+            },
+            "/%" => boundaries
+                .iter()
+                .find(|&&start| {
+                    start < value.len() && self.matches_glob_pattern(&value[start..], pattern)
+                })
+                .map_or_else(
+                    || value.to_string(),
+                    |&start| format!("{}{replacement}", &value[..start]),
+                ),
+            _ => {
+                let mut result = String::new();
+                let mut index = 0;
+                let mut replaced = false;
+                while index < boundaries.len() - 1 {
+                    let start = boundaries[index];
+                    if op == "//" || !replaced {
+                        if let Some(end) = longest_match_at(start) {
+                            result.push_str(replacement);
+                            replaced = true;
+                            index = boundaries
+                                .iter()
+                                .position(|&b| b == end)
+                                .unwrap_or(index + 1);
+                            continue;
+                        }
+                    }
+                    result.push_str(&value[start..boundaries[index + 1]]);
+                    index += 1;
+                }
+                result
+            }
+        }
+    }
+
+    /// Whether a parameter counts as set for an operator; the colon forms treat an
+    /// empty value like an unset one, the plain forms only test that it exists
+    fn parameter_is_set(value: Option<&str>, op: &str) -> bool {
+        match value {
+            Some(value) => !value.is_empty() || !op.starts_with(':'),
+            None => false,
+        }
+    }
+
+    /// Perform the `${name=word}` assignments and `${name?word}` checks in a word
+    /// before it is expanded, descending only into the words that will be used
+    pub fn assign_parameter_defaults(&mut self, word: &str) -> Result<(), String> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut in_double_quotes = false;
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '\\' => i += 2,
+                '\'' if !in_double_quotes => {
+                    i += chars[i + 1..]
+                        .iter()
+                        .position(|&c| c == '\'')
+                        .map_or(chars.len(), |p| p + 2);
+                }
+                '"' => {
+                    in_double_quotes = !in_double_quotes;
+                    i += 1;
+                }
+                '$' => {
+                    let len = Self::dollar_expression_len(&chars, i);
+                    if chars.get(i + 1) == Some(&'{') && len > 2 {
+                        let end = (i + len - 1).min(chars.len());
+                        let body: String = chars[i + 2..end].iter().collect();
+                        self.assign_parameter_default(&body)?;
+                    }
+                    i += len;
+                }
+                _ => i += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn assign_parameter_default(&mut self, body: &str) -> Result<(), String> {
+        let Some((name, op, word)) = Self::split_parameter_operator(body) else {
+            return Ok(());
+        };
+
+        let is_set = Self::parameter_is_set(self.parameter_value(name).as_deref(), op);
+        match op.trim_start_matches(':') {
+            "=" if !is_set => {
+                if !is_assignable_name(name) {
+                    return Err(format!("${name}: cannot assign in this way"));
+                }
+                self.assign_parameter_defaults(word)?;
+                let value = self.expand_variables(word);
+                self.set_variable(name, value)?;
+            }
+            "?" if !is_set => {
+                self.assign_parameter_defaults(word)?;
+                let message = self.expand_variables(word);
+                if !message.is_empty() {
+                    return Err(format!("{name}: {message}"));
+                } else if op.starts_with(':') {
+                    return Err(format!("{name}: parameter null or not set"));
+                }
+                return Err(format!("{name}: parameter not set"));
+            }
+            "-" if !is_set => self.assign_parameter_defaults(word)?,
+            "+" if is_set => self.assign_parameter_defaults(word)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Expand the value of an assignment from left to right, so that the assignments
+    /// and `++`/`--` updates of its `$(( ))` expansions are seen by the ones after them
+    pub fn expand_assignment_value(&mut self, value: &str) -> String {
+        let chars: Vec<char> = self.expand_tilde(value).chars().collect();
+        let mut result = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            if chars[i] != '$' {
+                result.push(chars[i]);
+                i += 1;
+                continue;
+            }
+            let len = Self::dollar_expression_len(&chars, i);
+            let expression: String = chars[i..i + len].iter().collect();
+            match Self::arithmetic_expansion(self, &expression) {
+                Some(value) => result.push_str(&value),
+                None => result.push_str(&self.expand_parameters(&expression)),
+            }
+            i += len;
+        }
+        result
+    }
+
+    /// Expand the body of a here-document whose delimiter was not quoted: only `$`
+    /// expansions and command substitutions apply, and a backslash only escapes `$`,
+    /// `` ` ``, `\` or a newline; quotes and `~` are kept as they are
+    pub fn expand_here_document(&mut self, body: &str) -> String {
+        let chars: Vec<char> = body.chars().collect();
+        let mut result = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '\\' => match chars.get(i + 1) {
+                    Some('\n') => i += 2,
+                    Some(&c @ ('$' | '`' | '\\')) => {
+                        result.push(c);
+                        i += 2;
+                    }
+                    _ => {
+                        result.push('\\');
+                        i += 1;
+                    }
+                },
+                '$' => {
+                    let len = Self::dollar_expression_len(&chars, i);
+                    let expression: String = chars[i..i + len].iter().collect();
+                    match Self::arithmetic_expansion(self, &expression) {
+                        Some(value) => result.push_str(&value),
+                        None => result.push_str(&self.expand_parameters(&expression)),
+                    }
+                    i += len;
+                }
+                '`' => {
+                    let end = chars[i + 1..]
+                        .iter()
+                        .position(|&c| c == '`')
+                        .map_or(chars.len(), |p| i + 1 + p);
+                    let command: String = chars[i + 1..end].iter().collect();
+                    if let Ok(output) = self.execute_command_for_substitution(&command) {
+                        result.push_str(&output);
+                    }
+                    i = end + 1;
+                }
+                c => {
+                    result.push(c);
+                    i += 1;
+                }
+            }
+        }
+        result
+    }
+
+    /// Characters used for field splitting; an unset IFS means space, tab and newline
+    fn ifs(&self) -> String {
+        self.variables
+            .get("IFS")
+            .cloned()
+            .unwrap_or_else(|| " \t\n".to_string())
+    }
+
+    /// Split a value into fields using `$IFS`; an empty IFS disables splitting
+    pub fn split_fields(&self, value: &str) -> Vec<String> {
+        let (fields, _, _) = self.split_fields_with_edges(value);
+        if fields.is_empty() && !value.is_empty() && self.ifs().is_empty() {
+            return vec![value.to_string()];
+        }
+        fields
+    }
+
+    /// Split a value on `$IFS`, also reporting whether it started or ended with a separator
+    fn split_fields_with_edges(&self, value: &str) -> (Vec<String>, bool, bool) {
+        let ifs = self.ifs();
+        if ifs.is_empty() {
+            let fields = if value.is_empty() {
+                Vec::new()
+            } else {
+                vec![value.to_string()]
+            };
+            return (fields, false, false);
+        }
+
+        let is_ifs_whitespace = |c: char| c.is_whitespace() && ifs.contains(c);
+        let is_ifs_delimiter = |c: char| !c.is_whitespace() && ifs.contains(c);
+
+        let mut fields = Vec::new();
+        let mut current = String::new();
+        let mut in_field = false;
+        let mut chars = value.chars().peekable();
+        let leading = value.starts_with(|c: char| ifs.contains(c));
+        let trailing = value.ends_with(|c: char| ifs.contains(c));
+
+        // Skip leading IFS whitespace
+        while chars.peek().is_some_and(|&c| is_ifs_whitespace(c)) {
+            chars.next();
+        }
+
+        while let Some(c) = chars.next() {
+            if is_ifs_whitespace(c) || is_ifs_delimiter(c) {
+                // A run of IFS whitespace with at most one delimiter ends the field
+                let mut saw_delimiter = is_ifs_delimiter(c);
+                while let Some(&next) = chars.peek() {
+                    if is_ifs_whitespace(next) {
+                        chars.next();
+                    } else if is_ifs_delimiter(next) && !saw_delimiter {
+                        saw_delimiter = true;
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if in_field || saw_delimiter {
+                    fields.push(mem::take(&mut current));
+                }
+                in_field = false;
+            } else {
+                current.push(c);
+                in_field = true;
+            }
+        }
+
+        if in_field {
+            fields.push(current);
+        }
+
+        (fields, leading, trailing)
+    }
+
+    /// Split a line read by `read` into at most `count` fields on `$IFS`, the last
+    /// field taking the rest of the line; escaped characters never separate fields
+    fn split_read_fields(&self, line: &[(char, bool)], count: usize) -> Vec<String> {
+        let ifs = self.ifs();
+        let is_ifs_whitespace =
+            |&(c, escaped): &(char, bool)| !escaped && c.is_whitespace() && ifs.contains(c);
+        let is_ifs_delimiter =
+            |&(c, escaped): &(char, bool)| !escaped && !c.is_whitespace() && ifs.contains(c);
+
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < line.len() && is_ifs_whitespace(&line[pos]) {
+            pos += 1;
+        }
+        while pos < line.len() && fields.len() + 1 < count {
+            let start = pos;
+            while pos < line.len()
+                && !is_ifs_whitespace(&line[pos])
+                && !is_ifs_delimiter(&line[pos])
+            {
+                pos += 1;
+            }
+            fields.push(line[start..pos].iter().map(|&(c, _)| c).collect());
+
+            // A run of IFS whitespace with at most one delimiter separates fields
+            let mut saw_delimiter = false;
+            while pos < line.len() {
+                if is_ifs_whitespace(&line[pos]) {
+                    pos += 1;
+                } else if is_ifs_delimiter(&line[pos]) && !saw_delimiter {
+                    saw_delimiter = true;
+                    pos += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+
+        // The last field keeps its inner separators, minus trailing IFS whitespace
+        let mut end = line.len();
+        while end > pos && is_ifs_whitespace(&line[end - 1]) {
+            end -= 1;
+        }
+        if pos < end {
+            fields.push(line[pos..end].iter().map(|&(c, _)| c).collect());
+        }
+        fields
+    }
+
+    /// Length of the `$` expression starting at `start` (`$name`, `${...}`, `$(...)`, `$((...))`)
+    fn dollar_expression_len(chars: &[char], start: usize) -> usize {
+        let Some(&next) = chars.get(start + 1) else {
+            return 1;
+        };
+
+        let matching = |open: char, close: char| {
+            let mut depth = 0;
+            for (offset, &c) in chars[start + 1..].iter().enumerate() {
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return offset + 2;
+                    }
+                }
+            }
+            chars.len() - start
+        };
+
+        match next {
+            '(' => matching('(', ')'),
+            '{' => matching('{', '}'),
+            '@' | '*' | '#' | '?' | '$' | '!' | '-' | '0'..='9' => 2,
+            c if c.is_alphabetic() || c == '_' => {
+                let name_len = chars[start + 1..]
+                    .iter()
+                    .take_while(|c| c.is_alphanumeric() || **c == '_')
+                    .count();
+                name_len + 1
+            }
+            _ => 1,
+        }
+    }
+
+    /// Expand every word of a command line into its final fields
+    pub fn expand_words(&mut self, words: &[String]) -> Vec<String> {
+        words
+            .iter()
+            .flat_map(|word| self.expand_word(word))
+            .collect()
+    }
+
+    /// Expand a single word: brace expansion, tilde and parameter expansion, command
+    /// substitution, field splitting of unquoted expansions, pathname expansion and
+    /// quote removal. Returns zero or more fields.
+    pub fn expand_word(&mut self, word: &str) -> Vec<String> {
+        // Brace expansion only applies to words without quoting
+        let has_brace = word
+            .char_indices()
+            .any(|(i, c)| c == '{' && !word[..i].ends_with('$'));
+        let words = if has_brace && !word.contains(['\'', '"', '\\']) {
+            self.expand_brace_patterns(word)
+        } else {
+            vec![word.to_string()]
+        };
+
+        let mut fields = Vec::new();
+        for word in words {
+            for (field, glob) in self.expand_word_fields(&word) {
+                if glob && self.contains_glob_pattern(&field) {
+                    let matches = self.glob_match(&field);
+                    if matches.is_empty() {
+                        fields.push(field);
+                    } else {
+                        fields.extend(matches);
+                    }
+                } else {
+                    fields.push(field);
+                }
+            }
+        }
+        fields
+    }
+
+    /// Expand a word into a single string without field splitting or pathname expansion,
+    /// as in the operands of `[[ ]]`
+    pub fn expand_word_unsplit(&mut self, word: &str) -> String {
+        Self::join_fields(Self::expand_word_fields_with(self, word, false))
+    }
+
+    /// `expand_word_unsplit` for callers holding `&self`, where the assignments of
+    /// `$(( ))` expansions are dropped
+    fn expand_word_unsplit_read_only(&self, word: &str) -> String {
+        Self::join_fields(Self::expand_word_fields_with(
+            &mut ReadOnlyVariables(self),
+            word,
+            false,
+        ))
+    }
+
+    fn join_fields(fields: Vec<(String, bool)>) -> String {
+        let fields: Vec<String> = fields.into_iter().map(|(field, _)| field).collect();
+        fields.join(" ")
+    }
+
+    /// Expand a word into fields, flagging the fields that contain unquoted glob characters
+    fn expand_word_fields(&mut self, word: &str) -> Vec<(String, bool)> {
+        Self::expand_word_fields_with(self, word, true)
+    }
+
+    /// Expand a word into fields through `context`, which evaluates its `$(( ))`
+    /// expansions as they are reached
+    fn expand_word_fields_with<X: Expansion>(
+        context: &mut X,
+        word: &str,
+        split: bool,
+    ) -> Vec<(String, bool)> {
+        let mut chars: Vec<char> = word.chars().collect();
+        let mut fields = Vec::new();
+        let mut current = String::new();
+        let mut has_field = false;
+        let mut glob = false;
+        let mut i = 0;
+
+        // Append an unquoted expansion result, splitting it on IFS
+        let push_unquoted = |this: &Interpreter,
+                             current: &mut String,
+                             has_field: &mut bool,
+                             glob: &mut bool,
+                             fields: &mut Vec<(String, bool)>,
+                             value: &str| {
+            if !split {
+                current.push_str(value);
+                *has_field = true;
+                return;
+            }
+            let (parts, leading, trailing) = this.split_fields_with_edges(value);
+            if parts.is_empty() {
+                if (leading || trailing) && *has_field {
+                    fields.push((mem::take(current), *glob));
+                    *has_field = false;
+                    *glob = false;
+                }
+                return;
+            }
+
+            let last = parts.len() - 1;
+            for (index, part) in parts.into_iter().enumerate() {
+                if (index > 0 || leading) && *has_field {
+                    fields.push((mem::take(current), *glob));
+                    *glob = false;
+                }
+                *glob |= this.contains_glob_pattern(&part);
+                current.push_str(&part);
+                *has_field = true;
+                if index == last && trailing {
+                    fields.push((mem::take(current), *glob));
+                    *has_field = false;
+                    *glob = false;
+                }
+            }
+        };
+
+        // Tilde expansion for an unquoted prefix up to the first slash
+        if chars.first() == Some(&'~') {
+            let end = chars.iter().position(|&c| c == '/').unwrap_or(chars.len());
+            let prefix: String = chars[..end].iter().collect();
+            if !prefix.contains(['\'', '"', '\\', '$']) {
+                current.push_str(&context.interpreter().expand_tilde(&prefix));
+                has_field = true;
+                i = end;
+            }
+        }
+
+        while i < chars.len() {
+            match chars[i] {
+                '\\' if matches!(chars.get(i + 1), Some('\\' | '"' | '\'' | '$' | '`')) => {
+                    current.push(chars[i + 1]);
+                    has_field = true;
+                    i += 2;
+                }
+                '\'' => {
+                    let end = chars[i + 1..]
+                        .iter()
+                        .position(|&c| c == '\'')
+                        .map_or(chars.len(), |p| i + 1 + p);
+                    current.extend(&chars[i + 1..end]);
+                    has_field = true;
+                    i = end + 1;
+                }
+                '"' => {
+                    has_field = true;
+                    i += 1;
+
+                    // Tilde expansion also applies inside double quotes
+                    let mut end = i;
+                    while end < chars.len() && chars[end] != '"' {
+                        end += if chars[end] == '\\' { 2 } else { 1 };
+                    }
+                    let end = end.min(chars.len());
+                    let quoted: String = chars[i..end].iter().collect();
+                    if quoted.contains('~') {
+                        let expanded = context.interpreter().expand_tilde(&quoted);
+                        let mut rest: Vec<char> = expanded.chars().collect();
+                        rest.extend_from_slice(&chars[end..]);
+                        chars = [&chars[..i], &rest[..]].concat();
+                    }
+
+                    while i < chars.len() && chars[i] != '"' {
+                        match chars[i] {
+                            '\\' if i + 1 < chars.len()
+                                && matches!(chars[i + 1], '$' | '`' | '"' | '\\' | '\n') =>
+                            {
+                                if chars[i + 1] != '\n' {
+                                    current.push(chars[i + 1]);
+                                }
+                                i += 2;
+                            }
+                            '$' => {
+                                let len = Self::dollar_expression_len(&chars, i);
+                                let expression: String = chars[i..i + len].iter().collect();
+                                if let Some(value) =
+                                    Self::arithmetic_expansion(context, &expression)
+                                {
+                                    current.push_str(&value);
+                                } else if let Some(params) =
+                                    context.interpreter().expansion_list(&expression, true)
+                                {
+                                    // "$@" keeps each positional parameter as its own field
+                                    for (index, param) in params.iter().enumerate() {
+                                        if index > 0 {
+                                            fields.push((mem::take(&mut current), glob));
+                                            glob = false;
+                                        }
+                                        current.push_str(param);
+                                    }
+                                    if params.is_empty() && current.is_empty() {
+                                        has_field = false;
+                                    }
+                                } else {
+                                    current.push_str(
+                                        &context.interpreter().expand_variables(&expression),
+                                    );
+                                }
+                                i += len;
+                            }
+                            '`' => {
+                                let end = chars[i + 1..]
+                                    .iter()
+                                    .position(|&c| c == '`')
+                                    .map_or(chars.len(), |p| i + 1 + p);
+                                let command: String = chars[i + 1..end].iter().collect();
+                                if let Ok(output) = context
+                                    .interpreter()
+                                    .execute_command_for_substitution(&command)
+                                {
+                                    current.push_str(&output);
+                                }
+                                i = end + 1;
+                            }
+                            c => {
+                                current.push(c);
+                                i += 1;
+                            }
+                        }
+                    }
+                    i += 1; // Skip closing quote
+                }
+                '$' => {
+                    let len = Self::dollar_expression_len(&chars, i);
+                    let expression: String = chars[i..i + len].iter().collect();
+                    if len == 1 {
+                        current.push('$');
+                        has_field = true;
+                    } else if let Some(value) = Self::arithmetic_expansion(context, &expression) {
+                        push_unquoted(
+                            context.interpreter(),
+                            &mut current,
+                            &mut has_field,
+                            &mut glob,
+                            &mut fields,
+                            &value,
+                        );
+                    } else if let Some(params) =
+                        context.interpreter().expansion_list(&expression, false)
+                    {
+                        // Each positional parameter starts a new field before splitting
+                        for (index, param) in params.iter().enumerate() {
+                            if index > 0 && has_field {
+                                fields.push((mem::take(&mut current), glob));
+                                has_field = false;
+                                glob = false;
+                            }
+                            push_unquoted(
+                                context.interpreter(),
+                                &mut current,
+                                &mut has_field,
+                                &mut glob,
+                                &mut fields,
+                                param,
+                            );
+                        }
+                    } else if let Some(operand) = context
+                        .interpreter()
+                        .substituted_operand(&expression)
+                        .filter(|operand| operand.contains(['\'', '"', '\\']))
+                    {
+                        // Quoting inside the word of ${name:-word} and ${name:+word} still
+                        // protects it from field splitting
+                        let (pieces, leading, trailing) = if split {
+                            context.interpreter().split_operand_on_ifs(operand)
+                        } else {
+                            (vec![operand.to_string()], false, false)
+                        };
+                        if leading && has_field {
+                            fields.push((mem::take(&mut current), glob));
+                            has_field = false;
+                            glob = false;
+                        }
+                        let parts: Vec<(String, bool)> = pieces
+                            .iter()
+                            .flat_map(|piece| Self::expand_word_fields_with(context, piece, split))
+                            .collect();
+                        let last = parts.len().saturating_sub(1);
+                        for (index, (part, part_glob)) in parts.into_iter().enumerate() {
+                            if index > 0 {
+                                fields.push((mem::take(&mut current), glob));
+                                glob = false;
+                            }
+                            current.push_str(&part);
+                            glob |= part_glob;
+                            has_field = true;
+                            if index == last && trailing {
+                                fields.push((mem::take(&mut current), glob));
+                                has_field = false;
+                                glob = false;
+                            }
+                        }
+                    } else {
+                        let value = context.interpreter().expand_variables(&expression);
+                        push_unquoted(
+                            context.interpreter(),
+                            &mut current,
+                            &mut has_field,
+                            &mut glob,
+                            &mut fields,
+                            &value,
+                        );
+                    }
+                    i += len;
+                }
+                '`' => {
+                    let end = chars[i + 1..]
+                        .iter()
+                        .position(|&c| c == '`')
+                        .map_or(chars.len(), |p| i + 1 + p);
+                    let command: String = chars[i + 1..end].iter().collect();
+                    let output = context
+                        .interpreter()
+                        .execute_command_for_substitution(&command)
+                        .unwrap_or_default();
+                    push_unquoted(
+                        context.interpreter(),
+                        &mut current,
+                        &mut has_field,
+                        &mut glob,
+                        &mut fields,
+                        &output,
+                    );
+                    i = end + 1;
+                }
+                c => {
+                    glob |= matches!(c, '*' | '?' | '[')
+                        || (matches!(c, '@' | '+' | '!') && chars.get(i + 1) == Some(&'('));
+                    current.push(c);
+                    has_field = true;
+                    i += 1;
+                }
+            }
+        }
+
+        if has_field {
+            fields.push((current, glob));
+        }
+        fields
+    }
+
+    /// Value of `expression` if it is a `$(( ))` expansion, evaluated through `context`
+    /// so that its assignments are seen by the expansions after it. A failing
+    /// expression expands to nothing and is reported through `context`.
+    fn arithmetic_expansion<X: Expansion>(context: &mut X, expression: &str) -> Option<String> {
+        let expr = expression.strip_prefix("$((")?.strip_suffix("))")?;
+        let expanded_expr = context.interpreter().expand_parameters(expr);
+        Some(match arithmetic::evaluate_with(&expanded_expr, context) {
+            Ok(value) => value.to_string(),
+            Err(message) => {
+                context.arithmetic_error(format!("flash: {expanded_expr}: {message}"));
+                String::new()
+            }
+        })
+    }
+
+    /// Expand a word used as a pattern, as in `case` and `[[ == ]]`: the characters that were quoted
+    /// are escaped so that they match themselves, while the rest keep their meaning
+    /// as glob characters
+    fn expand_pattern(&mut self, word: &str) -> String {
+        let chars: Vec<char> = word.chars().collect();
+        let mut pattern = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let closing = |quote: char| {
+                let mut end = i + 1;
+                while end < chars.len() && chars[end] != quote {
+                    end += if quote == '"' && chars[end] == '\\' {
+                        2
+                    } else {
+                        1
+                    };
+                }
+                end + 1
+            };
+            let end = match chars[i] {
+                '\\' => i + 2,
+                '\'' | '"' => closing(chars[i]),
+                _ => {
+                    // An unquoted run reaches the next quote outside of `$` expressions
+                    let mut end = i;
+                    while end < chars.len() && !matches!(chars[end], '\\' | '\'' | '"') {
+                        end += match chars[end] {
+                            '$' => Self::dollar_expression_len(&chars, end),
+                            _ => 1,
+                        };
+                    }
+                    end
+                }
+            };
+            let end = end.min(chars.len());
+            let segment: String = chars[i..end].iter().collect();
+            let value = self.expand_word_unsplit(&segment);
+
+            if matches!(chars[i], '\\' | '\'' | '"') {
+                for c in value.chars() {
+                    if matches!(c, '*' | '?' | '[' | ']' | '\\' | '(' | ')' | '|') {
+                        pattern.push('\\');
+                    }
+                    pattern.push(c);
+                }
+            } else {
+                pattern.push_str(&value);
+            }
+            i = end;
+        }
+        pattern
+    }
+
+    /// Expand tilde (~) to home directory
+    fn expand_tilde(&self, input: &str) -> String {
+        if input.is_empty() {
+            return input.to_string();
+        }
+
+        // Get home directory; without HOME a bare `~` is left unchanged
+        let home_dir = std::env::var("HOME").ok();
+        let home_dir = home_dir.as_deref().unwrap_or("~");
+
+        let mut result = String::new();
+        let mut chars = input.chars().peekable();
+        let mut at_word_start = true; // Track if we're at the start of a word
+
+        while let Some(c) = chars.next() {
+            if c == '~' && at_word_start {
+                // Check what follows the tilde
+                match chars.peek() {
+                    None => {
+                        // Tilde at end of string - expand to home directory
+                        result.push_str(home_dir);
+                    }
+                    Some('/') | Some(':') => {
+                        // Tilde followed by slash or a PATH separator - expand to home directory
+                        result.push_str(home_dir);
+                    }
+                    Some(' ') | Some('\t') => {
+                        // Tilde followed by whitespace - expand to home directory
+                        result.push_str(home_dir);
+                    }
+                    Some(_) => {
+                        // ~username expands to that user's home directory
+                        let mut username = String::new();
+                        while let Some(&ch) = chars.peek() {
+                            if ch == '/' || ch == ':' || ch.is_whitespace() {
+                                break;
+                            }
+                            username.push(ch);
+                            chars.next();
+                        }
+
+                        // An unknown user leaves the text unchanged
+                        let valid = username
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+                        match user_home_dir(&username).filter(|_| valid) {
+                            Some(home) => result.push_str(&home),
+                            None => {
+                                result.push('~');
+                                result.push_str(&username);
+                            }
+                        }
+                    }
+                }
+                at_word_start = false;
+            } else {
+                result.push(c);
+                // Update word boundary tracking
+                at_word_start = c.is_whitespace() || c == ':' || c == '=';
+            }
+        }
+
+        result
+    }
+
+    // Helper method to execute commands for substitution
+    /// Contents of the file named by `word` for `$(< file)`, without trailing newlines;
+    /// a file that cannot be read expands to nothing
+    fn read_substitution_file(&self, word: &str) -> String {
+        let path = self.expand_word_unsplit_read_only(word.trim());
+        match fs::read_to_string(&path) {
+            Ok(contents) => contents.trim_end_matches('\n').to_string(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let _ = writeln!(&self.stderr, "flash: {path}: No such file or directory");
+                String::new()
+            }
+            Err(e) => {
+                let _ = writeln!(&self.stderr, "flash: {path}: {e}");
+                String::new()
+            }
+        }
+    }
+
+    fn execute_command_for_substitution(&self, cmd: &str) -> Result<String, io::Error> {
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+
+        // $(< file) reads the file directly instead of running a command
+        if let Some(file) = cmd
+            .trim_start()
+            .strip_prefix('<')
+            .filter(|rest| !rest.starts_with(['<', '(']))
+        {
+            return Ok(self.read_substitution_file(file));
+        }
+
+        let lexer = Lexer::new(cmd);
+        let mut parser = Parser::new(lexer);
+        let ast = parser.parse_script();
+
+        // Create a temporary interpreter for execution
+        let mut temp_interpreter = Interpreter {
+            variables: self.variables.clone(),
+            arrays: self.arrays.clone(),
+            functions: self.functions.clone(),
+            aliases: self.aliases.clone(),
+            last_exit_code: self.last_exit_code,
+            history: Vec::new(),
+            history_file: None,
+            rc_file: None,
+            args: self.args.clone(),
+            return_value: None,
+            history_expansion_depth: 0,
+            completion_system: CompletionSystem::new(),
+            // Like bash, command substitutions do not inherit `set -e`
+            shell_options: self
+                .shell_options
+                .iter()
+                .filter(|option| *option != "errexit")
+                .cloned()
+                .collect(),
+            nesting_level: self.nesting_level + 1,
+            namerefs: self.namerefs.clone(),
+            // Listed by `jobs`, as in kill $(jobs -p), but not waited for
+            jobs: self
+                .jobs
+                .iter()
+                .map(|job| Job {
+                    inherited: true,
+                    ..job.clone()
+                })
+                .collect(),
+            integer_vars: self.integer_vars.clone(),
+            associative_arrays: self.associative_arrays.clone(),
+            exported_functions: self.exported_functions.clone(),
+            local_scopes: self.local_scopes.clone(),
+            loop_depth: 0,
+            condition_depth: 0,
+            custom_builtins: self.custom_builtins.clone(),
+            traps: HashMap::new(),
+            signal_dispositions: HashMap::new(),
+            getopts_offset: 0,
+            arithmetic_error: None,
+            readonly: self.readonly.clone(),
+            interactive: false,
+            interruptible: false,
+            dir_stack: self.dir_stack.clone(),
+            stdin: None,
+            stdin_spool: None,
+            stdin_redirections: 0,
+            stdout: self.stdout.share(),
+            stderr: self.stderr.share(),
+        };
+
+        let mut evaluator = DefaultEvaluator;
+        temp_interpreter.capture_command_output(&ast, &mut evaluator)
+    }
+
+    /// Expand glob patterns in command arguments
+    pub fn expand_glob_patterns(&self, args: &[String]) -> Vec<String> {
+        let mut expanded_args = Vec::new();
+
+        for arg in args {
+            // First expand brace patterns
+            let brace_expanded = self.expand_brace_patterns(arg);
+
+            // Then expand glob patterns for each brace-expanded result
+            for brace_arg in brace_expanded {
+                if self.contains_glob_pattern(&brace_arg) {
+                    let matches = self.glob_match(&brace_arg);
+                    if matches.is_empty() {
+                        // If no matches found, keep the original pattern
+                        expanded_args.push(brace_arg);
+                    } else {
+                        expanded_args.extend(matches);
+                    }
+                } else {
+                    expanded_args.push(brace_arg);
+                }
+            }
+        }
+
+        expanded_args
+    }
+
+    /// Expand brace patterns like {1..5} or {a..z}
+    pub fn expand_brace_patterns(&self, input: &str) -> Vec<String> {
+        // Check if the input contains brace expansion patterns
+        if !input.contains('{') || !input.contains('}') {
+            return vec![input.to_string()];
+        }
+
+        // Find all brace expansion patterns in the input
+        let mut result = vec![input.to_string()];
+
+        // Process each string in result for brace expansions
+        let mut i = 0;
+        while i < result.len() {
+            let current = &result[i].clone();
+            if let Some(expanded) = self.expand_single_brace_pattern(current) {
+                // Replace the current item with expanded items
+                result.remove(i);
+                for (j, item) in expanded.into_iter().enumerate() {
+                    result.insert(i + j, item);
+                }
+                // Continue processing from the same index since we may have more patterns
+                continue;
+            }
+            i += 1;
+        }
+
+        result
+    }
+
+    /// Expand the leftmost expandable brace pattern in a string.
+    /// Degenerate braces such as `{a}` or `{}` are left alone and skipped over.
+    fn expand_single_brace_pattern(&self, input: &str) -> Option<Vec<String>> {
+        let mut search_from = 0;
+
+        while let Some(offset) = input[search_from..].find('{') {
+            let start = search_from + offset;
+
+            // Find the matching closing brace
+            let mut brace_end = None;
+            let mut brace_count = 0;
+            for (i, c) in input[start..].char_indices() {
+                match c {
+                    '{' => brace_count += 1,
+                    '}' => {
+                        brace_count -= 1;
+                        if brace_count == 0 {
+                            brace_end = Some(start + i);
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let end = brace_end?;
+
+            let prefix = &input[..start];
+            let suffix = &input[end + 1..];
+            let brace_content = &input[start + 1..end];
+
+            // Check for range expansion (e.g., "1..5" or "a..z")
+            if brace_content.contains("..") {
+                if let Some(range_items) = self.expand_brace_range(brace_content) {
+                    let mut results = Vec::new();
+                    for item in range_items {
+                        results.push(format!("{prefix}{item}{suffix}"));
+                    }
+                    return Some(results);
+                }
+            }
+
+            // Check for comma-separated expansion (e.g., "a,b,c"); nested groups stay in
+            // their alternative and are expanded on a later pass
+            let items = Self::split_brace_alternatives(brace_content);
+            if items.len() > 1 {
+                let mut results = Vec::new();
+                for item in items {
+                    results.push(format!("{}{}{}", prefix, item.trim(), suffix));
+                }
+                return Some(results);
+            }
+
+            // Not expandable; keep it literal and look for a later pattern
+            search_from = start + 1;
+        }
+
+        None
+    }
+
+    /// Split the content of a brace group on the commas that are not inside nested braces
+    fn split_brace_alternatives(content: &str) -> Vec<&str> {
+        let mut items = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+        for (i, c) in content.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ',' if depth == 0 => {
+                    items.push(&content[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        items.push(&content[start..]);
+        items
+    }
+
+    /// Expand brace ranges like {1..10} or {a..z}, with an optional step as in
+    /// {0..20..5}. A leading zero on either number pads every result to the same width.
+    fn expand_brace_range(&self, content: &str) -> Option<Vec<String>> {
+        let parts: Vec<&str> = content.split("..").collect();
+        let (start, end, step) = match parts[..] {
+            [start, end] => (start.trim(), end.trim(), 1),
+            [start, end, step] => {
+                // The sign of the step is ignored; the endpoints give the direction
+                let step = step.trim().parse::<i64>().ok()?.unsigned_abs().max(1);
+                (start.trim(), end.trim(), step as usize)
+            }
+            _ => return None,
+        };
+
+        // Try numeric expansion
+        if let (Ok(start_num), Ok(end_num)) = (start.parse::<i64>(), end.parse::<i64>()) {
+            let padded = [start, end].iter().any(|endpoint| {
+                let digits = endpoint.trim_start_matches('-');
+                digits.len() > 1 && digits.starts_with('0')
+            });
+            let width = if padded {
+                start.len().max(end.len())
+            } else {
+                0
+            };
+            let numbers: Vec<i64> = if start_num <= end_num {
+                (start_num..=end_num).step_by(step).collect()
+            } else {
+                (end_num..=start_num).rev().step_by(step).collect()
+            };
+            return Some(
+                numbers
+                    .into_iter()
+                    .map(|n| format!("{n:0width$}"))
+                    .collect(),
+            );
+        }
+
+        // Try character expansion (single characters only)
+        if start.len() == 1 && end.len() == 1 {
+            let start_char = start.chars().next().unwrap();
+            let end_char = end.chars().next().unwrap();
+
+            // Only expand if both characters are in the same case (both lowercase or both uppercase)
+            let both_lowercase = start_char.is_ascii_lowercase() && end_char.is_ascii_lowercase();
+            let both_uppercase = start_char.is_ascii_uppercase() && end_char.is_ascii_uppercase();
+
+            if both_lowercase || both_uppercase {
+                let chars: Vec<char> = if start_char <= end_char {
+                    (start_char..=end_char).step_by(step).collect()
+                } else {
+                    (end_char..=start_char).rev().step_by(step).collect()
+                };
+                return Some(chars.into_iter().map(String::from).collect());
+            }
+        }
+
+        None
+    }
+
+    /// Check if a string contains glob patterns, including extended ones under `shopt -s extglob`
+    fn contains_glob_pattern(&self, s: &str) -> bool {
+        s.contains('*')
+            || s.contains('?')
+            || s.contains('[')
+            || (self.shell_options.contains("extglob") && has_ext_glob(s))
+    }
+
+    /// Match a glob pattern against files in the specified directory
+    fn glob_match_in_dir(&self, pattern: &str, search_dir: &Path) -> Vec<String> {
+        if self.shell_options.contains("globstar") && pattern.split('/').any(|c| c == "**") {
+            return self.globstar_match(pattern, search_dir);
+        }
+
+        let mut matches = Vec::new();
+
+        // Handle absolute and relative paths, expanding `~` and variables in the directory
+        let (dir, dir_path, file_pattern) = if let Some(last_slash) = pattern.rfind('/') {
+            let dir = self.expand_glob_base(&pattern[..last_slash]);
+            let file = &pattern[last_slash + 1..];
+            let dir_path = if dir.is_empty() {
+                PathBuf::from("/")
+            } else {
+                search_dir.join(&dir)
+            };
+            (dir, dir_path, file.to_string())
+        } else {
+            (String::new(), search_dir.to_path_buf(), pattern.to_string())
+        };
+
+        // Read directory entries
+        if let Ok(entries) = fs::read_dir(&dir_path) {
+            for entry in entries.flatten() {
+                if let Some(filename) = entry.file_name().to_str() {
+                    // Skip hidden files unless pattern explicitly starts with '.'
+                    if filename.starts_with('.') && !file_pattern.starts_with('.') {
+                        continue;
+                    }
+
+                    if self.matches_glob_pattern(filename, &file_pattern) {
+                        let full_path = if pattern.contains('/') {
+                            format!("{dir}/{filename}")
+                        } else {
+                            filename.to_string()
+                        };
+                        matches.push(full_path);
+                    }
+                }
+            }
+        }
+
+        // Sort matches for consistent output
+        matches.sort();
+        matches
+    }
+
+    /// Match a pattern under `shopt -s globstar`, where a `**` component matches any
+    /// number of directories, and `**` at the end also matches every file below them
+    fn globstar_match(&self, pattern: &str, search_dir: &Path) -> Vec<String> {
+        let pattern = self.expand_glob_base(pattern);
+        let (root, shown) = match pattern.strip_prefix('/') {
+            Some(_) => (PathBuf::from("/"), "/".to_string()),
+            None => (search_dir.to_path_buf(), String::new()),
+        };
+        let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+
+        let mut matches = Vec::new();
+        self.globstar_walk(&root, &shown, &components, &mut matches);
+        matches.sort();
+        matches.dedup();
+        matches
+    }
+
+    /// Match the remaining pattern `components` below `dir`, which is written as `shown`
+    fn globstar_walk(
+        &self,
+        dir: &Path,
+        shown: &str,
+        components: &[&str],
+        matches: &mut Vec<String>,
+    ) {
+        let Some((&component, rest)) = components.split_first() else {
+            return;
+        };
+        let join = |name: &str| {
+            if shown.is_empty() || shown.ends_with('/') {
+                format!("{shown}{name}")
+            } else {
+                format!("{shown}/{name}")
+            }
+        };
+
+        if !self.contains_glob_pattern(component) {
+            let path = dir.join(component);
+            if rest.is_empty() {
+                if path.symlink_metadata().is_ok() {
+                    matches.push(join(component));
+                }
+            } else if path.is_dir() {
+                self.globstar_walk(&path, &join(component), rest, matches);
+            }
+            return;
+        }
+
+        // `**` may stand for no directory at all
+        if component == "**" && !rest.is_empty() {
+            self.globstar_walk(dir, shown, rest, matches);
+        }
+
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let Some(name) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            if name.starts_with('.') && !component.starts_with('.') {
+                continue;
+            }
+            // Symbolic links to directories are not followed, so cycles cannot occur
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if component == "**" {
+                if rest.is_empty() {
+                    matches.push(join(&name));
+                }
+                if is_dir {
+                    self.globstar_walk(&entry.path(), &join(&name), components, matches);
+                }
+            } else if self.matches_glob_pattern(&name, component) {
+                if rest.is_empty() {
+                    matches.push(join(&name));
+                } else if entry.path().is_dir() {
+                    self.globstar_walk(&entry.path(), &join(&name), rest, matches);
+                }
+            }
+        }
+    }
+
+    /// Expand a leading `~` (using `$HOME`) and any variables in the directory part of a glob
+    fn expand_glob_base(&self, dir: &str) -> String {
+        let dir = match dir.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                match self.variables.get("HOME") {
+                    Some(home) => format!("{home}{rest}"),
+                    None => self.expand_tilde(dir),
+                }
+            }
+            _ => dir.to_string(),
+        };
+
+        if dir.contains('$') {
+            self.expand_variables(&dir)
+        } else {
+            dir
+        }
+    }
+
+    /// Match a glob pattern against files in the current directory
+    fn glob_match(&self, pattern: &str) -> Vec<String> {
+        self.glob_match_in_dir(pattern, Path::new("."))
+    }
+
+    /// Check if a filename matches a glob pattern
+    fn matches_glob_pattern(&self, filename: &str, pattern: &str) -> bool {
+        if self.shell_options.contains("extglob") && has_ext_glob(pattern) {
+            let filename: Vec<char> = filename.chars().collect();
+            let pattern: Vec<char> = pattern.chars().collect();
+            return self.ext_glob_match(&filename, &pattern);
+        }
+        self.glob_match_recursive(filename, pattern, 0, 0)
+    }
+
+    /// Match `name` against a pattern that may hold extended glob groups like `@(a|b)`
+    fn ext_glob_match(&self, name: &[char], pattern: &[char]) -> bool {
+        let Some(&first) = pattern.first() else {
+            return name.is_empty();
+        };
+
+        // An escaped character matches itself
+        if first == '\\' && pattern.len() > 1 {
+            return name.first() == Some(&pattern[1])
+                && self.ext_glob_match(&name[1..], &pattern[2..]);
+        }
+
+        if let Some((alternatives, end)) = ext_glob_group(pattern) {
+            let rest = &pattern[end..];
+            let matches_one = |text: &[char]| {
+                alternatives
+                    .iter()
+                    .any(|alternative| self.ext_glob_match(text, alternative))
+            };
+            // Each split point gives the text matched by the group and what follows it
+            let mut splits = 0..=name.len();
+            return match first {
+                // `?(...)` and `*(...)` may match nothing at all
+                '?' | '*' if self.ext_glob_match(name, rest) => true,
+                '?' | '@' => {
+                    splits.any(|k| matches_one(&name[..k]) && self.ext_glob_match(&name[k..], rest))
+                }
+                '*' | '+' => {
+                    // One occurrence, then zero or more of the same group
+                    let mut again = vec!['*'];
+                    again.extend_from_slice(&pattern[1..]);
+                    splits.any(|k| {
+                        k > 0 && matches_one(&name[..k]) && self.ext_glob_match(&name[k..], &again)
+                    })
+                }
+                _ => splits
+                    .any(|k| !matches_one(&name[..k]) && self.ext_glob_match(&name[k..], rest)),
+            };
+        }
+
+        match first {
+            '*' => (0..=name.len()).any(|k| self.ext_glob_match(&name[k..], &pattern[1..])),
+            '?' => !name.is_empty() && self.ext_glob_match(&name[1..], &pattern[1..]),
+            '[' => match self.find_closing_bracket(pattern, 0) {
+                Some(end) => {
+                    !name.is_empty()
+                        && self.matches_char_class(name[0], &pattern[1..end])
+                        && self.ext_glob_match(&name[1..], &pattern[end + 1..])
+                }
+                None => {
+                    name.first() == Some(&'[') && self.ext_glob_match(&name[1..], &pattern[1..])
+                }
+            },
+            c => name.first() == Some(&c) && self.ext_glob_match(&name[1..], &pattern[1..]),
+        }
+    }
+
+    /// Recursive glob pattern matching implementation
+    fn glob_match_recursive(
+        &self,
+        filename: &str,
+        pattern: &str,
+        f_idx: usize,
+        p_idx: usize,
+    ) -> bool {
+        let f_chars: Vec<char> = filename.chars().collect();
+        let p_chars: Vec<char> = pattern.chars().collect();
+
+        // Base cases
+        if p_idx >= p_chars.len() {
+            return f_idx >= f_chars.len();
+        }
+
+        if f_idx >= f_chars.len() {
+            // Check if remaining pattern is all '*'
+            return p_chars[p_idx..].iter().all(|&c| c == '*');
+        }
+
+        match p_chars[p_idx] {
+            '*' => {
+                // Try matching zero or more characters
+                // First try matching zero characters (skip the *)
+                if self.glob_match_recursive(filename, pattern, f_idx, p_idx + 1) {
+                    return true;
+                }
+                // Then try matching one or more characters
+                for i in f_idx..f_chars.len() {
+                    if self.glob_match_recursive(filename, pattern, i + 1, p_idx + 1) {
+                        return true;
+                    }
+                }
+                false
+            }
+            '?' => {
+                // Match exactly one character
+                self.glob_match_recursive(filename, pattern, f_idx + 1, p_idx + 1)
+            }
+            '[' => {
+                // Character class matching
+                if let Some(end_bracket) = self.find_closing_bracket(&p_chars, p_idx) {
+                    let char_class = &p_chars[p_idx + 1..end_bracket];
+                    if self.matches_char_class(f_chars[f_idx], char_class) {
+                        self.glob_match_recursive(filename, pattern, f_idx + 1, end_bracket + 1)
+                    } else {
+                        false
+                    }
+                } else {
+                    // Invalid bracket, treat as literal
+                    f_chars[f_idx] == '['
+                        && self.glob_match_recursive(filename, pattern, f_idx + 1, p_idx + 1)
+                }
+            }
+            '\\' if p_idx + 1 < p_chars.len() => {
+                // An escaped character matches itself
+                f_chars[f_idx] == p_chars[p_idx + 1]
+                    && self.glob_match_recursive(filename, pattern, f_idx + 1, p_idx + 2)
+            }
+            c => {
+                // Literal character match
+                f_chars[f_idx] == c
+                    && self.glob_match_recursive(filename, pattern, f_idx + 1, p_idx + 1)
+            }
+        }
+    }
+
+    /// Find the closing bracket for a character class
+    fn find_closing_bracket(&self, chars: &[char], start: usize) -> Option<usize> {
+        let mut i = start + 1;
+        // A `]` right after the opening bracket or its negation is a literal member
+        if matches!(chars.get(i), Some('!' | '^')) {
+            i += 1;
+        }
+        if chars.get(i) == Some(&']') {
+            i += 1;
+        }
+        while i < chars.len() {
+            // Skip over a named class like [:digit:]
+            if chars[i] == '[' && chars.get(i + 1) == Some(&':') {
+                if let Some(end) = find_class_name_end(chars, i + 2) {
+                    i = end + 2;
+                    continue;
+                }
+            }
+            if chars[i] == ']' {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Check if a character matches a character class
+    fn matches_char_class(&self, ch: char, char_class: &[char]) -> bool {
+        if char_class.is_empty() {
+            return false;
+        }
+
+        let negated = char_class[0] == '!' || char_class[0] == '^';
+        let class_chars = if negated {
+            &char_class[1..]
+        } else {
+            char_class
+        };
+
+        let mut matches = false;
+        let mut i = 0;
+
+        while i < class_chars.len() {
+            if class_chars[i] == '[' && class_chars.get(i + 1) == Some(&':') {
+                if let Some(end) = find_class_name_end(class_chars, i + 2) {
+                    let name: String = class_chars[i + 2..end].iter().collect();
+                    if matches_named_class(ch, &name) {
+                        matches = true;
+                        break;
+                    }
+                    i = end + 2;
+                    continue;
+                }
+            }
+            if i + 2 < class_chars.len() && class_chars[i + 1] == '-' {
+                // Range like a-z
+                let start = class_chars[i];
+                let end = class_chars[i + 2];
+                if ch >= start && ch <= end {
+                    matches = true;
+                    break;
+                }
+                i += 3;
+            } else {
+                // Single character
+                if ch == class_chars[i] {
+                    matches = true;
+                    break;
+                }
+                i += 1;
+            }
+        }
+
+        if negated { !matches } else { matches }
+    }
+
+    /// Find an executable in the given colon-separated search path
+    pub fn find_in_path(name: &str, path: &str) -> Option<PathBuf> {
+        let is_executable = |candidate: &Path| {
+            candidate.is_file()
+                && candidate
+                    .metadata()
+                    .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+                    .unwrap_or(false)
+        };
+
+        if name.contains('/') {
+            let candidate = PathBuf::from(name);
+            return is_executable(&candidate).then_some(candidate);
+        }
+
+        path.split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| Path::new(dir).join(name))
+            .find(|candidate| is_executable(candidate))
+    }
+
+    /// Print how a command name would be resolved (`command -v`/`command -V`)
+    fn describe_command(&self, name: &str, path: &str, verbose: bool) -> i32 {
+        if let Some(alias) = self.aliases.get(name) {
+            if verbose {
+                let _ = writeln!(&self.stdout, "{name} is aliased to `{alias}'");
+            } else {
+                let _ = writeln!(&self.stdout, "alias {name}='{alias}'");
+            }
+        } else if self.functions.contains_key(name) {
+            if verbose {
+                let _ = writeln!(&self.stdout, "{name} is a function");
+            } else {
+                let _ = writeln!(&self.stdout, "{name}");
+            }
+        } else if self.is_builtin(name) {
+            if verbose {
+                let _ = writeln!(&self.stdout, "{name} is a shell builtin");
+            } else {
+                let _ = writeln!(&self.stdout, "{name}");
+            }
+        } else if let Some(found) = Self::find_in_path(name, path) {
+            if verbose {
+                let _ = writeln!(&self.stdout, "{name} is {}", found.display());
+            } else {
+                let _ = writeln!(&self.stdout, "{}", found.display());
+            }
+        } else {
+            if verbose {
+                let _ = writeln!(&self.stderr, "command: {name}: not found");
+            }
+            return 1;
+        }
+        0
+    }
+
+    /// Run `node` in a forked child and record it as a background job
+    pub fn spawn_background<E: Evaluator>(
+        &mut self,
+        node: &Node,
+        evaluator: &mut E,
+    ) -> Result<i32, io::Error> {
+        let _ = io::stdout().flush();
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if pid == 0 {
+            self.enter_forked_child();
+            let code = self.evaluate_with_evaluator(node, evaluator).unwrap_or(1);
+            let _ = io::stdout().flush();
+            let _ = io::stderr().flush();
+            unsafe { libc::_exit(code) };
+        }
+
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job {
+            id,
+            pid,
+            command: job_command_text(node),
+            inherited: false,
+        });
+        self.variables.insert("!".to_string(), pid.to_string());
+        if self.interactive {
+            writeln!(&self.stderr, "[{id}] {pid}")?;
+        }
+        Ok(0)
+    }
+
+    /// Start a builtin, function or compound command in a forked copy of the shell,
+    /// reading stdin from `input`; returns its pid and the read end of a pipe carrying
+    /// its stdout, so a pipeline's next stage or a command substitution reads the output
+    /// as it is written
+    fn fork_stage<E: Evaluator>(
+        &mut self,
+        node: &Node,
+        input: PipeInput,
+        pipes: &HostPipes,
+        evaluator: &mut E,
+    ) -> Result<(libc::pid_t, fs::File), io::Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        io::stdout().flush()?;
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if pid == 0 {
+            let stdin_fd = match &input {
+                PipeInput::Inherit => None,
+                PipeInput::Pipe(stdout) => Some(stdout.as_raw_fd()),
+                PipeInput::File(file) => Some(file.as_raw_fd()),
+            };
+            unsafe {
+                // A reader that goes away ends the stage, as it would an external command,
+                // and so does Ctrl-C at the prompt
+                libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+                if self.interruptible {
+                    libc::signal(libc::SIGINT, libc::SIG_DFL);
+                }
+                if let Some(fd) = stdin_fd {
+                    libc::dup2(fd, 0);
+                }
+                libc::dup2(write.as_raw_fd(), 1);
+                if let Some(fd) = pipes.stderr_fd() {
+                    libc::dup2(fd, 2);
+                }
+            }
+            drop(input);
+            drop(read);
+            drop(write);
+            self.enter_forked_child();
+
+            let code = self.evaluate_with_evaluator(node, evaluator).unwrap_or(1);
+            let _ = io::stdout().flush();
+            let _ = io::stderr().flush();
+            unsafe { libc::_exit(code) };
+        }
+
+        Ok((pid, fs::File::from(read)))
+    }
+
+    /// Set up a forked copy of the shell: it writes to and reads from the descriptors
+    /// it was given, and keeps the parent's jobs to list but not to wait for, since
+    /// they are not its children
+    fn enter_forked_child(&mut self) {
+        self.detach_input();
+        self.stdout.detach();
+        self.stderr.detach();
+        for job in &mut self.jobs {
+            job.inherited = true;
+        }
+    }
+
+    /// Whether input comes from the host's reader rather than fd 0, which it does when
+    /// the host set one and fd 0 is not redirected
+    fn reads_host_input(&self) -> bool {
+        self.stdin.is_some() && self.stdin_redirections == 0
+    }
+
+    /// The host's input as a descriptor for commands that read fd 0 themselves. The rest
+    /// of the host's reader is copied to a temporary file the first time, and every
+    /// command shares its offset, so what one leaves unread is there for the next
+    fn host_input_file(&mut self) -> io::Result<Option<fs::File>> {
+        if !self.reads_host_input() {
+            return Ok(None);
+        }
+        if self.stdin_spool.is_none() {
+            let mut spool = tempfile::tempfile()?;
+            if let Some(input) = self.stdin.as_mut() {
+                io::copy(input, &mut spool)?;
+            }
+            spool.seek(SeekFrom::Start(0))?;
+            self.stdin_spool = Some(spool);
+        }
+        self.stdin_spool
+            .as_ref()
+            .map(fs::File::try_clone)
+            .transpose()
+    }
+
+    /// In a forked copy of the shell, read fd 0 from now on. The copy of the host's
+    /// reader is forgotten rather than dropped, like the writers in `OutputStream::detach`
+    fn detach_input(&mut self) {
+        mem::forget(self.stdin.take());
+        self.stdin_spool = None;
+    }
+
+    /// Read up to and including the next `delimiter` from the host-provided input, or
+    /// from descriptor 0 when there is none or it is redirected; None at end of input
+    fn read_input_delimited(&mut self, delimiter: u8) -> Result<Option<Vec<u8>>, io::Error> {
+        if !self.reads_host_input() {
+            return read_delimited(0, delimiter);
+        }
+        if let Some(spool) = &self.stdin_spool {
+            return read_delimited(spool.as_raw_fd(), delimiter);
+        }
+        let Some(input) = self.stdin.as_mut() else {
+            return read_delimited(0, delimiter);
+        };
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            match input.read(&mut byte) {
+                Ok(0) => return Ok((!line.is_empty()).then_some(line)),
+                Ok(_) => {
+                    line.push(byte[0]);
+                    if byte[0] == delimiter {
+                        return Ok(Some(line));
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Names of the variables and arrays `declare -p` lists, sorted
+    pub fn declared_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .variables
+            .keys()
+            .chain(self.arrays.keys())
+            .chain(self.associative_arrays.keys())
+            .filter(|name| is_assignable_name(name))
+            .cloned()
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// A `declare` command that recreates `name` with its attributes and value, or None
+    /// when it is neither set nor given any attribute
+    pub fn declare_definition(&self, name: &str) -> Option<String> {
+        let subscripts = self.array_subscripts(name);
+        let associative = self.associative_arrays.contains_key(name);
+        let indexed = self.arrays.contains_key(name);
+        let target = self.namerefs.get(name);
+        let mut flags = String::new();
+        if associative {
+            flags.push('A');
+        } else if indexed {
+            flags.push('a');
+        }
+        if self.integer_vars.contains(name) {
+            flags.push('i');
+        }
+        if target.is_some() {
+            flags.push('n');
+        }
+        if self.readonly.contains(name) {
+            flags.push('r');
+        }
+        if env::var_os(name).is_some() {
+            flags.push('x');
+        }
+        let flags = if flags.is_empty() {
+            "--".to_string()
+        } else {
+            format!("-{flags}")
+        };
+
+        let value = if let Some(target) = target {
+            Some(double_quote(target))
+        } else if indexed || associative {
+            let elements: Vec<String> = subscripts
+                .iter()
+                .filter_map(|key| {
+                    let value = self.stored_element(name, key)?;
+                    // Keys with spaces or brackets are quoted so the output can be read back
+                    let key = if key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                        key.clone()
+                    } else {
+                        double_quote(key)
+                    };
+                    Some(format!("[{key}]={}", double_quote(value)))
+                })
+                .collect();
+            Some(format!("({})", elements.join(" ")))
+        } else {
+            self.variables.get(name).map(|value| double_quote(value))
+        };
+        match value {
+            Some(value) => Some(format!("declare {flags} {name}={value}")),
+            None if flags != "--" => Some(format!("declare {flags} {name}")),
+            None => None,
+        }
+    }
+
+    /// The logical working directory, as kept in `$PWD`
+    pub fn working_directory(&self) -> String {
+        self.variables
+            .get("PWD")
+            .cloned()
+            .or_else(|| {
+                env::current_dir()
+                    .ok()
+                    .map(|d| d.to_string_lossy().to_string())
+            })
+            .unwrap_or_else(|| "/".to_string())
+    }
+
+    /// Change to `dir`, resolving `..` textually against `$PWD` unless `physical` is
+    /// set, and update `PWD` and `OLDPWD`
+    pub fn change_directory(&mut self, dir: &str, physical: bool) -> Result<(), io::Error> {
+        let current = self.working_directory();
+        let logical = logical_path(&current, dir);
+        if physical {
+            env::set_current_dir(dir)?;
+        } else {
+            // Fall back to the physical path when the logical one does not exist
+            env::set_current_dir(&logical).or_else(|_| env::set_current_dir(dir))?;
+        }
+
+        let physical_dir = env::current_dir()?;
+        let pwd = if physical || fs::canonicalize(&logical).ok() != Some(physical_dir.clone()) {
+            physical_dir
+        } else {
+            logical
+        };
+        self.variables.insert("OLDPWD".to_string(), current);
+        self.variables
+            .insert("PWD".to_string(), pwd.to_string_lossy().to_string());
+        Ok(())
+    }
+
+    /// The working directory followed by the pushd stack, with `$HOME` shown as `~`
+    /// when `tilde` is set
+    pub fn dir_stack_entries(&self, tilde: bool) -> Vec<String> {
+        let home = env::var("HOME").ok().filter(|home| !home.is_empty());
+        std::iter::once(self.working_directory())
+            .chain(self.dir_stack.iter().cloned())
+            .map(|dir| match &home {
+                Some(home) if tilde => match dir.strip_prefix(home.as_str()) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{rest}"),
+                    _ => dir,
+                },
+                _ => dir,
+            })
+            .collect()
+    }
+
+    /// Start `source` in a forked child for a process substitution, returning the path
+    /// the command reads its output from (`reads`) or writes its input to
+    fn start_process_substitution<E: Evaluator>(
+        &mut self,
+        source: &str,
+        reads: bool,
+        running: &[ProcessSubstitution],
+        evaluator: &mut E,
+    ) -> Result<(String, ProcessSubstitution), io::Error> {
+        let (path, end, child_end, fifo_dir) = if Path::new("/dev/fd").is_dir() {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let (read, write) =
+                unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            let (end, child_end) = if reads { (read, write) } else { (write, read) };
+            let path = format!("/dev/fd/{}", end.as_raw_fd());
+            (path, Some(end), Some(child_end), None)
+        } else {
+            // Without /dev/fd the child and the command meet at a named pipe
+            let dir = tempfile::tempdir()?;
+            let fifo = dir.path().join("fifo");
+            let fifo_name = std::ffi::CString::new(fifo.to_string_lossy().as_bytes())?;
+            if unsafe { libc::mkfifo(fifo_name.as_ptr(), 0o600) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            (fifo.to_string_lossy().to_string(), None, None, Some(dir))
+        };
+
+        let _ = io::stdout().flush();
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if pid == 0 {
+            let child_end = match child_end {
+                Some(child_end) => Ok(child_end),
+                None => fs::OpenOptions::new()
+                    .read(!reads)
+                    .write(reads)
+                    .open(&path)
+                    .map(OwnedFd::from),
+            };
+            let Ok(child_end) = child_end else {
+                unsafe { libc::_exit(1) };
+            };
+            let target = if reads { 1 } else { 0 };
+            unsafe {
+                libc::dup2(child_end.as_raw_fd(), target);
+            }
+            drop(child_end);
+            drop(end);
+            for end in running
+                .iter()
+                .filter_map(|substitution| substitution.end.as_ref())
+            {
+                unsafe { libc::close(end.as_raw_fd()) };
+            }
+            self.enter_forked_child();
+
+            let ast = Parser::new(Lexer::new(source)).parse_script();
+            let code = self.evaluate_with_evaluator(&ast, evaluator).unwrap_or(1);
+            let _ = io::stdout().flush();
+            let _ = io::stderr().flush();
+            unsafe { libc::_exit(code) };
+        }
+
+        let substitution = ProcessSubstitution {
+            pid,
+            reads,
+            end,
+            fifo_dir,
+        };
+        Ok((path, substitution))
+    }
+
+    /// Reap finished background jobs without blocking, returning their completion notices
+    pub fn reap_jobs(&mut self) -> Vec<String> {
+        let mut notices = Vec::new();
+        let mut i = 0;
+        while i < self.jobs.len() {
+            let mut status = 0;
+            // Inherited jobs belong to the parent shell, which reaps them itself
+            if self.jobs[i].inherited {
+                i += 1;
+                continue;
+            }
+            let pid = unsafe { libc::waitpid(self.jobs[i].pid, &mut status, libc::WNOHANG) };
+            if pid == 0 {
+                i += 1;
+                continue;
+            }
+
+            // Either reaped now or no longer our child; forget it in both cases
+            let job = self.jobs.remove(i);
+            if pid > 0 {
+                notices.push(job_notice(&job, wait_status_code(status)));
+            }
+        }
+        notices
+    }
+
+    /// Resolve a job spec (`%n`, `%+`/`%%`, `%-`, `%string`, `%?string`) to an index
+    /// into the jobs table. The current job is the most recently started one.
+    pub fn resolve_job_spec(&self, spec: &str) -> Result<usize, String> {
+        let no_such_job = || format!("{spec}: no such job");
+        let Some(body) = spec.strip_prefix('%') else {
+            return Err(no_such_job());
+        };
+
+        match body {
+            "" | "+" | "%" => self.jobs.len().checked_sub(1).ok_or_else(no_such_job),
+            // With a single job it is both the current and the previous one
+            "-" => match self.jobs.len() {
+                0 => Err(no_such_job()),
+                1 => Ok(0),
+                len => Ok(len - 2),
+            },
+            _ if body.chars().all(|c| c.is_ascii_digit()) => {
+                let id = body.parse::<usize>().map_err(|_| no_such_job())?;
+                self.jobs
+                    .iter()
+                    .position(|job| job.id == id)
+                    .ok_or_else(no_such_job)
+            }
+            _ => {
+                let matches: Vec<usize> = match body.strip_prefix('?') {
+                    Some(needle) => self
+                        .jobs
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, job)| job.command.contains(needle))
+                        .map(|(index, _)| index)
+                        .collect(),
+                    None => self
+                        .jobs
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, job)| job.command.starts_with(body))
+                        .map(|(index, _)| index)
+                        .collect(),
+                };
+                match matches.as_slice() {
+                    [index] => Ok(*index),
+                    [] => Err(no_such_job()),
+                    _ => Err(format!("{spec}: ambiguous job spec")),
+                }
+            }
+        }
+    }
+
+    /// Block until the background job at `index` finishes and return its exit code
+    pub fn wait_for_job(&mut self, index: usize) -> i32 {
+        let job = self.jobs.remove(index);
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(job.pid, &mut status, 0) };
+        if pid > 0 {
+            wait_status_code(status)
+        } else {
+            127
+        }
+    }
+
+    /// Shadow `name` in the innermost function scope, saving the caller's value.
+    /// Lookups stay dynamic: functions called from here see the local value.
+    pub fn declare_local(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        self.check_writable(name)?;
+        let previous = self.take_variable(name);
+        if let Some(scope) = self.local_scopes.last_mut() {
+            scope.entry(name.to_string()).or_insert(previous);
+        }
+        if let Some(value) = value {
+            self.variables.insert(name.to_string(), value.to_string());
+        }
+        Ok(())
+    }
+
+    /// Remove `name` whether it is a scalar or an array, returning what it held
+    fn take_variable(&mut self, name: &str) -> ShadowedVariable {
+        if let Some(value) = self.variables.remove(name) {
+            ShadowedVariable::Scalar(value)
+        } else if let Some(elements) = self.arrays.remove(name) {
+            ShadowedVariable::Indexed(elements)
+        } else if let Some(elements) = self.associative_arrays.remove(name) {
+            ShadowedVariable::Associative(elements)
+        } else {
+            ShadowedVariable::Unset
+        }
+    }
+
+    /// Leave a function scope, restoring every variable its `local`s shadowed
+    pub fn pop_local_scope(&mut self) {
+        let Some(scope) = self.local_scopes.pop() else {
+            return;
+        };
+        for (name, previous) in scope {
+            self.take_variable(&name);
+            match previous {
+                ShadowedVariable::Unset => {}
+                ShadowedVariable::Scalar(value) => {
+                    self.variables.insert(name, value);
+                }
+                ShadowedVariable::Indexed(elements) => {
+                    self.arrays.insert(name, elements);
+                }
+                ShadowedVariable::Associative(elements) => {
+                    self.associative_arrays.insert(name, elements);
+                }
+            }
+        }
+    }
+
+    /// Whether `name` would run as an external program rather than a function,
+    /// alias or builtin
+    pub fn is_external_command(&self, name: &str) -> bool {
+        !self.functions.contains_key(name)
+            && !self.aliases.contains_key(name)
+            && !self.is_builtin(name)
+            && name != "\\."
+    }
+
+    /// Record an interactive command in the history unless it is blank, repeats the
+    /// previous entry or matches one of the colon-separated `$HISTIGNORE` patterns
+    /// (where `&` stands for the previous entry). Returns whether it was recorded.
+    pub fn add_to_history(&mut self, input: &str) -> bool {
+        if input.trim().is_empty() || self.history.last().is_some_and(|last| last == input) {
+            return false;
+        }
+
+        if let Some(histignore) = self.variables.get("HISTIGNORE") {
+            let ignored = histignore.split(':').any(|pattern| match pattern {
+                "" => false,
+                "&" => self.history.last().is_some_and(|last| last == input),
+                pattern => self.matches_glob_pattern(input, pattern),
+            });
+            if ignored {
+                return false;
+            }
+        }
+
+        self.history.push(input.to_string());
+        true
+    }
+
+    /// Store the result of a `[[ =~ ]]` match in BASH_REMATCH, clearing the previous one
+    pub fn set_rematch(&mut self, captures: Option<&regex::Captures>) {
+        self.clear_array("BASH_REMATCH");
+        let Some(captures) = captures else {
+            return;
+        };
+        let groups = captures
+            .iter()
+            .map(|group| group.map_or(String::new(), |m| m.as_str().to_string()));
+        self.arrays
+            .insert("BASH_REMATCH".to_string(), groups.enumerate().collect());
+    }
+
+    /// Whether `name` is a shell builtin or a command registered by the embedder
+    pub fn is_builtin(&self, name: &str) -> bool {
+        Self::BUILTINS.contains(&name) || self.custom_builtins.contains_key(name)
+    }
+
+    /// Add a builtin command, consulted after functions and before `$PATH` lookup.
+    /// Registering a name that is already registered replaces the previous command.
+    pub fn register_builtin<F>(&mut self, name: &str, command: F)
+    where
+        F: Fn(&mut Interpreter, &[String]) -> Result<i32, io::Error> + Send + Sync + 'static,
+    {
+        self.custom_builtins
+            .insert(name.to_string(), Arc::new(command));
+    }
+
+    /// Follow a chain of `declare -n` name references to the variable it ends at
+    pub fn resolve_nameref(&self, name: &str) -> String {
+        let mut visited = HashSet::new();
+        let mut name = name;
+        while let Some(target) = self.namerefs.get(name) {
+            if !visited.insert(name) {
+                break;
+            }
+            name = target;
+        }
+        name.to_string()
+    }
+
+    /// Look up a shell variable, following name references
+    pub fn get_variable(&self, name: &str) -> Option<&String> {
+        self.variables.get(&self.resolve_nameref(name))
+    }
+
+    /// Make `name` a reference to `target`, rejecting self and circular references
+    pub fn declare_nameref(&mut self, name: &str, target: &str) -> Result<(), String> {
+        if name == target {
+            return Err(format!(
+                "{name}: nameref variable self references not allowed"
+            ));
+        }
+        // Walk the whole chain from the target, so longer loops are caught too
+        let mut visited = HashSet::from([name]);
+        let mut next = Some(target);
+        while let Some(current) = next {
+            if !visited.insert(current) {
+                return Err(format!("{name}: circular name reference"));
+            }
+            next = self.namerefs.get(current).map(String::as_str);
+        }
+        self.namerefs.insert(name.to_string(), target.to_string());
+        Ok(())
+    }
+
+    /// Evaluate a command whose status is tested, as an `if`/`while`/`until` condition
+    /// or under `!`, so `set -e` does not apply to it
+    pub fn evaluate_condition<E: Evaluator>(
+        &mut self,
+        node: &Node,
+        evaluator: &mut E,
+    ) -> Result<i32, io::Error> {
+        self.condition_depth += 1;
+        let result = self.evaluate_with_evaluator(node, evaluator);
+        self.condition_depth -= 1;
+        result
+    }
+
+    /// Enable or disable a `set -o` option
+    pub fn set_shell_option(&mut self, option: &str, enable: bool) {
+        if enable {
+            self.shell_options.insert(option.to_string());
+        } else {
+            self.shell_options.remove(option);
+        }
+    }
+
+    /// Build the xtrace prefix from `$PS4`, repeating its first character once per nesting level
+    pub fn xtrace_prefix(&self) -> String {
+        let ps4 = self
+            .variables
+            .get("PS4")
+            .cloned()
+            .unwrap_or_else(|| "+ ".to_string());
+        let expanded = self.expand_variables(&ps4);
+
+        let mut chars = expanded.chars();
+        match chars.next() {
+            Some(first) => {
+                let mut prefix = first.to_string().repeat(self.nesting_level + 1);
+                prefix.push_str(chars.as_str());
+                prefix
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Write the expanded command to stderr when `set -x` is active
+    fn trace_command(&self, name: &str, args: &[String]) {
+        if !self.shell_options.contains("xtrace") {
+            return;
+        }
+
+        let mut line = self.xtrace_prefix();
+        line.push_str(name);
+        // The arguments are already expanded, so they are only quoted for display
+        for arg in args {
+            line.push(' ');
+            line.push_str(&trace_quote(arg));
+        }
+        let _ = writeln!(&self.stderr, "{line}");
+    }
+
+    /// Parse alias value handling escaped spaces and quotes
+    fn parse_alias_value(&self, value: &str) -> Vec<String> {
+        let mut parts = Vec::new();
+        let mut current_part = String::new();
+        let mut chars = value.chars().peekable();
+        let mut in_quotes = false;
+        let mut quote_char = '\0';
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => {
+                    // Handle escape sequences
+                    if let Some(&next_ch) = chars.peek() {
+                        match next_ch {
+                            ' ' | '\t' | '\\' | '"' | '\'' => {
+                                // Escape the next character
+                                current_part.push(chars.next().unwrap());
+                            }
+                            _ => {
+                                // Not a recognized escape, keep the backslash
+                                current_part.push(ch);
+                            }
+                        }
+                    } else {
+                        // Backslash at end of string
+                        current_part.push(ch);
+                    }
+                }
+                '"' | '\'' => {
+                    if !in_quotes {
+                        // Start quoted section
+                        in_quotes = true;
+                        quote_char = ch;
+                    } else if ch == quote_char {
+                        // End quoted section
+                        in_quotes = false;
+                        quote_char = '\0';
+                    } else {
+                        // Different quote character inside quotes
+                        current_part.push(ch);
+                    }
+                }
+                ' ' | '\t' => {
+                    if in_quotes {
+                        // Space inside quotes is literal
+                        current_part.push(ch);
+                    } else {
+                        // Space outside quotes separates arguments
+                        if !current_part.is_empty() {
+                            parts.push(current_part);
+                            current_part = String::new();
+                        }
+                    }
+                }
+                _ => {
+                    current_part.push(ch);
+                }
+            }
+        }
+
+        // Add the last part if it's not empty
+        if !current_part.is_empty() {
+            parts.push(current_part);
+        }
+
+        parts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_custom_prompt() {
+        // Create interpreter without loading RC file
+        let mut interpreter = Interpreter {
+            variables: HashMap::default(),
+            arrays: HashMap::new(),
+            functions: HashMap::new(),
+            aliases: HashMap::new(),
+            last_exit_code: 0,
+            history: Vec::new(),
+            history_file: None,
+            rc_file: None,
+            args: Vec::new(),
+            return_value: None,
+            history_expansion_depth: 0,
+            completion_system: CompletionSystem::new(),
+            shell_options: HashSet::new(),
+            nesting_level: 0,
+            namerefs: HashMap::new(),
+            jobs: Vec::new(),
+            integer_vars: HashSet::new(),
+            associative_arrays: HashMap::new(),
+            exported_functions: HashSet::new(),
+            local_scopes: Vec::new(),
+            loop_depth: 0,
+            condition_depth: 0,
+            custom_builtins: HashMap::new(),
+            traps: HashMap::new(),
+            signal_dispositions: HashMap::new(),
+            getopts_offset: 0,
+            arithmetic_error: None,
+            readonly: HashSet::new(),
+            interactive: false,
+            interruptible: false,
+            dir_stack: Vec::new(),
+            stdin: None,
+            stdin_spool: None,
+            stdin_redirections: 0,
+            stdout: OutputStream::new(1),
+            stderr: OutputStream::new(2),
+        };
+
+        // Set PWD variable like the real interpreter does
+        if let Ok(current_dir) = std::env::current_dir() {
+            interpreter
+                .variables
+                .insert("PWD".to_string(), current_dir.to_string_lossy().to_string());
+        }
+
+        // Test default prompt
+        assert_eq!(interpreter.get_prompt(), "ϟ ");
+
+        // Test custom prompt
+        interpreter
+            .variables
+            .insert("PROMPT".to_string(), "flash> ".to_string());
+        assert_eq!(interpreter.get_prompt(), "flash> ");
+
+        // Test prompt with variable expansion
+        interpreter
+            .variables
+            .insert("USER".to_string(), "testuser".to_string());
+        interpreter
+            .variables
+            .insert("PROMPT".to_string(), "$USER> ".to_string());
+        assert_eq!(interpreter.get_prompt(), "testuser> ");
+
+        // Test prompt with PWD expansion - use a fixed PWD for testing
+        interpreter
+            .variables
+            .insert("TESTVAR".to_string(), "/test/path".to_string());
+        interpreter
+            .variables
+            .insert("PROMPT".to_string(), "flash:${TESTVAR}$ ".to_string());
+
+        let prompt = interpreter.get_prompt();
+        assert_eq!(prompt, "flash:/test/path$ ");
+    }
+
+    #[test]
+    fn test_variable_expansion() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .variables
+            .insert("NAME".to_string(), "world".to_string());
+
+        let expanded = interpreter.expand_variables("Hello $NAME!");
+        assert_eq!(expanded, "Hello world!");
+
+        let expanded = interpreter.expand_variables("Hello ${NAME}!");
+        assert_eq!(expanded, "Hello world!");
+    }
+
+    #[test]
+    fn test_pwd_updates_in_prompt_after_cd() {
+        let mut interpreter = Interpreter::new();
+
+        // Set up a prompt that uses PWD
+        interpreter
+            .variables
+            .insert("PROMPT".to_string(), "flash:$PWD$ ".to_string());
+
+        // Get initial prompt - should contain initial PWD
+        let initial_prompt = interpreter.get_prompt();
+        let initial_pwd = interpreter.variables.get("PWD").unwrap().clone();
+        assert!(initial_prompt.contains(&initial_pwd));
+
+        // Change directory to parent
+        let result = interpreter.execute("cd ..");
+        assert!(result.is_ok());
+
+        // Get new prompt - should contain updated PWD
+        let new_prompt = interpreter.get_prompt();
+        let new_pwd = interpreter.variables.get("PWD").unwrap();
+        assert!(new_prompt.contains(new_pwd));
+
+        // PWD should have changed
+        assert_ne!(&initial_pwd, new_pwd);
+
+        // The prompt should reflect the new PWD
+        assert_eq!(new_prompt, format!("flash:{new_pwd}$ "));
+    }
+
+    #[test]
+    fn test_command_substitution_basic() {
+        let mut interpreter = Interpreter::new();
+
+        // Test basic command substitution with echo
+        let result = interpreter.execute("X=$(echo hello)").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(interpreter.variables.get("X"), Some(&"hello".to_string()));
+
+        // Test command substitution in echo
+        let result = interpreter.execute("echo $(echo world)").unwrap();
+        assert_eq!(result, 0);
+    }
+
+    #[test]
+    fn test_command_substitution_in_strings() {
+        let interpreter = Interpreter::new();
+
+        // Test command substitution within variable expansion
+        let expanded = interpreter.expand_variables("Hello $(echo world)!");
+        assert_eq!(expanded, "Hello world!");
+
+        // Test nested command substitution - simplified for now
+        let expanded = interpreter.expand_variables("Result: $(echo test)");
+        assert_eq!(expanded, "Result: test");
+    }
+
+    #[test]
+    fn test_command_substitution_with_variables() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .variables
+            .insert("NAME".to_string(), "test".to_string());
+
+        // Test command substitution with variable expansion
+        let expanded = interpreter.expand_variables("$(echo $NAME)");
+        assert_eq!(expanded, "test");
+
+        // Test assignment with command substitution
+        let result = interpreter.execute("RESULT=$(echo $NAME)").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("RESULT"),
+            Some(&"test".to_string())
+        );
+    }
+
+    #[test]
+    fn test_command_substitution_with_pwd() {
+        let interpreter = Interpreter::new();
+
+        // Test pwd command substitution
+        // Skip this test if current directory is not accessible (e.g., in CI)
+        if let Ok(current_dir) = std::env::current_dir() {
+            let expected = current_dir.to_string_lossy().to_string();
+            let expanded = interpreter.expand_variables("$(pwd)");
+            assert_eq!(expanded, expected);
+        } else {
+            // In environments where current_dir fails, pwd should return empty string
+            let expanded = interpreter.expand_variables("$(pwd)");
+            assert_eq!(expanded, "");
+        }
+    }
+
+    #[test]
+    fn test_command_substitution_empty_result() {
+        let interpreter = Interpreter::new();
+
+        // Test command substitution with non-existent command
+        let expanded = interpreter.expand_variables("$(nonexistentcommand)");
+        assert_eq!(expanded, "");
+
+        // Test command substitution with empty echo
+        let expanded = interpreter.expand_variables("$(echo)");
+        assert_eq!(expanded, "");
+    }
+
+    #[test]
+    fn test_command_substitution_multiple() {
+        let interpreter = Interpreter::new();
+
+        // Test multiple command substitutions in one string
+        let expanded = interpreter.expand_variables("$(echo hello) $(echo world)");
+        assert_eq!(expanded, "hello world");
+
+        // Test command substitution with other text
+        let expanded = interpreter.expand_variables("prefix $(echo middle) suffix");
+        assert_eq!(expanded, "prefix middle suffix");
+    }
+
+    #[test]
+    fn test_command_substitution_with_arguments() {
+        let interpreter = Interpreter::new();
+
+        // Test command substitution with command arguments
+        let expanded = interpreter.expand_variables("$(echo hello world)");
+        assert_eq!(expanded, "hello world");
+
+        // Test with quoted arguments
+        let expanded = interpreter.expand_variables("$(echo 'hello world')");
+        assert_eq!(expanded, "hello world");
+    }
+
+    #[test]
+    fn test_command_substitution_whitespace_handling() {
+        let interpreter = Interpreter::new();
+
+        // Test that trailing newlines are trimmed
+        let expanded = interpreter.expand_variables("$(echo test)");
+        assert_eq!(expanded, "test");
+
+        // Test with multiple lines - should preserve internal newlines but trim trailing
+        let expanded = interpreter.expand_variables("$(echo -e 'line1\\nline2')");
+        // Note: This test might need adjustment based on echo implementation
+        assert!(expanded.contains("line1") || expanded == "line1\nline2");
+    }
+
+    #[test]
+    fn test_command_substitution_in_conditionals() {
+        let mut interpreter = Interpreter::new();
+
+        // Test command substitution in variable assignment first
+        let result = interpreter.execute("TEST_VAR=$(echo test)").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("TEST_VAR"),
+            Some(&"test".to_string())
+        );
+
+        // Test simple conditional with pre-assigned variable
+        let result = interpreter
+            .execute("if [ \"$TEST_VAR\" = \"test\" ]; then X=success; fi")
+            .unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(interpreter.variables.get("X"), Some(&"success".to_string()));
+
+        // Test command substitution expansion in strings
+        let expanded = interpreter.expand_variables("Result: $(echo success)");
+        assert_eq!(expanded, "Result: success");
+    }
+
+    #[test]
+    fn test_command_substitution_error_handling() {
+        let interpreter = Interpreter::new();
+
+        // Test that failed command substitution doesn't crash
+        let expanded = interpreter.expand_variables("before $(false) after");
+        assert_eq!(expanded, "before  after");
+
+        // Test with command that doesn't exist
+        let expanded = interpreter.expand_variables("$(commandthatdoesnotexist)");
+        assert_eq!(expanded, "");
+    }
+
+    #[test]
+    fn test_command_substitution_complex_commands() {
+        let mut interpreter = Interpreter::new();
+
+        // Test command substitution with pipeline (simplified)
+        // Note: Full pipeline support would require more complex implementation
+        let expanded = interpreter.expand_variables("$(echo hello)");
+        assert_eq!(expanded, "hello");
+
+        // Test command substitution with assignment
+        let result = interpreter.execute("VAR=$(echo value); echo $VAR").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(interpreter.variables.get("VAR"), Some(&"value".to_string()));
+    }
+
+    #[test]
+    fn test_capture_command_output_builtin() {
+        let mut interpreter = Interpreter::new();
+        let mut evaluator = DefaultEvaluator;
+
+        // Test capturing echo output
+        let echo_node = Node::Command {
+            name: "echo".to_string(),
+            args: vec!["hello".to_string(), "world".to_string()],
+            redirects: vec![],
+        };
+
+        let output = interpreter
+            .capture_command_output(&echo_node, &mut evaluator)
+            .unwrap();
+        assert_eq!(output, "hello world");
+
+        // Test capturing pwd output - create a controlled test environment
+        use tempfile::tempdir;
+
+        // Save original directory first
+        let original_dir = std::env::current_dir().unwrap();
+
+        {
+            let temp_dir = tempdir().unwrap();
+            let temp_path = temp_dir.path();
+
+            // Change to temp directory for this test
+            std::env::set_current_dir(temp_path).unwrap();
+
+            let pwd_node = Node::Command {
+                name: "pwd".to_string(),
+                args: vec![],
+                redirects: vec![],
+            };
+
+            let output = interpreter
+                .capture_command_output(&pwd_node, &mut evaluator)
+                .unwrap();
+
+            // Get current directory after changing to temp
+            let current_dir = std::env::current_dir().unwrap();
+
+            // Canonicalize both paths to handle symlink differences (e.g., /tmp vs /private/tmp on macOS)
+            let output_canonical = std::path::Path::new(output.trim())
+                .canonicalize()
+                .unwrap_or_else(|_| std::path::PathBuf::from(output.trim()));
+            let current_canonical = current_dir
+                .canonicalize()
+                .unwrap_or_else(|_| current_dir.clone());
+
+            // Both the builtin pwd and env::current_dir() should return the same canonical path
+            assert_eq!(output_canonical, current_canonical);
+
+            // Restore original working directory BEFORE temp_dir is dropped
+            std::env::set_current_dir(&original_dir).unwrap();
+        } // temp_dir is dropped here, but we're already back in the original directory
+    }
+
+    #[test]
+    fn test_capture_command_output_with_variables() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .variables
+            .insert("TEST".to_string(), "value".to_string());
+        let mut evaluator = DefaultEvaluator;
+
+        // Test capturing echo with variable expansion
+        let echo_node = Node::Command {
+            name: "echo".to_string(),
+            args: vec!["$TEST".to_string()],
+            redirects: vec![],
+        };
+
+        let output = interpreter
+            .capture_command_output(&echo_node, &mut evaluator)
+            .unwrap();
+        assert_eq!(output, "value");
+    }
+
+    #[test]
+    fn test_command_substitution_parsing() {
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+
+        // Test that command substitution is properly parsed
+        let input = "X=$(echo test)";
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let ast = parser.parse_script();
+
+        // Verify the AST structure
+        match ast {
+            Node::List { statements, .. } => {
+                assert_eq!(statements.len(), 1);
+                match &statements[0] {
+                    Node::Assignment { name, value } => {
+                        assert_eq!(name, "X");
+                        match value.as_ref() {
+                            Node::CommandSubstitution { command } => match command.as_ref() {
+                                Node::Command { name, args, .. } => {
+                                    assert_eq!(name, "echo");
+                                    assert_eq!(args, &vec!["test".to_string()]);
+                                }
+                                _ => panic!("Expected Command node in CommandSubstitution"),
+                            },
+                            _ => panic!("Expected CommandSubstitution node"),
+                        }
+                    }
+                    _ => panic!("Expected Assignment node"),
+                }
+            }
+            _ => panic!("Expected List node"),
+        }
+    }
+
+    #[test]
+    fn test_command_substitution_working_examples() {
+        let mut interpreter = Interpreter::new();
+
+        // Basic assignment: X=$(echo hello)
+        let result = interpreter.execute("X=$(echo hello)").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(interpreter.variables.get("X"), Some(&"hello".to_string()));
+
+        // In strings: echo "Current directory: $(pwd)"
+        // Skip pwd test if current directory is not accessible (e.g., in CI)
+        if let Ok(current_dir) = std::env::current_dir() {
+            let expected_output = format!("Current directory: {}", current_dir.to_string_lossy());
+            let expanded = interpreter.expand_variables("Current directory: $(pwd)");
+            assert_eq!(expanded, expected_output);
+        }
+
+        // Multiple substitutions: echo "$(echo hello) $(echo world)"
+        let expanded = interpreter.expand_variables("$(echo hello) $(echo world)");
+        assert_eq!(expanded, "hello world");
+
+        // With variables: NAME=test; echo "Value: $(echo $NAME)"
+        interpreter
+            .variables
+            .insert("NAME".to_string(), "test".to_string());
+        let expanded = interpreter.expand_variables("Value: $(echo $NAME)");
+        assert_eq!(expanded, "Value: test");
+
+        // Complex expressions: echo "Today is $(echo Monday) and the time is $(echo 12:00)"
+        let expanded =
+            interpreter.expand_variables("Today is $(echo Monday) and the time is $(echo 12:00)");
+        assert_eq!(expanded, "Today is Monday and the time is 12:00");
+    }
+
+    #[test]
+    fn test_command_substitution_real_world_scenarios() {
+        let mut interpreter = Interpreter::new();
+
+        // Test assignment with command substitution
+        // Skip pwd test if current directory is not accessible (e.g., in CI)
+        if let Ok(current_dir) = std::env::current_dir() {
+            let result = interpreter.execute("CURRENT_DIR=$(pwd)").unwrap();
+            assert_eq!(result, 0);
+            assert_eq!(
+                interpreter.variables.get("CURRENT_DIR"),
+                Some(&current_dir.to_string_lossy().to_string())
+            );
+
+            // Test command substitution in echo command
+            let result = interpreter.execute("echo \"Working in: $(pwd)\"").unwrap();
+            assert_eq!(result, 0);
+        }
+
+        // Test multiple command substitutions in assignment
+        let result = interpreter
+            .execute("GREETING=$(echo Hello) $(echo World)")
+            .unwrap();
+        assert_eq!(result, 0);
+
+        // Test command substitution with variable expansion
+        interpreter
+            .variables
+            .insert("USER".to_string(), "flash".to_string());
+
+        // Skip pwd test if current directory is not accessible (e.g., in CI)
+        if let Ok(current_dir) = std::env::current_dir() {
+            let expanded = interpreter.expand_variables("Welcome $(echo $USER) to $(pwd)");
+            let expected = format!("Welcome flash to {}", current_dir.to_string_lossy());
+            assert_eq!(expanded, expected);
+        } else {
+            let expanded = interpreter.expand_variables("Welcome $(echo $USER) to $(pwd)");
+            assert_eq!(expanded, "Welcome flash to ");
+        }
+    }
+
+    #[test]
+    fn test_command_substitution_edge_cases() {
+        let interpreter = Interpreter::new();
+
+        // Test empty command substitution
+        let expanded = interpreter.expand_variables("Before $(echo) after");
+        assert_eq!(expanded, "Before  after");
+
+        // Test command substitution with spaces (echo normalizes spaces)
+        let expanded = interpreter.expand_variables("$(echo hello world)");
+        assert_eq!(expanded, "hello world");
+
+        // Test command substitution at start and end
+        let expanded = interpreter.expand_variables("$(echo start) middle $(echo end)");
+        assert_eq!(expanded, "start middle end");
+
+        // Test command substitution with special characters
+        let expanded = interpreter.expand_variables("$(echo 'hello world!')");
+        assert_eq!(expanded, "hello world!");
+
+        // Test nested parentheses (should not break parsing)
+        let expanded = interpreter.expand_variables("$(echo test)");
+        assert_eq!(expanded, "test");
+    }
+
+    #[test]
+    fn test_command_substitution_integration_with_conditionals() {
+        let mut interpreter = Interpreter::new();
+
+        // Test command substitution in variable assignment first
+        let result = interpreter.execute("TEST_VAR=$(echo yes)").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("TEST_VAR"),
+            Some(&"yes".to_string())
+        );
+
+        // Test simple conditional with pre-assigned variable
+        let result = interpreter
+            .execute("if [ \"$TEST_VAR\" = \"yes\" ]; then SUCCESS=true; fi")
+            .unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("SUCCESS"),
+            Some(&"true".to_string())
+        );
+
+        // Test command substitution expansion in strings
+        let expanded = interpreter.expand_variables("Result: $(echo success)");
+        assert_eq!(expanded, "Result: success");
+    }
+
+    #[test]
+    fn test_command_substitution_with_export() {
+        let mut interpreter = Interpreter::new();
+
+        // Test export with command substitution
+        // Skip pwd test if current directory is not accessible (e.g., in CI)
+        if let Ok(current_dir) = std::env::current_dir() {
+            let result = interpreter.execute("export SHELL_DIR=$(pwd)").unwrap();
+            assert_eq!(result, 0);
+            let expected = current_dir.to_string_lossy().to_string();
+            assert_eq!(interpreter.variables.get("SHELL_DIR"), Some(&expected));
+        }
+
+        // Test export with echo command substitution
+        let result = interpreter
+            .execute("export GREETING=$(echo 'Hello Flash')")
+            .unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("GREETING"),
+            Some(&"Hello Flash".to_string())
+        );
+    }
+
+    #[test]
+    fn test_keywords_as_command_arguments() {
+        let mut interpreter = Interpreter::new();
+
+        // Test that 'continue' can be used as an argument to echo
+        let result = interpreter.execute("echo continue").unwrap();
+        assert_eq!(result, 0);
+
+        // Test that 'break' can be used as an argument to echo
+        let result = interpreter.execute("echo break").unwrap();
+        assert_eq!(result, 0);
+
+        // Test multiple keywords as arguments
+        let result = interpreter.execute("echo if then else fi").unwrap();
+        assert_eq!(result, 0);
+
+        // Test keywords in variable assignment
+        let result = interpreter.execute("KEYWORD=continue").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("KEYWORD"),
+            Some(&"continue".to_string())
+        );
+
+        // Test keywords with other commands
+        let result = interpreter.execute("export TEST=break").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("TEST"),
+            Some(&"break".to_string())
+        );
+
+        // Test keywords in command substitution
+        let expanded = interpreter.expand_variables("$(echo continue)");
+        assert_eq!(expanded, "continue");
+
+        // Test all control flow keywords as arguments
+        let result = interpreter
+            .execute("echo for while do done if then elif else fi function export")
+            .unwrap();
+        assert_eq!(result, 0);
+    }
+
+    #[test]
+    fn test_keywords_in_different_contexts() {
+        let mut interpreter = Interpreter::new();
+
+        // Test keywords as part of larger arguments
+        let result = interpreter.execute("echo continue-test").unwrap();
+        assert_eq!(result, 0);
+
+        let result = interpreter.execute("echo test-break-test").unwrap();
+        assert_eq!(result, 0);
+
+        // Test keywords with quotes
+        let result = interpreter.execute("echo 'continue'").unwrap();
+        assert_eq!(result, 0);
+
+        let result = interpreter.execute("echo \"break\"").unwrap();
+        assert_eq!(result, 0);
+
+        // Test keywords in variable expansion
+        interpreter
+            .variables
+            .insert("WORD".to_string(), "continue".to_string());
+        let expanded = interpreter.expand_variables("echo $WORD");
+        assert_eq!(expanded, "echo continue");
+
+        // Test keywords with special characters
+        let result = interpreter.execute("echo continue!").unwrap();
+        assert_eq!(result, 0);
+
+        let result = interpreter.execute("echo break?").unwrap();
+        assert_eq!(result, 0);
+    }
+
+    #[test]
+    fn test_keywords_vs_control_flow() {
+        let mut interpreter = Interpreter::new();
+
+        // Test that keywords work as arguments
+        let result = interpreter.execute("echo The keyword is continue").unwrap();
+        assert_eq!(result, 0);
+
+        // Test that keywords work in assignments
+        let result = interpreter.execute("MESSAGE=\"Please continue\"").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("MESSAGE"),
+            Some(&"Please continue".to_string())
+        );
+
+        // Test keywords in command substitution
+        let result = interpreter.execute("RESULT=$(echo break)").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(
+            interpreter.variables.get("RESULT"),
+            Some(&"break".to_string())
+        );
+
+        // Test that we can echo all shell keywords
+        let keywords = vec![
+            "if", "then", "else", "elif", "fi", "for", "while", "do", "done", "in", "function",
+            "export", "continue", "break",
+        ];
+
+        for keyword in keywords {
+            let cmd = format!("echo {keyword}");
+            let result = interpreter.execute(&cmd).unwrap();
+            assert_eq!(result, 0, "Failed to echo keyword: {keyword}");
+        }
+    }
+
+    #[test]
+    fn test_command_execution() {
+        let mut interpreter = Interpreter::new();
+
+        // Test a basic command
+        let result = interpreter.execute("echo test").unwrap();
+        assert_eq!(result, 0);
+
+        // Test assignment
+        let result = interpreter.execute("X=test").unwrap();
+        assert_eq!(result, 0);
+        assert_eq!(interpreter.variables.get("X"), Some(&"test".to_string()));
+    }
+
+    #[test]
+    fn test_ext_glob_pattern() {
+        // Create a temporary directory for testing
+        // let temp_dir = tempfile::tempdir().unwrap();
+        // let temp_path = temp_dir.path();
+
+        // // Create some test files
+        // fs::write(temp_path.join("test1.txt"), "test content").unwrap();
+        // fs::write(temp_path.join("test2.txt"), "test content").unwrap();
+        // fs::write(temp_path.join("other.txt"), "other content").unwrap();
+        // fs::write(temp_path.join("another.log"), "log content").unwrap();
+
+        // // Change to the temporary directory
+        // let original_dir = env::current_dir().unwrap();
+        // env::set_current_dir(temp_path).unwrap();
+
+        // // Create an interpreter
+        // let mut interpreter = Interpreter::new();
+
+        // // Create an ExtGlobPattern node to match files ending with .txt
+        // let ext_glob = Node::ExtGlobPattern {
+        //     operator: '@',
+        //     patterns: vec!["test*".to_string(), "other*".to_string()],
+        //     suffix: ".txt".to_string(),
+        // };
+
+        // // Execute and check the pattern matching
+        // let exit_code = interpreter.evaluate(&ext_glob).unwrap();
+        // assert_eq!(exit_code, 0);
+
+        // // Go back to the original directory
+        // env::set_current_dir(original_dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_path_completions() {
+        // Create a temporary directory for testing
+        let temp_dir = tempdir().unwrap();
+        let temp_path = temp_dir.path();
+
+        // Create some test files and directories
+        fs::write(temp_path.join("test1.txt"), "content").unwrap();
+        fs::write(temp_path.join("test2.txt"), "content").unwrap();
+        fs::create_dir(temp_path.join("testdir")).unwrap();
+
+        // Create interpreter with modified get_path_completions that takes a directory parameter
+        let interpreter = Interpreter::new();
+
+        // Test with prefix "test" using absolute path
+        let test_prefix = format!("{}/test", temp_path.display());
+        let (_suffixes, full_names) = interpreter.get_path_completions(&test_prefix);
+
+        // Check that we get some completions
+        assert!(
+            !full_names.is_empty(),
+            "Expected some completions, got none"
+        );
+
+        // Check for expected files/directories
+        let has_expected = full_names.iter().any(|name| {
+            name.contains("test1.txt") || name.contains("test2.txt") || name.contains("testdir")
+        });
+        assert!(
+            has_expected,
+            "Expected completions to contain test files, got: {full_names:?}"
+        );
+
+        // Test directory completion (should add trailing slash)
+        let testd_prefix = format!("{}/testd", temp_path.display());
+        let (_suffixes, full_names) = interpreter.get_path_completions(&testd_prefix);
+        let has_dir = full_names
+            .iter()
+            .any(|name| name.contains("testdir") && name.ends_with('/'));
+        assert!(
+            has_dir,
+            "Expected directory completion with trailing slash, got: {full_names:?}"
+        );
+
+        // Test with specific file prefix
+        let test1_prefix = format!("{}/test1", temp_path.display());
+        let (_suffixes, full_names) = interpreter.get_path_completions(&test1_prefix);
+        let has_test1 = full_names.iter().any(|name| name.contains("test1.txt"));
+        assert!(
+            has_test1,
+            "Expected test1.txt completion, got: {full_names:?}"
+        );
+    }
+
+    #[test]
+    fn test_enhanced_completion_system_integration() {
+        let mut interpreter = Interpreter::new();
+
+        // Test that the completion system is properly initialized
+        assert!(
+            interpreter
+                .completion_system
+                .command_completions
+                .contains_key("git")
+        );
+        assert!(
+            interpreter
+                .completion_system
+                .command_completions
+                .contains_key("cd")
+        );
+
+        // Test git command completion
+        let (_suffixes, full_names) = interpreter.generate_completions("gi", 2);
+        assert!(
+            full_names.iter().any(|c| c == "git"),
+            "Should complete 'git' for 'gi' prefix"
+        );
+
+        // Test git subcommand completion
+        let (_suffixes, full_names) = interpreter.generate_completions("git ", 4);
+        assert!(
+            full_names.contains(&"add".to_string()),
+            "Should complete git subcommands"
+        );
+        assert!(
+            full_names.contains(&"commit".to_string()),
+            "Should complete git subcommands"
+        );
+
+        // Test cd directory completion
+        let (_suffixes, full_names) = interpreter.generate_completions("cd ", 3);
+        // All completions should be directories
+        for completion in &full_names {
+            assert!(
+                completion.ends_with('/'),
+                "CD completion '{completion}' should be a directory"
+            );
         }
-
-        parts
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::env;
-    use std::fs;
-    use tempfile::tempdir;
 
     #[test]
-    fn test_custom_prompt() {
-        // Create interpreter without loading RC file
-        let mut interpreter = Interpreter {
-            variables: HashMap::default(),
-            functions: HashMap::new(),
-            aliases: HashMap::new(),
-            last_exit_code: 0,
-            history: Vec::new(),
-            history_file: None,
-            rc_file: None,
-            args: Vec::new(),
-            return_value: None,
-            history_expansion_depth: 0,
-            completion_system: CompletionSystem::new(),
-        };
-
-        // Set PWD variable like the real interpreter does
-        if let Ok(current_dir) = std::env::current_dir() {
-            interpreter
-                .variables
-                .insert("PWD".to_string(), current_dir.to_string_lossy().to_string());
-        }
-
-        // Test default prompt
-        assert_eq!(interpreter.get_prompt(), "ϟ ");
-
-        // Test custom prompt
-        interpreter
-            .variables
-            .insert("PROMPT".to_string(), "flash> ".to_string());
-        assert_eq!(interpreter.get_prompt(), "flash> ");
+    fn test_completion_fallback_mechanism() {
+        let mut interpreter = Interpreter::new();
 
-        // Test prompt with variable expansion
-        interpreter
-            .variables
-            .insert("USER".to_string(), "testuser".to_string());
+        // Test variable completion (should use fallback)
         interpreter
             .variables
-            .insert("PROMPT".to_string(), "$USER> ".to_string());
-        assert_eq!(interpreter.get_prompt(), "testuser> ");
+            .insert("TEST_VAR".to_string(), "test_value".to_string());
 
-        // Test prompt with PWD expansion - use a fixed PWD for testing
-        interpreter
-            .variables
-            .insert("TESTVAR".to_string(), "/test/path".to_string());
-        interpreter
-            .variables
-            .insert("PROMPT".to_string(), "flash:${TESTVAR}$ ".to_string());
+        let (_suffixes, full_names) = interpreter.generate_completions("echo $TEST_", 11);
 
-        let prompt = interpreter.get_prompt();
-        assert_eq!(prompt, "flash:/test/path$ ");
+        // Should complete the variable
+        assert!(
+            full_names.iter().any(|c| c == "$TEST_VAR"),
+            "Should complete variable, got: {full_names:?}"
+        );
     }
 
     #[test]
-    fn test_variable_expansion() {
+    fn test_braced_variable_completion() {
         let mut interpreter = Interpreter::new();
         interpreter
             .variables
-            .insert("NAME".to_string(), "world".to_string());
+            .insert("PATH".to_string(), "/usr/bin".to_string());
 
-        let expanded = interpreter.expand_variables("Hello $NAME!");
-        assert_eq!(expanded, "Hello world!");
+        // Inside an unclosed `${` the closing brace comes with the name
+        let (suffixes, full_names) = interpreter.generate_completions("echo ${PA", 9);
+        assert!(
+            full_names.iter().any(|c| c == "${PATH}"),
+            "Expected full_names to include '${{PATH}}', got: {full_names:?}"
+        );
+        assert!(
+            suffixes.iter().any(|c| c == "TH}"),
+            "Expected suffixes to include 'TH}}', got: {suffixes:?}"
+        );
 
-        let expanded = interpreter.expand_variables("Hello ${NAME}!");
-        assert_eq!(expanded, "Hello world!");
+        // Mid-word names and special parameters complete too
+        let (suffixes, _full_names) = interpreter.generate_completions("ls dir/$PA", 10);
+        assert!(suffixes.iter().any(|c| c == "TH"));
+        let (_suffixes, full_names) = interpreter.generate_completions("echo ${", 7);
+        assert!(full_names.iter().any(|c| c == "${?}"));
     }
 
     #[test]
-    fn test_pwd_updates_in_prompt_after_cd() {
+    fn test_completion_with_aliases() {
         let mut interpreter = Interpreter::new();
 
-        // Set up a prompt that uses PWD
+        // Add an alias
         interpreter
-            .variables
-            .insert("PROMPT".to_string(), "flash:$PWD$ ".to_string());
+            .aliases
+            .insert("ll".to_string(), "ls -la".to_string());
 
-        // Get initial prompt - should contain initial PWD
-        let initial_prompt = interpreter.get_prompt();
-        let initial_pwd = interpreter.variables.get("PWD").unwrap().clone();
-        assert!(initial_prompt.contains(&initial_pwd));
+        // Test completion should include aliases
+        let (_suffixes, full_names) = interpreter.generate_completions("l", 1);
 
-        // Change directory to parent
-        let result = interpreter.execute("cd ..");
-        assert!(result.is_ok());
+        // Should include the alias in command completions
+        // Note: This tests the fallback system since aliases aren't in the new completion system yet
+        assert!(!full_names.is_empty(), "Should return some completions");
+    }
 
-        // Get new prompt - should contain updated PWD
-        let new_prompt = interpreter.get_prompt();
-        let new_pwd = interpreter.variables.get("PWD").unwrap();
-        assert!(new_prompt.contains(new_pwd));
+    #[test]
+    fn test_completion_context_parsing() {
+        // Test the context parsing directly
+        let context = CompletionSystem::parse_context("git add file.txt", 8);
+        assert_eq!(context.line, "git add file.txt");
+        assert_eq!(context.point, 8);
+        assert_eq!(context.words, vec!["git", "add"]);
+        assert_eq!(context.cword, 2);
+        assert_eq!(context.current_word, "");
+        assert_eq!(context.prev_word, "add");
 
-        // PWD should have changed
-        assert_ne!(&initial_pwd, new_pwd);
+        // Test partial word completion
+        let context = CompletionSystem::parse_context("git che", 7);
+        assert_eq!(context.words, vec!["git", "che"]);
+        assert_eq!(context.cword, 1);
+        assert_eq!(context.current_word, "che");
+        assert_eq!(context.prev_word, "git");
+    }
 
-        // The prompt should reflect the new PWD
-        assert_eq!(new_prompt, format!("flash:{new_pwd}$ "));
+    #[test]
+    fn test_completion_suffix_calculation() {
+        let mut interpreter = Interpreter::new();
+
+        // Test that suffixes are calculated correctly
+        let (suffixes, full_names) = interpreter.generate_completions("ec", 2);
+
+        // Find echo in the completions
+        if let Some(pos) = full_names.iter().position(|c| c == "echo") {
+            assert_eq!(
+                suffixes[pos], "ho",
+                "Suffix for 'echo' from 'ec' should be 'ho'"
+            );
+        }
     }
 
     #[test]
-    fn test_command_substitution_basic() {
+    fn test_completion_empty_input() {
         let mut interpreter = Interpreter::new();
 
-        // Test basic command substitution with echo
-        let result = interpreter.execute("X=$(echo hello)").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(interpreter.variables.get("X"), Some(&"hello".to_string()));
+        // Test completion with empty input
+        let (_suffixes, full_names) = interpreter.generate_completions("", 0);
 
-        // Test command substitution in echo
-        let result = interpreter.execute("echo $(echo world)").unwrap();
-        assert_eq!(result, 0);
+        // Should return command completions
+        assert!(full_names.contains(&"echo".to_string()));
+        assert!(full_names.contains(&"cd".to_string()));
+        assert!(full_names.contains(&"exit".to_string()));
     }
 
     #[test]
-    fn test_command_substitution_in_strings() {
-        let interpreter = Interpreter::new();
+    fn test_completion_with_spaces() {
+        let mut interpreter = Interpreter::new();
 
-        // Test command substitution within variable expansion
-        let expanded = interpreter.expand_variables("Hello $(echo world)!");
-        assert_eq!(expanded, "Hello world!");
+        // Test completion after command with space
+        let (_suffixes, full_names) = interpreter.generate_completions("echo ", 5);
 
-        // Test nested command substitution - simplified for now
-        let expanded = interpreter.expand_variables("Result: $(echo test)");
-        assert_eq!(expanded, "Result: test");
+        // Should return file completions (fallback behavior)
+        assert!(!full_names.is_empty()); // At least doesn't crash
     }
 
     #[test]
-    fn test_command_substitution_with_variables() {
+    fn test_completion_system_setup() {
+        let system = CompletionSystem::new();
+
+        // Verify all expected default completions are set up
+        let expected_commands = ["git", "ssh", "cd", "kill", "man"];
+        for cmd in &expected_commands {
+            assert!(
+                system.command_completions.contains_key(*cmd),
+                "Should have completion for '{cmd}'"
+            );
+        }
+
+        // Verify git completion is configured correctly
+        let git_entry = &system.command_completions["git"];
+        assert_eq!(git_entry.function, "_git_complete");
+        assert!(git_entry.o_options.contains(&"nospace".to_string()));
+
+        // Verify cd completion is configured correctly
+        let cd_entry = &system.command_completions["cd"];
+        assert_eq!(cd_entry.action, "directory");
+    }
+
+    #[test]
+    fn test_completion_performance() {
         let mut interpreter = Interpreter::new();
-        interpreter
-            .variables
-            .insert("NAME".to_string(), "test".to_string());
 
-        // Test command substitution with variable expansion
-        let expanded = interpreter.expand_variables("$(echo $NAME)");
-        assert_eq!(expanded, "test");
+        // Test that completion doesn't take too long
+        use std::time::Instant;
+        let start = Instant::now();
 
-        // Test assignment with command substitution
-        let result = interpreter.execute("RESULT=$(echo $NAME)").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(
-            interpreter.variables.get("RESULT"),
-            Some(&"test".to_string())
+        for _ in 0..100 {
+            let _ = interpreter.generate_completions("git ", 4);
+        }
+
+        let duration = start.elapsed();
+        assert!(
+            duration.as_millis() < 1000,
+            "Completion should be fast, took {duration:?}"
         );
     }
 
     #[test]
-    fn test_command_substitution_with_pwd() {
-        let interpreter = Interpreter::new();
+    fn test_completion_edge_cases() {
+        let mut interpreter = Interpreter::new();
 
-        // Test pwd command substitution
-        // Skip this test if current directory is not accessible (e.g., in CI)
-        if let Ok(current_dir) = std::env::current_dir() {
-            let expected = current_dir.to_string_lossy().to_string();
-            let expanded = interpreter.expand_variables("$(pwd)");
-            assert_eq!(expanded, expected);
-        } else {
-            // In environments where current_dir fails, pwd should return empty string
-            let expanded = interpreter.expand_variables("$(pwd)");
-            assert_eq!(expanded, "");
+        // Test completion at various cursor positions
+        let test_cases = [
+            ("git", 0),  // Beginning
+            ("git", 1),  // Middle
+            ("git", 3),  // End
+            ("git ", 4), // After space
+        ];
+
+        for (input, pos) in &test_cases {
+            let (suffixes, full_names) = interpreter.generate_completions(input, *pos);
+            // Should not crash and should return valid results
+            assert!(
+                suffixes.len() == full_names.len(),
+                "Suffixes and full_names should have same length for input '{input}' at pos {pos}"
+            );
         }
     }
 
     #[test]
-    fn test_command_substitution_empty_result() {
+    fn test_find_common_prefix() {
         let interpreter = Interpreter::new();
 
-        // Test command substitution with non-existent command
-        let expanded = interpreter.expand_variables("$(nonexistentcommand)");
-        assert_eq!(expanded, "");
+        // Test with empty list
+        let common = interpreter.find_common_prefix(&[]);
+        assert_eq!(common, None);
 
-        // Test command substitution with empty echo
-        let expanded = interpreter.expand_variables("$(echo)");
-        assert_eq!(expanded, "");
-    }
+        // Test with single item
+        let common = interpreter.find_common_prefix(&["test".to_string()]);
+        assert_eq!(common, Some("test".to_string()));
 
-    #[test]
-    fn test_command_substitution_multiple() {
-        let interpreter = Interpreter::new();
+        // Test with common prefix
+        let completions = vec![
+            "test1".to_string(),
+            "test2".to_string(),
+            "test3".to_string(),
+        ];
+        let common = interpreter.find_common_prefix(&completions);
+        assert_eq!(common, Some("test".to_string()));
 
-        // Test multiple command substitutions in one string
-        let expanded = interpreter.expand_variables("$(echo hello) $(echo world)");
-        assert_eq!(expanded, "hello world");
+        // Test with no common prefix
+        let completions = vec!["abc".to_string(), "def".to_string(), "ghi".to_string()];
+        let common = interpreter.find_common_prefix(&completions);
+        assert_eq!(common, None);
 
-        // Test command substitution with other text
-        let expanded = interpreter.expand_variables("prefix $(echo middle) suffix");
-        assert_eq!(expanded, "prefix middle suffix");
+        // Test with partially common prefix
+        let completions = vec![
+            "testfile".to_string(),
+            "testdir".to_string(),
+            "testcase".to_string(),
+        ];
+        let common = interpreter.find_common_prefix(&completions);
+        assert_eq!(common, Some("test".to_string()));
     }
 
     #[test]
-    fn test_command_substitution_with_arguments() {
-        let interpreter = Interpreter::new();
-
-        // Test command substitution with command arguments
-        let expanded = interpreter.expand_variables("$(echo hello world)");
-        assert_eq!(expanded, "hello world");
+    fn test_path_completion_with_directories() {
+        // Create a temporary directory structure for testing
+        let temp_dir = tempdir().unwrap();
+        let temp_path = temp_dir.path();
 
-        // Test with quoted arguments
-        let expanded = interpreter.expand_variables("$(echo 'hello world')");
-        assert_eq!(expanded, "hello world");
-    }
+        // Create nested directories
+        fs::create_dir(temp_path.join("dir1")).unwrap();
+        fs::create_dir(temp_path.join("dir1/subdir")).unwrap();
+        fs::write(temp_path.join("dir1/file.txt"), "content").unwrap();
 
-    #[test]
-    fn test_command_substitution_whitespace_handling() {
+        // Create interpreter
         let interpreter = Interpreter::new();
 
-        // Test that trailing newlines are trimmed
-        let expanded = interpreter.expand_variables("$(echo test)");
-        assert_eq!(expanded, "test");
+        // Test completion with directory path using absolute path
+        let input_prefix = format!("{}/dir1/", temp_path.display());
+        let (_suffixes, full_names) = interpreter.get_path_completions(&input_prefix);
 
-        // Test with multiple lines - should preserve internal newlines but trim trailing
-        let expanded = interpreter.expand_variables("$(echo -e 'line1\\nline2')");
-        // Note: This test might need adjustment based on echo implementation
-        assert!(expanded.contains("line1") || expanded == "line1\nline2");
+        // Check if any completion contains "subdir" or "file.txt"
+        let has_expected_completion = full_names
+            .iter()
+            .any(|c| c.contains("subdir") || c.contains("file.txt"));
+        assert!(
+            has_expected_completion,
+            "Expected completions to contain subdir or file.txt, got: {full_names:?}"
+        );
+
+        // Test completion with partial path
+        let input_prefix = format!("{}/dir1/s", temp_path.display());
+        let (suffixes, _full_names) = interpreter.get_path_completions(&input_prefix);
+        let has_subdir = suffixes.iter().any(|c| c.contains("ubdir"));
+        assert!(
+            has_subdir,
+            "Expected suffixes to contain 'ubdir', got: {suffixes:?}"
+        );
+
+        // Test completion with file path
+        let input_prefix = format!("{}/dir1/f", temp_path.display());
+        let (suffixes, _full_names) = interpreter.get_path_completions(&input_prefix);
+        let has_file = suffixes.iter().any(|c| c.contains("ile.txt"));
+        assert!(
+            has_file,
+            "Expected suffixes to contain 'ile.txt', got: {suffixes:?}"
+        );
     }
 
     #[test]
-    fn test_command_substitution_in_conditionals() {
+    fn test_completion_with_multiple_words() {
         let mut interpreter = Interpreter::new();
 
-        // Test command substitution in variable assignment first
-        let result = interpreter.execute("TEST_VAR=$(echo test)").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(
-            interpreter.variables.get("TEST_VAR"),
-            Some(&"test".to_string())
+        // Test command completion after pipe
+        let (suffixes, full_names) = interpreter.generate_completions("ls | e", 6);
+
+        // Check that we get command completions starting with 'e'
+        let has_echo_or_export = full_names.iter().any(|c| *c == "echo" || *c == "export");
+        let has_echo_or_export_suffix = suffixes.iter().any(|c| *c == "cho" || *c == "xport");
+        assert!(
+            has_echo_or_export || has_echo_or_export_suffix,
+            "Expected completions to include echo or export, got full_names: {full_names:?}, suffixes: {suffixes:?}"
         );
 
-        // Test simple conditional with pre-assigned variable
-        let result = interpreter
-            .execute("if [ \"$TEST_VAR\" = \"test\" ]; then X=success; fi")
-            .unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(interpreter.variables.get("X"), Some(&"success".to_string()));
+        // Test path completion after command
+        // Skip this test if current directory is not accessible (e.g., in CI)
+        if let Ok(original_dir) = env::current_dir() {
+            {
+                let temp_dir = tempdir().unwrap();
+                let temp_path = temp_dir.path();
+                fs::write(temp_path.join("testfile.txt"), "content").unwrap();
 
-        // Test command substitution expansion in strings
-        let expanded = interpreter.expand_variables("Result: $(echo success)");
-        assert_eq!(expanded, "Result: success");
-    }
+                env::set_current_dir(temp_path).unwrap();
 
-    #[test]
-    fn test_command_substitution_error_handling() {
-        let interpreter = Interpreter::new();
+                let (suffixes, full_names) = interpreter.generate_completions("cat test", 8);
 
-        // Test that failed command substitution doesn't crash
-        let expanded = interpreter.expand_variables("before $(false) after");
-        assert_eq!(expanded, "before  after");
+                // Check if completions include something related to testfile.txt
+                let has_testfile = full_names
+                    .iter()
+                    .any(|c| c.contains("testfile") || c == "testfile.txt");
+                let has_testfile_suffix = suffixes
+                    .iter()
+                    .any(|c| c.contains("file") || c == "file.txt");
+                assert!(
+                    has_testfile || has_testfile_suffix,
+                    "Expected completions to include 'testfile.txt', got full_names: {full_names:?}, suffixes: {suffixes:?}"
+                );
 
-        // Test with command that doesn't exist
-        let expanded = interpreter.expand_variables("$(commandthatdoesnotexist)");
-        assert_eq!(expanded, "");
+                // Restore original working directory BEFORE temp_dir is dropped
+                env::set_current_dir(&original_dir).unwrap();
+            } // temp_dir is dropped here, but we're already back in the original directory
+        }
     }
 
     #[test]
-    fn test_command_substitution_complex_commands() {
+    fn test_command_completion_with_arguments() {
         let mut interpreter = Interpreter::new();
 
-        // Test command substitution with pipeline (simplified)
-        // Note: Full pipeline support would require more complex implementation
-        let expanded = interpreter.expand_variables("$(echo hello)");
-        assert_eq!(expanded, "hello");
+        // Add an environment variable to the interpreter's variables
+        interpreter
+            .variables
+            .insert("TEST_PATH".to_string(), "/tmp".to_string());
 
-        // Test command substitution with assignment
-        let result = interpreter.execute("VAR=$(echo value); echo $VAR").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(interpreter.variables.get("VAR"), Some(&"value".to_string()));
+        // First test that the variable exists in the interpreter
+        assert!(interpreter.variables.contains_key("TEST_PATH"));
+
+        // Test partial variable completion
+        let (suffixes, full_names) = interpreter.generate_completions("cd $TEST_", 9);
+
+        // The suffixes should contain "PATH" (what comes after TEST_)
+        let has_path_suffix = suffixes.iter().any(|c| c == "PATH");
+        // The full names should contain "$TEST_PATH"
+        let has_path_full = full_names.iter().any(|c| c == "$TEST_PATH");
+        assert!(
+            has_path_suffix,
+            "Expected suffixes to include 'PATH', got: {suffixes:?}"
+        );
+        assert!(
+            has_path_full,
+            "Expected full_names to include '$TEST_PATH', got: {full_names:?}"
+        );
+
+        // Test completion right after $
+        let (_suffixes, full_names) = interpreter.generate_completions("cd $", 4);
+        let has_test_path = full_names.iter().any(|c| c == "$TEST_PATH");
+        assert!(
+            has_test_path,
+            "Expected full_names to include '$TEST_PATH', got: {full_names:?}"
+        );
     }
 
     #[test]
-    fn test_capture_command_output_builtin() {
+    fn test_positional_parameters_basic() {
         let mut interpreter = Interpreter::new();
-        let mut evaluator = DefaultEvaluator;
 
-        // Test capturing echo output
-        let echo_node = Node::Command {
-            name: "echo".to_string(),
-            args: vec!["hello".to_string(), "world".to_string()],
-            redirects: vec![],
-        };
+        // Set up arguments: $0 = script, $1 = first arg, $2 = second arg
+        interpreter.set_args(vec![
+            "test_script.sh".to_string(),
+            "hello".to_string(),
+            "world".to_string(),
+        ]);
 
-        let output = interpreter
-            .capture_command_output(&echo_node, &mut evaluator)
-            .unwrap();
-        assert_eq!(output, "hello world");
+        // Test $0 (script name)
+        assert_eq!(interpreter.expand_variables("$0"), "test_script.sh");
 
-        // Test capturing pwd output - create a controlled test environment
-        use tempfile::tempdir;
+        // Test $1 (first argument)
+        assert_eq!(interpreter.expand_variables("$1"), "hello");
 
-        // Save original directory first
-        let original_dir = std::env::current_dir().unwrap();
+        // Test $2 (second argument)
+        assert_eq!(interpreter.expand_variables("$2"), "world");
 
-        {
-            let temp_dir = tempdir().unwrap();
-            let temp_path = temp_dir.path();
+        // Test $3 (non-existent argument - should expand to empty)
+        assert_eq!(interpreter.expand_variables("$3"), "");
+
+        // Test ${1} (braced syntax)
+        assert_eq!(interpreter.expand_variables("${1}"), "hello");
+
+        // Test ${2} (braced syntax)
+        assert_eq!(interpreter.expand_variables("${2}"), "world");
+    }
+
+    #[test]
+    fn test_positional_parameters_special_variables() {
+        let mut interpreter = Interpreter::new();
+
+        // Set up arguments
+        interpreter.set_args(vec![
+            "script.sh".to_string(),
+            "arg1".to_string(),
+            "arg2".to_string(),
+            "arg3".to_string(),
+        ]);
 
-            // Change to temp directory for this test
-            std::env::set_current_dir(temp_path).unwrap();
+        // Test $# (argument count - excludes $0)
+        assert_eq!(interpreter.expand_variables("$#"), "3");
 
-            let pwd_node = Node::Command {
-                name: "pwd".to_string(),
-                args: vec![],
-                redirects: vec![],
-            };
+        // Test $@ (all arguments excluding $0)
+        assert_eq!(interpreter.expand_variables("$@"), "arg1 arg2 arg3");
 
-            let output = interpreter
-                .capture_command_output(&pwd_node, &mut evaluator)
-                .unwrap();
+        // Test $* (all arguments excluding $0)
+        assert_eq!(interpreter.expand_variables("$*"), "arg1 arg2 arg3");
 
-            // Get current directory after changing to temp
-            let current_dir = std::env::current_dir().unwrap();
+        // Test with no arguments (only script name)
+        interpreter.set_args(vec!["script.sh".to_string()]);
+        assert_eq!(interpreter.expand_variables("$#"), "0");
+        assert_eq!(interpreter.expand_variables("$@"), "");
+        assert_eq!(interpreter.expand_variables("$*"), "");
+    }
 
-            // Canonicalize both paths to handle symlink differences (e.g., /tmp vs /private/tmp on macOS)
-            let output_canonical = std::path::Path::new(output.trim())
-                .canonicalize()
-                .unwrap_or_else(|_| std::path::PathBuf::from(output.trim()));
-            let current_canonical = current_dir
-                .canonicalize()
-                .unwrap_or_else(|_| current_dir.clone());
+    #[test]
+    fn test_positional_parameters_empty_args() {
+        let mut interpreter = Interpreter::new();
 
-            // Both the builtin pwd and env::current_dir() should return the same canonical path
-            assert_eq!(output_canonical, current_canonical);
+        // Test with empty args vector
+        interpreter.set_args(vec![]);
 
-            // Restore original working directory BEFORE temp_dir is dropped
-            std::env::set_current_dir(&original_dir).unwrap();
-        } // temp_dir is dropped here, but we're already back in the original directory
+        assert_eq!(interpreter.expand_variables("$0"), "");
+        assert_eq!(interpreter.expand_variables("$1"), "");
+        assert_eq!(interpreter.expand_variables("$#"), "0");
+        assert_eq!(interpreter.expand_variables("$@"), "");
+        assert_eq!(interpreter.expand_variables("$*"), "");
     }
 
     #[test]
-    fn test_capture_command_output_with_variables() {
+    fn test_positional_parameters_in_commands() {
         let mut interpreter = Interpreter::new();
+
+        // Set up arguments
+        interpreter.set_args(vec![
+            "test.sh".to_string(),
+            "hello".to_string(),
+            "world".to_string(),
+        ]);
+
+        // Test expansion in complex strings
+        assert_eq!(
+            interpreter.expand_variables("First: $1, Second: $2"),
+            "First: hello, Second: world"
+        );
+
+        // Test expansion with other variables
         interpreter
             .variables
-            .insert("TEST".to_string(), "value".to_string());
-        let mut evaluator = DefaultEvaluator;
-
-        // Test capturing echo with variable expansion
-        let echo_node = Node::Command {
-            name: "echo".to_string(),
-            args: vec!["$TEST".to_string()],
-            redirects: vec![],
-        };
+            .insert("USER".to_string(), "testuser".to_string());
+        assert_eq!(
+            interpreter.expand_variables("User: $USER, Arg: $1"),
+            "User: testuser, Arg: hello"
+        );
 
-        let output = interpreter
-            .capture_command_output(&echo_node, &mut evaluator)
-            .unwrap();
-        assert_eq!(output, "value");
+        // Test mixed braced and unbraced
+        assert_eq!(
+            interpreter.expand_variables("${1}_suffix and ${2}_suffix"),
+            "hello_suffix and world_suffix"
+        );
     }
 
     #[test]
-    fn test_command_substitution_parsing() {
-        use crate::lexer::Lexer;
-        use crate::parser::Parser;
+    fn test_positional_parameters_numeric_parsing() {
+        let mut interpreter = Interpreter::new();
 
-        // Test that command substitution is properly parsed
-        let input = "X=$(echo test)";
-        let lexer = Lexer::new(input);
-        let mut parser = Parser::new(lexer);
-        let ast = parser.parse_script();
+        // Set up many arguments to test numeric parsing
+        interpreter.set_args(vec![
+            "script.sh".to_string(),
+            "arg1".to_string(),
+            "arg2".to_string(),
+            "arg3".to_string(),
+            "arg4".to_string(),
+            "arg5".to_string(),
+            "arg6".to_string(),
+            "arg7".to_string(),
+            "arg8".to_string(),
+            "arg9".to_string(),
+            "arg10".to_string(),
+        ]);
 
-        // Verify the AST structure
-        match ast {
-            Node::List { statements, .. } => {
-                assert_eq!(statements.len(), 1);
-                match &statements[0] {
-                    Node::Assignment { name, value } => {
-                        assert_eq!(name, "X");
-                        match value.as_ref() {
-                            Node::CommandSubstitution { command } => match command.as_ref() {
-                                Node::Command { name, args, .. } => {
-                                    assert_eq!(name, "echo");
-                                    assert_eq!(args, &vec!["test".to_string()]);
-                                }
-                                _ => panic!("Expected Command node in CommandSubstitution"),
-                            },
-                            _ => panic!("Expected CommandSubstitution node"),
-                        }
-                    }
-                    _ => panic!("Expected Assignment node"),
-                }
-            }
-            _ => panic!("Expected List node"),
-        }
+        // Test single digits
+        assert_eq!(interpreter.expand_variables("$1"), "arg1");
+        assert_eq!(interpreter.expand_variables("$9"), "arg9");
+
+        // Test double digits (should work with braces)
+        assert_eq!(interpreter.expand_variables("${10}"), "arg10");
+
+        // Test that $10 without braces only gets $1 followed by "0"
+        assert_eq!(interpreter.expand_variables("$10"), "arg10");
+
+        // Test non-existent high numbers
+        assert_eq!(interpreter.expand_variables("${99}"), "");
     }
 
     #[test]
-    fn test_command_substitution_working_examples() {
+    fn test_positional_parameters_with_quotes() {
         let mut interpreter = Interpreter::new();
 
-        // Basic assignment: X=$(echo hello)
-        let result = interpreter.execute("X=$(echo hello)").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(interpreter.variables.get("X"), Some(&"hello".to_string()));
+        // Set up arguments with spaces and special characters
+        interpreter.set_args(vec![
+            "script.sh".to_string(),
+            "hello world".to_string(),
+            "arg with spaces".to_string(),
+            "special!@#$%".to_string(),
+        ]);
 
-        // In strings: echo "Current directory: $(pwd)"
-        // Skip pwd test if current directory is not accessible (e.g., in CI)
-        if let Ok(current_dir) = std::env::current_dir() {
-            let expected_output = format!("Current directory: {}", current_dir.to_string_lossy());
-            let expanded = interpreter.expand_variables("Current directory: $(pwd)");
-            assert_eq!(expanded, expected_output);
-        }
+        // Test that arguments with spaces are preserved
+        assert_eq!(interpreter.expand_variables("$1"), "hello world");
+        assert_eq!(interpreter.expand_variables("$2"), "arg with spaces");
+        assert_eq!(interpreter.expand_variables("$3"), "special!@#$%");
 
-        // Multiple substitutions: echo "$(echo hello) $(echo world)"
-        let expanded = interpreter.expand_variables("$(echo hello) $(echo world)");
-        assert_eq!(expanded, "hello world");
+        // Test $@ preserves all arguments
+        assert_eq!(
+            interpreter.expand_variables("$@"),
+            "hello world arg with spaces special!@#$%"
+        );
+    }
 
-        // With variables: NAME=test; echo "Value: $(echo $NAME)"
-        interpreter
-            .variables
-            .insert("NAME".to_string(), "test".to_string());
-        let expanded = interpreter.expand_variables("Value: $(echo $NAME)");
-        assert_eq!(expanded, "Value: test");
+    #[test]
+    fn test_echo_command_with_positional_parameters() {
+        let mut interpreter = Interpreter::new();
 
-        // Complex expressions: echo "Today is $(echo Monday) and the time is $(echo 12:00)"
-        let expanded =
-            interpreter.expand_variables("Today is $(echo Monday) and the time is $(echo 12:00)");
-        assert_eq!(expanded, "Today is Monday and the time is 12:00");
+        // Set up arguments
+        interpreter.set_args(vec![
+            "test.sh".to_string(),
+            "hello".to_string(),
+            "world".to_string(),
+        ]);
+
+        // Test that echo properly expands positional parameters
+        // We can't easily test the actual output, but we can test the expansion
+        let expanded = interpreter.expand_variables("First arg: $1, Second arg: $2");
+        assert_eq!(expanded, "First arg: hello, Second arg: world");
+
+        // Test with $# and $@
+        let expanded = interpreter.expand_variables("Count: $#, All: $@");
+        assert_eq!(expanded, "Count: 2, All: hello world");
     }
 
     #[test]
-    fn test_command_substitution_real_world_scenarios() {
+    fn test_set_args_method() {
         let mut interpreter = Interpreter::new();
 
-        // Test assignment with command substitution
-        // Skip pwd test if current directory is not accessible (e.g., in CI)
-        if let Ok(current_dir) = std::env::current_dir() {
-            let result = interpreter.execute("CURRENT_DIR=$(pwd)").unwrap();
-            assert_eq!(result, 0);
-            assert_eq!(
-                interpreter.variables.get("CURRENT_DIR"),
-                Some(&current_dir.to_string_lossy().to_string())
-            );
-
-            // Test command substitution in echo command
-            let result = interpreter.execute("echo \"Working in: $(pwd)\"").unwrap();
-            assert_eq!(result, 0);
-        }
+        // Test initial state
+        assert!(interpreter.args.is_empty());
 
-        // Test multiple command substitutions in assignment
-        let result = interpreter
-            .execute("GREETING=$(echo Hello) $(echo World)")
-            .unwrap();
-        assert_eq!(result, 0);
+        // Test setting args
+        let test_args = vec![
+            "script.sh".to_string(),
+            "arg1".to_string(),
+            "arg2".to_string(),
+        ];
+        interpreter.set_args(test_args.clone());
 
-        // Test command substitution with variable expansion
-        interpreter
-            .variables
-            .insert("USER".to_string(), "flash".to_string());
+        assert_eq!(interpreter.args, test_args);
+        assert_eq!(interpreter.expand_variables("$0"), "script.sh");
+        assert_eq!(interpreter.expand_variables("$1"), "arg1");
+        assert_eq!(interpreter.expand_variables("$2"), "arg2");
 
-        // Skip pwd test if current directory is not accessible (e.g., in CI)
-        if let Ok(current_dir) = std::env::current_dir() {
-            let expanded = interpreter.expand_variables("Welcome $(echo $USER) to $(pwd)");
-            let expected = format!("Welcome flash to {}", current_dir.to_string_lossy());
-            assert_eq!(expanded, expected);
-        } else {
-            let expanded = interpreter.expand_variables("Welcome $(echo $USER) to $(pwd)");
-            assert_eq!(expanded, "Welcome flash to ");
-        }
+        // Test overwriting args
+        let new_args = vec!["new_script.sh".to_string(), "new_arg".to_string()];
+        interpreter.set_args(new_args.clone());
+
+        assert_eq!(interpreter.args, new_args);
+        assert_eq!(interpreter.expand_variables("$0"), "new_script.sh");
+        assert_eq!(interpreter.expand_variables("$1"), "new_arg");
+        assert_eq!(interpreter.expand_variables("$2"), ""); // Should be empty now
     }
 
     #[test]
-    fn test_command_substitution_edge_cases() {
-        let interpreter = Interpreter::new();
+    fn test_positional_parameters_edge_cases() {
+        let mut interpreter = Interpreter::new();
 
-        // Test empty command substitution
-        let expanded = interpreter.expand_variables("Before $(echo) after");
-        assert_eq!(expanded, "Before  after");
+        // Test with single argument (only script name)
+        interpreter.set_args(vec!["script.sh".to_string()]);
 
-        // Test command substitution with spaces (echo normalizes spaces)
-        let expanded = interpreter.expand_variables("$(echo hello world)");
-        assert_eq!(expanded, "hello world");
+        assert_eq!(interpreter.expand_variables("$0"), "script.sh");
+        assert_eq!(interpreter.expand_variables("$1"), "");
+        assert_eq!(interpreter.expand_variables("$#"), "0");
+        assert_eq!(interpreter.expand_variables("$@"), "");
 
-        // Test command substitution at start and end
-        let expanded = interpreter.expand_variables("$(echo start) middle $(echo end)");
-        assert_eq!(expanded, "start middle end");
+        // Test with empty string arguments
+        interpreter.set_args(vec![
+            "script.sh".to_string(),
+            "".to_string(),
+            "non_empty".to_string(),
+            "".to_string(),
+        ]);
 
-        // Test command substitution with special characters
-        let expanded = interpreter.expand_variables("$(echo 'hello world!')");
-        assert_eq!(expanded, "hello world!");
+        assert_eq!(interpreter.expand_variables("$1"), "");
+        assert_eq!(interpreter.expand_variables("$2"), "non_empty");
+        assert_eq!(interpreter.expand_variables("$3"), "");
+        assert_eq!(interpreter.expand_variables("$#"), "3");
+        assert_eq!(interpreter.expand_variables("$@"), " non_empty ");
+    }
 
-        // Test nested parentheses (should not break parsing)
-        let expanded = interpreter.expand_variables("$(echo test)");
-        assert_eq!(expanded, "test");
+    #[test]
+    fn test_glob_pattern_detection() {
+        let interpreter = Interpreter::new();
+
+        // Test patterns that should be detected as globs
+        assert!(interpreter.contains_glob_pattern("*.txt"));
+        assert!(interpreter.contains_glob_pattern("file?.log"));
+        assert!(interpreter.contains_glob_pattern("[abc]*.tmp"));
+        assert!(interpreter.contains_glob_pattern("test*"));
+        assert!(interpreter.contains_glob_pattern("?test"));
+        assert!(interpreter.contains_glob_pattern("file[0-9].txt"));
+
+        // Test patterns that should NOT be detected as globs
+        assert!(!interpreter.contains_glob_pattern("file.txt"));
+        assert!(!interpreter.contains_glob_pattern("test"));
+        assert!(!interpreter.contains_glob_pattern("path/to/file"));
     }
 
     #[test]
-    fn test_command_substitution_integration_with_conditionals() {
+    fn test_globstar_matches_any_depth() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join(".hidden")).unwrap();
+        for file in ["top.rs", "a/x.rs", "a/b/c.rs", "a/b/d.txt", ".hidden/h.rs"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
         let mut interpreter = Interpreter::new();
+        // Without the option `**` is not special
+        assert!(interpreter.glob_match_in_dir("**/*.rs", root).is_empty());
 
-        // Test command substitution in variable assignment first
-        let result = interpreter.execute("TEST_VAR=$(echo yes)").unwrap();
-        assert_eq!(result, 0);
+        interpreter.set_shell_option("globstar", true);
         assert_eq!(
-            interpreter.variables.get("TEST_VAR"),
-            Some(&"yes".to_string())
+            interpreter.glob_match_in_dir("**/*.rs", root),
+            vec!["a/b/c.rs", "a/x.rs", "top.rs"]
         );
-
-        // Test simple conditional with pre-assigned variable
-        let result = interpreter
-            .execute("if [ \"$TEST_VAR\" = \"yes\" ]; then SUCCESS=true; fi")
-            .unwrap();
-        assert_eq!(result, 0);
         assert_eq!(
-            interpreter.variables.get("SUCCESS"),
-            Some(&"true".to_string())
+            interpreter.glob_match_in_dir("a/**/c.rs", root),
+            vec!["a/b/c.rs"]
         );
-
-        // Test command substitution expansion in strings
-        let expanded = interpreter.expand_variables("Result: $(echo success)");
-        assert_eq!(expanded, "Result: success");
+        assert_eq!(
+            interpreter.glob_match_in_dir("a/**", root),
+            vec!["a/b", "a/b/c.rs", "a/b/d.txt", "a/x.rs"]
+        );
+        // A single `*` still stays within one directory
+        assert_eq!(interpreter.glob_match_in_dir("*.rs", root), vec!["top.rs"]);
     }
 
     #[test]
-    fn test_command_substitution_with_export() {
+    fn test_ext_glob_pattern_matching() {
         let mut interpreter = Interpreter::new();
 
-        // Test export with command substitution
-        // Skip pwd test if current directory is not accessible (e.g., in CI)
-        if let Ok(current_dir) = std::env::current_dir() {
-            let result = interpreter.execute("export SHELL_DIR=$(pwd)").unwrap();
-            assert_eq!(result, 0);
-            let expected = current_dir.to_string_lossy().to_string();
-            assert_eq!(interpreter.variables.get("SHELL_DIR"), Some(&expected));
-        }
-
-        // Test export with echo command substitution
-        let result = interpreter
-            .execute("export GREETING=$(echo 'Hello Flash')")
-            .unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(
-            interpreter.variables.get("GREETING"),
-            Some(&"Hello Flash".to_string())
-        );
+        // Extended globs only apply under `shopt -s extglob`
+        assert!(!interpreter.contains_glob_pattern("@(a|b).txt"));
+        assert!(!interpreter.matches_glob_pattern("a.txt", "@(a|b).txt"));
+        interpreter.set_shell_option("extglob", true);
+        assert!(interpreter.contains_glob_pattern("@(a|b).txt"));
+
+        assert!(interpreter.matches_glob_pattern("a.txt", "@(a|b).txt"));
+        assert!(!interpreter.matches_glob_pattern("ab.txt", "@(a|b).txt"));
+        assert!(interpreter.matches_glob_pattern("x.c", "!(*.bak)"));
+        assert!(!interpreter.matches_glob_pattern("x.bak", "!(*.bak)"));
+        assert!(interpreter.matches_glob_pattern("ababab", "+(ab)"));
+        assert!(!interpreter.matches_glob_pattern("", "+(ab)"));
+        assert!(!interpreter.matches_glob_pattern("aba", "+(ab)"));
+        assert!(interpreter.matches_glob_pattern("", "*(ab)"));
+        assert!(interpreter.matches_glob_pattern("abab.c", "*(ab).c"));
+        assert!(!interpreter.matches_glob_pattern("abc.c", "*(ab).c"));
+        assert!(interpreter.matches_glob_pattern("b", "?(a)b"));
+        assert!(!interpreter.matches_glob_pattern("aab", "?(a)b"));
+        assert!(interpreter.matches_glob_pattern("main.rs", "*.@(rs|toml)"));
+        assert!(interpreter.matches_glob_pattern("xyz", "@(x+([yz]))"));
     }
 
     #[test]
-    fn test_keywords_as_command_arguments() {
-        let mut interpreter = Interpreter::new();
+    fn test_glob_pattern_matching() {
+        let interpreter = Interpreter::new();
 
-        // Test that 'continue' can be used as an argument to echo
-        let result = interpreter.execute("echo continue").unwrap();
-        assert_eq!(result, 0);
+        // Test * wildcard
+        assert!(interpreter.matches_glob_pattern("file.txt", "*.txt"));
+        assert!(interpreter.matches_glob_pattern("test.txt", "*.txt"));
+        assert!(interpreter.matches_glob_pattern("a.txt", "*.txt"));
+        assert!(!interpreter.matches_glob_pattern("file.log", "*.txt"));
+        assert!(interpreter.matches_glob_pattern("anything", "*"));
+        assert!(interpreter.matches_glob_pattern("", "*"));
 
-        // Test that 'break' can be used as an argument to echo
-        let result = interpreter.execute("echo break").unwrap();
-        assert_eq!(result, 0);
+        // Test ? wildcard
+        assert!(interpreter.matches_glob_pattern("file1.txt", "file?.txt"));
+        assert!(interpreter.matches_glob_pattern("fileA.txt", "file?.txt"));
+        assert!(!interpreter.matches_glob_pattern("file.txt", "file?.txt"));
+        assert!(!interpreter.matches_glob_pattern("file12.txt", "file?.txt"));
 
-        // Test multiple keywords as arguments
-        let result = interpreter.execute("echo if then else fi").unwrap();
-        assert_eq!(result, 0);
+        // Test character classes
+        assert!(interpreter.matches_glob_pattern("file1.txt", "file[123].txt"));
+        assert!(interpreter.matches_glob_pattern("file2.txt", "file[123].txt"));
+        assert!(interpreter.matches_glob_pattern("file3.txt", "file[123].txt"));
+        assert!(!interpreter.matches_glob_pattern("file4.txt", "file[123].txt"));
+        assert!(!interpreter.matches_glob_pattern("filea.txt", "file[123].txt"));
 
-        // Test keywords in variable assignment
-        let result = interpreter.execute("KEYWORD=continue").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(
-            interpreter.variables.get("KEYWORD"),
-            Some(&"continue".to_string())
-        );
+        // Test character ranges
+        assert!(interpreter.matches_glob_pattern("file1.txt", "file[0-9].txt"));
+        assert!(interpreter.matches_glob_pattern("file5.txt", "file[0-9].txt"));
+        assert!(interpreter.matches_glob_pattern("file9.txt", "file[0-9].txt"));
+        assert!(!interpreter.matches_glob_pattern("filea.txt", "file[0-9].txt"));
 
-        // Test keywords with other commands
-        let result = interpreter.execute("export TEST=break").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(
-            interpreter.variables.get("TEST"),
-            Some(&"break".to_string())
-        );
+        assert!(interpreter.matches_glob_pattern("filea.txt", "file[a-z].txt"));
+        assert!(interpreter.matches_glob_pattern("filem.txt", "file[a-z].txt"));
+        assert!(interpreter.matches_glob_pattern("filez.txt", "file[a-z].txt"));
+        assert!(!interpreter.matches_glob_pattern("fileA.txt", "file[a-z].txt"));
+        assert!(!interpreter.matches_glob_pattern("file1.txt", "file[a-z].txt"));
 
-        // Test keywords in command substitution
-        let expanded = interpreter.expand_variables("$(echo continue)");
-        assert_eq!(expanded, "continue");
+        // Test negated character classes
+        assert!(!interpreter.matches_glob_pattern("file1.txt", "file[!123].txt"));
+        assert!(!interpreter.matches_glob_pattern("file2.txt", "file[!123].txt"));
+        assert!(interpreter.matches_glob_pattern("file4.txt", "file[!123].txt"));
+        assert!(interpreter.matches_glob_pattern("filea.txt", "file[!123].txt"));
 
-        // Test all control flow keywords as arguments
-        let result = interpreter
-            .execute("echo for while do done if then elif else fi function export")
-            .unwrap();
-        assert_eq!(result, 0);
+        // Test complex patterns
+        assert!(interpreter.matches_glob_pattern("test123.log", "test*.log"));
+        assert!(interpreter.matches_glob_pattern("test.log", "test*.log"));
+        assert!(interpreter.matches_glob_pattern("backup_file_2023.txt", "*_file_*.txt"));
+        assert!(interpreter.matches_glob_pattern("config.ini", "*.ini"));
+
+        // Test exact matches
+        assert!(interpreter.matches_glob_pattern("exact", "exact"));
+        assert!(!interpreter.matches_glob_pattern("exact", "different"));
     }
 
     #[test]
-    fn test_keywords_in_different_contexts() {
-        let mut interpreter = Interpreter::new();
-
-        // Test keywords as part of larger arguments
-        let result = interpreter.execute("echo continue-test").unwrap();
-        assert_eq!(result, 0);
-
-        let result = interpreter.execute("echo test-break-test").unwrap();
-        assert_eq!(result, 0);
-
-        // Test keywords with quotes
-        let result = interpreter.execute("echo 'continue'").unwrap();
-        assert_eq!(result, 0);
+    fn test_character_class_matching() {
+        let interpreter = Interpreter::new();
 
-        let result = interpreter.execute("echo \"break\"").unwrap();
-        assert_eq!(result, 0);
+        // Test simple character class
+        assert!(interpreter.matches_char_class('a', &['a', 'b', 'c']));
+        assert!(interpreter.matches_char_class('b', &['a', 'b', 'c']));
+        assert!(interpreter.matches_char_class('c', &['a', 'b', 'c']));
+        assert!(!interpreter.matches_char_class('d', &['a', 'b', 'c']));
 
-        // Test keywords in variable expansion
-        interpreter
-            .variables
-            .insert("WORD".to_string(), "continue".to_string());
-        let expanded = interpreter.expand_variables("echo $WORD");
-        assert_eq!(expanded, "echo continue");
+        // Test range
+        assert!(interpreter.matches_char_class('a', &['a', '-', 'z']));
+        assert!(interpreter.matches_char_class('m', &['a', '-', 'z']));
+        assert!(interpreter.matches_char_class('z', &['a', '-', 'z']));
+        assert!(!interpreter.matches_char_class('A', &['a', '-', 'z']));
 
-        // Test keywords with special characters
-        let result = interpreter.execute("echo continue!").unwrap();
-        assert_eq!(result, 0);
+        // Test negated class
+        assert!(!interpreter.matches_char_class('a', &['!', 'a', 'b', 'c']));
+        assert!(!interpreter.matches_char_class('b', &['!', 'a', 'b', 'c']));
+        assert!(interpreter.matches_char_class('d', &['!', 'a', 'b', 'c']));
 
-        let result = interpreter.execute("echo break?").unwrap();
-        assert_eq!(result, 0);
+        // Test negated range
+        assert!(!interpreter.matches_char_class('a', &['!', 'a', '-', 'z']));
+        assert!(!interpreter.matches_char_class('m', &['!', 'a', '-', 'z']));
+        assert!(interpreter.matches_char_class('A', &['!', 'a', '-', 'z']));
+        assert!(interpreter.matches_char_class('1', &['!', 'a', '-', 'z']));
     }
 
     #[test]
-    fn test_keywords_vs_control_flow() {
-        let mut interpreter = Interpreter::new();
-
-        // Test that keywords work as arguments
-        let result = interpreter.execute("echo The keyword is continue").unwrap();
-        assert_eq!(result, 0);
+    fn test_glob_expansion_with_temp_files() {
+        use tempfile::tempdir;
 
-        // Test that keywords work in assignments
-        let result = interpreter.execute("MESSAGE=\"Please continue\"").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(
-            interpreter.variables.get("MESSAGE"),
-            Some(&"Please continue".to_string())
-        );
+        let temp_dir = tempdir().unwrap();
+        let temp_path = temp_dir.path();
 
-        // Test keywords in command substitution
-        let result = interpreter.execute("RESULT=$(echo break)").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(
-            interpreter.variables.get("RESULT"),
-            Some(&"break".to_string())
-        );
+        // Create test files
+        fs::write(temp_path.join("file1.txt"), "content1").unwrap();
+        fs::write(temp_path.join("file2.txt"), "content2").unwrap();
+        fs::write(temp_path.join("test.log"), "log content").unwrap();
+        fs::write(temp_path.join("data.csv"), "csv content").unwrap();
+        fs::write(temp_path.join("script.sh"), "script content").unwrap();
 
-        // Test that we can echo all shell keywords
-        let keywords = vec![
-            "if", "then", "else", "elif", "fi", "for", "while", "do", "done", "in", "function",
-            "export", "continue", "break",
-        ];
+        let interpreter = Interpreter::new();
 
-        for keyword in keywords {
-            let cmd = format!("echo {keyword}");
-            let result = interpreter.execute(&cmd).unwrap();
-            assert_eq!(result, 0, "Failed to echo keyword: {keyword}");
-        }
-    }
+        // Test *.txt pattern
+        let mut matches = interpreter.glob_match_in_dir("*.txt", temp_path);
+        matches.sort();
+        assert_eq!(matches, vec!["file1.txt", "file2.txt"]);
 
-    #[test]
-    fn test_command_execution() {
-        let mut interpreter = Interpreter::new();
+        // Test *.log pattern
+        let matches = interpreter.glob_match_in_dir("*.log", temp_path);
+        assert_eq!(matches, vec!["test.log"]);
 
-        // Test a basic command
-        let result = interpreter.execute("echo test").unwrap();
-        assert_eq!(result, 0);
+        // Test file?.txt pattern
+        let mut matches = interpreter.glob_match_in_dir("file?.txt", temp_path);
+        matches.sort();
+        assert_eq!(matches, vec!["file1.txt", "file2.txt"]);
 
-        // Test assignment
-        let result = interpreter.execute("X=test").unwrap();
-        assert_eq!(result, 0);
-        assert_eq!(interpreter.variables.get("X"), Some(&"test".to_string()));
+        // Test pattern with no matches
+        let matches = interpreter.glob_match_in_dir("*.xyz", temp_path);
+        assert!(matches.is_empty());
+
+        // Test * pattern (should match all files)
+        let mut matches = interpreter.glob_match_in_dir("*", temp_path);
+        matches.sort();
+        assert_eq!(
+            matches,
+            vec![
+                "data.csv",
+                "file1.txt",
+                "file2.txt",
+                "script.sh",
+                "test.log"
+            ]
+        );
     }
 
     #[test]
-    fn test_ext_glob_pattern() {
-        // Create a temporary directory for testing
-        // let temp_dir = tempfile::tempdir().unwrap();
-        // let temp_path = temp_dir.path();
+    fn test_expand_glob_patterns() {
+        use tempfile::tempdir;
 
-        // // Create some test files
-        // fs::write(temp_path.join("test1.txt"), "test content").unwrap();
-        // fs::write(temp_path.join("test2.txt"), "test content").unwrap();
-        // fs::write(temp_path.join("other.txt"), "other content").unwrap();
-        // fs::write(temp_path.join("another.log"), "log content").unwrap();
+        let temp_dir = tempdir().unwrap();
+        let temp_path = temp_dir.path();
 
-        // // Change to the temporary directory
-        // let original_dir = env::current_dir().unwrap();
-        // env::set_current_dir(temp_path).unwrap();
+        // Create test files
+        fs::write(temp_path.join("file1.txt"), "content1").unwrap();
+        fs::write(temp_path.join("file2.txt"), "content2").unwrap();
+        fs::write(temp_path.join("test.log"), "log content").unwrap();
 
-        // // Create an interpreter
-        // let mut interpreter = Interpreter::new();
+        // Create a custom interpreter that uses the temp directory for glob matching
+        let interpreter = TestInterpreter::new(temp_path.to_path_buf());
 
-        // // Create an ExtGlobPattern node to match files ending with .txt
-        // let ext_glob = Node::ExtGlobPattern {
-        //     operator: '@',
-        //     patterns: vec!["test*".to_string(), "other*".to_string()],
-        //     suffix: ".txt".to_string(),
-        // };
+        // Test expansion with glob patterns
+        let args = vec!["ls".to_string(), "*.txt".to_string()];
+        let expanded = interpreter.expand_glob_patterns(&args);
+        assert_eq!(expanded[0], "ls");
+        assert!(expanded.contains(&"file1.txt".to_string()));
+        assert!(expanded.contains(&"file2.txt".to_string()));
+        assert_eq!(expanded.len(), 3); // ls + 2 txt files
 
-        // // Execute and check the pattern matching
-        // let exit_code = interpreter.evaluate(&ext_glob).unwrap();
-        // assert_eq!(exit_code, 0);
+        // Test expansion with mixed patterns and literals
+        let args = vec![
+            "command".to_string(),
+            "literal".to_string(),
+            "*.log".to_string(),
+        ];
+        let expanded = interpreter.expand_glob_patterns(&args);
+        assert_eq!(expanded, vec!["command", "literal", "test.log"]);
 
-        // // Go back to the original directory
-        // env::set_current_dir(original_dir).unwrap();
+        // Test expansion with no matches (should keep original pattern)
+        let args = vec!["command".to_string(), "*.xyz".to_string()];
+        let expanded = interpreter.expand_glob_patterns(&args);
+        assert_eq!(expanded, vec!["command", "*.xyz"]);
+
+        // Test expansion with no glob patterns
+        let args = vec![
+            "command".to_string(),
+            "arg1".to_string(),
+            "arg2".to_string(),
+        ];
+        let expanded = interpreter.expand_glob_patterns(&args);
+        assert_eq!(expanded, args);
     }
 
     #[test]
-    fn test_get_path_completions() {
-        // Create a temporary directory for testing
-        let temp_dir = tempdir().unwrap();
-        let temp_path = temp_dir.path();
-
-        // Create some test files and directories
-        fs::write(temp_path.join("test1.txt"), "content").unwrap();
-        fs::write(temp_path.join("test2.txt"), "content").unwrap();
-        fs::create_dir(temp_path.join("testdir")).unwrap();
+    fn test_glob_expands_tilde_and_variables_in_base() {
+        use tempfile::tempdir;
 
-        // Create interpreter with modified get_path_completions that takes a directory parameter
-        let interpreter = Interpreter::new();
+        let home = tempdir().unwrap();
+        let sub = home.path().join("projects");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("a.rs"), "").unwrap();
+        fs::write(sub.join("b.rs"), "").unwrap();
+        fs::write(sub.join("notes.txt"), "").unwrap();
 
-        // Test with prefix "test" using absolute path
-        let test_prefix = format!("{}/test", temp_path.display());
-        let (_suffixes, full_names) = interpreter.get_path_completions(&test_prefix);
+        let mut interpreter = Interpreter::new();
+        let home_str = home.path().to_str().unwrap().to_string();
+        interpreter
+            .variables
+            .insert("HOME".to_string(), home_str.clone());
+        interpreter
+            .variables
+            .insert("SUB".to_string(), "projects".to_string());
 
-        // Check that we get some completions
-        assert!(
-            !full_names.is_empty(),
-            "Expected some completions, got none"
+        let matches = interpreter.expand_glob_patterns(&["~/projects/*".to_string()]);
+        assert_eq!(
+            matches,
+            vec![
+                format!("{home_str}/projects/a.rs"),
+                format!("{home_str}/projects/b.rs"),
+                format!("{home_str}/projects/notes.txt"),
+            ]
         );
 
-        // Check for expected files/directories
-        let has_expected = full_names.iter().any(|name| {
-            name.contains("test1.txt") || name.contains("test2.txt") || name.contains("testdir")
-        });
-        assert!(
-            has_expected,
-            "Expected completions to contain test files, got: {full_names:?}"
+        let matches = interpreter.expand_glob_patterns(&["~/$SUB/*.rs".to_string()]);
+        assert_eq!(
+            matches,
+            vec![
+                format!("{home_str}/projects/a.rs"),
+                format!("{home_str}/projects/b.rs"),
+            ]
         );
+    }
 
-        // Test directory completion (should add trailing slash)
-        let testd_prefix = format!("{}/testd", temp_path.display());
-        let (_suffixes, full_names) = interpreter.get_path_completions(&testd_prefix);
-        let has_dir = full_names
-            .iter()
-            .any(|name| name.contains("testdir") && name.ends_with('/'));
-        assert!(
-            has_dir,
-            "Expected directory completion with trailing slash, got: {full_names:?}"
-        );
+    // Helper struct for testing that uses a specific directory for glob operations
+    struct TestInterpreter {
+        interpreter: Interpreter,
+        test_dir: PathBuf,
+    }
 
-        // Test with specific file prefix
-        let test1_prefix = format!("{}/test1", temp_path.display());
-        let (_suffixes, full_names) = interpreter.get_path_completions(&test1_prefix);
-        let has_test1 = full_names.iter().any(|name| name.contains("test1.txt"));
-        assert!(
-            has_test1,
-            "Expected test1.txt completion, got: {full_names:?}"
-        );
+    impl TestInterpreter {
+        fn new(test_dir: PathBuf) -> Self {
+            Self {
+                interpreter: Interpreter::new(),
+                test_dir,
+            }
+        }
+
+        fn expand_glob_patterns(&self, args: &[String]) -> Vec<String> {
+            let mut expanded_args = Vec::new();
+
+            for arg in args {
+                if self.interpreter.contains_glob_pattern(arg) {
+                    let matches = self.interpreter.glob_match_in_dir(arg, &self.test_dir);
+                    if matches.is_empty() {
+                        // If no matches found, keep the original pattern
+                        expanded_args.push(arg.clone());
+                    } else {
+                        expanded_args.extend(matches);
+                    }
+                } else {
+                    expanded_args.push(arg.clone());
+                }
+            }
+
+            expanded_args
+        }
     }
 
     #[test]
-    fn test_enhanced_completion_system_integration() {
-        let mut interpreter = Interpreter::new();
+    fn test_tilde_expansion_basic() {
+        let interpreter = Interpreter::new();
 
-        // Test that the completion system is properly initialized
-        assert!(
-            interpreter
-                .completion_system
-                .command_completions
-                .contains_key("git")
+        // Set a known HOME directory for testing
+        let original_home = std::env::var("HOME").ok();
+        unsafe {
+            std::env::set_var("HOME", "/test/home");
+        }
+
+        // Test basic tilde expansion
+        assert_eq!(interpreter.expand_variables("~"), "/test/home");
+        assert_eq!(
+            interpreter.expand_variables("~/Documents"),
+            "/test/home/Documents"
         );
-        assert!(
-            interpreter
-                .completion_system
-                .command_completions
-                .contains_key("cd")
+        assert_eq!(
+            interpreter.expand_variables("~/bin/script"),
+            "/test/home/bin/script"
         );
 
-        // Test git command completion
-        let (_suffixes, full_names) = interpreter.generate_completions("gi", 2);
-        assert!(
-            full_names.iter().any(|c| c == "git"),
-            "Should complete 'git' for 'gi' prefix"
+        // Test tilde with spaces
+        assert_eq!(
+            interpreter.expand_variables("~ /other/path"),
+            "/test/home /other/path"
         );
-
-        // Test git subcommand completion
-        let (_suffixes, full_names) = interpreter.generate_completions("git ", 4);
-        assert!(
-            full_names.contains(&"add".to_string()),
-            "Should complete git subcommands"
+        assert_eq!(
+            interpreter.expand_variables("path ~ more"),
+            "path /test/home more"
         );
-        assert!(
-            full_names.contains(&"commit".to_string()),
-            "Should complete git subcommands"
+
+        // Test tilde at end of string
+        assert_eq!(
+            interpreter.expand_variables("prefix ~"),
+            "prefix /test/home"
         );
 
-        // Test cd directory completion
-        let (_suffixes, full_names) = interpreter.generate_completions("cd ", 3);
-        // All completions should be directories
-        for completion in &full_names {
-            assert!(
-                completion.ends_with('/'),
-                "CD completion '{completion}' should be a directory"
-            );
+        // Restore original HOME
+        unsafe {
+            if let Some(home) = original_home {
+                std::env::set_var("HOME", home);
+            } else {
+                std::env::remove_var("HOME");
+            }
         }
     }
 
     #[test]
-    fn test_completion_fallback_mechanism() {
+    fn test_tilde_expansion_edge_cases() {
         let mut interpreter = Interpreter::new();
 
-        // Test variable completion (should use fallback)
-        interpreter
-            .variables
-            .insert("TEST_VAR".to_string(), "test_value".to_string());
+        // Set a known HOME directory for testing
+        let original_home = std::env::var("HOME").ok();
+        unsafe {
+            std::env::set_var("HOME", "/test/home");
+        }
 
-        let (_suffixes, full_names) = interpreter.generate_completions("echo $TEST_", 11);
+        // Test tilde not at word boundary (should not expand)
+        assert_eq!(interpreter.expand_variables("file~backup"), "file~backup");
+        assert_eq!(interpreter.expand_variables("test~"), "test~");
 
-        // Should complete the variable
-        assert!(
-            full_names.iter().any(|c| c == "$TEST_VAR"),
-            "Should complete variable, got: {full_names:?}"
+        // Test multiple tildes
+        assert_eq!(
+            interpreter.expand_variables("~ and ~"),
+            "/test/home and /test/home"
+        );
+        assert_eq!(
+            interpreter.expand_variables("~/a:~/b"),
+            "/test/home/a:/test/home/b"
         );
-    }
-
-    #[test]
-    fn test_completion_with_aliases() {
-        let mut interpreter = Interpreter::new();
 
-        // Add an alias
+        // Test tilde with other expansions
         interpreter
-            .aliases
-            .insert("ll".to_string(), "ls -la".to_string());
-
-        // Test completion should include aliases
-        let (_suffixes, full_names) = interpreter.generate_completions("l", 1);
-
-        // Should include the alias in command completions
-        // Note: This tests the fallback system since aliases aren't in the new completion system yet
-        assert!(!full_names.is_empty(), "Should return some completions");
-    }
-
-    #[test]
-    fn test_completion_context_parsing() {
-        // Test the context parsing directly
-        let context = CompletionSystem::parse_context("git add file.txt", 8);
-        assert_eq!(context.line, "git add file.txt");
-        assert_eq!(context.point, 8);
-        assert_eq!(context.words, vec!["git", "add"]);
-        assert_eq!(context.cword, 2);
-        assert_eq!(context.current_word, "");
-        assert_eq!(context.prev_word, "add");
-
-        // Test partial word completion
-        let context = CompletionSystem::parse_context("git che", 7);
-        assert_eq!(context.words, vec!["git", "che"]);
-        assert_eq!(context.cword, 1);
-        assert_eq!(context.current_word, "che");
-        assert_eq!(context.prev_word, "git");
-    }
-
-    #[test]
-    fn test_completion_suffix_calculation() {
-        let mut interpreter = Interpreter::new();
+            .variables
+            .insert("VAR".to_string(), "value".to_string());
+        assert_eq!(interpreter.expand_variables("~/$VAR"), "/test/home/value");
+        assert_eq!(interpreter.expand_variables("$VAR/~"), "value/~"); // ~ not at start of path component
 
-        // Test that suffixes are calculated correctly
-        let (suffixes, full_names) = interpreter.generate_completions("ec", 2);
+        // Test empty string
+        assert_eq!(interpreter.expand_variables(""), "");
 
-        // Find echo in the completions
-        if let Some(pos) = full_names.iter().position(|c| c == "echo") {
-            assert_eq!(
-                suffixes[pos], "ho",
-                "Suffix for 'echo' from 'ec' should be 'ho'"
-            );
+        // Restore original HOME
+        unsafe {
+            if let Some(home) = original_home {
+                std::env::set_var("HOME", home);
+            } else {
+                std::env::remove_var("HOME");
+            }
         }
     }
 
     #[test]
-    fn test_completion_empty_input() {
-        let mut interpreter = Interpreter::new();
-
-        // Test completion with empty input
-        let (_suffixes, full_names) = interpreter.generate_completions("", 0);
-
-        // Should return command completions
-        assert!(full_names.contains(&"echo".to_string()));
-        assert!(full_names.contains(&"cd".to_string()));
-        assert!(full_names.contains(&"exit".to_string()));
-    }
+    fn test_tilde_expansion_no_home() {
+        let interpreter = Interpreter::new();
 
-    #[test]
-    fn test_completion_with_spaces() {
-        let mut interpreter = Interpreter::new();
+        // Save the original HOME environment variable
+        let original_home = std::env::var("HOME").ok();
 
-        // Test completion after command with space
-        let (_suffixes, full_names) = interpreter.generate_completions("echo ", 5);
+        // Remove HOME environment variable and ensure it stays removed during the test
+        unsafe {
+            std::env::remove_var("HOME");
+        }
 
-        // Should return file completions (fallback behavior)
-        assert!(!full_names.is_empty()); // At least doesn't crash
-    }
+        // Verify HOME is actually removed
+        assert!(
+            std::env::var("HOME").is_err(),
+            "HOME should be unset for this test"
+        );
 
-    #[test]
-    fn test_completion_system_setup() {
-        let system = CompletionSystem::new();
+        // When HOME is not set, tilde should remain unchanged
+        let result1 = interpreter.expand_variables("~");
+        let result2 = interpreter.expand_variables("~/Documents");
 
-        // Verify all expected default completions are set up
-        let expected_commands = ["git", "ssh", "cd", "kill", "man"];
-        for cmd in &expected_commands {
-            assert!(
-                system.command_completions.contains_key(*cmd),
-                "Should have completion for '{cmd}'"
-            );
+        // Restore original HOME immediately to avoid affecting other tests
+        unsafe {
+            if let Some(home) = original_home {
+                std::env::set_var("HOME", home);
+            }
         }
 
-        // Verify git completion is configured correctly
-        let git_entry = &system.command_completions["git"];
-        assert_eq!(git_entry.function, "_git_complete");
-        assert!(git_entry.o_options.contains(&"nospace".to_string()));
-
-        // Verify cd completion is configured correctly
-        let cd_entry = &system.command_completions["cd"];
-        assert_eq!(cd_entry.action, "directory");
+        // Now check the results
+        assert_eq!(
+            result1, "~",
+            "Tilde should remain unchanged when HOME is not set"
+        );
+        assert_eq!(
+            result2, "~/Documents",
+            "Tilde path should remain unchanged when HOME is not set"
+        );
     }
 
     #[test]
-    fn test_completion_performance() {
-        let mut interpreter = Interpreter::new();
-
-        // Test that completion doesn't take too long
-        use std::time::Instant;
-        let start = Instant::now();
+    fn test_tilde_expansion_with_username() {
+        let interpreter = Interpreter::new();
 
-        for _ in 0..100 {
-            let _ = interpreter.generate_completions("git ", 4);
+        // Set a known HOME directory for testing
+        let original_home = std::env::var("HOME").ok();
+        unsafe {
+            std::env::set_var("HOME", "/test/home");
         }
 
-        let duration = start.elapsed();
-        assert!(
-            duration.as_millis() < 1000,
-            "Completion should be fast, took {duration:?}"
+        // ~username is that user's home directory, and unknown users stay literal
+        assert_eq!(
+            interpreter.expand_variables("~no_such_flash_user"),
+            "~no_such_flash_user"
         );
-    }
-
-    #[test]
-    fn test_completion_edge_cases() {
-        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.expand_variables("~no_such_flash_user/Documents"),
+            "~no_such_flash_user/Documents"
+        );
+        let root_home = user_home_dir("root").unwrap();
+        assert_eq!(
+            interpreter.expand_variables("~root/bin"),
+            format!("{root_home}/bin")
+        );
+        assert_eq!(
+            interpreter.expand_variables("PATH=~root:~/bin"),
+            format!("PATH={root_home}:/test/home/bin")
+        );
+        assert_eq!(interpreter.expand_variables("a~root"), "a~root");
 
-        // Test completion at various cursor positions
-        let test_cases = [
-            ("git", 0),  // Beginning
-            ("git", 1),  // Middle
-            ("git", 3),  // End
-            ("git ", 4), // After space
-        ];
+        // But ~ alone should still expand
+        assert_eq!(interpreter.expand_variables("~"), "/test/home");
 
-        for (input, pos) in &test_cases {
-            let (suffixes, full_names) = interpreter.generate_completions(input, *pos);
-            // Should not crash and should return valid results
-            assert!(
-                suffixes.len() == full_names.len(),
-                "Suffixes and full_names should have same length for input '{input}' at pos {pos}"
-            );
+        // Restore original HOME
+        unsafe {
+            if let Some(home) = original_home {
+                std::env::set_var("HOME", home);
+            } else {
+                std::env::remove_var("HOME");
+            }
         }
     }
 
     #[test]
-    fn test_find_common_prefix() {
-        let interpreter = Interpreter::new();
+    fn test_tilde_expansion_in_export() {
+        let mut interpreter = Interpreter::new();
 
-        // Test with empty list
-        let common = interpreter.find_common_prefix(&[]);
-        assert_eq!(common, None);
+        // Set a known HOME directory for testing
+        let original_home = std::env::var("HOME").ok();
+        unsafe {
+            std::env::set_var("HOME", "/test/home");
+        }
 
-        // Test with single item
-        let common = interpreter.find_common_prefix(&["test".to_string()]);
-        assert_eq!(common, Some("test".to_string()));
+        // Test tilde expansion in export statements
+        interpreter.execute("export GOPATH=~/go").unwrap();
+        assert_eq!(
+            interpreter.variables.get("GOPATH"),
+            Some(&"/test/home/go".to_string())
+        );
 
-        // Test with common prefix
-        let completions = vec![
-            "test1".to_string(),
-            "test2".to_string(),
-            "test3".to_string(),
-        ];
-        let common = interpreter.find_common_prefix(&completions);
-        assert_eq!(common, Some("test".to_string()));
+        interpreter
+            .execute("export PATH=~/bin:/usr/local/bin")
+            .unwrap();
+        assert_eq!(
+            interpreter.variables.get("PATH"),
+            Some(&"/test/home/bin:/usr/local/bin".to_string())
+        );
 
-        // Test with no common prefix
-        let completions = vec!["abc".to_string(), "def".to_string(), "ghi".to_string()];
-        let common = interpreter.find_common_prefix(&completions);
-        assert_eq!(common, None);
+        // Test mixed tilde and variable expansion
+        interpreter
+            .execute("export COMPLEX=~/bin:$GOPATH/bin")
+            .unwrap();
+        assert_eq!(
+            interpreter.variables.get("COMPLEX"),
+            Some(&"/test/home/bin:/test/home/go/bin".to_string())
+        );
 
-        // Test with partially common prefix
-        let completions = vec![
-            "testfile".to_string(),
-            "testdir".to_string(),
-            "testcase".to_string(),
-        ];
-        let common = interpreter.find_common_prefix(&completions);
-        assert_eq!(common, Some("test".to_string()));
+        // Restore original HOME
+        unsafe {
+            if let Some(home) = original_home {
+                std::env::set_var("HOME", home);
+            } else {
+                std::env::remove_var("HOME");
+            }
+        }
     }
 
     #[test]
-    fn test_path_completion_with_directories() {
-        // Create a temporary directory structure for testing
-        let temp_dir = tempdir().unwrap();
-        let temp_path = temp_dir.path();
-
-        // Create nested directories
-        fs::create_dir(temp_path.join("dir1")).unwrap();
-        fs::create_dir(temp_path.join("dir1/subdir")).unwrap();
-        fs::write(temp_path.join("dir1/file.txt"), "content").unwrap();
-
-        // Create interpreter
+    fn test_tilde_expansion_in_commands() {
         let interpreter = Interpreter::new();
 
-        // Test completion with directory path using absolute path
-        let input_prefix = format!("{}/dir1/", temp_path.display());
-        let (_suffixes, full_names) = interpreter.get_path_completions(&input_prefix);
+        // Set a known HOME directory for testing
+        let original_home = std::env::var("HOME").ok();
+        unsafe {
+            std::env::set_var("HOME", "/test/home");
+        }
 
-        // Check if any completion contains "subdir" or "file.txt"
-        let has_expected_completion = full_names
-            .iter()
-            .any(|c| c.contains("subdir") || c.contains("file.txt"));
-        assert!(
-            has_expected_completion,
-            "Expected completions to contain subdir or file.txt, got: {full_names:?}"
+        // Test that tilde expansion works in command arguments
+        // We can't easily test actual command execution, but we can test variable expansion
+        assert_eq!(
+            interpreter.expand_variables("ls ~/Documents"),
+            "ls /test/home/Documents"
         );
-
-        // Test completion with partial path
-        let input_prefix = format!("{}/dir1/s", temp_path.display());
-        let (suffixes, _full_names) = interpreter.get_path_completions(&input_prefix);
-        let has_subdir = suffixes.iter().any(|c| c.contains("ubdir"));
-        assert!(
-            has_subdir,
-            "Expected suffixes to contain 'ubdir', got: {suffixes:?}"
+        assert_eq!(
+            interpreter.expand_variables("cd ~ && pwd"),
+            "cd /test/home && pwd"
         );
-
-        // Test completion with file path
-        let input_prefix = format!("{}/dir1/f", temp_path.display());
-        let (suffixes, _full_names) = interpreter.get_path_completions(&input_prefix);
-        let has_file = suffixes.iter().any(|c| c.contains("ile.txt"));
-        assert!(
-            has_file,
-            "Expected suffixes to contain 'ile.txt', got: {suffixes:?}"
+        assert_eq!(
+            interpreter.expand_variables("cp file ~/backup/"),
+            "cp file /test/home/backup/"
         );
+
+        // Restore original HOME
+        unsafe {
+            if let Some(home) = original_home {
+                std::env::set_var("HOME", home);
+            } else {
+                std::env::remove_var("HOME");
+            }
+        }
     }
 
     #[test]
-    fn test_completion_with_multiple_words() {
+    fn test_cd_with_tilde_expansion() {
         let mut interpreter = Interpreter::new();
 
-        // Test command completion after pipe
-        let (suffixes, full_names) = interpreter.generate_completions("ls | e", 6);
+        // Set a known HOME directory for testing
+        let original_home = std::env::var("HOME").ok();
+        let original_pwd = std::env::current_dir().ok();
 
-        // Check that we get command completions starting with 'e'
-        let has_echo_or_export = full_names.iter().any(|c| *c == "echo" || *c == "export");
-        let has_echo_or_export_suffix = suffixes.iter().any(|c| *c == "cho" || *c == "xport");
-        assert!(
-            has_echo_or_export || has_echo_or_export_suffix,
-            "Expected completions to include echo or export, got full_names: {full_names:?}, suffixes: {suffixes:?}"
-        );
+        unsafe {
+            std::env::set_var("HOME", "/tmp");
+        }
 
-        // Test path completion after command
-        // Skip this test if current directory is not accessible (e.g., in CI)
-        if let Ok(original_dir) = env::current_dir() {
-            {
-                let temp_dir = tempdir().unwrap();
-                let temp_path = temp_dir.path();
-                fs::write(temp_path.join("testfile.txt"), "content").unwrap();
+        // Test cd with tilde
+        let result = interpreter.execute("cd ~");
+        assert!(result.is_ok());
 
-                env::set_current_dir(temp_path).unwrap();
+        // Check that PWD was updated (handle symlinks like /tmp -> /private/tmp on macOS)
+        let pwd = interpreter.variables.get("PWD").unwrap();
+        assert!(pwd.ends_with("/tmp") || pwd == "/tmp");
 
-                let (suffixes, full_names) = interpreter.generate_completions("cat test", 8);
+        // Test cd with tilde and subdirectory
+        // Note: We can't actually test this without creating the directory,
+        // but we can test that the expansion happens correctly
 
-                // Check if completions include something related to testfile.txt
-                let has_testfile = full_names
-                    .iter()
-                    .any(|c| c.contains("testfile") || c == "testfile.txt");
-                let has_testfile_suffix = suffixes
-                    .iter()
-                    .any(|c| c.contains("file") || c == "file.txt");
-                assert!(
-                    has_testfile || has_testfile_suffix,
-                    "Expected completions to include 'testfile.txt', got full_names: {full_names:?}, suffixes: {suffixes:?}"
-                );
+        // Restore original state
+        unsafe {
+            if let Some(home) = original_home {
+                std::env::set_var("HOME", home);
+            } else {
+                std::env::remove_var("HOME");
+            }
+        }
 
-                // Restore original working directory BEFORE temp_dir is dropped
-                env::set_current_dir(&original_dir).unwrap();
-            } // temp_dir is dropped here, but we're already back in the original directory
+        if let Some(pwd) = original_pwd {
+            let _ = std::env::set_current_dir(pwd);
         }
     }
 
     #[test]
-    fn test_command_completion_with_arguments() {
-        let mut interpreter = Interpreter::new();
+    fn test_flash_version_variable() {
+        let interpreter = Interpreter::new();
 
-        // Add an environment variable to the interpreter's variables
-        interpreter
-            .variables
-            .insert("TEST_PATH".to_string(), "/tmp".to_string());
+        // Test that FLASH_VERSION is set
+        let flash_version = interpreter.variables.get("FLASH_VERSION");
+        assert!(flash_version.is_some(), "FLASH_VERSION should be set");
 
-        // First test that the variable exists in the interpreter
-        assert!(interpreter.variables.contains_key("TEST_PATH"));
+        let version = flash_version.unwrap();
+        assert!(!version.is_empty(), "FLASH_VERSION should not be empty");
 
-        // Test partial variable completion
-        let (suffixes, full_names) = interpreter.generate_completions("cd $TEST_", 9);
+        // Test that it matches the cargo package version
+        assert_eq!(version, env!("CARGO_PKG_VERSION"));
 
-        // The suffixes should contain "PATH" (what comes after TEST_)
-        let has_path_suffix = suffixes.iter().any(|c| c == "PATH");
-        // The full names should contain "$TEST_PATH"
-        let has_path_full = full_names.iter().any(|c| c == "$TEST_PATH");
-        assert!(
-            has_path_suffix,
-            "Expected suffixes to include 'PATH', got: {suffixes:?}"
-        );
-        assert!(
-            has_path_full,
-            "Expected full_names to include '$TEST_PATH', got: {full_names:?}"
-        );
+        // Test that we can access it via variable expansion
+        let expanded = interpreter.expand_variables("$FLASH_VERSION");
+        assert_eq!(expanded, env!("CARGO_PKG_VERSION"));
 
-        // Test completion right after $
-        let (_suffixes, full_names) = interpreter.generate_completions("cd $", 4);
-        let has_test_path = full_names.iter().any(|c| c == "$TEST_PATH");
-        assert!(
-            has_test_path,
-            "Expected full_names to include '$TEST_PATH', got: {full_names:?}"
-        );
+        // Test that we can access it in command substitution context
+        let expanded_braces = interpreter.expand_variables("${FLASH_VERSION}");
+        assert_eq!(expanded_braces, env!("CARGO_PKG_VERSION"));
     }
 
     #[test]
-    fn test_positional_parameters_basic() {
+    fn test_split_fields_with_ifs() {
         let mut interpreter = Interpreter::new();
+        interpreter.variables.remove("IFS");
 
-        // Set up arguments: $0 = script, $1 = first arg, $2 = second arg
-        interpreter.set_args(vec![
-            "test_script.sh".to_string(),
-            "hello".to_string(),
-            "world".to_string(),
-        ]);
-
-        // Test $0 (script name)
-        assert_eq!(interpreter.expand_variables("$0"), "test_script.sh");
-
-        // Test $1 (first argument)
-        assert_eq!(interpreter.expand_variables("$1"), "hello");
-
-        // Test $2 (second argument)
-        assert_eq!(interpreter.expand_variables("$2"), "world");
-
-        // Test $3 (non-existent argument - should expand to empty)
-        assert_eq!(interpreter.expand_variables("$3"), "");
+        // Unset IFS splits on whitespace runs
+        assert_eq!(
+            interpreter.split_fields("  a b\t\nc  "),
+            vec!["a", "b", "c"]
+        );
 
-        // Test ${1} (braced syntax)
-        assert_eq!(interpreter.expand_variables("${1}"), "hello");
+        // Non-whitespace delimiters keep empty fields
+        interpreter
+            .variables
+            .insert("IFS".to_string(), ":".to_string());
+        assert_eq!(interpreter.split_fields("a::b"), vec!["a", "", "b"]);
 
-        // Test ${2} (braced syntax)
-        assert_eq!(interpreter.expand_variables("${2}"), "world");
+        // Empty IFS disables splitting entirely
+        interpreter
+            .variables
+            .insert("IFS".to_string(), String::new());
+        assert_eq!(interpreter.split_fields("a b c"), vec!["a b c"]);
+        assert_eq!(interpreter.expand_word("$x"), Vec::<String>::new());
+        interpreter
+            .variables
+            .insert("x".to_string(), "a b c".to_string());
+        assert_eq!(interpreter.expand_word("$x"), vec!["a b c"]);
     }
 
     #[test]
-    fn test_positional_parameters_special_variables() {
+    fn test_alternate_value_field_splitting() {
         let mut interpreter = Interpreter::new();
+        interpreter.execute("x=1").unwrap();
 
-        // Set up arguments
-        interpreter.set_args(vec![
-            "script.sh".to_string(),
-            "arg1".to_string(),
-            "arg2".to_string(),
-            "arg3".to_string(),
-        ]);
-
-        // Test $# (argument count - excludes $0)
-        assert_eq!(interpreter.expand_variables("$#"), "3");
-
-        // Test $@ (all arguments excluding $0)
-        assert_eq!(interpreter.expand_variables("$@"), "arg1 arg2 arg3");
+        // Unquoted, the alternate word is split into fields; quoted, it stays one field
+        assert_eq!(interpreter.expand_word("${x:+a b}"), vec!["a", "b"]);
+        assert_eq!(interpreter.expand_word("\"${x:+a b}\""), vec!["a b"]);
+        assert_eq!(interpreter.expand_word("${u:-a b}"), vec!["a", "b"]);
+        assert_eq!(interpreter.expand_word("${u:+a b}"), Vec::<String>::new());
 
-        // Test $* (all arguments excluding $0)
-        assert_eq!(interpreter.expand_variables("$*"), "arg1 arg2 arg3");
+        // Quotes inside the word protect their part from splitting
+        assert_eq!(interpreter.expand_word("${x:+\"a b\" c}"), vec!["a b", "c"]);
+        assert_eq!(interpreter.expand_word("${u:-'a b'}"), vec!["a b"]);
+    }
 
-        // Test with no arguments (only script name)
-        interpreter.set_args(vec!["script.sh".to_string()]);
-        assert_eq!(interpreter.expand_variables("$#"), "0");
-        assert_eq!(interpreter.expand_variables("$@"), "");
-        assert_eq!(interpreter.expand_variables("$*"), "");
+    #[test]
+    fn test_ppid_variable() {
+        let interpreter = Interpreter::new();
+
+        let ppid = interpreter.expand_variables("$PPID");
+        let ppid: u32 = ppid.parse().expect("PPID should be an integer");
+        assert!(ppid > 0, "PPID should be positive");
+
+        // The shell's parent is the process running the tests
+        assert_eq!(ppid, unsafe { libc::getppid() } as u32);
     }
 
     #[test]
-    fn test_positional_parameters_empty_args() {
+    fn test_declare_nameref() {
         let mut interpreter = Interpreter::new();
 
-        // Test with empty args vector
-        interpreter.set_args(vec![]);
+        interpreter.execute("target=1").unwrap();
+        assert_eq!(interpreter.execute("declare -n ref=target").unwrap(), 0);
 
-        assert_eq!(interpreter.expand_variables("$0"), "");
-        assert_eq!(interpreter.expand_variables("$1"), "");
-        assert_eq!(interpreter.expand_variables("$#"), "0");
-        assert_eq!(interpreter.expand_variables("$@"), "");
-        assert_eq!(interpreter.expand_variables("$*"), "");
+        // Assigning through the nameref updates the target
+        interpreter.execute("ref=5").unwrap();
+        assert_eq!(interpreter.variables.get("target"), Some(&"5".to_string()));
+        assert!(!interpreter.variables.contains_key("ref"));
+
+        // Expanding the nameref reads the target
+        assert_eq!(interpreter.expand_variables("$ref"), "5");
     }
 
     #[test]
-    fn test_positional_parameters_in_commands() {
+    fn test_export_f_marks_functions() {
         let mut interpreter = Interpreter::new();
+        interpreter.execute("greet() { echo hi; }").unwrap();
 
-        // Set up arguments
-        interpreter.set_args(vec![
-            "test.sh".to_string(),
-            "hello".to_string(),
-            "world".to_string(),
-        ]);
+        assert_eq!(interpreter.execute("export -f greet").unwrap(), 0);
+        assert!(interpreter.exported_functions.contains("greet"));
 
-        // Test expansion in complex strings
+        // Only defined functions can be exported
+        assert_eq!(interpreter.execute("export -f missing").unwrap(), 1);
+        assert!(!interpreter.exported_functions.contains("missing"));
+
+        assert_eq!(interpreter.execute("export -fn greet").unwrap(), 0);
+        assert!(interpreter.exported_functions.is_empty());
+        assert_eq!(interpreter.execute("declare -fx greet").unwrap(), 0);
+        assert!(interpreter.exported_functions.contains("greet"));
+    }
+
+    /// Status of a shell that kills itself with `signal`
+    fn status_killed_by(signal: &str) -> ExitStatus {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("kill -{signal} $$"))
+            .status()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_signaled_commands_exit_with_128_plus_signal() {
         assert_eq!(
-            interpreter.expand_variables("First: $1, Second: $2"),
-            "First: hello, Second: world"
+            exit_status_code(status_killed_by("TERM")),
+            128 + libc::SIGTERM
         );
-
-        // Test expansion with other variables
-        interpreter
-            .variables
-            .insert("USER".to_string(), "testuser".to_string());
         assert_eq!(
-            interpreter.expand_variables("User: $USER, Arg: $1"),
-            "User: testuser, Arg: hello"
+            exit_status_code(status_killed_by("KILL")),
+            128 + libc::SIGKILL
         );
 
-        // Test mixed braced and unbraced
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.execute("sh -c 'kill -TERM $$'").unwrap(), 143);
+        assert_eq!(interpreter.execute("sh -c 'exit 3'").unwrap(), 3);
+    }
+
+    #[test]
+    fn test_double_quote_escapes_expansions() {
+        assert_eq!(double_quote("plain"), "\"plain\"");
         assert_eq!(
-            interpreter.expand_variables("${1}_suffix and ${2}_suffix"),
-            "hello_suffix and world_suffix"
+            double_quote("a \"b\" $c `d` \\"),
+            r#""a \"b\" \$c \`d\` \\""#
         );
     }
 
     #[test]
-    fn test_positional_parameters_numeric_parsing() {
+    fn test_getopts_reads_clustered_options_one_letter_at_a_time() {
         let mut interpreter = Interpreter::new();
+        interpreter.args = ["flash", "-abc", "file"].map(String::from).to_vec();
 
-        // Set up many arguments to test numeric parsing
-        interpreter.set_args(vec![
-            "script.sh".to_string(),
-            "arg1".to_string(),
-            "arg2".to_string(),
-            "arg3".to_string(),
-            "arg4".to_string(),
-            "arg5".to_string(),
-            "arg6".to_string(),
-            "arg7".to_string(),
-            "arg8".to_string(),
-            "arg9".to_string(),
-            "arg10".to_string(),
-        ]);
-
-        // Test single digits
-        assert_eq!(interpreter.expand_variables("$1"), "arg1");
-        assert_eq!(interpreter.expand_variables("$9"), "arg9");
+        let mut seen = Vec::new();
+        while interpreter.execute("getopts abc opt").unwrap() == 0 {
+            seen.push((
+                interpreter.variables["opt"].clone(),
+                interpreter.variables["OPTIND"].clone(),
+            ));
+        }
+        let seen: Vec<(&str, &str)> = seen.iter().map(|(o, i)| (o.as_str(), i.as_str())).collect();
+        // OPTIND only moves past -abc once its last letter is read
+        assert_eq!(seen, [("a", "1"), ("b", "1"), ("c", "2")]);
+        assert_eq!(interpreter.variables["OPTIND"], "2");
+        assert_eq!(interpreter.variables["opt"], "?");
+    }
 
-        // Test double digits (should work with braces)
-        assert_eq!(interpreter.expand_variables("${10}"), "arg10");
+    #[test]
+    fn test_special_parameters_reject_assignment() {
+        let mut interpreter = Interpreter::new();
+        interpreter.args = vec!["flash".to_string(), "one".to_string()];
 
-        // Test that $10 without braces only gets $1 followed by "0"
-        assert_eq!(interpreter.expand_variables("$10"), "arg10");
+        assert_eq!(interpreter.execute("1=x").unwrap(), 1);
+        assert_eq!(interpreter.expand_variables("$1"), "one");
+        assert_eq!(interpreter.execute("?=5").unwrap(), 1);
+        assert_eq!(interpreter.execute("declare '#=3'").unwrap(), 1);
+        assert_eq!(interpreter.execute("export 0=sh").unwrap(), 1);
 
-        // Test non-existent high numbers
-        assert_eq!(interpreter.expand_variables("${99}"), "");
+        // $? still follows the status of each command
+        interpreter.execute("false; x=$?; true").unwrap();
+        assert_eq!(interpreter.variables.get("x").unwrap(), "1");
+        assert_eq!(interpreter.expand_variables("$?"), "0");
     }
 
     #[test]
-    fn test_positional_parameters_with_quotes() {
+    fn test_declare_nameref_rejects_cycles() {
         let mut interpreter = Interpreter::new();
 
-        // Set up arguments with spaces and special characters
-        interpreter.set_args(vec![
-            "script.sh".to_string(),
-            "hello world".to_string(),
-            "arg with spaces".to_string(),
-            "special!@#$%".to_string(),
-        ]);
+        assert_eq!(interpreter.execute("declare -n self=self").unwrap(), 1);
+        assert!(!interpreter.namerefs.contains_key("self"));
 
-        // Test that arguments with spaces are preserved
-        assert_eq!(interpreter.expand_variables("$1"), "hello world");
-        assert_eq!(interpreter.expand_variables("$2"), "arg with spaces");
-        assert_eq!(interpreter.expand_variables("$3"), "special!@#$%");
+        assert_eq!(interpreter.execute("declare -n a=b").unwrap(), 0);
+        assert_eq!(interpreter.execute("declare -n b=a").unwrap(), 1);
+        assert!(!interpreter.namerefs.contains_key("b"));
 
-        // Test $@ preserves all arguments
-        assert_eq!(
-            interpreter.expand_variables("$@"),
-            "hello world arg with spaces special!@#$%"
-        );
+        // Longer loops are found by walking the whole chain
+        assert_eq!(interpreter.execute("declare -n b=c").unwrap(), 0);
+        assert_eq!(interpreter.execute("declare -n c=a").unwrap(), 1);
+        assert!(!interpreter.namerefs.contains_key("c"));
+
+        // A chain that ends at a variable resolves to it
+        interpreter
+            .execute("declare -n c=target; target=end")
+            .unwrap();
+        assert_eq!(interpreter.resolve_nameref("a"), "target");
+        assert_eq!(interpreter.expand_variables("$a"), "end");
     }
 
     #[test]
-    fn test_echo_command_with_positional_parameters() {
+    fn test_jobs_are_listed_in_command_substitutions_and_pipeline_stages() {
         let mut interpreter = Interpreter::new();
+        interpreter.execute("sleep 5 &").unwrap();
+        let pid = interpreter.variables["!"].clone();
 
-        // Set up arguments
-        interpreter.set_args(vec![
-            "test.sh".to_string(),
-            "hello".to_string(),
-            "world".to_string(),
-        ]);
-
-        // Test that echo properly expands positional parameters
-        // We can't easily test the actual output, but we can test the expansion
-        let expanded = interpreter.expand_variables("First arg: $1, Second arg: $2");
-        assert_eq!(expanded, "First arg: hello, Second arg: world");
+        let (code, stdout, _) = interpreter
+            .execute_capture("x=$(jobs -p); echo \"$x\"; jobs -p | cat")
+            .unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(stdout, format!("{pid}\n{pid}\n"));
 
-        // Test with $# and $@
-        let expanded = interpreter.expand_variables("Count: $#, All: $@");
-        assert_eq!(expanded, "Count: 2, All: hello world");
+        // The copies only listed the job; the shell itself still waits for it
+        let code = interpreter.execute("kill $(jobs -p); wait").unwrap();
+        assert_eq!(code, 0);
+        assert!(interpreter.jobs.is_empty());
     }
 
     #[test]
-    fn test_set_args_method() {
+    fn test_reap_finished_background_job() {
         let mut interpreter = Interpreter::new();
 
-        // Test initial state
-        assert!(interpreter.args.is_empty());
+        // Reaping is the shell's job here, not std's
+        #[allow(clippy::zombie_processes)]
+        let child = Command::new("true").spawn().expect("Failed to spawn true");
+        interpreter.jobs.push(Job {
+            id: 1,
+            pid: child.id() as i32,
+            command: "true".to_string(),
+            inherited: false,
+        });
 
-        // Test setting args
-        let test_args = vec![
-            "script.sh".to_string(),
-            "arg1".to_string(),
-            "arg2".to_string(),
-        ];
-        interpreter.set_args(test_args.clone());
+        // Poll without blocking until the child has exited and been reaped
+        let mut notices = Vec::new();
+        for _ in 0..200 {
+            notices = interpreter.reap_jobs();
+            if !notices.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
 
-        assert_eq!(interpreter.args, test_args);
-        assert_eq!(interpreter.expand_variables("$0"), "script.sh");
-        assert_eq!(interpreter.expand_variables("$1"), "arg1");
-        assert_eq!(interpreter.expand_variables("$2"), "arg2");
+        assert_eq!(notices, vec!["[1]+ Done true".to_string()]);
+        assert!(interpreter.jobs.is_empty());
+    }
 
-        // Test overwriting args
-        let new_args = vec!["new_script.sh".to_string(), "new_arg".to_string()];
-        interpreter.set_args(new_args.clone());
+    #[test]
+    fn test_printf_v_assigns_variable() {
+        let mut interpreter = Interpreter::new();
 
-        assert_eq!(interpreter.args, new_args);
-        assert_eq!(interpreter.expand_variables("$0"), "new_script.sh");
-        assert_eq!(interpreter.expand_variables("$1"), "new_arg");
-        assert_eq!(interpreter.expand_variables("$2"), ""); // Should be empty now
+        assert_eq!(interpreter.execute("printf -v s '%05d' 42").unwrap(), 0);
+        assert_eq!(interpreter.variables.get("s"), Some(&"00042".to_string()));
+
+        // The format is reused until every argument is consumed
+        interpreter.execute("printf -v list '%s,' a b c").unwrap();
+        assert_eq!(
+            interpreter.variables.get("list"),
+            Some(&"a,b,c,".to_string())
+        );
     }
 
     #[test]
-    fn test_positional_parameters_edge_cases() {
+    fn test_printf_v_assigns_array_element() {
         let mut interpreter = Interpreter::new();
 
-        // Test with single argument (only script name)
-        interpreter.set_args(vec!["script.sh".to_string()]);
+        assert_eq!(interpreter.execute("printf -v arr[0] '%x' 255").unwrap(), 0);
+        assert_eq!(interpreter.array_values("arr"), ["ff"]);
+        assert!(!interpreter.variables.contains_key("arr[0]"));
 
-        assert_eq!(interpreter.expand_variables("$0"), "script.sh");
-        assert_eq!(interpreter.expand_variables("$1"), "");
-        assert_eq!(interpreter.expand_variables("$#"), "0");
-        assert_eq!(interpreter.expand_variables("$@"), "");
+        // Invalid targets are rejected without assigning anything
+        assert_eq!(interpreter.execute("printf -v 1bad '%s' x").unwrap(), 2);
+        assert!(!interpreter.variables.contains_key("1bad"));
+    }
 
-        // Test with empty string arguments
-        interpreter.set_args(vec![
-            "script.sh".to_string(),
-            "".to_string(),
-            "non_empty".to_string(),
-            "".to_string(),
-        ]);
+    #[test]
+    fn test_mapfile_callback_quantum() {
+        let mut interpreter = Interpreter::new();
 
-        assert_eq!(interpreter.expand_variables("$1"), "");
-        assert_eq!(interpreter.expand_variables("$2"), "non_empty");
-        assert_eq!(interpreter.expand_variables("$3"), "");
-        assert_eq!(interpreter.expand_variables("$#"), "3");
-        assert_eq!(interpreter.expand_variables("$@"), " non_empty ");
+        interpreter
+            .execute("calls=0; seen=; cb() { calls=$(( calls + 1 )); seen=\"$seen $1:$2\"; }")
+            .unwrap();
+        interpreter
+            .execute("printf 'a\\nb\\nc\\nd\\ne\\n' | mapfile -t -C cb -c 2 lines")
+            .unwrap();
+
+        assert_eq!(interpreter.variables.get("calls"), Some(&"2".to_string()));
+        assert_eq!(
+            interpreter.variables.get("seen"),
+            Some(&" 1:b 3:d".to_string())
+        );
+        assert_eq!(
+            interpreter.array_subscripts("lines"),
+            ["0", "1", "2", "3", "4"]
+        );
+        assert_eq!(interpreter.array_values("lines")[4], "e");
+
+        assert_eq!(interpreter.execute("mapfile -c 0 lines").unwrap(), 1);
     }
 
     #[test]
-    fn test_glob_pattern_detection() {
-        let interpreter = Interpreter::new();
+    fn test_printf_alternate_form() {
+        let mut interpreter = Interpreter::new();
 
-        // Test patterns that should be detected as globs
-        assert!(interpreter.contains_glob_pattern("*.txt"));
-        assert!(interpreter.contains_glob_pattern("file?.log"));
-        assert!(interpreter.contains_glob_pattern("[abc]*.tmp"));
-        assert!(interpreter.contains_glob_pattern("test*"));
-        assert!(interpreter.contains_glob_pattern("?test"));
-        assert!(interpreter.contains_glob_pattern("file[0-9].txt"));
+        interpreter.execute("printf -v hex '%#x' 255").unwrap();
+        assert_eq!(interpreter.variables.get("hex"), Some(&"0xff".to_string()));
 
-        // Test patterns that should NOT be detected as globs
-        assert!(!interpreter.contains_glob_pattern("file.txt"));
-        assert!(!interpreter.contains_glob_pattern("test"));
-        assert!(!interpreter.contains_glob_pattern("path/to/file"));
+        interpreter.execute("printf -v oct '%#o' 8").unwrap();
+        assert_eq!(interpreter.variables.get("oct"), Some(&"010".to_string()));
+
+        // Zero padding goes between the prefix and the digits
+        interpreter.execute("printf -v padded '%#06X' 255").unwrap();
+        assert_eq!(
+            interpreter.variables.get("padded"),
+            Some(&"0X00FF".to_string())
+        );
     }
 
     #[test]
-    fn test_glob_pattern_matching() {
-        let interpreter = Interpreter::new();
+    fn test_declare_integer_stores_decimal_value() {
+        let mut interpreter = Interpreter::new();
 
-        // Test * wildcard
-        assert!(interpreter.matches_glob_pattern("file.txt", "*.txt"));
-        assert!(interpreter.matches_glob_pattern("test.txt", "*.txt"));
-        assert!(interpreter.matches_glob_pattern("a.txt", "*.txt"));
-        assert!(!interpreter.matches_glob_pattern("file.log", "*.txt"));
-        assert!(interpreter.matches_glob_pattern("anything", "*"));
-        assert!(interpreter.matches_glob_pattern("", "*"));
+        interpreter.execute("declare -i n=6*7").unwrap();
+        assert!(interpreter.integer_vars.contains("n"));
+        assert_eq!(interpreter.variables.get("n"), Some(&"42".to_string()));
+    }
 
-        // Test ? wildcard
-        assert!(interpreter.matches_glob_pattern("file1.txt", "file?.txt"));
-        assert!(interpreter.matches_glob_pattern("fileA.txt", "file?.txt"));
-        assert!(!interpreter.matches_glob_pattern("file.txt", "file?.txt"));
-        assert!(!interpreter.matches_glob_pattern("file12.txt", "file?.txt"));
+    #[test]
+    fn test_assigning_integer_variable_evaluates_arithmetic() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("declare -i n; x=5").unwrap();
 
-        // Test character classes
-        assert!(interpreter.matches_glob_pattern("file1.txt", "file[123].txt"));
-        assert!(interpreter.matches_glob_pattern("file2.txt", "file[123].txt"));
-        assert!(interpreter.matches_glob_pattern("file3.txt", "file[123].txt"));
-        assert!(!interpreter.matches_glob_pattern("file4.txt", "file[123].txt"));
-        assert!(!interpreter.matches_glob_pattern("filea.txt", "file[123].txt"));
+        interpreter.execute("n=3+4").unwrap();
+        assert_eq!(interpreter.variables["n"], "7");
+        interpreter.execute("n='x * 2'").unwrap();
+        assert_eq!(interpreter.variables["n"], "10");
+        // Words that are not numbers or set variables evaluate to 0
+        interpreter.execute("n=foo").unwrap();
+        assert_eq!(interpreter.variables["n"], "0");
 
-        // Test character ranges
-        assert!(interpreter.matches_glob_pattern("file1.txt", "file[0-9].txt"));
-        assert!(interpreter.matches_glob_pattern("file5.txt", "file[0-9].txt"));
-        assert!(interpreter.matches_glob_pattern("file9.txt", "file[0-9].txt"));
-        assert!(!interpreter.matches_glob_pattern("filea.txt", "file[0-9].txt"));
+        // Variables without the attribute keep the text
+        interpreter.execute("m=3+4").unwrap();
+        assert_eq!(interpreter.variables["m"], "3+4");
 
-        assert!(interpreter.matches_glob_pattern("filea.txt", "file[a-z].txt"));
-        assert!(interpreter.matches_glob_pattern("filem.txt", "file[a-z].txt"));
-        assert!(interpreter.matches_glob_pattern("filez.txt", "file[a-z].txt"));
-        assert!(!interpreter.matches_glob_pattern("fileA.txt", "file[a-z].txt"));
-        assert!(!interpreter.matches_glob_pattern("file1.txt", "file[a-z].txt"));
+        // += adds to an integer variable and appends text to others
+        interpreter.execute("n+=x+1; m+=5").unwrap();
+        assert_eq!(interpreter.variables["n"], "6");
+        assert_eq!(interpreter.variables["m"], "3+45");
+    }
 
-        // Test negated character classes
-        assert!(!interpreter.matches_glob_pattern("file1.txt", "file[!123].txt"));
-        assert!(!interpreter.matches_glob_pattern("file2.txt", "file[!123].txt"));
-        assert!(interpreter.matches_glob_pattern("file4.txt", "file[!123].txt"));
-        assert!(interpreter.matches_glob_pattern("filea.txt", "file[!123].txt"));
+    #[test]
+    fn test_local_is_dynamically_scoped() {
+        let mut interpreter = Interpreter::new();
 
-        // Test complex patterns
-        assert!(interpreter.matches_glob_pattern("test123.log", "test*.log"));
-        assert!(interpreter.matches_glob_pattern("test.log", "test*.log"));
-        assert!(interpreter.matches_glob_pattern("backup_file_2023.txt", "*_file_*.txt"));
-        assert!(interpreter.matches_glob_pattern("config.ini", "*.ini"));
+        interpreter
+            .execute("b() { seen=$x; local x=from_b; inner=$x; }")
+            .unwrap();
+        interpreter
+            .execute("a() { local x=5; b; after_b=$x; }")
+            .unwrap();
+        interpreter.execute("x=global").unwrap();
+        interpreter.execute("a").unwrap();
 
-        // Test exact matches
-        assert!(interpreter.matches_glob_pattern("exact", "exact"));
-        assert!(!interpreter.matches_glob_pattern("exact", "different"));
+        // b sees a's local through dynamic scoping
+        assert_eq!(interpreter.variables.get("seen"), Some(&"5".to_string()));
+        assert_eq!(
+            interpreter.variables.get("inner"),
+            Some(&"from_b".to_string())
+        );
+        // b's own local does not leak back into a, nor a's into the global scope
+        assert_eq!(interpreter.variables.get("after_b"), Some(&"5".to_string()));
+        assert_eq!(interpreter.variables.get("x"), Some(&"global".to_string()));
+        assert!(interpreter.local_scopes.is_empty());
+    }
+
+    #[test]
+    fn test_command_substitution_reads_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("VERSION");
+        fs::write(&path, "1.2.3\n\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .variables
+            .insert("FILE".to_string(), path.display().to_string());
+
+        interpreter
+            .execute("version=$(< $FILE); quoted=\"$(<$FILE)\"")
+            .unwrap();
+        assert_eq!(
+            interpreter.variables.get("version"),
+            Some(&"1.2.3".to_string())
+        );
+        assert_eq!(
+            interpreter.variables.get("quoted"),
+            Some(&"1.2.3".to_string())
+        );
+        assert_eq!(interpreter.expand_word("$(< $FILE)"), vec!["1.2.3"]);
+
+        // A missing file expands to nothing
+        interpreter.execute("missing=$(< $FILE.missing)").unwrap();
+        assert_eq!(interpreter.variables.get("missing"), Some(&String::new()));
     }
 
     #[test]
-    fn test_character_class_matching() {
-        let interpreter = Interpreter::new();
+    fn test_parameter_default_command_runs_only_when_needed() {
+        let dir = tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .variables
+            .insert("MARKER".to_string(), marker.display().to_string());
 
-        // Test simple character class
-        assert!(interpreter.matches_char_class('a', &['a', 'b', 'c']));
-        assert!(interpreter.matches_char_class('b', &['a', 'b', 'c']));
-        assert!(interpreter.matches_char_class('c', &['a', 'b', 'c']));
-        assert!(!interpreter.matches_char_class('d', &['a', 'b', 'c']));
+        interpreter.execute("SET=value").unwrap();
+        interpreter
+            .execute("a=${SET:-$(touch $MARKER)}; b=${UNSET:+$(touch $MARKER)}")
+            .unwrap();
+        assert!(!marker.exists());
+        assert_eq!(interpreter.variables.get("a"), Some(&"value".to_string()));
+        assert_eq!(interpreter.variables.get("b"), Some(&String::new()));
 
-        // Test range
-        assert!(interpreter.matches_char_class('a', &['a', '-', 'z']));
-        assert!(interpreter.matches_char_class('m', &['a', '-', 'z']));
-        assert!(interpreter.matches_char_class('z', &['a', '-', 'z']));
-        assert!(!interpreter.matches_char_class('A', &['a', '-', 'z']));
+        interpreter
+            .execute("c=${UNSET:-$(echo fallback)}; d=${SET:+$(echo alt)}")
+            .unwrap();
+        assert_eq!(
+            interpreter.variables.get("c"),
+            Some(&"fallback".to_string())
+        );
+        assert_eq!(interpreter.variables.get("d"), Some(&"alt".to_string()));
 
-        // Test negated class
-        assert!(!interpreter.matches_char_class('a', &['!', 'a', 'b', 'c']));
-        assert!(!interpreter.matches_char_class('b', &['!', 'a', 'b', 'c']));
-        assert!(interpreter.matches_char_class('d', &['!', 'a', 'b', 'c']));
+        // := expands its word once and assigns it
+        interpreter.execute("e=${NEW:=$(echo assigned)}").unwrap();
+        assert_eq!(
+            interpreter.variables.get("NEW"),
+            Some(&"assigned".to_string())
+        );
+        assert_eq!(
+            interpreter.variables.get("e"),
+            Some(&"assigned".to_string())
+        );
 
-        // Test negated range
-        assert!(!interpreter.matches_char_class('a', &['!', 'a', '-', 'z']));
-        assert!(!interpreter.matches_char_class('m', &['!', 'a', '-', 'z']));
-        assert!(interpreter.matches_char_class('A', &['!', 'a', '-', 'z']));
-        assert!(interpreter.matches_char_class('1', &['!', 'a', '-', 'z']));
+        interpreter.execute("f=${UNSET:-$(touch $MARKER)}").unwrap();
+        assert!(marker.exists());
     }
 
     #[test]
-    fn test_glob_expansion_with_temp_files() {
-        use tempfile::tempdir;
+    fn test_parameter_defaults_distinguish_empty_from_unset() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("EMPTY=").unwrap();
 
-        let temp_dir = tempdir().unwrap();
-        let temp_path = temp_dir.path();
+        interpreter
+            .execute("a=${EMPTY:-default}; b=${EMPTY-default}; c=${UNSET-default}")
+            .unwrap();
+        assert_eq!(interpreter.variables.get("a"), Some(&"default".to_string()));
+        assert_eq!(interpreter.variables.get("b"), Some(&String::new()));
+        assert_eq!(interpreter.variables.get("c"), Some(&"default".to_string()));
 
-        // Create test files
-        fs::write(temp_path.join("file1.txt"), "content1").unwrap();
-        fs::write(temp_path.join("file2.txt"), "content2").unwrap();
-        fs::write(temp_path.join("test.log"), "log content").unwrap();
-        fs::write(temp_path.join("data.csv"), "csv content").unwrap();
-        fs::write(temp_path.join("script.sh"), "script content").unwrap();
+        interpreter
+            .execute("d=${EMPTY:+alt}; e=${EMPTY+alt}; f=${UNSET+alt}")
+            .unwrap();
+        assert_eq!(interpreter.variables.get("d"), Some(&String::new()));
+        assert_eq!(interpreter.variables.get("e"), Some(&"alt".to_string()));
+        assert_eq!(interpreter.variables.get("f"), Some(&String::new()));
+
+        // = leaves an empty value alone, := replaces it
+        interpreter.execute("g=${EMPTY=x}").unwrap();
+        assert_eq!(interpreter.variables.get("EMPTY"), Some(&String::new()));
+        interpreter.execute("h=${EMPTY:=x}").unwrap();
+        assert_eq!(interpreter.variables.get("EMPTY"), Some(&"x".to_string()));
+
+        // The default word is expanded recursively
+        interpreter.execute("NAME=anonymous").unwrap();
+        interpreter.execute("i=${USER_NAME:-${NAME}}").unwrap();
+        assert_eq!(
+            interpreter.variables.get("i"),
+            Some(&"anonymous".to_string())
+        );
 
-        let interpreter = Interpreter::new();
+        // ? fails the command when the parameter is unset, and ends a shell that is
+        // not interactive
+        interpreter.interactive = true;
+        assert_eq!(interpreter.execute("j=${UNSET?missing}").unwrap(), 1);
+        assert_eq!(interpreter.execute("k=${EMPTY?missing}").unwrap(), 0);
+        assert_eq!(interpreter.variables.get("j"), None);
+    }
 
-        // Test *.txt pattern
-        let mut matches = interpreter.glob_match_in_dir("*.txt", temp_path);
-        matches.sort();
-        assert_eq!(matches, vec!["file1.txt", "file2.txt"]);
+    #[test]
+    fn test_parameter_length_expansion() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("NAME=héllo").unwrap();
+        interpreter.args = vec!["flash".to_string(), "one".to_string(), "two".to_string()];
 
-        // Test *.log pattern
-        let matches = interpreter.glob_match_in_dir("*.log", temp_path);
-        assert_eq!(matches, vec!["test.log"]);
+        assert_eq!(interpreter.expand_variables("${#NAME}"), "5");
+        assert_eq!(interpreter.expand_variables("${#UNSET}"), "0");
+        assert_eq!(interpreter.expand_variables("${#1}"), "3");
+        assert_eq!(interpreter.expand_variables("${#} $#"), "2 2");
+        assert_eq!(interpreter.expand_variables("${#@} ${#*}"), "2 2");
+    }
 
-        // Test file?.txt pattern
-        let mut matches = interpreter.glob_match_in_dir("file?.txt", temp_path);
-        matches.sort();
-        assert_eq!(matches, vec!["file1.txt", "file2.txt"]);
+    #[test]
+    fn test_register_builtin() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_builtin("greet", |interpreter, args| {
+            let name = args.first().map_or("world", String::as_str);
+            interpreter
+                .variables
+                .insert("GREETING".to_string(), format!("hello, {name}"));
+            Ok(if args.len() > 1 { 2 } else { 0 })
+        });
 
-        // Test pattern with no matches
-        let matches = interpreter.glob_match_in_dir("*.xyz", temp_path);
-        assert!(matches.is_empty());
+        assert!(interpreter.is_builtin("greet"));
+        assert!(!interpreter.is_external_command("greet"));
+        assert_eq!(interpreter.execute("greet flash").unwrap(), 0);
+        assert_eq!(
+            interpreter.variables.get("GREETING"),
+            Some(&"hello, flash".to_string())
+        );
+        assert_eq!(interpreter.execute("greet a b").unwrap(), 2);
 
-        // Test * pattern (should match all files)
-        let mut matches = interpreter.glob_match_in_dir("*", temp_path);
-        matches.sort();
+        // Functions still take precedence
+        interpreter
+            .execute("greet() { GREETING=function; }; greet")
+            .unwrap();
         assert_eq!(
-            matches,
-            vec![
-                "data.csv",
-                "file1.txt",
-                "file2.txt",
-                "script.sh",
-                "test.log"
-            ]
+            interpreter.variables.get("GREETING"),
+            Some(&"function".to_string())
         );
     }
 
     #[test]
-    fn test_expand_glob_patterns() {
-        use tempfile::tempdir;
-
-        let temp_dir = tempdir().unwrap();
-        let temp_path = temp_dir.path();
-
-        // Create test files
-        fs::write(temp_path.join("file1.txt"), "content1").unwrap();
-        fs::write(temp_path.join("file2.txt"), "content2").unwrap();
-        fs::write(temp_path.join("test.log"), "log content").unwrap();
+    fn test_extended_test_short_circuits() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("v=abc").unwrap();
 
-        // Create a custom interpreter that uses the temp directory for glob matching
-        let interpreter = TestInterpreter::new(temp_path.to_path_buf());
+        assert_eq!(interpreter.execute("[[ $v =~ ^a(b)c$ ]]").unwrap(), 0);
+        assert_eq!(interpreter.expand_variables("${BASH_REMATCH[1]}"), "b");
 
-        // Test expansion with glob patterns
-        let args = vec!["ls".to_string(), "*.txt".to_string()];
-        let expanded = interpreter.expand_glob_patterns(&args);
-        assert_eq!(expanded[0], "ls");
-        assert!(expanded.contains(&"file1.txt".to_string()));
-        assert!(expanded.contains(&"file2.txt".to_string()));
-        assert_eq!(expanded.len(), 3); // ls + 2 txt files
+        // The right side of && and || is never reached, so BASH_REMATCH keeps its value
+        assert_eq!(interpreter.execute("[[ 1 == 2 && $v =~ (c) ]]").unwrap(), 1);
+        assert_eq!(interpreter.execute("[[ -n $v || $v =~ (c) ]]").unwrap(), 0);
+        assert_eq!(interpreter.expand_variables("${BASH_REMATCH[1]}"), "b");
 
-        // Test expansion with mixed patterns and literals
-        let args = vec![
-            "command".to_string(),
-            "literal".to_string(),
-            "*.log".to_string(),
-        ];
-        let expanded = interpreter.expand_glob_patterns(&args);
-        assert_eq!(expanded, vec!["command", "literal", "test.log"]);
+        assert_eq!(interpreter.execute("[[ -n $v && $v =~ (c) ]]").unwrap(), 0);
+        assert_eq!(interpreter.expand_variables("${BASH_REMATCH[1]}"), "c");
+    }
 
-        // Test expansion with no matches (should keep original pattern)
-        let args = vec!["command".to_string(), "*.xyz".to_string()];
-        let expanded = interpreter.expand_glob_patterns(&args);
-        assert_eq!(expanded, vec!["command", "*.xyz"]);
+    #[test]
+    fn test_extended_test_patterns_and_grouping() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("f=notes.txt; s='a b'").unwrap();
 
-        // Test expansion with no glob patterns
-        let args = vec![
-            "command".to_string(),
-            "arg1".to_string(),
-            "arg2".to_string(),
-        ];
-        let expanded = interpreter.expand_glob_patterns(&args);
-        assert_eq!(expanded, args);
+        assert_eq!(interpreter.execute("[[ $f == *.txt ]]").unwrap(), 0);
+        assert_eq!(interpreter.execute("[[ $f == \"*.txt\" ]]").unwrap(), 1);
+        assert_eq!(interpreter.execute("[[ $s == \"a b\" ]]").unwrap(), 0);
+        // Only the quoted part of a pattern is literal
+        assert_eq!(interpreter.execute("[[ $f == \"notes\"* ]]").unwrap(), 0);
+        assert_eq!(interpreter.execute("[[ $f == \"note\"? ]]").unwrap(), 1);
+        assert_eq!(interpreter.execute("[[ ! ( $f != *.md ) ]]").unwrap(), 1);
+        assert_eq!(interpreter.execute("[[ 3 -lt 10 && b > a ]]").unwrap(), 0);
+        assert_eq!(interpreter.execute("[[ a == ]]").unwrap(), 2);
     }
 
-    // Helper struct for testing that uses a specific directory for glob operations
-    struct TestInterpreter {
-        interpreter: Interpreter,
-        test_dir: PathBuf,
+    #[test]
+    fn test_parameter_prefix_and_suffix_removal() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("f=/a/b/c.txt; sep=/").unwrap();
+
+        assert_eq!(interpreter.expand_variables("${f##*/}"), "c.txt");
+        assert_eq!(interpreter.expand_variables("${f#*/}"), "a/b/c.txt");
+        assert_eq!(interpreter.expand_variables("${f%.*}"), "/a/b/c");
+        assert_eq!(interpreter.expand_variables("${f%%/*}"), "");
+        assert_eq!(interpreter.expand_variables("${f%$sep*}"), "/a/b");
+        assert_eq!(interpreter.expand_variables("${f%.txt}"), "/a/b/c");
+        // A pattern that does not match leaves the value alone
+        assert_eq!(interpreter.expand_variables("${f#b}"), "/a/b/c.txt");
     }
 
-    impl TestInterpreter {
-        fn new(test_dir: PathBuf) -> Self {
-            Self {
-                interpreter: Interpreter::new(),
-                test_dir,
-            }
-        }
+    #[test]
+    fn test_parameter_pattern_substitution() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .execute("P=/bin:/usr/bin:/sbin; x=axbxcx")
+            .unwrap();
 
-        fn expand_glob_patterns(&self, args: &[String]) -> Vec<String> {
-            let mut expanded_args = Vec::new();
+        assert_eq!(
+            interpreter.expand_variables("${P//:/ }"),
+            "/bin /usr/bin /sbin"
+        );
+        assert_eq!(
+            interpreter.expand_variables("${P/:/ }"),
+            "/bin /usr/bin:/sbin"
+        );
+        // An empty or missing replacement deletes the matches
+        assert_eq!(interpreter.expand_variables("${x//x/}"), "abc");
+        assert_eq!(interpreter.expand_variables("${x//x}"), "abc");
+        // Patterns match as much as they can
+        assert_eq!(interpreter.expand_variables("${x/b*/Q}"), "axQ");
+        assert_eq!(interpreter.expand_variables("${x//[bc]/-}"), "ax-x-x");
+        // Anchored forms only match at the start or the end
+        assert_eq!(interpreter.expand_variables("${x/#a/A}"), "Axbxcx");
+        assert_eq!(interpreter.expand_variables("${x/#x/A}"), "axbxcx");
+        assert_eq!(interpreter.expand_variables("${x/%x/Z}"), "axbxcZ");
+        assert_eq!(interpreter.expand_variables("${x/%a/Z}"), "axbxcx");
+    }
 
-            for arg in args {
-                if self.interpreter.contains_glob_pattern(arg) {
-                    let matches = self.interpreter.glob_match_in_dir(arg, &self.test_dir);
-                    if matches.is_empty() {
-                        // If no matches found, keep the original pattern
-                        expanded_args.push(arg.clone());
-                    } else {
-                        expanded_args.extend(matches);
-                    }
-                } else {
-                    expanded_args.push(arg.clone());
-                }
-            }
+    #[test]
+    fn test_printf_output_is_raw_bytes() {
+        let (output, status) = format_printf("a\\0b", &[], io::sink());
+        assert_eq!(output, b"a\0b");
+        assert_eq!(status, 0);
+
+        // Octal and hex escapes produce single bytes, even outside ASCII
+        let (output, _) = format_printf("\\101\\x42\\377", &[], io::sink());
+        assert_eq!(output, [b'A', b'B', 0xff]);
+
+        let (output, _) = format_printf(
+            "%b|%3b",
+            &["x\\0101\\0".to_string(), "y".to_string()],
+            io::sink(),
+        );
+        assert_eq!(output, b"xA\0|  y");
 
-            expanded_args
-        }
+        // The bytes reach a writer unchanged
+        let mut captured = Vec::new();
+        captured
+            .write_all(&format_printf("a\\0b", &[], io::sink()).0)
+            .unwrap();
+        assert_eq!(captured.len(), 3);
     }
 
     #[test]
-    fn test_tilde_expansion_basic() {
-        let interpreter = Interpreter::new();
+    fn test_array_subscript_expansion() {
+        let mut interpreter = Interpreter::new();
 
-        // Set a known HOME directory for testing
-        let original_home = std::env::var("HOME").ok();
-        unsafe {
-            std::env::set_var("HOME", "/test/home");
-        }
+        interpreter
+            .execute("dense[0]=a; dense[1]=b; dense[2]=c")
+            .unwrap();
+        assert_eq!(interpreter.expand_variables("${!dense[@]}"), "0 1 2");
 
-        // Test basic tilde expansion
-        assert_eq!(interpreter.expand_variables("~"), "/test/home");
-        assert_eq!(
-            interpreter.expand_variables("~/Documents"),
-            "/test/home/Documents"
-        );
+        // Only the indices that were set, in numeric order
+        interpreter
+            .execute("sparse[10]=x; sparse[2]=y; sparse[5]=z")
+            .unwrap();
+        assert_eq!(interpreter.expand_variables("${!sparse[@]}"), "2 5 10");
+        assert_eq!(interpreter.expand_variables("${!sparse[*]}"), "2 5 10");
+
+        interpreter
+            .execute("declare -A colors; colors[sky]=blue; colors[grass]=green")
+            .unwrap();
+        assert_eq!(interpreter.expand_variables("${!colors[@]}"), "grass sky");
+
+        assert_eq!(interpreter.expand_variables("${!missing[@]}"), "");
+    }
+
+    #[test]
+    fn test_indexed_arrays() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("a=(x 'y z' w); i=1").unwrap();
+
+        assert_eq!(interpreter.expand_variables("${a[1]} ${#a[@]}"), "y z 3");
+        // Subscripts are arithmetic and negative ones count from the end
+        assert_eq!(interpreter.expand_variables("${a[i+1]} ${a[$i-1]}"), "w x");
+        assert_eq!(interpreter.expand_variables("${a[-1]}"), "w");
+        // The bare name is element 0
+        assert_eq!(interpreter.expand_variables("$a ${a}"), "x x");
         assert_eq!(
-            interpreter.expand_variables("~/bin/script"),
-            "/test/home/bin/script"
+            interpreter.expand_variables("${#a[1]} ${a[7]:-none}"),
+            "3 none"
         );
 
-        // Test tilde with spaces
+        // "${a[@]}" keeps the elements apart, "${a[*]}" joins them
         assert_eq!(
-            interpreter.expand_variables("~ /other/path"),
-            "/test/home /other/path"
+            interpreter.expand_word("\"${a[@]}\""),
+            vec!["x", "y z", "w"]
         );
+        assert_eq!(interpreter.expand_word("\"${a[*]}\""), vec!["x y z w"]);
+        assert_eq!(interpreter.expand_word("${a[@]}"), vec!["x", "y", "z", "w"]);
+
+        interpreter.execute("a[i+4]=v; a=first").unwrap();
+        assert_eq!(interpreter.expand_variables("${!a[@]}"), "0 1 2 5");
         assert_eq!(
-            interpreter.expand_variables("path ~ more"),
-            "path /test/home more"
+            interpreter.expand_word("\"${a[@]}\""),
+            vec!["first", "y z", "w", "v"]
         );
 
-        // Test tilde at end of string
+        // Assigning a list replaces the whole array
+        interpreter.execute("a=(one)").unwrap();
+        assert_eq!(interpreter.expand_variables("${#a[@]} ${a[@]}"), "1 one");
+
+        // += appends after the last index, and to the value of an element
+        interpreter
+            .execute("a[3]=three; a+=(four 'f v'); a[0]+=-1; s=x; s+=(y); s+=z")
+            .unwrap();
+        assert_eq!(interpreter.expand_variables("${!a[@]}"), "0 3 4 5");
         assert_eq!(
-            interpreter.expand_variables("prefix ~"),
-            "prefix /test/home"
+            interpreter.expand_word("\"${a[@]}\""),
+            vec!["one-1", "three", "four", "f v"]
         );
-
-        // Restore original HOME
-        unsafe {
-            if let Some(home) = original_home {
-                std::env::set_var("HOME", home);
-            } else {
-                std::env::remove_var("HOME");
-            }
-        }
+        assert_eq!(interpreter.expand_variables("${s[@]}"), "xz y");
     }
 
     #[test]
-    fn test_tilde_expansion_edge_cases() {
+    fn test_associative_arrays() {
         let mut interpreter = Interpreter::new();
+        interpreter
+            .execute("declare -A map; map[key]=value; k='two words'; map[$k]=x; map[1+1]=y")
+            .unwrap();
 
-        // Set a known HOME directory for testing
-        let original_home = std::env::var("HOME").ok();
-        unsafe {
-            std::env::set_var("HOME", "/test/home");
-        }
+        // Subscripts are strings, not arithmetic
+        assert_eq!(
+            interpreter.expand_variables("${map[key]} ${map[$k]} ${map[1+1]}"),
+            "value x y"
+        );
+        assert_eq!(interpreter.expand_variables("${map[missing]}"), "");
+        assert_eq!(
+            interpreter.expand_word("\"${!map[@]}\""),
+            vec!["1+1", "key", "two words"]
+        );
 
-        // Test tilde not at word boundary (should not expand)
-        assert_eq!(interpreter.expand_variables("file~backup"), "file~backup");
-        assert_eq!(interpreter.expand_variables("test~"), "test~");
+        // A compound assignment replaces the whole map
+        interpreter
+            .execute("map=([a]=1 [b]=\"2 3\" [$k]=$k)")
+            .unwrap();
+        assert_eq!(interpreter.expand_variables("${!map[@]}"), "a b two words");
+        assert_eq!(
+            interpreter.expand_word("\"${map[@]}\""),
+            vec!["1", "2 3", "two words"]
+        );
+        assert_eq!(interpreter.expand_variables("${#map[@]}"), "3");
 
-        // Test multiple tildes
+        // += adds keys to the map and appends to the value of one
+        interpreter
+            .execute("map+=([c]=4 [a]=0); map[c]+=5")
+            .unwrap();
+        assert_eq!(
+            interpreter.expand_variables("${!map[@]} ${map[a]} ${map[c]}"),
+            "a b c two words 0 45"
+        );
+
+        // Keys may hold brackets when quoted or expanded, and elements are kept out of
+        // the variables that child processes inherit
+        interpreter
+            .execute("declare -A odd; odd[\"a]b\"]=1; k='c]'; odd[$k]=2; odd=([\"x y\"]=3 [$k]=4)")
+            .unwrap();
+        assert_eq!(
+            interpreter.expand_variables("${odd[\"x y\"]} ${odd[c]]}"),
+            "3 4"
+        );
         assert_eq!(
-            interpreter.expand_variables("~ and ~"),
-            "/test/home and /test/home"
+            interpreter.declare_definition("odd").unwrap(),
+            "declare -A odd=([\"c]\"]=\"4\" [\"x y\"]=\"3\")"
         );
+        assert!(interpreter.variables.keys().all(|name| !name.contains('[')));
+
+        // Indexed arrays mix subscripts with the next free index
+        interpreter.execute("i=(a [5]=f g [1]=b)").unwrap();
         assert_eq!(
-            interpreter.expand_variables("~/a:~/b"),
-            "/test/home/a:/test/home/b"
+            interpreter.expand_variables("${!i[@]} ${i[@]}"),
+            "0 1 5 6 a b f g"
         );
+    }
 
-        // Test tilde with other expansions
+    #[test]
+    fn test_parameter_substring_expansion() {
+        let mut interpreter = Interpreter::new();
+        interpreter.execute("s=hello; n=1").unwrap();
+
+        assert_eq!(interpreter.expand_variables("${s:1:3}"), "ell");
+        assert_eq!(interpreter.expand_variables("${s:2}"), "llo");
+        // A negative offset counts from the end; the space keeps it apart from :-
+        assert_eq!(interpreter.expand_variables("${s: -2}"), "lo");
+        assert_eq!(interpreter.expand_variables("${s:-2}"), "hello");
+        assert_eq!(interpreter.expand_variables("${unset:-1}"), "1");
+        assert_eq!(interpreter.expand_variables("${s: -1}"), "o");
+        assert_eq!(interpreter.expand_variables("${s:0-1}"), "o");
+        assert_eq!(interpreter.expand_variables("${s:(-3):2}"), "ll");
+        // Offsets and lengths are arithmetic, and a negative length trims the end
+        assert_eq!(interpreter.expand_variables("${s:n+1:2}"), "ll");
+        assert_eq!(interpreter.expand_variables("${s:1:-1}"), "ell");
+        assert_eq!(interpreter.expand_variables("${s:9}"), "");
+    }
+
+    #[test]
+    fn test_histignore_skips_matching_commands() {
+        let mut interpreter = Interpreter::new();
         interpreter
             .variables
-            .insert("VAR".to_string(), "value".to_string());
-        assert_eq!(interpreter.expand_variables("~/$VAR"), "/test/home/value");
-        assert_eq!(interpreter.expand_variables("$VAR/~"), "value/~"); // ~ not at start of path component
+            .insert("HISTIGNORE".to_string(), "ls*:exit:[bf]g".to_string());
 
-        // Test empty string
-        assert_eq!(interpreter.expand_variables(""), "");
+        assert!(!interpreter.add_to_history("ls -la"));
+        assert!(!interpreter.add_to_history("exit"));
+        assert!(!interpreter.add_to_history("fg"));
+        assert!(interpreter.add_to_history("echo ls"));
+        // Patterns must match the whole command
+        assert!(interpreter.add_to_history("exit 1"));
+        assert_eq!(interpreter.history, vec!["echo ls", "exit 1"]);
 
-        // Restore original HOME
-        unsafe {
-            if let Some(home) = original_home {
-                std::env::set_var("HOME", home);
-            } else {
-                std::env::remove_var("HOME");
-            }
-        }
+        // Blank lines and immediate repeats are never recorded
+        interpreter.variables.remove("HISTIGNORE");
+        assert!(!interpreter.add_to_history("  "));
+        assert!(!interpreter.add_to_history("exit 1"));
+        assert!(interpreter.add_to_history("ls"));
     }
 
     #[test]
-    fn test_tilde_expansion_no_home() {
-        let interpreter = Interpreter::new();
-
-        // Save the original HOME environment variable
-        let original_home = std::env::var("HOME").ok();
-
-        // Remove HOME environment variable and ensure it stays removed during the test
-        unsafe {
-            std::env::remove_var("HOME");
-        }
+    fn test_reverse_search_history() {
+        let mut interpreter = Interpreter::new();
+        interpreter.history.clear();
 
-        // Verify HOME is actually removed
-        assert!(
-            std::env::var("HOME").is_err(),
-            "HOME should be unset for this test"
+        // An empty history fails the search instead of underflowing, and accepting the
+        // failed search keeps the line that was being edited
+        assert_eq!(interpreter.reverse_search_history("", 0), None);
+        assert_eq!(interpreter.reverse_search_history("ls", usize::MAX), None);
+        let found = interpreter.reverse_search_history("ls", interpreter.history.len());
+        assert_eq!(
+            interpreter.reverse_search_result(found, "echo original"),
+            "echo original"
         );
 
-        // When HOME is not set, tilde should remain unchanged
-        let result1 = interpreter.expand_variables("~");
-        let result2 = interpreter.expand_variables("~/Documents");
-
-        // Restore original HOME immediately to avoid affecting other tests
-        unsafe {
-            if let Some(home) = original_home {
-                std::env::set_var("HOME", home);
-            }
-        }
+        interpreter.history = vec!["ls a".to_string(), "pwd".to_string(), "ls b".to_string()];
+        let newest = interpreter.reverse_search_history("ls", interpreter.history.len());
+        assert_eq!(newest, Some(2));
+        assert_eq!(
+            interpreter.reverse_search_result(newest, "echo original"),
+            "ls b"
+        );
+        // Repeating Ctrl-R continues from before the current match, down to the oldest entry
+        assert_eq!(interpreter.reverse_search_history("ls", 2), Some(0));
+        assert_eq!(interpreter.reverse_search_history("ls", 0), None);
+    }
 
-        // Now check the results
+    #[test]
+    fn test_echo_escapes_in_posix_mode() {
+        assert_eq!(expand_echo_escapes("a\\tb\\n"), (b"a\tb\n".to_vec(), false));
         assert_eq!(
-            result1, "~",
-            "Tilde should remain unchanged when HOME is not set"
+            expand_echo_escapes("\\0101 stop\\c ignored"),
+            (b"A stop".to_vec(), true)
         );
         assert_eq!(
-            result2, "~/Documents",
-            "Tilde path should remain unchanged when HOME is not set"
+            expand_echo_escapes("\\x41\\u00e9\\U0001F600"),
+            ("A\u{e9}\u{1F600}".as_bytes().to_vec(), false)
+        );
+        // Without hex digits the escape is printed as written
+        assert_eq!(
+            expand_echo_escapes("\\u \\xg"),
+            (b"\\u \\xg".to_vec(), false)
         );
     }
 
     #[test]
-    fn test_tilde_expansion_with_username() {
-        let interpreter = Interpreter::new();
-
-        // Set a known HOME directory for testing
-        let original_home = std::env::var("HOME").ok();
-        unsafe {
-            std::env::set_var("HOME", "/test/home");
-        }
+    fn test_posix_mode_rejects_non_portable_alias_names() {
+        let mut interpreter = Interpreter::new();
 
-        // Test ~username (should remain literal for now)
-        assert_eq!(interpreter.expand_variables("~user"), "~user");
-        assert_eq!(
-            interpreter.expand_variables("~user/Documents"),
-            "~user/Documents"
-        );
-        assert_eq!(interpreter.expand_variables("~root/bin"), "~root/bin");
+        assert_eq!(interpreter.execute("alias a.b='echo hi'").unwrap(), 0);
+        assert!(interpreter.aliases.contains_key("a.b"));
 
-        // But ~ alone should still expand
-        assert_eq!(interpreter.expand_variables("~"), "/test/home");
+        interpreter.execute("set -o posix").unwrap();
+        assert!(interpreter.shell_options.contains("posix"));
+        assert_eq!(interpreter.execute("alias c.d='echo hi'").unwrap(), 1);
+        assert!(!interpreter.aliases.contains_key("c.d"));
 
-        // Restore original HOME
-        unsafe {
-            if let Some(home) = original_home {
-                std::env::set_var("HOME", home);
-            } else {
-                std::env::remove_var("HOME");
-            }
-        }
+        interpreter.execute("set +o posix").unwrap();
+        assert_eq!(interpreter.execute("alias c.d='echo hi'").unwrap(), 0);
     }
 
     #[test]
-    fn test_tilde_expansion_in_export() {
-        let mut interpreter = Interpreter::new();
+    fn test_test_command_negation() {
+        use tempfile::tempdir;
 
-        // Set a known HOME directory for testing
-        let original_home = std::env::var("HOME").ok();
-        unsafe {
-            std::env::set_var("HOME", "/test/home");
-        }
+        let temp_dir = tempdir().unwrap();
+        let existing = temp_dir.path().join("existing");
+        fs::write(&existing, "content").unwrap();
+        let missing = temp_dir.path().join("missing");
+
+        let mut interpreter = Interpreter::new();
+        let existing = existing.display();
+        let missing = missing.display();
 
-        // Test tilde expansion in export statements
-        interpreter.execute("export GOPATH=~/go").unwrap();
         assert_eq!(
-            interpreter.variables.get("GOPATH"),
-            Some(&"/test/home/go".to_string())
+            interpreter
+                .execute(&format!("[ ! -f {existing} ]"))
+                .unwrap(),
+            1
         );
-
-        interpreter
-            .execute("export PATH=~/bin:/usr/local/bin")
-            .unwrap();
         assert_eq!(
-            interpreter.variables.get("PATH"),
-            Some(&"/test/home/bin:/usr/local/bin".to_string())
+            interpreter.execute(&format!("[ ! -f {missing} ]")).unwrap(),
+            0
+        );
+        assert_eq!(
+            interpreter
+                .execute(&format!("test ! -f {missing}"))
+                .unwrap(),
+            0
         );
 
-        // Test mixed tilde and variable expansion
+        // `!` as the left operand of a binary operator is just a string
+        assert_eq!(interpreter.execute("[ ! = ! ]").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_ansi_c_quoted_assignment() {
+        let mut interpreter = Interpreter::new();
         interpreter
-            .execute("export COMPLEX=~/bin:$GOPATH/bin")
+            .execute("tab=$'\\t'; len=${#tab}; nl=$'a\\nb'; declare q=$'it\\'s $HOME'")
             .unwrap();
+
+        assert_eq!(interpreter.variables.get("tab"), Some(&"\t".to_string()));
+        assert_eq!(interpreter.variables.get("len"), Some(&"1".to_string()));
+        assert_eq!(interpreter.variables.get("nl"), Some(&"a\nb".to_string()));
+        // The decoded value is not expanded again
         assert_eq!(
-            interpreter.variables.get("COMPLEX"),
-            Some(&"/test/home/bin:/test/home/go/bin".to_string())
+            interpreter.variables.get("q"),
+            Some(&"it's $HOME".to_string())
         );
-
-        // Restore original HOME
-        unsafe {
-            if let Some(home) = original_home {
-                std::env::set_var("HOME", home);
-            } else {
-                std::env::remove_var("HOME");
-            }
-        }
     }
 
     #[test]
-    fn test_tilde_expansion_in_commands() {
-        let interpreter = Interpreter::new();
+    fn test_test_command_dash_a() {
+        use tempfile::tempdir;
 
-        // Set a known HOME directory for testing
-        let original_home = std::env::var("HOME").ok();
-        unsafe {
-            std::env::set_var("HOME", "/test/home");
-        }
+        let temp_dir = tempdir().unwrap();
+        let existing = temp_dir.path().join("existing");
+        fs::write(&existing, "content").unwrap();
+        let missing = temp_dir.path().join("missing");
 
-        // Test that tilde expansion works in command arguments
-        // We can't easily test actual command execution, but we can test variable expansion
+        let mut interpreter = Interpreter::new();
+        let existing = existing.display();
+        let missing = missing.display();
+
+        // With two arguments `-a` tests that the file exists
         assert_eq!(
-            interpreter.expand_variables("ls ~/Documents"),
-            "ls /test/home/Documents"
+            interpreter.execute(&format!("[ -a {existing} ]")).unwrap(),
+            0
         );
         assert_eq!(
-            interpreter.expand_variables("cd ~ && pwd"),
-            "cd /test/home && pwd"
+            interpreter.execute(&format!("[ -a {missing} ]")).unwrap(),
+            1
         );
+
+        // Between two operands it is the "and" connective
+        assert_eq!(interpreter.execute("[ x -a y ]").unwrap(), 0);
+        assert_eq!(interpreter.execute("[ x -a '' ]").unwrap(), 1);
+        assert_eq!(interpreter.execute("test -a -a -a").unwrap(), 0);
         assert_eq!(
-            interpreter.expand_variables("cp file ~/backup/"),
-            "cp file /test/home/backup/"
+            interpreter
+                .execute(&format!("[ -a {existing} -a -n x ]"))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            interpreter
+                .execute(&format!("[ -a {existing} -a -a {missing} ]"))
+                .unwrap(),
+            1
         );
-
-        // Restore original HOME
-        unsafe {
-            if let Some(home) = original_home {
-                std::env::set_var("HOME", home);
-            } else {
-                std::env::remove_var("HOME");
-            }
-        }
     }
 
     #[test]
-    fn test_cd_with_tilde_expansion() {
+    fn test_test_command_connectives_and_grouping() {
         let mut interpreter = Interpreter::new();
 
-        // Set a known HOME directory for testing
-        let original_home = std::env::var("HOME").ok();
-        let original_pwd = std::env::current_dir().ok();
-
-        unsafe {
-            std::env::set_var("HOME", "/tmp");
-        }
+        interpreter.execute("x=foo").unwrap();
+        assert_eq!(
+            interpreter
+                .execute("[ -n \"$x\" -a \"$x\" = foo ]")
+                .unwrap(),
+            0
+        );
+        interpreter.execute("x=").unwrap();
+        assert_eq!(
+            interpreter
+                .execute("[ -n \"$x\" -a \"$x\" = foo ]")
+                .unwrap(),
+            1
+        );
+        assert_eq!(interpreter.execute("[ ! -e /nonexistent ]").unwrap(), 0);
 
-        // Test cd with tilde
-        let result = interpreter.execute("cd ~");
-        assert!(result.is_ok());
+        // -a binds tighter than -o, and parentheses group
+        assert_eq!(interpreter.execute("[ x -o '' -a '' ]").unwrap(), 0);
+        assert_eq!(interpreter.execute("[ '(' x -o '' ')' -a '' ]").unwrap(), 1);
+        assert_eq!(
+            interpreter
+                .execute("[ ! '(' 1 -eq 2 -o a = b ')' ]")
+                .unwrap(),
+            0
+        );
+        assert_eq!(interpreter.execute("test '' -o ! -n ''").unwrap(), 0);
 
-        // Check that PWD was updated (handle symlinks like /tmp -> /private/tmp on macOS)
-        let pwd = interpreter.variables.get("PWD").unwrap();
-        assert!(pwd.ends_with("/tmp") || pwd == "/tmp");
+        // Malformed expressions are errors rather than false
+        assert_eq!(interpreter.execute("[ '(' x -o y ]").unwrap(), 2);
+        assert_eq!(interpreter.execute("[ a b c d e ]").unwrap(), 2);
+    }
 
-        // Test cd with tilde and subdirectory
-        // Note: We can't actually test this without creating the directory,
-        // but we can test that the expansion happens correctly
+    #[test]
+    fn test_test_command_orders_strings_and_files() {
+        let temp_dir = tempdir().unwrap();
+        let old = temp_dir.path().join("old");
+        let new = temp_dir.path().join("new");
+        fs::write(&old, "").unwrap();
+        fs::File::create(&new)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        std::os::unix::fs::symlink(&new, temp_dir.path().join("link")).unwrap();
 
-        // Restore original state
-        unsafe {
-            if let Some(home) = original_home {
-                std::env::set_var("HOME", home);
-            } else {
-                std::env::remove_var("HOME");
-            }
-        }
+        let mut interpreter = Interpreter::new();
+        let dir = temp_dir.path().display();
+        assert_eq!(interpreter.execute("[ abc \\< abd ]").unwrap(), 0);
+        assert_eq!(interpreter.execute("[ abc '>' abd ]").unwrap(), 1);
+        assert_eq!(interpreter.execute("test B '<' a").unwrap(), 0);
 
-        if let Some(pwd) = original_pwd {
-            let _ = std::env::set_current_dir(pwd);
-        }
+        let mut status = |left: &str, operator: &str, right: &str| {
+            interpreter
+                .execute(&format!("[ {dir}/{left} {operator} {dir}/{right} ]"))
+                .unwrap()
+        };
+        assert_eq!(status("new", "-nt", "old"), 0);
+        assert_eq!(status("new", "-ot", "old"), 1);
+        assert_eq!(status("old", "-ot", "new"), 0);
+        // A missing file is older than any existing one
+        assert_eq!(status("old", "-nt", "missing"), 0);
+        assert_eq!(status("missing", "-ot", "old"), 0);
+        assert_eq!(status("link", "-ef", "new"), 0);
+        assert_eq!(status("old", "-ef", "new"), 1);
+        assert_eq!(status("missing", "-ef", "missing"), 1);
     }
 
     #[test]
-    fn test_flash_version_variable() {
-        let interpreter = Interpreter::new();
+    fn test_run_ast_with_positional_args() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_args(vec!["script".to_string(), "outer".to_string()]);
+        let ast = Parser::new(Lexer::new("seen=\"$seen$1:$#;\"")).parse_script();
 
-        // Test that FLASH_VERSION is set
-        let flash_version = interpreter.variables.get("FLASH_VERSION");
-        assert!(flash_version.is_some(), "FLASH_VERSION should be set");
+        assert_eq!(interpreter.run_ast(&ast, vec!["a".to_string()]).unwrap(), 0);
+        assert_eq!(
+            interpreter
+                .run_ast(&ast, vec!["b".to_string(), "c".to_string()])
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            interpreter.variables.get("seen"),
+            Some(&"a:1;b:2;".to_string())
+        );
+        assert_eq!(interpreter.args, vec!["script", "outer"]);
+    }
 
-        let version = flash_version.unwrap();
-        assert!(!version.is_empty(), "FLASH_VERSION should not be empty");
+    #[test]
+    fn test_resolve_job_specs() {
+        let mut interpreter = Interpreter::new();
+        for (id, command) in [(1, "sleep 100"), (2, "make all"), (3, "tail -f log")] {
+            interpreter.jobs.push(Job {
+                id,
+                pid: 0,
+                command: command.to_string(),
+                inherited: false,
+            });
+        }
 
-        // Test that it matches the cargo package version
-        assert_eq!(version, env!("CARGO_PKG_VERSION"));
+        // The most recent job is current, the one before it previous
+        assert_eq!(interpreter.resolve_job_spec("%+"), Ok(2));
+        assert_eq!(interpreter.resolve_job_spec("%%"), Ok(2));
+        assert_eq!(interpreter.resolve_job_spec("%-"), Ok(1));
+        assert_eq!(interpreter.resolve_job_spec("%1"), Ok(0));
+        assert_eq!(interpreter.resolve_job_spec("%sl"), Ok(0));
+        assert_eq!(interpreter.resolve_job_spec("%?all"), Ok(1));
 
-        // Test that we can access it via variable expansion
-        let expanded = interpreter.expand_variables("$FLASH_VERSION");
-        assert_eq!(expanded, env!("CARGO_PKG_VERSION"));
+        assert!(interpreter.resolve_job_spec("%4").is_err());
+        assert!(interpreter.resolve_job_spec("%nope").is_err());
+        interpreter.jobs[2].command = "make test".to_string();
+        assert_eq!(
+            interpreter.resolve_job_spec("%make"),
+            Err("%make: ambiguous job spec".to_string())
+        );
+    }
 
-        // Test that we can access it in command substitution context
-        let expanded_braces = interpreter.expand_variables("${FLASH_VERSION}");
-        assert_eq!(expanded_braces, env!("CARGO_PKG_VERSION"));
+    #[test]
+    fn test_signal_number() {
+        assert_eq!(signal_number("9"), Some(9));
+        assert_eq!(signal_number("KILL"), Some(libc::SIGKILL));
+        assert_eq!(signal_number("sigterm"), Some(libc::SIGTERM));
+        assert_eq!(signal_number("BOGUS"), None);
+    }
+
+    #[test]
+    fn test_signal_name() {
+        assert_eq!(signal_name(libc::SIGKILL), Some("KILL"));
+        assert_eq!(signal_name(128 + libc::SIGTERM), Some("TERM"));
+        assert_eq!(signal_name(0), None);
+        // Every name maps back to its own number
+        for (name, number) in SIGNAL_NAMES {
+            assert_eq!(signal_name(*number), Some(*name));
+        }
     }
 
     #[test]
@@ -6688,6 +13447,23 @@ mod tests {
             result,
             vec!["1", "2", "3", "4", "5", "6", "7", "8", "9", "10"]
         );
+
+        // A leading zero pads every number to the widest endpoint
+        let result = interpreter.expand_brace_patterns("{08..11}");
+        assert_eq!(result, vec!["08", "09", "10", "11"]);
+
+        let result = interpreter.expand_brace_patterns("{1..003}");
+        assert_eq!(result, vec!["001", "002", "003"]);
+
+        // A third component is the step, whose sign is ignored
+        let result = interpreter.expand_brace_patterns("{0..20..5}");
+        assert_eq!(result, vec!["0", "5", "10", "15", "20"]);
+
+        let result = interpreter.expand_brace_patterns("{10..1..-4}");
+        assert_eq!(result, vec!["10", "6", "2"]);
+
+        let result = interpreter.expand_brace_patterns("{1..2..x}");
+        assert_eq!(result, vec!["{1..2..x}"]);
     }
 
     #[test]
@@ -6713,6 +13489,10 @@ mod tests {
         // Mixed case (should not work - only single case ranges)
         let result = interpreter.expand_brace_patterns("{a..Z}");
         assert_eq!(result, vec!["{a..Z}"]);
+
+        // Stepped character range
+        let result = interpreter.expand_brace_patterns("{a..z..5}");
+        assert_eq!(result, vec!["a", "f", "k", "p", "u", "z"]);
     }
 
     #[test]
@@ -6783,6 +13563,19 @@ mod tests {
             result,
             vec!["file1.txt", "file1.log", "file2.txt", "file2.log"]
         );
+
+        // Commas inside a nested group do not split the outer one
+        let result = interpreter.expand_brace_patterns("{a,{b,c}d}");
+        assert_eq!(result, vec!["a", "bd", "cd"]);
+
+        let result = interpreter.expand_brace_patterns("x{a,{b,c}}y");
+        assert_eq!(result, vec!["xay", "xby", "xcy"]);
+
+        let result = interpreter.expand_brace_patterns("a{b,c{d,e{f,g}}}h");
+        assert_eq!(result, vec!["abh", "acdh", "acefh", "acegh"]);
+
+        let result = interpreter.expand_brace_patterns("{{a,b},{1..2}}");
+        assert_eq!(result, vec!["a", "b", "1", "2"]);
     }
 
     #[test]
@@ -6820,6 +13613,22 @@ mod tests {
         assert_eq!(result, vec!["{1...5}"]);
     }
 
+    #[test]
+    fn test_brace_expansion_skips_degenerate_braces() {
+        let interpreter = Interpreter::new();
+
+        // A degenerate group stays literal while later groups still expand
+        let result = interpreter.expand_brace_patterns("{a}{b,c}");
+        assert_eq!(result, vec!["{a}b", "{a}c"]);
+
+        let result = interpreter.expand_brace_patterns("x{}{1,2}");
+        assert_eq!(result, vec!["x{}1", "x{}2"]);
+
+        // Expansion proceeds left to right like bash
+        let result = interpreter.expand_brace_patterns("a{b,c}d{1,2}");
+        assert_eq!(result, vec!["abd1", "abd2", "acd1", "acd2"]);
+    }
+
     #[test]
     fn test_brace_expansion_integration_with_commands() {
         let mut interpreter = Interpreter::new();
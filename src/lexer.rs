@@ -5,6 +5,8 @@
  * under GNU General Public License v3.0.
  */
 
+use std::collections::VecDeque;
+
 /// Token types that can be produced by the lexer
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenKind {
@@ -13,6 +15,8 @@ pub enum TokenKind {
     Pipe,                     // |
     Semicolon,                // ;
     DoubleSemicolon,          // ;;
+    SemicolonAnd,             // ;&
+    DoubleSemicolonAnd,       // ;;&
     Newline,                  // \n
     And,                      // &&
     Background,               // & (add this new token)
@@ -24,6 +28,8 @@ pub enum TokenKind {
     Less,                     // <
     Great,                    // >
     DGreat,                   // >>
+    LessAnd,                  // <&
+    GreatAnd,                 // >&
     Dollar,                   // $
     Quote,                    // "
     SingleQuote,              // '
@@ -32,6 +38,7 @@ pub enum TokenKind {
     CmdSubst,                 // $(
     ArithSubst,               // $((
     ArithCommand,             // ((
+    AnsiCString(String),      // $'...' with its escapes decoded
     ParamExpansion,           // ${
     ParamExpansionOp(String), // :-, :=, :?, :+, #, ##, %, %%
     ProcessSubstIn,           // <(
@@ -121,6 +128,95 @@ fn is_word_terminator(ch: char) -> bool {
     )
 }
 
+/// Decode the backslash escapes of an ANSI-C quoted `$'...'` string
+fn ansi_c_unescape(raw: &str) -> String {
+    let chars: Vec<char> = raw.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '\\' || i + 1 == chars.len() {
+            result.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let escape = chars[i + 1];
+        i += 2;
+        // Numeric escapes take up to a fixed number of digits in their base
+        let number = |i: &mut usize, radix: u32, max: usize| {
+            let digits: String = chars[*i..]
+                .iter()
+                .take(max)
+                .take_while(|d| d.is_digit(radix))
+                .collect();
+            *i += digits.len();
+            (!digits.is_empty()).then(|| u32::from_str_radix(&digits, radix).unwrap_or(0))
+        };
+        let decoded = match escape {
+            'a' => Some('\x07'),
+            'b' => Some('\x08'),
+            'e' | 'E' => Some('\x1b'),
+            'f' => Some('\x0c'),
+            'n' => Some('\n'),
+            'r' => Some('\r'),
+            't' => Some('\t'),
+            'v' => Some('\x0b'),
+            '\\' | '\'' | '"' | '?' => Some(escape),
+            '0'..='7' => {
+                i -= 1;
+                number(&mut i, 8, 3).and_then(|n| char::from_u32(n & 0xff))
+            }
+            'x' => number(&mut i, 16, 2).and_then(char::from_u32),
+            'u' => number(&mut i, 16, 4).and_then(char::from_u32),
+            'U' => number(&mut i, 16, 8).and_then(char::from_u32),
+            'c' if i < chars.len() => {
+                i += 1;
+                char::from_u32(chars[i - 1] as u32 & 0x1f)
+            }
+            _ => None,
+        };
+        match decoded {
+            Some(c) => result.push(c),
+            None => {
+                // Unknown escapes, and numeric ones without digits, stay as written
+                result.push('\\');
+                result.push(escape);
+            }
+        }
+    }
+    result
+}
+
+/// A here-document body that was read ahead of the line holding its operator
+#[derive(Debug, Clone)]
+struct HereDocument {
+    operator: usize,      // Input index of the `<<` operator
+    delimiter: String,    // Delimiter word as written, quotes included
+    delimiter_end: usize, // Input index just past the delimiter word
+    body: String,
+    start: usize,    // Input index of the first body line
+    body_end: usize, // Input index of the closing delimiter line
+    end: usize,      // Input index just past the closing delimiter line
+}
+
+/// Iterator returned by [`Lexer::tokens`], yielding each token with its start and
+/// end byte offsets in the input
+pub struct Tokens<'a> {
+    lexer: &'a mut Lexer,
+    offsets: Vec<usize>, // Byte offset of each input character, and of the end
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = (Token, usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (token, start, end) = self.lexer.next_spanned_token();
+        if token.kind == TokenKind::EOF {
+            return None;
+        }
+        Some((token, self.offsets[start], self.offsets[end]))
+    }
+}
+
 /// Lexer that converts input text into tokens
 #[derive(Clone)]
 pub struct Lexer {
@@ -132,6 +228,12 @@ pub struct Lexer {
     column: usize,
     in_quotes: Option<char>,
     quote_after_cmdsubst: Option<char>,
+    in_param_expansion: bool,
+    here_documents: Vec<HereDocument>,
+    queued: VecDeque<(Token, usize, usize)>,
+    blank_before: bool, // Whether blanks separated the last token from the one before it
+    quote_start: Position, // Where the quote being read was opened
+    unterminated_quote: Option<Position>, // A quote that was still open at the end of the input
 }
 
 impl Lexer {
@@ -145,6 +247,12 @@ impl Lexer {
             column: 0,
             in_quotes: None,
             quote_after_cmdsubst: None,
+            in_param_expansion: false,
+            here_documents: Vec::new(),
+            queued: VecDeque::new(),
+            blank_before: false,
+            quote_start: Position::new(1, 0),
+            unterminated_quote: None,
         };
         lexer.read_char();
         lexer
@@ -182,6 +290,10 @@ impl Lexer {
         let saved_ch = self.ch;
         let saved_line = self.line;
         let saved_column = self.column;
+        let saved_in_param_expansion = self.in_param_expansion;
+        let saved_queued = self.queued.clone();
+        let saved_blank_before = self.blank_before;
+        let saved_unterminated_quote = self.unterminated_quote;
 
         // Get the next token
         let token = self.next_token();
@@ -192,17 +304,54 @@ impl Lexer {
         self.ch = saved_ch;
         self.line = saved_line;
         self.column = saved_column;
+        self.in_param_expansion = saved_in_param_expansion;
+        self.queued = saved_queued;
+        self.blank_before = saved_blank_before;
+        self.unterminated_quote = saved_unterminated_quote;
 
         token
     }
 
+    /// Whether blanks separated the last token returned by `next_token` from the
+    /// token before it; a word continues across tokens that are not separated,
+    /// as in `a'b'"c"`
+    pub fn blank_before(&self) -> bool {
+        self.blank_before
+    }
+
+    /// Where a quote was opened if the input ended before it was closed
+    pub fn unterminated_quote(&self) -> Option<Position> {
+        self.unterminated_quote
+    }
+
     pub fn next_token(&mut self) -> Token {
+        self.blank_before = false;
+
+        // Delimiter and body of a here-document follow its operator
+        if let Some((token, _, _)) = self.queued.pop_front() {
+            return token;
+        }
+
+        // The body of ${...} is kept verbatim up to its closing brace
+        if self.in_param_expansion {
+            self.in_param_expansion = false;
+            if self.ch != '}' && self.ch != '\0' {
+                return self.read_parameter_body();
+            }
+        }
+
         if self.in_quotes.is_none() {
-            self.skip_whitespace();
+            self.blank_before = self.skip_whitespace();
         }
 
         let current_position = Position::new(self.line, self.column);
 
+        // A quote still open at the end of the input ends with it
+        if self.in_quotes.is_some() && self.ch == '\0' {
+            self.in_quotes = None;
+            self.unterminated_quote = Some(self.quote_start);
+        }
+
         // Check for quote start/end
         if (self.ch == '"' || self.ch == '\'') && self.in_quotes.is_none() {
             // Starting a quoted section
@@ -218,6 +367,7 @@ impl Lexer {
             };
 
             self.in_quotes = Some(quote_type); // Set the in_quotes state
+            self.quote_start = current_position;
             self.read_char();
             return token;
         } else if self.in_quotes.is_some() && self.ch == self.in_quotes.unwrap() {
@@ -280,10 +430,26 @@ impl Lexer {
             }
             ';' => {
                 if self.peek_char() == ';' {
+                    self.read_char();
+                    if self.peek_char() == '&' {
+                        self.read_char();
+                        Token {
+                            kind: TokenKind::DoubleSemicolonAnd,
+                            value: ";;&".to_string(),
+                            position: current_position,
+                        }
+                    } else {
+                        Token {
+                            kind: TokenKind::DoubleSemicolon,
+                            value: ";;".to_string(),
+                            position: current_position,
+                        }
+                    }
+                } else if self.peek_char() == '&' {
                     self.read_char();
                     Token {
-                        kind: TokenKind::DoubleSemicolon,
-                        value: ";;".to_string(),
+                        kind: TokenKind::SemicolonAnd,
+                        value: ";&".to_string(),
                         position: current_position,
                     }
                 } else {
@@ -313,6 +479,7 @@ impl Lexer {
             '\n' => {
                 self.line += 1;
                 self.column = 0;
+                self.skip_here_documents();
                 Token {
                     kind: TokenKind::Newline,
                     value: "\n".to_string(),
@@ -323,9 +490,13 @@ impl Lexer {
                 // Check for arithmetic command (( syntax
                 if self.peek_char() == '(' {
                     self.read_char(); // Consume second '('
+                    let value = match self.read_arithmetic_body() {
+                        Some(body) => format!("(({body}))"),
+                        None => "((".to_string(),
+                    };
                     Token {
                         kind: TokenKind::ArithCommand,
-                        value: "((".to_string(),
+                        value,
                         position: current_position,
                     }
                 } else {
@@ -349,8 +520,13 @@ impl Lexer {
                 }
             }
             '{' => {
-                // Check if this looks like brace expansion (e.g., {1..10}, {a..z})
-                if self.is_brace_expansion() {
+                // Check if this looks like brace expansion (e.g., {1..10}, {a..z}).
+                // Otherwise `{` only opens a group when followed by a blank or when it
+                // starts a function body, so `{a}` and `{}` are plain words.
+                let opens_body = self.position > 0 && self.input[self.position - 1] == ')';
+                if self.is_brace_expansion()
+                    || (!opens_body && !matches!(self.peek_char(), ' ' | '\t' | '\n' | '\0'))
+                {
                     self.read_word()
                 } else {
                     Token {
@@ -388,6 +564,7 @@ impl Lexer {
                     } else if self.peek_char() == '-' {
                         // Here document with dash <<-
                         self.read_char(); // Consume '-'
+                        self.queue_here_document(true);
                         Token {
                             kind: TokenKind::HereDocDash,
                             value: "<<-".to_string(),
@@ -395,12 +572,21 @@ impl Lexer {
                         }
                     } else {
                         // Regular here document <<
+                        self.queue_here_document(false);
                         Token {
                             kind: TokenKind::HereDoc,
                             value: "<<".to_string(),
                             position: current_position,
                         }
                     }
+                } else if self.peek_char() == '&' {
+                    // Input duplication <&
+                    self.read_char(); // Consume '&'
+                    Token {
+                        kind: TokenKind::LessAnd,
+                        value: "<&".to_string(),
+                        position: current_position,
+                    }
                 } else {
                     Token {
                         kind: TokenKind::Less,
@@ -425,6 +611,14 @@ impl Lexer {
                         value: ">(".to_string(),
                         position: current_position,
                     }
+                } else if self.peek_char() == '&' {
+                    // Output duplication >&
+                    self.read_char(); // Consume '&'
+                    Token {
+                        kind: TokenKind::GreatAnd,
+                        value: ">&".to_string(),
+                        position: current_position,
+                    }
                 } else {
                     Token {
                         kind: TokenKind::Great,
@@ -434,8 +628,15 @@ impl Lexer {
                 }
             }
             '!' => {
-                // Check for != operator
-                if self.peek_char() == '=' {
+                // $! is the last background job's process ID, not a history event
+                if self.position > 0 && self.input[self.position - 1] == '$' {
+                    Token {
+                        kind: TokenKind::Word("!".to_string()),
+                        value: "!".to_string(),
+                        position: current_position,
+                    }
+                } else if self.peek_char() == '=' {
+                    // Check for != operator
                     self.read_char(); // Consume the '='
                     Token {
                         kind: TokenKind::Word("!=".to_string()),
@@ -470,8 +671,13 @@ impl Lexer {
                 }
             }
             '[' => {
-                // Check for [[ extended test command
-                if self.peek_char() == '[' {
+                // Check for [[ extended test command; like any keyword it must stand alone,
+                // so [[:alpha:]] stays a glob word
+                let keyword_end = self
+                    .input
+                    .get(self.position + 2)
+                    .is_none_or(|c| c.is_whitespace());
+                if self.peek_char() == '[' && keyword_end {
                     self.read_char(); // Consume the second '['
                     Token {
                         kind: TokenKind::DoubleLBracket,
@@ -505,9 +711,13 @@ impl Lexer {
                     {
                         self.read_char(); // Consume first '('
                         self.read_char(); // Consume second '('
+                        let value = match self.read_arithmetic_body() {
+                            Some(body) => format!("$(({body}))"),
+                            None => "$((".to_string(),
+                        };
                         Token {
                             kind: TokenKind::ArithSubst,
-                            value: "$((".to_string(),
+                            value,
                             position: current_position,
                         }
                     } else {
@@ -519,9 +729,37 @@ impl Lexer {
                             position: current_position,
                         }
                     }
+                } else if self.peek_char() == '#' {
+                    // $# is the positional parameter count, not the start of a comment
+                    self.read_char(); // Consume the '#'
+                    Token {
+                        kind: TokenKind::Word("$#".to_string()),
+                        value: "$#".to_string(),
+                        position: current_position,
+                    }
+                } else if self.peek_char() == '\'' {
+                    // ANSI-C quoting $'...', with the escapes decoded here
+                    self.read_char(); // Consume the opening quote
+                    let mut raw = String::new();
+                    self.read_char();
+                    while self.ch != '\'' && self.ch != '\0' {
+                        if self.ch == '\\' && self.peek_char() != '\0' {
+                            raw.push(self.ch);
+                            self.read_char();
+                        }
+                        raw.push(self.ch);
+                        self.read_char();
+                    }
+                    let value = ansi_c_unescape(&raw);
+                    Token {
+                        kind: TokenKind::AnsiCString(value.clone()),
+                        value,
+                        position: current_position,
+                    }
                 } else if self.peek_char() == '{' {
                     // Parameter expansion ${
                     self.read_char(); // Consume the '{'
+                    self.in_param_expansion = true;
                     Token {
                         kind: TokenKind::ParamExpansion,
                         value: "${".to_string(),
@@ -1064,6 +1302,11 @@ impl Lexer {
                     self.read_char();
                 }
 
+                // We moved ahead one character, so step back
+                self.position -= 1;
+                self.read_position -= 1;
+                self.column -= 1;
+
                 return Token {
                     kind: TokenKind::Word(word.clone()),
                     value: word,
@@ -1087,6 +1330,11 @@ impl Lexer {
                 }
                 break; // Exit the main loop after handling the argument
             }
+            // A '#' inside a word, as in `a#b` or `16#ff`, does not start a comment
+            else if self.ch == '#' && !word.is_empty() {
+                word.push(self.ch);
+                self.read_char();
+            }
             // Check for other word terminators
             else if is_word_terminator(self.ch) {
                 break;
@@ -1125,9 +1373,18 @@ impl Lexer {
                     self.read_char();
                 }
 
-                // Read until closing bracket
+                // Read until closing bracket; quotes keep a subscript like ["a]b"] together
                 while self.ch != ']' && self.ch != '\0' && !self.ch.is_whitespace() {
-                    word.push(self.ch);
+                    if self.ch == '"' || self.ch == '\'' {
+                        let quote = self.ch;
+                        self.read_char();
+                        while self.ch != quote && self.ch != '\0' {
+                            word.push(self.ch);
+                            self.read_char();
+                        }
+                    } else {
+                        word.push(self.ch);
+                    }
                     self.read_char();
                 }
 
@@ -1137,6 +1394,9 @@ impl Lexer {
                     self.read_char();
                 }
             }
+            // A backslash-newline continues the word on the next line
+            else if self.skip_line_continuation() {
+            }
             // Handle escape sequences
             else if self.ch == '\\' {
                 // Look at the next character
@@ -1276,9 +1536,17 @@ impl Lexer {
 
         // Keep reading until we hit the closing quote or EOF
         while self.ch != quote_char && self.ch != '\0' {
-            // Handle escaped quotes
-            if self.ch == '\\' && self.peek_char() == quote_char {
-                self.read_char(); // Skip the backslash
+            // Inside double quotes a backslash escapes the quote, and an escaped
+            // backslash can't escape it; inside single quotes it is an ordinary character
+            if quote_char == '"' && self.ch == '\\' {
+                match self.peek_char() {
+                    '"' => self.read_char(), // Skip the backslash
+                    '\\' => {
+                        content.push(self.ch);
+                        self.read_char();
+                    }
+                    _ => {}
+                }
             }
 
             if self.ch == '\n' {
@@ -1297,10 +1565,94 @@ impl Lexer {
         }
     }
 
-    fn skip_whitespace(&mut self) {
-        while self.ch.is_whitespace() && self.ch != '\n' {
+    /// Skip blanks and line continuations, returning whether any blanks were skipped
+    fn skip_whitespace(&mut self) -> bool {
+        let mut blanks = false;
+        loop {
+            if self.ch.is_whitespace() && self.ch != '\n' {
+                blanks = true;
+                self.read_char();
+            } else if !self.skip_line_continuation() {
+                return blanks;
+            }
+        }
+    }
+
+    /// Step over a backslash-newline, which joins the next line onto this one
+    fn skip_line_continuation(&mut self) -> bool {
+        if self.ch != '\\' || self.peek_char() != '\n' {
+            return false;
+        }
+        self.read_char(); // Skip the backslash
+        self.line += 1;
+        self.column = 0;
+        self.read_char(); // Skip the newline
+        true
+    }
+
+    // Read the raw body of ${...}, leaving the closing brace as the next token
+    fn read_parameter_body(&mut self) -> Token {
+        let position = Position::new(self.line, self.column);
+        let mut body = String::new();
+        let mut depth = 0;
+        let mut quote: Option<char> = None;
+
+        while self.ch != '\0' {
+            match (quote, self.ch) {
+                (None, '}') if depth == 0 => break,
+                (None, '{') => depth += 1,
+                (None, '}') => depth -= 1,
+                (None, '\'' | '"') => quote = Some(self.ch),
+                (Some(q), c) if c == q => quote = None,
+                (q, '\\') if q != Some('\'') && self.peek_char() != '\0' => {
+                    body.push(self.ch);
+                    self.read_char();
+                }
+                _ => {}
+            }
+            if self.ch == '\n' {
+                self.line += 1;
+                self.column = 0;
+            }
+            body.push(self.ch);
             self.read_char();
         }
+
+        Token {
+            kind: TokenKind::Word(body.clone()),
+            value: body,
+            position,
+        }
+    }
+
+    // Read the source text of an arithmetic expression after `((`, leaving the
+    // closing `))` as the current character so that operators such as `<<=` are
+    // never taken apart; nothing is consumed when the parentheses do not close
+    // as a pair, as in `((cd dir) && ls)`
+    fn read_arithmetic_body(&mut self) -> Option<String> {
+        let start = self.read_position;
+        let mut depth = 0;
+        let mut end = start;
+        loop {
+            match self.input.get(end)? {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' if self.input.get(end + 1) == Some(&')') => break,
+                ')' => return None,
+                _ => {}
+            }
+            end += 1;
+        }
+
+        let body: String = self.input[start..end].iter().collect();
+        while self.read_position <= end + 1 {
+            if self.ch == '\n' {
+                self.line += 1;
+                self.column = 0;
+            }
+            self.read_char();
+        }
+        Some(body)
     }
 
     // Parse parameter expansion content after ${
@@ -1426,6 +1778,181 @@ impl Lexer {
         tokens
     }
 
+    /// Iterate over the remaining tokens, up to but not including `EOF`, together with
+    /// the byte offsets in the input where each one starts and ends
+    pub fn tokens(&mut self) -> Tokens<'_> {
+        let mut offsets = Vec::with_capacity(self.input.len() + 1);
+        let mut offset = 0;
+        for c in &self.input {
+            offsets.push(offset);
+            offset += c.len_utf8();
+        }
+        offsets.push(offset);
+        Tokens {
+            lexer: self,
+            offsets,
+        }
+    }
+
+    /// The next token with the input indices (in characters) of its start and end
+    fn next_spanned_token(&mut self) -> (Token, usize, usize) {
+        if let Some(&(_, start, end)) = self.queued.front() {
+            return (self.next_token(), start, end);
+        }
+        if self.in_quotes.is_none() && !self.in_param_expansion {
+            self.skip_whitespace();
+        }
+        let start = self.position.min(self.input.len());
+        let token = self.next_token();
+        let end = match token.kind {
+            // A newline steps over the here-document bodies that follow it, and a
+            // here-document operator over its delimiter
+            TokenKind::Newline | TokenKind::HereDoc | TokenKind::HereDocDash => {
+                start + token.value.len()
+            }
+            _ => self.position.min(self.input.len()).max(start),
+        };
+        (token, start, end)
+    }
+
+    /// Read the delimiter after a `<<` operator and the body on the lines that follow
+    /// the current one, queueing them as the next tokens. The body is skipped when the
+    /// lexer reaches the end of the operator's line.
+    fn queue_here_document(&mut self, strip_tabs: bool) {
+        let operator = self.position;
+        let document = match self.here_documents.iter().find(|d| d.operator == operator) {
+            Some(document) => document.clone(),
+            None => match self.scan_here_document(operator, strip_tabs) {
+                Some(document) => {
+                    self.here_documents.push(document.clone());
+                    document
+                }
+                None => return,
+            },
+        };
+
+        // Leave the lexer on the last character of the delimiter
+        let delimiter_start = document.delimiter_end - document.delimiter.chars().count();
+        let delimiter_position =
+            Position::new(self.line, self.column + delimiter_start - self.position);
+        while self.read_position < document.delimiter_end {
+            self.read_char();
+        }
+
+        self.queued.push_back((
+            Token {
+                kind: TokenKind::Word(document.delimiter.clone()),
+                value: document.delimiter,
+                position: delimiter_position,
+            },
+            delimiter_start,
+            document.delimiter_end,
+        ));
+        self.queued.push_back((
+            Token {
+                kind: TokenKind::HereDocContent(document.body.clone()),
+                value: document.body,
+                position: delimiter_position,
+            },
+            document.start,
+            document.body_end,
+        ));
+    }
+
+    fn scan_here_document(&self, operator: usize, strip_tabs: bool) -> Option<HereDocument> {
+        let input = &self.input;
+        let mut index = self.read_position;
+        while index < input.len() && (input[index] == ' ' || input[index] == '\t') {
+            index += 1;
+        }
+
+        // The delimiter ends at unquoted whitespace or an operator character
+        let delimiter_start = index;
+        let mut quote: Option<char> = None;
+        while index < input.len() {
+            let ch = input[index];
+            match quote {
+                Some(q) if ch == q => quote = None,
+                Some(_) => {}
+                None if ch == '\'' || ch == '"' => quote = Some(ch),
+                None if ch == '\\' => index += 1,
+                None if ch.is_whitespace()
+                    || matches!(ch, ';' | '|' | '&' | '<' | '>' | '(' | ')') =>
+                {
+                    break;
+                }
+                None => {}
+            }
+            index += 1;
+        }
+        let delimiter_end = index.min(input.len());
+        if delimiter_end == delimiter_start {
+            return None;
+        }
+        let delimiter: String = input[delimiter_start..delimiter_end].iter().collect();
+        let word: String = delimiter
+            .chars()
+            .filter(|c| !matches!(c, '\'' | '"' | '\\'))
+            .collect();
+
+        // The body starts on the next line, after any earlier here-documents on it
+        while index < input.len() && input[index] != '\n' {
+            index += 1;
+        }
+        let mut start = (index + 1).min(input.len());
+        while let Some(document) = self.here_documents.iter().find(|d| d.start == start) {
+            start = document.end;
+        }
+
+        let mut body = String::new();
+        let mut index = start;
+        let mut body_end = input.len();
+        while index < input.len() {
+            let line_end = input[index..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(input.len(), |offset| index + offset);
+            let mut line: String = input[index..line_end].iter().collect();
+            if strip_tabs {
+                line = line.trim_start_matches('\t').to_string();
+            }
+            let line_start = index;
+            index = (line_end + 1).min(input.len());
+            if line == word {
+                body_end = line_start;
+                break;
+            }
+            body.push_str(&line);
+            body.push('\n');
+        }
+
+        Some(HereDocument {
+            operator,
+            delimiter,
+            delimiter_end,
+            body,
+            start,
+            body_end,
+            end: index,
+        })
+    }
+
+    /// Step over the here-document bodies that begin right after the current newline
+    fn skip_here_documents(&mut self) {
+        while let Some(document) = self
+            .here_documents
+            .iter()
+            .find(|d| d.start == self.read_position && d.end > d.start)
+        {
+            let end = document.end;
+            self.line += self.input[self.read_position..end]
+                .iter()
+                .filter(|&&c| c == '\n')
+                .count();
+            self.read_position = end;
+        }
+    }
+
     // Parse here-document content
     pub fn read_here_document(&mut self, delimiter: &str, dash_variant: bool) -> String {
         let mut content = String::new();
@@ -1747,8 +2274,7 @@ mod lexer_tests {
             TokenKind::Great,
             TokenKind::Word("output.txt".to_string()),
             TokenKind::Word("2".to_string()),
-            TokenKind::Great,
-            TokenKind::Background,
+            TokenKind::GreatAnd,
             TokenKind::Word("1".to_string()),
         ];
         test_tokens(input, expected);
@@ -2139,6 +2665,24 @@ mod lexer_tests {
         test_tokens(input, expected);
     }
 
+    #[test]
+    fn test_degenerate_braces_are_words() {
+        let input = "echo {a} {} {a}{b,c}; { echo hi; }";
+        let expected = vec![
+            TokenKind::Word("echo".to_string()),
+            TokenKind::Word("{a}".to_string()),
+            TokenKind::Word("{}".to_string()),
+            TokenKind::Word("{a}{b,c}".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::LBrace,
+            TokenKind::Word("echo".to_string()),
+            TokenKind::Word("hi".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::RBrace,
+        ];
+        test_tokens(input, expected);
+    }
+
     #[test]
     fn test_function_call() {
         let input = "greet; greet arg1 arg2";
@@ -2340,17 +2884,6 @@ mod lexer_tests {
         let expected = vec![
             TokenKind::For,
             TokenKind::ArithCommand,
-            TokenKind::Word("i".to_string()),
-            TokenKind::Assignment,
-            TokenKind::Word("0".to_string()),
-            TokenKind::Semicolon,
-            TokenKind::Word("i".to_string()),
-            TokenKind::Less,
-            TokenKind::Word("5".to_string()),
-            TokenKind::Semicolon,
-            TokenKind::Word("i++".to_string()),
-            TokenKind::RParen,
-            TokenKind::RParen,
             TokenKind::Semicolon,
             TokenKind::Do,
             TokenKind::Word("echo".to_string()),
@@ -2368,17 +2901,6 @@ mod lexer_tests {
         let expected = vec![
             TokenKind::For,
             TokenKind::ArithCommand,
-            TokenKind::Word("i".to_string()),
-            TokenKind::Assignment,
-            TokenKind::Word("5".to_string()),
-            TokenKind::Semicolon,
-            TokenKind::Word("i".to_string()),
-            TokenKind::Great,
-            TokenKind::Word("0".to_string()),
-            TokenKind::Semicolon,
-            TokenKind::Word("i--".to_string()),
-            TokenKind::RParen,
-            TokenKind::RParen,
             TokenKind::Semicolon,
             TokenKind::Do,
             TokenKind::Word("echo".to_string()),
@@ -2410,9 +2932,6 @@ mod lexer_tests {
             TokenKind::Word("i".to_string()),
             TokenKind::Assignment,
             TokenKind::ArithSubst,
-            TokenKind::Word("i+1".to_string()),
-            TokenKind::RParen,
-            TokenKind::RParen,
             TokenKind::Semicolon,
             TokenKind::Done,
         ];
@@ -2584,9 +3103,6 @@ mod lexer_tests {
             TokenKind::Word("count".to_string()),
             TokenKind::Assignment,
             TokenKind::ArithSubst,
-            TokenKind::Word("count+1".to_string()),
-            TokenKind::RParen,
-            TokenKind::RParen,
             TokenKind::Semicolon,
             TokenKind::Done,
         ];
@@ -2634,8 +3150,7 @@ mod lexer_tests {
             TokenKind::Great,
             TokenKind::Word("output.txt".to_string()),
             TokenKind::Word("2".to_string()),
-            TokenKind::Great,
-            TokenKind::Background,
+            TokenKind::GreatAnd,
             TokenKind::Word("1".to_string()),
             TokenKind::DGreat,
             TokenKind::Word("append.log".to_string()),
@@ -2721,9 +3236,6 @@ mod lexer_tests {
             TokenKind::Word("USER".to_string()),
             TokenKind::RBrace,
             TokenKind::ArithSubst,
-            TokenKind::Word("2+3".to_string()),
-            TokenKind::RParen,
-            TokenKind::RParen,
             TokenKind::Dollar,
             TokenKind::Word("?".to_string()),
         ];
@@ -2742,7 +3254,23 @@ mod lexer_tests {
             TokenKind::Word("array[@]".to_string()),
             TokenKind::RBrace,
             TokenKind::ParamExpansion,
-            TokenKind::Comment,
+            TokenKind::Word("#array[@]".to_string()),
+            TokenKind::RBrace,
+        ];
+        test_tokens(input, expected);
+    }
+
+    #[test]
+    fn test_quoted_array_subscript() {
+        // Quotes inside the brackets keep spaces and `]` in the subscript
+        let input = "m[\"a b\"]=1 m['x]']=2";
+        let expected = vec![
+            TokenKind::Word("m[a b]".to_string()),
+            TokenKind::Assignment,
+            TokenKind::Word("1".to_string()),
+            TokenKind::Word("m[x]]".to_string()),
+            TokenKind::Assignment,
+            TokenKind::Word("2".to_string()),
         ];
         test_tokens(input, expected);
     }
@@ -3237,4 +3765,93 @@ mod lexer_tests {
         ];
         test_tokens(input, expected);
     }
+
+    #[test]
+    fn test_unterminated_quote_ends_at_eof() {
+        test_tokens(
+            "echo \"abc",
+            vec![
+                TokenKind::Word("echo".to_string()),
+                TokenKind::Quote,
+                TokenKind::Word("abc".to_string()),
+            ],
+        );
+
+        let mut lexer = Lexer::new("echo ok\ncat 'abc");
+        while lexer.next_token().kind != TokenKind::EOF {}
+        let position = lexer.unterminated_quote().unwrap();
+        assert_eq!((position.line, position.column), (2, 5));
+        assert!(Lexer::new("echo 'a'").unterminated_quote().is_none());
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        test_tokens(
+            "echo one \\\n  two wo\\\nrd",
+            vec![
+                TokenKind::Word("echo".to_string()),
+                TokenKind::Word("one".to_string()),
+                TokenKind::Word("two".to_string()),
+                TokenKind::Word("word".to_string()),
+            ],
+        );
+
+        // Single quotes keep the backslash-newline
+        test_tokens(
+            "echo 'a\\\nb'",
+            vec![
+                TokenKind::Word("echo".to_string()),
+                TokenKind::SingleQuote,
+                TokenKind::Word("a\\\nb".to_string()),
+                TokenKind::SingleQuote,
+            ],
+        );
+
+        // An escaped backslash before a newline does not continue the line
+        test_tokens(
+            "echo a\\\\\nb",
+            vec![
+                TokenKind::Word("echo".to_string()),
+                TokenKind::Word("a\\".to_string()),
+                TokenKind::Newline,
+                TokenKind::Word("b".to_string()),
+            ],
+        );
+
+        // A backslash at the end of the input is kept literally
+        test_tokens(
+            "echo end\\",
+            vec![
+                TokenKind::Word("echo".to_string()),
+                TokenKind::Word("end\\".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_blank_before_tracks_word_boundaries() {
+        let mut lexer = Lexer::new("echo a'b' ?(c) d");
+        let mut blanks = Vec::new();
+        loop {
+            let token = lexer.next_token();
+            if token.kind == TokenKind::EOF {
+                break;
+            }
+            blanks.push((token.value, lexer.blank_before()));
+        }
+
+        let blanks: Vec<(&str, bool)> = blanks.iter().map(|(v, b)| (v.as_str(), *b)).collect();
+        assert_eq!(
+            blanks,
+            vec![
+                ("echo", false),
+                ("a", true),
+                ("'", false),
+                ("b", false),
+                ("'", false),
+                ("?(c)", true),
+                ("d", true),
+            ]
+        );
+    }
 }
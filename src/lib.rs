@@ -11,6 +11,8 @@ pub mod parser;
 // mod lexer_simd;
 // mod simd;
 
+#[cfg(feature = "interpreter")]
+pub mod arithmetic;
 #[cfg(feature = "interpreter")]
 pub mod completion;
 #[cfg(feature = "interpreter")]
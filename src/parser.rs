@@ -9,9 +9,11 @@ use crate::lexer::Lexer;
 use crate::lexer::Position;
 use crate::lexer::Token;
 use crate::lexer::TokenKind;
+use std::fmt;
 
 /// AST node types
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Node {
     Command {
         name: String,
@@ -29,6 +31,10 @@ pub enum Node {
         name: String,
         value: Box<Node>,
     },
+    PrefixedCommand {
+        assignments: Vec<Node>, // Assignment nodes scoped to this command (VAR=value cmd)
+        command: Box<Node>,
+    },
     CommandSubstitution {
         command: Box<Node>,
     },
@@ -84,6 +90,12 @@ pub enum Node {
     Return {
         value: Option<Box<Node>>,
     },
+    Break {
+        level: usize, // Number of enclosing loops to exit (break N)
+    },
+    Continue {
+        level: usize, // Resume the Nth enclosing loop (continue N)
+    },
     // Bash-specific features
     ExtendedTest {
         condition: Box<Node>,
@@ -111,6 +123,10 @@ pub enum Node {
     Negation {
         command: Box<Node>,
     },
+    Time {
+        pipeline: Box<Node>,
+        posix: bool, // time -p
+    },
     SelectStatement {
         variable: String,
         items: Box<Node>,
@@ -119,6 +135,11 @@ pub enum Node {
     Group {
         list: Box<Node>,
     },
+    /// A compound command with redirections that apply to all of it
+    Redirected {
+        command: Box<Node>,
+        redirects: Vec<Redirect>,
+    },
     ParameterExpansion {
         parameter: String,
         expansion_type: ParameterExpansionType,
@@ -130,12 +151,14 @@ pub enum Node {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ProcessSubstDirection {
     Input,  // <(cmd)
     Output, // >(cmd)
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ParameterExpansionType {
     Simple,                              // ${var}
     Default(String),                     // ${var:-default}
@@ -157,19 +180,45 @@ pub enum ParameterExpansionType {
 
 /// Case pattern for case statements
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CasePattern {
     pub patterns: Vec<String>, // Multiple patterns separated by |
     pub body: Box<Node>,
+    pub terminator: CaseTerminator,
+}
+
+/// How a case clause ends, which decides what happens after its body runs
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CaseTerminator {
+    #[default]
+    Break, // ;; stops after the clause
+    FallThrough, // ;& also runs the next clause's body
+    Continue,    // ;;& keeps testing the following patterns
+}
+
+impl CaseTerminator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaseTerminator::Break => ";;",
+            CaseTerminator::FallThrough => ";&",
+            CaseTerminator::Continue => ";;&",
+        }
+    }
 }
 
 /// Redirection types
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Redirect {
     pub kind: RedirectKind,
     pub file: String,
+    pub fd: i32,      // Descriptor being redirected, as the 2 in `2>&1`
+    pub quoted: bool, // Here-document delimiter was quoted, so the body is not expanded
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RedirectKind {
     Input,       // <
     Output,      // >
@@ -181,11 +230,669 @@ pub enum RedirectKind {
     OutputDup,   // >&
 }
 
+impl RedirectKind {
+    /// Descriptor redirected when the operator has no number in front of it
+    pub fn default_fd(&self) -> i32 {
+        match self {
+            RedirectKind::Output | RedirectKind::Append | RedirectKind::OutputDup => 1,
+            _ => 0,
+        }
+    }
+
+    /// Operator that introduces the redirection, without its descriptor
+    pub fn operator(&self) -> &'static str {
+        match self {
+            RedirectKind::Input => "<",
+            RedirectKind::Output => ">",
+            RedirectKind::Append => ">>",
+            RedirectKind::HereDoc => "<<",
+            RedirectKind::HereDocDash => "<<-",
+            RedirectKind::HereString => "<<<",
+            RedirectKind::InputDup => "<&",
+            RedirectKind::OutputDup => ">&",
+        }
+    }
+}
+
+impl Node {
+    /// Render the node back into shell source that parses to the same AST, on as few
+    /// lines as possible and with single spaces between words. The formatter builds its
+    /// layouts on top of this and writes every node it does not lay out through it.
+    pub fn to_shell_source(&self) -> String {
+        let mut writer = SourceWriter::default();
+        writer.node(self);
+        writer.finish()
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_shell_source())
+    }
+}
+
+/// Characters that end a word or start an operator when left unquoted
+const SHELL_METACHARACTERS: &[char] = &[
+    ' ', '\t', '\n', '|', '&', ';', '<', '>', '(', ')', '\'', '"', '\\',
+];
+
+/// Wrap `text` in single quotes, closing and reopening them around embedded quotes
+fn single_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// Whether `word` contains quotes that all close, as in `it"'"s`
+fn has_balanced_quotes(word: &str) -> bool {
+    let mut quoted = false;
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' => {
+                quoted = true;
+                if !chars.any(|c| c == '\'') {
+                    return false;
+                }
+            }
+            '"' => {
+                quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            chars.next();
+                        }
+                        Some(_) => {}
+                        None => return false,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    quoted
+}
+
+/// Quote a command word for output. Words holding expansions, escapes or their own
+/// quotes are kept in source form by the parser and written as they are.
+pub(crate) fn quote_word(word: &str) -> String {
+    if word.contains(['$', '`', '\\']) || has_balanced_quotes(word) {
+        word.to_string()
+    } else if word.is_empty() || word.starts_with('#') || word.contains(SHELL_METACHARACTERS) {
+        // Double quotes read more naturally, but would change `"` and history `!`
+        if word.contains(['"', '!']) {
+            single_quote(word)
+        } else {
+            format!("\"{word}\"")
+        }
+    } else {
+        word.to_string()
+    }
+}
+
+/// Builds the text for `Node::to_shell_source`
+#[derive(Default)]
+struct SourceWriter {
+    out: String,
+    heredocs: Vec<String>, // Here-document bodies that start after the current line
+}
+
+impl SourceWriter {
+    fn finish(mut self) -> String {
+        if !self.heredocs.is_empty() {
+            self.newline();
+        }
+        self.out
+    }
+
+    fn push(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    /// End the line, writing any pending here-document bodies after it
+    fn newline(&mut self) {
+        self.out.push('\n');
+        for body in self.heredocs.drain(..) {
+            self.out.push_str(&body);
+        }
+    }
+
+    /// Write a compound command body followed by the separator its closing keyword needs
+    fn body(&mut self, node: &Node) {
+        self.node(node);
+        // Comments need the line to end, and a body that ended its line keeps doing so
+        let ends_line = match node {
+            Node::List {
+                statements,
+                operators,
+            } => {
+                matches!(statements.last(), Some(Node::Comment(_)))
+                    || statements.len() == operators.len()
+                        && operators.last().is_some_and(|operator| operator == "\n")
+            }
+            _ => matches!(node, Node::Comment(_)),
+        };
+        if ends_line {
+            self.newline();
+        } else if !matches!(node, Node::List { statements, .. } if statements.is_empty())
+            && !self.out.ends_with(['\n', '&'])
+        {
+            self.push("; ");
+        } else if !self.out.ends_with('\n') {
+            self.push(" ");
+        }
+    }
+
+    fn words(&mut self, words: &[String]) {
+        for word in words {
+            self.push(" ");
+            self.push(&quote_word(word));
+        }
+    }
+
+    fn simple_command(&mut self, name: &str, args: &[String], redirects: &[Redirect]) {
+        if !name.is_empty() {
+            self.push(&quote_word(name));
+        }
+        self.words(args);
+        for redirect in redirects {
+            if !self.out.is_empty() && !self.out.ends_with([' ', '\n']) {
+                self.push(" ");
+            }
+            self.redirect(redirect);
+        }
+    }
+
+    fn redirect(&mut self, redirect: &Redirect) {
+        if redirect.fd != redirect.kind.default_fd() {
+            self.push(&redirect.fd.to_string());
+        }
+        self.push(redirect.kind.operator());
+        match redirect.kind {
+            RedirectKind::HereDoc | RedirectKind::HereDocDash => {
+                // Pick a delimiter that does not appear as a line of the body
+                let mut delimiter = "EOF".to_string();
+                while redirect.file.lines().any(|line| line == delimiter) {
+                    delimiter.push('_');
+                }
+                if redirect.quoted {
+                    self.push(&single_quote(&delimiter));
+                } else {
+                    self.push(&delimiter);
+                }
+                self.heredocs
+                    .push(format!("{}{delimiter}\n", redirect.file));
+            }
+            // Here-string words keep their source form
+            RedirectKind::HereString => self.push(&format!(" {}", redirect.file)),
+            _ => self.push(&quote_word(&redirect.file)),
+        }
+    }
+
+    /// Write an assignment value, which is not split into words
+    fn value(&mut self, value: &Node) {
+        match value {
+            Node::StringLiteral(text)
+                if text.contains(['$', '`']) && text.contains(SHELL_METACHARACTERS) =>
+            {
+                self.push(&format!("\"{}\"", text.replace('"', "\\\"")));
+            }
+            _ => self.node(value),
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::Command {
+                name,
+                args,
+                redirects,
+            }
+            | Node::FunctionCall {
+                name,
+                args,
+                redirects,
+            } => self.simple_command(name, args, redirects),
+            Node::Pipeline { commands } => {
+                for (i, command) in commands.iter().enumerate() {
+                    if i > 0 {
+                        self.push(" | ");
+                    }
+                    self.node(command);
+                }
+            }
+            Node::List {
+                statements,
+                operators,
+            } => {
+                for (i, statement) in statements.iter().enumerate() {
+                    self.node(statement);
+                    let last = i + 1 == statements.len();
+                    match operators.get(i).map(String::as_str) {
+                        Some("&") if last => self.push(" &"),
+                        Some("&") => self.push(" & "),
+                        _ if last => {}
+                        // A comment runs to the end of the line
+                        _ if matches!(statement, Node::Comment(_)) => self.newline(),
+                        Some("\n") => self.newline(),
+                        Some(operator @ ("&&" | "||")) => self.push(&format!(" {operator} ")),
+                        // Assignments written one after another have no separator
+                        Some("") => self.push(" "),
+                        _ => self.push("; "),
+                    }
+                }
+            }
+            Node::Assignment { name, value } => {
+                self.push(name);
+                self.push("=");
+                self.value(value);
+            }
+            Node::PrefixedCommand {
+                assignments,
+                command,
+            } => {
+                for assignment in assignments {
+                    self.node(assignment);
+                    self.push(" ");
+                }
+                self.node(command);
+            }
+            Node::CommandSubstitution { command } => {
+                self.push("$(");
+                self.node(command);
+                self.push(")");
+            }
+            Node::ArithmeticExpansion { expression } => {
+                self.push(&format!("$(({expression}))"));
+            }
+            Node::ArithmeticCommand { expression } => self.push(&format!("(({expression}))")),
+            Node::Subshell { list } => {
+                self.push("(");
+                self.node(list);
+                self.push(")");
+            }
+            Node::Group { list } => {
+                self.push("{ ");
+                self.body(list);
+                self.push("}");
+            }
+            Node::Redirected { command, redirects } => {
+                self.node(command);
+                for redirect in redirects {
+                    self.push(" ");
+                    self.redirect(redirect);
+                }
+            }
+            Node::Comment(text) => self.push(text),
+            Node::StringLiteral(text) => self.push(&quote_word(text)),
+            Node::SingleQuotedString(text) => self.push(&single_quote(text)),
+            Node::ExtGlobPattern {
+                operator,
+                patterns,
+                suffix,
+            } => self.push(&format!("{operator}({}){suffix}", patterns.join("|"))),
+            Node::IfStatement {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.push("if ");
+                self.branches(condition, consequence, alternative.as_deref());
+                self.push("fi");
+            }
+            // Branches are written by their `if`; on their own they still read as one
+            Node::ElifBranch {
+                condition,
+                consequence,
+            } => {
+                self.push("if ");
+                self.branches(condition, consequence, None);
+                self.push("fi");
+            }
+            Node::ElseBranch { consequence } => self.node(consequence),
+            Node::CaseStatement {
+                expression,
+                patterns,
+            } => {
+                self.push("case ");
+                self.node(expression);
+                self.push(" in ");
+                for pattern in patterns {
+                    self.push(&pattern.patterns.join(" | "));
+                    self.push(") ");
+                    self.node(&pattern.body);
+                    if !self.out.ends_with(['\n', ' ']) {
+                        self.push(" ");
+                    }
+                    self.push(pattern.terminator.as_str());
+                    self.push(" ");
+                }
+                self.push("esac");
+            }
+            Node::Array { elements } => {
+                self.push("(");
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        self.push(" ");
+                    }
+                    self.push(&quote_word(element));
+                }
+                self.push(")");
+            }
+            Node::Function { name, body } => {
+                self.push(&format!("{name}() {{ "));
+                self.body(body);
+                self.push("}");
+            }
+            Node::Export { name, value } => {
+                self.push("export ");
+                self.push(name);
+                if let Some(value) = value {
+                    self.push("=");
+                    self.value(value);
+                }
+            }
+            Node::Return { value } => {
+                self.push("return");
+                if let Some(value) = value {
+                    self.push(" ");
+                    self.node(value);
+                }
+            }
+            Node::Break { level } => self.loop_control("break", *level),
+            Node::Continue { level } => self.loop_control("continue", *level),
+            Node::ExtendedTest { condition } => match condition.as_ref() {
+                // Operators such as && and < are part of the test, so they stay unquoted
+                Node::Command { args, .. } => self.push(&format!("[[ {} ]]", args.join(" "))),
+                condition => {
+                    self.push("[[ ");
+                    self.node(condition);
+                    self.push(" ]]");
+                }
+            },
+            Node::HistoryExpansion { pattern } if pattern.is_empty() => self.push("!!"),
+            Node::HistoryExpansion { pattern } => self.push(&format!("!{pattern}")),
+            Node::Complete { options, command } => {
+                self.push("complete");
+                self.words(options);
+                self.words(std::slice::from_ref(command));
+            }
+            Node::ForLoop {
+                variable,
+                iterable,
+                body,
+            } => self.for_loop("for", variable, iterable, body),
+            Node::SelectStatement {
+                variable,
+                items,
+                body,
+            } => self.for_loop("select", variable, items, body),
+            Node::WhileLoop { condition, body } => self.while_loop("while", condition, body),
+            Node::UntilLoop { condition, body } => self.while_loop("until", condition, body),
+            Node::Negation { command } => {
+                self.push("! ");
+                self.node(command);
+            }
+            Node::Time { pipeline, posix } => {
+                self.push(if *posix { "time -p " } else { "time " });
+                self.node(pipeline);
+            }
+            Node::ParameterExpansion {
+                parameter,
+                expansion_type,
+            } => {
+                let expansion = match expansion_type {
+                    ParameterExpansionType::Simple => parameter.clone(),
+                    ParameterExpansionType::Default(word) => format!("{parameter}:-{word}"),
+                    ParameterExpansionType::Assign(word) => format!("{parameter}:={word}"),
+                    ParameterExpansionType::Error(word) => format!("{parameter}:?{word}"),
+                    ParameterExpansionType::Alternative(word) => format!("{parameter}:+{word}"),
+                    ParameterExpansionType::Length => format!("#{parameter}"),
+                    ParameterExpansionType::RemoveSmallestPrefix(word) => {
+                        format!("{parameter}#{word}")
+                    }
+                    ParameterExpansionType::RemoveLargestPrefix(word) => {
+                        format!("{parameter}##{word}")
+                    }
+                    ParameterExpansionType::RemoveSmallestSuffix(word) => {
+                        format!("{parameter}%{word}")
+                    }
+                    ParameterExpansionType::RemoveLargestSuffix(word) => {
+                        format!("{parameter}%%{word}")
+                    }
+                    ParameterExpansionType::Substring(offset, length) => {
+                        // A space keeps a negative offset from reading as `:-`
+                        let offset = match offset {
+                            Some(offset) if *offset < 0 => format!(" {offset}"),
+                            Some(offset) => offset.to_string(),
+                            None => String::new(),
+                        };
+                        match length {
+                            Some(length) => format!("{parameter}:{offset}:{length}"),
+                            None => format!("{parameter}:{offset}"),
+                        }
+                    }
+                    ParameterExpansionType::Indirect => format!("!{parameter}"),
+                    ParameterExpansionType::ArrayAll => format!("{parameter}[@]"),
+                    ParameterExpansionType::ArrayStar => format!("{parameter}[*]"),
+                    ParameterExpansionType::ArrayLength => format!("#{parameter}[@]"),
+                    ParameterExpansionType::ArrayIndex(index) => format!("{parameter}[{index}]"),
+                };
+                self.push(&format!("${{{expansion}}}"));
+            }
+            Node::ProcessSubstitution { command, direction } => {
+                self.push(match direction {
+                    ProcessSubstDirection::Input => "<(",
+                    ProcessSubstDirection::Output => ">(",
+                });
+                self.node(command);
+                self.push(")");
+            }
+        }
+    }
+
+    /// Write `condition; then consequence` and any elif/else branches after it
+    fn branches(&mut self, condition: &Node, consequence: &Node, alternative: Option<&Node>) {
+        self.body(condition);
+        self.push("then ");
+        self.body(consequence);
+        match alternative {
+            Some(Node::IfStatement {
+                condition,
+                consequence,
+                alternative,
+            }) => {
+                self.push("elif ");
+                self.branches(condition, consequence, alternative.as_deref());
+            }
+            Some(Node::ElifBranch {
+                condition,
+                consequence,
+            }) => {
+                self.push("elif ");
+                self.branches(condition, consequence, None);
+            }
+            Some(Node::ElseBranch { consequence }) => {
+                self.push("else ");
+                self.body(consequence);
+            }
+            Some(other) => {
+                self.push("else ");
+                self.body(other);
+            }
+            None => {}
+        }
+    }
+
+    fn loop_control(&mut self, keyword: &str, level: usize) {
+        self.push(keyword);
+        if level != 1 {
+            self.push(&format!(" {level}"));
+        }
+    }
+
+    fn for_loop(&mut self, keyword: &str, variable: &str, items: &Node, body: &Node) {
+        self.push(&format!("{keyword} {variable} in"));
+        match items {
+            Node::Array { elements } => self.words(elements),
+            items => {
+                self.push(" ");
+                self.node(items);
+            }
+        }
+        self.push("; do ");
+        self.body(body);
+        self.push("done");
+    }
+
+    fn while_loop(&mut self, keyword: &str, condition: &Node, body: &Node) {
+        self.push(keyword);
+        self.push(" ");
+        self.body(condition);
+        self.push("do ");
+        self.body(body);
+        self.push("done");
+    }
+}
+
+/// Parse `input` as a script and render its AST as JSON
+#[cfg(feature = "serde")]
+pub fn parse_to_json(input: &str) -> Result<String, String> {
+    let mut parser = Parser::new(Lexer::new(input));
+    let ast = parser.parse_script();
+    serde_json::to_string(&ast).map_err(|e| e.to_string())
+}
+
+/// A syntax error found by `Parser::parse_script_checked`, at the token where it was noticed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Split the body of `${...}` into the parameter name and the expansion applied to it.
+/// `${var:-1}` is a default value, while a blank or digit after the colon, as in
+/// `${var: -1}`, starts a substring offset. Bodies that do not fit a known form are kept
+/// whole as the parameter.
+fn split_parameter_body(body: &str) -> (String, ParameterExpansionType) {
+    let whole = || (body.to_string(), ParameterExpansionType::Simple);
+    let name_length = match body.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => body
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(body.len()),
+        Some(c) if c.is_ascii_digit() => body
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(body.len()),
+        Some('@' | '*' | '#' | '?' | '$' | '!' | '-') if body.len() == 1 => 1,
+        Some('#' | '!') => {
+            let (name, expansion) = split_parameter_body(&body[1..]);
+            return match expansion {
+                ParameterExpansionType::Simple if body.starts_with('#') => {
+                    (name, ParameterExpansionType::Length)
+                }
+                ParameterExpansionType::Simple => (name, ParameterExpansionType::Indirect),
+                _ => whole(),
+            };
+        }
+        _ => return whole(),
+    };
+    let (name, rest) = body.split_at(name_length);
+    let name = name.to_string();
+    let word = |operator: &str| rest[operator.len()..].to_string();
+
+    let expansion = if rest.is_empty() {
+        ParameterExpansionType::Simple
+    } else if rest.starts_with(":-") {
+        ParameterExpansionType::Default(word(":-"))
+    } else if rest.starts_with(":=") {
+        ParameterExpansionType::Assign(word(":="))
+    } else if rest.starts_with(":?") {
+        ParameterExpansionType::Error(word(":?"))
+    } else if rest.starts_with(":+") {
+        ParameterExpansionType::Alternative(word(":+"))
+    } else if rest.starts_with("##") {
+        ParameterExpansionType::RemoveLargestPrefix(word("##"))
+    } else if rest.starts_with('#') {
+        ParameterExpansionType::RemoveSmallestPrefix(word("#"))
+    } else if rest.starts_with("%%") {
+        ParameterExpansionType::RemoveLargestSuffix(word("%%"))
+    } else if rest.starts_with('%') {
+        ParameterExpansionType::RemoveSmallestSuffix(word("%"))
+    } else if let Some(range) = rest.strip_prefix(':') {
+        // Offsets and lengths are arithmetic; only plain numbers are typed here
+        let number = |text: &str| {
+            let text = text.trim();
+            let text = text
+                .strip_prefix('(')
+                .and_then(|text| text.strip_suffix(')'))
+                .unwrap_or(text);
+            text.trim().parse::<i32>().ok()
+        };
+        let (offset, length) = match range.split_once(':') {
+            Some((offset, length)) => (offset, Some(length)),
+            None => (range, None),
+        };
+        match (number(offset), length.map(number)) {
+            (Some(offset), None) => ParameterExpansionType::Substring(Some(offset), None),
+            (Some(offset), Some(Some(length))) => {
+                ParameterExpansionType::Substring(Some(offset), Some(length))
+            }
+            _ => return whole(),
+        }
+    } else {
+        return whole();
+    };
+    (name, expansion)
+}
+
+/// Whether a token is a redirection operator such as `<`, `>>` or `<<<`
+fn is_redirect_operator(kind: &TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Less
+            | TokenKind::Great
+            | TokenKind::DGreat
+            | TokenKind::LessAnd
+            | TokenKind::GreatAnd
+            | TokenKind::HereDoc
+            | TokenKind::HereDocDash
+            | TokenKind::HereString
+    )
+}
+
+/// Source text of the keyword or operator that closes a construct
+fn closing_text(kind: &TokenKind) -> &'static str {
+    match kind {
+        TokenKind::Fi => "fi",
+        TokenKind::Done => "done",
+        TokenKind::Esac => "esac",
+        TokenKind::Then => "then",
+        TokenKind::Do => "do",
+        TokenKind::RBrace => "}",
+        TokenKind::RParen => ")",
+        _ => "end of command",
+    }
+}
+
 /// Parser converts tokens into an AST
 pub struct Parser {
     pub lexer: Lexer,
     pub current_token: Token,
     pub peek_token: Token,
+    current_blank_before: bool, // Blanks separate the current token from the previous one
+    peek_blank_before: bool,
+    errors: Vec<ParseError>, // Syntax errors found so far, reported by parse_script_checked
 }
 
 impl Parser {
@@ -202,6 +909,9 @@ impl Parser {
                 value: String::new(),
                 position: Position::new(0, 0),
             },
+            current_blank_before: false,
+            peek_blank_before: false,
+            errors: Vec::new(),
         };
 
         parser.next_token();
@@ -211,7 +921,29 @@ impl Parser {
 
     pub fn next_token(&mut self) {
         self.current_token = self.peek_token.clone();
+        self.current_blank_before = self.peek_blank_before;
         self.peek_token = self.lexer.next_token();
+        self.peek_blank_before = self.lexer.blank_before();
+    }
+
+    /// Record a syntax error at the current token
+    fn error(&mut self, message: String) {
+        let Position { line, column } = self.current_token.position;
+        self.errors.push(ParseError {
+            message,
+            line,
+            column,
+        });
+    }
+
+    /// Record that `expected` should have come where the current token is
+    fn expected(&mut self, expected: &str) {
+        let found = match self.current_token.kind {
+            TokenKind::EOF => "end of file".to_string(),
+            TokenKind::Newline => "newline".to_string(),
+            _ => format!("`{}`", self.current_token.value),
+        };
+        self.error(format!("expected `{expected}` but found {found}"));
     }
 
     // Function definition: name() { ... }
@@ -313,9 +1045,9 @@ impl Parser {
                     }
                 }
 
-                // Check for variable assignment (VAR=value)
+                // Check for variable assignment (VAR=value), possibly prefixing a command
                 if self.peek_token.kind == TokenKind::Assignment {
-                    return Some(self.parse_assignment());
+                    return Some(self.parse_assignments_and_command());
                 }
 
                 // Check for export statement
@@ -328,19 +1060,30 @@ impl Parser {
                     return Some(self.parse_negation());
                 }
 
+                // Check for the time reserved word
+                if word == "time" {
+                    return Some(self.parse_time());
+                }
+
                 let command_node = self.parse_command();
                 Some(command_node)
             }
-            TokenKind::If => Some(self.parse_if_statement()),
-            TokenKind::Case => Some(self.parse_case_statement()),
-            TokenKind::For => Some(self.parse_for_loop()),
-            TokenKind::While => Some(self.parse_while_loop()),
-            TokenKind::Until => Some(self.parse_until_loop()),
-            TokenKind::Select => Some(self.parse_select_statement()),
+            TokenKind::Quote | TokenKind::SingleQuote | TokenKind::Dollar => {
+                Some(self.parse_command())
+            }
+            TokenKind::If => Some(self.parse_compound_command(Self::parse_if_statement)),
+            TokenKind::Case => Some(self.parse_compound_command(Self::parse_case_statement)),
+            TokenKind::For => Some(self.parse_compound_command(Self::parse_for_loop)),
+            TokenKind::While => Some(self.parse_compound_command(Self::parse_while_loop)),
+            TokenKind::Until => Some(self.parse_compound_command(Self::parse_until_loop)),
+            TokenKind::Select => Some(self.parse_compound_command(Self::parse_select_statement)),
             TokenKind::Elif => Some(self.parse_elif_branch()),
             TokenKind::Else => Some(self.parse_else_branch()),
-            TokenKind::LParen => Some(self.parse_subshell()),
-            TokenKind::ArithCommand => Some(self.parse_arithmetic_command()),
+            TokenKind::LParen => Some(self.parse_compound_command(Self::parse_subshell)),
+            TokenKind::LBrace => Some(self.parse_compound_command(Self::parse_group)),
+            TokenKind::ArithCommand => {
+                Some(self.parse_compound_command(Self::parse_arithmetic_command))
+            }
             TokenKind::Comment => {
                 let comment = self.current_token.value.clone();
                 self.next_token();
@@ -349,7 +1092,10 @@ impl Parser {
             TokenKind::ExtGlob(_) => Some(self.parse_extglob()),
             TokenKind::Export => Some(self.parse_export()),
             TokenKind::Return => Some(self.parse_return()),
-            TokenKind::DoubleLBracket => Some(self.parse_extended_test()),
+            TokenKind::Break | TokenKind::Continue => Some(self.parse_loop_control()),
+            TokenKind::DoubleLBracket => {
+                Some(self.parse_compound_command(Self::parse_extended_test))
+            }
             TokenKind::History => Some(self.parse_history_expansion()),
             TokenKind::ParamExpansion => Some(self.parse_parameter_expansion()),
             TokenKind::ProcessSubstIn => {
@@ -363,8 +1109,82 @@ impl Parser {
         }
     }
 
+    // Parse a compound command with `parse`, then the redirections that apply to all of
+    // it and the rest of a pipeline it starts, as in `while read l; do ...; done < f | sort`
+    fn parse_compound_command(&mut self, parse: fn(&mut Self) -> Node) -> Node {
+        let mut command = parse(self);
+
+        let mut redirects = Vec::new();
+        loop {
+            if let Some(fd) = self.redirect_fd_number() {
+                self.next_token(); // Skip the number
+                redirects.push(self.parse_redirect(Some(fd)));
+            } else if is_redirect_operator(&self.current_token.kind) {
+                redirects.push(self.parse_redirect(None));
+            } else {
+                break;
+            }
+        }
+        if !redirects.is_empty() {
+            command = Node::Redirected {
+                command: Box::new(command),
+                redirects,
+            };
+        }
+
+        if self.current_token.kind != TokenKind::Pipe {
+            return command;
+        }
+        self.next_token(); // Skip the '|'
+
+        let mut commands = vec![command];
+        match self.parse_pipeline_stage() {
+            Node::Pipeline {
+                commands: more_commands,
+            } => commands.extend(more_commands),
+            next_command => commands.push(next_command),
+        }
+        Node::Pipeline { commands }
+    }
+
+    // Parse the command after a `|`, which may be compound or have prefix assignments
+    fn parse_pipeline_stage(&mut self) -> Node {
+        match self.current_token.kind {
+            TokenKind::Word(_) if self.peek_token.kind == TokenKind::Assignment => {
+                self.parse_assignments_and_command()
+            }
+            TokenKind::Word(_) => self.parse_command(),
+            _ => self
+                .parse_statement()
+                .unwrap_or_else(|| self.parse_command()),
+        }
+    }
+
+    // Parse a brace group: { list; }
+    fn parse_group(&mut self) -> Node {
+        self.next_token(); // Skip '{'
+        let list = self.parse_until_token_kind(TokenKind::RBrace);
+        self.next_token(); // Skip '}'
+        Node::Group {
+            list: Box::new(list),
+        }
+    }
+
     // Parse export statement: export VAR=value or export VAR
     fn parse_export(&mut self) -> Node {
+        // Options such as -f are handled by the export builtin
+        if matches!(&self.peek_token.kind, TokenKind::Word(word) if word.starts_with('-')) {
+            let mut command = self.parse_command();
+            let first = match &mut command {
+                Node::Pipeline { commands } => commands.first_mut(),
+                command => Some(command),
+            };
+            if let Some(Node::Command { name, .. }) = first {
+                *name = "export".to_string();
+            }
+            return command;
+        }
+
         self.next_token(); // Skip 'export' keyword
 
         // Get variable name
@@ -406,6 +1226,26 @@ impl Parser {
         }
     }
 
+    // Parse loop control: break [n] or continue [n]
+    fn parse_loop_control(&mut self) -> Node {
+        let is_break = self.current_token.kind == TokenKind::Break;
+        self.next_token(); // Skip 'break' or 'continue' keyword
+
+        let mut level = 1;
+        if let TokenKind::Word(word) = &self.current_token.kind {
+            if let Ok(n) = word.parse::<usize>() {
+                level = n;
+                self.next_token(); // Skip the level
+            }
+        }
+
+        if is_break {
+            Node::Break { level }
+        } else {
+            Node::Continue { level }
+        }
+    }
+
     // Parse return statement: return [value]
     fn parse_return(&mut self) -> Node {
         self.next_token(); // Skip 'return' keyword
@@ -431,6 +1271,17 @@ impl Parser {
         match self.current_token.kind {
             TokenKind::Quote => return self.parse_quoted_string(TokenKind::Quote),
             TokenKind::SingleQuote => return self.parse_quoted_string(TokenKind::SingleQuote),
+            TokenKind::AnsiCString(ref value)
+                if matches!(
+                    self.peek_token.kind,
+                    TokenKind::Semicolon | TokenKind::Newline | TokenKind::EOF
+                ) =>
+            {
+                // The escapes are already decoded, so the value is taken literally
+                let value = value.clone();
+                self.next_token();
+                return Node::SingleQuotedString(value);
+            }
             TokenKind::CmdSubst => return self.parse_command_substitution(),
             TokenKind::ArithSubst => return self.parse_arithmetic_expansion(),
             _ => {}
@@ -464,6 +1315,10 @@ impl Parser {
                         result.push_str(&s);
                     }
                 }
+                TokenKind::AnsiCString(ref value) => {
+                    result.push_str(value);
+                    self.next_token();
+                }
                 TokenKind::CmdSubst => {
                     // For command substitution in concatenated context, preserve the syntax
                     let cmd_node = self.parse_command_substitution();
@@ -599,6 +1454,8 @@ impl Parser {
 
         if self.current_token.kind == TokenKind::RParen {
             self.next_token();
+        } else {
+            self.expected(")");
         }
 
         Node::Array {
@@ -621,8 +1478,14 @@ impl Parser {
 
         let mut quoted_value = String::new();
         while self.current_token.kind != quote_type && self.current_token.kind != TokenKind::EOF {
-            if let TokenKind::Word(word) = &self.current_token.kind {
-                quoted_value.push_str(word);
+            match &self.current_token.kind {
+                TokenKind::Word(word) => quoted_value.push_str(word),
+                // The lexer splits a quoted string that opens with $( into tokens
+                TokenKind::CmdSubst => {
+                    quoted_value.push_str(&self.parse_command_substitution_word());
+                    continue;
+                }
+                _ => {}
             }
             self.next_token();
         }
@@ -641,7 +1504,11 @@ impl Parser {
         // Parse condition as a single command until we hit "then"
         let condition = self.parse_condition_until_token_kind(TokenKind::Then);
 
-        self.next_token(); // Skip "then"
+        if self.current_token.kind == TokenKind::Then {
+            self.next_token(); // Skip "then"
+        } else {
+            self.expected("then");
+        }
 
         // Parse consequence (body of the if block)
         let consequence =
@@ -665,7 +1532,11 @@ impl Parser {
 
                 // Parse elif condition
                 let elif_condition = self.parse_condition_until_token_kind(TokenKind::Then);
-                self.next_token(); // Skip "then"
+                if self.current_token.kind == TokenKind::Then {
+                    self.next_token(); // Skip "then"
+                } else {
+                    self.expected("then");
+                }
 
                 // Parse elif consequence
                 let elif_consequence = self.parse_until_token_kinds(&[
@@ -766,6 +1637,17 @@ impl Parser {
                 // Handle quoted strings
                 self.parse_quoted_string(TokenKind::Quote)
             }
+            TokenKind::SingleQuote => self.parse_quoted_string(TokenKind::SingleQuote),
+            TokenKind::AnsiCString(value) => {
+                // The lexer already decoded the escapes of $'...'
+                let expr = Node::SingleQuotedString(value.clone());
+                self.next_token();
+                expr
+            }
+            TokenKind::Dollar | TokenKind::ParamExpansion => {
+                // A parameter, expanded when the statement runs
+                Node::StringLiteral(self.parse_case_pattern().unwrap_or_default())
+            }
             TokenKind::CmdSubst => {
                 // Handle command substitution
                 self.parse_command_substitution()
@@ -796,20 +1678,21 @@ impl Parser {
                 break;
             }
 
+            // The pattern list may open with an optional (
+            if self.current_token.kind == TokenKind::LParen {
+                self.next_token();
+            }
+
             // Parse pattern(s) - can be multiple patterns separated by |
             let mut pattern_list = Vec::new();
-
-            // Parse the first pattern
-            if let TokenKind::Word(pattern) = &self.current_token.kind {
-                pattern_list.push(pattern.clone());
-                self.next_token();
+            if let Some(pattern) = self.parse_case_pattern() {
+                pattern_list.push(pattern);
 
                 // Check for additional patterns separated by |
                 while self.current_token.kind == TokenKind::Pipe {
                     self.next_token(); // Skip |
-                    if let TokenKind::Word(pattern) = &self.current_token.kind {
-                        pattern_list.push(pattern.clone());
-                        self.next_token();
+                    if let Some(pattern) = self.parse_case_pattern() {
+                        pattern_list.push(pattern);
                     }
                 }
             }
@@ -824,28 +1707,13 @@ impl Parser {
                 self.next_token();
             }
 
-            // Parse the body until we hit ;; or esac
-            // Use a simpler approach - parse until we hit ;; or esac
-            let mut body_statements = Vec::new();
-            let mut body_operators = Vec::new();
-
-            while self.current_token.kind != TokenKind::DoubleSemicolon
-                && self.current_token.kind != TokenKind::Esac
-                && self.current_token.kind != TokenKind::EOF
-            {
-                if let Some(statement) = self.parse_statement() {
-                    body_statements.push(statement);
-
-                    // Handle operators between statements
-                    if self.current_token.kind == TokenKind::Semicolon {
-                        body_operators.push(";".to_string());
-                        self.next_token();
-                    } else if self.current_token.kind == TokenKind::Newline {
-                        body_operators.push("\n".to_string());
-                        self.next_token();
-                    }
-                }
-            }
+            // Parse the body until we hit a clause terminator or esac
+            let (body_statements, body_operators) = self.parse_list_until(&[
+                TokenKind::DoubleSemicolon,
+                TokenKind::SemicolonAnd,
+                TokenKind::DoubleSemicolonAnd,
+                TokenKind::Esac,
+            ]);
 
             let body = if body_statements.len() == 1 && body_operators.is_empty() {
                 body_statements.into_iter().next().unwrap()
@@ -856,12 +1724,22 @@ impl Parser {
                 }
             };
 
-            // Skip ;; if present
-            if self.current_token.kind == TokenKind::DoubleSemicolon {
+            // Skip the terminator if present, remembering which one it was
+            let terminator = match self.current_token.kind {
+                TokenKind::SemicolonAnd => CaseTerminator::FallThrough,
+                TokenKind::DoubleSemicolonAnd => CaseTerminator::Continue,
+                _ => CaseTerminator::Break,
+            };
+            if matches!(
+                self.current_token.kind,
+                TokenKind::DoubleSemicolon
+                    | TokenKind::SemicolonAnd
+                    | TokenKind::DoubleSemicolonAnd
+            ) {
                 self.next_token();
             }
 
-            // Skip any newlines after ;;
+            // Skip any newlines after the terminator
             while self.current_token.kind == TokenKind::Newline {
                 self.next_token();
             }
@@ -870,6 +1748,7 @@ impl Parser {
                 patterns.push(CasePattern {
                     patterns: pattern_list,
                     body: Box::new(body),
+                    terminator,
                 });
             }
         }
@@ -877,6 +1756,8 @@ impl Parser {
         // Skip "esac"
         if self.current_token.kind == TokenKind::Esac {
             self.next_token();
+        } else {
+            self.expected("esac");
         }
 
         Node::CaseStatement {
@@ -893,6 +1774,7 @@ impl Parser {
         let variable = if let TokenKind::Word(var_name) = &self.current_token.kind {
             var_name.clone()
         } else {
+            self.expected("variable name");
             return Node::Command {
                 name: "echo".to_string(),
                 args: vec!["syntax error: expected variable name after 'for'".to_string()],
@@ -901,18 +1783,24 @@ impl Parser {
         };
         self.next_token();
 
-        // Expect "in"
-        if self.current_token.kind != TokenKind::In {
-            return Node::Command {
-                name: "echo".to_string(),
-                args: vec!["syntax error: expected 'in' after variable name".to_string()],
-                redirects: Vec::new(),
-            };
-        }
-        self.next_token(); // Skip "in"
-
-        // Parse iterable (could be words, brace expansion, etc.)
-        let iterable = self.parse_for_iterable();
+        // Without `in`, the loop runs over the positional parameters as `in "$@"` would
+        let iterable = match self.current_token.kind {
+            TokenKind::In => {
+                self.next_token(); // Skip "in"
+                self.parse_for_iterable()
+            }
+            TokenKind::Semicolon | TokenKind::Newline | TokenKind::Do => Node::Array {
+                elements: vec!["\"$@\"".to_string()],
+            },
+            _ => {
+                self.expected("in");
+                return Node::Command {
+                    name: "echo".to_string(),
+                    args: vec!["syntax error: expected 'in' after variable name".to_string()],
+                    redirects: Vec::new(),
+                };
+            }
+        };
 
         // Skip optional semicolon or newline
         while self.current_token.kind == TokenKind::Semicolon
@@ -923,6 +1811,7 @@ impl Parser {
 
         // Expect "do"
         if self.current_token.kind != TokenKind::Do {
+            self.expected("do");
             return Node::Command {
                 name: "echo".to_string(),
                 args: vec!["syntax error: expected 'do' after iterable".to_string()],
@@ -943,111 +1832,58 @@ impl Parser {
         }
     }
 
-    // Parse the iterable part of a for loop (handles brace expansion)
+    // Parse the words of a for loop, kept in source form so that expansion, field
+    // splitting and quote removal happen each time the loop runs
     fn parse_for_iterable(&mut self) -> Node {
         let mut elements = Vec::new();
 
-        while self.current_token.kind != TokenKind::Semicolon
-            && self.current_token.kind != TokenKind::Newline
-            && self.current_token.kind != TokenKind::Do
-            && self.current_token.kind != TokenKind::EOF
-        {
-            if let TokenKind::Word(word) = &self.current_token.kind {
-                // Check for brace expansion like {1..10} or {a,b,c}
-                if word.starts_with('{')
-                    && word.ends_with('}')
-                    && (word.contains("..") || word.contains(','))
-                {
-                    if let Some(expanded) = self.expand_brace_pattern(word) {
-                        elements.extend(expanded);
-                    } else {
-                        elements.push(word.clone());
+        loop {
+            match &self.current_token.kind {
+                TokenKind::Word(word) => {
+                    elements.push(Self::escape_word(word));
+                    self.next_token();
+                }
+                TokenKind::Quote => {
+                    if let Node::StringLiteral(s) = self.parse_quoted_string(TokenKind::Quote) {
+                        elements.push(Self::quoted_word(s, '"'));
                     }
-                } else {
-                    elements.push(word.clone());
                 }
-            }
-            self.next_token();
-        }
-
-        Node::Array { elements }
-    }
-
-    // Expand brace patterns like {1..10}, {a..z}, or {a,b,c}
-    fn expand_brace_pattern(&self, word: &str) -> Option<Vec<String>> {
-        if !word.starts_with('{') || !word.ends_with('}') {
-            return None;
-        }
-
-        let inner = &word[1..word.len() - 1]; // Remove { and }
-
-        // Check for range expansion first
-        if inner.contains("..") {
-            return self.expand_brace_range(word);
-        }
-
-        // Check for comma-separated expansion
-        if inner.contains(',') {
-            let items: Vec<&str> = inner.split(',').collect();
-            let mut result = Vec::new();
-            for item in items {
-                result.push(item.trim().to_string());
-            }
-            return Some(result);
-        }
-
-        None
-    }
-
-    // Expand brace ranges like {1..10} or {a..z}
-    fn expand_brace_range(&self, word: &str) -> Option<Vec<String>> {
-        if !word.starts_with('{') || !word.ends_with('}') || !word.contains("..") {
-            return None;
-        }
-
-        let inner = &word[1..word.len() - 1]; // Remove { and }
-        let parts: Vec<&str> = inner.split("..").collect();
-        if parts.len() != 2 {
-            return None;
-        }
-
-        let start = parts[0];
-        let end = parts[1];
-
-        // Try numeric expansion
-        if let (Ok(start_num), Ok(end_num)) = (start.parse::<i32>(), end.parse::<i32>()) {
-            let mut result = Vec::new();
-            if start_num <= end_num {
-                for i in start_num..=end_num {
-                    result.push(i.to_string());
+                TokenKind::SingleQuote => {
+                    if let Node::SingleQuotedString(s) =
+                        self.parse_quoted_string(TokenKind::SingleQuote)
+                    {
+                        elements.push(Self::quoted_word(s, '\''));
+                    }
                 }
-            } else {
-                for i in (end_num..=start_num).rev() {
-                    result.push(i.to_string());
+                TokenKind::ParamExpansion => elements.push(self.parse_parameter_word()),
+                TokenKind::CmdSubst => elements.push(self.parse_command_substitution_word()),
+                TokenKind::ArithSubst => {
+                    if let Node::ArithmeticExpansion { expression } =
+                        self.parse_arithmetic_expansion()
+                    {
+                        elements.push(format!("$(({expression}))"));
+                    }
                 }
-            }
-            return Some(result);
-        }
-
-        // Try character expansion (single characters only)
-        if start.len() == 1 && end.len() == 1 {
-            let start_char = start.chars().next().unwrap();
-            let end_char = end.chars().next().unwrap();
-            let mut result = Vec::new();
-
-            if start_char <= end_char {
-                for c in start_char..=end_char {
-                    result.push(c.to_string());
+                TokenKind::Dollar => {
+                    self.next_token(); // Skip $
+                    let mut word = "$".to_string();
+                    if let TokenKind::Word(name) = &self.current_token.kind {
+                        word.push_str(name);
+                        self.next_token();
+                    }
+                    elements.push(word);
                 }
-            } else {
-                for c in (end_char..=start_char).rev() {
-                    result.push(c.to_string());
+                TokenKind::Semicolon | TokenKind::Newline | TokenKind::Do | TokenKind::EOF => {
+                    break;
+                }
+                _ => {
+                    elements.push(self.current_token.value.clone());
+                    self.next_token();
                 }
             }
-            return Some(result);
         }
 
-        None
+        Node::Array { elements }
     }
 
     // Parse while loop: while condition; do body; done
@@ -1059,6 +1895,7 @@ impl Parser {
 
         // Expect "do"
         if self.current_token.kind != TokenKind::Do {
+            self.expected("do");
             return Node::Command {
                 name: "echo".to_string(),
                 args: vec!["syntax error: expected 'do' after while condition".to_string()],
@@ -1087,6 +1924,7 @@ impl Parser {
 
         // Expect "do"
         if self.current_token.kind != TokenKind::Do {
+            self.expected("do");
             return Node::Command {
                 name: "echo".to_string(),
                 args: vec!["syntax error: expected 'do' after until condition".to_string()],
@@ -1116,6 +1954,7 @@ impl Parser {
             self.next_token();
             var
         } else {
+            self.expected("variable name");
             return Node::Command {
                 name: "echo".to_string(),
                 args: vec!["syntax error: expected variable name after 'select'".to_string()],
@@ -1125,6 +1964,7 @@ impl Parser {
 
         // Expect "in"
         if self.current_token.kind != TokenKind::In {
+            self.expected("in");
             return Node::Command {
                 name: "echo".to_string(),
                 args: vec!["syntax error: expected 'in' after select variable".to_string()],
@@ -1145,6 +1985,7 @@ impl Parser {
 
         // Expect "do"
         if self.current_token.kind != TokenKind::Do {
+            self.expected("do");
             return Node::Command {
                 name: "echo".to_string(),
                 args: vec!["syntax error: expected 'do' after select items".to_string()],
@@ -1190,94 +2031,49 @@ impl Parser {
         }
     }
 
-    // method to parse a single command condition until a specific token kind is encountered
+    // Parse the condition of if/elif/while/until: a list of commands, as in
+    // `if [ a ] && [ b ]; then`, up to the keyword that ends it. A word that closes
+    // some construct ends it too, so `if a; fi` reports the missing `then`
     fn parse_condition_until_token_kind(&mut self, stop_at: TokenKind) -> Node {
-        // Parse a single command as the condition
-        if let Some(statement) = self.parse_statement() {
-            // Skip any semicolons or newlines before the stop token
-            while (self.current_token.kind == TokenKind::Semicolon
-                || self.current_token.kind == TokenKind::Newline)
-                && self.current_token.kind != stop_at
-                && self.current_token.kind != TokenKind::EOF
-            {
-                self.next_token();
-            }
-            statement
-        } else {
-            // Return empty command if no valid statement found
-            Node::Command {
-                name: String::new(),
-                args: Vec::new(),
-                redirects: Vec::new(),
-            }
-        }
-    }
-
-    // method to parse statements until a specific token kind is encountered
-    fn parse_until_token_kind(&mut self, stop_at: TokenKind) -> Node {
-        let mut statements = Vec::new();
-        let mut operators = Vec::new();
-
-        while self.current_token.kind != stop_at && self.current_token.kind != TokenKind::EOF {
-            if let Some(statement) = self.parse_statement() {
-                statements.push(statement);
-
-                // Check for operators between statements
-                match self.current_token.kind {
-                    TokenKind::Semicolon => {
-                        operators.push(";".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::Newline => {
-                        operators.push("\n".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::And => {
-                        operators.push("&&".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::Background => {
-                        operators.push("&".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::Or => {
-                        operators.push("||".to_string());
-                        self.next_token();
-                    }
-                    _ => {
-                        if statements.len() > 1 && operators.len() < statements.len() - 1 {
-                            operators.push("".to_string());
-                        }
-                    }
-                }
-            } else {
-                // Skip tokens that don't form valid statements
-                self.next_token();
-            }
-        }
-
-        // Ensure we have the right number of operators
-        while operators.len() < statements.len() - 1 {
-            operators.push("".to_string());
+        let (mut statements, mut operators) = self.parse_list_until(&[
+            stop_at,
+            TokenKind::Then,
+            TokenKind::Do,
+            TokenKind::Elif,
+            TokenKind::Else,
+            TokenKind::Fi,
+            TokenKind::Done,
+            TokenKind::Esac,
+        ]);
+
+        // The `;` or newline before the keyword only ends the list
+        if operators.len() == statements.len()
+            && operators.last().is_some_and(|op| op == ";" || op == "\n")
+        {
+            operators.pop();
         }
 
-        // If we have statements, return a List node; otherwise, return an empty Command node
-        if !statements.is_empty() {
-            Node::List {
-                statements,
-                operators,
-            }
-        } else {
+        if statements.len() == 1 && operators.is_empty() {
+            statements.pop().unwrap()
+        } else if statements.is_empty() {
+            // Return empty command if no valid statement found
             Node::Command {
                 name: String::new(),
                 args: Vec::new(),
                 redirects: Vec::new(),
             }
+        } else {
+            Node::List {
+                statements,
+                operators,
+            }
         }
     }
 
-    // Helper method to parse statements until one of several token kinds is encountered
-    fn parse_until_token_kinds(&mut self, stop_at: &[TokenKind]) -> Node {
+    // Parse statements and the operators between them until one of the token kinds, or
+    // the end of input. Tokens that don't start a statement are skipped, so this always
+    // moves on
+    fn parse_list_until(&mut self, stop_at: &[TokenKind]) -> (Vec<Node>, Vec<String>) {
         let mut statements = Vec::new();
         let mut operators = Vec::new();
 
@@ -1288,43 +2084,50 @@ impl Parser {
                 statements.push(statement);
 
                 // Check for operators between statements
-                match self.current_token.kind {
-                    TokenKind::Semicolon => {
-                        operators.push(";".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::Newline => {
-                        operators.push("\n".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::And => {
-                        operators.push("&&".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::Background => {
-                        operators.push("&".to_string());
-                        self.next_token();
-                    }
-                    TokenKind::Or => {
-                        operators.push("||".to_string());
-                        self.next_token();
-                    }
+                let operator = match self.current_token.kind {
+                    TokenKind::Semicolon => ";",
+                    TokenKind::Newline => "\n",
+                    TokenKind::And => "&&",
+                    TokenKind::Background => "&",
+                    TokenKind::Or => "||",
                     _ => {
                         if statements.len() > 1 && operators.len() < statements.len() - 1 {
                             operators.push("".to_string());
                         }
+                        continue;
                     }
-                }
+                };
+                operators.push(operator.to_string());
+                self.next_token();
             } else {
                 // Skip tokens that don't form valid statements
                 self.next_token();
             }
         }
 
+        (statements, operators)
+    }
+
+    // method to parse statements until a specific token kind is encountered
+    fn parse_until_token_kind(&mut self, stop_at: TokenKind) -> Node {
+        self.parse_until_token_kinds(&[stop_at])
+    }
+
+    // Helper method to parse statements until one of several token kinds is encountered
+    fn parse_until_token_kinds(&mut self, stop_at: &[TokenKind]) -> Node {
+        let (statements, mut operators) = self.parse_list_until(stop_at);
+
+        // The last kind is the one that closes the construct, as `fi` after elif/else
+        if self.current_token.kind == TokenKind::EOF && !stop_at.contains(&TokenKind::EOF) {
+            if let Some(closing) = stop_at.last() {
+                self.expected(closing_text(closing));
+            }
+        }
+
         // If we have statements, return a List node; otherwise, return an empty Command node
         if !statements.is_empty() {
             // Ensure we have the right number of operators
-            while operators.len() < statements.len() - 1 {
+            while operators.len() < statements.len().saturating_sub(1) {
                 operators.push("".to_string());
             }
 
@@ -1419,22 +2222,7 @@ impl Parser {
         let value = match self.current_token.kind {
             TokenKind::Quote => {
                 // Handle double quoted string
-                self.next_token(); // Skip opening quote
-
-                let mut quoted_value = String::new();
-                while self.current_token.kind != TokenKind::Quote
-                    && self.current_token.kind != TokenKind::EOF
-                {
-                    if let TokenKind::Word(word) = &self.current_token.kind {
-                        quoted_value.push_str(word);
-                    }
-                    self.next_token();
-                }
-
-                if self.current_token.kind == TokenKind::Quote {
-                    self.next_token(); // Skip closing quote
-                }
-
+                let quoted_value = self.parse_quoted_string_value(TokenKind::Quote);
                 Box::new(Node::StringLiteral(quoted_value))
             }
             TokenKind::SingleQuote => {
@@ -1457,6 +2245,12 @@ impl Parser {
 
                 Box::new(Node::StringLiteral(quoted_value))
             }
+            TokenKind::AnsiCString(ref value) => {
+                // Handle $'...', whose escapes the lexer already decoded
+                let value = value.clone();
+                self.next_token();
+                Box::new(Node::SingleQuotedString(value))
+            }
             TokenKind::CmdSubst => {
                 // Handle command substitution like $(...)
                 let cmd_subst = self.parse_command_substitution();
@@ -1467,6 +2261,28 @@ impl Parser {
                 let arith_subst = self.parse_arithmetic_expansion();
                 Box::new(arith_subst)
             }
+            TokenKind::ParamExpansion => {
+                // Handle ${...}, expanded when assigned
+                Box::new(Node::StringLiteral(self.parse_parameter_word()))
+            }
+            TokenKind::Dollar => {
+                // Handle a variable reference like $name, expanded when assigned
+                self.next_token(); // Skip '$'
+                let mut value = String::from("$");
+                match &self.current_token.kind {
+                    TokenKind::Word(word) => {
+                        value.push_str(word);
+                        self.next_token(); // Skip variable name
+                    }
+                    TokenKind::Dollar => {
+                        // $$, the process ID of the shell
+                        value.push('$');
+                        self.next_token();
+                    }
+                    _ => {}
+                }
+                Box::new(Node::StringLiteral(value))
+            }
             TokenKind::Word(ref word) => {
                 let value = word.clone();
                 self.next_token(); // Skip value
@@ -1538,90 +2354,196 @@ impl Parser {
         Node::Assignment { name, value }
     }
 
+    // Parse leading VAR=value words; if a command follows on the same line,
+    // the assignments only apply to that command (FOO=bar cmd)
+    fn parse_assignments_and_command(&mut self) -> Node {
+        // Snapshot the parser so plain assignments can be re-parsed one at a time
+        let error_count = self.errors.len();
+        let snapshot = (
+            self.lexer.clone(),
+            self.current_token.clone(),
+            self.peek_token.clone(),
+            self.current_blank_before,
+            self.peek_blank_before,
+        );
+
+        let mut assignments = vec![self.parse_assignment()];
+
+        while matches!(self.current_token.kind, TokenKind::Word(_))
+            && self.peek_token.kind == TokenKind::Assignment
+        {
+            assignments.push(self.parse_assignment());
+        }
+
+        if !matches!(self.current_token.kind, TokenKind::Word(_)) {
+            // Plain assignments: keep them as standalone statements
+            if assignments.len() > 1 {
+                (
+                    self.lexer,
+                    self.current_token,
+                    self.peek_token,
+                    self.current_blank_before,
+                    self.peek_blank_before,
+                ) = snapshot;
+                self.errors.truncate(error_count);
+                return self.parse_assignment();
+            }
+            return assignments.remove(0);
+        }
+
+        // Only the first command of a pipeline receives the prefix assignments
+        match self.parse_command() {
+            Node::Pipeline { mut commands } if !commands.is_empty() => {
+                let first = commands.remove(0);
+                commands.insert(
+                    0,
+                    Node::PrefixedCommand {
+                        assignments,
+                        command: Box::new(first),
+                    },
+                );
+                Node::Pipeline { commands }
+            }
+            command => Node::PrefixedCommand {
+                assignments,
+                command: Box::new(command),
+            },
+        }
+    }
+
+    // Parse the elements of `name=(...)` in source form. Pieces that touch form one
+    // element, so `[key]=value` and `a"b c"` each stay whole.
     fn parse_array_assignment(&mut self, name: String) -> Node {
         self.next_token(); // Skip '('
 
-        let mut array_elements = Vec::new();
-
-        // Parse array elements until closing parenthesis
+        let mut elements: Vec<String> = Vec::new();
+        let mut in_element = false;
         while self.current_token.kind != TokenKind::RParen
             && self.current_token.kind != TokenKind::EOF
         {
-            match &self.current_token.kind {
+            let continues = in_element && !self.current_blank_before;
+            let piece = match &self.current_token.kind {
                 TokenKind::Word(word) => {
-                    array_elements.push(word.clone());
+                    let word = Self::escape_word(word);
                     self.next_token();
+                    word
                 }
-                TokenKind::SingleQuote => {
-                    self.next_token(); // Skip opening quote
-
-                    let mut quoted_value = String::new();
-                    while self.current_token.kind != TokenKind::SingleQuote
-                        && self.current_token.kind != TokenKind::EOF
-                    {
-                        if let TokenKind::Word(word) = &self.current_token.kind {
-                            quoted_value.push_str(word);
-                        }
-                        self.next_token();
-                    }
-
-                    if self.current_token.kind == TokenKind::SingleQuote {
-                        self.next_token(); // Skip closing quote
-                    }
-
-                    array_elements.push(quoted_value);
-                }
-                TokenKind::Quote => {
-                    self.next_token(); // Skip opening quote
-
-                    let mut quoted_value = String::new();
-                    while self.current_token.kind != TokenKind::Quote
-                        && self.current_token.kind != TokenKind::EOF
-                    {
-                        if let TokenKind::Word(word) = &self.current_token.kind {
-                            quoted_value.push_str(word);
-                        }
-                        self.next_token();
-                    }
-
-                    if self.current_token.kind == TokenKind::Quote {
-                        self.next_token(); // Skip closing quote
-                    }
-
-                    array_elements.push(quoted_value);
+                TokenKind::Quote => match self.parse_quoted_string(TokenKind::Quote) {
+                    Node::StringLiteral(s) => Self::quoted_word(s, '"'),
+                    _ => String::new(),
+                },
+                TokenKind::SingleQuote => match self.parse_quoted_string(TokenKind::SingleQuote) {
+                    Node::SingleQuotedString(s) => Self::quoted_word(s, '\''),
+                    _ => String::new(),
+                },
+                TokenKind::ParamExpansion => self.parse_parameter_word(),
+                TokenKind::CmdSubst => self.parse_command_substitution_word(),
+                TokenKind::ArithSubst => match self.parse_arithmetic_expansion() {
+                    Node::ArithmeticExpansion { expression } => format!("$(({expression}))"),
+                    _ => String::new(),
+                },
+                TokenKind::Assignment | TokenKind::Dollar => {
+                    let text = self.current_token.value.clone();
+                    self.next_token();
+                    text
                 }
                 _ => {
-                    // Skip other tokens like newlines or spaces in array
+                    // Newlines and comments separate elements
                     self.next_token();
+                    in_element = false;
+                    continue;
                 }
+            };
+            match elements.last_mut() {
+                Some(element) if continues => element.push_str(&piece),
+                _ => elements.push(piece),
             }
+            in_element = true;
         }
 
         if self.current_token.kind == TokenKind::RParen {
             self.next_token(); // Skip the closing parenthesis
+        } else {
+            self.expected(")");
         }
 
         Node::Assignment {
             name,
-            value: Box::new(Node::Array {
-                elements: array_elements,
-            }),
+            value: Box::new(Node::Array { elements }),
+        }
+    }
+
+    // Quoted arguments keep their quotes only when quoting changes how the
+    // interpreter expands them (expansions, globs, escapes or empty strings)
+    fn quoted_word(content: String, quote: char) -> String {
+        let needs_quotes = content.is_empty()
+            || content.contains(['$', '`', '\\', '*', '?', '[', '{', '~', '\'', '"']);
+        if needs_quotes {
+            format!("{quote}{content}{quote}")
+        } else {
+            content
+        }
+    }
+
+    // The lexer already removed the escapes of unquoted words, so escape the
+    // characters the interpreter would otherwise treat as quoting again
+    fn escape_word(word: &str) -> String {
+        let mut escaped = String::with_capacity(word.len());
+        for c in word.chars() {
+            if matches!(c, '\\' | '"' | '\'') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
         }
+        escaped
     }
 
     pub fn parse_command(&mut self) -> Node {
+        // A quoted name keeps its quotes, like a quoted argument, as in "$cmd" arg
         let name = match &self.current_token.kind {
-            TokenKind::Word(word) => word.clone(),
-            _ => String::new(),
+            TokenKind::Dollar => {
+                self.next_token(); // Skip $
+                let mut name = "$".to_string();
+                if let TokenKind::Word(word) = &self.current_token.kind {
+                    if !self.current_blank_before {
+                        name.push_str(word);
+                        self.next_token();
+                    }
+                }
+                name
+            }
+            TokenKind::Quote => match self.parse_quoted_string(TokenKind::Quote) {
+                Node::StringLiteral(s) => Self::quoted_word(s, '"'),
+                _ => String::new(),
+            },
+            TokenKind::SingleQuote => match self.parse_quoted_string(TokenKind::SingleQuote) {
+                Node::SingleQuotedString(s) => Self::quoted_word(s, '\''),
+                _ => String::new(),
+            },
+            kind => {
+                let name = match kind {
+                    TokenKind::Word(word) => word.clone(),
+                    _ => String::new(),
+                };
+                self.next_token();
+                name
+            }
         };
 
-        self.next_token();
-
-        let mut args = Vec::new();
+        let mut args: Vec<String> = Vec::new();
         let mut redirects = Vec::new();
+        let mut continues_word = false;
+        // Declaration builtins take whole arrays, as in `local a=(1 2)`, so other
+        // parentheses in their arguments stay quoted to tell them apart
+        let declaration = matches!(name.as_str(), "declare" | "typeset" | "local" | "readonly");
 
         // Loop to collect arguments and handle quotes
         loop {
+            // A part written right after the previous one, with no blank in between,
+            // belongs to the same word, as in a'b'"c"
+            let parts = args.len();
+            let joins = continues_word && !self.current_blank_before;
+
             match &self.current_token.kind {
                 TokenKind::Word(word) => {
                     // Special case: if command name is "[" and we encounter "]", include it and stop
@@ -1630,9 +2552,20 @@ impl Parser {
                         self.next_token(); // Skip the "]"
                         break;
                     }
+                    // A number written right before a redirection names the descriptor
+                    if let Some(fd) = self.redirect_fd_number() {
+                        self.next_token(); // Skip the number
+                        redirects.push(self.parse_redirect(Some(fd)));
+                        continue;
+                    }
                     // Check if this word is a variable reference (starts with $)
                     // and keep it as a single token
-                    args.push(word.clone());
+                    let word = Self::escape_word(word);
+                    if declaration {
+                        args.push(word.replace('(', "\"(\"").replace(')', "\")\""));
+                    } else {
+                        args.push(word);
+                    }
                     self.next_token();
                 }
                 TokenKind::ArithSubst => {
@@ -1642,47 +2575,50 @@ impl Parser {
                         args.push(format!("$(({expression}))"));
                     }
                 }
+                TokenKind::ParamExpansion => {
+                    // Keep ${...} as a single word, expanded when the command runs
+                    args.push(self.parse_parameter_word());
+                }
                 TokenKind::CmdSubst => {
                     // Handle command substitution like $(...)
-                    let cmd_subst = self.parse_command_substitution();
-                    if let Node::CommandSubstitution { command } = &cmd_subst {
-                        if let Node::Command {
-                            name,
-                            args: cmd_args,
-                            redirects,
-                        } = command.as_ref()
-                        {
-                            if redirects.is_empty() && cmd_args.is_empty() {
-                                args.push(format!("$({name})"));
-                            } else if redirects.is_empty() {
-                                let mut cmd_str = name.clone();
-                                for arg in cmd_args {
-                                    cmd_str.push(' ');
-                                    cmd_str.push_str(arg);
-                                }
-                                args.push(format!("$({cmd_str})"));
-                            } else {
-                                args.push("$(...)".to_string());
-                            }
-                        } else {
-                            args.push("$(...)".to_string());
-                        }
-                    }
+                    args.push(self.parse_command_substitution_word());
+                }
+                TokenKind::ProcessSubstIn => {
+                    // <(...) is kept in source form and started when the command runs
+                    let node = self.parse_process_substitution(ProcessSubstDirection::Input);
+                    args.push(node.to_shell_source());
+                }
+                TokenKind::ProcessSubstOut => {
+                    let node = self.parse_process_substitution(ProcessSubstDirection::Output);
+                    args.push(node.to_shell_source());
                 }
                 TokenKind::Quote => {
                     // Handle double quoted strings
                     let quoted = self.parse_quoted_string(TokenKind::Quote);
                     if let Node::StringLiteral(s) = quoted {
-                        args.push(s);
+                        if declaration && s.contains(['(', ')']) {
+                            args.push(format!("\"{s}\""));
+                        } else {
+                            args.push(Self::quoted_word(s, '"'));
+                        }
                     }
                 }
                 TokenKind::SingleQuote => {
                     // Handle single quoted strings
                     let quoted = self.parse_quoted_string(TokenKind::SingleQuote);
                     if let Node::SingleQuotedString(s) = quoted {
-                        args.push(s);
+                        if declaration && s.contains(['(', ')']) {
+                            args.push(format!("'{s}'"));
+                        } else {
+                            args.push(Self::quoted_word(s, '\''));
+                        }
                     }
                 }
+                TokenKind::AnsiCString(value) => {
+                    // The decoded value is single-quoted so it is not expanded again
+                    args.push(Self::quoted_word(value.replace('\'', "'\\''"), '\''));
+                    self.next_token();
+                }
                 // Handle keywords as regular arguments when they appear in command arguments
                 TokenKind::Continue => {
                     args.push("continue".to_string());
@@ -1759,8 +2695,15 @@ impl Parser {
 
                     args.push(pattern_str);
                 }
-                TokenKind::Less | TokenKind::Great | TokenKind::DGreat => {
-                    let redirect = self.parse_redirect();
+                TokenKind::Less
+                | TokenKind::Great
+                | TokenKind::DGreat
+                | TokenKind::LessAnd
+                | TokenKind::GreatAnd
+                | TokenKind::HereDoc
+                | TokenKind::HereDocDash
+                | TokenKind::HereString => {
+                    let redirect = self.parse_redirect(None);
                     redirects.push(redirect);
                 }
                 TokenKind::Dollar => {
@@ -1790,7 +2733,7 @@ impl Parser {
 
                     // Check if the next token is also a Dollar or Word that should be concatenated
                     // This handles cases like $i$j where consecutive variables should be one argument
-                    while let TokenKind::Dollar = &self.current_token.kind {
+                    while !self.current_blank_before {
                         match &self.current_token.kind {
                             TokenKind::Dollar => {
                                 // Another variable reference - concatenate it
@@ -1828,8 +2771,29 @@ impl Parser {
                 }
                 TokenKind::Assignment => {
                     // In command context, treat = as a regular argument
-                    args.push("=".to_string());
                     self.next_token();
+                    // The elements of `name=(...)` stay in source form for the interpreter
+                    if declaration
+                        && self.current_token.kind == TokenKind::LParen
+                        && !self.current_blank_before
+                    {
+                        // `name=(`, each element and `)` become separate arguments
+                        match args.last_mut() {
+                            Some(word) if joins => word.push_str("=("),
+                            _ => args.push("=(".to_string()),
+                        }
+                        if let Node::Assignment { value, .. } =
+                            self.parse_array_assignment(String::new())
+                        {
+                            if let Node::Array { elements } = *value {
+                                args.extend(elements);
+                            }
+                        }
+                        args.push(")".to_string());
+                        continues_word = false;
+                        continue;
+                    }
+                    args.push("=".to_string());
                 }
                 TokenKind::LBrace => {
                     // Handle brace expansion like {1..5} or {a,b,c}
@@ -1862,14 +2826,21 @@ impl Parser {
                 }
                 _ => break, // Exit when we're not on a word, quote, or redirect token
             }
+
+            continues_word = args.len() > parts;
+            if joins && continues_word && parts > 0 {
+                let part = args.pop().unwrap_or_default();
+                if let Some(word) = args.last_mut() {
+                    word.push_str(&part);
+                }
+            }
         }
 
         // Check for pipeline
         if self.current_token.kind == TokenKind::Pipe {
             self.next_token(); // Skip the '|'
 
-            // Parse the next command in the pipeline
-            let next_command = self.parse_command();
+            let next_command = self.parse_pipeline_stage();
 
             let mut commands = vec![Node::Command {
                 name,
@@ -1902,6 +2873,21 @@ impl Parser {
     pub fn parse_command_substitution(&mut self) -> Node {
         self.next_token(); // Skip '$('
 
+        // $(< file) is a bare input redirection, which reads the file
+        if self.current_token.kind == TokenKind::Less {
+            let redirect = self.parse_redirect(None);
+            if self.current_token.kind == TokenKind::RParen {
+                self.next_token(); // Skip ')'
+            }
+            return Node::CommandSubstitution {
+                command: Box::new(Node::Command {
+                    name: String::new(),
+                    args: Vec::new(),
+                    redirects: vec![redirect],
+                }),
+            };
+        }
+
         // Parse the command inside the substitution
         let mut statements = Vec::new();
         let mut operators = Vec::new();
@@ -1941,7 +2927,7 @@ impl Parser {
         }
 
         // Ensure we have the right number of operators
-        while operators.len() < statements.len() - 1 {
+        while operators.len() < statements.len().saturating_sub(1) {
             operators.push("".to_string());
         }
 
@@ -1962,7 +2948,17 @@ impl Parser {
         }
     }
 
+    fn arithmetic_source(value: &str, opening: &str) -> Option<String> {
+        let body = value.strip_prefix(opening)?.strip_suffix("))")?;
+        Some(body.trim().to_string())
+    }
+
     pub fn parse_arithmetic_expansion(&mut self) -> Node {
+        // The lexer keeps the source text when the closing `))` is found
+        if let Some(expression) = Self::arithmetic_source(&self.current_token.value, "$((") {
+            self.next_token();
+            return Node::ArithmeticExpansion { expression };
+        }
         self.next_token(); // Skip '$(('
 
         let mut expression = String::new();
@@ -1985,7 +2981,10 @@ impl Parser {
                     self.next_token();
                 }
                 TokenKind::Word(word) => {
-                    if !expression.is_empty() && !expression.ends_with(' ') {
+                    if !expression.is_empty()
+                        && !expression.ends_with(' ')
+                        && !expression.ends_with('$')
+                    {
                         expression.push(' ');
                     }
                     expression.push_str(word);
@@ -1995,6 +2994,13 @@ impl Parser {
                     expression.push('$');
                     self.next_token();
                 }
+                TokenKind::ParamExpansion => {
+                    if !expression.is_empty() && !expression.ends_with(' ') {
+                        expression.push(' ');
+                    }
+                    let word = self.parse_parameter_word();
+                    expression.push_str(&word);
+                }
                 TokenKind::Assignment => {
                     expression.push('=');
                     self.next_token();
@@ -2023,6 +3029,10 @@ impl Parser {
     }
 
     pub fn parse_arithmetic_command(&mut self) -> Node {
+        if let Some(expression) = Self::arithmetic_source(&self.current_token.value, "((") {
+            self.next_token();
+            return Node::ArithmeticCommand { expression };
+        }
         self.next_token(); // Skip '(('
 
         let mut expression = String::new();
@@ -2088,6 +3098,16 @@ impl Parser {
                         }
                     }
                 }
+                TokenKind::ParamExpansion => {
+                    if !expression.is_empty()
+                        && !expression.ends_with(' ')
+                        && !expression.ends_with('(')
+                    {
+                        expression.push(' ');
+                    }
+                    let word = self.parse_parameter_word();
+                    expression.push_str(&word);
+                }
                 TokenKind::Assignment => {
                     if !expression.is_empty() && !expression.ends_with(' ') {
                         expression.push(' ');
@@ -2277,6 +3297,25 @@ impl Parser {
         }
     }
 
+    /// Parse the whole input like `parse_script`, but fail with the syntax errors found
+    /// instead of recovering from them
+    pub fn parse_script_checked(&mut self) -> Result<Node, Vec<ParseError>> {
+        let script = self.parse_script();
+        if let Some(Position { line, column }) = self.lexer.unterminated_quote() {
+            self.errors.push(ParseError {
+                message: "unterminated quote".to_string(),
+                line,
+                column,
+            });
+        }
+        if self.errors.is_empty() {
+            return Ok(script);
+        }
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.sort_by_key(|error| (error.line, error.column));
+        Err(errors)
+    }
+
     pub fn parse_script(&mut self) -> Node {
         let mut statements = Vec::new();
         let mut operators = Vec::new();
@@ -2316,41 +3355,134 @@ impl Parser {
                     }
                 }
             } else {
-                // Skip tokens that don't form valid statements
+                // Closing words and operators with nothing open are errors; other tokens
+                // that don't form statements are skipped
+                if matches!(
+                    self.current_token.kind,
+                    TokenKind::RParen
+                        | TokenKind::Fi
+                        | TokenKind::Done
+                        | TokenKind::Esac
+                        | TokenKind::Then
+                        | TokenKind::Do
+                        | TokenKind::Elif
+                        | TokenKind::Else
+                ) {
+                    let token = self.current_token.value.clone();
+                    self.error(format!("unexpected `{token}`"));
+                }
+                self.next_token();
+            }
+        }
+
+        // make sure we have the right number of operators
+        while operators.len() < statements.len().saturating_sub(1) {
+            operators.push("".to_string());
+        }
+
+        Node::List {
+            statements,
+            operators,
+        }
+    }
+
+    // Fix for redirection handling
+    fn parse_redirect(&mut self, fd: Option<i32>) -> Redirect {
+        let kind = match self.current_token.kind {
+            TokenKind::Less => RedirectKind::Input,
+            TokenKind::Great => RedirectKind::Output,
+            TokenKind::DGreat => RedirectKind::Append,
+            TokenKind::LessAnd => RedirectKind::InputDup,
+            TokenKind::GreatAnd => RedirectKind::OutputDup,
+            TokenKind::HereDoc => RedirectKind::HereDoc,
+            TokenKind::HereDocDash => RedirectKind::HereDocDash,
+            TokenKind::HereString => RedirectKind::HereString,
+            _ => panic!("Expected a redirection token"),
+        };
+
+        self.next_token(); // Skip the redirection operator
+        let fd = fd.unwrap_or_else(|| kind.default_fd());
+
+        // A here-document carries its delimiter and then the body read by the lexer
+        if matches!(kind, RedirectKind::HereDoc | RedirectKind::HereDocDash) {
+            let mut quoted = false;
+            if let TokenKind::Word(delimiter) = &self.current_token.kind {
+                quoted = delimiter.contains(['\'', '"', '\\']);
+                self.next_token();
+            }
+            let mut file = String::new();
+            if let TokenKind::HereDocContent(body) = &self.current_token.kind {
+                file = body.clone();
                 self.next_token();
             }
+            return Redirect {
+                kind,
+                file,
+                fd,
+                quoted,
+            };
+        }
+
+        // The target is kept in source form and expanded when the command runs. Parts
+        // written without blanks between them form one word, as in out"$n".txt
+        let mut file = String::new();
+        loop {
+            let part = match &self.current_token.kind {
+                TokenKind::Word(word) => {
+                    let word = Self::escape_word(word);
+                    self.next_token();
+                    word
+                }
+                TokenKind::Quote => match self.parse_quoted_string(TokenKind::Quote) {
+                    Node::StringLiteral(s) => Self::quoted_word(s, '"'),
+                    _ => String::new(),
+                },
+                TokenKind::SingleQuote => match self.parse_quoted_string(TokenKind::SingleQuote) {
+                    Node::SingleQuotedString(s) => Self::quoted_word(s, '\''),
+                    _ => String::new(),
+                },
+                TokenKind::ParamExpansion => self.parse_parameter_word(),
+                TokenKind::CmdSubst => self.parse_command_substitution_word(),
+                TokenKind::Dollar => {
+                    self.next_token(); // Skip $
+                    let mut word = "$".to_string();
+                    if let TokenKind::Word(name) = &self.current_token.kind {
+                        word.push_str(name);
+                        self.next_token();
+                    }
+                    word
+                }
+                _ => break,
+            };
+            file.push_str(&part);
+            if self.current_blank_before {
+                break;
+            }
         }
 
-        // make sure we have the right number of operators
-        while operators.len() < statements.len() - 1 {
-            operators.push("".to_string());
-        }
-
-        Node::List {
-            statements,
-            operators,
+        Redirect {
+            kind,
+            file,
+            fd,
+            quoted: false,
         }
     }
 
-    // Fix for redirection handling
-    fn parse_redirect(&mut self) -> Redirect {
-        let kind = match self.current_token.kind {
-            TokenKind::Less => RedirectKind::Input,
-            TokenKind::Great => RedirectKind::Output,
-            TokenKind::DGreat => RedirectKind::Append,
-            _ => panic!("Expected a redirection token"),
-        };
-
-        self.next_token(); // Skip the redirection operator
-
-        let file = match &self.current_token.kind {
-            TokenKind::Word(word) => word.clone(),
-            _ => String::new(),
+    /// The descriptor number when the current word is digits written directly
+    /// before a redirection operator, as in `2>file`
+    fn redirect_fd_number(&self) -> Option<i32> {
+        let TokenKind::Word(word) = &self.current_token.kind else {
+            return None;
         };
-
-        self.next_token(); // Skip the filename
-
-        Redirect { kind, file }
+        let is_redirect = is_redirect_operator(&self.peek_token.kind);
+        let current = &self.current_token.position;
+        let next = &self.peek_token.position;
+        let adjacent = next.line == current.line && next.column == current.column + word.len();
+        if is_redirect && adjacent && word.bytes().all(|b| b.is_ascii_digit()) {
+            word.parse().ok()
+        } else {
+            None
+        }
     }
 
     fn parse_subshell(&mut self) -> Node {
@@ -2418,6 +3550,8 @@ impl Parser {
         // Skip closing parenthesis if present
         if self.current_token.kind == TokenKind::RParen {
             self.next_token();
+        } else {
+            self.expected(")");
         }
 
         // Create the subshell node
@@ -2435,22 +3569,94 @@ impl Parser {
     fn parse_extended_test(&mut self) -> Node {
         self.next_token(); // Skip '[['
 
-        // Parse the condition inside [[ ]]
-        let mut condition_parts = Vec::new();
+        // Parse the condition inside [[ ]] into words, joining tokens that touch in
+        // the source. Quoting is kept so operators and patterns can tell quoted text apart.
+        let mut condition_parts: Vec<String> = Vec::new();
+        let mut previous_end: Option<(usize, usize)> = None;
 
         while self.current_token.kind != TokenKind::DoubleRBracket
             && self.current_token.kind != TokenKind::EOF
         {
-            match &self.current_token.kind {
-                TokenKind::Word(word) => {
-                    condition_parts.push(word.clone());
+            let start = self.current_token.position;
+            let after_regex_operator = condition_parts.last().is_some_and(|w| w == "=~");
+            let in_regex = after_regex_operator
+                || condition_parts.len() >= 2 && condition_parts[condition_parts.len() - 2] == "=~";
+
+            let (text, end, operator) = match &self.current_token.kind {
+                TokenKind::Newline => {
                     self.next_token();
+                    previous_end = None;
+                    continue;
                 }
-                _ => {
-                    condition_parts.push(self.current_token.value.clone());
+                TokenKind::Quote | TokenKind::SingleQuote => {
+                    let quote_kind = self.current_token.kind.clone();
+                    let quote = if quote_kind == TokenKind::Quote {
+                        '"'
+                    } else {
+                        '\''
+                    };
+                    self.next_token(); // Skip opening quote
+
+                    let mut content = String::new();
+                    while self.current_token.kind != quote_kind
+                        && self.current_token.kind != TokenKind::EOF
+                    {
+                        content.push_str(&self.current_token.value);
+                        self.next_token();
+                    }
+                    let close = self.current_token.position;
+                    self.next_token(); // Skip closing quote
+                    (
+                        format!("{quote}{content}{quote}"),
+                        (close.line, close.column + 1),
+                        false,
+                    )
+                }
+                TokenKind::ParamExpansion => {
+                    let word = self.parse_parameter_word();
+                    let end = (start.line, start.column + word.chars().count());
+                    (word, end, false)
+                }
+                TokenKind::CmdSubst => {
+                    // The closing parenthesis is consumed by the sub-parser, so nothing joins after it
+                    (self.parse_command_substitution_word(), (0, 0), false)
+                }
+                TokenKind::ArithSubst => {
+                    let mut word = String::new();
+                    if let Node::ArithmeticExpansion { expression } =
+                        self.parse_arithmetic_expansion()
+                    {
+                        word = format!("$(({expression}))");
+                    }
+                    (word, (0, 0), false)
+                }
+                kind => {
+                    let operator = match kind {
+                        TokenKind::And | TokenKind::Or => true,
+                        TokenKind::LParen | TokenKind::RParen => !in_regex,
+                        _ => false,
+                    };
+                    let text = match kind {
+                        TokenKind::Word(word) => Self::escape_word(word),
+                        _ => self.current_token.value.clone(),
+                    };
+                    let end = (
+                        start.line,
+                        start.column + self.current_token.value.chars().count(),
+                    );
                     self.next_token();
+                    (text, end, operator)
+                }
+            };
+
+            let touches_previous = previous_end == Some((start.line, start.column));
+            match condition_parts.last_mut() {
+                Some(last) if touches_previous && !operator => {
+                    last.push_str(&text);
                 }
+                _ => condition_parts.push(text),
             }
+            previous_end = if operator { None } else { Some(end) };
         }
 
         if self.current_token.kind == TokenKind::DoubleRBracket {
@@ -2492,6 +3698,93 @@ impl Parser {
         Node::HistoryExpansion { pattern }
     }
 
+    // Read $(...) back into a word for the interpreter to run when expanding it
+    fn parse_command_substitution_word(&mut self) -> String {
+        let cmd_subst = self.parse_command_substitution();
+        let Node::CommandSubstitution { command } = &cmd_subst else {
+            return String::new();
+        };
+        match command.as_ref() {
+            Node::Command {
+                name,
+                args,
+                redirects,
+            } if redirects.is_empty() => {
+                let mut cmd_str = name.clone();
+                for arg in args {
+                    cmd_str.push(' ');
+                    cmd_str.push_str(arg);
+                }
+                format!("$({cmd_str})")
+            }
+            Node::Command {
+                name,
+                args,
+                redirects,
+            } if name.is_empty() && args.is_empty() && redirects.len() == 1 => {
+                format!("$(< {})", redirects[0].file)
+            }
+            command => format!("$({})", command.to_shell_source()),
+        }
+    }
+
+    // Read ${...} back into its source text
+    /// One alternative of a case pattern, kept in source form so the interpreter can
+    /// tell quoted parts (matched literally) from glob characters
+    fn parse_case_pattern(&mut self) -> Option<String> {
+        let mut pattern = String::new();
+        loop {
+            match &self.current_token.kind {
+                TokenKind::Word(word) => {
+                    pattern.push_str(&Self::escape_word(word));
+                    self.next_token();
+                }
+                TokenKind::Quote => {
+                    if let Node::StringLiteral(s) = self.parse_quoted_string(TokenKind::Quote) {
+                        pattern.push_str(&format!("\"{s}\""));
+                    }
+                }
+                TokenKind::SingleQuote => {
+                    if let Node::SingleQuotedString(s) =
+                        self.parse_quoted_string(TokenKind::SingleQuote)
+                    {
+                        pattern.push_str(&format!("'{s}'"));
+                    }
+                }
+                TokenKind::AnsiCString(value) => {
+                    pattern.push_str(&format!("'{}'", value.replace('\'', "'\\''")));
+                    self.next_token();
+                }
+                TokenKind::ParamExpansion => pattern.push_str(&self.parse_parameter_word()),
+                TokenKind::Dollar => {
+                    self.next_token(); // Skip $
+                    pattern.push('$');
+                    if let TokenKind::Word(name) = &self.current_token.kind {
+                        pattern.push_str(name);
+                        self.next_token();
+                    }
+                }
+                _ => break,
+            }
+        }
+        (!pattern.is_empty()).then_some(pattern)
+    }
+
+    fn parse_parameter_word(&mut self) -> String {
+        self.next_token(); // Skip ${
+
+        let mut word = String::from("${");
+        if let TokenKind::Word(body) = &self.current_token.kind {
+            word.push_str(body);
+            self.next_token(); // Skip the body
+        }
+        if self.current_token.kind == TokenKind::RBrace {
+            word.push('}');
+            self.next_token(); // Skip }
+        }
+        word
+    }
+
     // Parse parameter expansion: ${var}, ${var:-default}, etc.
     fn parse_parameter_expansion(&mut self) -> Node {
         self.next_token(); // Skip ${
@@ -2517,8 +3810,9 @@ impl Parser {
                     self.next_token(); // Skip variable name
                 }
             } else {
-                // Regular variable name
-                parameter = word.clone();
+                // The lexer keeps the whole body as one word, so the name and operator
+                // are split from it
+                (parameter, expansion_type) = split_parameter_body(word);
                 self.next_token(); // Skip variable name
 
                 // Check for expansion operators
@@ -2712,6 +4006,27 @@ impl Parser {
 
         Node::Negation { command }
     }
+
+    // Parse time [-p] pipeline
+    fn parse_time(&mut self) -> Node {
+        self.next_token(); // Skip 'time'
+
+        let posix = self.current_token.kind == TokenKind::Word("-p".to_string());
+        if posix {
+            self.next_token(); // Skip '-p'
+        }
+
+        // Time the whole pipeline that follows; a bare `time` times nothing
+        let pipeline = match self.parse_statement() {
+            Some(pipeline) => Box::new(pipeline),
+            None => Box::new(Node::List {
+                statements: vec![],
+                operators: vec![],
+            }),
+        };
+
+        Node::Time { pipeline, posix }
+    }
 }
 
 #[cfg(test)]
@@ -3528,6 +4843,60 @@ mod parser_tests {
         }
     }
 
+    #[test]
+    fn test_break_and_continue_levels() {
+        let result = parse_test("while true; do break; continue 2; done");
+        let Node::List { statements, .. } = result else {
+            panic!("expected a list");
+        };
+        let Node::WhileLoop { body, .. } = &statements[0] else {
+            panic!("expected a while loop, got {:?}", statements[0]);
+        };
+        let Node::List { statements, .. } = body.as_ref() else {
+            panic!("expected a loop body list");
+        };
+        assert_eq!(statements[0], Node::Break { level: 1 });
+        assert_eq!(statements[1], Node::Continue { level: 2 });
+    }
+
+    #[test]
+    fn test_assignment_from_variable() {
+        let result = parse_test("copy=$original");
+        assert_eq!(
+            result,
+            Node::List {
+                statements: vec![Node::Assignment {
+                    name: "copy".to_string(),
+                    value: Box::new(Node::StringLiteral("$original".to_string())),
+                }],
+                operators: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_extended_test_words() {
+        let result = parse_test("[[ ! ( $v == \"a b\" ) && $x =~ ^a(b|c)$ ]]");
+        assert_eq!(
+            result,
+            Node::List {
+                statements: vec![Node::ExtendedTest {
+                    condition: Box::new(Node::Command {
+                        name: "[[".to_string(),
+                        args: [
+                            "!", "(", "$v", "==", "\"a b\"", ")", "&&", "$x", "=~", "^a(b|c)$"
+                        ]
+                        .iter()
+                        .map(|word| word.to_string())
+                        .collect(),
+                        redirects: vec![],
+                    }),
+                }],
+                operators: vec![],
+            }
+        );
+    }
+
     #[test]
     fn test_simple_assignments() {
         let input = r#"
@@ -3559,7 +4928,7 @@ mod parser_tests {
                                         args: vec![
                                             "$LOG_DIR".to_string(),
                                             "-name".to_string(),
-                                            "*.log".to_string()
+                                            "\"*.log\"".to_string()
                                         ],
                                         redirects: vec![],
                                     },
@@ -4148,6 +5517,185 @@ EOF"#;
         let _result = parse_test(input);
     }
 
+    #[test]
+    fn test_here_string() {
+        let result = parse_test(r#"grep foo <<< "$CONTENT"x file"#);
+
+        let Node::List { statements, .. } = result else {
+            panic!("Expected List node, got: {result:?}");
+        };
+        let Node::Command {
+            args, redirects, ..
+        } = &statements[0]
+        else {
+            panic!("Expected Command node, got: {:?}", statements[0]);
+        };
+        assert_eq!(args, &["foo", "file"]);
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(redirects[0].kind, RedirectKind::HereString);
+        assert_eq!(redirects[0].fd, 0);
+        assert_eq!(redirects[0].file, "\"$CONTENT\"x");
+    }
+
+    #[test]
+    fn test_parse_script_checked_reports_positions() {
+        let check = |input: &str| Parser::new(Lexer::new(input)).parse_script_checked();
+        let errors = |input: &str| {
+            check(input)
+                .unwrap_err()
+                .into_iter()
+                .map(|error| (error.line, error.column, error.message))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(check("if a; then b; fi\nfor i in 1; do echo $i; done").is_ok());
+        match check("echo hi | while read l; do echo $l; done | sort") {
+            Ok(Node::List { statements, .. }) => assert!(matches!(
+                &statements[..],
+                [Node::Pipeline { commands }]
+                    if matches!(&commands[..], [_, Node::WhileLoop { .. }, _])
+            )),
+            other => panic!("expected a pipeline, got {other:?}"),
+        }
+
+        // Redirections after a compound command belong to it
+        let redirected = |input: &str| match check(input) {
+            Ok(Node::List { statements, .. }) => match &statements[..] {
+                [Node::Redirected { command, redirects }] => {
+                    Some((command.as_ref().clone(), redirects[0].file.clone()))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        assert!(matches!(
+            redirected("{ echo a; } > f"),
+            Some((Node::Group { .. }, file)) if file == "f"
+        ));
+        assert!(matches!(
+            redirected("while read l; do echo $l; done < f"),
+            Some((Node::WhileLoop { .. }, file)) if file == "f"
+        ));
+        assert_eq!(
+            errors("echo ok\necho \"abc"),
+            [(2, 6, "unterminated quote".to_string())]
+        );
+        assert_eq!(
+            errors("if a\nthen\n  b\n"),
+            [(4, 1, "expected `fi` but found end of file".to_string())]
+        );
+        assert_eq!(
+            errors("for i in 1; do"),
+            [(1, 15, "expected `done` but found end of file".to_string())]
+        );
+        assert_eq!(
+            errors("if a; fi"),
+            [(1, 7, "expected `then` but found `fi`".to_string())]
+        );
+        assert_eq!(errors("echo a )"), [(1, 8, "unexpected `)`".to_string())]);
+        assert_eq!(
+            errors("(echo a; case x in a) b;;"),
+            [
+                (1, 26, "expected `esac` but found end of file".to_string()),
+                (1, 26, "expected `)` but found end of file".to_string())
+            ]
+        );
+
+        // The lenient parser still returns a tree for the same input
+        let mut parser = Parser::new(Lexer::new("for i in 1; do"));
+        assert!(matches!(parser.parse_script(), Node::List { .. }));
+    }
+
+    #[test]
+    fn test_parse_script_checked_accepts_condition_lists_and_quoted_words() {
+        let check = |input: &str| Parser::new(Lexer::new(input)).parse_script_checked();
+        for input in [
+            "if [ a ] && [ b ]; then echo y; fi",
+            "if true || false; then echo y; fi",
+            "while true && false; do echo; done",
+            "until ! true || false\ndo echo; done",
+            "if a; then b; elif [ c ] && d; then e; fi",
+            "if \"$x\"; then echo y; fi",
+            "'ls' -l; $cmd arg",
+            // Clause bodies with && and || used to keep the parser from moving on
+            "case $1 in\n--help) printf '%s\\n' \"$usage\" || exit 1; exit;;\nesac",
+            "case $1 in x) a && b;; y) c & ;; esac",
+            // A backslash doesn't escape the closing single quote
+            "case $i in (*'\\'*) x=1;; (*) y;; esac",
+            "echo \"a\\\\\" b",
+        ] {
+            assert!(check(input).is_ok(), "{input}: {:?}", check(input));
+        }
+
+        // The whole and-or list is the condition, not just its first command
+        match check("if [ a ] && [ b ]; then echo y; fi") {
+            Ok(Node::List { statements, .. }) => match &statements[..] {
+                [Node::IfStatement { condition, .. }] => assert!(matches!(
+                    condition.as_ref(),
+                    Node::List { statements, operators }
+                        if statements.len() == 2 && operators == &["&&"]
+                )),
+                other => panic!("expected an if statement, got {other:?}"),
+            },
+            other => panic!("expected a list, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_to_shell_source_normalizes_and_requotes() {
+        assert_eq!(
+            parse_test("echo  hello   world").to_shell_source(),
+            "echo hello world"
+        );
+        assert_eq!(
+            parse_test("if true;then echo \"a b\" x\\ y;fi").to_shell_source(),
+            "if true; then echo \"a b\" \"x y\"; fi"
+        );
+        // Words holding expansions keep the quotes they were written with
+        assert_eq!(
+            parse_test("echo \"$HOME\"  it\"'\"s").to_string(),
+            "echo \"$HOME\" it\"'\"s"
+        );
+    }
+
+    #[test]
+    fn test_to_shell_source_round_trips() {
+        let scripts = [
+            "if a; then b; elif c; then d; else e; fi",
+            "for i in 1 \"2 3\" $x; do echo $i; done",
+            "case $x in a|b) echo ab;; *) echo no;; esac",
+            "case $x in a) echo a;& b) echo b;;& *) echo c;; esac",
+            "x=1 y=(1 \"2 3\") z=\"$a b\" cmd arg",
+            "f() { echo hi; }; f && g || h",
+            "! a | b &\ntime -p sleep 1; (cd /; ls) && ((i++))",
+            "echo $(ls) >out 2>&1 3<&0; grep x <<< \"$w\"",
+            "cat <<EOF; echo b\nhello $x\nEOF\necho c",
+            "while read l; do # line\n  echo \"$l\"\ndone",
+            "[[ $a == b && -n \"$c\" ]]; diff <(ls) >(cat)",
+            "${s: -1}; ${s:-1}; ${s:2:3}; ${#s}; ${s%%.*}",
+        ];
+        for script in scripts {
+            let ast = parse_test(script);
+            let source = ast.to_shell_source();
+            assert_eq!(
+                parse_test(&source),
+                ast,
+                "{script:?} rendered as {source:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_parse_to_json_round_trips_nested_nodes() {
+        let input = "if true; then cat < in | grep x > out; fi";
+        let json = parse_to_json(input).unwrap();
+        assert!(json.contains("\"IfStatement\""));
+
+        let ast: Node = serde_json::from_str(&json).unwrap();
+        assert_eq!(ast, parse_test(input));
+    }
+
     #[test]
     fn test_background_execution() {
         let input = "long_running_command &";
@@ -4162,6 +5710,18 @@ EOF"#;
         }
     }
 
+    #[test]
+    fn test_process_substitution_arguments() {
+        let result = create_parser("diff <(sort a) >(cat -n)").parse_statement();
+        match result {
+            Some(Node::Command { name, args, .. }) => {
+                assert_eq!(name, "diff");
+                assert_eq!(args, vec!["<(sort a)", ">(cat -n)"]);
+            }
+            _ => panic!("Expected Command node, got {result:?}"),
+        }
+    }
+
     #[test]
     fn test_redirect_with_file_descriptor() {
         let input = "command 2>&1";
@@ -4224,6 +5784,51 @@ EOF"#;
         let _result = parse_test(input);
     }
 
+    #[test]
+    fn test_parameter_expansion_splits_operator_from_name() {
+        let expansion = |input: &str| match parse_test(input) {
+            Node::List { statements, .. } => match &statements[0] {
+                Node::ParameterExpansion {
+                    parameter,
+                    expansion_type,
+                } => (parameter.clone(), expansion_type.clone()),
+                other => panic!("Expected ParameterExpansion node, got {other:?}"),
+            },
+            _ => panic!("Expected List node"),
+        };
+
+        assert_eq!(
+            expansion("${unset:-1}"),
+            (
+                "unset".to_string(),
+                ParameterExpansionType::Default("1".to_string())
+            )
+        );
+        assert_eq!(
+            expansion("${set: -1}"),
+            (
+                "set".to_string(),
+                ParameterExpansionType::Substring(Some(-1), None)
+            )
+        );
+        assert_eq!(
+            expansion("${set:(-3):2}"),
+            (
+                "set".to_string(),
+                ParameterExpansionType::Substring(Some(-3), Some(2))
+            )
+        );
+        assert_eq!(
+            expansion("${#set}"),
+            ("set".to_string(), ParameterExpansionType::Length)
+        );
+        // Arithmetic offsets are left for the interpreter
+        assert_eq!(
+            expansion("${set:0-1}"),
+            ("set:0-1".to_string(), ParameterExpansionType::Simple)
+        );
+    }
+
     #[test]
     fn test_glob_pattern() {
         let input = "ls *.rs";
@@ -4606,6 +6211,58 @@ esac
         }
     }
 
+    #[test]
+    fn test_prefix_assignment_on_later_pipeline_stage() {
+        let result = parse_test("printf x | LANG=C sort | wc -l");
+
+        let Node::List { statements, .. } = result else {
+            panic!("Expected List node, got: {result:?}");
+        };
+        let Node::Pipeline { commands } = &statements[0] else {
+            panic!("Expected Pipeline node, got: {:?}", statements[0]);
+        };
+        assert_eq!(commands.len(), 3);
+        match &commands[1] {
+            Node::PrefixedCommand {
+                assignments,
+                command,
+            } => {
+                assert!(
+                    matches!(&assignments[..], [Node::Assignment { name, .. }] if name == "LANG")
+                );
+                assert!(matches!(command.as_ref(), Node::Command { name, .. } if name == "sort"));
+            }
+            other => panic!("Expected PrefixedCommand node, got: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_adjacent_quoted_parts_form_one_argument() {
+        let result = parse_test("echo a'b'c\"d\" 42'was not'here \"x\" y");
+
+        let Node::List { statements, .. } = result else {
+            panic!("Expected List node, got: {result:?}");
+        };
+        let Node::Command { name, args, .. } = &statements[0] else {
+            panic!("Expected Command node, got: {:?}", statements[0]);
+        };
+        assert_eq!(name, "echo");
+        assert_eq!(args, &["abcd", "42was nothere", "x", "y"]);
+    }
+
+    #[test]
+    fn test_blank_separated_parameters_stay_separate_arguments() {
+        let result = parse_test("echo $x $y $? $i$j");
+
+        let Node::List { statements, .. } = result else {
+            panic!("Expected List node, got: {result:?}");
+        };
+        let Node::Command { args, .. } = &statements[0] else {
+            panic!("Expected Command node, got: {:?}", statements[0]);
+        };
+        assert_eq!(args, &["$x", "$y", "$?", "$i$j"]);
+    }
+
     #[test]
     fn test_multiple_variable_assignments() {
         let input = "VAR1=value1 VAR2=value2 command arg1 arg2";
@@ -4613,7 +6270,17 @@ esac
 
         match result {
             Node::List { statements, .. } => {
-                assert_eq!(statements.len(), 3);
+                assert_eq!(statements.len(), 1);
+
+                // Prefix assignments are attached to the command they precede
+                let (statements, command) = match &statements[0] {
+                    Node::PrefixedCommand {
+                        assignments,
+                        command,
+                    } => (assignments, command.as_ref()),
+                    _ => panic!("Expected PrefixedCommand node"),
+                };
+                assert_eq!(statements.len(), 2);
 
                 // Check first assignment
                 match &statements[0] {
@@ -4644,7 +6311,7 @@ esac
                 }
 
                 // Check command
-                match &statements[2] {
+                match command {
                     Node::Command { name, args, .. } => {
                         assert_eq!(name, "command");
                         assert_eq!(args, &["arg1", "arg2"]);
@@ -4724,7 +6391,8 @@ fi
                 if let Node::Command { name, args, .. } = &**condition {
                     assert_eq!(name, "[");
                     assert_eq!(args.len(), 4);
-                    assert_eq!(args[0], "$1");
+                    // Quoted expansions keep their quotes for the interpreter
+                    assert_eq!(args[0], "\"$1\"");
                     assert_eq!(args[1], "=");
                     assert_eq!(args[2], "test");
                     assert_eq!(args[3], "]");
@@ -4807,7 +6475,8 @@ fi
                 if let Node::Command { name, args, .. } = &**condition {
                     assert_eq!(name, "[");
                     assert_eq!(args.len(), 4);
-                    assert_eq!(args[0], "$1");
+                    // Quoted expansions keep their quotes for the interpreter
+                    assert_eq!(args[0], "\"$1\"");
                     assert_eq!(args[1], "=");
                     assert_eq!(args[2], "test1");
                     assert_eq!(args[3], "]");
@@ -4841,7 +6510,7 @@ fi
                             if let Node::Command { name, args, .. } = &**elif_condition {
                                 assert_eq!(name, "[");
                                 assert_eq!(args.len(), 4);
-                                assert_eq!(args[0], "$1");
+                                assert_eq!(args[0], "\"$1\"");
                                 assert_eq!(args[1], "=");
                                 assert_eq!(args[2], "test2");
                                 assert_eq!(args[3], "]");
@@ -4873,7 +6542,7 @@ fi
                             if let Node::Command { name, args, .. } = &**elif_condition {
                                 assert_eq!(name, "[");
                                 assert_eq!(args.len(), 4);
-                                assert_eq!(args[0], "$1");
+                                assert_eq!(args[0], "\"$1\"");
                                 assert_eq!(args[1], "=");
                                 assert_eq!(args[2], "test2");
                                 assert_eq!(args[3], "]");
@@ -5369,6 +7038,22 @@ fi
         }
     }
 
+    #[test]
+    fn test_array_elements_join_touching_pieces() {
+        let input = r#"map=([a]=1 [$k]="$v w" x'y'z)"#;
+        let mut parser = create_parser(input);
+
+        match parser.parse_statement().unwrap() {
+            Node::Assignment { value, .. } => match *value {
+                Node::Array { elements } => {
+                    assert_eq!(elements, vec!["[a]=1", "[$k]=\"$v w\"", "xyz"]);
+                }
+                _ => panic!("Expected Node::Array, got something else"),
+            },
+            _ => panic!("Expected Node::Assignment, got something else"),
+        }
+    }
+
     #[test]
     fn test_multiple_array_assignments() {
         let input = "fruits=('apple' 'banana')\ncolors=('red' 'blue')";
@@ -6167,7 +7852,7 @@ fi
                         assert_eq!(name, "[");
                         assert_eq!(args.len(), 3); // "-n", "$HOME", "]"
                         assert_eq!(args[0], "-n");
-                        assert_eq!(args[1], "$HOME");
+                        assert_eq!(args[1], "\"$HOME\"");
                         assert_eq!(args[2], "]");
                     }
                     _ => panic!("Expected Command node for variable test"),
@@ -6651,4 +8336,33 @@ fi
             _ => panic!("Expected List node, got: {result:?}"),
         }
     }
+
+    #[test]
+    fn test_time_attaches_to_pipeline() {
+        let result = parse_test("time sleep 0 | cat");
+
+        match result {
+            Node::List { statements, .. } => {
+                assert_eq!(statements.len(), 1);
+                match &statements[0] {
+                    Node::Time { pipeline, posix } => {
+                        assert!(!posix);
+                        match pipeline.as_ref() {
+                            Node::Pipeline { commands } => assert_eq!(commands.len(), 2),
+                            _ => panic!("Expected Pipeline inside time, got: {pipeline:?}"),
+                        }
+                    }
+                    _ => panic!("Expected Time node, got: {:?}", &statements[0]),
+                }
+            }
+            _ => panic!("Expected List node, got: {result:?}"),
+        }
+
+        match parse_test("time -p true") {
+            Node::List { statements, .. } => {
+                assert!(matches!(&statements[0], Node::Time { posix: true, .. }));
+            }
+            result => panic!("Expected List node, got: {result:?}"),
+        }
+    }
 }
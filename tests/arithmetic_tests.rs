@@ -5,7 +5,7 @@
  * under GNU General Public License v3.0.
  */
 
-use flash::interpreter::Interpreter;
+use flash::interpreter::{DefaultEvaluator, Interpreter};
 use flash::lexer::Lexer;
 use flash::parser::Parser;
 
@@ -275,9 +275,10 @@ fn test_arithmetic_lexer_tokens() {
     })
     .collect();
 
-    // Should start with ArithCommand token
+    // The whole command is one ArithCommand token carrying its source text
+    assert_eq!(tokens.len(), 1);
     assert_eq!(tokens[0].kind, flash::lexer::TokenKind::ArithCommand);
-    assert_eq!(tokens[0].value, "((");
+    assert_eq!(tokens[0].value, "(( 5 + 3 ))");
 }
 
 #[test]
@@ -323,10 +324,12 @@ fn test_arithmetic_expansion_parser() {
 #[test]
 fn test_arithmetic_error_handling() {
     let mut interpreter = Interpreter::new();
+    // A failed expansion would end a non-interactive shell
+    interpreter.interactive = true;
 
     // Test division by zero
     let result = interpreter.execute("echo $((5 / 0))");
-    assert!(result.is_ok()); // Should not crash, but may print error
+    assert_eq!(result.unwrap(), 1); // The command fails and reports the error
 
     // Test invalid expression
     let result = interpreter.execute("(( invalid_expr ))");
@@ -617,3 +620,144 @@ fn debug_deeply_nested() {
     let result = interpreter.execute("(( $((1 + $((2 * 3)))) == 7 ))");
     println!("Result: {result:?}");
 }
+
+#[test]
+fn test_arithmetic_precedence_and_associativity() {
+    let cases = [
+        ("2-3-4", -5),
+        ("2+3*4", 14),
+        ("(2+3)*4", 20),
+        ("10/3/2", 1),
+        ("2 * 3 - 4 % 3", 5),
+        ("2**3**2", 512),
+        ("-2**2", 4),
+        ("1 + 2 == 3 && 4 > 3", 1),
+        ("1 | 2 ^ 3 & 6 << 1", 3),
+        ("!0 + ~0", 0),
+        ("1, 2, 3", 3),
+    ];
+
+    for (expr, expected) in cases {
+        assert_eq!(
+            DefaultEvaluator::evaluate_arithmetic_expression(expr),
+            Ok(expected),
+            "{expr}"
+        );
+    }
+
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute("x=$((2 - 3 - 4)); (( y = (2 + 3) * 4 ))")
+        .unwrap();
+    assert_eq!(interpreter.variables.get("x"), Some(&"-5".to_string()));
+    assert_eq!(interpreter.variables.get("y"), Some(&"20".to_string()));
+}
+
+#[test]
+fn test_arithmetic_variable_holding_expression() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute(r#"a="1+1"; x=$(( a * 2 )); y=$(( $a * 2 )); (( z = a * 5 ))"#)
+        .unwrap();
+
+    // A bare name is evaluated as a subexpression, while `$a` is substituted as text
+    assert_eq!(interpreter.variables.get("x"), Some(&"4".to_string()));
+    assert_eq!(interpreter.variables.get("y"), Some(&"3".to_string()));
+    assert_eq!(interpreter.variables.get("z"), Some(&"10".to_string()));
+}
+
+#[test]
+fn test_arithmetic_increment_and_compound_assignment() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute("n=5; a=$(( n++ )); b=$n; c=$(( n += 10 )); (( n -= 6, ++n ))")
+        .unwrap();
+
+    assert_eq!(interpreter.variables.get("a"), Some(&"5".to_string()));
+    assert_eq!(interpreter.variables.get("b"), Some(&"6".to_string()));
+    assert_eq!(interpreter.variables.get("c"), Some(&"16".to_string()));
+    assert_eq!(interpreter.variables.get("n"), Some(&"11".to_string()));
+
+    // Expansions in command arguments update variables before the words are expanded
+    interpreter
+        .execute("i=0; [ $(( i++ )) -eq 0 ] && x=yes; y=$i")
+        .unwrap();
+    assert_eq!(interpreter.variables.get("x"), Some(&"yes".to_string()));
+    assert_eq!(interpreter.variables.get("y"), Some(&"1".to_string()));
+}
+
+#[test]
+fn test_arithmetic_conditional_operator() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute("x=-7; abs=$(( x > 0 ? x : -x )); a=1; b=0; n=$(( a ? b ? 1 : 2 : 3 ))")
+        .unwrap();
+    interpreter.execute("(( m = b ? 1 : a ? 2 : 3 ))").unwrap();
+
+    assert_eq!(interpreter.variables.get("abs"), Some(&"7".to_string()));
+    assert_eq!(interpreter.variables.get("n"), Some(&"2".to_string()));
+    assert_eq!(interpreter.variables.get("m"), Some(&"2".to_string()));
+}
+
+#[test]
+fn test_arithmetic_integer_constants() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute(
+            "mask=0x0f; a=$(( 0xFF & mask )); b=$(( 2#1010 + 16#ff )); c=$(( 010 )); d=$(( mask ))",
+        )
+        .unwrap();
+
+    assert_eq!(interpreter.variables.get("a"), Some(&"15".to_string()));
+    assert_eq!(interpreter.variables.get("b"), Some(&"265".to_string()));
+    assert_eq!(interpreter.variables.get("c"), Some(&"8".to_string()));
+    assert_eq!(interpreter.variables.get("d"), Some(&"15".to_string()));
+
+    // An invalid octal digit is an error rather than a truncated value
+    assert!(DefaultEvaluator::evaluate_arithmetic_expression("09").is_err());
+}
+
+#[test]
+fn test_arithmetic_overflow_wraps_around() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute(
+            "a=$(( 9223372036854775807 + 1 )); b=$(( -9223372036854775807 - 3 )); c=$(( 4611686018427387904 * 2 ))",
+        )
+        .unwrap();
+
+    assert_eq!(
+        interpreter.variables.get("a"),
+        Some(&"-9223372036854775808".to_string())
+    );
+    assert_eq!(
+        interpreter.variables.get("b"),
+        Some(&"9223372036854775806".to_string())
+    );
+    assert_eq!(
+        interpreter.variables.get("c"),
+        Some(&"-9223372036854775808".to_string())
+    );
+}
+
+#[test]
+fn test_arithmetic_expansions_update_variables_in_word_order() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute("j=1; s=\"$j $((j=7)) $j\"; i=3; printf -v t '%s' \"$i $((i--)) $i\"")
+        .unwrap();
+
+    assert_eq!(interpreter.variables.get("s"), Some(&"1 7 7".to_string()));
+    assert_eq!(interpreter.variables.get("t"), Some(&"3 3 2".to_string()));
+}
+
+#[test]
+fn test_arithmetic_command_compound_assignment_without_spaces() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .execute("x=1; ((x+=5)); a=$x; ((x-=1)); ((x*=2)); ((x<<=1)); ((x>>=2))")
+        .unwrap();
+
+    assert_eq!(interpreter.variables.get("a"), Some(&"6".to_string()));
+    assert_eq!(interpreter.variables.get("x"), Some(&"5".to_string()));
+}
@@ -355,3 +355,46 @@ fn test_completion_sorting_and_deduplication() {
         "Completions should be deduplicated"
     );
 }
+
+/// Run `script` to register completions, then complete `line` as if the cursor were at its end
+fn complete_after(interpreter: &mut Interpreter, script: &str, line: &str) -> Vec<String> {
+    interpreter.execute(script).unwrap();
+    let context = CompletionSystem::parse_context(line, line.len());
+    interpreter.completion_system.complete(&context)
+}
+
+#[test]
+fn test_complete_wordlist_falls_back_to_files_with_default_option() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("zeta.txt"), "").unwrap();
+    let prefix = format!("{}/ze", temp_dir.path().display());
+    let expected = format!("{}/zeta.txt", temp_dir.path().display());
+    let mut interpreter = Interpreter::new();
+
+    // Without -o default, a prefix that matches no word completes to nothing
+    let completions = complete_after(
+        &mut interpreter,
+        "complete -W 'alpha beta' plain",
+        &format!("plain {prefix}"),
+    );
+    assert!(completions.is_empty());
+
+    // With it, words still win when they match, and file names are used otherwise
+    let script = "complete -o default -W 'alpha beta' withdefault";
+    let completions = complete_after(&mut interpreter, script, "withdefault al");
+    assert_eq!(completions, vec!["alpha"]);
+    let completions = complete_after(&mut interpreter, script, &format!("withdefault {prefix}"));
+    assert_eq!(completions, vec![expected.clone()]);
+
+    // -o bashdefault also falls back to file names for arguments
+    let completions = complete_after(
+        &mut interpreter,
+        "complete -o bashdefault -W 'alpha' bashdefault",
+        &format!("bashdefault {prefix}"),
+    );
+    assert_eq!(completions, vec![expected]);
+
+    // complete -r removes the registration
+    let completions = complete_after(&mut interpreter, "complete -r plain", "plain al");
+    assert!(!completions.contains(&"alpha".to_string()));
+}
@@ -0,0 +1,324 @@
+/*
+ * Copyright (c) 2025 Raphael Amorim
+ *
+ * This file is part of flash, which is licensed
+ * under GNU General Public License v3.0.
+ */
+
+use flash::interpreter::{DefaultEvaluator, Interpreter};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::tempdir;
+
+/// A writer whose contents stay readable after the interpreter takes ownership of it
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run the test `name` again in a child process leading its own process group, with
+/// `FLASH_TEST_CHILD` set so the test takes its child branch. Signal dispositions the
+/// prompt changes stay in the child, and signals sent to the group reach only it.
+fn spawn_child_test(name: &str) -> Child {
+    Command::new(env::current_exe().unwrap())
+        .args(["--exact", name, "--nocapture", "--test-threads=1"])
+        .env("FLASH_TEST_CHILD", "1")
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+#[test]
+fn test_execute_capture_returns_output_and_status() {
+    let mut interpreter = Interpreter::new();
+
+    let (code, stdout, stderr) = interpreter
+        .execute_capture(
+            "echo hello; printf '%s\\n' world; sh -c 'echo external'; echo oops >&2; false",
+        )
+        .unwrap();
+    assert_eq!(code, 1);
+    assert_eq!(stdout, "hello\nworld\nexternal\n");
+    assert_eq!(stderr, "oops\n");
+
+    // The interpreter keeps its state between captures
+    let (code, stdout, stderr) = interpreter
+        .execute_capture("GREETING=hi; echo $GREETING")
+        .unwrap();
+    assert_eq!((code, stdout.as_str(), stderr.as_str()), (0, "hi\n", ""));
+}
+
+#[test]
+fn test_with_io_reads_input_and_collects_output() {
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_io(
+        Box::new(Cursor::new("first line\nsecond\n")),
+        Box::new(stdout.clone()),
+        Box::new(stderr.clone()),
+    );
+
+    let code = interpreter
+        .execute(
+            "read a b; echo \"$b $a\"; read line; echo $line | tr a-z A-Z; read x || echo eof >&2",
+        )
+        .unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(stdout.contents(), "line first\nSECOND\n");
+    assert_eq!(stderr.contents(), "eof\n");
+}
+
+#[test]
+fn test_host_writers_keep_builtin_and_external_output_in_order() {
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_io(
+        Box::new(io::empty()),
+        Box::new(stdout.clone()),
+        Box::new(stderr.clone()),
+    );
+
+    let code = interpreter
+        .execute("echo a; sh -c 'echo b; echo x >&2'; echo c; echo y >&2; sh -c 'echo d' 2>&1")
+        .unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(stdout.contents(), "a\nb\nc\nd\n");
+    assert_eq!(stderr.contents(), "x\ny\n");
+}
+
+#[test]
+fn test_host_writers_follow_redirections_and_pipelines() {
+    let dir = tempdir().unwrap();
+    let file = dir.path().join("out");
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_io(
+        Box::new(io::empty()),
+        Box::new(stdout.clone()),
+        Box::new(stderr.clone()),
+    );
+    interpreter
+        .variables
+        .insert("FILE".to_string(), file.to_string_lossy().into_owned());
+
+    let code = interpreter
+        .execute(
+            "{ echo one; sh -c 'echo two'; } > \"$FILE\"; echo kept 2>&1 >> \"$FILE\"
+             shout() { echo \"$1\"; }; shout three | tr a-z A-Z
+             x=$(echo four | tr a-z A-Z); echo \"$x\"; echo five 3>&1 1>&2 2>&3",
+        )
+        .unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "one\ntwo\nkept\n");
+    assert_eq!(stdout.contents(), "THREE\nFOUR\n");
+    assert_eq!(stderr.contents(), "five\n");
+}
+
+// Embedders hand interpreters to worker threads, host streams and builtins included
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Interpreter>();
+};
+
+#[test]
+fn test_interpreter_built_with_host_streams_moves_to_another_thread() {
+    let stdout = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_io(
+        Box::new(Cursor::new("typed\n")),
+        Box::new(stdout.clone()),
+        Box::new(io::sink()),
+    );
+    interpreter.register_builtin("greet", |interpreter, args| {
+        writeln!(&interpreter.stdout, "hello {}", args[0])?;
+        Ok(0)
+    });
+
+    let code = thread::spawn(move || {
+        interpreter
+            .execute("read line; greet \"$line\"; echo done")
+            .unwrap()
+    })
+    .join()
+    .unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(stdout.contents(), "hello typed\ndone\n");
+}
+
+#[test]
+fn test_interpreters_on_separate_threads_capture_their_own_output() {
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            thread::spawn(move || {
+                let mut interpreter = Interpreter::new();
+                for round in 0..50 {
+                    let (code, stdout, stderr) = interpreter
+                        .execute_capture(&format!("echo {worker}-{round}; echo e{worker} >&2"))
+                        .unwrap();
+                    assert_eq!(code, 0);
+                    assert_eq!(stdout, format!("{worker}-{round}\n"));
+                    assert_eq!(stderr, format!("e{worker}\n"));
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+#[test]
+fn test_host_input_reaches_redirections_pipes_and_external_commands() {
+    let dir = tempdir().unwrap();
+    let file = dir.path().join("in");
+    fs::write(&file, "from file\nsecond line\n").unwrap();
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_io(
+        Box::new(Cursor::new("one\ntwo\nthree\nfour\n")),
+        Box::new(stdout.clone()),
+        Box::new(stderr.clone()),
+    );
+    interpreter
+        .variables
+        .insert("FILE".to_string(), file.to_string_lossy().into_owned());
+
+    let code = interpreter
+        .execute(
+            "read a < \"$FILE\"; echo \"$a\"; echo piped | read b; echo \"$b\"
+             while read line; do echo \"<$line>\"; done < \"$FILE\"
+             read first; echo \"$first\"; sh -c 'read x; echo \"sh:$x\"'
+             read -p 'next? ' third; echo \"$third\"; cat | tr a-z A-Z",
+        )
+        .unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(
+        stdout.contents(),
+        "from file\npiped\n<from file>\n<second line>\none\nsh:two\nthree\nFOUR\n"
+    );
+    assert_eq!(stderr.contents(), "next? ");
+}
+
+#[test]
+fn test_run_ast_writes_to_host_streams() {
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_io(
+        Box::new(io::empty()),
+        Box::new(stdout.clone()),
+        Box::new(stderr.clone()),
+    );
+    let ast = flash::parser::Parser::new(flash::lexer::Lexer::new(
+        "echo \"hello $1\"; sh -c 'echo ext'; echo bad >&2",
+    ))
+    .parse_script();
+
+    assert_eq!(interpreter.run_ast(&ast, vec!["a".to_string()]).unwrap(), 0);
+    assert_eq!(interpreter.run_ast(&ast, vec!["b".to_string()]).unwrap(), 0);
+    assert_eq!(stdout.contents(), "hello a\next\nhello b\next\n");
+    assert_eq!(stderr.contents(), "bad\nbad\n");
+}
+
+#[test]
+fn test_interactive_prompt_reads_and_writes_host_streams() {
+    if env::var_os("FLASH_TEST_CHILD").is_none() {
+        let status = spawn_child_test("test_interactive_prompt_reads_and_writes_host_streams")
+            .wait()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    let stdout = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_io(
+        Box::new(Cursor::new("echo typed\n")),
+        Box::new(stdout.clone()),
+        Box::new(io::sink()),
+    );
+    interpreter.history_file = None;
+
+    interpreter.run_interactive().unwrap();
+
+    // The line is drawn as it is typed, then the command's output follows
+    let output = stdout.contents();
+    assert!(output.contains("echo typed\ntyped\n"), "{output:?}");
+}
+
+#[test]
+fn test_ctrl_c_abandons_foreground_loops_but_not_the_shell() {
+    if env::var_os("FLASH_TEST_CHILD").is_some() {
+        // Like the prompt, which ignores Ctrl-C between commands
+        unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+        let mut interpreter = Interpreter::new();
+        let mut evaluator = DefaultEvaluator;
+        for command in [
+            "echo ready; while [ 1 ]; do x=1; done; echo unreachable",
+            "echo ready; while [ 1 ]; do sleep 1; done; echo unreachable",
+            "echo alive",
+        ] {
+            let status = interpreter
+                .execute_foreground_with_evaluator(command, &mut evaluator)
+                .unwrap();
+            println!("status {status}");
+        }
+        return;
+    }
+
+    let mut child = spawn_child_test("test_ctrl_c_abandons_foreground_loops_but_not_the_shell");
+    let group = child.id() as libc::pid_t;
+    // Kill the child if it never gets that far, rather than hang the test run
+    let (done, finished) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(Duration::from_secs(30)) {
+            unsafe { libc::kill(-group, libc::SIGKILL) };
+        }
+    });
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    // The harness prints the test's name without a newline before the first line
+    let mut expect_line = |expected: &str| {
+        let line = lines.by_ref().map(Result::unwrap).find(|line| {
+            ["ready", "unreachable", "alive"]
+                .iter()
+                .any(|marker| line.ends_with(marker))
+                || line.starts_with("status")
+        });
+        assert!(line.is_some_and(|line| line.ends_with(expected)));
+    };
+
+    // Ctrl-C reaches every process in the foreground group, the shell included:
+    // first a loop of builtins only, then one whose command dies of SIGINT
+    for _ in 0..2 {
+        expect_line("ready");
+        thread::sleep(Duration::from_millis(300));
+        unsafe { libc::kill(-group, libc::SIGINT) };
+        expect_line("status 130");
+    }
+    expect_line("alive");
+    expect_line("status 0");
+    assert!(child.wait().unwrap().success());
+    drop(done);
+    watchdog.join().unwrap();
+}
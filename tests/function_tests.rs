@@ -511,3 +511,28 @@ fn test_select_variable_assignment() {
         _ => panic!("Expected List node"),
     }
 }
+
+#[test]
+fn test_local_restores_shadowed_and_removes_new_variables() {
+    let mut interpreter = Interpreter::new();
+    let mut evaluator = DefaultEvaluator;
+    let script = r#"
+        f() {
+            local y=inner
+            local fresh=1
+            seen=$y
+            return 3
+        }
+        y=outer
+        f
+    "#;
+
+    let result = interpreter.execute_with_evaluator(script, &mut evaluator);
+    assert_eq!(result.unwrap(), 3);
+    assert_eq!(
+        interpreter.variables.get("seen"),
+        Some(&"inner".to_string())
+    );
+    assert_eq!(interpreter.variables.get("y"), Some(&"outer".to_string()));
+    assert!(!interpreter.variables.contains_key("fresh"));
+}
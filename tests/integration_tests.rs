@@ -6,7 +6,9 @@
  */
 
 use std::fs;
-use std::process::Command;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
 use tempfile::tempdir;
 
 /// Get the path to the flash binary, handling cross-compilation targets
@@ -508,6 +510,43 @@ echo "Args provided: $@""#,
     assert!(output.status.success());
 }
 
+#[test]
+fn test_arithmetic_expansion_errors_fail_with_the_error_token() {
+    let binary_path = get_flash_binary_path();
+
+    for (script, stderr) in [
+        (
+            "echo $((09)); echo after",
+            "flash: 09: value too great for base (error token is \"09\")\n",
+        ),
+        (
+            "x=$((1/0)); echo after",
+            "flash: 1/0: division by 0 (error token is \"0\")\n",
+        ),
+    ] {
+        let output = Command::new(&binary_path)
+            .args(["-c", script])
+            .output()
+            .expect("Failed to execute flash");
+
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "", "{script}");
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), stderr);
+        assert_eq!(output.status.code(), Some(1), "{script}");
+    }
+
+    // An arithmetic command only fails, and the shell goes on
+    let output = Command::new(&binary_path)
+        .args(["-c", "((2 * 09)); echo \"after $?\""])
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "after 1\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "flash: ((: 2 * 09: value too great for base (error token is \"09\")\n"
+    );
+}
+
 #[test]
 fn test_arithmetic_expansion_with_argument_count() {
     let temp_dir = tempdir().unwrap();
@@ -806,6 +845,27 @@ echo test*.dat"#,
     assert!(output.status.success());
 }
 
+#[test]
+fn test_extended_glob_patterns() {
+    let temp_dir = tempdir().unwrap();
+    for name in ["a.txt", "b.txt", "c.txt", "notes.bak", "ab", "abab", "abc"] {
+        fs::write(temp_dir.path().join(name), "").unwrap();
+    }
+
+    let binary_path = get_flash_binary_path();
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("echo @(a|b).txt; shopt -s extglob; echo @(a|b).txt; echo !(*.bak); echo +(ab)")
+        .current_dir(temp_dir.path())
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "@(a|b).txt\na.txt b.txt\na.txt ab abab abc b.txt c.txt\nab abab\n"
+    );
+}
+
 #[test]
 fn test_glob_pattern_character_classes() {
     let temp_dir = tempdir().unwrap();
@@ -1814,6 +1874,32 @@ fn test_for_loop_syntax_errors() {
     assert!(!output.status.success() || output.status.code() == Some(0));
 }
 
+#[test]
+fn test_for_loop_over_positional_parameters() {
+    let binary_path = get_flash_binary_path();
+
+    // Without `in`, the loop iterates over "$@" and keeps each argument whole
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(r#"f() { for x; do echo "[$x]"; done; }; f a "b c" d"#)
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.trim(), "[a]\n[b c]\n[d]");
+    assert!(output.status.success());
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(r#"f() { for x in "$@"; do echo "[$x]"; done; for x in $*; do echo "<$x>"; done; }; f a "b c" d"#)
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.trim(), "[a]\n[b c]\n[d]\n<a>\n<b>\n<c>\n<d>");
+    assert!(output.status.success());
+}
+
 #[test]
 fn test_brace_expansion_edge_cases() {
     let binary_path = get_flash_binary_path();
@@ -2205,6 +2291,121 @@ fn test_case_statement_pattern_matching() {
     assert!(output.status.success());
 }
 
+#[test]
+fn test_case_statement_alternation_and_quoted_patterns() {
+    let binary_path = get_flash_binary_path();
+
+    // `|` separates alternatives, and a quoted `*` only matches a literal star
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"case b in a|b) echo hit ;; esac
+        for word in '*' star; do
+            case "$word" in
+            '*') echo "literal $word" ;;
+            *) echo "glob $word" ;;
+            esac
+        done"#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "hit\nliteral *\nglob star\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_case_statement_mixed_quoting_patterns() {
+    let binary_path = get_flash_binary_path();
+
+    // Only the quoted parts of a pattern are literal; the rest still globs
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"prefix=f
+        for word in foo 'f*x' bar; do
+            case $word in
+            "f*"?) echo "literal $word" ;;
+            "$prefix"*) echo "prefix $word" ;;
+            b"a"*) echo "mixed $word" ;;
+            esac
+        done"#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "prefix foo\nliteral f*x\nmixed bar\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_case_statement_with_command_substitution() {
+    let binary_path = get_flash_binary_path();
+
+    // The subject is the command's output, without its trailing newlines
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"case $(echo hello) in hello) echo yes ;; *) echo no ;; esac
+        case $(printf 'x\n\n') in x) echo trimmed ;; esac"#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "yes\ntrimmed\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_case_statement_terminators() {
+    let binary_path = get_flash_binary_path();
+
+    // ;& runs the next body unconditionally, ;;& keeps testing the later patterns
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"for w in a b c; do
+            case $w in
+            a) echo "A" ;&
+            b) echo "B" ;;&
+            c|b) echo "C" ;;
+            *) echo "other" ;;
+            esac
+        done"#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "A\nB\nother\nB\nC\nC\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_case_statement_character_classes() {
+    let binary_path = get_flash_binary_path();
+
+    // Bracket expressions support ranges, negation and named classes
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"case a5 in a[0-9]) echo "digit" ;; esac
+        case ab in a[!x]) echo "ok" ;; esac
+        case ab in a[!b]) echo "wrong" ;; *) echo "negated" ;; esac
+        case x in [[:alpha:]]) echo "alpha" ;; esac
+        case 7 in [![:alpha:]_]) echo "not alpha" ;; esac"#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "digit\nok\nnegated\nalpha\nnot alpha\n");
+    assert!(output.status.success());
+}
+
 #[test]
 fn test_nested_case_statements() {
     let binary_path = get_flash_binary_path();
@@ -2292,3 +2493,1353 @@ fn test_select_variable_scoping() {
     assert!(stdout.contains("Select function completed"));
     assert!(output.status.success());
 }
+
+#[test]
+fn test_xtrace_prints_expanded_command_with_ps4() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("set -x; echo hi")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stdout, "hi\n");
+    assert!(stderr.contains("+ echo hi\n"));
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_set_o_posix_toggles_echo_escapes() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("echo 'a\\tb'; set -o posix; echo 'a\\tb'; set +o posix; echo 'a\\tb'")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "a\\tb\na\tb\na\\tb\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_echo_e_interprets_escapes() {
+    let binary_path = get_flash_binary_path();
+
+    // \\c ends the output, including the trailing newline
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("echo -e 'a\\cb'; echo -e '\\x41 \\0102'; echo -e '\\u00e9 \\U0001F600'")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "aA B\n\u{e9} \u{1F600}\n"
+    );
+
+    // -n drops the newline, -E turns escapes back off, and other words are printed
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("echo -ne 'x\\ty'; echo -e -E '|\\t'; echo -nx -")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "x\ty|\\t\n-nx -\n"
+    );
+}
+
+#[test]
+fn test_xtrace_repeats_ps4_per_nesting_level() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("greet() { echo inner; }; set -x; greet")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("+ greet\n"));
+    assert!(stderr.contains("++ echo inner\n"));
+
+    // A custom PS4 has its first character repeated instead
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("greet() { echo inner; }; PS4='>> '; set -x; greet")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(">> greet\n"));
+    assert!(stderr.contains(">>> echo inner\n"));
+}
+
+#[test]
+fn test_prefix_assignment_scoped_to_function_call() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"
+            show() { echo "inner:[$FOO]"; }
+            outer() {
+                FOO=bar show
+                echo "outer:[$FOO]"
+            }
+            outer
+            echo "global:[$FOO]"
+        "#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "inner:[bar]\nouter:[]\nglobal:[]\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_prefix_assignment_scoped_to_builtin() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"
+            wrapper() {
+                FOO=bar true
+                echo "function:[$FOO]"
+            }
+            wrapper
+            FOO=baz echo done
+            echo "global:[$FOO]"
+        "#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "function:[]\ndone\nglobal:[]\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_prefix_assignment_scoped_to_external_command() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"
+            FOO=original
+            FOO=bar printenv FOO
+            echo "restored:[$FOO]"
+            BAR=qux printenv BAR
+            echo "absent:[$BAR]"
+            echo x | BAZ=piped printenv BAZ
+            echo "piped:[$BAZ]"
+        "#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "bar\nrestored:[original]\nqux\nabsent:[]\npiped\npiped:[]\n"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_time_pipeline_reports_timing() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("time sleep 0 | cat")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success());
+    assert!(stderr.contains("real\t"));
+    assert!(stderr.contains("user\t"));
+    assert!(stderr.contains("sys\t"));
+}
+
+#[test]
+fn test_wait_blocks_for_background_jobs() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("sleep 0.2 & echo started; wait; echo finished")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "started\nfinished\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_wait_for_last_background_pid() {
+    let binary_path = get_flash_binary_path();
+
+    // $! names the last background job, and wait returns its exit status
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("(exit 3) & pid=$!; wait $!; echo \"status $?\"; [ \"$pid\" = \"$!\" ] && echo same")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "status 3\nsame\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_process_substitution() {
+    let binary_path = get_flash_binary_path();
+
+    // <(...) reads a command's output through a path, >(...) feeds its input
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("cat <(echo hi); diff <(printf 'a\\nb\\n') <(printf 'a\\nc\\n') >/dev/null; echo \"diff $?\"; echo up | tee >(tr a-z A-Z) >/dev/null")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "hi\ndiff 1\nUP\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_pipeline_connects_stages() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("echo hello | cat; printf 'b\\na\\nc\\n' | sort | head -n 2; x=$(echo up | tr a-z A-Z); echo $x")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "hello\na\nb\nUP\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_pipestatus_records_each_stage() {
+    let binary_path = get_flash_binary_path();
+
+    // $? is the last stage's status, PIPESTATUS holds every stage's
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("false | true; echo \"${PIPESTATUS[@]} $?\"; f() { return 4; }; true | f | cat; echo \"${PIPESTATUS[@]}\"; false; echo \"${PIPESTATUS[@]}\"")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "1 0 0\n0 4 0\n1\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_pipefail_reports_rightmost_failure() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("false | cat | true; echo $?; set -o pipefail; f() { return 3; }; f | false | true; echo $?; set +o pipefail; false | true; echo $?")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "0\n1\n0\n");
+    assert!(output.status.success());
+
+    // Together with set -e a failing stage ends the script
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("set -eo pipefail; true | true; echo ok; false | true; echo unreachable")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "ok\n");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_pipeline_feeds_functions_and_uses_last_status() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("only_x() { grep x; }; printf 'x1\\ny\\nx2\\n' | only_x; false | true && echo last-ok; true | false || echo last-failed")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "x1\nx2\nlast-ok\nlast-failed\n");
+}
+
+#[test]
+fn test_pipeline_early_exit_does_not_hang() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("yes | head -n 2")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "y\ny\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_pipeline_streams_functions_and_compound_stages() {
+    let binary_path = get_flash_binary_path();
+
+    // Stages that never end on their own stop once the reader is gone
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            "f() { while true; do echo y; done; }; f | head -n 1
+            while true; do echo w; done | head -n 2
+            { echo b; echo a; } | sort | { read first; echo \"first $first\"; }",
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "y\nw\nw\nfirst a\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_break_and_continue_in_loops() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("for i in 1 2 3; do if [ $i = 2 ]; then break; fi; echo $i; done")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+
+    // continue 2 resumes the outer loop, break 2 leaves both
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("for a in a b c; do for n in 1 2; do if [ $n = 2 ]; then continue 2; fi; echo $a$n; done; done; for a in x y; do for n in 1 2; do break 2; done; echo never; done; echo after")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "a1\nb1\nc1\nafter\n"
+    );
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("while true; do echo once; break; echo never; done; break; echo done")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "once\ndone\n");
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("only meaningful in a `for', `while', or `until' loop")
+    );
+}
+
+#[test]
+fn test_cd_double_dash_and_physical_option() {
+    let binary_path = get_flash_binary_path();
+    let temp_dir = tempdir().unwrap();
+    let real = temp_dir.path().join("real");
+    fs::create_dir_all(real.join("-x")).unwrap();
+    let link = temp_dir.path().join("link");
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+
+    // -- ends option parsing so a dash-prefixed name is a directory
+    let output = Command::new(&binary_path)
+        .current_dir(&real)
+        .arg("-c")
+        .arg("cd -- -x && basename $PWD")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "-x\n");
+    assert!(output.status.success());
+
+    // -L keeps the symlink in $PWD, -P resolves it
+    let script = format!(
+        "cd {link}; echo $PWD; cd -P {link}; echo $PWD",
+        link = link.display()
+    );
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(&script)
+        .output()
+        .expect("Failed to execute flash");
+    let real = fs::canonicalize(&real).unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}\n{}\n", link.display(), real.display())
+    );
+}
+
+#[test]
+fn test_cd_dash_returns_to_oldpwd() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("cd /tmp; cd /; echo $OLDPWD; cd -; pwd; cd -")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "/tmp\n/tmp\n/tmp\n/\n");
+    assert!(output.status.success());
+
+    // Without a previous directory there is nowhere to go back to
+    let output = Command::new(&binary_path)
+        .env_remove("OLDPWD")
+        .arg("-c")
+        .arg("cd -")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "cd: OLDPWD not set\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_pushd_popd_restore_directory() {
+    let binary_path = get_flash_binary_path();
+
+    // Each push prints the stack, and popping returns to where the cycle started
+    let output = Command::new(&binary_path)
+        .current_dir("/")
+        .env("HOME", "/nonexistent")
+        .arg("-c")
+        .arg("pushd /tmp; pushd /usr; dirs; popd; popd; pwd; echo $OLDPWD; popd")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "/tmp /\n/usr /tmp /\n/usr /tmp /\n/tmp /\n/\n/\n/tmp\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "popd: directory stack empty\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_printf_writes_nul_bytes() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("printf 'a\\0b'; printf '\\377'")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(output.stdout, [b'a', 0, b'b', 0xff]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_signal_traps_run_after_the_current_command() {
+    let binary_path = get_flash_binary_path();
+
+    // A trapped signal runs its command and the script carries on
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("trap 'echo caught' INT; kill -INT $$; echo after $?; trap '' TERM; kill -TERM $$; echo ignored; trap")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "caught\nafter 0\nignored\ntrap -- 'echo caught' SIGINT\ntrap -- '' SIGTERM\n"
+    );
+    assert!(output.status.success());
+
+    // `trap -- SIG` restores the default action, which ends the shell at once
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("trap 'echo cleanup' EXIT; trap 'echo term' TERM; trap -- TERM; kill -TERM $$; echo unreachable")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(output.status.signal(), Some(15));
+}
+
+#[test]
+fn test_trap_p_lists_traps_and_other_options_are_rejected() {
+    let binary_path = get_flash_binary_path();
+
+    // -p lists the named traps, or all of them, without setting any
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("trap 'echo bye' EXIT; trap 'echo it'\\''s' INT; trap -p INT; trap -p")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "trap -- 'echo it'\\''s' SIGINT\n\
+         trap -- 'echo bye' EXIT\n\
+         trap -- 'echo it'\\''s' SIGINT\n\
+         bye\n"
+    );
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("trap -x INT; echo $?; trap -p; trap -p BOGUS; echo $?")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\n1\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "trap: -x: invalid option\n\
+         trap: usage: trap [-lp] [[arg] signal_spec ...]\n\
+         trap: BOGUS: invalid signal specification\n"
+    );
+}
+
+#[test]
+fn test_getopts_parses_bundled_flags_and_silent_errors() {
+    let binary_path = get_flash_binary_path();
+
+    // -ab value sets a, then takes the next word as the argument of b
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("while getopts 'ab:' opt -ab value rest; do echo \"$opt $OPTARG\"; done; echo $OPTIND")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "a \nb value\n3\n"
+    );
+
+    // A leading colon reports bad and missing options through the variables only
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("while getopts ':ab:' opt -x -a -b; do echo \"$opt $OPTARG\"; done")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "? x\na \n: b\n");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn test_readonly_lists_variables_quoted() {
+    let binary_path = get_flash_binary_path();
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("readonly GREETING='say \"hi\" to $USER' EMPTY=; readonly UNSET; readonly -p")
+        .output()
+        .expect("Failed to execute flash");
+
+    // Values come back double-quoted so the listing can be sourced again
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "readonly EMPTY=\"\"\nreadonly GREETING=\"say \\\"hi\\\" to \\$USER\"\nreadonly UNSET\n"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_exit_trap_runs_once() {
+    let binary_path = get_flash_binary_path();
+    let temp_dir = tempdir().unwrap();
+    let script_path = temp_dir.path().join("script.sh");
+    fs::write(&script_path, "trap 'echo cleanup' EXIT\necho working\n").unwrap();
+
+    // Normal end of a script file
+    let output = Command::new(&binary_path)
+        .arg(&script_path)
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "working\ncleanup\n"
+    );
+    assert!(output.status.success());
+
+    // Explicit exit keeps its status and stops the rest of the script
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("trap 'echo cleanup $?' EXIT; echo working; exit 3; echo unreachable")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "working\ncleanup 3\n"
+    );
+    assert_eq!(output.status.code(), Some(3));
+
+    // An exit inside the trap does not run it again
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("trap 'echo cleanup; exit 4' EXIT; true")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "cleanup\n");
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn test_command_p_uses_default_path() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("PATH=/nonexistent/garbage; command -p ls -d /")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "/\n");
+    assert!(output.status.success());
+
+    // Without -p the garbage PATH is used and the lookup fails
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("PATH=; command -v ls; command -pv ls")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with("/ls\n"));
+    assert_eq!(stdout.lines().count(), 1);
+}
+
+#[test]
+fn test_empty_ifs_disables_field_splitting() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"
+            fields() { printf '<%s>' "$@"; echo; }
+            joined() { echo joined: $*; echo "quoted: [$*]"; }
+            x="a b c"
+            fields $x
+            IFS=
+            fields $x
+            joined a b c
+        "#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "<a><b><c>\n<a b c>\njoined: a b c\nquoted: [abc]\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_stderr_redirection_and_duplication() {
+    let binary_path = get_flash_binary_path();
+    let temp_dir = tempdir().unwrap();
+
+    // 2>&1 in a pipeline sends stderr down the pipe along with stdout
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("sh -c 'echo out; echo err >&2' 2>&1 | tr a-z A-Z")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "OUT\nERR\n");
+    assert!(output.stderr.is_empty());
+
+    // 2>/dev/null silences only stderr, for external and builtin commands alike
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("sh -c 'echo out; echo err >&2' 2>/dev/null; echo builtin 2>/dev/null >&2")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "out\n");
+    assert!(output.stderr.is_empty());
+
+    // >file 2>&1 captures both streams, in order
+    let log = temp_dir.path().join("both.log");
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(format!(
+            "sh -c 'echo out; echo err >&2' >{} 2>&1; echo done >&2",
+            log.display()
+        ))
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(fs::read_to_string(&log).unwrap(), "out\nerr\n");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "done\n");
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_source_sets_bash_source_and_keeps_dollar_zero() {
+    let binary_path = get_flash_binary_path();
+    let temp_dir = tempdir().unwrap();
+    let outer = temp_dir.path().join("outer.sh");
+    let inner = temp_dir.path().join("inner.sh");
+    let nested = temp_dir.path().join("nested.sh");
+
+    fs::write(
+        &outer,
+        format!(
+            "source {}\necho \"after: [${{BASH_SOURCE[0]}}]\"\n",
+            inner.display()
+        ),
+    )
+    .unwrap();
+    fs::write(
+        &inner,
+        format!(
+            "echo \"$0 ${{BASH_SOURCE[0]}}\"\n. {}\necho \"${{BASH_SOURCE[0]}}\"\n",
+            nested.display()
+        ),
+    )
+    .unwrap();
+    fs::write(&nested, "echo \"${BASH_SOURCE[0]} ${BASH_SOURCE[1]}\"\n").unwrap();
+
+    let output = Command::new(&binary_path)
+        .arg(&outer)
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let (outer, inner, nested) = (outer.display(), inner.display(), nested.display());
+    assert_eq!(
+        stdout,
+        format!("{outer} {inner}\n{nested} {inner}\n{inner}\nafter: []\n")
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_here_document_expansion_follows_delimiter_quoting() {
+    let binary_path = get_flash_binary_path();
+    let script = "cat <<EOF\nuser: $USER\nEOF\ncat <<'EOF'\nuser: $USER\nEOF\nif true; then\n\tcat <<-END\n\t\tindented $(echo sub)\n\tEND\nfi\n";
+
+    let output = Command::new(&binary_path)
+        .args(["-c", script])
+        .env("USER", "flash-user")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "user: flash-user\nuser: $USER\nindented sub\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_here_document_body_quoting_rules() {
+    let binary_path = get_flash_binary_path();
+    // Backslash only escapes `$`, `` ` ``, `\` and newline; quotes and `~` stay literal
+    let script = "x=1\ncat <<EOF\n\\$x $x \\\\ \\n \"$x\" '$x' ~ \\`q\\`\nsplit \\\nline\nEOF\n";
+
+    let output = Command::new(&binary_path)
+        .args(["-c", script])
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "$x 1 \\ \\n \"1\" '1' ~ `q`\nsplit line\n");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_here_string_expands_its_word() {
+    let binary_path = get_flash_binary_path();
+    let script = "CONTENT=\"foo 1\nbar 2\"; grep foo <<< \"$CONTENT\"; cat <<< '$CONTENT'; x=\"multiple   words\"; cat <<< $x; read a b <<< $(echo 1 2); echo $b";
+
+    let output = Command::new(&binary_path)
+        .args(["-c", script])
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "foo 1\n$CONTENT\nmultiple   words\n2\n");
+    assert!(output.status.success());
+
+    // The word is not split or globbed, so an unquoted multi-line value keeps its lines
+    let output = Command::new(&binary_path)
+        .args(["-c", "C=$'foo line\\nbar line'; cat <<< $C; cat <<< *"])
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "foo line\nbar line\n*\n");
+}
+
+fn run_with_stdin(script: &str, input: &str) -> (String, i32) {
+    let mut child = Command::new(get_flash_binary_path())
+        .args(["-c", script])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute flash");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        output.status.code().unwrap_or(-1),
+    )
+}
+
+#[test]
+fn test_read_splits_fields_into_variables() {
+    let (stdout, _) = run_with_stdin(
+        "read first rest; echo \"[$first] [$rest]\"; read; echo \"[$REPLY]\"",
+        "  alpha beta  gamma  \n  kept as is \n",
+    );
+    assert_eq!(stdout, "[alpha] [beta  gamma]\n[  kept as is ]\n");
+
+    let (stdout, _) = run_with_stdin(
+        "read a b; echo \"[$a] [$b]\"; read -r c; echo \"[$c]\"",
+        "one\\ two three\nback\\slash\n",
+    );
+    assert_eq!(stdout, "[one two] [three]\n[back\\slash]\n");
+}
+
+#[test]
+fn test_while_read_stops_at_end_of_input() {
+    let (stdout, status) = run_with_stdin(
+        "while read line; do echo \"<$line>\"; done; read missing || echo \"eof [$missing]\"",
+        "first\nsecond\n",
+    );
+    assert_eq!(stdout, "<first>\n<second>\neof []\n");
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn test_read_loop_with_redirected_input() {
+    let dir = tempdir().unwrap();
+    let file = dir.path().join("lines.txt");
+    std::fs::write(&file, "a 1\nb 2\n").unwrap();
+
+    let script = format!(
+        "while read name n; do echo \"$n:$name\"; done < {}; s='x y'; while read p q; do echo \"$q $p\"; done <<< \"$s\"",
+        file.display()
+    );
+    let (stdout, status) = run_with_stdin(&script, "");
+    assert_eq!(stdout, "1:a\n2:b\ny x\n");
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn test_read_in_compound_pipeline_stages() {
+    let (stdout, status) = run_with_stdin(
+        "echo hi | while read l; do echo \"loop $l\"; done; echo there | { read x; echo \"group $x\"; }; printf '2\\n1\\n' | while read n; do echo $n; done | sort",
+        "",
+    );
+    assert_eq!(stdout, "loop hi\ngroup there\n1\n2\n");
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn test_set_errexit_xtrace_and_positional_parameters() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r#"set -- a b c
+echo "$# $2"
+set -e
+if false; then echo never; fi
+false || true
+false && echo skipped; echo "and-or lists continue"
+set -x
+echo traced
+set +x
+false
+echo unreachable"#,
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stdout, "3 b\nand-or lists continue\ntraced\n");
+    assert!(stderr.contains("+ echo traced\n"));
+    assert_eq!(output.status.code(), Some(1));
+
+    // `set +e` turns errexit back off
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("set -e; set +e; false; echo survived")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "survived\n");
+}
+
+#[test]
+fn test_alternate_value_expansion_field_count() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(r#"x=1; printf '[%s]' ${x:+a b}; echo; printf '[%s]' "${x:+a b}"; echo"#)
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "[a][b]\n[a b]\n");
+}
+
+#[test]
+fn test_unset_parameter_error_ends_a_non_interactive_shell() {
+    let binary_path = get_flash_binary_path();
+
+    // The EXIT trap still runs, but nothing after the failed expansion does
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(r#"trap 'echo "exit $?"' EXIT; V=; echo before; echo ${V:?is empty}; echo after"#)
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "before\nexit 1\n"
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "V: is empty\n");
+    assert_eq!(output.status.code(), Some(1));
+
+    let dir = tempdir().unwrap();
+    let script = dir.path().join("script.sh");
+    fs::write(&script, "x=${UNSET?}\necho after\n").unwrap();
+    let output = Command::new(&binary_path)
+        .arg(&script)
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_adjacent_quoted_parts_concatenate_into_one_word() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(r#"printf '[%s]' a'b'c"d" 42'was not'here"with me"; echo; x=nothing#here; echo "$x""#)
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[abcd][42was notherewith me]\nnothing#here\n"
+    );
+}
+
+#[test]
+fn test_special_parameter_assignment_fails() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(r#"?=5; echo $?; 1=x; echo "[$1]"; false; echo $?"#)
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n[]\n1\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("`?': not a valid identifier"));
+    assert!(stderr.contains("`1': not a valid identifier"));
+}
+
+#[test]
+fn test_backslash_newline_continues_the_line() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("echo one \\\n  two\necho 'a\\\nb'\necho c\\\\\necho d")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "one two\na\\\nb\nc\\\nd\n"
+    );
+}
+
+#[test]
+fn test_ansi_c_quoted_arguments_and_case_patterns() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            r"echo $'\x41'; printf '%s' $'line1\nline2'; echo; case $'a\tb' in $'a\tb') echo tab;; esac",
+        )
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "A\nline1\nline2\ntab\n"
+    );
+}
+
+#[test]
+fn test_compgen_wordlist_and_default_fallback() {
+    let binary_path = get_flash_binary_path();
+    let temp_dir = tempdir().unwrap();
+    std::fs::write(temp_dir.path().join("zeta.txt"), "").unwrap();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(format!(
+            "compgen -W 'alpha beta alps' al; compgen -W 'alpha' {dir}/ze; compgen -o default -W 'alpha' {dir}/ze",
+            dir = temp_dir.path().display()
+        ))
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("alpha\nalps\n{}/zeta.txt\n", temp_dir.path().display())
+    );
+}
+
+#[test]
+fn test_command_substitution_file_read_warns_on_missing_file() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("v=$(< /nonexistent/VERSION); echo \"[$v]\"")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "[]\n");
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("flash: /nonexistent/VERSION: No such file or directory")
+    );
+}
+
+#[test]
+fn test_declare_fx_lists_exported_functions() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("b() { :; }; a() { :; }; c() { :; }; export -f b a; export -f nope; declare -fx")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "declare -fx a\ndeclare -fx b\n"
+    );
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("export: nope: not a function")
+    );
+}
+
+#[test]
+fn test_kill_and_trap_list_signals() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("kill -l 9; kill -l TERM; kill -l; trap -l")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(&lines[..2], ["KILL", "15"]);
+
+    // kill -l and trap -l print the same numbered columns
+    let (kill_list, trap_list) = lines[2..].split_at((lines.len() - 2) / 2);
+    assert_eq!(kill_list, trap_list);
+    assert!(kill_list[0].starts_with(" 1) SIGHUP\t 2) SIGINT\t"));
+    for name in ["SIGHUP", "SIGINT", "SIGTERM"] {
+        assert!(kill_list.iter().any(|line| line.contains(name)));
+    }
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_jobs_lists_background_job_pids() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("sleep 5 & jobs -p; jobs -l; jobs; jobs -s; kill %1")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3, "unexpected output: {stdout}");
+
+    // jobs -p prints only the PID, which jobs -l shows next to the job number
+    let pid: i32 = lines[0].parse().expect("jobs -p should print a PID");
+    assert_eq!(
+        lines[1],
+        format!("[1]+ {pid} Running                 sleep 5 &")
+    );
+    assert_eq!(lines[2], "[1]+  Running                 sleep 5 &");
+}
+
+#[test]
+fn test_extended_test_matches_globs_and_regexes() {
+    let binary_path = get_flash_binary_path();
+
+    // Unquoted right-hand sides are patterns, and =~ fills BASH_REMATCH
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            "file=notes.txt; x=123; [[ $file == *.txt ]] && echo glob; \
+             [[ $x =~ ^([0-9])([0-9]+)$ ]] && echo \"$BASH_REMATCH ${BASH_REMATCH[2]}\"; \
+             [[ $x =~ ^[a-z]+$ ]] || echo nomatch",
+        )
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "glob\n123 23\nnomatch\n"
+    );
+
+    // File and string tests combine with && and || without word splitting
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("s='a b'; [[ -d / && -z '' && ! -f /nonexistent ]] && [[ -n $s || $s == x ]] && echo ok")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ok\n");
+}
+
+#[test]
+fn test_indexed_array_elements_and_length() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("a=(x y z); echo ${a[1]} ${#a[@]}; for e in \"${a[@]}\"; do echo \"<$e>\"; done")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "y 3\n<x>\n<y>\n<z>\n"
+    );
+
+    // Arrays cannot be exported, so their elements stay out of the environment
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("a=(x y); s=1; s[2]=z; echo ${s[0]} ${!s[@]}; env | grep -c '^[as]\\['")
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1 0 2\n0\n");
+}
+
+#[test]
+fn test_declare_attributes() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("declare -i n; n=3+4; echo $n; typeset -x E=5; printenv E; declare -p n E")
+        .output()
+        .expect("Failed to execute flash");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "7\n5\ndeclare -i n=\"7\"\ndeclare -x E=\"5\"\n");
+    assert!(output.status.success());
+
+    // The expression is evaluated against the old value, and a bad one is reported
+    // without changing the variable
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("declare -i n=2; n=n*5; echo $n; n=1/0; echo \"$? $n\"")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "10\n1 10\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "flash: 1/0: division by 0 (error token is \"0\")\n"
+    );
+
+    // Readonly variables keep their value and the assignment fails
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("declare -r C=1; C=2; echo \"$? $C\"")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1 1\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "flash: C: readonly variable\n"
+    );
+
+    // -a makes an indexed array, turning a scalar into its element 0
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("declare -a arr=(x y); s=v; declare -a s; declare -p arr s")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "declare -a arr=([0]=\"x\" [1]=\"y\")\ndeclare -a s=([0]=\"v\")\n"
+    );
+
+    // +i and +x remove attributes, and other + flags are refused rather than declared
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("declare -ix n=1; declare +i +x n; n=2+3; declare -p n; declare +r n; echo $?")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "declare -- n=\"2+3\"\n2\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "declare: +r: invalid option\n"
+    );
+}
+
+#[test]
+fn test_readonly_refuses_reassignment() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg("readonly PI=3; PI=4; echo \"$? $PI\"; export PI=5; echo \"$? $PI\"; readonly -p")
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 3\n1 3\nreadonly PI=\"3\"\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "flash: PI: readonly variable\nflash: PI: readonly variable\n"
+    );
+
+    // Every builtin that assigns a variable refuses a readonly one
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            "readonly R=1; read R <<< x; printf -v R %s y; getopts a R -a; \
+             f() { local R=5; }; f; ((R=3)); for R in z; do :; done; mapfile R </dev/null; \
+             echo \"$R\"",
+        )
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr.matches("R: readonly variable").count(),
+        7,
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_declaration_builtins_assign_arrays() {
+    let binary_path = get_flash_binary_path();
+
+    // The array is assigned before it becomes readonly, and local arrays are undone
+    // when the function returns
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            "readonly A=(1 \"a b\"); A[0]=x; declare -p A; \
+             f() { local L=(p q); echo \"${L[@]}\"; }; L=s; f; declare -p L; \
+             declare -A m=([k]=v); declare w='(x)'; declare -p m w",
+        )
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "declare -ar A=([0]=\"1\" [1]=\"a b\")\np q\ndeclare -- L=\"s\"\n\
+         declare -A m=([k]=\"v\")\ndeclare -- w=\"(x)\"\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "flash: A: readonly variable\n"
+    );
+}
+
+#[test]
+fn test_declaration_builtins_reject_a_separate_equals_sign() {
+    let binary_path = get_flash_binary_path();
+
+    // `name = value` is three arguments, and `=` is not a name
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(
+            "declare a = b; echo \"$? [$a]\"; f() { local l = b; echo \"$? [$l]\"; }; f; \
+             readonly q = 5; echo \"$? [$q]\"",
+        )
+        .output()
+        .expect("Failed to execute flash");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 []\n1 []\n1 []\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "declare: `=': not a valid identifier\n\
+         local: `=': not a valid identifier\n\
+         readonly: `=': not a valid identifier\n\
+         readonly: `5': not a valid identifier\n"
+    );
+}
+
+#[test]
+fn test_xtrace_quotes_expanded_arguments_without_expanding_them_again() {
+    let binary_path = get_flash_binary_path();
+
+    let output = Command::new(&binary_path)
+        .arg("-c")
+        .arg(r#"set -x; echo '$HOME' "a b" '' "it's""#)
+        .output()
+        .expect("Failed to execute flash");
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "$HOME a b  it's\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "+ echo '$HOME' 'a b' '' 'it'\\''s'\n"
+    );
+}
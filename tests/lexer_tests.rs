@@ -86,14 +86,12 @@ fn test_lexer_command_substitution() {
 
 #[test]
 fn test_lexer_arithmetic_expansion() {
-    let mut lexer = Lexer::new("$((1 + 2))");
+    let mut lexer = Lexer::new("$((1 + 2)) x");
 
-    assert_eq!(lexer.next_token().kind, TokenKind::ArithSubst);
-    assert_eq!(lexer.next_token().kind, TokenKind::Word("1".to_string()));
-    assert_eq!(lexer.next_token().kind, TokenKind::Word("+".to_string()));
-    assert_eq!(lexer.next_token().kind, TokenKind::Word("2".to_string()));
-    assert_eq!(lexer.next_token().kind, TokenKind::RParen);
-    assert_eq!(lexer.next_token().kind, TokenKind::RParen);
+    let token = lexer.next_token();
+    assert_eq!(token.kind, TokenKind::ArithSubst);
+    assert_eq!(token.value, "$((1 + 2))");
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("x".to_string()));
 }
 
 #[test]
@@ -133,6 +131,79 @@ fn test_lexer_comments() {
     );
 }
 
+#[test]
+fn test_lexer_hash_inside_word() {
+    let mut lexer = Lexer::new("echo 16#ff #comment");
+
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("echo".to_string()));
+    assert_eq!(
+        lexer.next_token().kind,
+        TokenKind::Word("16#ff".to_string())
+    );
+    assert_eq!(lexer.next_token().kind, TokenKind::Comment);
+}
+
+#[test]
+fn test_lexer_here_document_body() {
+    let mut lexer = Lexer::new("cat <<-'EOF' | wc\n\thello\n\tEOF\necho");
+
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("cat".to_string()));
+    assert_eq!(lexer.next_token().kind, TokenKind::HereDocDash);
+    assert_eq!(
+        lexer.next_token().kind,
+        TokenKind::Word("'EOF'".to_string())
+    );
+    assert_eq!(
+        lexer.next_token().kind,
+        TokenKind::HereDocContent("hello\n".to_string())
+    );
+    assert_eq!(lexer.next_token().kind, TokenKind::Pipe);
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("wc".to_string()));
+    assert_eq!(lexer.next_token().kind, TokenKind::Newline);
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("echo".to_string()));
+}
+
+#[test]
+fn test_lexer_ansi_c_string() {
+    let mut lexer = Lexer::new(r"x=$'a\tb\x41\101\u00e9\cA\'\q'");
+
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("x".to_string()));
+    assert_eq!(lexer.next_token().kind, TokenKind::Assignment);
+    assert_eq!(
+        lexer.next_token().kind,
+        TokenKind::AnsiCString("a\tbAA\u{e9}\x01'\\q".to_string())
+    );
+    assert_eq!(lexer.next_token().kind, TokenKind::EOF);
+}
+
+#[test]
+fn test_lexer_token_spans() {
+    let input = "x=\"é\" && cat <<EOF\nbody\nEOF\nls";
+    let mut lexer = Lexer::new(input);
+
+    let spans: Vec<_> = lexer
+        .tokens()
+        .map(|(token, start, end)| (token.kind, &input[start..end]))
+        .collect();
+    assert_eq!(
+        spans,
+        vec![
+            (TokenKind::Word("x".to_string()), "x"),
+            (TokenKind::Assignment, "="),
+            (TokenKind::Quote, "\""),
+            (TokenKind::Word("é".to_string()), "é"),
+            (TokenKind::Quote, "\""),
+            (TokenKind::And, "&&"),
+            (TokenKind::Word("cat".to_string()), "cat"),
+            (TokenKind::HereDoc, "<<"),
+            (TokenKind::Word("EOF".to_string()), "EOF"),
+            (TokenKind::HereDocContent("body\n".to_string()), "body\n"),
+            (TokenKind::Newline, "\n"),
+            (TokenKind::Word("ls".to_string()), "ls"),
+        ]
+    );
+}
+
 #[test]
 fn test_lexer_newlines() {
     let mut lexer = Lexer::new("echo\n\nworld");
@@ -235,6 +306,22 @@ fn test_lexer_double_semicolon() {
     );
     assert_eq!(lexer.next_token().kind, TokenKind::DoubleSemicolon);
     assert_eq!(lexer.next_token().kind, TokenKind::Esac);
+
+    // Fall-through and continue-testing terminators
+    let mut lexer = Lexer::new("a ;& b ;;& c");
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("a".to_string()));
+    assert_eq!(lexer.next_token().kind, TokenKind::SemicolonAnd);
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("b".to_string()));
+    assert_eq!(lexer.next_token().kind, TokenKind::DoubleSemicolonAnd);
+    assert_eq!(lexer.next_token().kind, TokenKind::Word("c".to_string()));
+
+    // A bracket expression with a named class is a pattern word, not [[
+    let mut lexer = Lexer::new("[[:alpha:]])");
+    assert_eq!(
+        lexer.next_token().kind,
+        TokenKind::Word("[[:alpha:]]".to_string())
+    );
+    assert_eq!(lexer.next_token().kind, TokenKind::RParen);
 }
 
 #[test]
@@ -318,8 +405,7 @@ fn test_lexer_complex_redirection() {
 
     assert_eq!(lexer.next_token().kind, TokenKind::Word("cmd".to_string()));
     assert_eq!(lexer.next_token().kind, TokenKind::Word("2".to_string()));
-    assert_eq!(lexer.next_token().kind, TokenKind::Great);
-    assert_eq!(lexer.next_token().kind, TokenKind::Background);
+    assert_eq!(lexer.next_token().kind, TokenKind::GreatAnd);
     assert_eq!(lexer.next_token().kind, TokenKind::Word("1".to_string()));
     assert_eq!(lexer.next_token().kind, TokenKind::Word("3".to_string()));
     assert_eq!(lexer.next_token().kind, TokenKind::Less);
@@ -386,7 +472,8 @@ fn test_arith_command_full() {
 
     // Check that the first token is ArithCommand
     assert_eq!(tokens[0].kind, TokenKind::ArithCommand);
-    assert_eq!(tokens[0].value, "((");
+    assert_eq!(tokens[0].value, "(( 5 == 10 ))");
+    assert_eq!(tokens[1].kind, TokenKind::EOF);
 }
 
 #[test]